@@ -1,27 +1,45 @@
+use crate::api_types::DocCreationRequest;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 /// Request for generating a presigned URL for content upload
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct ContentUploadRequest {
     /// The content type of the file to upload
     #[serde(rename = "contentType")]
     pub content_type: String,
+
+    /// Hex encoded SHA-256 of the file. When given, the asset is stored under its hash and
+    /// the upload is skipped if the document already has an asset with the same content.
+    #[serde(default)]
+    pub sha256: Option<String>,
+
+    /// Size of the file in bytes. Required by servers with a quota on asset bytes.
+    #[serde(rename = "contentLength", default)]
+    pub content_length: Option<u64>,
+
+    /// Original name of the file, given to the file when the asset is downloaded.
+    #[serde(default)]
+    pub filename: Option<String>,
 }
 
 /// Response containing a presigned URL for content upload
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct ContentUploadResponse {
-    /// The signed URL for uploading the content
-    #[serde(rename = "uploadUrl")]
-    pub upload_url: String,
+    /// The signed URL for uploading the content. Absent when the upload was deduplicated.
+    #[serde(rename = "uploadUrl", skip_serializing_if = "Option::is_none")]
+    pub upload_url: Option<String>,
 
     /// The asset ID that will be used to store the content
     #[serde(rename = "assetId")]
     pub asset_id: String,
+
+    /// Whether an asset with the same content already exists, so nothing needs to be uploaded
+    pub deduplicated: bool,
 }
 
 /// Asset URL with presigned download URL
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct AssetUrl {
     /// The asset ID (without extension) of the asset
     #[serde(rename = "assetId")]
@@ -30,25 +48,113 @@ pub struct AssetUrl {
     /// The signed URL for downloading the asset
     #[serde(rename = "downloadUrl")]
     pub download_url: String,
+
+    /// Original name of the file, if it was given on upload.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+
+    /// Progress of the post-upload processing of the asset, on servers with an asset hook.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<AssetStatus>,
+}
+
+/// Progress of the post-upload processing of an asset
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AssetStatus {
+    /// The upload was confirmed and the asset hook has not succeeded yet; it may be retrying.
+    Pending,
+    /// The asset hook succeeded.
+    Ready,
+    /// The asset hook failed on every attempt.
+    Failed,
+}
+
+/// Response to confirming the upload of an asset
+#[derive(Serialize, Deserialize)]
+pub struct AssetConfirmResponse {
+    /// The asset ID of the confirmed asset, with its extension
+    #[serde(rename = "assetId")]
+    pub asset_id: String,
+
+    pub status: AssetStatus,
+}
+
+/// An uploaded asset reported by the store
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ReportedAsset {
+    #[serde(rename = "docId")]
+    pub doc_id: String,
+
+    /// The asset ID, with its extension
+    #[serde(rename = "assetId")]
+    pub asset_id: String,
+
+    pub status: AssetStatus,
+}
+
+/// Response to a batch of store event notifications
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AssetEventsResponse {
+    /// The uploads processed from the notifications
+    pub assets: Vec<ReportedAsset>,
+
+    /// Number of events that were not new uploads of assets
+    pub ignored: usize,
+}
+
+/// Request for a token granting read access to one asset
+#[derive(Serialize, Deserialize, Default)]
+pub struct AssetTokenRequest {
+    /// How long the token is valid, in seconds
+    #[serde(
+        rename = "validForSeconds",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub valid_for_seconds: Option<u64>,
+}
+
+/// Token granting read access to one asset, with the URL downloading the asset through the
+/// server
+#[derive(Serialize, Deserialize)]
+pub struct AssetTokenResponse {
+    /// The asset ID of the asset, with its extension
+    #[serde(rename = "assetId")]
+    pub asset_id: String,
+
+    /// The token, absent when the server does not check tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+
+    /// URL downloading the asset with the token, e.g. to embed it in a page
+    pub url: String,
+
+    /// When the token expires (epoch millis)
+    #[serde(rename = "expiresAt")]
+    pub expires_at: u64,
 }
 
 /// Response containing a list of assets with presigned download URLs
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct AssetsResponse {
     /// List of asset URLs with signed download URLs
     pub assets: Vec<AssetUrl>,
 }
 
 /// Request for copying a document to a new document ID
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct DocCopyRequest {
     /// The ID of the destination document where the source document will be copied to
     #[serde(rename = "destinationDocId")]
     pub destination_doc_id: String,
+    /// Only report the objects that would be written, without copying the document.
+    #[serde(default, rename = "dryRun")]
+    pub dry_run: bool,
 }
 
 /// Response for document copy operation
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct DocCopyResponse {
     /// The ID of the source document that was copied
     #[serde(rename = "sourceDocId")]
@@ -58,10 +164,24 @@ pub struct DocCopyResponse {
     pub destination_doc_id: String,
     /// Whether the copy operation was successful
     pub success: bool,
+    /// Whether the document was left uncopied.
+    #[serde(rename = "dryRun")]
+    pub dry_run: bool,
+    /// Keys of the objects that would be written, only listed for dry runs.
+    #[serde(rename = "writtenObjects", skip_serializing_if = "Option::is_none")]
+    pub written_objects: Option<Vec<String>>,
+}
+
+/// Request for deleting a document
+#[derive(Serialize, Deserialize, Default)]
+pub struct DocDeleteRequest {
+    /// Only report the objects that would be removed, without deleting the document.
+    #[serde(default, rename = "dryRun")]
+    pub dry_run: bool,
 }
 
 /// Response for document deletion operation
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct DocDeleteResponse {
     /// The document that was deleted.
     #[serde(rename = "docId")]
@@ -74,4 +194,594 @@ pub struct DocDeleteResponse {
     pub deleted_assets: usize,
     /// Indicates that the delete operation completed without errors.
     pub success: bool,
+    /// Whether the document was left in place.
+    #[serde(rename = "dryRun")]
+    pub dry_run: bool,
+    /// Keys of the objects that would be removed, only listed for dry runs.
+    #[serde(rename = "removedObjects", skip_serializing_if = "Option::is_none")]
+    pub removed_objects: Option<Vec<String>>,
+}
+
+/// Request for deleting multiple documents in one call
+#[derive(Deserialize)]
+pub struct DocBatchDeleteRequest {
+    /// Explicit list of document IDs to delete
+    #[serde(default, rename = "docIds")]
+    pub doc_ids: Vec<String>,
+    /// Delete every document whose ID starts with this prefix
+    #[serde(default)]
+    pub prefix: Option<String>,
+    /// Only report the objects that would be removed, without deleting the documents.
+    #[serde(default, rename = "dryRun")]
+    pub dry_run: bool,
+}
+
+/// Result of deleting a single document as part of a batch
+#[derive(Serialize)]
+pub struct DocBatchDeleteResult {
+    /// The document this result refers to.
+    #[serde(rename = "docId")]
+    pub doc_id: String,
+    /// Whether the document was deleted without errors.
+    pub success: bool,
+    /// Whether the stored snapshot (data.ysweet) was removed.
+    #[serde(rename = "dataDeleted")]
+    pub data_deleted: bool,
+    /// Number of asset objects removed from storage.
+    #[serde(rename = "deletedAssets")]
+    pub deleted_assets: usize,
+    /// Keys of the objects that would be removed, only listed for dry runs.
+    #[serde(rename = "removedObjects", skip_serializing_if = "Option::is_none")]
+    pub removed_objects: Option<Vec<String>>,
+    /// Error message when the deletion failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Response for batch document deletion
+#[derive(Serialize)]
+pub struct DocBatchDeleteResponse {
+    /// Per-document results, one entry per requested document.
+    pub results: Vec<DocBatchDeleteResult>,
+    /// Number of documents deleted successfully.
+    pub deleted: usize,
+    /// Number of documents that could not be deleted.
+    pub failed: usize,
+    /// Whether the documents were left in place.
+    #[serde(rename = "dryRun")]
+    pub dry_run: bool,
+}
+
+/// Response for document archive and unarchive operations
+#[derive(Serialize)]
+pub struct DocArchiveResponse {
+    /// The document that was archived or restored.
+    #[serde(rename = "docId")]
+    pub doc_id: String,
+    /// Whether the document is archived after the operation.
+    pub archived: bool,
+    /// Number of asset objects moved along with the document data.
+    #[serde(rename = "movedAssets")]
+    pub moved_assets: usize,
+}
+
+/// Request for setting a document's time-to-live
+#[derive(Deserialize)]
+pub struct DocTtlRequest {
+    /// Number of seconds from now after which the document is deleted.
+    #[serde(rename = "expiresInSeconds")]
+    pub expires_in_seconds: u64,
+}
+
+/// Response for document time-to-live operations
+#[derive(Serialize)]
+pub struct DocTtlResponse {
+    /// The document the TTL applies to.
+    #[serde(rename = "docId")]
+    pub doc_id: String,
+    /// Expiration time in epoch milliseconds, or null if the document does not expire.
+    #[serde(rename = "expiresAt")]
+    pub expires_at: Option<u64>,
+}
+
+/// Response for freezing or unfreezing a document
+#[derive(Serialize)]
+pub struct DocFreezeResponse {
+    /// The document that was frozen or unfrozen.
+    #[serde(rename = "docId")]
+    pub doc_id: String,
+    /// Whether the document is now read-only.
+    pub frozen: bool,
+}
+
+/// Format of a published document
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PublishFormat {
+    /// A standalone HTML page.
+    #[default]
+    Html,
+    #[serde(alias = "md")]
+    Markdown,
+}
+
+/// Request for publishing a read-only snapshot of a document
+#[derive(Serialize, Deserialize, Default)]
+pub struct DocPublishRequest {
+    #[serde(default)]
+    pub format: PublishFormat,
+
+    /// Root type to render, `default` if not given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub root: Option<String>,
+}
+
+/// Response for publishing or unpublishing a document
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DocPublishResponse {
+    /// The document that was published or unpublished.
+    #[serde(rename = "docId")]
+    pub doc_id: String,
+    /// Whether the document is published after the operation.
+    pub published: bool,
+    /// Public URL of the published snapshot, if published.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<PublishFormat>,
+}
+
+/// Response for pinning or unpinning a document
+#[derive(Serialize)]
+pub struct DocPinResponse {
+    /// The document that was pinned or unpinned.
+    #[serde(rename = "docId")]
+    pub doc_id: String,
+    /// Whether the document is now kept loaded.
+    pub pinned: bool,
+}
+
+/// Request for compacting the update log of a document
+#[derive(Deserialize, Default)]
+pub struct DocCompactRequest {
+    /// Only report the objects that would be written and removed, without compacting.
+    #[serde(default, rename = "dryRun")]
+    pub dry_run: bool,
+}
+
+/// Response for compacting the update log of a document
+#[derive(Serialize)]
+pub struct DocCompactResponse {
+    /// The document that was compacted.
+    #[serde(rename = "docId")]
+    pub doc_id: String,
+    /// Last update log segment included in the fresh snapshot, or null if nothing was
+    /// logged yet.
+    #[serde(rename = "snapshotSeq")]
+    pub snapshot_seq: Option<u64>,
+    /// Number of update log segments removed from the store.
+    #[serde(rename = "reclaimedObjects")]
+    pub reclaimed_objects: usize,
+    /// Time the compaction took, in milliseconds.
+    #[serde(rename = "durationMs")]
+    pub duration_ms: u64,
+    /// Whether the document was left uncompacted.
+    #[serde(rename = "dryRun")]
+    pub dry_run: bool,
+    /// Keys of the objects that would be written, only listed for dry runs.
+    #[serde(rename = "writtenObjects", skip_serializing_if = "Option::is_none")]
+    pub written_objects: Option<Vec<String>>,
+    /// Keys of the objects that would be removed, only listed for dry runs.
+    #[serde(rename = "removedObjects", skip_serializing_if = "Option::is_none")]
+    pub removed_objects: Option<Vec<String>>,
+}
+
+/// Request for restoring a document as of a point of its history. Exactly one of `seq` and
+/// `timestamp` is set.
+#[derive(Deserialize)]
+pub struct DocRestoreRequest {
+    /// Sequence number of the last update log segment to restore.
+    #[serde(default)]
+    pub seq: Option<u64>,
+    /// Time to restore the document as of, in epoch milliseconds.
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+    /// The document to restore into. If not provided, the document is restored in place.
+    #[serde(default, rename = "targetDocId")]
+    pub target_doc_id: Option<String>,
+}
+
+/// Response for document restore operation
+#[derive(Serialize)]
+pub struct DocRestoreResponse {
+    /// The document holding the restored state.
+    #[serde(rename = "docId")]
+    pub doc_id: String,
+    /// The document whose history was restored.
+    #[serde(rename = "sourceDocId")]
+    pub source_doc_id: String,
+    /// Last update log segment included in the restored state, or null if it predates
+    /// every segment still in the log.
+    #[serde(rename = "restoredSeq")]
+    pub restored_seq: Option<u64>,
+}
+
+/// A document whose changes could not be persisted
+#[derive(Serialize)]
+pub struct FailingDoc {
+    #[serde(rename = "docId")]
+    pub doc_id: String,
+    /// Time of the first failure since the last successful checkpoint, in epoch milliseconds.
+    #[serde(rename = "failingSince")]
+    pub failing_since: u64,
+    /// Failed attempts since the last successful checkpoint.
+    pub attempts: u32,
+    #[serde(rename = "lastError")]
+    pub last_error: String,
+    /// Whether the document has stopped accepting writes.
+    #[serde(rename = "rejectsWrites")]
+    pub rejects_writes: bool,
+}
+
+/// Response for the health check
+#[derive(Serialize)]
+pub struct HealthResponse {
+    /// False while any document is failing to persist.
+    pub ok: bool,
+    /// Number of documents whose last checkpoint failed.
+    #[serde(rename = "failingDocs")]
+    pub failing_docs: usize,
+    /// Number of failed checkpoints since the server started.
+    #[serde(rename = "persistFailures")]
+    pub persist_failures: u64,
+    /// The failing documents, only listed for authenticated requests.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failing: Option<Vec<FailingDoc>>,
+}
+
+/// Request for garbage collecting the deleted content of a document
+#[derive(Deserialize, Default)]
+pub struct DocGcRequest {
+    /// Only report the expected savings, without rewriting the document.
+    #[serde(default, rename = "dryRun")]
+    pub dry_run: bool,
+}
+
+/// Response for garbage collecting the deleted content of a document
+#[derive(Serialize, Debug)]
+pub struct DocGcResponse {
+    /// The document that was garbage collected.
+    #[serde(rename = "docId")]
+    pub doc_id: String,
+    /// Whether the document was left as it was.
+    #[serde(rename = "dryRun")]
+    pub dry_run: bool,
+    /// Size of the stored state before garbage collection, in bytes.
+    #[serde(rename = "bytesBefore")]
+    pub bytes_before: usize,
+    /// Size of the stored state after garbage collection, in bytes.
+    #[serde(rename = "bytesAfter")]
+    pub bytes_after: usize,
+    /// Bytes saved by garbage collection.
+    #[serde(rename = "savedBytes")]
+    pub saved_bytes: usize,
+}
+
+/// Statistics of a document whose stored state loaded successfully
+#[derive(Serialize, Debug)]
+pub struct DocStats {
+    /// Base64 encoded Yjs (v1) state vector of the stored state.
+    #[serde(rename = "stateVector")]
+    pub state_vector: String,
+    /// Number of clients that edited the document.
+    pub clients: usize,
+    /// Number of elements ever inserted into the document, deleted ones included.
+    pub elements: u64,
+    /// Names of the root types of the document.
+    #[serde(rename = "rootTypes")]
+    pub root_types: Vec<String>,
+    /// Size of the document encoded as a Yjs (v1) update, in bytes.
+    #[serde(rename = "updateBytes")]
+    pub update_bytes: usize,
+}
+
+/// Result of verifying the stored state of a document
+#[derive(Serialize, Debug)]
+pub struct DocVerifyResult {
+    /// The document this result refers to.
+    #[serde(rename = "docId")]
+    pub doc_id: String,
+    /// Whether the stored state is intact and decodes into a valid document.
+    pub ok: bool,
+    /// Size of the stored snapshot in bytes, or null if there is none.
+    #[serde(rename = "snapshotBytes")]
+    pub snapshot_bytes: Option<usize>,
+    /// Why the stored state is not valid.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Statistics of the document, when it is valid.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stats: Option<DocStats>,
+}
+
+/// Request for verifying multiple documents in one call
+#[derive(Deserialize)]
+pub struct DocBatchVerifyRequest {
+    /// Explicit list of document IDs to verify
+    #[serde(default, rename = "docIds")]
+    pub doc_ids: Vec<String>,
+    /// Verify every stored document whose ID starts with this prefix
+    #[serde(default)]
+    pub prefix: Option<String>,
+}
+
+/// Response for batch document verification
+#[derive(Serialize)]
+pub struct DocBatchVerifyResponse {
+    /// Per-document results, one entry per verified document.
+    pub results: Vec<DocVerifyResult>,
+    /// Number of documents whose stored state is valid.
+    pub valid: usize,
+    /// Number of documents whose stored state is not valid.
+    pub invalid: usize,
+}
+
+/// Request for merging another document into a document
+#[derive(Deserialize)]
+pub struct DocMergeRequest {
+    /// The ID of the document whose content is merged into the target document
+    #[serde(rename = "sourceDocId")]
+    pub source_doc_id: String,
+}
+
+/// Response for document merge operation
+#[derive(Serialize)]
+pub struct DocMergeResponse {
+    /// The ID of the document that received the merged content
+    #[serde(rename = "docId")]
+    pub doc_id: String,
+    /// The ID of the document whose content was merged
+    #[serde(rename = "sourceDocId")]
+    pub source_doc_id: String,
+    /// Whether the merge operation was successful
+    pub success: bool,
+}
+
+/// Lineage of a document that was created by forking another document
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DocLineage {
+    /// The ID of the document this document was forked from
+    #[serde(rename = "parentDocId")]
+    pub parent_doc_id: String,
+    /// Base64 encoded (v1) state vector of the parent document at the fork point
+    #[serde(rename = "forkStateVector")]
+    pub fork_state_vector: String,
+    /// Time of the fork in epoch milliseconds
+    #[serde(rename = "forkedAt")]
+    pub forked_at: u64,
+}
+
+/// Metadata stored alongside a document
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct DocMetadata {
+    /// Set when the document was created by a fork
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lineage: Option<DocLineage>,
+    /// Set while the document is read-only
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub frozen: bool,
+}
+
+/// Response for document metadata retrieval
+#[derive(Serialize)]
+pub struct DocMetadataResponse {
+    /// The document the metadata belongs to
+    #[serde(rename = "docId")]
+    pub doc_id: String,
+    #[serde(flatten)]
+    pub metadata: DocMetadata,
+}
+
+/// Request for forking a document
+#[derive(Deserialize, Default)]
+pub struct DocForkRequest {
+    /// The ID of the new document. If not provided, a random ID will be generated.
+    #[serde(default, rename = "destinationDocId")]
+    pub destination_doc_id: Option<String>,
+}
+
+/// Response for document fork operation
+#[derive(Serialize)]
+pub struct DocForkResponse {
+    /// The ID of the newly created fork
+    #[serde(rename = "docId")]
+    pub doc_id: String,
+    /// Lineage recorded for the fork
+    pub lineage: DocLineage,
+}
+
+/// Response for an optimistic-concurrency update that was based on an outdated state
+#[derive(Serialize)]
+pub struct DocUpdateConflictResponse {
+    /// Base64 encoded (v1) state vector of the document's current state
+    #[serde(rename = "stateVector")]
+    pub state_vector: String,
+}
+
+/// Response for reloading the configuration
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ConfigReloadResponse {
+    /// Options of the configuration file that were applied
+    pub reloaded: Vec<String>,
+}
+
+/// Activity of a tenant since the server started
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TenantStats {
+    /// The ID of the tenant
+    #[serde(rename = "tenantId")]
+    pub tenant_id: String,
+    /// Number of documents of the tenant currently loaded
+    #[serde(rename = "loadedDocs")]
+    pub loaded_docs: usize,
+    /// Number of open WebSocket connections to documents of the tenant
+    pub connections: usize,
+    /// Number of times documents of the tenant were loaded
+    #[serde(rename = "docsLoaded")]
+    pub docs_loaded: u64,
+    /// Number of times documents of the tenant were unloaded by the GC or the memory budget
+    #[serde(rename = "docsEvicted")]
+    pub docs_evicted: u64,
+    /// Number of checkpoints of documents of the tenant written to its store
+    #[serde(rename = "docsPersisted")]
+    pub docs_persisted: u64,
+    /// Number of updates applied to documents of the tenant
+    #[serde(rename = "updatesApplied")]
+    pub updates_applied: u64,
+    /// Number of documents of the tenant whose last checkpoint failed
+    #[serde(rename = "failingDocs")]
+    pub failing_docs: usize,
+}
+
+/// Response listing the tenants of the server
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TenantsResponse {
+    pub tenants: Vec<TenantStats>,
+}
+
+/// Limits of a quota and what the documents it covers use of them
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QuotaStatus {
+    /// Prefix of the IDs of the documents the quota covers, empty for every document
+    pub prefix: String,
+    /// Maximum number of documents
+    #[serde(rename = "maxDocs")]
+    pub max_docs: Option<u64>,
+    /// Maximum total size of the stored state of the documents
+    #[serde(rename = "maxSnapshotBytes")]
+    pub max_snapshot_bytes: Option<u64>,
+    /// Maximum total size of the assets of the documents
+    #[serde(rename = "maxAssetBytes")]
+    pub max_asset_bytes: Option<u64>,
+    /// Number of documents
+    pub docs: u64,
+    /// Total size of the stored state of the documents
+    #[serde(rename = "snapshotBytes")]
+    pub snapshot_bytes: u64,
+    /// Total size of the assets of the documents
+    #[serde(rename = "assetBytes")]
+    pub asset_bytes: u64,
+}
+
+/// Response listing the quotas of the server
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QuotasResponse {
+    /// Whether the documents in the store were counted. Until then, usage only covers the
+    /// documents created or loaded since the server started.
+    pub counted: bool,
+    pub quotas: Vec<QuotaStatus>,
+}
+
+/// An entry of the audit trail of a document
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEntry {
+    /// When the action happened, in epoch millis
+    pub time: u64,
+    /// What happened, e.g. `token_issued`, `client_connected` or `document_deleted`
+    pub action: String,
+    /// Who did it: `server` for the server token, `doc:full` or `doc:read-only` for
+    /// document tokens, and `system` for the server itself
+    pub principal: String,
+    /// Fingerprint of the token of the principal, the start of its SHA-256 in hex
+    #[serde(rename = "tokenId", default, skip_serializing_if = "Option::is_none")]
+    pub token_id: Option<String>,
+    /// Details of the action, which depend on it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<serde_json::Value>,
+}
+
+/// Response listing a page of the audit trail of a document
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuditResponse {
+    /// Entries in chronological order
+    pub entries: Vec<AuditEntry>,
+    /// Pass as `cursor` to get the next page, absent on the last page
+    #[serde(
+        rename = "nextCursor",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub next_cursor: Option<String>,
+}
+
+/// A document matching a full-text search
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SearchHit {
+    #[serde(rename = "docId")]
+    pub doc_id: String,
+    /// Relevance of the document to the query, higher is better
+    pub score: f32,
+    /// Excerpt of the document around the terms of the query, if the index keeps the text
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snippet: Option<String>,
+}
+
+/// Response of `GET /search`, with the best matches first
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SearchResponse {
+    pub results: Vec<SearchHit>,
+}
+
+/// Request replacing the labels of a document
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct DocLabelsRequest {
+    /// Labels by key, e.g. `project` or `customer`. An empty map removes every label.
+    pub labels: BTreeMap<String, String>,
+}
+
+/// Response listing the labels of a document
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DocLabelsResponse {
+    #[serde(rename = "docId")]
+    pub doc_id: String,
+    pub labels: BTreeMap<String, String>,
+}
+
+/// A document listed by `GET /docs`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DocListEntry {
+    #[serde(rename = "docId")]
+    pub doc_id: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+}
+
+/// Response listing a page of documents, sorted by ID
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DocListResponse {
+    pub docs: Vec<DocListEntry>,
+    /// Pass as `cursor` to get the next page, absent on the last page
+    #[serde(
+        rename = "nextCursor",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub next_cursor: Option<String>,
+}
+
+/// Document creation request accepted by the server, extending the upstream request
+#[derive(Deserialize, Debug)]
+pub struct DocCreationRequestExt {
+    #[serde(flatten)]
+    pub request: DocCreationRequest,
+    /// If provided, the document (and its assets) is deleted this many seconds after creation.
+    #[serde(default, rename = "expiresInSeconds")]
+    pub expires_in_seconds: Option<u64>,
+}
+
+/// Whether `c` may appear in a document name, as accepted by
+/// [crate::api_types::validate_doc_name]. Generated document names (see `DocIdGenerator` in
+/// the server) are restricted to the same characters.
+pub fn is_doc_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
 }
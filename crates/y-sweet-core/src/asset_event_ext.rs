@@ -0,0 +1,112 @@
+//! Notifications of changes to the assets of a document for the WebSocket protocol.
+//!
+//! Every client connected to a document is told when its assets change, with a custom
+//! message, so that editors can refresh their attachments without polling the asset
+//! listing. When the upload of an asset finishes (the client confirmed it, or the store
+//! reported it), and again when the asset hook is done with it:
+//!
+//! ```text
+//! [MSG_ASSET : varUint, varBuf(payload)]
+//! payload = [assetId : varString, status : varString]
+//! ```
+//!
+//! and when an asset is deleted:
+//!
+//! ```text
+//! [MSG_ASSET_DELETED : varUint, varBuf(assetId : varString)]
+//! ```
+//!
+//! `assetId` has its extension, as in the upload response, and `status` is the status of
+//! its processing (`pending` while an asset hook runs, then `ready` or `failed`; `ready`
+//! without a hook). Clients that do not know the messages ignore them.
+
+use crate::api_types_ext::AssetStatus;
+use crate::sync::Message;
+use yrs::{
+    encoding::{
+        read::{Cursor, Read},
+        write::Write,
+    },
+    updates::{
+        decoder::DecoderV1,
+        encoder::{Encode, Encoder, EncoderV1},
+    },
+};
+
+/// Tag id of the asset notification, sent as [Message::Custom].
+pub const MSG_ASSET: u8 = 105;
+
+/// Tag id of the notification of a deleted asset, sent as [Message::Custom].
+pub const MSG_ASSET_DELETED: u8 = 106;
+
+fn status_name(status: AssetStatus) -> &'static str {
+    match status {
+        AssetStatus::Pending => "pending",
+        AssetStatus::Ready => "ready",
+        AssetStatus::Failed => "failed",
+    }
+}
+
+/// Notify that `asset_id` was uploaded, with the status of its processing.
+pub fn encode_asset_message(asset_id: &str, status: AssetStatus) -> Vec<u8> {
+    let mut encoder = EncoderV1::new();
+    encoder.write_string(asset_id);
+    encoder.write_string(status_name(status));
+    Message::Custom(MSG_ASSET, encoder.to_vec()).encode_v1()
+}
+
+/// Decode the payload of an asset notification into its asset ID and status. Unknown
+/// statuses are None.
+pub fn decode_asset_payload(
+    payload: &[u8],
+) -> Result<(String, Option<AssetStatus>), yrs::encoding::read::Error> {
+    let mut decoder = DecoderV1::new(Cursor::new(payload));
+    let asset_id = decoder.read_string()?.to_string();
+    let status = match decoder.read_string()? {
+        "pending" => Some(AssetStatus::Pending),
+        "ready" => Some(AssetStatus::Ready),
+        "failed" => Some(AssetStatus::Failed),
+        _ => None,
+    };
+    Ok((asset_id, status))
+}
+
+/// Notify that `asset_id` was deleted.
+pub fn encode_asset_deleted_message(asset_id: &str) -> Vec<u8> {
+    let mut encoder = EncoderV1::new();
+    encoder.write_string(asset_id);
+    Message::Custom(MSG_ASSET_DELETED, encoder.to_vec()).encode_v1()
+}
+
+/// Decode the payload of a deleted asset notification into its asset ID.
+pub fn decode_asset_deleted_payload(payload: &[u8]) -> Result<String, yrs::encoding::read::Error> {
+    let mut decoder = DecoderV1::new(Cursor::new(payload));
+    Ok(decoder.read_string()?.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use yrs::updates::decoder::Decode;
+
+    #[test]
+    fn asset_message_round_trip() {
+        let data = encode_asset_message("ckxyz123.png", AssetStatus::Pending);
+        let Message::Custom(MSG_ASSET, payload) = Message::decode_v1(&data).unwrap() else {
+            panic!("expected an asset notification");
+        };
+        assert_eq!(
+            decode_asset_payload(&payload).unwrap(),
+            ("ckxyz123.png".to_string(), Some(AssetStatus::Pending))
+        );
+
+        let data = encode_asset_deleted_message("ckxyz123.png");
+        let Message::Custom(MSG_ASSET_DELETED, payload) = Message::decode_v1(&data).unwrap() else {
+            panic!("expected a deleted asset notification");
+        };
+        assert_eq!(
+            decode_asset_deleted_payload(&payload).unwrap(),
+            "ckxyz123.png"
+        );
+    }
+}
@@ -0,0 +1,182 @@
+//! Pluggable token verification, and tokens granting access to a single asset.
+//!
+//! By default a server verifies the signed tokens of an [Authenticator]. Embedders can give
+//! the server builder their own [AuthProvider] instead.
+
+use crate::{
+    api_types::Authorization,
+    auth::{
+        AuthError, AuthenticatedRequest, Authenticator, DocPermission, ExpirationTimeEpochMillis,
+        Permission, BASE64_CUSTOM,
+    },
+};
+use bincode::Options;
+
+/// Verifies and issues the tokens of a server, so that embedders can plug in their own
+/// token scheme (e.g. session cookies or JWTs) instead of the signed tokens of an
+/// [Authenticator].
+pub trait AuthProvider: Send + Sync {
+    /// Whether `token` grants access to the whole server.
+    fn verify_server_token(
+        &self,
+        token: &str,
+        current_time_epoch_millis: u64,
+    ) -> Result<(), AuthError>;
+
+    /// The access to `doc` that `token` grants.
+    fn verify_doc_token(
+        &self,
+        token: &str,
+        doc: &str,
+        current_time_epoch_millis: u64,
+    ) -> Result<Authorization, AuthError>;
+
+    /// A token granting `authorization` on `doc_id` until `expiration_time`, returned to
+    /// clients by `/doc/:doc_id/auth`.
+    fn gen_doc_token(
+        &self,
+        doc_id: &str,
+        authorization: Authorization,
+        expiration_time: ExpirationTimeEpochMillis,
+    ) -> String;
+}
+
+impl AuthProvider for Authenticator {
+    fn verify_server_token(
+        &self,
+        token: &str,
+        current_time_epoch_millis: u64,
+    ) -> Result<(), AuthError> {
+        Authenticator::verify_server_token(self, token, current_time_epoch_millis)
+    }
+
+    fn verify_doc_token(
+        &self,
+        token: &str,
+        doc: &str,
+        current_time_epoch_millis: u64,
+    ) -> Result<Authorization, AuthError> {
+        Authenticator::verify_doc_token(self, token, doc, current_time_epoch_millis)
+    }
+
+    fn gen_doc_token(
+        &self,
+        doc_id: &str,
+        authorization: Authorization,
+        expiration_time: ExpirationTimeEpochMillis,
+    ) -> String {
+        Authenticator::gen_doc_token(self, doc_id, authorization, expiration_time)
+    }
+}
+
+/// Resource of the tokens granting read access to one asset of a document. Document names
+/// cannot contain `/`, so it never names a document.
+fn asset_resource(doc_id: &str, asset_id: &str) -> String {
+    format!("{}/assets/{}", doc_id, asset_id)
+}
+
+/// The permission a token claims, without verifying its signature.
+fn claimed_permission(token: &str) -> Result<Permission, AuthError> {
+    let token = token.split_once('.').map_or(token, |(_, token)| token);
+    let bytes = BASE64_CUSTOM
+        .decode(token.as_bytes())
+        .map_err(|_| AuthError::InvalidToken)?;
+    let request: AuthenticatedRequest = bincode::DefaultOptions::new()
+        .deserialize(&bytes)
+        .map_err(|_| AuthError::InvalidToken)?;
+    Ok(request.payload.payload)
+}
+
+/// A token granting read access to `asset_id` (with its extension) of `doc_id` only,
+/// until `expiration_time`. It is a read-only document token for a resource naming the asset.
+pub fn gen_asset_token(
+    authenticator: &Authenticator,
+    doc_id: &str,
+    asset_id: &str,
+    expiration_time: ExpirationTimeEpochMillis,
+) -> String {
+    authenticator.gen_doc_token(
+        &asset_resource(doc_id, asset_id),
+        Authorization::ReadOnly,
+        expiration_time,
+    )
+}
+
+/// The asset of `doc` that an asset token grants read access to.
+pub fn verify_asset_token(
+    authenticator: &Authenticator,
+    token: &str,
+    doc: &str,
+    current_time_epoch_millis: u64,
+) -> Result<String, AuthError> {
+    let Permission::Doc(DocPermission {
+        doc_id: resource, ..
+    }) = claimed_permission(token)?
+    else {
+        return Err(AuthError::InvalidResource);
+    };
+    let asset_id = resource
+        .strip_prefix(doc)
+        .and_then(|rest| rest.strip_prefix("/assets/"))
+        .ok_or(AuthError::InvalidResource)?;
+    authenticator.verify_doc_token(token, &resource, current_time_epoch_millis)?;
+    Ok(asset_id.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_asset_token_only_grants_its_asset() {
+        let authenticator = Authenticator::gen_key().unwrap();
+        let token = gen_asset_token(
+            &authenticator,
+            "doc123",
+            "photo.png",
+            ExpirationTimeEpochMillis(1000),
+        );
+        assert_eq!(
+            verify_asset_token(&authenticator, &token, "doc123", 0),
+            Ok("photo.png".to_string())
+        );
+        assert_eq!(
+            verify_asset_token(&authenticator, &token, "doc456", 0),
+            Err(AuthError::InvalidResource)
+        );
+        assert_eq!(
+            verify_asset_token(&authenticator, &token, "doc123", 1001),
+            Err(AuthError::Expired)
+        );
+        assert!(matches!(
+            authenticator.verify_doc_token(&token, "doc123", 0),
+            Err(AuthError::InvalidResource)
+        ));
+        assert_eq!(
+            authenticator.verify_server_token(&token, 0),
+            Err(AuthError::InvalidResource)
+        );
+
+        let doc_token = authenticator.gen_doc_token(
+            "doc123",
+            Authorization::ReadOnly,
+            ExpirationTimeEpochMillis(1000),
+        );
+        assert_eq!(
+            verify_asset_token(&authenticator, &doc_token, "doc123", 0),
+            Err(AuthError::InvalidResource)
+        );
+
+        let other_key = Authenticator::gen_key().unwrap();
+        let forged = gen_asset_token(
+            &other_key,
+            "doc123",
+            "photo.png",
+            ExpirationTimeEpochMillis(1000),
+        );
+        assert_eq!(
+            verify_asset_token(&authenticator, &forged, "doc123", 0),
+            Err(AuthError::InvalidSignature)
+        );
+    }
+}
@@ -0,0 +1,84 @@
+//! Checkpoints triggered by the amount of pending changes.
+//!
+//! A document is normally persisted at most once per checkpoint interval. With triggers, a
+//! checkpoint is also due as soon as enough updates, or enough bytes of updates, are pending,
+//! so a burst of edits is not left unpersisted for the whole interval.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Amounts of pending changes at which a checkpoint is due. `None` disables a trigger.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CheckpointTriggers {
+    pub max_pending_updates: Option<u64>,
+    pub max_pending_bytes: Option<u64>,
+}
+
+/// Changes made since the last checkpoint, counted against the triggers.
+#[derive(Default)]
+pub struct PendingChanges {
+    updates: AtomicU64,
+    bytes: AtomicU64,
+    /// Trigger thresholds, with 0 meaning disabled.
+    max_updates: AtomicU64,
+    max_bytes: AtomicU64,
+}
+
+impl PendingChanges {
+    pub fn set_triggers(&self, triggers: CheckpointTriggers) {
+        self.max_updates
+            .store(triggers.max_pending_updates.unwrap_or(0), Ordering::SeqCst);
+        self.max_bytes
+            .store(triggers.max_pending_bytes.unwrap_or(0), Ordering::SeqCst);
+    }
+
+    /// Count an update. Returns whether it made a checkpoint due.
+    pub fn record(&self, bytes: usize) -> bool {
+        let was_due = self.is_due();
+        self.updates.fetch_add(1, Ordering::SeqCst);
+        self.bytes.fetch_add(bytes as u64, Ordering::SeqCst);
+        !was_due && self.is_due()
+    }
+
+    /// Whether enough changes are pending for a checkpoint to be due.
+    pub fn is_due(&self) -> bool {
+        let exceeds = |count: &AtomicU64, max: &AtomicU64| {
+            let max = max.load(Ordering::SeqCst);
+            max > 0 && count.load(Ordering::SeqCst) >= max
+        };
+        exceeds(&self.updates, &self.max_updates) || exceeds(&self.bytes, &self.max_bytes)
+    }
+
+    /// Start counting again, when a checkpoint starts.
+    pub fn reset(&self) {
+        self.updates.store(0, Ordering::SeqCst);
+        self.bytes.store(0, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_first_trigger_reached_makes_checkpoint_due() {
+        let pending = PendingChanges::default();
+        assert!(!pending.record(1000));
+        assert!(!pending.is_due());
+
+        pending.set_triggers(CheckpointTriggers {
+            max_pending_updates: Some(3),
+            max_pending_bytes: Some(100),
+        });
+        pending.reset();
+        assert!(!pending.record(10));
+        assert!(!pending.record(10));
+        assert!(pending.record(10));
+        // Only the update reaching a trigger reports it
+        assert!(!pending.record(10));
+        assert!(pending.is_due());
+
+        pending.reset();
+        assert!(!pending.is_due());
+        assert!(pending.record(100));
+    }
+}
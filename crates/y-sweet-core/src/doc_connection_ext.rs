@@ -0,0 +1,437 @@
+//! Server-side document connection.
+//!
+//! Extends the upstream [crate::doc_connection::DocConnection] with connections whose
+//! updates are fanned out by the caller, a read-only flag and an update validator that can
+//! deny writes of an open connection, and clearing of every awareness state a connection
+//! sent when it goes away.
+
+use crate::api_types::Authorization;
+use crate::sync::{
+    self,
+    awareness::{Awareness, Event},
+    DefaultProtocol, Message, Protocol, SyncMessage, MSG_SYNC, MSG_SYNC_UPDATE,
+};
+use crate::validate_ext::{validate_update, UpdateValidator};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, OnceLock, RwLock,
+    },
+};
+use yrs::{
+    block::ClientID,
+    encoding::write::Write,
+    updates::{
+        decoder::Decode,
+        encoder::{Encode, Encoder, EncoderV1},
+    },
+    ReadTxn, Subscription, Transact, Update,
+};
+
+#[cfg(not(feature = "sync"))]
+type Callback = Arc<dyn Fn(&[u8]) + 'static>;
+
+#[cfg(feature = "sync")]
+type Callback = Arc<dyn Fn(&[u8]) + 'static + Send + Sync>;
+
+const SYNC_STATUS_MESSAGE: u8 = 102;
+
+/// Encode a document update (as emitted by `observe_update_v1`) as a sync update message.
+pub fn encode_update_message(update: &[u8]) -> Vec<u8> {
+    // https://github.com/y-crdt/y-sync/blob/56958e83acfd1f3c09f5dd67cf23c9c72f000707/src/net/broadcast.rs#L47-L52
+    let mut encoder = EncoderV1::new();
+    encoder.write_var(MSG_SYNC);
+    encoder.write_var(MSG_SYNC_UPDATE);
+    encoder.write_buf(update);
+    encoder.to_vec()
+}
+
+/// Encode the clients changed by an awareness event as an awareness message.
+pub fn encode_awareness_message(awareness: &Awareness, e: &Event) -> Option<Vec<u8>> {
+    // https://github.com/y-crdt/y-sync/blob/56958e83acfd1f3c09f5dd67cf23c9c72f000707/src/net/broadcast.rs#L59
+    let added = e.added();
+    let updated = e.updated();
+    let removed = e.removed();
+    let mut changed = Vec::with_capacity(added.len() + updated.len() + removed.len());
+    changed.extend_from_slice(added);
+    changed.extend_from_slice(updated);
+    changed.extend_from_slice(removed);
+
+    let update = awareness.update_with_clients(changed).ok()?;
+    Some(Message::Awareness(update).encode_v1())
+}
+
+/// Clock of the latest known state of a client, as sent in awareness updates.
+fn state_clock(awareness: &Awareness, client_id: ClientID) -> Option<u32> {
+    let update = awareness.update_with_clients([client_id]).ok()?;
+    update.clients.get(&client_id).map(|entry| entry.clock)
+}
+
+pub struct DocConnection {
+    awareness: Arc<RwLock<Awareness>>,
+    #[allow(unused)] // acts as RAII guard
+    doc_subscription: Option<Subscription>,
+    #[allow(unused)] // acts as RAII guard
+    awareness_subscription: Option<Subscription>,
+    authorization: Authorization,
+    /// When set, writes are denied whatever the authorization.
+    read_only: Option<Arc<AtomicBool>>,
+    /// Validates updates before they are applied, with the ID of the document.
+    update_validator: Option<(String, Arc<dyn UpdateValidator>)>,
+    callback: Callback,
+    closed: Arc<OnceLock<()>>,
+
+    /// Awareness clients whose state was sent over this connection, with the clock of
+    /// their latest state. Used to clear those states when the connection goes away,
+    /// however it was closed.
+    client_clocks: Mutex<HashMap<ClientID, u32>>,
+}
+
+impl DocConnection {
+    #[cfg(not(feature = "sync"))]
+    pub fn new<F>(
+        awareness: Arc<RwLock<Awareness>>,
+        authorization: Authorization,
+        callback: F,
+    ) -> Self
+    where
+        F: Fn(&[u8]) + 'static,
+    {
+        Self::new_inner(awareness, authorization, Arc::new(callback), true)
+    }
+
+    #[cfg(feature = "sync")]
+    pub fn new<F>(
+        awareness: Arc<RwLock<Awareness>>,
+        authorization: Authorization,
+        callback: F,
+    ) -> Self
+    where
+        F: Fn(&[u8]) + 'static + Send + Sync,
+    {
+        Self::new_inner(awareness, authorization, Arc::new(callback), true)
+    }
+
+    /// Like [DocConnection::new], but document and awareness updates are not forwarded to
+    /// `callback`. The caller is expected to fan them out to the connection itself, e.g. from
+    /// a channel shared by all connections to the document.
+    #[cfg(feature = "sync")]
+    pub fn new_without_updates<F>(
+        awareness: Arc<RwLock<Awareness>>,
+        authorization: Authorization,
+        callback: F,
+    ) -> Self
+    where
+        F: Fn(&[u8]) + 'static + Send + Sync,
+    {
+        Self::new_inner(awareness, authorization, Arc::new(callback), false)
+    }
+
+    pub fn new_inner(
+        awareness: Arc<RwLock<Awareness>>,
+        authorization: Authorization,
+        callback: Callback,
+        forward_updates: bool,
+    ) -> Self {
+        let closed = Arc::new(OnceLock::new());
+
+        let (doc_subscription, awareness_subscription) = {
+            let mut awareness = awareness.write().unwrap();
+
+            // Initial handshake is based on this:
+            // https://github.com/y-crdt/y-sync/blob/56958e83acfd1f3c09f5dd67cf23c9c72f000707/src/sync.rs#L45-L54
+
+            {
+                // Send a server-side state vector, so that the client can send
+                // updates that happened offline.
+                let sv = awareness.doc().transact().state_vector();
+                let sync_step_1 = Message::Sync(SyncMessage::SyncStep1(sv)).encode_v1();
+                callback(&sync_step_1);
+            }
+
+            {
+                // Send the initial awareness state.
+                let update = awareness.update().unwrap();
+                let awareness = Message::Awareness(update).encode_v1();
+                callback(&awareness);
+            }
+
+            if !forward_updates {
+                (None, None)
+            } else {
+                let doc_subscription = {
+                    let doc = awareness.doc();
+                    let callback = callback.clone();
+                    let closed = closed.clone();
+                    doc.observe_update_v1(move |_, event| {
+                        if closed.get().is_some() {
+                            return;
+                        }
+                        let msg = encode_update_message(&event.update);
+                        callback(&msg);
+                    })
+                    .unwrap()
+                };
+
+                let callback = callback.clone();
+                let closed = closed.clone();
+                let awareness_subscription = awareness.on_update(move |awareness, e| {
+                    if closed.get().is_some() {
+                        return;
+                    }
+
+                    if let Some(msg) = encode_awareness_message(awareness, e) {
+                        callback(&msg);
+                    }
+                });
+
+                (Some(doc_subscription), Some(awareness_subscription))
+            }
+        };
+
+        Self {
+            awareness,
+            doc_subscription,
+            awareness_subscription,
+            authorization,
+            read_only: None,
+            update_validator: None,
+            callback,
+            client_clocks: Mutex::default(),
+            closed,
+        }
+    }
+
+    /// Deny writes over this connection for as long as `read_only` is set, so that a
+    /// document can be made read-only while connections are open.
+    pub fn with_read_only_flag(mut self, read_only: Arc<AtomicBool>) -> Self {
+        self.read_only = Some(read_only);
+        self
+    }
+
+    /// Deny the updates of this connection that `validator` rejects, as updates to
+    /// `doc_id`.
+    pub fn with_update_validator(
+        mut self,
+        doc_id: &str,
+        validator: Arc<dyn UpdateValidator>,
+    ) -> Self {
+        self.update_validator = Some((doc_id.to_string(), validator));
+        self
+    }
+
+    /// Apply an update from the client, unless the update validator rejects it. The
+    /// awareness stays locked in between, so the update is validated against the document
+    /// it is applied to.
+    fn apply_update<P: Protocol>(
+        &self,
+        protocol: &P,
+        update: &[u8],
+        step2: bool,
+    ) -> Result<Option<Message>, sync::Error> {
+        let mut awareness = self.awareness.write().unwrap();
+        if let Some((doc_id, validator)) = &self.update_validator {
+            validate_update(validator.as_ref(), doc_id, awareness.doc(), update)
+                .map_err(|e| sync::Error::Other(Box::new(e)))?;
+        }
+        let update = Update::decode_v1(update)?;
+        if step2 {
+            protocol.handle_sync_step2(&mut awareness, update)
+        } else {
+            protocol.handle_update(&mut awareness, update)
+        }
+    }
+
+    fn write_denied_reason(&self) -> Option<&'static str> {
+        if !matches!(self.authorization, Authorization::Full) {
+            Some("Token does not have write access")
+        } else if self
+            .read_only
+            .as_ref()
+            .is_some_and(|read_only| read_only.load(Ordering::SeqCst))
+        {
+            Some("Document is read-only")
+        } else {
+            None
+        }
+    }
+
+    pub async fn send(&self, update: &[u8]) -> Result<(), anyhow::Error> {
+        let msg = Message::decode_v1(update)?;
+        let result = self.handle_msg(&DefaultProtocol, msg)?;
+
+        if let Some(result) = result {
+            let msg = result.encode_v1();
+            (self.callback)(&msg);
+        }
+
+        Ok(())
+    }
+
+    // Adapted from:
+    // https://github.com/y-crdt/y-sync/blob/56958e83acfd1f3c09f5dd67cf23c9c72f000707/src/net/conn.rs#L184C1-L222C1
+    pub fn handle_msg<P: Protocol>(
+        &self,
+        protocol: &P,
+        msg: Message,
+    ) -> Result<Option<Message>, sync::Error> {
+        let write_denied = self.write_denied_reason();
+        let a = &self.awareness;
+        match msg {
+            Message::Sync(msg) => match msg {
+                SyncMessage::SyncStep1(sv) => {
+                    let awareness = a.read().unwrap();
+                    protocol.handle_sync_step1(&awareness, sv)
+                }
+                SyncMessage::SyncStep2(update) => {
+                    if let Some(reason) = write_denied {
+                        Err(sync::Error::PermissionDenied {
+                            reason: reason.to_string(),
+                        })
+                    } else {
+                        self.apply_update(protocol, &update, true)
+                    }
+                }
+                SyncMessage::Update(update) => {
+                    if let Some(reason) = write_denied {
+                        Err(sync::Error::PermissionDenied {
+                            reason: reason.to_string(),
+                        })
+                    } else {
+                        self.apply_update(protocol, &update, false)
+                    }
+                }
+            },
+            Message::Auth(reason) => {
+                let awareness = a.read().unwrap();
+                protocol.handle_auth(&awareness, reason)
+            }
+            Message::AwarenessQuery => {
+                let awareness = a.read().unwrap();
+                protocol.handle_awareness_query(&awareness)
+            }
+            Message::Awareness(update) => {
+                let sent: Vec<(ClientID, u32)> = update
+                    .clients
+                    .iter()
+                    .map(|(client_id, entry)| (*client_id, entry.clock))
+                    .collect();
+                let mut awareness = a.write().unwrap();
+                let result = protocol.handle_awareness_update(&mut awareness, update);
+
+                let mut client_clocks = self.client_clocks.lock().unwrap();
+                for (client_id, clock) in sent {
+                    if awareness.clients().contains_key(&client_id) {
+                        if state_clock(&awareness, client_id) == Some(clock) {
+                            client_clocks.insert(client_id, clock);
+                        }
+                    } else {
+                        // The client cleared its state itself
+                        client_clocks.remove(&client_id);
+                    }
+                }
+                result
+            }
+            Message::Custom(SYNC_STATUS_MESSAGE, data) => {
+                // Respond to the client with the same payload it sent.
+                Ok(Some(Message::Custom(SYNC_STATUS_MESSAGE, data)))
+            }
+            Message::Custom(tag, data) => {
+                let mut awareness = a.write().unwrap();
+                protocol.missing_handle(&mut awareness, tag, data)
+            }
+        }
+    }
+}
+
+impl Drop for DocConnection {
+    fn drop(&mut self) {
+        self.closed.set(()).unwrap();
+
+        // Remove the awareness states sent over this connection, unless a newer state
+        // arrived since (e.g. the client reconnected before this connection timed out).
+        // Removing a state notifies the other connections.
+        let client_clocks = std::mem::take(self.client_clocks.get_mut().unwrap());
+        let mut awareness = self.awareness.write().unwrap();
+        for (client_id, clock) in client_clocks {
+            if state_clock(&awareness, client_id) == Some(clock) {
+                awareness.remove_state(client_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use yrs::Doc;
+
+    fn awareness_message(client_id: ClientID, clock: u32) -> Vec<u8> {
+        let mut awareness = Awareness::new(Doc::with_client_id(client_id));
+        for _ in 0..clock {
+            awareness.set_local_state(r#"{"user":"a"}"#);
+        }
+        Message::Awareness(awareness.update().unwrap()).encode_v1()
+    }
+
+    #[tokio::test]
+    async fn awareness_state_is_removed_when_connection_drops() {
+        let awareness = Arc::new(RwLock::new(Awareness::new(Doc::new())));
+
+        let connection = DocConnection::new(awareness.clone(), Authorization::Full, |_| {});
+        connection.send(&awareness_message(7, 1)).await.unwrap();
+        assert!(awareness.read().unwrap().clients().contains_key(&7));
+
+        drop(connection);
+        assert!(!awareness.read().unwrap().clients().contains_key(&7));
+    }
+
+    #[tokio::test]
+    async fn newer_awareness_state_survives_stale_connection() {
+        let awareness = Arc::new(RwLock::new(Awareness::new(Doc::new())));
+
+        let stale = DocConnection::new(awareness.clone(), Authorization::Full, |_| {});
+        stale.send(&awareness_message(7, 1)).await.unwrap();
+
+        // The client reconnects before the old connection is noticed to be dead
+        let fresh = DocConnection::new(awareness.clone(), Authorization::Full, |_| {});
+        fresh.send(&awareness_message(7, 2)).await.unwrap();
+
+        drop(stale);
+        assert!(awareness.read().unwrap().clients().contains_key(&7));
+
+        drop(fresh);
+        assert!(!awareness.read().unwrap().clients().contains_key(&7));
+    }
+
+    #[tokio::test]
+    async fn read_only_flag_denies_writes_of_open_connection() {
+        use yrs::{Text, Transact};
+
+        let update = {
+            let doc = Doc::new();
+            let text = doc.get_or_insert_text("text");
+            text.insert(&mut doc.transact_mut(), 0, "hello");
+            let update = doc
+                .transact()
+                .encode_state_as_update_v1(&Default::default());
+            Message::Sync(SyncMessage::Update(update)).encode_v1()
+        };
+        let awareness = Arc::new(RwLock::new(Awareness::new(Doc::new())));
+        let read_only = Arc::new(AtomicBool::new(false));
+        let connection = DocConnection::new(awareness.clone(), Authorization::Full, |_| {})
+            .with_read_only_flag(read_only.clone());
+
+        read_only.store(true, Ordering::SeqCst);
+        assert!(connection.send(&update).await.is_err());
+
+        read_only.store(false, Ordering::SeqCst);
+        connection.send(&update).await.unwrap();
+        let awareness = awareness.read().unwrap();
+        assert_ne!(
+            awareness.doc().transact().state_vector(),
+            Default::default()
+        );
+    }
+}
@@ -0,0 +1,131 @@
+//! Runtime statistics of a loaded document: updates applied, bytes exchanged with clients,
+//! connections and the time of the last activity.
+
+use crate::update_log_ext::current_time_epoch_millis;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters of a document since it was loaded. Updated from every connection, so atomic.
+#[derive(Debug)]
+pub struct DocStats {
+    loaded_at: u64,
+    updates_applied: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    connections: AtomicU64,
+    peak_connections: AtomicU64,
+    last_activity: AtomicU64,
+}
+
+impl Default for DocStats {
+    fn default() -> Self {
+        let now = current_time_epoch_millis();
+        Self {
+            loaded_at: now,
+            updates_applied: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            connections: AtomicU64::new(0),
+            peak_connections: AtomicU64::new(0),
+            last_activity: AtomicU64::new(now),
+        }
+    }
+}
+
+impl DocStats {
+    fn touch(&self) {
+        self.last_activity
+            .fetch_max(current_time_epoch_millis(), Ordering::Relaxed);
+    }
+
+    /// Record an update applied to the document, from any source.
+    pub fn record_update(&self) {
+        self.updates_applied.fetch_add(1, Ordering::Relaxed);
+        self.touch();
+    }
+
+    /// Record `bytes` received from a client.
+    pub fn record_bytes_in(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.touch();
+    }
+
+    /// Record `bytes` sent to a client.
+    pub fn record_bytes_out(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Record a client connecting; the returned guard records it disconnecting when dropped.
+    pub fn connect(&self) -> ConnectionGuard<'_> {
+        let connections = self.connections.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak_connections
+            .fetch_max(connections, Ordering::Relaxed);
+        self.touch();
+        ConnectionGuard { stats: self }
+    }
+
+    /// The current values of the counters.
+    pub fn snapshot(&self) -> DocStatsSnapshot {
+        DocStatsSnapshot {
+            loaded_at: self.loaded_at,
+            updates_applied: self.updates_applied.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            connections: self.connections.load(Ordering::Relaxed),
+            peak_connections: self.peak_connections.load(Ordering::Relaxed),
+            last_activity: self.last_activity.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Counts a connection of a document for as long as it is held.
+pub struct ConnectionGuard<'a> {
+    stats: &'a DocStats,
+}
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.stats.connections.fetch_sub(1, Ordering::Relaxed);
+        self.stats.touch();
+    }
+}
+
+/// The statistics of a document at one point in time. Times are epoch millis.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DocStatsSnapshot {
+    pub loaded_at: u64,
+    pub updates_applied: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub connections: u64,
+    pub peak_connections: u64,
+    pub last_activity: u64,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_peak_connections_outlive_connections() {
+        let stats = DocStats::default();
+        {
+            let _first = stats.connect();
+            let _second = stats.connect();
+            stats.record_bytes_in(10);
+            stats.record_bytes_out(25);
+            stats.record_update();
+            assert_eq!(stats.snapshot().connections, 2);
+        }
+        let _third = stats.connect();
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.connections, 1);
+        assert_eq!(snapshot.peak_connections, 2);
+        assert_eq!(snapshot.updates_applied, 1);
+        assert_eq!(snapshot.bytes_in, 10);
+        assert_eq!(snapshot.bytes_out, 25);
+        assert!(snapshot.last_activity >= snapshot.loaded_at);
+    }
+}
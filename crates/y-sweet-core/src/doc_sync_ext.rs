@@ -0,0 +1,190 @@
+//! Server-side document with its key-value store.
+//!
+//! Extends the upstream [crate::doc_sync::DocWithSyncKv] with persistence through an update
+//! log, runtime statistics, and checkpoints that are only flushed once due.
+
+use crate::{
+    doc_connection::DOC_NAME,
+    doc_stats_ext::DocStats,
+    store::Store,
+    sync::awareness::Awareness,
+    sync_kv::SyncKv,
+    update_log_ext::{list_segments, read_segment, read_snapshot_meta, UpdateLog, UpdateLogConfig},
+};
+use anyhow::{anyhow, Context, Result};
+use std::sync::{Arc, RwLock};
+use yrs::{updates::decoder::Decode, ReadTxn, StateVector, Subscription, Transact, Update};
+use yrs_kvstore::DocOps;
+
+pub struct DocWithSyncKv {
+    awareness: Arc<RwLock<Awareness>>,
+    sync_kv: Arc<SyncKv>,
+    stats: Arc<DocStats>,
+    #[allow(unused)] // acts as RAII guard
+    subscription: Subscription,
+}
+
+impl DocWithSyncKv {
+    pub fn awareness(&self) -> Arc<RwLock<Awareness>> {
+        self.awareness.clone()
+    }
+
+    pub fn sync_kv(&self) -> Arc<SyncKv> {
+        self.sync_kv.clone()
+    }
+
+    /// Statistics of the document since it was loaded.
+    pub fn stats(&self) -> Arc<DocStats> {
+        self.stats.clone()
+    }
+
+    pub async fn new<F>(
+        key: &str,
+        store: Option<Arc<Box<dyn Store>>>,
+        dirty_callback: F,
+        skip_gc: bool,
+    ) -> Result<Self>
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        Self::new_with_update_log(key, store, dirty_callback, skip_gc, None).await
+    }
+
+    /// Like [DocWithSyncKv::new], but persists through an update log when `update_log` is
+    /// set and there is a store. The segments written since the last snapshot are replayed.
+    pub async fn new_with_update_log<F>(
+        key: &str,
+        store: Option<Arc<Box<dyn Store>>>,
+        dirty_callback: F,
+        skip_gc: bool,
+        update_log: Option<UpdateLogConfig>,
+    ) -> Result<Self>
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        let log_store = store.clone().filter(|_| update_log.is_some());
+        let sync_kv = SyncKv::new(store, key, dirty_callback)
+            .await
+            .context("Failed to create SyncKv")?;
+
+        let mut segments = Vec::new();
+        let sync_kv = match (update_log, &log_store) {
+            (Some(config), Some(store)) => {
+                let store = store.as_ref().as_ref();
+                let meta = read_snapshot_meta(store, key).await?;
+                let seqs = list_segments(store, key).await?;
+                // Compaction may have removed every segment the snapshot includes
+                let next_seq = seqs
+                    .last()
+                    .copied()
+                    .max(meta.snapshot_seq)
+                    .map_or(0, |seq| seq + 1);
+                for &seq in &seqs {
+                    if meta
+                        .snapshot_seq
+                        .is_none_or(|snapshot_seq| seq > snapshot_seq)
+                    {
+                        segments.push(read_segment(store, key, seq).await?.updates);
+                    }
+                }
+                tracing::debug!(
+                    "Replaying {} update log segments for {}",
+                    segments.len(),
+                    key
+                );
+                let log = UpdateLog::new(
+                    key,
+                    config,
+                    next_seq,
+                    segments.len() as u32,
+                    seqs.len() as u32,
+                );
+                sync_kv.with_update_log(log)
+            }
+            _ => sync_kv,
+        };
+
+        let sync_kv = Arc::new(sync_kv);
+        let doc = yrs::Doc::with_options(yrs::Options {
+            skip_gc,
+            ..yrs::Options::default()
+        });
+
+        {
+            let mut txn = doc.transact_mut();
+            tracing::debug!("Attempting to load existing document data for {}", key);
+
+            match sync_kv.load_doc(DOC_NAME, &mut txn) {
+                Ok(result) => {
+                    tracing::debug!("Successfully loaded document data, result: {:?}", result);
+                }
+                Err(e) => {
+                    tracing::error!("Failed to load document data: {:?}", e);
+                    return Err(anyhow!("Failed to load doc: {:?}", e));
+                }
+            }
+
+            for update in segments.iter().flatten() {
+                let decoded = Update::decode_v1(update)
+                    .map_err(|_| anyhow!("Failed to decode update log segment"))?;
+                txn.apply_update(decoded);
+                // Keep the snapshot in line with the document
+                sync_kv
+                    .push_update(DOC_NAME, update)
+                    .map_err(|e| anyhow!("Failed to replay update: {:?}", e))?;
+            }
+            if !segments.is_empty() {
+                sync_kv
+                    .flush_doc_with(DOC_NAME, Default::default())
+                    .map_err(|e| anyhow!("Failed to replay update: {:?}", e))?;
+            }
+        }
+
+        let stats = Arc::new(DocStats::default());
+        let subscription = {
+            let sync_kv = sync_kv.clone();
+            let stats = stats.clone();
+            doc.observe_update_v1(move |_, event| {
+                stats.record_update();
+                sync_kv.record_update(&event.update);
+                sync_kv.push_update(DOC_NAME, &event.update).unwrap();
+                if sync_kv.flush_due(event.update.len()) {
+                    sync_kv
+                        .flush_doc_with(DOC_NAME, Default::default())
+                        .unwrap();
+                }
+            })
+            .map_err(|_| anyhow!("Failed to subscribe to updates"))?
+        };
+
+        let awareness = Arc::new(RwLock::new(Awareness::new(doc)));
+        Ok(Self {
+            awareness,
+            sync_kv,
+            stats,
+            subscription,
+        })
+    }
+
+    pub fn as_update(&self) -> Vec<u8> {
+        let awareness_guard = self.awareness.read().unwrap();
+        let doc = &awareness_guard.doc;
+
+        let txn = doc.transact();
+
+        txn.encode_state_as_update_v1(&StateVector::default())
+    }
+
+    pub fn apply_update(&self, update: &[u8]) -> Result<()> {
+        let awareness_guard = self.awareness.write().unwrap();
+        let doc = &awareness_guard.doc;
+
+        let update: Update =
+            Update::decode_v1(update).map_err(|_| anyhow!("Failed to decode update"))?;
+
+        let mut txn = doc.transact_mut();
+        txn.apply_update(update);
+
+        Ok(())
+    }
+}
@@ -1,8 +1,20 @@
 pub mod api_types;
 pub mod api_types_ext;
+pub mod asset_event_ext;
 pub mod auth;
+pub mod auth_ext;
+pub mod checkpoint_ext;
 pub mod doc_connection;
+pub mod doc_connection_ext;
+pub mod doc_stats_ext;
 pub mod doc_sync;
+pub mod doc_sync_ext;
+pub mod protocol_error_ext;
+pub mod shard_ext;
+pub mod snapshot_ext;
 pub mod store;
+pub mod subdoc_ext;
 pub mod sync;
 pub mod sync_kv;
+pub mod update_log_ext;
+pub mod validate_ext;
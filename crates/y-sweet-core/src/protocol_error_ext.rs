@@ -0,0 +1,143 @@
+//! Error replies for the WebSocket protocol.
+//!
+//! When the server cannot handle a message, it answers with a custom message instead of
+//! dropping it silently, so that clients can tell why their message had no effect:
+//!
+//! ```text
+//! [MSG_ERROR : varUint, varBuf(payload)]
+//! payload = [code : varUint, reason : varString]
+//! ```
+//!
+//! The connection stays open after an error reply. Violations of connection limits (message
+//! size, message rate) close the connection instead, with WebSocket close codes 1009 and 1008.
+
+use crate::sync::{self, Message};
+use crate::validate_ext::UpdateRejected;
+use yrs::{
+    encoding::{
+        read::{Cursor, Read},
+        write::Write,
+    },
+    updates::{
+        decoder::DecoderV1,
+        encoder::{Encode, Encoder, EncoderV1},
+    },
+};
+
+/// Tag id of the error reply, sent as [Message::Custom].
+pub const MSG_ERROR: u8 = 104;
+
+/// Kind of failure reported in an error reply.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProtocolErrorCode {
+    /// The token does not allow the operation, e.g. a write with a read-only token.
+    PermissionDenied = 1,
+    /// The message could not be decoded or is not supported.
+    InvalidMessage = 2,
+    /// The server failed to handle a valid message.
+    Internal = 3,
+    /// An update was denied by the update validator of the server.
+    UpdateRejected = 4,
+}
+
+impl ProtocolErrorCode {
+    /// Classify an error returned while handling a client message.
+    pub fn for_error(error: &anyhow::Error) -> Self {
+        if let Some(error) = error.downcast_ref::<sync::Error>() {
+            match error {
+                sync::Error::PermissionDenied { .. } => ProtocolErrorCode::PermissionDenied,
+                sync::Error::EncodingError(_)
+                | sync::Error::AwarenessEncoding(_)
+                | sync::Error::Unsupported(_) => ProtocolErrorCode::InvalidMessage,
+                sync::Error::Other(e) if e.is::<UpdateRejected>() => {
+                    ProtocolErrorCode::UpdateRejected
+                }
+                sync::Error::Other(_) => ProtocolErrorCode::Internal,
+            }
+        } else if error.is::<yrs::encoding::read::Error>() || error.is::<InvalidMessage>() {
+            ProtocolErrorCode::InvalidMessage
+        } else {
+            ProtocolErrorCode::Internal
+        }
+    }
+
+    fn from_u32(code: u32) -> Option<Self> {
+        match code {
+            1 => Some(ProtocolErrorCode::PermissionDenied),
+            2 => Some(ProtocolErrorCode::InvalidMessage),
+            3 => Some(ProtocolErrorCode::Internal),
+            4 => Some(ProtocolErrorCode::UpdateRejected),
+            _ => None,
+        }
+    }
+}
+
+/// A client message that is well-formed for y-sync but not acceptable to this server.
+#[derive(thiserror::Error, Debug)]
+#[error("invalid message: {0}")]
+pub struct InvalidMessage(pub String);
+
+/// Build the error reply to a message whose handling failed with `error`.
+/// Details of internal errors are not disclosed to the client.
+pub fn error_reply(error: &anyhow::Error) -> Vec<u8> {
+    let code = ProtocolErrorCode::for_error(error);
+    let reason = match code {
+        ProtocolErrorCode::Internal => "internal error".to_string(),
+        _ => error.to_string(),
+    };
+    encode_error_message(code, &reason)
+}
+
+pub fn encode_error_message(code: ProtocolErrorCode, reason: &str) -> Vec<u8> {
+    let mut encoder = EncoderV1::new();
+    encoder.write_var(code as u32);
+    encoder.write_string(reason);
+    Message::Custom(MSG_ERROR, encoder.to_vec()).encode_v1()
+}
+
+/// Decode the payload of an error reply into its code and reason.
+pub fn decode_error_payload(
+    payload: &[u8],
+) -> Result<(Option<ProtocolErrorCode>, String), yrs::encoding::read::Error> {
+    let mut decoder = DecoderV1::new(Cursor::new(payload));
+    let code: u32 = decoder.read_var()?;
+    let reason = decoder.read_string()?.to_string();
+    Ok((ProtocolErrorCode::from_u32(code), reason))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use yrs::updates::decoder::Decode;
+
+    fn decode(data: &[u8]) -> (Option<ProtocolErrorCode>, String) {
+        let Message::Custom(MSG_ERROR, payload) = Message::decode_v1(data).unwrap() else {
+            panic!("expected an error reply");
+        };
+        decode_error_payload(&payload).unwrap()
+    }
+
+    #[test]
+    fn permission_denied_reply() {
+        let error = anyhow::Error::from(sync::Error::PermissionDenied {
+            reason: "Token does not have write access".to_string(),
+        });
+        let (code, reason) = decode(&error_reply(&error));
+        assert_eq!(code, Some(ProtocolErrorCode::PermissionDenied));
+        assert!(reason.contains("write access"));
+    }
+
+    #[test]
+    fn invalid_and_internal_replies() {
+        let error = anyhow::Error::from(InvalidMessage("bad guid".to_string()));
+        assert_eq!(
+            decode(&error_reply(&error)).0,
+            Some(ProtocolErrorCode::InvalidMessage)
+        );
+
+        let error = anyhow::anyhow!("store unavailable at 10.0.0.1");
+        let (code, reason) = decode(&error_reply(&error));
+        assert_eq!(code, Some(ProtocolErrorCode::Internal));
+        assert_eq!(reason, "internal error");
+    }
+}
@@ -0,0 +1,218 @@
+//! Sharded snapshots of large documents.
+//!
+//! By default, the snapshot of a document is one object holding every entry of its
+//! `SyncKv`, rewritten in full on every checkpoint. With sharding, the entries are split by
+//! key range into shards (`{doc_id}/shards/{hash}`), and the snapshot object
+//! (`{doc_id}/data.ysweet`) holds a manifest listing them instead. Shards are named after
+//! the hash of their content, so a checkpoint only writes the shards whose content changed,
+//! then the manifest, then removes the shards the manifest no longer references. A write
+//! interrupted before the manifest leaves the previous manifest and its shards intact.
+//!
+//! Shards keep the key ranges of the previous manifest, so that changes to one range do not
+//! move the entries of the others, and a range that grows past twice the target size is
+//! split. A single entry is never split: updates are kept as separate entries and only
+//! folded into the document state once a shard's worth of them is pending (see
+//! `SyncKv::flush_due`).
+
+use crate::{
+    snapshot_ext::{decode_snapshot, encode_snapshot},
+    store::Store,
+};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+/// Marks a snapshot object holding a manifest rather than entries.
+const MANIFEST_MAGIC: &[u8; 4] = b"YSWM";
+
+pub const SHARDS_DIR: &str = "shards";
+
+/// The shards of a snapshot, in key order.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct ShardManifest {
+    pub shards: Vec<ShardRef>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ShardRef {
+    /// First key of the range of the shard. The range ends where the next shard starts.
+    pub start: Vec<u8>,
+    /// Hex SHA-256 hash of the stored shard, which names it.
+    pub hash: String,
+    /// Size of the stored shard, in bytes.
+    pub bytes: usize,
+}
+
+/// A shard encoded for the store.
+pub struct Shard {
+    pub start: Vec<u8>,
+    pub hash: String,
+    pub value: Vec<u8>,
+}
+
+/// Relative to the document, so that shards keep working when its objects are copied.
+pub fn shard_key(doc_id: &str, hash: &str) -> String {
+    format!("{}/{}/{}", doc_id, SHARDS_DIR, hash)
+}
+
+pub fn encode_manifest(manifest: &ShardManifest) -> Result<Vec<u8>> {
+    let mut value = MANIFEST_MAGIC.to_vec();
+    value.extend(encode_snapshot(&bincode::serialize(manifest)?));
+    Ok(value)
+}
+
+/// The manifest held by a snapshot object, or `None` if the snapshot holds entries.
+pub fn decode_manifest(snapshot: &[u8]) -> Option<Result<ShardManifest>> {
+    let encoded = snapshot.strip_prefix(MANIFEST_MAGIC)?;
+    Some(
+        decode_snapshot(encoded)
+            .map_err(anyhow::Error::from)
+            .and_then(|payload| {
+                bincode::deserialize(payload).context("The shard manifest cannot be decoded")
+            }),
+    )
+}
+
+/// Read the entries of every shard of `manifest`, verifying their checksums.
+pub async fn read_shards(
+    store: &dyn Store,
+    doc_id: &str,
+    manifest: &ShardManifest,
+) -> Result<BTreeMap<Vec<u8>, Vec<u8>>> {
+    let mut data = BTreeMap::new();
+    for shard in &manifest.shards {
+        let key = shard_key(doc_id, &shard.hash);
+        let value = store
+            .get(&key)
+            .await?
+            .with_context(|| format!("Shard {} does not exist", key))?;
+        let payload = decode_snapshot(&value).with_context(|| format!("Shard {}", key))?;
+        let entries: BTreeMap<Vec<u8>, Vec<u8>> = bincode::deserialize(payload)
+            .with_context(|| format!("Shard {} cannot be decoded", key))?;
+        data.extend(entries);
+    }
+    Ok(data)
+}
+
+/// Split `data` into shards of about `target_bytes`, keeping the ranges of `previous`.
+pub fn split_shards(
+    data: &BTreeMap<Vec<u8>, Vec<u8>>,
+    previous: Option<&ShardManifest>,
+    target_bytes: usize,
+) -> Result<Vec<Shard>> {
+    let starts: Vec<&[u8]> = previous
+        .map(|manifest| manifest.shards.iter().map(|s| s.start.as_slice()).collect())
+        .unwrap_or_default();
+
+    // Entries grouped by the ranges of the previous manifest
+    let mut ranges: Vec<Vec<(&Vec<u8>, &Vec<u8>)>> = vec![Vec::new()];
+    let mut range = 0;
+    for entry in data {
+        while range + 1 < starts.len() && entry.0.as_slice() >= starts[range + 1] {
+            range += 1;
+            ranges.push(Vec::new());
+        }
+        ranges.last_mut().unwrap().push(entry);
+    }
+
+    let mut groups: Vec<Vec<(&Vec<u8>, &Vec<u8>)>> = Vec::new();
+    for entries in ranges.into_iter().filter(|entries| !entries.is_empty()) {
+        let bytes: usize = entries.iter().map(|(k, v)| k.len() + v.len()).sum();
+        if bytes <= 2 * target_bytes {
+            groups.push(entries);
+            continue;
+        }
+        let mut group = Vec::new();
+        let mut group_bytes = 0;
+        for (key, value) in entries {
+            group_bytes += key.len() + value.len();
+            group.push((key, value));
+            if group_bytes >= target_bytes {
+                groups.push(std::mem::take(&mut group));
+                group_bytes = 0;
+            }
+        }
+        if !group.is_empty() {
+            groups.push(group);
+        }
+    }
+
+    let mut shards = Vec::with_capacity(groups.len());
+    for (i, group) in groups.into_iter().enumerate() {
+        // The first shard covers every key before the second
+        let start = if i == 0 {
+            Vec::new()
+        } else {
+            group[0].0.clone()
+        };
+        let entries: BTreeMap<&Vec<u8>, &Vec<u8>> = group.into_iter().collect();
+        let value = encode_snapshot(&bincode::serialize(&entries)?);
+        shards.push(Shard {
+            start,
+            hash: data_encoding::HEXLOWER.encode(&Sha256::digest(&value)),
+            value,
+        });
+    }
+    Ok(shards)
+}
+
+/// Remove every shard of a document. Returns how many were removed.
+pub async fn remove_shards(store: &dyn Store, doc_id: &str) -> Result<usize> {
+    let names = store
+        .list_objects(&format!("{}/{}/", doc_id, SHARDS_DIR))
+        .await?;
+    for name in &names {
+        store.remove(&shard_key(doc_id, name)).await?;
+    }
+    Ok(names.len())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entries(range: std::ops::Range<u32>) -> BTreeMap<Vec<u8>, Vec<u8>> {
+        range
+            .map(|i| (i.to_be_bytes().to_vec(), vec![0; 96]))
+            .collect()
+    }
+
+    fn manifest(shards: &[Shard]) -> ShardManifest {
+        ShardManifest {
+            shards: shards
+                .iter()
+                .map(|shard| ShardRef {
+                    start: shard.start.clone(),
+                    hash: shard.hash.clone(),
+                    bytes: shard.value.len(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_appending_only_changes_the_last_shard() {
+        let shards = split_shards(&entries(0..100), None, 1000).unwrap();
+        assert_eq!(shards.len(), 10);
+        assert!(shards[0].start.is_empty());
+
+        let previous = manifest(&shards);
+        let grown = split_shards(&entries(0..105), Some(&previous), 1000).unwrap();
+        assert_eq!(grown.len(), 10);
+        let unchanged = grown
+            .iter()
+            .zip(&shards)
+            .filter(|(a, b)| a.hash == b.hash)
+            .count();
+        assert_eq!(unchanged, 9);
+
+        // Past twice the target size, the last range is split
+        let split = split_shards(&entries(0..125), Some(&manifest(&grown)), 1000).unwrap();
+        assert_eq!(split.len(), 13);
+
+        let encoded = encode_manifest(&previous).unwrap();
+        assert_eq!(decode_manifest(&encoded).unwrap().unwrap(), previous);
+        assert!(decode_manifest(&shards[0].value).is_none());
+    }
+}
@@ -0,0 +1,108 @@
+//! Integrity of stored snapshots.
+//!
+//! A snapshot (`{doc_id}/data.ysweet`) is written behind a header holding a format version
+//! and a SHA-256 checksum of its content, which is verified when the snapshot is loaded, so
+//! that a corrupted snapshot is reported as such instead of as a failure to decode the
+//! document. Snapshots written before the header was introduced have none and are loaded
+//! without verification.
+//!
+//! With backups enabled, every snapshot is also written to `{doc_id}/data.ysweet.bak` once
+//! the snapshot itself is written, so at least one of the two is intact when a write is
+//! interrupted. A document whose snapshot is corrupted is loaded from its backup, if the
+//! backup is valid.
+
+use sha2::{Digest, Sha256};
+
+/// Marks a snapshot with a header. Snapshots without a header start with the number of
+/// entries they hold, which would have to exceed a billion to match.
+const MAGIC: &[u8; 4] = b"YSWT";
+
+/// Format version written in the header of new snapshots.
+pub const SNAPSHOT_FORMAT_VERSION: u8 = 1;
+
+const CHECKSUM_LEN: usize = 32;
+const HEADER_LEN: usize = MAGIC.len() + 1 + CHECKSUM_LEN;
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum SnapshotError {
+    #[error("The snapshot header is truncated")]
+    Truncated,
+    #[error("The snapshot checksum does not match its content")]
+    ChecksumMismatch,
+    #[error("Snapshot format version {0} is not supported (latest is {SNAPSHOT_FORMAT_VERSION})")]
+    UnsupportedVersion(u8),
+}
+
+pub fn snapshot_key(doc_id: &str) -> String {
+    format!("{}/data.ysweet", doc_id)
+}
+
+pub fn snapshot_backup_key(doc_id: &str) -> String {
+    format!("{}/data.ysweet.bak", doc_id)
+}
+
+/// Prefix `payload` with a header carrying the format version and its checksum.
+pub fn encode_snapshot(payload: &[u8]) -> Vec<u8> {
+    let mut snapshot = Vec::with_capacity(HEADER_LEN + payload.len());
+    snapshot.extend_from_slice(MAGIC);
+    snapshot.push(SNAPSHOT_FORMAT_VERSION);
+    snapshot.extend_from_slice(&Sha256::digest(payload));
+    snapshot.extend_from_slice(payload);
+    snapshot
+}
+
+/// The payload of a stored snapshot, after verifying its checksum. Snapshots without a
+/// header are returned as they are.
+pub fn decode_snapshot(snapshot: &[u8]) -> Result<&[u8], SnapshotError> {
+    let Some(rest) = snapshot.strip_prefix(MAGIC) else {
+        return Ok(snapshot);
+    };
+    if rest.len() < 1 + CHECKSUM_LEN {
+        return Err(SnapshotError::Truncated);
+    }
+
+    let (version, rest) = (rest[0], &rest[1..]);
+    if version != SNAPSHOT_FORMAT_VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+    let (checksum, payload) = rest.split_at(CHECKSUM_LEN);
+    if Sha256::digest(payload).as_slice() != checksum {
+        return Err(SnapshotError::ChecksumMismatch);
+    }
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_and_corruption() {
+        let payload = b"some document state".to_vec();
+        let mut snapshot = encode_snapshot(&payload);
+        assert_eq!(decode_snapshot(&snapshot), Ok(payload.as_slice()));
+
+        // Snapshots written without a header are loaded as they are
+        assert_eq!(decode_snapshot(&payload), Ok(payload.as_slice()));
+
+        let last = snapshot.len() - 1;
+        snapshot[last] ^= 1;
+        assert_eq!(
+            decode_snapshot(&snapshot),
+            Err(SnapshotError::ChecksumMismatch)
+        );
+
+        assert_eq!(
+            decode_snapshot(&snapshot[..HEADER_LEN - 1]),
+            Err(SnapshotError::Truncated)
+        );
+
+        snapshot[MAGIC.len()] = SNAPSHOT_FORMAT_VERSION + 1;
+        assert_eq!(
+            decode_snapshot(&snapshot),
+            Err(SnapshotError::UnsupportedVersion(
+                SNAPSHOT_FORMAT_VERSION + 1
+            ))
+        );
+    }
+}
@@ -1,3 +1,4 @@
+#[cfg(feature = "s3")]
 pub mod s3;
 pub mod store_ext;
 
@@ -32,8 +33,27 @@ pub trait Store: 'static {
     // When merging from upstream, these should be preserved as they don't conflict with base Store functionality.
     async fn generate_upload_presigned_url(&self, key: &str, content_type: &str) -> Result<String>;
     async fn generate_download_presigned_url(&self, key: &str) -> Result<String>;
+    /// A presigned URL downloading the object at `key`, with `content_disposition` as the
+    /// `Content-Disposition` of the response (e.g. to name the downloaded file). Stores that
+    /// cannot set it presign the plain download.
+    async fn generate_download_presigned_url_with_disposition(
+        &self,
+        key: &str,
+        _content_disposition: &str,
+    ) -> Result<String> {
+        self.generate_download_presigned_url(key).await
+    }
     async fn list_objects(&self, prefix: &str) -> Result<Vec<String>>;
     async fn copy_document(&self, source_doc_id: &str, destination_doc_id: &str) -> Result<()>;
+    async fn list_documents(&self, prefix: &str) -> Result<Vec<String>>;
+    /// When the object at `key` was last written, in epoch millis, if the store records it.
+    async fn last_modified(&self, _key: &str) -> Result<Option<u64>> {
+        Ok(None)
+    }
+    /// Size of the object at `key` in bytes, or `None` if there is no such object.
+    async fn object_size(&self, key: &str) -> Result<Option<u64>> {
+        Ok(self.get(key).await?.map(|value| value.len() as u64))
+    }
     // === Extensions (end) ===
 }
 
@@ -51,7 +71,26 @@ pub trait Store: Send + Sync {
     // When merging from upstream, these should be preserved as they don't conflict with base Store functionality.
     async fn generate_upload_presigned_url(&self, key: &str, content_type: &str) -> Result<String>;
     async fn generate_download_presigned_url(&self, key: &str) -> Result<String>;
+    /// A presigned URL downloading the object at `key`, with `content_disposition` as the
+    /// `Content-Disposition` of the response (e.g. to name the downloaded file). Stores that
+    /// cannot set it presign the plain download.
+    async fn generate_download_presigned_url_with_disposition(
+        &self,
+        key: &str,
+        _content_disposition: &str,
+    ) -> Result<String> {
+        self.generate_download_presigned_url(key).await
+    }
     async fn list_objects(&self, prefix: &str) -> Result<Vec<String>>;
     async fn copy_document(&self, source_doc_id: &str, destination_doc_id: &str) -> Result<()>;
+    async fn list_documents(&self, prefix: &str) -> Result<Vec<String>>;
+    /// When the object at `key` was last written, in epoch millis, if the store records it.
+    async fn last_modified(&self, _key: &str) -> Result<Option<u64>> {
+        Ok(None)
+    }
+    /// Size of the object at `key` in bytes, or `None` if there is no such object.
+    async fn object_size(&self, key: &str) -> Result<Option<u64>> {
+        Ok(self.get(key).await?.map(|value| value.len() as u64))
+    }
     // === Extensions (end) ===
 }
@@ -250,6 +250,60 @@ impl S3Store {
         }
     }
 
+    pub async fn last_modified(&self, key: &str) -> Result<Option<u64>> {
+        self.init().await?;
+        let k = self.prefixed_key(key);
+
+        match self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(k)
+            .send()
+            .await
+        {
+            Ok(out) => Ok(out.last_modified().map(|time| {
+                (time.secs() as u64) * 1000 + (time.subsec_nanos() / 1_000_000) as u64
+            })),
+            Err(err) => {
+                if is_not_found(&err) {
+                    Ok(None)
+                } else {
+                    Err(StoreError::ConnectionError(format!(
+                        "Failed to get the modification time of object '{}' in bucket '{}': {err}",
+                        key, self.bucket
+                    )))
+                }
+            }
+        }
+    }
+
+    pub async fn object_size(&self, key: &str) -> Result<Option<u64>> {
+        self.init().await?;
+        let k = self.prefixed_key(key);
+
+        match self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(k)
+            .send()
+            .await
+        {
+            Ok(out) => Ok(Some(out.content_length().unwrap_or_default().max(0) as u64)),
+            Err(err) => {
+                if is_not_found(&err) {
+                    Ok(None)
+                } else {
+                    Err(StoreError::ConnectionError(format!(
+                        "Failed to get the size of object '{}' in bucket '{}': {err}",
+                        key, self.bucket
+                    )))
+                }
+            }
+        }
+    }
+
     // ========== Presigned URL ==========
     pub async fn generate_upload_presigned_url(
         &self,
@@ -285,6 +339,23 @@ impl S3Store {
     }
 
     pub async fn generate_download_presigned_url(&self, key: &str) -> Result<String> {
+        self.presign_download(key, None).await
+    }
+
+    /// Presigned download URL overriding the `Content-Disposition` of the response.
+    pub async fn generate_download_presigned_url_with_disposition(
+        &self,
+        key: &str,
+        content_disposition: &str,
+    ) -> Result<String> {
+        self.presign_download(key, Some(content_disposition)).await
+    }
+
+    async fn presign_download(
+        &self,
+        key: &str,
+        content_disposition: Option<&str>,
+    ) -> Result<String> {
         self.init().await?;
         let k = self.prefixed_key(key);
 
@@ -313,6 +384,9 @@ impl S3Store {
             req = req
                 .response_cache_control(format!("public, max-age={cache_max_age_secs}, immutable"));
         }
+        if let Some(content_disposition) = content_disposition {
+            req = req.response_content_disposition(content_disposition);
+        }
 
         let req = req.presigned(presign_conf).await.map_err(|e| {
             StoreError::ConnectionError(format!(
@@ -372,6 +446,60 @@ impl S3Store {
         Ok(results)
     }
 
+    // ========== List Documents (doc ID prefix) ==========
+    pub async fn list_documents(&self, prefix: &str) -> Result<Vec<String>> {
+        self.init().await?;
+        let base = match &self.prefix {
+            Some(pref) => format!("{}/", pref.trim_end_matches('/')),
+            None => String::new(),
+        };
+        let full_prefix = format!("{}{}", base, prefix);
+
+        let mut results = Vec::new();
+        let mut cont: Option<String> = None;
+
+        loop {
+            // Use "/" as delimiter so that each document directory is returned once
+            // as a common prefix instead of listing every object below it.
+            let mut req = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.bucket)
+                .prefix(&full_prefix)
+                .delimiter("/");
+
+            if let Some(token) = &cont {
+                req = req.continuation_token(token);
+            }
+
+            let out = req.send().await.map_err(|e| {
+                StoreError::ConnectionError(format!(
+                    "Failed to list documents with prefix '{}' in bucket '{}': {e}",
+                    prefix, self.bucket
+                ))
+            })?;
+
+            for common in out.common_prefixes() {
+                if let Some(p) = common.prefix() {
+                    if let Some(rel) = p.strip_prefix(&base) {
+                        let doc_id = rel.trim_end_matches('/');
+                        if !doc_id.is_empty() {
+                            results.push(doc_id.to_string());
+                        }
+                    }
+                }
+            }
+
+            if out.is_truncated().unwrap_or(false) {
+                cont = out.next_continuation_token().map(|s| s.to_string());
+            } else {
+                break;
+            }
+        }
+
+        Ok(results)
+    }
+
     // ========== Prefix Copy (Server Side) ==========
     async fn copy_object(&self, source_key: &str, destination_key: &str) -> Result<()> {
         // copy_source format is "bucket/source_key" (SDK handles proper encoding)
@@ -457,6 +585,15 @@ impl Store for S3Store {
         S3Store::generate_download_presigned_url(self, key).await
     }
 
+    async fn generate_download_presigned_url_with_disposition(
+        &self,
+        key: &str,
+        content_disposition: &str,
+    ) -> Result<String> {
+        S3Store::generate_download_presigned_url_with_disposition(self, key, content_disposition)
+            .await
+    }
+
     async fn list_objects(&self, prefix: &str) -> Result<Vec<String>> {
         S3Store::list_objects(self, prefix).await
     }
@@ -464,6 +601,18 @@ impl Store for S3Store {
     async fn copy_document(&self, source_doc_id: &str, destination_doc_id: &str) -> Result<()> {
         S3Store::copy_document(self, source_doc_id, destination_doc_id).await
     }
+
+    async fn list_documents(&self, prefix: &str) -> Result<Vec<String>> {
+        S3Store::list_documents(self, prefix).await
+    }
+
+    async fn last_modified(&self, key: &str) -> Result<Option<u64>> {
+        S3Store::last_modified(self, key).await
+    }
+
+    async fn object_size(&self, key: &str) -> Result<Option<u64>> {
+        S3Store::object_size(self, key).await
+    }
 }
 
 #[cfg(test)]
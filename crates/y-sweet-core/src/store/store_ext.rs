@@ -19,6 +19,9 @@ pub trait StoreExt: Store {
 
     /// ドキュメントを別のドキュメントIDにコピーします
     async fn copy_document(&self, source_doc_id: &str, destination_doc_id: &str) -> Result<()>;
+
+    /// 指定されたプレフィックスで始まるドキュメントIDのリストを取得します
+    async fn list_documents(&self, prefix: &str) -> Result<Vec<String>>;
 }
 
 #[cfg(not(target_arch = "wasm32"))]
@@ -35,4 +38,7 @@ pub trait StoreExt: Store + Send + Sync {
 
     /// ドキュメントを別のドキュメントIDにコピーします
     async fn copy_document(&self, source_doc_id: &str, destination_doc_id: &str) -> Result<()>;
+
+    /// 指定されたプレフィックスで始まるドキュメントIDのリストを取得します
+    async fn list_documents(&self, prefix: &str) -> Result<Vec<String>>;
 }
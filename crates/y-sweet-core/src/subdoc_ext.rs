@@ -0,0 +1,101 @@
+//! Yjs subdocument support for the WebSocket protocol.
+//!
+//! A subdocument is synced over its parent's connection by wrapping ordinary y-sync
+//! messages in a custom message:
+//!
+//! ```text
+//! [MSG_SUBDOC : varUint, varBuf(payload)]
+//! payload = [guid : varString, varBuf(y-sync message)]
+//! ```
+//!
+//! Messages in both directions use this envelope. The server loads a subdocument lazily
+//! when the first message for its guid arrives and persists it under its own key
+//! (see [subdoc_key]), next to the parent document's data.
+
+use crate::sync::Message;
+use yrs::{
+    encoding::{
+        read::{Cursor, Read},
+        write::Write,
+    },
+    updates::{
+        decoder::DecoderV1,
+        encoder::{Encode, Encoder, EncoderV1},
+    },
+};
+
+/// Tag id of the subdocument envelope, sent as [Message::Custom].
+pub const MSG_SUBDOC: u8 = 103;
+
+/// Directory below a document's key holding its subdocuments.
+pub const SUBDOCS_DIR: &str = "subdocs";
+
+/// Storage key of a subdocument: `{parent_doc_id}/subdocs/{guid}`.
+/// Because it contains slashes it never collides with a valid document ID.
+pub fn subdoc_key(parent_doc_id: &str, guid: &str) -> String {
+    format!("{}/{}/{}", parent_doc_id, SUBDOCS_DIR, guid)
+}
+
+/// Prefix shared by the storage keys of all subdocuments of a document.
+pub fn subdoc_prefix(parent_doc_id: &str) -> String {
+    format!("{}/{}/", parent_doc_id, SUBDOCS_DIR)
+}
+
+/// Whether a key (as used in the server's document map) belongs to a subdocument.
+pub fn is_subdoc_key(key: &str) -> bool {
+    key.contains(&format!("/{}/", SUBDOCS_DIR))
+}
+
+/// Whether a raw WebSocket message is a subdocument envelope.
+pub fn is_subdoc_message(data: &[u8]) -> bool {
+    // Tags below 128 are encoded as a single byte.
+    data.first() == Some(&MSG_SUBDOC)
+}
+
+/// Wrap an encoded y-sync message for the subdocument `guid`.
+pub fn encode_subdoc_message(guid: &str, message: &[u8]) -> Vec<u8> {
+    let mut encoder = EncoderV1::new();
+    encoder.write_string(guid);
+    encoder.write_buf(message);
+    Message::Custom(MSG_SUBDOC, encoder.to_vec()).encode_v1()
+}
+
+/// Unwrap the payload of a subdocument envelope into its guid and encoded y-sync message.
+pub fn decode_subdoc_payload(
+    payload: &[u8],
+) -> Result<(String, Vec<u8>), yrs::encoding::read::Error> {
+    let mut decoder = DecoderV1::new(Cursor::new(payload));
+    let guid = decoder.read_string()?.to_string();
+    let message = decoder.read_buf()?.to_vec();
+    Ok((guid, message))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sync::SyncMessage;
+    use yrs::{updates::decoder::Decode, StateVector};
+
+    #[test]
+    fn subdoc_envelope_round_trip() {
+        let inner = Message::Sync(SyncMessage::SyncStep1(StateVector::default())).encode_v1();
+        let data = encode_subdoc_message("6f1c5b7e-page-1", &inner);
+        assert!(is_subdoc_message(&data));
+        assert!(!is_subdoc_message(&inner));
+
+        let Message::Custom(MSG_SUBDOC, payload) = Message::decode_v1(&data).unwrap() else {
+            panic!("expected a subdocument envelope");
+        };
+        let (guid, message) = decode_subdoc_payload(&payload).unwrap();
+        assert_eq!(guid, "6f1c5b7e-page-1");
+        assert_eq!(message, inner);
+    }
+
+    #[test]
+    fn subdoc_keys() {
+        assert_eq!(subdoc_key("board", "page-1"), "board/subdocs/page-1");
+        assert!(subdoc_key("board", "page-1").starts_with(&subdoc_prefix("board")));
+        assert!(is_subdoc_key("board/subdocs/page-1"));
+        assert!(!is_subdoc_key("board"));
+    }
+}
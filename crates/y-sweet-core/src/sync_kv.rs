@@ -1,11 +1,25 @@
-use crate::store::Store;
-use anyhow::{Context, Result};
+use crate::{
+    checkpoint_ext::{CheckpointTriggers, PendingChanges},
+    doc_connection::DOC_NAME,
+    shard_ext::{
+        decode_manifest, encode_manifest, read_shards, shard_key, split_shards, ShardManifest,
+        ShardRef,
+    },
+    snapshot_ext::{decode_snapshot, encode_snapshot, snapshot_backup_key, snapshot_key},
+    store::Store,
+    update_log_ext::{
+        base_snapshot_key, current_time_epoch_millis, list_segments, segment_key,
+        snapshot_meta_key, write_base_snapshot, write_snapshot_meta, BaseSnapshot, Compaction,
+        CompactionPlan, Segment, SnapshotMeta, UpdateLog,
+    },
+};
+use anyhow::{anyhow, Context, Result};
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, HashSet},
     convert::Infallible,
     ops::Bound,
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         Arc, Mutex,
     },
 };
@@ -14,10 +28,22 @@ use yrs_kvstore::{DocOps, KVEntry};
 pub struct SyncKv {
     data: Arc<Mutex<BTreeMap<Vec<u8>, Vec<u8>>>>,
     store: Option<Arc<Box<dyn Store>>>,
+    doc_id: String,
     key: String,
+    backup_key: String,
+    /// Whether each snapshot is also written to `backup_key`.
+    backup: AtomicBool,
+    /// Target size of the shards of the snapshot, or 0 to write it as one object.
+    shard_bytes: AtomicUsize,
+    /// Shards of the snapshot as last loaded or written, if it is sharded.
+    manifest: Mutex<Option<ShardManifest>>,
+    /// Bytes of updates pushed since the document state was last flushed.
+    unflushed_bytes: AtomicUsize,
     dirty: AtomicBool,
     dirty_callback: Box<dyn Fn() + Send + Sync>,
     shutdown: AtomicBool,
+    update_log: Option<UpdateLog>,
+    pending: PendingChanges,
 }
 
 impl SyncKv {
@@ -26,22 +52,32 @@ impl SyncKv {
         key: &str,
         callback: Callback,
     ) -> Result<Self> {
-        let key = format!("{}/data.ysweet", key);
+        let doc_id = key.to_string();
+        let backup_key = snapshot_backup_key(key);
+        let key = snapshot_key(key);
 
-        let data = if let Some(store) = &store {
+        // A snapshot restored from its backup is written again on the next checkpoint
+        let mut recovered = false;
+        let (data, manifest) = if let Some(store) = &store {
             if let Some(snapshot) = store.get(&key).await.context("Failed to get from store.")? {
                 tracing::debug!(size=?snapshot.len(), "Loaded snapshot for key: {}", key);
-                bincode::deserialize(&snapshot).context("Failed to deserialize.")?
+                match load_snapshot(store, &doc_id, &snapshot).await {
+                    Ok(loaded) => loaded,
+                    Err(e) => {
+                        recovered = true;
+                        recover_snapshot(store, &doc_id, &key, &backup_key, e).await?
+                    }
+                }
             } else {
                 tracing::debug!("No snapshot found for key: {}, creating new document", key);
-                BTreeMap::new()
+                (BTreeMap::new(), None)
             }
         } else {
             tracing::debug!(
                 "No store configured for key: {}, creating new document",
                 key
             );
-            BTreeMap::new()
+            (BTreeMap::new(), None)
         };
 
         let data_len = data.len();
@@ -54,13 +90,82 @@ impl SyncKv {
         Ok(Self {
             data: Arc::new(Mutex::new(data)),
             store,
+            doc_id,
             key,
-            dirty: AtomicBool::new(false),
+            backup_key,
+            backup: AtomicBool::new(false),
+            shard_bytes: AtomicUsize::new(0),
+            manifest: Mutex::new(manifest),
+            unflushed_bytes: AtomicUsize::new(0),
+            dirty: AtomicBool::new(recovered),
             dirty_callback: Box::new(callback),
             shutdown: AtomicBool::new(false),
+            update_log: None,
+            pending: PendingChanges::default(),
         })
     }
 
+    /// Persist by appending to `update_log` instead of writing the full snapshot on every
+    /// checkpoint.
+    pub fn with_update_log(self, update_log: UpdateLog) -> Self {
+        Self {
+            update_log: Some(update_log),
+            ..self
+        }
+    }
+
+    /// Persist as soon as the pending changes reach one of `triggers`, rather than only
+    /// once per checkpoint interval.
+    pub fn set_checkpoint_triggers(&self, triggers: CheckpointTriggers) {
+        self.pending.set_triggers(triggers);
+    }
+
+    /// Also write each snapshot to a backup, loaded if the snapshot turns out to be
+    /// corrupted.
+    pub fn set_snapshot_backup(&self, enabled: bool) {
+        self.backup.store(enabled, Ordering::SeqCst);
+    }
+
+    /// Split the snapshot into shards of about `shard_bytes`, so that checkpoints only write
+    /// the shards that changed, or write it as one object with `None`.
+    pub fn set_shard_size(&self, shard_bytes: Option<usize>) {
+        self.shard_bytes
+            .store(shard_bytes.unwrap_or(0), Ordering::SeqCst);
+    }
+
+    /// Record an update of `update_len` bytes pushed to the store, returning whether the
+    /// document state should be flushed. Sharded snapshots keep updates as separate entries
+    /// until a shard's worth of them is pending, so that they do not rewrite the shard
+    /// holding the document state on every checkpoint.
+    pub fn flush_due(&self, update_len: usize) -> bool {
+        let shard_bytes = self.shard_bytes.load(Ordering::SeqCst);
+        if shard_bytes == 0 {
+            return true;
+        }
+        let unflushed = self.unflushed_bytes.fetch_add(update_len, Ordering::SeqCst) + update_len;
+        if unflushed < shard_bytes {
+            return false;
+        }
+        self.unflushed_bytes.store(0, Ordering::SeqCst);
+        true
+    }
+
+    /// Whether enough changes are pending to persist before the checkpoint interval passes.
+    pub fn checkpoint_due(&self) -> bool {
+        self.pending.is_due()
+    }
+
+    /// Record a document update for the update log, if there is one, and the checkpoint
+    /// triggers. Wakes the persistence worker when a trigger is reached.
+    pub fn record_update(&self, update: &[u8]) {
+        if let Some(update_log) = &self.update_log {
+            update_log.record(update);
+        }
+        if self.pending.record(update.len()) && !self.shutdown.load(Ordering::SeqCst) {
+            (self.dirty_callback)();
+        }
+    }
+
     fn mark_dirty(&self) {
         if !self.shutdown.load(Ordering::SeqCst) {
             let was_updated = !self.dirty.swap(true, Ordering::SeqCst);
@@ -77,19 +182,306 @@ impl SyncKv {
             return Ok(());
         }
 
+        // Changes made from here on count towards the next checkpoint
+        self.pending.reset();
         if let Some(store) = &self.store {
-            let snapshot = {
+            match &self.update_log {
+                Some(update_log) => self.persist_segment(store, update_log).await?,
+                None => self.persist_snapshot(store).await?,
+            }
+        }
+        self.dirty.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    async fn persist_snapshot(&self, store: &Arc<Box<dyn Store>>) -> Result<()> {
+        let shard_bytes = self.shard_bytes.load(Ordering::SeqCst);
+        if shard_bytes > 0 {
+            return self.persist_shards(store, shard_bytes).await;
+        }
+
+        let snapshot = {
+            let data = self.data.lock().unwrap();
+            encode_snapshot(&bincode::serialize(&*data)?)
+        };
+
+        tracing::debug!(size=?snapshot.len(), "Persisting snapshot");
+        self.write_snapshot(store, snapshot).await?;
+
+        // Shards of a previously sharded snapshot are no longer referenced
+        let previous = self.manifest.lock().unwrap().take();
+        if let Some(previous) = previous {
+            self.remove_stale_shards(store, &previous, &HashSet::new())
+                .await;
+        }
+        Ok(())
+    }
+
+    /// Write the shards that changed since the last checkpoint, then the manifest listing
+    /// them, then remove the shards it no longer lists.
+    async fn persist_shards(&self, store: &Arc<Box<dyn Store>>, shard_bytes: usize) -> Result<()> {
+        let previous = self.manifest.lock().unwrap().clone();
+        let shards = {
+            let data = self.data.lock().unwrap();
+            split_shards(&data, previous.as_ref(), shard_bytes)?
+        };
+
+        let stored: HashSet<String> = previous
+            .iter()
+            .flat_map(|manifest| manifest.shards.iter().map(|shard| shard.hash.clone()))
+            .collect();
+        let mut manifest = ShardManifest::default();
+        let mut written = 0;
+        for shard in shards {
+            let bytes = shard.value.len();
+            if !stored.contains(&shard.hash) {
+                store
+                    .set(&shard_key(&self.doc_id, &shard.hash), shard.value)
+                    .await?;
+                written += 1;
+            }
+            manifest.shards.push(ShardRef {
+                start: shard.start,
+                hash: shard.hash,
+                bytes,
+            });
+        }
+
+        tracing::debug!(
+            shards = manifest.shards.len(),
+            written,
+            "Persisting sharded snapshot"
+        );
+        self.write_snapshot(store, encode_manifest(&manifest)?)
+            .await?;
+
+        let referenced: HashSet<&str> = manifest
+            .shards
+            .iter()
+            .map(|shard| shard.hash.as_str())
+            .collect();
+        if let Some(previous) = &previous {
+            self.remove_stale_shards(store, previous, &referenced).await;
+        }
+        *self.manifest.lock().unwrap() = Some(manifest);
+        Ok(())
+    }
+
+    async fn write_snapshot(&self, store: &Arc<Box<dyn Store>>, snapshot: Vec<u8>) -> Result<()> {
+        if self.backup.load(Ordering::SeqCst) {
+            // Written after the snapshot, so that one of them is intact if a write fails
+            store.set(&self.key, snapshot.clone()).await?;
+            store.set(&self.backup_key, snapshot).await?;
+        } else {
+            store.set(&self.key, snapshot).await?;
+        }
+        Ok(())
+    }
+
+    /// Remove the shards of `previous` that are not `referenced`. Shards left behind only
+    /// waste space, so failures are logged rather than failing the checkpoint.
+    async fn remove_stale_shards(
+        &self,
+        store: &Arc<Box<dyn Store>>,
+        previous: &ShardManifest,
+        referenced: &HashSet<&str>,
+    ) {
+        for shard in &previous.shards {
+            if referenced.contains(shard.hash.as_str()) {
+                continue;
+            }
+            let key = shard_key(&self.doc_id, &shard.hash);
+            if let Err(e) = store.remove(&key).await {
+                tracing::warn!(
+                    message = "Failed to remove stale snapshot shard",
+                    event = "shard_remove_failed",
+                    key = %key,
+                    error = %e
+                );
+            }
+        }
+    }
+
+    /// Fold the update log into a fresh snapshot and remove the segments it includes, after
+    /// recording the state of the document, as encoded by `encode_state`, as the base to
+    /// replay history from. Returns `None` if there is no update log.
+    pub async fn compact(
+        &self,
+        encode_state: impl FnOnce() -> Vec<u8>,
+    ) -> Result<Option<Compaction>> {
+        let (Some(store), Some(update_log)) = (&self.store, &self.update_log) else {
+            return Ok(None);
+        };
+        let Some(snapshot_seq) = update_log.last_seq() else {
+            return Ok(Some(Compaction {
+                snapshot_seq: None,
+                reclaimed_objects: 0,
+            }));
+        };
+
+        // The in-memory state includes every segment written so far
+        let base = BaseSnapshot {
+            seq: Some(snapshot_seq),
+            written_at: current_time_epoch_millis(),
+            update: encode_state(),
+        };
+        self.persist_snapshot(store).await?;
+        write_snapshot_meta(
+            store.as_ref().as_ref(),
+            update_log.doc_id(),
+            &SnapshotMeta {
+                snapshot_seq: Some(snapshot_seq),
+            },
+        )
+        .await?;
+        update_log.snapshot_written();
+        write_base_snapshot(store.as_ref().as_ref(), update_log.doc_id(), &base).await?;
+
+        let mut reclaimed_objects = 0;
+        for seq in list_segments(store.as_ref().as_ref(), update_log.doc_id()).await? {
+            if seq <= snapshot_seq {
+                store.remove(&segment_key(update_log.doc_id(), seq)).await?;
+                reclaimed_objects += 1;
+            }
+        }
+        update_log.segments_removed(reclaimed_objects as u32);
+
+        Ok(Some(Compaction {
+            snapshot_seq: Some(snapshot_seq),
+            reclaimed_objects,
+        }))
+    }
+
+    /// Objects `compact` would write and remove, without changing anything. Returns `None` if
+    /// there is no update log.
+    pub async fn plan_compaction(&self) -> Result<Option<CompactionPlan>> {
+        let (Some(store), Some(update_log)) = (&self.store, &self.update_log) else {
+            return Ok(None);
+        };
+        let Some(snapshot_seq) = update_log.last_seq() else {
+            return Ok(Some(CompactionPlan::default()));
+        };
+
+        let mut plan = CompactionPlan {
+            snapshot_seq: Some(snapshot_seq),
+            ..CompactionPlan::default()
+        };
+        self.plan_snapshot(&mut plan)?;
+        plan.written.push(snapshot_meta_key(&self.doc_id));
+        plan.written.push(base_snapshot_key(&self.doc_id));
+        for seq in list_segments(store.as_ref().as_ref(), &self.doc_id).await? {
+            if seq <= snapshot_seq {
+                plan.removed.push(segment_key(&self.doc_id, seq));
+            }
+        }
+        Ok(Some(plan))
+    }
+
+    /// Add the objects `persist_snapshot` would write and remove to `plan`.
+    fn plan_snapshot(&self, plan: &mut CompactionPlan) -> Result<()> {
+        let shard_bytes = self.shard_bytes.load(Ordering::SeqCst);
+        let previous = self.manifest.lock().unwrap().clone();
+
+        let mut referenced = HashSet::new();
+        if shard_bytes > 0 {
+            let shards = {
                 let data = self.data.lock().unwrap();
-                bincode::serialize(&*data)?
+                split_shards(&data, previous.as_ref(), shard_bytes)?
             };
+            let stored: HashSet<&str> = previous
+                .iter()
+                .flat_map(|manifest| manifest.shards.iter().map(|shard| shard.hash.as_str()))
+                .collect();
+            for shard in shards {
+                if !stored.contains(shard.hash.as_str()) {
+                    plan.written.push(shard_key(&self.doc_id, &shard.hash));
+                }
+                referenced.insert(shard.hash);
+            }
+        }
 
-            tracing::debug!(size=?snapshot.len(), "Persisting snapshot");
-            store.set(&self.key, snapshot).await?;
+        plan.written.push(self.key.clone());
+        if self.backup.load(Ordering::SeqCst) {
+            plan.written.push(self.backup_key.clone());
+        }
+        for shard in previous.iter().flat_map(|manifest| &manifest.shards) {
+            if !referenced.contains(&shard.hash) {
+                plan.removed.push(shard_key(&self.doc_id, &shard.hash));
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace the state with the document encoded by `update`, and write it as the
+    /// snapshot, whatever the persistence mode. Changes that were not persisted are lost.
+    pub async fn replace_snapshot(&self, update: &[u8]) -> Result<()> {
+        self.data.lock().unwrap().clear();
+        self.push_update(DOC_NAME, update)
+            .map_err(|e| anyhow!("Failed to replace state: {:?}", e))?;
+        // Stored as it is, without collecting the garbage of the document
+        let options = yrs::Options {
+            skip_gc: true,
+            ..Default::default()
+        };
+        self.flush_doc_with(DOC_NAME, options)
+            .map_err(|e| anyhow!("Failed to replace state: {:?}", e))?;
+
+        if let Some(store) = &self.store {
+            self.persist_snapshot(store).await?;
         }
         self.dirty.store(false, Ordering::SeqCst);
         Ok(())
     }
 
+    /// Number of update log segments a compaction would remove, or 0 without an update log.
+    pub fn stored_segments(&self) -> u32 {
+        self.update_log
+            .as_ref()
+            .map_or(0, |update_log| update_log.stored_segments())
+    }
+
+    /// Append the updates made since the last checkpoint to the log, and write the snapshot
+    /// when it is due.
+    async fn persist_segment(
+        &self,
+        store: &Arc<Box<dyn Store>>,
+        update_log: &UpdateLog,
+    ) -> Result<()> {
+        // Changes that did not come from document updates (e.g. replayed segments) are
+        // already in the log
+        let Some((seq, updates)) = update_log.take_segment() else {
+            return Ok(());
+        };
+
+        let segment = Segment {
+            written_at: current_time_epoch_millis(),
+            updates,
+        };
+        let value = bincode::serialize(&segment)?;
+        tracing::debug!(size=?value.len(), seq, "Persisting update log segment");
+        if let Err(e) = store
+            .set(&segment_key(update_log.doc_id(), seq), value)
+            .await
+        {
+            update_log.restore_segment(segment.updates);
+            return Err(e.into());
+        }
+
+        if update_log.commit_segment() {
+            self.persist_snapshot(store).await?;
+            write_snapshot_meta(
+                store.as_ref().as_ref(),
+                update_log.doc_id(),
+                &SnapshotMeta {
+                    snapshot_seq: Some(seq),
+                },
+            )
+            .await?;
+            update_log.snapshot_written();
+        }
+        Ok(())
+    }
+
     #[cfg(test)]
     fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
         let map = self.data.lock().unwrap();
@@ -111,6 +503,12 @@ impl SyncKv {
         self.data.lock().unwrap().is_empty()
     }
 
+    /// Total size of the stored keys and values, in bytes.
+    pub fn size_bytes(&self) -> usize {
+        let data = self.data.lock().unwrap();
+        data.iter().map(|(k, v)| k.len() + v.len()).sum()
+    }
+
     pub fn is_shutdown(&self) -> bool {
         self.shutdown.load(Ordering::SeqCst)
     }
@@ -122,6 +520,72 @@ impl SyncKv {
     }
 }
 
+/// Load the entries of a stored snapshot, from its shards if it holds a shard manifest,
+/// along with the manifest.
+pub async fn load_snapshot(
+    store: &Arc<Box<dyn Store>>,
+    doc_id: &str,
+    snapshot: &[u8],
+) -> Result<(BTreeMap<Vec<u8>, Vec<u8>>, Option<ShardManifest>)> {
+    match decode_manifest(snapshot) {
+        Some(manifest) => {
+            let manifest = manifest?;
+            let data = read_shards(store.as_ref().as_ref(), doc_id, &manifest)
+                .await
+                .context("The snapshot shards cannot be read")?;
+            Ok((data, Some(manifest)))
+        }
+        None => Ok((read_snapshot(snapshot)?, None)),
+    }
+}
+
+/// Decode a stored snapshot into its entries, after verifying its checksum.
+pub fn read_snapshot(snapshot: &[u8]) -> Result<BTreeMap<Vec<u8>, Vec<u8>>> {
+    let payload = decode_snapshot(snapshot)?;
+    bincode::deserialize(payload).context("The snapshot content cannot be decoded")
+}
+
+/// Load the backup of the corrupted snapshot at `key`, failing with an error explaining
+/// how to recover the document if there is no valid backup.
+async fn recover_snapshot(
+    store: &Arc<Box<dyn Store>>,
+    doc_id: &str,
+    key: &str,
+    backup_key: &str,
+    error: anyhow::Error,
+) -> Result<(BTreeMap<Vec<u8>, Vec<u8>>, Option<ShardManifest>)> {
+    tracing::error!(
+        message = "Stored snapshot is corrupted",
+        event = "snapshot_corrupted",
+        key = %key,
+        error = %error
+    );
+
+    let backup = match store.get(backup_key).await {
+        Ok(Some(backup)) => load_snapshot(store, doc_id, &backup).await,
+        Ok(None) => Err(anyhow!("There is no backup")),
+        Err(e) => Err(anyhow!("Failed to read the backup: {}", e)),
+    };
+    match backup {
+        Ok(loaded) => {
+            tracing::warn!(
+                message = "Loaded the backup of a corrupted snapshot",
+                event = "snapshot_recovered",
+                key = %key,
+                backup_key = %backup_key
+            );
+            Ok(loaded)
+        }
+        Err(backup_error) => Err(anyhow!(
+            "Snapshot {} is corrupted ({:#}) and has no valid backup ({:#}). Restore the \
+             document from its update log history or from a copy of the store, or delete it.",
+            key,
+            error,
+            backup_error
+        )),
+    }
+}
+
 impl<'d> DocOps<'d> for SyncKv {}
 
 pub struct SyncKvEntry {
@@ -299,6 +763,17 @@ mod test {
 
             Ok(())
         }
+
+        async fn list_documents(&self, prefix: &str) -> Result<Vec<String>> {
+            Ok(self
+                .data
+                .iter()
+                .filter_map(|entry| {
+                    let doc_id = entry.key().strip_suffix("/data.ysweet")?;
+                    doc_id.starts_with(prefix).then(|| doc_id.to_string())
+                })
+                .collect())
+        }
     }
 
     #[derive(Default, Clone)]
@@ -392,4 +867,82 @@ mod test {
         // Should not persist when not dirty
         assert!(store.data.is_empty());
     }
+
+    #[tokio::test]
+    async fn loads_backup_of_corrupted_snapshot() {
+        let store = MemoryStore::default();
+        let sync_kv = SyncKv::new(Some(Arc::new(Box::new(store.clone()))), "foo", || ())
+            .await
+            .unwrap();
+        sync_kv.set_snapshot_backup(true);
+        sync_kv.set(b"foo", b"bar");
+        sync_kv.persist().await.unwrap();
+        assert!(store.data.contains_key("foo/data.ysweet.bak"));
+
+        *store
+            .data
+            .get_mut("foo/data.ysweet")
+            .unwrap()
+            .last_mut()
+            .unwrap() ^= 1;
+        let recovered = SyncKv::new(Some(Arc::new(Box::new(store.clone()))), "foo", || ())
+            .await
+            .unwrap();
+        assert_eq!(recovered.get(b"foo"), Some(b"bar".to_vec()));
+        // The snapshot is written again on the next checkpoint
+        assert!(recovered.dirty.load(Ordering::SeqCst));
+
+        store.data.remove("foo/data.ysweet.bak");
+        let error = SyncKv::new(Some(Arc::new(Box::new(store.clone()))), "foo", || ())
+            .await
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("is corrupted"));
+    }
+
+    #[tokio::test]
+    async fn persists_sharded_snapshot() {
+        let store = MemoryStore::default();
+        let shard_keys = || -> HashSet<String> {
+            store
+                .data
+                .iter()
+                .filter(|entry| entry.key().starts_with("foo/shards/"))
+                .map(|entry| entry.key().clone())
+                .collect()
+        };
+        let sync_kv = SyncKv::new(Some(Arc::new(Box::new(store.clone()))), "foo", || ())
+            .await
+            .unwrap();
+        sync_kv.set_shard_size(Some(500));
+        for i in 0..20u8 {
+            sync_kv.set(&[i], &[i; 99]);
+        }
+        sync_kv.persist().await.unwrap();
+        let before = shard_keys();
+        assert_eq!(before.len(), 4);
+
+        // Only the shard holding the new entry is rewritten
+        sync_kv.set(&[20], &[20; 99]);
+        sync_kv.persist().await.unwrap();
+        let after = shard_keys();
+        assert_eq!(after.len(), 4);
+        assert_eq!(before.intersection(&after).count(), 3);
+
+        let loaded = SyncKv::new(Some(Arc::new(Box::new(store.clone()))), "foo", || ())
+            .await
+            .unwrap();
+        assert_eq!(loaded.len(), 21);
+        assert_eq!(loaded.get(&[20]), Some(vec![20; 99]));
+
+        // Writing the snapshot as one object again removes the shards
+        loaded.set_shard_size(None);
+        loaded.set(&[21], &[21; 99]);
+        loaded.persist().await.unwrap();
+        assert!(shard_keys().is_empty());
+        let loaded = SyncKv::new(Some(Arc::new(Box::new(store.clone()))), "foo", || ())
+            .await
+            .unwrap();
+        assert_eq!(loaded.len(), 22);
+    }
 }
@@ -0,0 +1,411 @@
+//! Append-only update log persistence.
+//!
+//! By default, every checkpoint writes the full snapshot of a document (`{doc_id}/data.ysweet`),
+//! which costs O(document size). In update log mode, a checkpoint instead appends the updates
+//! made since the previous one as a small segment object, `{doc_id}/updates/{seq}`. Every
+//! `snapshot_every` segments the full snapshot is written as well, and
+//! `{doc_id}/updates/snapshot.json` records the last segment it includes.
+//!
+//! A document is loaded from its snapshot plus the segments written after it. Yjs updates
+//! are idempotent, so replaying a segment the snapshot already includes (e.g. after a crash
+//! between writing the snapshot and recording it) is harmless.
+//!
+//! Older segments are kept, so a document can be reconstructed as of any segment, or any
+//! time, for point-in-time recovery. A compaction folds the segments into the snapshot and
+//! removes them, after recording the state they add up to as the base snapshot
+//! (`{doc_id}/updates/base.ysweet`), from which history is replayed from then on. Without a
+//! base, history is replayed from an empty document, so a document persisted before it used
+//! the update log can only be reconstructed correctly once it has been compacted.
+
+use crate::store::Store;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use yrs::{updates::decoder::Decode, Doc, ReadTxn, StateVector, Transact, Update};
+
+/// Directory of the update log, under the document's directory.
+pub const UPDATES_DIR: &str = "updates";
+
+/// Default number of segments written between full snapshots.
+pub const DEFAULT_SNAPSHOT_EVERY: u32 = 100;
+
+const SNAPSHOT_META: &str = "snapshot.json";
+const BASE_SNAPSHOT: &str = "base.ysweet";
+
+#[derive(Clone, Copy, Debug)]
+pub struct UpdateLogConfig {
+    /// Number of segments written between full snapshots.
+    pub snapshot_every: u32,
+}
+
+impl Default for UpdateLogConfig {
+    fn default() -> Self {
+        Self {
+            snapshot_every: DEFAULT_SNAPSHOT_EVERY,
+        }
+    }
+}
+
+/// Key of a segment. Sequence numbers are zero-padded so that keys sort in log order.
+pub fn segment_key(doc_id: &str, seq: u64) -> String {
+    format!("{}/{}/{:020}", doc_id, UPDATES_DIR, seq)
+}
+
+pub(crate) fn snapshot_meta_key(doc_id: &str) -> String {
+    format!("{}/{}/{}", doc_id, UPDATES_DIR, SNAPSHOT_META)
+}
+
+pub(crate) fn base_snapshot_key(doc_id: &str) -> String {
+    format!("{}/{}/{}", doc_id, UPDATES_DIR, BASE_SNAPSHOT)
+}
+
+pub(crate) fn current_time_epoch_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// The updates of one checkpoint, as stored.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Segment {
+    /// Epoch millis at which the segment was written.
+    pub written_at: u64,
+    /// Updates in the order they were made.
+    pub updates: Vec<Vec<u8>>,
+}
+
+/// State of a document at a point of its log, from which its history is replayed.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct BaseSnapshot {
+    /// Last segment the base includes, or `None` if it precedes every segment.
+    pub seq: Option<u64>,
+    /// Epoch millis at which the base was written.
+    pub written_at: u64,
+    /// Yjs v1 update encoding the state of the document.
+    pub update: Vec<u8>,
+}
+
+pub async fn read_base_snapshot(store: &dyn Store, doc_id: &str) -> Result<Option<BaseSnapshot>> {
+    let Some(value) = store.get(&base_snapshot_key(doc_id)).await? else {
+        return Ok(None);
+    };
+    let base = bincode::deserialize(&value).context("Failed to deserialize base snapshot")?;
+    Ok(Some(base))
+}
+
+pub async fn write_base_snapshot(
+    store: &dyn Store,
+    doc_id: &str,
+    base: &BaseSnapshot,
+) -> Result<()> {
+    store
+        .set(&base_snapshot_key(doc_id), bincode::serialize(base)?)
+        .await?;
+    Ok(())
+}
+
+/// Which part of the log the snapshot includes.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct SnapshotMeta {
+    /// Last segment included in the snapshot, if any.
+    pub snapshot_seq: Option<u64>,
+}
+
+pub async fn read_snapshot_meta(store: &dyn Store, doc_id: &str) -> Result<SnapshotMeta> {
+    let Some(value) = store.get(&snapshot_meta_key(doc_id)).await? else {
+        return Ok(SnapshotMeta::default());
+    };
+    serde_json::from_slice(&value).context("Failed to parse update log snapshot metadata")
+}
+
+pub async fn write_snapshot_meta(
+    store: &dyn Store,
+    doc_id: &str,
+    meta: &SnapshotMeta,
+) -> Result<()> {
+    store
+        .set(&snapshot_meta_key(doc_id), serde_json::to_vec(meta)?)
+        .await?;
+    Ok(())
+}
+
+/// Sequence numbers of the segments of a document, in log order.
+pub async fn list_segments(store: &dyn Store, doc_id: &str) -> Result<Vec<u64>> {
+    let names = store
+        .list_objects(&format!("{}/{}/", doc_id, UPDATES_DIR))
+        .await?;
+    let mut segments: Vec<u64> = names.iter().filter_map(|name| name.parse().ok()).collect();
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+pub async fn read_segment(store: &dyn Store, doc_id: &str, seq: u64) -> Result<Segment> {
+    let value = store
+        .get(&segment_key(doc_id, seq))
+        .await?
+        .with_context(|| format!("Segment {} of {} does not exist", seq, doc_id))?;
+    bincode::deserialize(&value).context("Failed to deserialize update log segment")
+}
+
+/// Remove every object of the update log of a document. Returns how many were removed.
+pub async fn remove_update_log(store: &dyn Store, doc_id: &str) -> Result<usize> {
+    let names = store
+        .list_objects(&format!("{}/{}/", doc_id, UPDATES_DIR))
+        .await?;
+    for name in &names {
+        store
+            .remove(&format!("{}/{}/{}", doc_id, UPDATES_DIR, name))
+            .await?;
+    }
+    Ok(names.len())
+}
+
+/// Point of the history of a document to reconstruct it as of.
+#[derive(Clone, Copy, Debug)]
+pub enum RestorePoint {
+    /// After the segment with this sequence number.
+    Seq(u64),
+    /// After the last segment written at or before this time, in epoch millis.
+    Time(u64),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum HistoryError {
+    #[error("The document has no update log history")]
+    NoHistory,
+    #[error("History before the last compaction or restore is no longer available")]
+    Compacted,
+    #[error("Segment {0} of the update log is missing")]
+    MissingSegment(u64),
+    #[error(transparent)]
+    Store(#[from] anyhow::Error),
+}
+
+/// A document reconstructed from its history.
+pub struct Reconstruction {
+    /// Last segment applied, or `None` if only the base (or nothing) was.
+    pub seq: Option<u64>,
+    /// Yjs v1 update encoding the state of the document.
+    pub update: Vec<u8>,
+}
+
+/// Reconstruct a document as of `point` from its base snapshot and the segments after it.
+pub async fn reconstruct(
+    store: &dyn Store,
+    doc_id: &str,
+    point: RestorePoint,
+) -> Result<Reconstruction, HistoryError> {
+    let base = read_base_snapshot(store, doc_id).await?;
+    let seqs = list_segments(store, doc_id).await?;
+    if base.is_none() && seqs.is_empty() {
+        return Err(HistoryError::NoHistory);
+    }
+
+    let mut seq = None;
+    let mut updates = Vec::new();
+    if let Some(base) = base {
+        let before_base = match point {
+            RestorePoint::Seq(target) => base.seq.is_some_and(|base_seq| target < base_seq),
+            RestorePoint::Time(target) => target < base.written_at,
+        };
+        if before_base {
+            return Err(HistoryError::Compacted);
+        }
+        seq = base.seq;
+        updates.push(base.update);
+    }
+
+    // Read everything first: a transaction must not be held across awaits
+    let first = seq.map_or(0, |seq| seq + 1);
+    for (expected, next) in (first..).zip(seqs.into_iter().filter(|next| *next >= first)) {
+        if matches!(point, RestorePoint::Seq(target) if next > target) {
+            break;
+        }
+        if next != expected {
+            return Err(HistoryError::MissingSegment(expected));
+        }
+        let segment = read_segment(store, doc_id, next).await?;
+        if matches!(point, RestorePoint::Time(target) if segment.written_at > target) {
+            break;
+        }
+        seq = Some(next);
+        updates.extend(segment.updates);
+    }
+
+    let doc = Doc::new();
+    {
+        let mut txn = doc.transact_mut();
+        for update in &updates {
+            let update = Update::decode_v1(update)
+                .map_err(|_| anyhow::anyhow!("Failed to decode update log segment"))?;
+            txn.apply_update(update);
+        }
+    }
+    let update = doc
+        .transact()
+        .encode_state_as_update_v1(&StateVector::default());
+    Ok(Reconstruction { seq, update })
+}
+
+/// Outcome of folding the update log of a document into a fresh snapshot.
+#[derive(Debug, PartialEq)]
+pub struct Compaction {
+    /// Last segment included in the snapshot, or `None` if nothing was logged yet.
+    pub snapshot_seq: Option<u64>,
+    /// Number of segments removed from the store.
+    pub reclaimed_objects: usize,
+}
+
+/// Objects a compaction would write and remove, as reported by a dry run.
+#[derive(Debug, Default, PartialEq)]
+pub struct CompactionPlan {
+    /// Last segment the snapshot would include, or `None` if nothing was logged yet.
+    pub snapshot_seq: Option<u64>,
+    /// Keys of the objects that would be written.
+    pub written: Vec<String>,
+    /// Keys of the objects that would be removed.
+    pub removed: Vec<String>,
+}
+
+struct LogState {
+    next_seq: u64,
+    segments_since_snapshot: u32,
+    /// Segments in the store, i.e. not removed by a compaction yet.
+    stored_segments: u32,
+    /// Updates made since the previous checkpoint, not yet written to the log.
+    pending: Vec<Vec<u8>>,
+}
+
+/// The update log of a loaded document.
+pub struct UpdateLog {
+    doc_id: String,
+    config: UpdateLogConfig,
+    state: Mutex<LogState>,
+}
+
+impl UpdateLog {
+    /// Continue a log whose next segment is `next_seq`, with `stored_segments` segments in
+    /// the store, `segments_since_snapshot` of which are not included in the snapshot yet.
+    pub fn new(
+        doc_id: &str,
+        config: UpdateLogConfig,
+        next_seq: u64,
+        segments_since_snapshot: u32,
+        stored_segments: u32,
+    ) -> Self {
+        Self {
+            doc_id: doc_id.to_string(),
+            config,
+            state: Mutex::new(LogState {
+                next_seq,
+                segments_since_snapshot,
+                stored_segments,
+                pending: Vec::new(),
+            }),
+        }
+    }
+
+    /// Number of segments in the store that a compaction would remove.
+    pub fn stored_segments(&self) -> u32 {
+        self.state.lock().unwrap().stored_segments
+    }
+
+    /// Last segment written, if any.
+    pub(crate) fn last_seq(&self) -> Option<u64> {
+        self.state.lock().unwrap().next_seq.checked_sub(1)
+    }
+
+    pub fn doc_id(&self) -> &str {
+        &self.doc_id
+    }
+
+    /// Record an update to write with the next segment.
+    pub fn record(&self, update: &[u8]) {
+        self.state.lock().unwrap().pending.push(update.to_vec());
+    }
+
+    /// Take the pending updates, and the sequence number to write them as.
+    pub(crate) fn take_segment(&self) -> Option<(u64, Vec<Vec<u8>>)> {
+        let mut state = self.state.lock().unwrap();
+        if state.pending.is_empty() {
+            return None;
+        }
+        Some((state.next_seq, std::mem::take(&mut state.pending)))
+    }
+
+    /// Put back the updates of a segment that could not be written, ahead of newer ones.
+    pub(crate) fn restore_segment(&self, mut updates: Vec<Vec<u8>>) {
+        let mut state = self.state.lock().unwrap();
+        updates.append(&mut state.pending);
+        state.pending = updates;
+    }
+
+    /// Record that a segment was written. Returns whether a snapshot is due.
+    pub(crate) fn commit_segment(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.next_seq += 1;
+        state.segments_since_snapshot += 1;
+        state.stored_segments += 1;
+        state.segments_since_snapshot >= self.config.snapshot_every
+    }
+
+    pub(crate) fn snapshot_written(&self) {
+        self.state.lock().unwrap().segments_since_snapshot = 0;
+    }
+
+    pub(crate) fn segments_removed(&self, count: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.stored_segments = state.stored_segments.saturating_sub(count);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_segment_keys_sort_in_log_order() {
+        assert!(segment_key("doc", 9) < segment_key("doc", 10));
+        assert_eq!(
+            segment_key("doc", 1)
+                .rsplit('/')
+                .next()
+                .unwrap()
+                .parse::<u64>()
+                .unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_failed_segment_is_retried_in_order() {
+        let log = UpdateLog::new("doc", UpdateLogConfig { snapshot_every: 2 }, 5, 0, 5);
+        assert!(log.take_segment().is_none());
+        assert_eq!(log.last_seq(), Some(4));
+
+        log.record(b"a");
+        let (seq, updates) = log.take_segment().unwrap();
+        assert_eq!(seq, 5);
+
+        log.record(b"b");
+        log.restore_segment(updates);
+        let (seq, updates) = log.take_segment().unwrap();
+        assert_eq!(seq, 5);
+        assert_eq!(updates, vec![b"a".to_vec(), b"b".to_vec()]);
+
+        assert!(!log.commit_segment());
+        log.record(b"c");
+        assert_eq!(log.take_segment().unwrap().0, 6);
+        assert!(log.commit_segment());
+        log.snapshot_written();
+        assert!(!log.commit_segment());
+
+        assert_eq!(log.stored_segments(), 8);
+        log.segments_removed(8);
+        assert_eq!(log.stored_segments(), 0);
+    }
+}
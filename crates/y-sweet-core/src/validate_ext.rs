@@ -0,0 +1,113 @@
+//! Validation of incoming updates.
+//!
+//! An [UpdateValidator] registered on the server sees every update a client sends, over a
+//! WebSocket connection or `POST /update`, before it is applied, and can deny it, e.g. to
+//! enforce the schema of a document, limit its size or keep forbidden content out of it.
+//! Denied updates are not applied: WebSocket clients get an error reply with the
+//! [crate::protocol_error_ext::ProtocolErrorCode::UpdateRejected] code, and HTTP clients a
+//! 422 response, both with the reason the validator gave.
+
+use anyhow::{Context, Result};
+use yrs::{updates::decoder::Decode, Doc, ReadTxn, StateVector, Transact, Update};
+
+/// Inspects updates before they are applied, denying those that must not be.
+pub trait UpdateValidator: Send + Sync {
+    /// Called before `update.update` is applied to `update.doc_id`. An error denies the
+    /// update, with its message as the reason given to the client.
+    fn validate(&self, update: &IncomingUpdate) -> Result<()>;
+}
+
+impl<F> UpdateValidator for F
+where
+    F: Fn(&IncomingUpdate) -> Result<()> + Send + Sync,
+{
+    fn validate(&self, update: &IncomingUpdate) -> Result<()> {
+        self(update)
+    }
+}
+
+/// An update a client sent, and the document it is about to be applied to.
+pub struct IncomingUpdate<'a> {
+    pub doc_id: &'a str,
+    /// The document as it is before the update.
+    pub doc: &'a Doc,
+    /// The update, encoded as v1.
+    pub update: &'a [u8],
+}
+
+impl IncomingUpdate<'_> {
+    pub fn decode(&self) -> Result<Update> {
+        Update::decode_v1(self.update).context("Failed to decode update")
+    }
+
+    /// A copy of the document with the update applied, to validate the document the update
+    /// would result in rather than the update itself. Copying the document is as costly as
+    /// the document is large.
+    pub fn preview(&self) -> Result<Doc> {
+        let state = self
+            .doc
+            .transact()
+            .encode_state_as_update_v1(&StateVector::default());
+        let preview = Doc::new();
+        {
+            let mut txn = preview.transact_mut();
+            txn.apply_update(Update::decode_v1(&state).context("Failed to copy document")?);
+            txn.apply_update(self.decode()?);
+        }
+        Ok(preview)
+    }
+}
+
+/// An update denied by an [UpdateValidator].
+#[derive(thiserror::Error, Debug)]
+#[error("update rejected: {0}")]
+pub struct UpdateRejected(pub String);
+
+/// Run `validator` on an update, turning a denial into [UpdateRejected].
+pub fn validate_update(
+    validator: &dyn UpdateValidator,
+    doc_id: &str,
+    doc: &Doc,
+    update: &[u8],
+) -> Result<(), UpdateRejected> {
+    let update = IncomingUpdate {
+        doc_id,
+        doc,
+        update,
+    };
+    validator
+        .validate(&update)
+        .map_err(|e| UpdateRejected(format!("{:#}", e)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use yrs::{GetString, Text};
+
+    #[test]
+    fn test_preview_applies_update_to_copy() {
+        let doc = Doc::new();
+        let text = doc.get_or_insert_text("text");
+        text.insert(&mut doc.transact_mut(), 0, "hello");
+
+        let other = Doc::new();
+        other
+            .get_or_insert_text("text")
+            .insert(&mut other.transact_mut(), 0, "!");
+        let update = other
+            .transact()
+            .encode_state_as_update_v1(&StateVector::default());
+
+        let max_len = |incoming: &IncomingUpdate| {
+            let preview = incoming.preview()?;
+            let text = preview.get_or_insert_text("text");
+            anyhow::ensure!(text.get_string(&preview.transact()).len() <= 5, "Too long");
+            Ok(())
+        };
+        let error = validate_update(&max_len, "doc", &doc, &update).unwrap_err();
+        assert_eq!(error.to_string(), "update rejected: Too long");
+        // The document itself is left as it was
+        assert_eq!(text.get_string(&doc.transact()), "hello");
+    }
+}
@@ -4,6 +4,8 @@ use worker::Env;
 use y_sweet_core::auth::KeyId;
 use y_sweet_core::store::s3::S3Config;
 
+use crate::presign::R2PresignConfig;
+
 const BUCKET: &str = "Y_SWEET_DATA";
 const BUCKET_KIND: &str = "BUCKET_KIND";
 const AUTH_KEY: &str = "AUTH_KEY";
@@ -14,6 +16,10 @@ const S3_REGION: &str = "AWS_REGION";
 const S3_ENDPOINT: &str = "AWS_ENDPOINT_URL_S3";
 const S3_BUCKET_PREFIX: &str = "S3_BUCKET_PREFIX";
 const S3_BUCKET_NAME: &str = "S3_BUCKET_NAME";
+const R2_ACCOUNT_ID: &str = "R2_ACCOUNT_ID";
+const R2_ACCESS_KEY_ID: &str = "R2_ACCESS_KEY_ID";
+const R2_SECRET_ACCESS_KEY: &str = "R2_SECRET_ACCESS_KEY";
+const R2_BUCKET_NAME: &str = "R2_BUCKET_NAME";
 
 // Note: unlike the native server, the worker checkpoint frequency is not configurable because
 // it directly relates to Cloudflare platform configuration. Per their docs:
@@ -55,6 +61,9 @@ pub struct Configuration {
     pub bucket: String,
     pub s3_store_config: Option<S3Config>,
     pub bucket_prefix: Option<String>,
+    /// Credentials to presign asset URLs of the native R2 bucket with.
+    #[serde(default)]
+    pub r2_presign_config: Option<R2PresignConfig>,
     pub url_prefix: Option<String>,
     pub timeout_interval: Duration,
 }
@@ -92,6 +101,17 @@ fn parse_s3_config(env: &Env) -> anyhow::Result<S3Config> {
     })
 }
 
+/// The R2 API credentials, if all of them are configured.
+fn parse_r2_presign_config(env: &Env) -> Option<R2PresignConfig> {
+    let var = |name: &str| env.var(name).ok().map(|v| v.to_string());
+    Some(R2PresignConfig {
+        account_id: var(R2_ACCOUNT_ID)?,
+        access_key_id: var(R2_ACCESS_KEY_ID)?,
+        secret_access_key: var(R2_SECRET_ACCESS_KEY)?,
+        bucket_name: var(R2_BUCKET_NAME)?,
+    })
+}
+
 impl TryFrom<&Env> for Configuration {
     type Error = anyhow::Error;
 
@@ -115,6 +135,7 @@ impl TryFrom<&Env> for Configuration {
             bucket: BUCKET.to_string(),
             s3_store_config: s3_config,
             bucket_prefix: env.var(S3_BUCKET_PREFIX).map(|s| s.to_string()).ok(),
+            r2_presign_config: parse_r2_presign_config(env),
             url_prefix: None,
             timeout_interval,
         })
@@ -100,6 +100,8 @@ impl DurableObject for YServe {
             .get_async("/doc/ws/:doc_id", websocket_connect)
             .get_async("/doc/:doc_id/as-update", as_update)
             .post_async("/doc/:doc_id/update", update_doc)
+            .post_async("/doc/:doc_id/persist", persist_doc)
+            .delete_async("/doc/:doc_id", unload_doc)
             .run(req, env)
             .await
     }
@@ -147,6 +149,23 @@ async fn update_doc(mut req: Request, ctx: RouteContext<&mut YServe>) -> Result<
     Response::ok("ok")
 }
 
+/// Persist the document if it is loaded, e.g. before it is copied from the store.
+async fn persist_doc(_req: Request, ctx: RouteContext<&mut YServe>) -> Result<Response> {
+    if let Some(DocIdPair { doc, .. }) = ctx.data.lazy_doc.as_ref() {
+        doc.sync_kv()
+            .persist()
+            .await
+            .map_err(|_| "Couldn't persist doc.")?;
+    }
+    Response::ok("ok")
+}
+
+/// Drop the loaded document, so that it is not persisted again once deleted from the store.
+async fn unload_doc(_req: Request, ctx: RouteContext<&mut YServe>) -> Result<Response> {
+    ctx.data.lazy_doc = None;
+    Response::ok("ok")
+}
+
 async fn handle_doc_create(req: Request, ctx: RouteContext<&mut YServe>) -> Result<Response> {
     let doc_id = ctx
         .param("doc_id")
@@ -33,6 +33,10 @@ pub enum Error {
     CouldNotForwardRequest(worker::Error),
     #[error("Error creating doc.")]
     ErrorCreatingDoc(String),
+    #[error("Content type {0} is not allowed. Only image and video files are supported.")]
+    ContentTypeNotAllowed(String),
+    #[error("Store error. {0}")]
+    StoreError(String),
 }
 
 impl Error {
@@ -52,6 +56,8 @@ impl Error {
             Self::CouldNotConstructRequest => 500,
             Self::CouldNotForwardRequest(_) => 500,
             Self::ErrorCreatingDoc(_) => 500,
+            Self::ContentTypeNotAllowed(_) => 400,
+            Self::StoreError(_) => 500,
         }
     }
 }
@@ -20,8 +20,10 @@ use y_sweet_core::{
 pub mod config;
 pub mod durable_object;
 pub mod error;
+pub mod presign;
 pub mod r2_store;
 pub mod server_context;
+pub mod server_ext;
 pub mod threadless;
 
 const DURABLE_OBJECT: &str = "Y_SWEET";
@@ -34,7 +36,7 @@ fn get_time_millis_since_epoch() -> u64 {
 pub fn router(
     context: ServerContext,
 ) -> std::result::Result<Router<'static, ServerContext>, Error> {
-    Ok(Router::with_data(context)
+    let router = Router::with_data(context)
         .get("/", |_, _| Response::ok("Y-Sweet!"))
         .get_async("/check_store", check_store_handler)
         .post_async("/check_store", check_store_handler)
@@ -42,7 +44,8 @@ pub fn router(
         .post_async("/doc/:doc_id/auth", auth_doc_handler)
         .get_async("/doc/:doc_id/as-update", as_update_handler)
         .post_async("/doc/:doc_id/update", update_handler)
-        .get_async("/doc/ws/:doc_id", forward_to_durable_object))
+        .get_async("/doc/ws/:doc_id", forward_to_durable_object);
+    Ok(server_ext::ext_routes(router))
 }
 
 #[cfg(feature = "fetch-event")]
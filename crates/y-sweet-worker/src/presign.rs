@@ -0,0 +1,189 @@
+//! Presigned URLs for objects of an R2 bucket.
+//!
+//! The R2 binding of a worker cannot sign URLs, so they are signed for the S3-compatible API
+//! of R2 (AWS Signature Version 4, with the `auto` region), with an API token of the account
+//! configured through the `R2_*` variables. Clients then upload and download assets directly
+//! from R2, as they do from S3 with the native server.
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+const PRESIGNED_URL_DURATION: Duration = Duration::from_secs(60 * 60); // 60 min
+const UPLOAD_PRESIGNED_URL_DURATION: Duration = Duration::from_secs(15 * 60); // 15 min
+
+// Download URLs are signed from the start of a time bucket, so repeated calls yield the same
+// (cacheable) URL, as with the S3 store of the native server.
+const PRESIGNED_URL_TIME_BUCKET: Duration = Duration::from_secs(30 * 60); // 30 min
+
+const REGION: &str = "auto";
+const SERVICE: &str = "s3";
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct R2PresignConfig {
+    pub account_id: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// Name of the bucket bound to the worker, which the S3 API addresses it by.
+    pub bucket_name: String,
+}
+
+impl R2PresignConfig {
+    fn host(&self) -> String {
+        format!("{}.r2.cloudflarestorage.com", self.account_id)
+    }
+
+    /// URL to upload an object of `content_type` to `key` with a PUT request.
+    pub fn upload_url(&self, key: &str, content_type: &str, now_secs: u64) -> String {
+        self.presign(
+            "PUT",
+            key,
+            Some(content_type),
+            &[],
+            now_secs,
+            UPLOAD_PRESIGNED_URL_DURATION,
+        )
+    }
+
+    /// URL to download the object at `key`.
+    pub fn download_url(&self, key: &str, now_secs: u64) -> String {
+        let bucket = PRESIGNED_URL_TIME_BUCKET.as_secs();
+        let start = now_secs - now_secs % bucket;
+        let cache_max_age = PRESIGNED_URL_DURATION - PRESIGNED_URL_TIME_BUCKET;
+        let cache_control = format!("public, max-age={}, immutable", cache_max_age.as_secs());
+        self.presign(
+            "GET",
+            key,
+            None,
+            &[("response-cache-control", &cache_control)],
+            start,
+            PRESIGNED_URL_DURATION,
+        )
+    }
+
+    fn presign(
+        &self,
+        method: &str,
+        key: &str,
+        content_type: Option<&str>,
+        extra_query: &[(&str, &str)],
+        start_secs: u64,
+        expires_in: Duration,
+    ) -> String {
+        let host = self.host();
+        let timestamp = amz_timestamp(start_secs);
+        let date = &timestamp[..8];
+        let scope = format!("{}/{}/{}/aws4_request", date, REGION, SERVICE);
+        let signed_headers = if content_type.is_some() {
+            "content-type;host"
+        } else {
+            "host"
+        };
+
+        let credential = format!("{}/{}", self.access_key_id, scope);
+        let expires = expires_in.as_secs().to_string();
+        let mut query: Vec<(&str, &str)> = vec![
+            ("X-Amz-Algorithm", "AWS4-HMAC-SHA256"),
+            ("X-Amz-Credential", &credential),
+            ("X-Amz-Date", &timestamp),
+            ("X-Amz-Expires", &expires),
+            ("X-Amz-SignedHeaders", signed_headers),
+        ];
+        query.extend_from_slice(extra_query);
+        query.sort();
+        let canonical_query = query
+            .iter()
+            .map(|(name, value)| format!("{}={}", uri_encode(name, true), uri_encode(value, true)))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let path = format!(
+            "/{}/{}",
+            uri_encode(&self.bucket_name, true),
+            uri_encode(key, false)
+        );
+        let mut canonical_headers = String::new();
+        if let Some(content_type) = content_type {
+            canonical_headers.push_str(&format!("content-type:{}\n", content_type.trim()));
+        }
+        canonical_headers.push_str(&format!("host:{}\n", host));
+
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\nUNSIGNED-PAYLOAD",
+            method, path, canonical_query, canonical_headers, signed_headers
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            timestamp,
+            scope,
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let key = hmac(
+            format!("AWS4{}", self.secret_access_key).as_bytes(),
+            date.as_bytes(),
+        );
+        let key = hmac(&key, REGION.as_bytes());
+        let key = hmac(&key, SERVICE.as_bytes());
+        let key = hmac(&key, b"aws4_request");
+        let signature = hex(&hmac(&key, string_to_sign.as_bytes()));
+
+        format!(
+            "https://{}{}?{}&X-Amz-Signature={}",
+            host, path, canonical_query, signature
+        )
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Percent-encodes everything but the unreserved characters (and `/`, in paths).
+fn uri_encode(value: &str, encode_slash: bool) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// `secs` since the epoch in the basic ISO 8601 format of SigV4, e.g. `20240131T235959Z`.
+fn amz_timestamp(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let time = secs % 86400;
+
+    // Civil date from days since the epoch (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
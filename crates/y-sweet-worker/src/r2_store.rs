@@ -1,9 +1,11 @@
+use crate::presign::R2PresignConfig;
 use async_trait::async_trait;
-use worker::Bucket;
+use worker::{Bucket, Date};
 use y_sweet_core::store::{Result, Store, StoreError};
 pub struct R2Store {
     bucket: Bucket,
     path_prefix: Option<String>,
+    presign: Option<R2PresignConfig>,
 }
 
 impl R2Store {
@@ -11,9 +13,45 @@ impl R2Store {
         Self {
             bucket,
             path_prefix,
+            presign: None,
         }
     }
 
+    /// Signs asset URLs with the credentials of `presign`. Without them, the store cannot
+    /// generate presigned URLs.
+    pub fn with_presign(self, presign: Option<R2PresignConfig>) -> Self {
+        Self { presign, ..self }
+    }
+
+    fn presign(&self) -> Result<&R2PresignConfig> {
+        self.presign.as_ref().ok_or_else(|| {
+            StoreError::NotAuthorized("Presigned URLs need the R2_* API credentials".into())
+        })
+    }
+
+    /// Keys of the objects whose (prefixed) key starts with `prefix`, following the cursor
+    /// of truncated listings.
+    async fn list_keys(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let mut list = self.bucket.list().prefix(prefix);
+            if let Some(cursor) = cursor.take() {
+                list = list.cursor(cursor);
+            }
+            let objects = list
+                .execute()
+                .await
+                .map_err(|e| StoreError::ConnectionError(format!("Failed to list objects {e}")))?;
+            keys.extend(objects.objects().iter().map(|object| object.key()));
+            match objects.cursor() {
+                Some(next) if objects.truncated() => cursor = Some(next),
+                _ => break,
+            }
+        }
+        Ok(keys)
+    }
+
     fn prefixed_key(&self, key: &str) -> String {
         if let Some(path_prefix) = &self.path_prefix {
             format!("{}/{}", path_prefix, key)
@@ -60,8 +98,11 @@ impl Store for R2Store {
         Ok(())
     }
 
-    async fn remove(&self, _key: &str) -> Result<()> {
-        todo!()
+    async fn remove(&self, key: &str) -> Result<()> {
+        self.bucket
+            .delete(self.prefixed_key(key))
+            .await
+            .map_err(|e| StoreError::ConnectionError(format!("Failed to delete object {e}")))
     }
 
     async fn exists(&self, key: &str) -> Result<bool> {
@@ -71,4 +112,93 @@ impl Store for R2Store {
             .map(|r| r.is_some())
             .map_err(|e| StoreError::ConnectionError(format!("Failed to head object {e}")))
     }
+
+    // === Extensions (start) ===
+    async fn generate_upload_presigned_url(&self, key: &str, content_type: &str) -> Result<String> {
+        let now_secs = Date::now().as_millis() / 1000;
+        Ok(self
+            .presign()?
+            .upload_url(&self.prefixed_key(key), content_type, now_secs))
+    }
+
+    async fn generate_download_presigned_url(&self, key: &str) -> Result<String> {
+        let now_secs = Date::now().as_millis() / 1000;
+        Ok(self
+            .presign()?
+            .download_url(&self.prefixed_key(key), now_secs))
+    }
+
+    /// Keys below `prefix`, relative to it, as with the S3 store.
+    async fn list_objects(&self, prefix: &str) -> Result<Vec<String>> {
+        let full_prefix = self.prefixed_key(prefix).trim_end_matches('/').to_string() + "/";
+        Ok(self
+            .list_keys(&full_prefix)
+            .await?
+            .iter()
+            .filter_map(|key| key.strip_prefix(&full_prefix))
+            .filter(|rel| !rel.is_empty())
+            .map(str::to_string)
+            .collect())
+    }
+
+    /// The binding has no server-side copy, so objects are copied through the worker.
+    async fn copy_document(&self, source_doc_id: &str, destination_doc_id: &str) -> Result<()> {
+        let source_doc_id = source_doc_id.trim_matches('/');
+        let destination_doc_id = destination_doc_id.trim_matches('/');
+        for rel in self.list_objects(&format!("{}/", source_doc_id)).await? {
+            let value = self
+                .get(&format!("{}/{}", source_doc_id, rel))
+                .await?
+                .ok_or_else(|| StoreError::DoesNotExist(rel.clone()))?;
+            self.set(&format!("{}/{}", destination_doc_id, rel), value)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn list_documents(&self, prefix: &str) -> Result<Vec<String>> {
+        let base = match &self.path_prefix {
+            Some(path_prefix) => format!("{}/", path_prefix.trim_end_matches('/')),
+            None => String::new(),
+        };
+        let mut doc_ids = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            // Each document directory is listed once, as a delimited prefix
+            let mut list = self
+                .bucket
+                .list()
+                .prefix(format!("{}{}", base, prefix))
+                .delimiter("/");
+            if let Some(cursor) = cursor.take() {
+                list = list.cursor(cursor);
+            }
+            let objects = list.execute().await.map_err(|e| {
+                StoreError::ConnectionError(format!("Failed to list documents {e}"))
+            })?;
+            for delimited in objects.delimited_prefixes() {
+                if let Some(rel) = delimited.strip_prefix(&base) {
+                    let doc_id = rel.trim_end_matches('/');
+                    if !doc_id.is_empty() {
+                        doc_ids.push(doc_id.to_string());
+                    }
+                }
+            }
+            match objects.cursor() {
+                Some(next) if objects.truncated() => cursor = Some(next),
+                _ => break,
+            }
+        }
+        Ok(doc_ids)
+    }
+
+    async fn last_modified(&self, key: &str) -> Result<Option<u64>> {
+        let object = self
+            .bucket
+            .head(self.prefixed_key(key))
+            .await
+            .map_err(|e| StoreError::ConnectionError(format!("Failed to head object {e}")))?;
+        Ok(object.map(|object| object.uploaded().as_millis()))
+    }
+    // === Extensions (end) ===
 }
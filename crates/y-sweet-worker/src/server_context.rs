@@ -19,7 +19,10 @@ impl ServerContext {
         let store: Box<dyn Store> = if let Some(s3) = config.s3_store_config.as_ref() {
             Box::new(S3Store::new(s3.clone()))
         } else {
-            Box::new(R2Store::new(bucket, config.bucket_prefix.clone()))
+            Box::new(
+                R2Store::new(bucket, config.bucket_prefix.clone())
+                    .with_presign(config.r2_presign_config.clone()),
+            )
         };
         #[allow(clippy::arc_with_non_send_sync)] // Arc required for compatibility with core.
         let store: Arc<Box<dyn Store>> = Arc::new(store);
@@ -0,0 +1,334 @@
+//! Custom endpoints of the worker: the asset, delete and copy endpoints of the native
+//! server, on top of the R2 (or S3-compatible) store.
+
+use crate::{
+    check_server_token,
+    error::{Error, IntoResponse},
+    forward_to_durable_object_with_doc_id, get_time_millis_since_epoch,
+    server_context::ServerContext,
+};
+use worker::{Method, Request, Response, Result, RouteContext, Router};
+use y_sweet_core::{
+    api_types::validate_doc_name,
+    api_types_ext::{
+        AssetUrl, AssetsResponse, ContentUploadRequest, ContentUploadResponse, DocCopyRequest,
+        DocCopyResponse, DocDeleteRequest, DocDeleteResponse,
+    },
+    auth::Authenticator,
+    store::StoreError,
+};
+
+pub fn ext_routes(router: Router<'static, ServerContext>) -> Router<'static, ServerContext> {
+    router
+        .post_async("/d/:doc_id/assets", upload_url_handler)
+        .get_async("/d/:doc_id/assets", assets_handler)
+        .delete_async("/d/:doc_id", delete_handler)
+        .post_async("/d/:doc_id/copy", copy_handler)
+}
+
+/// Check the doc token of a `Bearer` authorization header, as the asset endpoints of the
+/// native server do.
+fn check_doc_token(
+    req: &Request,
+    auth: Option<&Authenticator>,
+    doc_id: &str,
+) -> std::result::Result<(), Error> {
+    let Some(auth) = auth else {
+        return Ok(());
+    };
+    let auth_header = req
+        .headers()
+        .get("Authorization")
+        .map_err(|_| Error::ExpectedClientAuthHeader)?
+        .ok_or(Error::ExpectedClientAuthHeader)?;
+    let token = auth_header
+        .strip_prefix("Bearer ")
+        .ok_or(Error::BadClientAuthHeader)?;
+    auth.verify_doc_token(token, doc_id, get_time_millis_since_epoch())
+        .map_err(|_| Error::BadClientAuthHeader)?;
+    Ok(())
+}
+
+fn store_error(e: StoreError) -> Error {
+    Error::StoreError(e.to_string())
+}
+
+async fn check_doc_exists(
+    ctx: &mut RouteContext<ServerContext>,
+    doc_id: &str,
+) -> std::result::Result<(), Error> {
+    let exists = ctx
+        .data
+        .store()
+        .exists(&format!("{doc_id}/data.ysweet"))
+        .await
+        .map_err(|_| Error::UpstreamConnectionError)?;
+    if !exists {
+        return Err(Error::NoSuchDocument);
+    }
+    Ok(())
+}
+
+/// Tell the durable object of `doc_id` about a change to the document in the store.
+async fn notify_durable_object(
+    ctx: RouteContext<ServerContext>,
+    doc_id: &str,
+    path: &str,
+    method: Method,
+) -> std::result::Result<(), Error> {
+    let req = Request::new(&format!("http://ignored/doc/{doc_id}{path}"), method)
+        .map_err(|_| Error::CouldNotConstructRequest)?;
+    let response = forward_to_durable_object_with_doc_id(req, ctx, doc_id)
+        .await
+        .map_err(Error::CouldNotForwardRequest)?;
+    if response.status_code() != 200 {
+        return Err(Error::InternalError);
+    }
+    Ok(())
+}
+
+/// Only images and videos are accepted as assets.
+fn is_allowed_content_type(content_type: &str) -> bool {
+    match content_type.parse::<mime::Mime>() {
+        Ok(mime) => mime.type_() == mime::IMAGE || mime.type_() == mime::VIDEO,
+        Err(_) => false,
+    }
+}
+
+fn get_extension_from_content_type(content_type: &str) -> String {
+    let mime = content_type
+        .parse::<mime::Mime>()
+        .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+    let extension = mime_guess::get_mime_extensions(&mime)
+        .and_then(|exts| exts.first())
+        .unwrap_or(&"bin");
+    format!(".{}", extension)
+}
+
+/// Validate a client supplied SHA-256 (64 hex digits) and normalize it to lowercase.
+fn normalize_sha256(sha256: &str) -> std::result::Result<String, Error> {
+    if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(Error::BadRequest);
+    }
+    Ok(sha256.to_ascii_lowercase())
+}
+
+async fn upload_url_handler(req: Request, ctx: RouteContext<ServerContext>) -> Result<Response> {
+    upload_url(req, ctx).await.into_response()
+}
+
+async fn upload_url(
+    mut req: Request,
+    mut ctx: RouteContext<ServerContext>,
+) -> std::result::Result<ContentUploadResponse, Error> {
+    let doc_id = ctx.param("doc_id").unwrap().to_string();
+    check_doc_token(&req, ctx.data.auth()?, &doc_id)?;
+    check_doc_exists(&mut ctx, &doc_id).await?;
+
+    let body = req
+        .json::<ContentUploadRequest>()
+        .await
+        .map_err(|_| Error::BadRequest)?;
+    if !is_allowed_content_type(&body.content_type) {
+        return Err(Error::ContentTypeNotAllowed(body.content_type));
+    }
+
+    let asset_id = match &body.sha256 {
+        Some(sha256) => normalize_sha256(sha256)?,
+        None => nanoid::nanoid!(),
+    };
+    let asset_name = format!(
+        "{}{}",
+        asset_id,
+        get_extension_from_content_type(&body.content_type)
+    );
+    let key = format!("{}/assets/{}", doc_id, asset_name);
+
+    let store = ctx.data.store();
+    if body.sha256.is_some() && store.exists(&key).await.map_err(store_error)? {
+        return Ok(ContentUploadResponse {
+            upload_url: None,
+            asset_id: asset_name,
+            deduplicated: true,
+        });
+    }
+
+    let upload_url = store
+        .generate_upload_presigned_url(&key, &body.content_type)
+        .await
+        .map_err(store_error)?;
+    Ok(ContentUploadResponse {
+        upload_url: Some(upload_url),
+        asset_id: asset_name,
+        deduplicated: false,
+    })
+}
+
+async fn assets_handler(req: Request, ctx: RouteContext<ServerContext>) -> Result<Response> {
+    match assets(req, ctx).await {
+        Ok(assets) => {
+            let mut response = Response::from_json(&assets)?;
+            response
+                .headers_mut()
+                .set("Cache-Control", "private, max-age=30")?;
+            Ok(response)
+        }
+        Err(err) => Err::<AssetsResponse, _>(err).into_response(),
+    }
+}
+
+async fn assets(
+    req: Request,
+    mut ctx: RouteContext<ServerContext>,
+) -> std::result::Result<AssetsResponse, Error> {
+    let doc_id = ctx.param("doc_id").unwrap().to_string();
+    check_doc_token(&req, ctx.data.auth()?, &doc_id)?;
+    check_doc_exists(&mut ctx, &doc_id).await?;
+
+    let store = ctx.data.store();
+    let mut assets = Vec::new();
+    for filename in store
+        .list_objects(&format!("{}/assets/", doc_id))
+        .await
+        .map_err(store_error)?
+    {
+        let download_url = store
+            .generate_download_presigned_url(&format!("{}/assets/{}", doc_id, filename))
+            .await
+            .map_err(store_error)?;
+        let asset_id = match filename.rfind('.') {
+            Some(dot) if dot > 0 => filename[..dot].to_string(),
+            _ => filename,
+        };
+        assets.push(AssetUrl {
+            asset_id,
+            download_url,
+            filename: None,
+            status: None,
+        });
+    }
+    Ok(AssetsResponse { assets })
+}
+
+async fn delete_handler(req: Request, ctx: RouteContext<ServerContext>) -> Result<Response> {
+    delete(req, ctx).await.into_response()
+}
+
+/// Delete every object of the document, unloading it from its durable object first so that
+/// it is not persisted again.
+async fn delete(
+    mut req: Request,
+    mut ctx: RouteContext<ServerContext>,
+) -> std::result::Result<DocDeleteResponse, Error> {
+    check_server_token(&req, ctx.data.auth()?)?;
+    let doc_id = ctx.param("doc_id").unwrap().to_string();
+    if !validate_doc_name(&doc_id) {
+        return Err(Error::InvalidDocName);
+    }
+
+    let body = req.text().await.map_err(|_| Error::BadRequest)?;
+    let DocDeleteRequest { dry_run } = if body.is_empty() {
+        DocDeleteRequest::default()
+    } else {
+        serde_json::from_str(&body).map_err(|_| Error::BadRequest)?
+    };
+
+    let store = ctx.data.store();
+    let names = store
+        .list_objects(&format!("{}/", doc_id))
+        .await
+        .map_err(store_error)?;
+    let data_deleted = names.iter().any(|name| name == "data.ysweet");
+    let deleted_assets = names
+        .iter()
+        .filter(|name| name.starts_with("assets/"))
+        .count();
+
+    if dry_run {
+        let mut removed_objects: Vec<String> = names
+            .iter()
+            .map(|name| format!("{}/{}", doc_id, name))
+            .collect();
+        removed_objects.sort();
+        return Ok(DocDeleteResponse {
+            doc_id,
+            data_deleted,
+            deleted_assets,
+            success: true,
+            dry_run: true,
+            removed_objects: Some(removed_objects),
+        });
+    }
+
+    notify_durable_object(ctx, &doc_id, "", Method::Delete).await?;
+    for name in names {
+        match store.remove(&format!("{}/{}", doc_id, name)).await {
+            Ok(()) | Err(StoreError::DoesNotExist(_)) => {}
+            Err(e) => return Err(store_error(e)),
+        }
+    }
+
+    Ok(DocDeleteResponse {
+        doc_id,
+        data_deleted,
+        deleted_assets,
+        success: true,
+        dry_run: false,
+        removed_objects: None,
+    })
+}
+
+async fn copy_handler(req: Request, ctx: RouteContext<ServerContext>) -> Result<Response> {
+    copy(req, ctx).await.into_response()
+}
+
+async fn copy(
+    mut req: Request,
+    mut ctx: RouteContext<ServerContext>,
+) -> std::result::Result<DocCopyResponse, Error> {
+    check_server_token(&req, ctx.data.auth()?)?;
+    let source_doc_id = ctx.param("doc_id").unwrap().to_string();
+    let body = req
+        .json::<DocCopyRequest>()
+        .await
+        .map_err(|_| Error::BadRequest)?;
+    let destination_doc_id = body.destination_doc_id;
+    if !validate_doc_name(&source_doc_id) || !validate_doc_name(&destination_doc_id) {
+        return Err(Error::InvalidDocName);
+    }
+    check_doc_exists(&mut ctx, &source_doc_id).await?;
+
+    let store = ctx.data.store();
+    if body.dry_run {
+        let mut written_objects: Vec<String> = store
+            .list_objects(&format!("{}/", source_doc_id))
+            .await
+            .map_err(store_error)?
+            .iter()
+            .map(|name| format!("{}/{}", destination_doc_id, name))
+            .collect();
+        written_objects.sort();
+        return Ok(DocCopyResponse {
+            source_doc_id,
+            destination_doc_id,
+            success: true,
+            dry_run: true,
+            written_objects: Some(written_objects),
+        });
+    }
+
+    // Copy the latest state of the source, not the last checkpoint
+    notify_durable_object(ctx, &source_doc_id, "/persist", Method::Post).await?;
+    store
+        .copy_document(&source_doc_id, &destination_doc_id)
+        .await
+        .map_err(store_error)?;
+
+    Ok(DocCopyResponse {
+        source_doc_id,
+        destination_doc_id,
+        success: true,
+        dry_run: false,
+        written_objects: None,
+    })
+}
@@ -0,0 +1,17 @@
+fn main() {
+    // Custom: generate the gRPC management API (see the `grpc` feature)
+    #[cfg(feature = "grpc")]
+    {
+        // Use the vendored protoc so building does not require a system install.
+        if std::env::var_os("PROTOC").is_none() {
+            let protoc = protoc_bin_vendored::protoc_bin_path().expect("vendored protoc");
+            std::env::set_var("PROTOC", protoc);
+        }
+
+        println!("cargo:rerun-if-changed=proto/y_sweet.proto");
+        tonic_build::configure()
+            .build_client(false)
+            .compile_protos(&["proto/y_sweet.proto"], &["proto"])
+            .expect("failed to compile protos");
+    }
+}
@@ -0,0 +1,43 @@
+//! Separate listener for the management endpoints.
+//!
+//! By default every endpoint is served on the one listener. With an admin listener, the
+//! public listener only serves what clients need (`/ready`, `/health`, auth, sync over
+//! WebSocket and HTTP, exports and assets), and the endpoints that create, copy, delete or
+//! otherwise manage documents are only served on the admin listener, which can be bound
+//! to a private interface instead of being filtered by path in front of the server.
+
+use axum::Router;
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+/// Serve the management endpoints until the server shuts down
+pub(crate) async fn serve_admin(
+    listener: TcpListener,
+    app: Router,
+    cancellation_token: CancellationToken,
+) {
+    let addr = listener.local_addr().ok();
+    info!(
+        message = "Management API listening",
+        event = "admin_server_started",
+        address = ?addr
+    );
+
+    let result = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(async move { cancellation_token.cancelled().await })
+    .await;
+
+    if let Err(e) = result {
+        error!(
+            message = "Management API failed",
+            event = "admin_server_failed",
+            error = %e
+        );
+    }
+    tracing::debug!("Exiting management API server");
+}
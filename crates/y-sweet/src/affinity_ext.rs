@@ -0,0 +1,109 @@
+//! Client URLs that carry session affinity hints.
+//!
+//! By default the URLs returned by `auth_doc` are built from `--url-prefix` (or the request's
+//! host), so every client of every document is sent to the same address. With several
+//! instances behind a load balancer, a template can instead place the node that owns the
+//! document, or the document ID itself, in the URL, for the load balancer to route on.
+
+use anyhow::{anyhow, Result};
+use url::Url;
+
+const NODE_PLACEHOLDER: &str = "{node}";
+const DOC_ID_PLACEHOLDER: &str = "{doc_id}";
+
+/// Template of the base URL handed to clients, e.g. `https://{node}.sync.example.com/` or
+/// `https://sync.example.com/n/{node}/`.
+///
+/// `{node}` is replaced by the ID of the node owning the document in a cluster, or else by
+/// the ID of this node; `{doc_id}` by the document ID.
+#[derive(Clone, Debug)]
+pub struct ClientUrlTemplate {
+    template: String,
+    node_id: Option<String>,
+}
+
+impl ClientUrlTemplate {
+    pub fn new(template: &str, node_id: Option<String>) -> Result<Self> {
+        if template.contains(NODE_PLACEHOLDER) && node_id.is_none() {
+            return Err(anyhow!(
+                "Client URL template uses {} but no node ID is set",
+                NODE_PLACEHOLDER
+            ));
+        }
+
+        let template = Self {
+            template: template.to_string(),
+            node_id,
+        };
+        // Catch malformed templates at startup rather than on the first request
+        template.render("doc", None)?;
+        Ok(template)
+    }
+
+    /// Base URL for a document. `owner` is the node owning it, if known.
+    pub fn render(&self, doc_id: &str, owner: Option<&str>) -> Result<Url> {
+        let node = owner.or(self.node_id.as_deref()).unwrap_or_default();
+        let mut rendered = self
+            .template
+            .replace(NODE_PLACEHOLDER, node)
+            .replace(DOC_ID_PLACEHOLDER, doc_id);
+        // Paths are joined onto the URL, so it must end with a slash
+        if !rendered.ends_with('/') {
+            rendered.push('/');
+        }
+
+        let url =
+            Url::parse(&rendered).map_err(|e| anyhow!("Invalid client URL {}: {}", rendered, e))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(anyhow!("Client URL {} must be http or https", rendered));
+        }
+        Ok(url)
+    }
+
+    /// The WebSocket URL and the base URL of a document, for its `ClientToken`.
+    pub fn client_urls(&self, doc_id: &str, owner: Option<&str>) -> Result<(String, String)> {
+        let base = self.render(doc_id, owner)?;
+
+        let mut url = base.join(&format!("d/{doc_id}/ws"))?;
+        let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+        url.set_scheme(scheme)
+            .map_err(|_| anyhow!("Failed to set WebSocket scheme"))?;
+
+        let base_url = base.join(&format!("d/{doc_id}"))?;
+        Ok((url.to_string(), base_url.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_node_in_host() {
+        let template =
+            ClientUrlTemplate::new("https://{node}.sync.example.com", Some("a".into())).unwrap();
+
+        let (url, base_url) = template.client_urls("doc1", None).unwrap();
+        assert_eq!(url, "wss://a.sync.example.com/d/doc1/ws");
+        assert_eq!(base_url, "https://a.sync.example.com/d/doc1");
+
+        let (url, _) = template.client_urls("doc1", Some("b")).unwrap();
+        assert_eq!(url, "wss://b.sync.example.com/d/doc1/ws");
+    }
+
+    #[test]
+    fn test_affinity_path_is_kept() {
+        let template =
+            ClientUrlTemplate::new("http://lb.example.com/route/{doc_id}/", None).unwrap();
+        let (url, base_url) = template.client_urls("doc1", None).unwrap();
+        assert_eq!(url, "ws://lb.example.com/route/doc1/d/doc1/ws");
+        assert_eq!(base_url, "http://lb.example.com/route/doc1/d/doc1");
+    }
+
+    #[test]
+    fn test_invalid_templates() {
+        assert!(ClientUrlTemplate::new("https://{node}.example.com", None).is_err());
+        assert!(ClientUrlTemplate::new("not a url", None).is_err());
+        assert!(ClientUrlTemplate::new("ftp://example.com", None).is_err());
+    }
+}
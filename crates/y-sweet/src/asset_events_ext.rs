@@ -0,0 +1,387 @@
+//! Ingestion of the event notifications of the store (`POST /assets/events`), so that the
+//! server learns of asset uploads as soon as they finish, without waiting for the client to
+//! confirm them.
+//!
+//! The bucket is configured to send its `s3:ObjectCreated:*` notifications to the endpoint,
+//! either directly or through an SNS topic with an HTTPS subscription (whose confirmation
+//! is handled here). Since SNS cannot set headers, the notifications may be authenticated
+//! with the asset events secret (`--asset-events-secret`) in the `secret` query parameter
+//! instead of the server token in the `Authorization` header. The secret only gives access
+//! to this endpoint, and is replaced by reloading the configuration, so a secret leaked
+//! with the URL is easily revoked. Each created object that
+//! is an asset of an existing document is processed like a confirmed upload (see
+//! `asset_hook_ext`), unless the asset already has a status (its upload was confirmed, or
+//! reported by an earlier event, while an asset hook is configured); other events are
+//! ignored.
+
+use anyhow::anyhow;
+use axum::{
+    body::Bytes,
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+use axum_extra::typed_header::TypedHeader;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::{sync::Arc, time::Duration};
+use tracing::{info, warn};
+use url::Url;
+use y_sweet_core::api_types_ext::{AssetEventsResponse, ReportedAsset};
+
+use crate::asset_hook_ext::{process_uploaded_asset, read_asset_status};
+use crate::audit_ext::{record_audit, AuditPrincipal};
+use crate::server::{AppError, Server};
+use crate::server_ext::is_asset_name;
+
+/// Longest time to confirm an SNS subscription.
+const SUBSCRIPTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An S3 event notification, possibly a test event without records.
+#[derive(Debug, Deserialize)]
+struct S3Event {
+    #[serde(rename = "Records", default)]
+    records: Vec<S3EventRecord>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct S3EventRecord {
+    event_name: String,
+    s3: S3Entity,
+}
+
+#[derive(Debug, Deserialize)]
+struct S3Entity {
+    object: S3Object,
+}
+
+#[derive(Debug, Deserialize)]
+struct S3Object {
+    /// URL encoded, with spaces as `+`
+    key: String,
+}
+
+/// The envelope of a message delivered by SNS.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "Type")]
+enum SnsMessage {
+    Notification {
+        #[serde(rename = "Message")]
+        message: String,
+    },
+    SubscriptionConfirmation {
+        #[serde(rename = "SubscribeURL")]
+        subscribe_url: String,
+    },
+    UnsubscribeConfirmation {},
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AssetEventsQuery {
+    secret: Option<String>,
+}
+
+/// Whether `secret` is the asset events secret of the server. Digests are compared, so
+/// that the comparison takes as long whichever bytes differ.
+fn is_asset_events_secret(server_state: &Server, secret: &str) -> bool {
+    server_state
+        .asset_events_secret()
+        .is_some_and(|expected| Sha256::digest(expected) == Sha256::digest(secret))
+}
+
+/// The document ID and asset name of the object `key`, if it is an asset: the store may
+/// prefix keys, so only the last segments (`{doc_id}/assets/{name}`) are considered.
+fn parse_asset_key(key: &str) -> Option<(&str, &str)> {
+    let mut segments = key.rsplit('/');
+    let name = segments.next()?;
+    if segments.next()? != "assets" || !is_asset_name(name) || name.is_empty() {
+        return None;
+    }
+    let doc_id = segments.next().filter(|doc_id| !doc_id.is_empty())?;
+    Some((doc_id, name))
+}
+
+/// Decode an object key of an S3 event. Asset keys are made of URL-safe characters, so
+/// only percent escapes and `+` need decoding.
+fn decode_object_key(key: &str) -> String {
+    url::form_urlencoded::parse(key.as_bytes())
+        .next()
+        .map(|(key, _)| key.into_owned())
+        .unwrap_or_default()
+}
+
+/// Confirm an SNS subscription by fetching its `SubscribeURL`, which must be an AWS URL.
+async fn confirm_subscription(subscribe_url: &str) -> Result<(), AppError> {
+    let url = Url::parse(subscribe_url)
+        .ok()
+        .filter(|url| url.scheme() == "https")
+        .filter(|url| {
+            url.host_str()
+                .is_some_and(|host| host.ends_with(".amazonaws.com"))
+        })
+        .ok_or_else(|| {
+            AppError(
+                StatusCode::BAD_REQUEST,
+                anyhow!("Invalid subscription URL {}", subscribe_url),
+            )
+        })?;
+    reqwest::Client::new()
+        .get(url)
+        .timeout(SUBSCRIPTION_TIMEOUT)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| {
+            AppError(
+                StatusCode::BAD_GATEWAY,
+                anyhow!("Failed to confirm the subscription: {}", e),
+            )
+        })?;
+    info!(
+        message = "Confirmed the SNS subscription of asset events",
+        event = "asset_events_subscribed"
+    );
+    Ok(())
+}
+
+/// Process the asset uploads reported by S3 event notifications
+pub async fn ingest_asset_events(
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    Query(query): Query<AssetEventsQuery>,
+    body: Bytes,
+) -> Result<Json<AssetEventsResponse>, AppError> {
+    let principal = match query.secret {
+        Some(secret) if is_asset_events_secret(&server_state, &secret) => {
+            AuditPrincipal::asset_events()
+        }
+        Some(_) => {
+            return Err(AppError(
+                StatusCode::UNAUTHORIZED,
+                anyhow!("Invalid asset events secret"),
+            ))
+        }
+        None => {
+            let principal = AuditPrincipal::server(&auth_header);
+            server_state.check_auth(auth_header)?;
+            principal
+        }
+    };
+
+    let invalid = |e: serde_json::Error| {
+        AppError(
+            StatusCode::BAD_REQUEST,
+            anyhow!("Invalid event notification: {}", e),
+        )
+    };
+    let body: serde_json::Value = serde_json::from_slice(&body).map_err(invalid)?;
+    let event: S3Event = if body.get("Type").is_some() {
+        match serde_json::from_value(body).map_err(invalid)? {
+            SnsMessage::Notification { message } => {
+                serde_json::from_str(&message).map_err(invalid)?
+            }
+            SnsMessage::SubscriptionConfirmation { subscribe_url } => {
+                confirm_subscription(&subscribe_url).await?;
+                S3Event { records: vec![] }
+            }
+            SnsMessage::UnsubscribeConfirmation {} => S3Event { records: vec![] },
+        }
+    } else {
+        serde_json::from_value(body).map_err(invalid)?
+    };
+
+    let Some(store) = server_state.store.clone() else {
+        return Err(AppError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            anyhow!("No store configured"),
+        ));
+    };
+
+    let mut assets = Vec::new();
+    let mut ignored = 0;
+    for record in event.records {
+        let key = decode_object_key(&record.s3.object.key);
+        let asset = record
+            .event_name
+            .starts_with("ObjectCreated:")
+            .then(|| parse_asset_key(&key))
+            .flatten();
+        let Some((doc_id, asset_name)) = asset else {
+            ignored += 1;
+            continue;
+        };
+        if !server_state.doc_exists(doc_id).await {
+            warn!(
+                message = "Ignored an asset event of a missing document",
+                event = "asset_event_ignored",
+                doc_id = %doc_id,
+                asset_id = %asset_name
+            );
+            ignored += 1;
+            continue;
+        }
+        // Already confirmed by the client, or reported by an earlier (redelivered) event
+        if read_asset_status(&store, doc_id, asset_name)
+            .await
+            .is_some()
+        {
+            ignored += 1;
+            continue;
+        }
+
+        let status =
+            process_uploaded_asset(&server_state, store.clone(), doc_id, asset_name).await?;
+        record_audit(
+            &server_state,
+            doc_id,
+            "asset_upload_reported",
+            principal.clone(),
+            Some(json!({ "assetId": asset_name })),
+        )
+        .await;
+        assets.push(ReportedAsset {
+            doc_id: doc_id.to_string(),
+            asset_id: asset_name.to_string(),
+            status,
+        });
+    }
+
+    Ok(Json(AssetEventsResponse { assets, ignored }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::asset_hook_ext::{AssetHook, AssetHooks, AssetUpload};
+    use crate::stores::memory::MemoryStore;
+    use async_trait::async_trait;
+    use axum::{body::Body, http::Request};
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+    use y_sweet_core::{api_types_ext::AssetStatus, auth::Authenticator};
+
+    struct AcceptingHook;
+
+    #[async_trait]
+    impl AssetHook for AcceptingHook {
+        async fn asset_uploaded(&self, _: &AssetUpload) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_parse_asset_key() {
+        assert_eq!(
+            parse_asset_key("prefix/doc1/assets/a1.png"),
+            Some(("doc1", "a1.png"))
+        );
+        assert_eq!(
+            parse_asset_key("doc1/assets/a1.png"),
+            Some(("doc1", "a1.png"))
+        );
+        assert_eq!(parse_asset_key("doc1/assets/thumbs/a1.png"), None);
+        assert_eq!(parse_asset_key("doc1/data.ysweet"), None);
+        assert_eq!(parse_asset_key("assets/a1.png"), None);
+        assert_eq!(
+            decode_object_key("doc1/assets/my+file%21.png"),
+            "doc1/assets/my file!.png"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_created_assets_are_processed_once() {
+        let store = MemoryStore::new();
+        let auth = Authenticator::gen_key().unwrap();
+        let server_token = auth.server_token();
+        let server = Arc::new(
+            Server::builder()
+                .store(Some(Box::new(store.clone())))
+                .authenticator(Some(auth))
+                .build()
+                .await
+                .unwrap()
+                .with_asset_hooks(AssetHooks::new(AcceptingHook))
+                .with_asset_events_secret("events-secret".to_string()),
+        );
+        let doc_id = server.create_doc().await.unwrap();
+        store.insert_object(&format!("{}/assets/a1.png", doc_id), b"png".to_vec());
+
+        let s3_event = json!({
+            "Records": [
+                {
+                    "eventName": "ObjectCreated:Put",
+                    "s3": { "object": { "key": format!("{}/assets/a1.png", doc_id), "size": 3 } }
+                },
+                {
+                    "eventName": "ObjectCreated:Put",
+                    "s3": { "object": { "key": "missing/assets/a2.png", "size": 3 } }
+                },
+                {
+                    "eventName": "ObjectRemoved:Delete",
+                    "s3": { "object": { "key": format!("{}/assets/a1.png", doc_id) } }
+                }
+            ]
+        });
+        // Delivered through SNS, authenticated with the secret in the URL
+        let sns_message = json!({
+            "Type": "Notification",
+            "MessageId": "1",
+            "Message": s3_event.to_string(),
+        });
+        let request = |uri: String| {
+            Request::builder()
+                .method("POST")
+                .uri(uri)
+                .body(Body::from(sns_message.to_string()))
+                .unwrap()
+        };
+
+        let response = server
+            .routes()
+            .oneshot(request("/assets/events".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // The server token is only accepted in the header, out of the logged URL
+        for uri in [
+            format!("/assets/events?token={}", server_token),
+            format!("/assets/events?secret={}", server_token),
+        ] {
+            let response = server.routes().oneshot(request(uri)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        let response = server
+            .routes()
+            .oneshot(request("/assets/events?secret=events-secret".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: AssetEventsResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body.assets,
+            vec![ReportedAsset {
+                doc_id: doc_id.clone(),
+                asset_id: "a1.png".to_string(),
+                status: AssetStatus::Pending,
+            }]
+        );
+        assert_eq!(body.ignored, 2);
+
+        // Redelivered, to the server token
+        let mut redelivered = request("/assets/events".to_string());
+        redelivered.headers_mut().insert(
+            axum::http::header::AUTHORIZATION,
+            format!("Bearer {}", server_token).parse().unwrap(),
+        );
+        let response = server.routes().oneshot(redelivered).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: AssetEventsResponse = serde_json::from_slice(&body).unwrap();
+        assert!(body.assets.is_empty());
+        assert_eq!(body.ignored, 3);
+    }
+}
@@ -0,0 +1,273 @@
+//! Original filenames of assets, given to the files users download.
+//!
+//! Assets are stored under generated IDs (`ckxyz123.png`). When the upload URL is requested
+//! with the `filename` of the file, it is kept in `{doc_id}/assets/filenames/`, and the
+//! presigned download URLs of the asset listing set `Content-Disposition` to it (on stores
+//! that can, e.g. S3), as does the download through the server
+//! (`GET /d/:doc_id/assets/:asset_id`).
+
+use anyhow::anyhow;
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use axum_extra::typed_header::TypedHeader;
+use std::sync::Arc;
+use tracing::warn;
+use y_sweet_core::store::Store;
+
+use crate::asset_token_ext::{check_granted_asset, verify_asset_request, AssetTokenQuery};
+use crate::server::{get_token_from_header, AppError, Server};
+use crate::server_ext::{ext_check_not_archived, find_asset_name, FILENAMES_DIR};
+
+/// Longest filename kept, in bytes.
+const MAX_FILENAME_LEN: usize = 255;
+
+/// Key of the original filename of `asset_name` (with its extension) of `doc_id`.
+pub fn asset_filename_key(doc_id: &str, asset_name: &str) -> String {
+    format!("{}/assets/{}/{}", doc_id, FILENAMES_DIR, asset_name)
+}
+
+/// `filename` without the directories of the client and control characters, truncated to
+/// [MAX_FILENAME_LEN] bytes, or None if nothing is left.
+pub fn sanitize_filename(filename: &str) -> Option<String> {
+    let name = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+    let mut sanitized = String::new();
+    for c in name.chars().filter(|c| !c.is_control()) {
+        if sanitized.len() + c.len_utf8() > MAX_FILENAME_LEN {
+            break;
+        }
+        sanitized.push(c);
+    }
+    let sanitized = sanitized.trim();
+    (!sanitized.is_empty() && sanitized != "." && sanitized != "..").then(|| sanitized.to_string())
+}
+
+/// `Content-Disposition` downloading the response as `filename`: an ASCII fallback for old
+/// clients, and the UTF-8 name percent-encoded as in RFC 6266.
+pub fn content_disposition(filename: &str) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+    let mut encoded = String::new();
+    for byte in filename.bytes() {
+        match byte {
+            b'a'..=b'z'
+            | b'A'..=b'Z'
+            | b'0'..=b'9'
+            | b'!'
+            | b'#'
+            | b'$'
+            | b'&'
+            | b'+'
+            | b'-'
+            | b'.'
+            | b'^'
+            | b'_'
+            | b'`'
+            | b'|'
+            | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback, encoded
+    )
+}
+
+/// Keep `filename` as the original filename of `asset_name` of `doc_id`.
+pub(crate) async fn write_asset_filename(
+    server_state: &Server,
+    doc_id: &str,
+    asset_name: &str,
+    filename: &str,
+) -> Result<(), AppError> {
+    let (Some(store), Some(filename)) = (&server_state.store, sanitize_filename(filename)) else {
+        return Ok(());
+    };
+    store
+        .set(
+            &asset_filename_key(doc_id, asset_name),
+            filename.into_bytes(),
+        )
+        .await
+        .map_err(|e| {
+            AppError(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                anyhow!("Failed to write asset filename: {}", e),
+            )
+        })
+}
+
+/// The original filename of `asset_name` of `doc_id`, if it was given on upload (and can
+/// be read).
+pub async fn read_asset_filename(
+    store: &Arc<Box<dyn Store>>,
+    doc_id: &str,
+    asset_name: &str,
+) -> Option<String> {
+    match store.get(&asset_filename_key(doc_id, asset_name)).await {
+        Ok(bytes) => bytes.and_then(|bytes| String::from_utf8(bytes).ok()),
+        Err(e) => {
+            warn!(
+                message = "Failed to read asset filename",
+                event = "asset_filename_read_failed",
+                doc_id = %doc_id,
+                asset_id = %asset_name,
+                error = %e
+            );
+            None
+        }
+    }
+}
+
+/// Download an asset through the server, named with its original filename. Accepts an
+/// asset token in the query instead of a token of the document.
+pub async fn download_asset(
+    Path((doc_id, asset_id)): Path<(String, String)>,
+    Query(query): Query<AssetTokenQuery>,
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+) -> Result<Response, AppError> {
+    let token = get_token_from_header(auth_header);
+    let granted = verify_asset_request(&server_state, &doc_id, token.as_deref(), &query)?;
+
+    ext_check_not_archived(&server_state, &doc_id).await?;
+
+    if !server_state.doc_exists(&doc_id).await {
+        Err((StatusCode::NOT_FOUND, anyhow!("Doc {} not found", doc_id)))?;
+    }
+
+    let Some(store) = &server_state.store else {
+        return Err(AppError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            anyhow!("No store configured"),
+        ));
+    };
+    let asset_name = find_asset_name(store, &doc_id, &asset_id).await?;
+    check_granted_asset(granted.as_deref(), &asset_name)?;
+    let content = store
+        .get(&format!("{}/assets/{}", doc_id, asset_name))
+        .await
+        .map_err(|e| {
+            AppError(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                anyhow!("Failed to read asset: {}", e),
+            )
+        })?
+        .ok_or_else(|| {
+            AppError(
+                StatusCode::NOT_FOUND,
+                anyhow!("Asset {} not found", asset_id),
+            )
+        })?;
+
+    let filename = read_asset_filename(store, &doc_id, &asset_name)
+        .await
+        .unwrap_or_else(|| asset_name.clone());
+    let content_type = mime_guess::from_path(&asset_name)
+        .first_or_octet_stream()
+        .to_string();
+    let mut response = content.into_response();
+    let headers = response.headers_mut();
+    if let Ok(value) = HeaderValue::from_str(&content_type) {
+        headers.insert(header::CONTENT_TYPE, value);
+    }
+    if let Ok(value) = HeaderValue::from_str(&content_disposition(&filename)) {
+        headers.insert(header::CONTENT_DISPOSITION, value);
+    }
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static("private, max-age=3600"),
+    );
+    Ok(response)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stores::memory::MemoryStore;
+    use axum::{body::Body, http::Request};
+    use http_body_util::BodyExt;
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    #[test]
+    fn test_content_disposition_encodes_filename() {
+        assert_eq!(
+            sanitize_filename("C:\\Users\\me\\design v2.png").as_deref(),
+            Some("design v2.png")
+        );
+        assert_eq!(sanitize_filename("../"), None);
+        assert_eq!(
+            content_disposition("design v2.png"),
+            "attachment; filename=\"design v2.png\"; filename*=UTF-8''design%20v2.png"
+        );
+        assert_eq!(
+            content_disposition("résumé \"final\".pdf"),
+            "attachment; filename=\"r_sum_ _final_.pdf\"; filename*=UTF-8''r%C3%A9sum%C3%A9%20%22final%22.pdf"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_asset_is_downloaded_with_its_original_filename() {
+        let store = MemoryStore::new();
+        let server = Arc::new(
+            Server::builder()
+                .store(Some(Box::new(store.clone())))
+                .build()
+                .await
+                .unwrap(),
+        );
+        let doc_id = server.create_doc().await.unwrap();
+
+        let request = Request::builder()
+            .method("POST")
+            .uri(format!("/d/{}/assets", doc_id))
+            .header("Content-Type", "application/json")
+            .body(Body::from(
+                json!({ "contentType": "image/png", "filename": "design-v2.png" }).to_string(),
+            ))
+            .unwrap();
+        let response = server.routes().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let asset_name = body["assetId"].as_str().unwrap().to_string();
+        store.insert_object(
+            &format!("{}/assets/{}", doc_id, asset_name),
+            b"png".to_vec(),
+        );
+
+        let get = |uri: String| Request::builder().uri(uri).body(Body::empty()).unwrap();
+        let response = server
+            .routes()
+            .oneshot(get(format!("/d/{}/assets", doc_id)))
+            .await
+            .unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["assets"].as_array().unwrap().len(), 1);
+        assert_eq!(body["assets"][0]["filename"], "design-v2.png");
+
+        let response = server
+            .routes()
+            .oneshot(get(format!("/d/{}/assets/{}", doc_id, asset_name)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+        assert_eq!(
+            response.headers()[header::CONTENT_DISPOSITION],
+            "attachment; filename=\"design-v2.png\"; filename*=UTF-8''design-v2.png"
+        );
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"png");
+    }
+}
@@ -0,0 +1,451 @@
+//! Processing of assets once their upload is confirmed (`POST /d/:doc_id/assets/:asset_id/confirm`).
+//!
+//! Assets are uploaded straight to the store through presigned URLs, so the server only
+//! learns of an upload when the client confirms it, or when the store reports it (see
+//! `asset_events_ext`). It then calls the [AssetHook] of the deployment (or POSTs to
+//! `--asset-webhook-url`) with the document, asset and content type, e.g. to scan, transcode
+//! or index the asset. Failed calls are retried with exponential backoff, and the outcome is
+//! kept in `{doc_id}/assets/status/` and listed with the assets as their `status`. Retries
+//! do not survive a restart of the server: confirming the upload again calls the hook again.
+//! Clients connected to the document are told of each processed upload with an
+//! [asset notification](y_sweet_core::asset_event_ext).
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use axum_extra::typed_header::TypedHeader;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{sync::Arc, time::Duration};
+use tracing::{info, warn};
+use url::Url;
+use y_sweet_core::{
+    api_types_ext::{AssetConfirmResponse, AssetStatus},
+    asset_event_ext::encode_asset_message,
+    store::Store,
+};
+
+use crate::audit_ext::{record_audit, AuditPrincipal};
+use crate::server::{current_time_epoch_millis, get_token_from_header, AppError, Server};
+use crate::server_ext::{ext_check_not_archived, find_asset_name, STATUS_DIR};
+
+/// How many times a failed asset hook is retried by default.
+pub const DEFAULT_ASSET_HOOK_RETRIES: u32 = 3;
+
+/// Delay before the first retry of a failed asset hook, doubled on each retry.
+pub const DEFAULT_ASSET_HOOK_BACKOFF: Duration = Duration::from_secs(1);
+
+/// How long the asset webhook has to respond.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// An asset whose upload was confirmed.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AssetUpload {
+    pub doc_id: String,
+    /// The asset ID with its extension, as returned when the upload URL was issued.
+    pub asset_id: String,
+    pub content_type: String,
+}
+
+/// Called when the upload of an asset is confirmed. An error is retried, so the hook should
+/// be idempotent.
+#[async_trait]
+pub trait AssetHook: Send + Sync {
+    async fn asset_uploaded(&self, upload: &AssetUpload) -> Result<()>;
+}
+
+/// POSTs each [AssetUpload] as JSON to a URL, failing on responses other than 2xx.
+pub struct WebhookAssetHook {
+    client: reqwest::Client,
+    url: Url,
+}
+
+impl WebhookAssetHook {
+    pub fn new(url: Url) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url,
+        }
+    }
+}
+
+#[async_trait]
+impl AssetHook for WebhookAssetHook {
+    async fn asset_uploaded(&self, upload: &AssetUpload) -> Result<()> {
+        self.client
+            .post(self.url.clone())
+            .timeout(WEBHOOK_TIMEOUT)
+            .json(upload)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// An [AssetHook] with its retry policy.
+pub struct AssetHooks {
+    hook: Arc<dyn AssetHook>,
+    retries: u32,
+    backoff: Duration,
+}
+
+impl AssetHooks {
+    pub fn new(hook: impl AssetHook + 'static) -> Self {
+        Self {
+            hook: Arc::new(hook),
+            retries: DEFAULT_ASSET_HOOK_RETRIES,
+            backoff: DEFAULT_ASSET_HOOK_BACKOFF,
+        }
+    }
+
+    /// Retries a failed hook up to `retries` times before marking the asset as failed.
+    pub fn with_retries(self, retries: u32) -> Self {
+        Self { retries, ..self }
+    }
+
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    /// Waits `backoff` before the first retry, doubling it on each retry.
+    pub fn with_backoff(self, backoff: Duration) -> Self {
+        Self { backoff, ..self }
+    }
+
+    /// Call the hook for `upload` until it succeeds or runs out of retries, recording the
+    /// status of the asset after each attempt. Returns the final status.
+    async fn process(&self, store: &Arc<Box<dyn Store>>, upload: &AssetUpload) -> AssetStatus {
+        let mut backoff = self.backoff;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let (status, error) = match self.hook.asset_uploaded(upload).await {
+                Ok(()) => (AssetStatus::Ready, None),
+                Err(e) if attempts > self.retries => (AssetStatus::Failed, Some(e.to_string())),
+                Err(e) => (AssetStatus::Pending, Some(e.to_string())),
+            };
+            let record = AssetStatusRecord {
+                status,
+                attempts,
+                error: error.clone(),
+                updated_at: current_time_epoch_millis(),
+            };
+            if let Err(e) =
+                write_asset_status(store, &upload.doc_id, &upload.asset_id, &record).await
+            {
+                warn!(
+                    message = "Failed to write asset status",
+                    event = "asset_status_write_failed",
+                    doc_id = %upload.doc_id,
+                    asset_id = %upload.asset_id,
+                    error = %e
+                );
+            }
+
+            match status {
+                AssetStatus::Ready => {
+                    info!(
+                        message = "Asset hook succeeded",
+                        event = "asset_hook_succeeded",
+                        doc_id = %upload.doc_id,
+                        asset_id = %upload.asset_id,
+                        attempts = attempts
+                    );
+                    return status;
+                }
+                AssetStatus::Failed => {
+                    warn!(
+                        message = "Asset hook failed, giving up",
+                        event = "asset_hook_failed",
+                        doc_id = %upload.doc_id,
+                        asset_id = %upload.asset_id,
+                        attempts = attempts,
+                        error = error.unwrap_or_default()
+                    );
+                    return status;
+                }
+                AssetStatus::Pending => {
+                    warn!(
+                        message = "Asset hook failed, retrying",
+                        event = "asset_hook_retry",
+                        doc_id = %upload.doc_id,
+                        asset_id = %upload.asset_id,
+                        attempts = attempts,
+                        retry_in_ms = backoff.as_millis() as u64,
+                        error = error.unwrap_or_default()
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+            }
+        }
+    }
+}
+
+/// Status of the processing of an asset, as kept in the store.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AssetStatusRecord {
+    pub status: AssetStatus,
+    /// Calls of the hook so far.
+    pub attempts: u32,
+    /// Error of the last failed call.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Time of the last change of status (epoch millis).
+    pub updated_at: u64,
+}
+
+/// Key of the status of `asset_name` (with its extension) of `doc_id`.
+pub fn asset_status_key(doc_id: &str, asset_name: &str) -> String {
+    format!("{}/assets/{}/{}.json", doc_id, STATUS_DIR, asset_name)
+}
+
+async fn write_asset_status(
+    store: &Arc<Box<dyn Store>>,
+    doc_id: &str,
+    asset_name: &str,
+    record: &AssetStatusRecord,
+) -> Result<()> {
+    store
+        .set(
+            &asset_status_key(doc_id, asset_name),
+            serde_json::to_vec(record)?,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Status of the processing of `asset_name` of `doc_id`, or None if its upload was never
+/// confirmed (or the status cannot be read).
+pub async fn read_asset_status(
+    store: &Arc<Box<dyn Store>>,
+    doc_id: &str,
+    asset_name: &str,
+) -> Option<AssetStatus> {
+    let key = asset_status_key(doc_id, asset_name);
+    let bytes = match store.get(&key).await {
+        Ok(bytes) => bytes?,
+        Err(e) => {
+            warn!(
+                message = "Failed to read asset status",
+                event = "asset_status_read_failed",
+                doc_id = %doc_id,
+                asset_id = %asset_name,
+                error = %e
+            );
+            return None;
+        }
+    };
+    serde_json::from_slice::<AssetStatusRecord>(&bytes)
+        .ok()
+        .map(|record| record.status)
+}
+
+/// Confirm that an asset was uploaded, calling the asset hook in the background. Responds
+/// 202 with the `pending` status while the hook runs, or 200 with `ready` without a hook.
+pub async fn confirm_asset_upload(
+    Path((doc_id, asset_id)): Path<(String, String)>,
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+) -> Result<(StatusCode, Json<AssetConfirmResponse>), AppError> {
+    let token = get_token_from_header(auth_header);
+    let authorization = server_state.verify_doc_token(token.as_deref(), &doc_id)?;
+
+    ext_check_not_archived(&server_state, &doc_id).await?;
+
+    if !server_state.doc_exists(&doc_id).await {
+        Err((StatusCode::NOT_FOUND, anyhow!("Doc {} not found", doc_id)))?;
+    }
+
+    let Some(store) = server_state.store.clone() else {
+        return Err(AppError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            anyhow!("No store configured"),
+        ));
+    };
+    let asset_name = find_asset_name(&store, &doc_id, &asset_id).await?;
+
+    record_audit(
+        &server_state,
+        &doc_id,
+        "asset_upload_confirmed",
+        AuditPrincipal::doc(token.as_deref(), authorization),
+        Some(json!({ "assetId": asset_name })),
+    )
+    .await;
+
+    let status = process_uploaded_asset(&server_state, store, &doc_id, &asset_name).await?;
+    let code = match status {
+        AssetStatus::Pending => StatusCode::ACCEPTED,
+        _ => StatusCode::OK,
+    };
+    Ok((
+        code,
+        Json(AssetConfirmResponse {
+            asset_id: asset_name,
+            status,
+        }),
+    ))
+}
+
+/// Process an uploaded asset: call the asset hook in the background (the asset is `pending`
+/// meanwhile), or consider it `ready` without a hook. The clients connected to the document
+/// are told of the asset, and of its final status once the hook is done.
+pub(crate) async fn process_uploaded_asset(
+    server_state: &Arc<Server>,
+    store: Arc<Box<dyn Store>>,
+    doc_id: &str,
+    asset_name: &str,
+) -> Result<AssetStatus, AppError> {
+    let status = match server_state.asset_hooks() {
+        Some(hooks) => {
+            let record = AssetStatusRecord {
+                status: AssetStatus::Pending,
+                attempts: 0,
+                error: None,
+                updated_at: current_time_epoch_millis(),
+            };
+            write_asset_status(&store, doc_id, asset_name, &record)
+                .await
+                .map_err(|e| AppError(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+
+            let upload = AssetUpload {
+                doc_id: doc_id.to_string(),
+                asset_id: asset_name.to_string(),
+                content_type: mime_guess::from_path(asset_name)
+                    .first_or_octet_stream()
+                    .to_string(),
+            };
+            let server_state = server_state.clone();
+            tokio::spawn(async move {
+                let status = hooks.process(&store, &upload).await;
+                server_state.notify_doc_connections(
+                    &upload.doc_id,
+                    encode_asset_message(&upload.asset_id, status),
+                );
+            });
+            AssetStatus::Pending
+        }
+        None => AssetStatus::Ready,
+    };
+
+    server_state.notify_doc_connections(doc_id, encode_asset_message(asset_name, status));
+    Ok(status)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stores::memory::MemoryStore;
+    use axum::{body::Body, http::Request};
+    use http_body_util::BodyExt;
+    use std::sync::Mutex;
+    use tower::ServiceExt;
+
+    /// Fails the first call for each asset, and every call for assets named `bad*`.
+    #[derive(Default)]
+    struct FlakyHook {
+        calls: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl AssetHook for Arc<FlakyHook> {
+        async fn asset_uploaded(&self, upload: &AssetUpload) -> Result<()> {
+            let mut calls = self.calls.lock().unwrap();
+            let first = !calls.contains(&upload.asset_id);
+            calls.push(upload.asset_id.clone());
+            if first || upload.asset_id.starts_with("bad") {
+                anyhow::bail!("unavailable");
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_confirmed_upload_is_retried_and_listed_with_status() {
+        let store = MemoryStore::new();
+        let hook = Arc::new(FlakyHook::default());
+        let server = Server::builder()
+            .store(Some(Box::new(store.clone())))
+            .build()
+            .await
+            .unwrap()
+            .with_asset_hooks(
+                AssetHooks::new(hook.clone())
+                    .with_retries(1)
+                    .with_backoff(Duration::from_millis(1)),
+            );
+        let server = Arc::new(server);
+        let doc_id = server.create_doc().await.unwrap();
+        store.insert_object(&format!("{}/assets/good.png", doc_id), b"png".to_vec());
+        store.insert_object(&format!("{}/assets/bad.mp4", doc_id), b"mp4".to_vec());
+
+        let confirm = |asset_id: &str| {
+            Request::builder()
+                .method("POST")
+                .uri(format!("/d/{}/assets/{}/confirm", doc_id, asset_id))
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = server.routes().oneshot(confirm("good")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, json!({ "assetId": "good.png", "status": "pending" }));
+        let response = server.routes().oneshot(confirm("bad.mp4")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let response = server.routes().oneshot(confirm("missing")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let store: Arc<Box<dyn Store>> = Arc::new(Box::new(store));
+        for _ in 0..100 {
+            let good = read_asset_status(&store, &doc_id, "good.png").await;
+            let bad = read_asset_status(&store, &doc_id, "bad.mp4").await;
+            if good == Some(AssetStatus::Ready) && bad == Some(AssetStatus::Failed) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let record: AssetStatusRecord = serde_json::from_slice(
+            &store
+                .get(&asset_status_key(&doc_id, "bad.mp4"))
+                .await
+                .unwrap()
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(record.status, AssetStatus::Failed);
+        assert_eq!(record.attempts, 2);
+        assert_eq!(record.error.as_deref(), Some("unavailable"));
+        assert_eq!(hook.calls.lock().unwrap().len(), 4);
+
+        let request = Request::builder()
+            .uri(format!("/d/{}/assets", doc_id))
+            .body(Body::empty())
+            .unwrap();
+        let response = server.routes().oneshot(request).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let mut statuses: Vec<_> = body["assets"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|asset| (asset["assetId"].clone(), asset["status"].clone()))
+            .collect();
+        statuses.sort_by_key(|(asset_id, _)| asset_id.to_string());
+        assert_eq!(
+            statuses,
+            vec![
+                (json!("bad"), json!("failed")),
+                (json!("good"), json!("ready")),
+            ]
+        );
+    }
+}
@@ -0,0 +1,230 @@
+//! Tokens granting read access to a single asset (`POST /d/:doc_id/assets/:asset_id/token`).
+//!
+//! Published read-only documents embed their images without the token of the document:
+//! the page gets a short-lived token for each asset instead, signed by the server, and
+//! downloads the asset through the server with it (`GET /d/:doc_id/assets/:asset_id?token=`,
+//! also accepted by the thumbnails). Asset tokens are signed with the auth key, so servers
+//! with a custom auth provider cannot issue them.
+
+use anyhow::anyhow;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use axum_extra::typed_header::TypedHeader;
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+use y_sweet_core::{
+    api_types_ext::{AssetTokenRequest, AssetTokenResponse},
+    auth::ExpirationTimeEpochMillis,
+    auth_ext::{gen_asset_token, verify_asset_token},
+};
+
+use crate::audit_ext::{record_audit, token_id, AuditPrincipal};
+use crate::proxy_ext::ClientInfo;
+use crate::server::{current_time_epoch_millis, get_token_from_header, AppError, Server};
+use crate::server_ext::{ext_check_not_archived, find_asset_name};
+
+/// How long asset tokens are valid by default.
+pub const DEFAULT_ASSET_TOKEN_SECONDS: u64 = 300;
+
+/// Longest validity of an asset token, a day.
+pub const MAX_ASSET_TOKEN_SECONDS: u64 = 24 * 60 * 60;
+
+/// The `?token=` of a request for an asset.
+#[derive(Debug, Default, Deserialize)]
+pub struct AssetTokenQuery {
+    pub token: Option<String>,
+}
+
+/// Check that a request for an asset of `doc_id` is allowed: with an asset token in the
+/// query, or with a token of the document in the header otherwise. Returns the asset the
+/// token is limited to, which the caller must check once it knows the name of the asset.
+pub(crate) fn verify_asset_request(
+    server_state: &Server,
+    doc_id: &str,
+    header_token: Option<&str>,
+    query: &AssetTokenQuery,
+) -> Result<Option<String>, AppError> {
+    let Some(token) = &query.token else {
+        server_state.verify_doc_token(header_token, doc_id)?;
+        return Ok(None);
+    };
+    if server_state.auth_provider().is_none() {
+        return Ok(None);
+    }
+    let Some(authenticator) = server_state.authenticator() else {
+        return Err(AppError(
+            StatusCode::UNAUTHORIZED,
+            anyhow!("Asset tokens are not supported by this server"),
+        ));
+    };
+    let asset_name = verify_asset_token(&authenticator, token, doc_id, current_time_epoch_millis())
+        .map_err(|e| AppError(StatusCode::UNAUTHORIZED, e.into()))?;
+    Ok(Some(asset_name))
+}
+
+/// Fail unless the asset `granted` by a token (if limited to one) is `asset_name`.
+pub(crate) fn check_granted_asset(granted: Option<&str>, asset_name: &str) -> Result<(), AppError> {
+    match granted {
+        Some(granted) if granted != asset_name => Err(AppError(
+            StatusCode::FORBIDDEN,
+            anyhow!("The token is not valid for asset {}", asset_name),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Issue a token granting read access to one asset, with the URL downloading it
+pub async fn issue_asset_token(
+    Path((doc_id, asset_id)): Path<(String, String)>,
+    TypedHeader(host): TypedHeader<headers::Host>,
+    client: Option<Extension<ClientInfo>>,
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    body: Option<Json<AssetTokenRequest>>,
+) -> Result<Json<AssetTokenResponse>, AppError> {
+    let token = get_token_from_header(auth_header);
+    let authorization = server_state.verify_doc_token(token.as_deref(), &doc_id)?;
+
+    ext_check_not_archived(&server_state, &doc_id).await?;
+
+    if !server_state.doc_exists(&doc_id).await {
+        Err((StatusCode::NOT_FOUND, anyhow!("Doc {} not found", doc_id)))?;
+    }
+
+    let Json(AssetTokenRequest { valid_for_seconds }) = body.unwrap_or_default();
+    let valid_for_seconds = valid_for_seconds.unwrap_or(DEFAULT_ASSET_TOKEN_SECONDS);
+    if valid_for_seconds == 0 || valid_for_seconds > MAX_ASSET_TOKEN_SECONDS {
+        return Err(AppError(
+            StatusCode::BAD_REQUEST,
+            anyhow!(
+                "validForSeconds must be between 1 and {}",
+                MAX_ASSET_TOKEN_SECONDS
+            ),
+        ));
+    }
+
+    let Some(store) = &server_state.store else {
+        return Err(AppError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            anyhow!("No store configured"),
+        ));
+    };
+    let asset_name = find_asset_name(store, &doc_id, &asset_id).await?;
+
+    let expires_at = current_time_epoch_millis() + valid_for_seconds * 1000;
+    let asset_token = match (server_state.auth_provider(), server_state.authenticator()) {
+        (None, _) => None,
+        (Some(_), Some(authenticator)) => Some(gen_asset_token(
+            &authenticator,
+            &doc_id,
+            &asset_name,
+            ExpirationTimeEpochMillis(expires_at),
+        )),
+        (Some(_), None) => {
+            return Err(AppError(
+                StatusCode::NOT_IMPLEMENTED,
+                anyhow!("Asset tokens require the auth key of the server"),
+            ))
+        }
+    };
+
+    record_audit(
+        &server_state,
+        &doc_id,
+        "asset_token_issued",
+        AuditPrincipal::doc(token.as_deref(), authorization),
+        Some(json!({
+            "assetId": asset_name,
+            "validForSeconds": valid_for_seconds,
+            "tokenId": asset_token.as_deref().map(token_id),
+        })),
+    )
+    .await;
+
+    let client = client.map(|Extension(client)| client).unwrap_or_default();
+    let base_url = server_state.doc_base_url(&doc_id, &host, client);
+    let url = match &asset_token {
+        Some(asset_token) => format!("{}/assets/{}?token={}", base_url, asset_name, asset_token),
+        None => format!("{}/assets/{}", base_url, asset_name),
+    };
+
+    Ok(Json(AssetTokenResponse {
+        asset_id: asset_name,
+        token: asset_token,
+        url,
+        expires_at,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stores::memory::MemoryStore;
+    use axum::{body::Body, http::Request};
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+    use y_sweet_core::auth::Authenticator;
+
+    #[tokio::test]
+    async fn test_asset_token_downloads_its_asset_only() {
+        let store = MemoryStore::new();
+        let auth = Authenticator::gen_key().unwrap();
+        let server_token = auth.server_token();
+        let server = Arc::new(
+            Server::builder()
+                .store(Some(Box::new(store.clone())))
+                .authenticator(Some(auth))
+                .build()
+                .await
+                .unwrap(),
+        );
+        let doc_id = server.create_doc().await.unwrap();
+        store.insert_object(&format!("{}/assets/logo.png", doc_id), b"logo".to_vec());
+        store.insert_object(&format!("{}/assets/secret.png", doc_id), b"secret".to_vec());
+
+        let request = Request::builder()
+            .method("POST")
+            .uri(format!("/d/{}/assets/logo/token", doc_id))
+            .header("Host", "collab.example.com")
+            .header("Authorization", format!("Bearer {}", server_token))
+            .body(Body::empty())
+            .unwrap();
+        let response = server.routes().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: AssetTokenResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body.asset_id, "logo.png");
+        let token = body.token.unwrap();
+        assert_eq!(
+            body.url,
+            format!(
+                "http://collab.example.com/d/{}/assets/logo.png?token={}",
+                doc_id, token
+            )
+        );
+
+        let get = |uri: String| Request::builder().uri(uri).body(Body::empty()).unwrap();
+        let uri = format!("/d/{}/assets/logo.png?token={}", doc_id, token);
+        let response = server.routes().oneshot(get(uri)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"logo");
+
+        // Not another asset, nor the document
+        let uri = format!("/d/{}/assets/secret.png?token={}", doc_id, token);
+        let response = server.routes().oneshot(get(uri)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let uri = format!("/d/{}/assets", doc_id);
+        let request = Request::builder()
+            .uri(uri)
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap();
+        let response = server.routes().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,382 @@
+//! Audit trail of each document, persisted to the store.
+//!
+//! With `--audit`, the server appends an entry to the trail of a document whenever a token
+//! is issued for it, a client connects to or disconnects from it, an update is applied
+//! through the REST API, or it is deleted, copied, forked or has assets uploaded or
+//! listed. Entries record when the action happened, the principal that did it and the
+//! fingerprint of its token.
+//!
+//! Each entry is an object of its own under `{doc_id}/audit/`, named after its time so
+//! that listing the objects lists the entries in order; nothing ever rewrites an entry.
+//! The trail outlives the document: deleting a document keeps its trail, and a copy or a
+//! fork starts a trail of its own. `GET /d/:doc_id/audit` pages through it.
+
+use anyhow::anyhow;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use axum_extra::typed_header::TypedHeader;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use tracing::error;
+use y_sweet_core::{
+    api_types::{validate_doc_name, Authorization},
+    api_types_ext::{AuditEntry, AuditResponse},
+    store::{Store, StoreError},
+};
+
+use crate::server::{current_time_epoch_millis, get_token_from_header, AppError, Server};
+
+/// Default number of entries in a page of `GET /d/:doc_id/audit`.
+pub const DEFAULT_AUDIT_PAGE_SIZE: usize = 100;
+/// Maximum number of entries in a page of `GET /d/:doc_id/audit`.
+pub const MAX_AUDIT_PAGE_SIZE: usize = 1000;
+
+/// Orders the entries this process writes within the same millisecond.
+static AUDIT_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+fn audit_prefix(doc_id: &str) -> String {
+    format!("{}/audit/", doc_id)
+}
+
+/// Who did an audited action.
+#[derive(Debug, Clone)]
+pub struct AuditPrincipal {
+    pub principal: &'static str,
+    pub token_id: Option<String>,
+}
+
+type BearerHeader = TypedHeader<headers::Authorization<headers::authorization::Bearer>>;
+
+impl AuditPrincipal {
+    /// The server itself, e.g. when the TTL reaper deletes a document.
+    pub fn system() -> Self {
+        Self {
+            principal: "system",
+            token_id: None,
+        }
+    }
+
+    /// The holder of the server token in `auth_header`.
+    pub fn server(auth_header: &Option<BearerHeader>) -> Self {
+        Self {
+            principal: "server",
+            token_id: get_token_from_header(auth_header.clone()).map(|t| token_id(&t)),
+        }
+    }
+
+    /// The store, notifying asset uploads with the asset events secret.
+    pub fn asset_events() -> Self {
+        Self {
+            principal: "asset_events",
+            token_id: None,
+        }
+    }
+
+    /// The holder of the document `token`.
+    pub fn doc(token: Option<&str>, authorization: Authorization) -> Self {
+        Self {
+            principal: match authorization {
+                Authorization::Full => "doc:full",
+                Authorization::ReadOnly => "doc:read-only",
+            },
+            token_id: token.map(token_id),
+        }
+    }
+}
+
+/// Fingerprint of a token, to tell tokens apart in the trail without recording them.
+pub fn token_id(token: &str) -> String {
+    data_encoding::HEXLOWER.encode(&Sha256::digest(token.as_bytes())[..8])
+}
+
+/// Append an entry to the audit trail of `doc_id`, if the server keeps audit trails.
+/// Failing to write it is logged, not returned: the action already happened.
+pub async fn record_audit(
+    server_state: &Server,
+    doc_id: &str,
+    action: &str,
+    principal: AuditPrincipal,
+    detail: Option<serde_json::Value>,
+) {
+    if !server_state.audit() {
+        return;
+    }
+    let Some(store) = &server_state.store else {
+        return;
+    };
+    let entry = AuditEntry {
+        time: current_time_epoch_millis(),
+        action: action.to_string(),
+        principal: principal.principal.to_string(),
+        token_id: principal.token_id,
+        detail,
+    };
+    let key = format!(
+        "{}{:013}-{:010}-{}",
+        audit_prefix(doc_id),
+        entry.time,
+        AUDIT_SEQUENCE.fetch_add(1, Ordering::Relaxed),
+        nanoid::nanoid!(8)
+    );
+    let value = serde_json::to_vec(&entry).expect("audit entries serialize");
+    if let Err(e) = store.set(&key, value).await {
+        error!(
+            message = "Failed to write audit entry",
+            event = "audit_write_failed",
+            doc_id = %doc_id,
+            action = %action,
+            error = %e
+        );
+    }
+}
+
+/// Remove the entries a copy of `source_doc_id` brought along to `destination_doc_id`,
+/// so that the copy starts a trail of its own.
+pub async fn remove_copied_audit(
+    store: &dyn Store,
+    source_doc_id: &str,
+    destination_doc_id: &str,
+) -> Result<(), StoreError> {
+    let copied = match store.list_objects(&audit_prefix(source_doc_id)).await {
+        Ok(names) => names,
+        Err(StoreError::DoesNotExist(_)) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let prefix = audit_prefix(destination_doc_id);
+    for name in copied {
+        match store.remove(&format!("{}{}", prefix, name)).await {
+            Ok(()) | Err(StoreError::DoesNotExist(_)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// A page of the audit trail of `doc_id`: at most `limit` entries after `cursor`.
+pub async fn read_audit(
+    store: &dyn Store,
+    doc_id: &str,
+    cursor: Option<&str>,
+    limit: usize,
+) -> Result<AuditResponse, StoreError> {
+    let mut names = match store.list_objects(&audit_prefix(doc_id)).await {
+        Ok(names) => names,
+        Err(StoreError::DoesNotExist(_)) => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    names.sort();
+    let names: Vec<String> = names
+        .into_iter()
+        .filter(|name| cursor.is_none_or(|cursor| name.as_str() > cursor))
+        .collect();
+
+    let mut entries = Vec::new();
+    for name in names.iter().take(limit) {
+        let Some(value) = store
+            .get(&format!("{}{}", audit_prefix(doc_id), name))
+            .await?
+        else {
+            continue;
+        };
+        match serde_json::from_slice(&value) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => error!(
+                message = "Skipping unreadable audit entry",
+                event = "audit_read_failed",
+                doc_id = %doc_id,
+                entry = %name,
+                error = %e
+            ),
+        }
+    }
+    let next_cursor = (names.len() > limit).then(|| names[limit - 1].clone());
+    Ok(AuditResponse {
+        entries,
+        next_cursor,
+    })
+}
+
+#[derive(Deserialize)]
+pub struct AuditParams {
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
+/// List the audit trail of a document, oldest entries first
+pub async fn get_document_audit(
+    Path(doc_id): Path<String>,
+    State(server_state): State<Arc<Server>>,
+    Query(params): Query<AuditParams>,
+    auth_header: Option<BearerHeader>,
+) -> Result<Json<AuditResponse>, AppError> {
+    server_state.check_auth(auth_header)?;
+
+    if !validate_doc_name(&doc_id) {
+        return Err(AppError(
+            StatusCode::BAD_REQUEST,
+            anyhow!("Invalid document ID"),
+        ));
+    }
+    let Some(store) = &server_state.store else {
+        return Err(AppError(
+            StatusCode::BAD_REQUEST,
+            anyhow!("Audit trails require a store"),
+        ));
+    };
+
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_AUDIT_PAGE_SIZE)
+        .clamp(1, MAX_AUDIT_PAGE_SIZE);
+    let page = read_audit(
+        store.as_ref().as_ref(),
+        &doc_id,
+        params.cursor.as_deref(),
+        limit,
+    )
+    .await
+    .map_err(|e| {
+        AppError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            anyhow!("Failed to read the audit trail: {}", e),
+        )
+    })?;
+    Ok(Json(page))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stores::memory::MemoryStore;
+    use axum::{
+        body::Body,
+        http::{header, Request},
+        response::Response,
+    };
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+    use y_sweet_core::{api_types::ClientToken, auth::Authenticator};
+    use yrs::{Doc, ReadTxn, StateVector, Text, Transact};
+
+    async fn send(
+        server: &Arc<Server>,
+        method: &str,
+        path: &str,
+        token: &str,
+        body: Body,
+    ) -> Response {
+        let request = Request::builder()
+            .method(method)
+            .uri(path)
+            .header(header::HOST, "localhost")
+            .header(header::AUTHORIZATION, format!("Bearer {}", token))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .unwrap();
+        server.routes().oneshot(request).await.unwrap()
+    }
+
+    async fn json<T: serde::de::DeserializeOwned>(response: Response) -> T {
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_audit_trail_is_recorded_and_paged() {
+        let auth = Authenticator::gen_key().unwrap();
+        let server_token = auth.server_token();
+        let server = Arc::new(
+            Server::builder()
+                .store(Some(Box::new(MemoryStore::new())))
+                .authenticator(Some(auth))
+                .build()
+                .await
+                .unwrap()
+                .with_audit(),
+        );
+
+        let body = Body::from(r#"{"docId": "audited"}"#);
+        let response = send(&server, "POST", "/doc/new", &server_token, body).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send(
+            &server,
+            "POST",
+            "/doc/audited/auth",
+            &server_token,
+            Body::empty(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let client_token: ClientToken = json(response).await;
+        let doc_token = client_token.token.unwrap();
+
+        let doc = Doc::new();
+        let text = doc.get_or_insert_text("text");
+        text.insert(&mut doc.transact_mut(), 0, "hello");
+        let update = doc
+            .transact()
+            .encode_state_as_update_v1(&StateVector::default());
+        let response = send(
+            &server,
+            "POST",
+            "/d/audited/update",
+            &doc_token,
+            update.into(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send(
+            &server,
+            "DELETE",
+            "/d/audited",
+            &server_token,
+            Body::empty(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Deleting the document keeps its trail, which pages one entry at a time
+        let mut entries = Vec::new();
+        let mut path = "/d/audited/audit?limit=1".to_string();
+        loop {
+            let response = send(&server, "GET", &path, &server_token, Body::empty()).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let page: AuditResponse = json(response).await;
+            assert!(page.entries.len() <= 1);
+            entries.extend(page.entries);
+            match page.next_cursor {
+                Some(cursor) => path = format!("/d/audited/audit?limit=1&cursor={}", cursor),
+                None => break,
+            }
+        }
+
+        let actions: Vec<&str> = entries.iter().map(|e| e.action.as_str()).collect();
+        assert_eq!(
+            actions,
+            vec!["token_issued", "update_applied", "document_deleted"]
+        );
+        assert_eq!(entries[0].principal, "server");
+        assert_eq!(entries[0].token_id, Some(token_id(&server_token)));
+        assert_eq!(entries[1].principal, "doc:full");
+        assert_eq!(entries[1].token_id, Some(token_id(&doc_token)));
+        assert!(entries.windows(2).all(|w| w[0].time <= w[1].time));
+
+        // Only the server token may read the trail
+        let response = send(
+            &server,
+            "GET",
+            "/d/audited/audit",
+            &doc_token,
+            Body::empty(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,130 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use y_sweet_core::sync::{awareness::Awareness, Message, SyncMessage};
+use yrs::{updates::encoder::Encode, ReadTxn, StateVector, Transact};
+
+/// Default number of outgoing messages buffered per WebSocket connection.
+pub const DEFAULT_WS_SEND_BUFFER: usize = 1024;
+
+/// WebSocket close code telling the client its state is stale and it must reconnect.
+pub const RESYNC_REQUIRED_CLOSE_CODE: u16 = 4000;
+
+/// What to do with a connection whose send buffer overflowed, i.e. a client that reads
+/// slower than the document changes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SlowConsumerPolicy {
+    /// Close the connection with [RESYNC_REQUIRED_CLOSE_CODE].
+    #[default]
+    Close,
+    /// Keep the connection and send the full document state once the client has caught up.
+    Resync,
+}
+
+/// How the writer of a connection recovers from dropped messages.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SlowConsumerAction {
+    Close,
+    Resync,
+}
+
+/// Records that messages to a connection were dropped.
+#[derive(Default)]
+pub struct SendOverflow {
+    doc: AtomicBool,
+    subdoc: AtomicBool,
+}
+
+impl SendOverflow {
+    /// A message of the document itself was dropped.
+    pub fn mark(&self) {
+        self.doc.store(true, Ordering::SeqCst);
+    }
+
+    /// A message of a subdocument was dropped. Subdocuments are not resent, so this
+    /// always closes the connection.
+    pub fn mark_subdoc(&self) {
+        self.subdoc.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.doc.load(Ordering::SeqCst) || self.subdoc.load(Ordering::SeqCst)
+    }
+
+    /// Clear the overflow and decide how to recover from it.
+    pub fn take(&self, policy: SlowConsumerPolicy) -> Option<SlowConsumerAction> {
+        let doc = self.doc.swap(false, Ordering::SeqCst);
+        let subdoc = self.subdoc.swap(false, Ordering::SeqCst);
+        match (doc, subdoc, policy) {
+            (false, false, _) => None,
+            (_, true, _) | (_, _, SlowConsumerPolicy::Close) => Some(SlowConsumerAction::Close),
+            (true, false, SlowConsumerPolicy::Resync) => Some(SlowConsumerAction::Resync),
+        }
+    }
+}
+
+/// Messages bringing a client that missed updates back in sync: the full document state
+/// and the current awareness states.
+pub fn resync_messages(awareness: &Awareness) -> Vec<Vec<u8>> {
+    let update = awareness
+        .doc()
+        .transact()
+        .encode_state_as_update_v1(&StateVector::default());
+    let mut messages = vec![Message::Sync(SyncMessage::Update(update)).encode_v1()];
+    if let Ok(update) = awareness.update() {
+        messages.push(Message::Awareness(update).encode_v1());
+    }
+    messages
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use yrs::{updates::decoder::Decode, Doc, GetString, Text, Update};
+
+    #[test]
+    fn test_overflow_actions() {
+        let overflow = SendOverflow::default();
+        assert!(!overflow.is_set());
+        assert_eq!(overflow.take(SlowConsumerPolicy::Resync), None);
+
+        overflow.mark();
+        assert!(overflow.is_set());
+        assert_eq!(
+            overflow.take(SlowConsumerPolicy::Resync),
+            Some(SlowConsumerAction::Resync)
+        );
+        assert!(!overflow.is_set());
+
+        overflow.mark();
+        assert_eq!(
+            overflow.take(SlowConsumerPolicy::Close),
+            Some(SlowConsumerAction::Close)
+        );
+
+        overflow.mark_subdoc();
+        assert_eq!(
+            overflow.take(SlowConsumerPolicy::Resync),
+            Some(SlowConsumerAction::Close)
+        );
+    }
+
+    #[test]
+    fn test_resync_messages_contain_full_state() {
+        let doc = Doc::new();
+        let text = doc.get_or_insert_text("text");
+        text.insert(&mut doc.transact_mut(), 0, "hello");
+        let awareness = Awareness::new(doc);
+
+        let messages = resync_messages(&awareness);
+        let Message::Sync(SyncMessage::Update(update)) = Message::decode_v1(&messages[0]).unwrap()
+        else {
+            panic!("expected a sync update");
+        };
+
+        let replica = Doc::new();
+        let replica_text = replica.get_or_insert_text("text");
+        replica
+            .transact_mut()
+            .apply_update(Update::decode_v1(&update).unwrap());
+        assert_eq!(replica_text.get_string(&replica.transact()), "hello");
+    }
+}
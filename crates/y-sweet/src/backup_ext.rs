@@ -0,0 +1,292 @@
+//! Backup archives of the documents of a store.
+//!
+//! A backup is a zstd-compressed tar archive holding every object of each document
+//! (snapshot, shards, update log history, metadata, subdocuments and assets) under
+//! `objects/{key}`, followed by `manifest.json`, which lists the objects of each document
+//! with their size and SHA-256 hash. The objects of a document are archived with its
+//! snapshot last, so that restoring stops short of a snapshot, which makes the document
+//! visible, if the archive is cut off midway.
+//!
+//! Restoring reads the archive twice: once to check every object against the manifest,
+//! then to write the objects to the store, so that a damaged archive writes nothing.
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{Read, Write},
+    path::Path,
+    sync::Arc,
+};
+use y_sweet_core::{snapshot_ext::snapshot_key, store::Store};
+
+use crate::server::current_time_epoch_millis;
+use crate::store_copy_ext::list_doc_objects;
+
+/// Format version written in the manifest of new backups.
+pub const BACKUP_FORMAT_VERSION: u32 = 1;
+
+const MANIFEST_PATH: &str = "manifest.json";
+const OBJECTS_DIR: &str = "objects/";
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupManifest {
+    pub format_version: u32,
+    /// Epoch millis at which the backup was made.
+    pub created_at: u64,
+    pub documents: Vec<BackupDoc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupDoc {
+    pub doc_id: String,
+    pub objects: Vec<BackupObject>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupObject {
+    pub key: String,
+    pub bytes: usize,
+    /// Hex SHA-256 hash of the object.
+    pub sha256: String,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupRestoreReport {
+    pub restored: Vec<String>,
+    /// Documents that already exist in the store, left as they are.
+    pub skipped: Vec<String>,
+    pub objects: usize,
+    pub bytes: usize,
+}
+
+fn sha256_hex(value: &[u8]) -> String {
+    data_encoding::HEXLOWER.encode(&Sha256::digest(value))
+}
+
+/// Archive every object of `doc_ids` to `out`, calling `progress` with each document
+/// archived and the number of documents archived so far.
+pub async fn write_backup(
+    store: &Arc<Box<dyn Store>>,
+    doc_ids: Vec<String>,
+    out: &Path,
+    progress: impl Fn(&BackupDoc, usize),
+) -> Result<BackupManifest> {
+    let file = File::create(out).with_context(|| format!("Failed to create {:?}", out))?;
+    let mut archive = tar::Builder::new(zstd::Encoder::new(file, ZSTD_LEVEL)?);
+    let mut manifest = BackupManifest {
+        format_version: BACKUP_FORMAT_VERSION,
+        created_at: current_time_epoch_millis(),
+        documents: Vec::with_capacity(doc_ids.len()),
+    };
+
+    for doc_id in doc_ids {
+        let snapshot = snapshot_key(&doc_id);
+        let mut keys = list_doc_objects(store.as_ref().as_ref(), &doc_id)
+            .await
+            .with_context(|| format!("Failed to list the objects of {}", doc_id))?;
+        keys.retain(|key| key != &snapshot);
+        keys.push(snapshot);
+
+        let mut doc = BackupDoc {
+            doc_id,
+            objects: Vec::with_capacity(keys.len()),
+        };
+        for key in keys {
+            // Removed since it was listed
+            let Some(value) = store.get(&key).await? else {
+                continue;
+            };
+            append(&mut archive, &format!("{}{}", OBJECTS_DIR, key), &value)?;
+            doc.objects.push(BackupObject {
+                sha256: sha256_hex(&value),
+                bytes: value.len(),
+                key,
+            });
+        }
+        progress(&doc, manifest.documents.len() + 1);
+        manifest.documents.push(doc);
+    }
+
+    append(
+        &mut archive,
+        MANIFEST_PATH,
+        &serde_json::to_vec_pretty(&manifest)?,
+    )?;
+    archive.into_inner()?.finish()?.flush()?;
+    Ok(manifest)
+}
+
+fn append(archive: &mut tar::Builder<impl Write>, path: &str, value: &[u8]) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(value.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(current_time_epoch_millis() / 1000);
+    archive
+        .append_data(&mut header, path, value)
+        .with_context(|| format!("Failed to archive {}", path))
+}
+
+/// The path and content of an archive entry.
+fn read_entry(entry: std::io::Result<tar::Entry<impl Read>>) -> Result<(String, Vec<u8>)> {
+    let mut entry = entry.context("The archive is damaged")?;
+    let path = entry
+        .path()?
+        .to_str()
+        .ok_or_else(|| anyhow!("The archive holds a path that is not UTF-8"))?
+        .to_string();
+    let mut value = Vec::with_capacity(entry.size() as usize);
+    entry
+        .read_to_end(&mut value)
+        .with_context(|| format!("Failed to read {}", path))?;
+    Ok((path, value))
+}
+
+/// Read the manifest of the backup at `path`, after checking every object it lists.
+pub fn read_backup(path: &Path) -> Result<BackupManifest> {
+    let file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let mut archive = tar::Archive::new(zstd::Decoder::new(file)?);
+    let mut hashes = HashMap::new();
+    let mut manifest = None;
+    for entry in archive.entries()? {
+        let (entry_path, value) = read_entry(entry)?;
+        if entry_path == MANIFEST_PATH {
+            manifest = Some(
+                serde_json::from_slice::<BackupManifest>(&value)
+                    .context("The backup manifest cannot be parsed")?,
+            );
+        } else if let Some(key) = entry_path.strip_prefix(OBJECTS_DIR) {
+            hashes.insert(key.to_string(), sha256_hex(&value));
+        }
+    }
+
+    let manifest = manifest.context("The archive has no manifest")?;
+    if manifest.format_version > BACKUP_FORMAT_VERSION {
+        bail!(
+            "Backup format version {} is not supported (latest is {})",
+            manifest.format_version,
+            BACKUP_FORMAT_VERSION
+        );
+    }
+    for object in manifest.documents.iter().flat_map(|doc| &doc.objects) {
+        match hashes.get(&object.key) {
+            Some(hash) if hash == &object.sha256 => {}
+            Some(_) => bail!("{} does not match its hash in the manifest", object.key),
+            None => bail!("{} is missing from the archive", object.key),
+        }
+    }
+    Ok(manifest)
+}
+
+/// Write the documents of the backup at `path` to `store`. Documents that already exist
+/// in the store are skipped, unless `overwrite` is set, in which case their objects are
+/// removed first.
+pub async fn restore_backup(
+    store: &Arc<Box<dyn Store>>,
+    path: &Path,
+    overwrite: bool,
+) -> Result<BackupRestoreReport> {
+    let manifest = read_backup(path)?;
+
+    let mut report = BackupRestoreReport::default();
+    for doc in &manifest.documents {
+        if store.exists(&snapshot_key(&doc.doc_id)).await? {
+            if !overwrite {
+                report.skipped.push(doc.doc_id.clone());
+                continue;
+            }
+            for key in list_doc_objects(store.as_ref().as_ref(), &doc.doc_id).await? {
+                store.remove(&key).await?;
+            }
+        }
+        report.restored.push(doc.doc_id.clone());
+    }
+    let restored: HashSet<&str> = report.restored.iter().map(String::as_str).collect();
+    let keys: HashSet<&str> = manifest
+        .documents
+        .iter()
+        .filter(|doc| restored.contains(doc.doc_id.as_str()))
+        .flat_map(|doc| doc.objects.iter().map(|object| object.key.as_str()))
+        .collect();
+
+    let file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let mut archive = tar::Archive::new(zstd::Decoder::new(file)?);
+    for entry in archive.entries()? {
+        let (entry_path, value) = read_entry(entry)?;
+        let Some(key) = entry_path.strip_prefix(OBJECTS_DIR) else {
+            continue;
+        };
+        if !keys.contains(key) {
+            continue;
+        }
+        report.objects += 1;
+        report.bytes += value.len();
+        store
+            .set(key, value)
+            .await
+            .with_context(|| format!("Failed to write {}", key))?;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stores::filesystem::FileSystemStore;
+    use crate::verify_ext::verify_doc;
+    use y_sweet_core::doc_sync_ext::DocWithSyncKv;
+    use yrs::{Text, Transact};
+
+    fn store(path: &Path) -> Arc<Box<dyn Store>> {
+        Arc::new(Box::new(FileSystemStore::new(path.to_path_buf()).unwrap()))
+    }
+
+    #[tokio::test]
+    async fn test_backup_round_trip() {
+        let dir = std::env::temp_dir().join(format!("y-sweet-backup-{}", nanoid::nanoid!()));
+        let source = store(&dir.join("source"));
+        let doc = DocWithSyncKv::new("doc", Some(source.clone()), || {}, false)
+            .await
+            .unwrap();
+        {
+            let awareness = doc.awareness();
+            let awareness = awareness.write().unwrap();
+            let text = awareness.doc().get_or_insert_text("text");
+            text.insert(&mut awareness.doc().transact_mut(), 0, "hello");
+        }
+        doc.sync_kv().persist().await.unwrap();
+        source
+            .set("doc/assets/image.png", b"image".to_vec())
+            .await
+            .unwrap();
+
+        let archive = dir.join("backup.tar.zst");
+        let manifest = write_backup(&source, vec!["doc".to_string()], &archive, |_, _| {})
+            .await
+            .unwrap();
+        assert_eq!(manifest.documents[0].objects.len(), 2);
+        assert_eq!(
+            manifest.documents[0].objects.last().unwrap().key,
+            "doc/data.ysweet"
+        );
+
+        let target = store(&dir.join("target"));
+        let report = restore_backup(&target, &archive, false).await.unwrap();
+        assert_eq!(report.restored, vec!["doc".to_string()]);
+        assert!(verify_doc(&target, "doc").await.ok);
+        assert_eq!(
+            target.get("doc/assets/image.png").await.unwrap(),
+            Some(b"image".to_vec())
+        );
+
+        let report = restore_backup(&target, &archive, false).await.unwrap();
+        assert_eq!(report.skipped, vec!["doc".to_string()]);
+    }
+}
@@ -0,0 +1,405 @@
+//! Load testing of a running server.
+//!
+//! `y-sweet bench` connects synthetic clients to a number of documents over WebSocket, the
+//! way the Yjs WebSocket provider does. Each client types into a shared text, replaces
+//! some of it, sets map entries and moves its cursor in awareness, at a fixed rate.
+//!
+//! Every edit inserts at least one item, so the client ID and clock reached by an update
+//! identify it: when another client of the document receives it, the time since it was sent
+//! is recorded as its latency, and updates that never reach some of the other clients are
+//! counted as dropped. With the PID of a server running on the same (Linux) host, its CPU
+//! time and memory are sampled from `/proc` during the run.
+
+use anyhow::{Context, Result};
+use dashmap::DashMap;
+use futures::{SinkExt, StreamExt};
+use serde::Serialize;
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::task::JoinSet;
+use tokio_tungstenite::tungstenite;
+use tracing::warn;
+use url::Url;
+use y_sweet_core::{
+    api_types::Authorization,
+    auth::{Authenticator, ExpirationTimeEpochMillis},
+    sync::{awareness::Awareness, Message, MessageReader, SyncMessage},
+};
+use yrs::{
+    encoding::read::Cursor,
+    updates::{
+        decoder::{Decode, DecoderV1},
+        encoder::Encode,
+    },
+    Doc, Map, ReadTxn, StateVector, Text, Transact, Update,
+};
+
+use crate::server::current_time_epoch_millis;
+use crate::tail_ext::socket_url;
+
+/// Time given to updates in flight to arrive once clients stop editing.
+const DRAIN_PERIOD: Duration = Duration::from_secs(2);
+
+/// Interval between samples of the server process.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Clients move their cursor in awareness every this many edits.
+const AWARENESS_EVERY: u64 = 5;
+
+pub struct BenchOptions {
+    /// URL of the server.
+    pub url: Url,
+    pub docs: usize,
+    /// Number of clients editing each document. At least two, for updates to be received.
+    pub clients_per_doc: usize,
+    /// Edits per second made by each client.
+    pub ops_rate: f64,
+    pub duration: Duration,
+    /// The server's auth key, to sign client tokens with, if the server has auth enabled.
+    pub auth: Option<Authenticator>,
+    /// Prefix of the IDs of the documents created for the run.
+    pub doc_prefix: String,
+    /// PID of the server process, if it runs on this host.
+    pub server_pid: Option<u32>,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct LatencyPercentiles {
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerImpact {
+    /// CPU time used by the server during the run.
+    pub cpu_seconds: f64,
+    pub rss_start_bytes: u64,
+    pub rss_end_bytes: u64,
+    pub rss_peak_bytes: u64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct BenchReport {
+    pub docs: usize,
+    pub clients: usize,
+    /// Clients that failed to connect, or whose connection failed during the run.
+    pub failed_clients: usize,
+    /// Edits sent by every client.
+    pub sent: u64,
+    pub sent_per_second: f64,
+    /// Updates received by other clients of their document.
+    pub delivered: u64,
+    /// Updates that did not reach every other client of their document.
+    pub dropped: u64,
+    pub latency: LatencyPercentiles,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server: Option<ServerImpact>,
+}
+
+#[derive(Default)]
+struct BenchStats {
+    /// When each update was sent, by the client and clock it reached.
+    sent_at: DashMap<(u64, u32), Instant>,
+    sent: AtomicU64,
+    delivered: AtomicU64,
+    failed_clients: AtomicUsize,
+    latencies: Mutex<Vec<Duration>>,
+}
+
+/// A small xorshift generator: edits only need to vary, not to be unpredictable.
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: u32) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n.max(1) as u64) as u32
+    }
+}
+
+/// Make an edit to `doc`, returning it encoded as an update.
+fn edit(doc: &Doc, rng: &mut Rng) -> Vec<u8> {
+    const WORDS: [&str; 6] = ["the ", "quick ", "brown ", "fox ", "jumps ", "\n"];
+    let text = doc.get_or_insert_text("content");
+    let meta = doc.get_or_insert_map("meta");
+    let mut txn = doc.transact_mut();
+    let len = text.len(&txn);
+    let word = WORDS[rng.below(WORDS.len() as u32) as usize];
+    match rng.below(100) {
+        // Typing at some position
+        0..=59 => text.insert(&mut txn, rng.below(len + 1), word),
+        // Replacing a few characters
+        60..=84 => {
+            let index = rng.below(len + 1);
+            let removed = rng.below(4).min(len - index);
+            text.remove_range(&mut txn, index, removed);
+            text.insert(&mut txn, index, word);
+        }
+        // Changing a setting
+        _ => {
+            let key = format!("setting-{}", rng.below(10));
+            meta.insert(&mut txn, key, rng.below(1000) as f64);
+        }
+    }
+    txn.encode_update_v1()
+}
+
+async fn run_client(
+    url: Url,
+    stats: Arc<BenchStats>,
+    ops_rate: f64,
+    editing_until: Instant,
+) -> Result<()> {
+    let (socket, _) = tokio_tungstenite::connect_async(url.as_str())
+        .await
+        .context("Failed to connect")?;
+    let (mut sink, mut stream) = socket.split();
+
+    let mut awareness = Awareness::new(Doc::new());
+    let client_id = awareness.doc().client_id();
+    let mut rng = Rng(client_id | 1);
+    let sync_step_1 = Message::Sync(SyncMessage::SyncStep1(StateVector::default())).encode_v1();
+    sink.send(tungstenite::Message::Binary(sync_step_1)).await?;
+
+    let mut ticks = tokio::time::interval(Duration::from_secs_f64(1.0 / ops_rate));
+    let drained = tokio::time::sleep_until((editing_until + DRAIN_PERIOD).into());
+    tokio::pin!(drained);
+    let mut edits = 0;
+    loop {
+        tokio::select! {
+            _ = ticks.tick(), if Instant::now() < editing_until => {
+                let update = edit(awareness.doc(), &mut rng);
+                let clock = awareness.doc().transact().state_vector().get(&client_id);
+                stats.sent_at.insert((client_id, clock), Instant::now());
+                stats.sent.fetch_add(1, Ordering::Relaxed);
+                let message = Message::Sync(SyncMessage::Update(update)).encode_v1();
+                sink.send(tungstenite::Message::Binary(message)).await?;
+
+                edits += 1;
+                if edits % AWARENESS_EVERY == 0 {
+                    let cursor = rng.below(1000);
+                    awareness.set_local_state(format!(r#"{{"cursor":{}}}"#, cursor));
+                    let message = Message::Awareness(awareness.update()?).encode_v1();
+                    sink.send(tungstenite::Message::Binary(message)).await?;
+                }
+            }
+            message = stream.next() => {
+                let Some(message) = message else {
+                    anyhow::bail!("The server closed the connection");
+                };
+                let tungstenite::Message::Binary(payload) = message? else {
+                    continue;
+                };
+                let mut decoder = DecoderV1::new(Cursor::new(&payload));
+                for message in MessageReader::new(&mut decoder) {
+                    match message? {
+                        Message::Sync(SyncMessage::SyncStep2(update)) => {
+                            awareness.doc().transact_mut().apply_update(Update::decode_v1(&update)?);
+                        }
+                        Message::Sync(SyncMessage::Update(update)) => {
+                            let update = Update::decode_v1(&update)?;
+                            for (client, clock) in update.state_vector().iter() {
+                                if *client == client_id {
+                                    continue;
+                                }
+                                if let Some(sent_at) = stats.sent_at.get(&(*client, *clock)) {
+                                    stats.delivered.fetch_add(1, Ordering::Relaxed);
+                                    stats.latencies.lock().unwrap().push(sent_at.elapsed());
+                                }
+                            }
+                            awareness.doc().transact_mut().apply_update(update);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            _ = &mut drained => break,
+        }
+    }
+    let _ = sink.close().await;
+    Ok(())
+}
+
+fn percentiles(mut latencies: Vec<Duration>) -> LatencyPercentiles {
+    if latencies.is_empty() {
+        return LatencyPercentiles::default();
+    }
+    latencies.sort_unstable();
+    let at = |q: f64| {
+        let index = ((latencies.len() - 1) as f64 * q).round() as usize;
+        latencies[index].as_secs_f64() * 1000.0
+    };
+    LatencyPercentiles {
+        p50_ms: at(0.5),
+        p90_ms: at(0.9),
+        p99_ms: at(0.99),
+        max_ms: at(1.0),
+    }
+}
+
+/// CPU time and resident memory of a process, from `/proc`.
+struct ProcessSample {
+    cpu_seconds: f64,
+    rss_bytes: u64,
+}
+
+impl ProcessSample {
+    fn read(pid: u32) -> Result<Self> {
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid))
+            .with_context(|| format!("Failed to read the stats of process {}", pid))?;
+        // Fields after the command name, which may hold spaces, start with the state
+        let fields: Vec<&str> = stat
+            .rsplit_once(')')
+            .map(|(_, rest)| rest.split_whitespace().collect())
+            .unwrap_or_default();
+        let ticks = |index: usize| -> u64 {
+            fields
+                .get(index)
+                .and_then(|field| field.parse().ok())
+                .unwrap_or_default()
+        };
+        // utime and stime, in clock ticks, which are 100 per second on Linux
+        let cpu_seconds = (ticks(11) + ticks(12)) as f64 / 100.0;
+        // rss, in pages of 4 KiB
+        let rss_bytes = ticks(21) * 4096;
+        Ok(Self {
+            cpu_seconds,
+            rss_bytes,
+        })
+    }
+}
+
+/// Run the benchmark described by `options`.
+pub async fn run_bench(options: BenchOptions) -> Result<BenchReport> {
+    let stats = Arc::new(BenchStats::default());
+    let started = Instant::now();
+    let editing_until = started + options.duration;
+    let server_start = options.server_pid.map(ProcessSample::read).transpose()?;
+    let rss_peak = Arc::new(AtomicU64::new(
+        server_start
+            .as_ref()
+            .map(|s| s.rss_bytes)
+            .unwrap_or_default(),
+    ));
+
+    let mut clients = JoinSet::new();
+    let token_expiration = current_time_epoch_millis()
+        + options.duration.as_millis() as u64
+        + DRAIN_PERIOD.as_millis() as u64
+        + 60_000;
+    for doc in 0..options.docs {
+        let doc_id = format!("{}{}", options.doc_prefix, doc);
+        // Full access, so that the first client creates the document
+        let token = options.auth.as_ref().map(|auth| {
+            auth.gen_doc_token(
+                &doc_id,
+                Authorization::Full,
+                ExpirationTimeEpochMillis(token_expiration),
+            )
+        });
+        let url = socket_url(&options.url, &doc_id, token.as_deref())?;
+        for _ in 0..options.clients_per_doc {
+            let stats = stats.clone();
+            let url = url.clone();
+            let ops_rate = options.ops_rate;
+            clients.spawn(async move {
+                if let Err(e) = run_client(url, stats.clone(), ops_rate, editing_until).await {
+                    stats.failed_clients.fetch_add(1, Ordering::Relaxed);
+                    warn!(
+                        message = "Bench client failed",
+                        event = "bench_client_failed",
+                        error = format!("{:#}", e)
+                    );
+                }
+            });
+        }
+    }
+
+    let sampler = options.server_pid.map(|pid| {
+        let rss_peak = rss_peak.clone();
+        tokio::spawn(async move {
+            let mut samples = tokio::time::interval(SAMPLE_INTERVAL);
+            loop {
+                samples.tick().await;
+                if let Ok(sample) = ProcessSample::read(pid) {
+                    rss_peak.fetch_max(sample.rss_bytes, Ordering::Relaxed);
+                }
+            }
+        })
+    });
+    while clients.join_next().await.is_some() {}
+    if let Some(sampler) = sampler {
+        sampler.abort();
+    }
+
+    let server = match (options.server_pid, server_start) {
+        (Some(pid), Some(start)) => {
+            let end = ProcessSample::read(pid)?;
+            Some(ServerImpact {
+                cpu_seconds: end.cpu_seconds - start.cpu_seconds,
+                rss_start_bytes: start.rss_bytes,
+                rss_end_bytes: end.rss_bytes,
+                rss_peak_bytes: rss_peak.load(Ordering::Relaxed).max(end.rss_bytes),
+            })
+        }
+        _ => None,
+    };
+
+    let sent = stats.sent.load(Ordering::Relaxed);
+    let delivered = stats.delivered.load(Ordering::Relaxed);
+    let expected = sent * (options.clients_per_doc as u64 - 1);
+    let latencies = std::mem::take(&mut *stats.latencies.lock().unwrap());
+    Ok(BenchReport {
+        docs: options.docs,
+        clients: options.docs * options.clients_per_doc,
+        failed_clients: stats.failed_clients.load(Ordering::Relaxed),
+        sent,
+        sent_per_second: sent as f64 / options.duration.as_secs_f64(),
+        delivered,
+        dropped: expected.saturating_sub(delivered),
+        latency: percentiles(latencies),
+        server,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use yrs::GetString;
+
+    #[test]
+    fn test_every_edit_advances_the_clock() {
+        let doc = Doc::new();
+        let client_id = doc.client_id();
+        let mut rng = Rng(client_id | 1);
+        for i in 1..=100 {
+            let before = doc.transact().state_vector().get(&client_id);
+            let update = Update::decode_v1(&edit(&doc, &mut rng)).unwrap();
+            let after = doc.transact().state_vector().get(&client_id);
+            assert!(after > before, "edit {} did not insert", i);
+            assert_eq!(update.state_vector().get(&client_id), after);
+        }
+        let text = doc.get_or_insert_text("content");
+        assert!(!text.get_string(&doc.transact()).is_empty());
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let latencies = (1..=100).map(Duration::from_millis).collect();
+        let percentiles = percentiles(latencies);
+        assert_eq!(percentiles.p50_ms, 51.0);
+        assert_eq!(percentiles.p99_ms, 99.0);
+        assert_eq!(percentiles.max_ms, 100.0);
+    }
+}
@@ -0,0 +1,273 @@
+//! Limits on the size of request bodies, by kind of route.
+//!
+//! `--max-body-size` limits the body of every request (axum's default of 2 MB applies
+//! without it). Document updates, imports and the asset routes can be given limits of
+//! their own, smaller or larger, with `--max-update-body-size`, `--max-import-body-size`
+//! and `--max-asset-body-size`. Larger bodies are refused with 413 Payload Too Large: up
+//! front, with the limit in the message, when they declare their `Content-Length`, and as
+//! soon as the limit is reached otherwise.
+//!
+//! The limits apply where handlers read the body. Middleware reading bodies before them,
+//! such as the logging of request bodies, only reads their first bytes with
+//! [peek_body] and passes the rest on as it streams in, so that no body is buffered whole
+//! before its limit is checked.
+
+use anyhow::anyhow;
+use axum::{
+    body::{Body, Bytes},
+    extract::{DefaultBodyLimit, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::MethodRouter,
+};
+use futures::StreamExt;
+use http_body_util::BodyExt;
+use std::convert::Infallible;
+
+use crate::server::AppError;
+
+/// Largest request bodies accepted, in bytes, by kind of route. Routes without a limit of
+/// their own use the limit of every request.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BodyLimits {
+    /// `POST /d/:doc_id/update`
+    pub update: Option<usize>,
+    /// `POST /d/:doc_id/import`
+    pub import: Option<usize>,
+    /// Requests of the asset routes: upload URLs, confirmations, asset tokens and store
+    /// events.
+    pub assets: Option<usize>,
+}
+
+/// The kinds of routes with a body limit of their own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BodyKind {
+    Update,
+    Import,
+    Asset,
+}
+
+impl BodyKind {
+    fn describe(self) -> &'static str {
+        match self {
+            BodyKind::Update => "document updates",
+            BodyKind::Import => "document imports",
+            BodyKind::Asset => "asset requests",
+        }
+    }
+}
+
+impl BodyLimits {
+    /// The limit of the routes of `kind`, if any.
+    pub fn get(&self, kind: BodyKind) -> Option<usize> {
+        match kind {
+            BodyKind::Update => self.update,
+            BodyKind::Import => self.import,
+            BodyKind::Asset => self.assets,
+        }
+    }
+}
+
+/// Limit the request bodies of `route` to the limit of `kind` in `limits`, if any.
+pub fn limit_body<S>(route: MethodRouter<S>, limits: BodyLimits, kind: BodyKind) -> MethodRouter<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let Some(limit) = limits.get(kind) else {
+        return route;
+    };
+    route
+        .layer::<_, Infallible>(middleware::from_fn_with_state(
+            (limit, kind),
+            reject_declared_length,
+        ))
+        .layer(DefaultBodyLimit::max(limit))
+}
+
+/// Refuse a request whose `Content-Length` exceeds the limit before reading its body.
+async fn reject_declared_length(
+    State((limit, kind)): State<(usize, BodyKind)>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let length = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<u64>().ok());
+    match length {
+        Some(length) if length > limit as u64 => AppError(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            anyhow!(
+                "Request body of {} bytes exceeds the limit of {} bytes for {}",
+                length,
+                limit,
+                kind.describe()
+            ),
+        )
+        .into_response(),
+        _ => next.run(request).await,
+    }
+}
+
+/// Read the start of `body`, up to at least `max` bytes unless it is shorter. Returns the
+/// bytes read, whether they are the whole body, and a body with the same content as `body`
+/// (or its error) to pass on.
+pub async fn peek_body(mut body: Body, max: usize) -> (Bytes, bool, Body) {
+    let mut start = Vec::new();
+    while start.len() < max {
+        match body.frame().await {
+            Some(Ok(frame)) => {
+                if let Ok(data) = frame.into_data() {
+                    start.extend_from_slice(&data);
+                }
+            }
+            Some(Err(e)) => {
+                let start = Bytes::from(start);
+                let rest = futures::stream::iter([Ok(start.clone()), Err(e)]);
+                return (start, false, Body::from_stream(rest));
+            }
+            None => {
+                let start = Bytes::from(start);
+                return (start.clone(), true, Body::from(start));
+            }
+        }
+    }
+    let start = Bytes::from(start);
+    let rest = futures::stream::once(futures::future::ready(Ok(start.clone())))
+        .chain(body.into_data_stream());
+    (start, false, Body::from_stream(rest))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::Server;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_bodies_are_limited_by_route() {
+        let server = Arc::new(
+            Server::builder()
+                .max_body_size(Some(1_000))
+                .body_limits(BodyLimits {
+                    update: Some(10),
+                    import: Some(10_000),
+                    assets: None,
+                })
+                .build()
+                .await
+                .unwrap(),
+        );
+        let doc_id = server.create_doc().await.unwrap();
+        let post = |path: &str, body: Vec<u8>, chunked: bool| {
+            let mut builder = Request::builder()
+                .method("POST")
+                .uri(format!("/d/{}/{}", doc_id, path))
+                .header(header::CONTENT_TYPE, "application/json");
+            let body = if chunked {
+                let chunks = futures::stream::iter([Ok::<_, std::io::Error>(body)]);
+                Body::from_stream(chunks)
+            } else {
+                builder = builder.header(header::CONTENT_LENGTH, body.len());
+                Body::from(body)
+            };
+            builder.body(body).unwrap()
+        };
+
+        let app = || server.app(server.routes(), true);
+        let response = app()
+            .oneshot(post("update", vec![0; 11], false))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(String::from_utf8_lossy(&body).contains(
+            "Request body of 11 bytes exceeds the limit of 10 bytes for document updates"
+        ));
+        // Without a declared length, the body is cut at the limit
+        let response = app()
+            .oneshot(post("update", vec![0; 11], true))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Imports may be larger than other requests
+        let mut import = serde_json::to_vec(&serde_json::json!({
+            "type": "doc",
+            "content": [{ "type": "paragraph", "content": [{ "type": "text", "text": "x" }] }]
+        }))
+        .unwrap();
+        import.extend(vec![b' '; 2_000]);
+        let response = app()
+            .oneshot(post("import?format=prosemirror", import.clone(), false))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        #[cfg(feature = "assets")]
+        {
+            let response = app().oneshot(post("assets", import, false)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_chunked_bodies_are_not_read_past_the_limit() {
+        let server = Arc::new(
+            Server::builder()
+                .body_limits(BodyLimits {
+                    update: Some(10),
+                    import: None,
+                    assets: None,
+                })
+                .build()
+                .await
+                .unwrap(),
+        );
+        let doc_id = server.create_doc().await.unwrap();
+
+        // 64 MiB in chunks of 64 KiB, without a Content-Length, counting what is read
+        let read = Arc::new(AtomicUsize::new(0));
+        let chunks = futures::stream::iter(0..1024).map({
+            let read = read.clone();
+            move |_| {
+                read.fetch_add(64 * 1024, Ordering::SeqCst);
+                Ok::<_, std::io::Error>(vec![0u8; 64 * 1024])
+            }
+        });
+        let request = Request::builder()
+            .method("POST")
+            .uri(format!("/d/{}/update", doc_id))
+            .body(Body::from_stream(chunks))
+            .unwrap();
+
+        let response = server
+            .app(server.routes(), true)
+            .oneshot(request)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        // Only what the request log shows, and the chunk over the limit, were read
+        assert!(read.load(Ordering::SeqCst) <= 4 * 64 * 1024);
+    }
+
+    #[tokio::test]
+    async fn test_peek_body() {
+        let chunks = futures::stream::iter([b"abc".to_vec(), b"def".to_vec(), b"gh".to_vec()])
+            .map(Ok::<_, std::io::Error>);
+        let (start, complete, body) = peek_body(Body::from_stream(chunks), 4).await;
+        assert_eq!(&start[..], b"abcdef");
+        assert!(!complete);
+        let bytes = body.collect().await.unwrap().to_bytes();
+        assert_eq!(&bytes[..], b"abcdefgh");
+
+        let (start, complete, body) = peek_body(Body::from("short"), 4_000).await;
+        assert_eq!(&start[..], b"short");
+        assert!(complete);
+        let bytes = body.collect().await.unwrap().to_bytes();
+        assert_eq!(&bytes[..], b"short");
+    }
+}
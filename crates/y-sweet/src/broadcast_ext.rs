@@ -0,0 +1,216 @@
+use dashmap::DashMap;
+use std::sync::{Arc, RwLock, Weak};
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+use y_sweet_core::{
+    doc_connection_ext::{encode_awareness_message, encode_update_message},
+    sync::awareness::Awareness,
+};
+use yrs::Subscription;
+
+/// Number of messages a lagging connection may fall behind before it is disconnected.
+pub const DOC_BROADCAST_CAPACITY: usize = 1024;
+
+/// WebSocket close code telling the client its document was deleted and must not be
+/// reconnected to.
+pub const DOC_DELETED_CLOSE_CODE: u16 = 4404;
+
+/// Fan-out of document and awareness updates to every connection of a document.
+///
+/// Each update is encoded once and shared by all subscribers, instead of every connection
+/// observing the document and encoding it separately.
+pub struct DocBroadcast {
+    doc_id: String,
+    sender: broadcast::Sender<Arc<Vec<u8>>>,
+    registry: Weak<DashMap<String, Weak<DocBroadcast>>>,
+    /// Cancelled when the document is deleted, to close every connection.
+    deleted: CancellationToken,
+    #[allow(unused)] // acts as RAII guard
+    doc_subscription: Subscription,
+    #[allow(unused)] // acts as RAII guard
+    awareness_subscription: Subscription,
+}
+
+impl DocBroadcast {
+    /// Receive the updates made from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Vec<u8>>> {
+        self.sender.subscribe()
+    }
+
+    /// Cancelled once the document is deleted
+    pub fn deleted(&self) -> CancellationToken {
+        self.deleted.clone()
+    }
+}
+
+impl Drop for DocBroadcast {
+    fn drop(&mut self) {
+        // Forget the entry unless a new broadcast has replaced it in the meantime
+        if let Some(registry) = self.registry.upgrade() {
+            registry.remove_if(&self.doc_id, |_, broadcast| broadcast.strong_count() == 0);
+        }
+    }
+}
+
+/// Broadcasts by document ID. Entries are weak: a broadcast lives as long as a connection
+/// uses it, and unsubscribes from the document when the last one closes.
+pub struct DocBroadcasts {
+    broadcasts: Arc<DashMap<String, Weak<DocBroadcast>>>,
+    capacity: usize,
+}
+
+impl Default for DocBroadcasts {
+    fn default() -> Self {
+        Self::with_capacity(DOC_BROADCAST_CAPACITY)
+    }
+}
+
+impl DocBroadcasts {
+    /// Create broadcasts that buffer up to `capacity` messages for a lagging connection
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            broadcasts: Arc::default(),
+            capacity,
+        }
+    }
+
+    /// Get the broadcast of a document, creating it if no connection currently uses one
+    pub fn get_or_create(
+        &self,
+        doc_id: &str,
+        awareness: &Arc<RwLock<Awareness>>,
+    ) -> Arc<DocBroadcast> {
+        // The entry lock makes creation atomic per document
+        let mut entry = self.broadcasts.entry(doc_id.to_string()).or_default();
+        if let Some(broadcast) = entry.upgrade() {
+            return broadcast;
+        }
+
+        let (sender, _) = broadcast::channel(self.capacity);
+        let mut awareness = awareness.write().unwrap();
+
+        let doc_subscription = {
+            let sender = sender.clone();
+            awareness
+                .doc()
+                .observe_update_v1(move |_, event| {
+                    // Fails only when there are no subscribers
+                    let _ = sender.send(Arc::new(encode_update_message(&event.update)));
+                })
+                .unwrap()
+        };
+
+        let awareness_subscription = {
+            let sender = sender.clone();
+            awareness.on_update(move |awareness, e| {
+                if let Some(msg) = encode_awareness_message(awareness, e) {
+                    let _ = sender.send(Arc::new(msg));
+                }
+            })
+        };
+
+        let broadcast = Arc::new(DocBroadcast {
+            doc_id: doc_id.to_string(),
+            sender,
+            registry: Arc::downgrade(&self.broadcasts),
+            deleted: CancellationToken::new(),
+            doc_subscription,
+            awareness_subscription,
+        });
+        *entry = Arc::downgrade(&broadcast);
+        broadcast
+    }
+
+    /// Send an encoded message to every connection of a document. Returns whether it had
+    /// any.
+    pub fn send(&self, doc_id: &str, message: Vec<u8>) -> bool {
+        let broadcast = self
+            .broadcasts
+            .get(doc_id)
+            .and_then(|broadcast| broadcast.upgrade());
+        match broadcast {
+            Some(broadcast) => broadcast.sender.send(Arc::new(message)).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Signal the connections of a deleted document to close. Returns whether it had any.
+    pub fn mark_deleted(&self, doc_id: &str) -> bool {
+        let broadcast = self
+            .broadcasts
+            .get(doc_id)
+            .and_then(|broadcast| broadcast.upgrade());
+        match broadcast {
+            Some(broadcast) => {
+                broadcast.deleted.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether no document has an active broadcast
+    pub fn is_empty(&self) -> bool {
+        self.broadcasts.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use yrs::{Doc, Text, Transact};
+
+    #[tokio::test]
+    async fn test_updates_are_encoded_once_for_all_subscribers() {
+        let awareness = Arc::new(RwLock::new(Awareness::new(Doc::new())));
+        let broadcasts = DocBroadcasts::default();
+
+        let broadcast = broadcasts.get_or_create("doc", &awareness);
+        let same = broadcasts.get_or_create("doc", &awareness);
+        assert!(Arc::ptr_eq(&broadcast, &same));
+
+        let mut first = broadcast.subscribe();
+        let mut second = same.subscribe();
+
+        {
+            let awareness = awareness.read().unwrap();
+            let text = awareness.doc().get_or_insert_text("text");
+            let mut txn = awareness.doc().transact_mut();
+            text.insert(&mut txn, 0, "hello");
+        }
+
+        let a = first.recv().await.unwrap();
+        let b = second.recv().await.unwrap();
+        assert!(Arc::ptr_eq(&a, &b));
+
+        drop(broadcast);
+        drop(same);
+        assert!(broadcasts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_messages_are_sent_to_connections() {
+        let awareness = Arc::new(RwLock::new(Awareness::new(Doc::new())));
+        let broadcasts = DocBroadcasts::default();
+        assert!(!broadcasts.send("doc", vec![1]));
+
+        let broadcast = broadcasts.get_or_create("doc", &awareness);
+        let mut receiver = broadcast.subscribe();
+        assert!(broadcasts.send("doc", vec![1]));
+        assert_eq!(*receiver.recv().await.unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn test_deleted_document_is_signalled() {
+        let awareness = Arc::new(RwLock::new(Awareness::new(Doc::new())));
+        let broadcasts = DocBroadcasts::default();
+        assert!(!broadcasts.mark_deleted("doc"));
+
+        let broadcast = broadcasts.get_or_create("doc", &awareness);
+        let deleted = broadcast.deleted();
+        assert!(!deleted.is_cancelled());
+
+        assert!(broadcasts.mark_deleted("doc"));
+        assert!(deleted.is_cancelled());
+    }
+}
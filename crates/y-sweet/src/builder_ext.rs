@@ -0,0 +1,344 @@
+//! Construction of a [Server] from its options.
+//!
+//! Every option of a [ServerBuilder] has a default, so callers only set the ones they need,
+//! and options added later do not break them the way new parameters of a constructor do.
+//! The builder covers what a server is constructed with: its store, authentication,
+//! checkpoint policy, limits and hooks. Everything else (replication, clustering, update
+//! logs, ...) is set on the built server with its `with_*` methods.
+
+use anyhow::{bail, Result};
+use std::{sync::Arc, time::Duration};
+use tokio_util::sync::CancellationToken;
+use url::Url;
+use y_sweet_core::{
+    auth::Authenticator, auth_ext::AuthProvider, checkpoint_ext::CheckpointTriggers, store::Store,
+    validate_ext::UpdateValidator,
+};
+
+use crate::body_limits_ext::BodyLimits;
+use crate::events_ext::EventHandler;
+use crate::message_limits_ext::MessageLimits;
+use crate::server::Server;
+
+/// How often documents are persisted by default, as with `y-sweet serve`.
+pub const DEFAULT_CHECKPOINT_FREQ: Duration = Duration::from_secs(10);
+
+pub struct ServerBuilder {
+    pub(crate) store: Option<Box<dyn Store>>,
+    pub(crate) checkpoint_freq: Duration,
+    pub(crate) checkpoint_triggers: CheckpointTriggers,
+    pub(crate) authenticator: Option<Authenticator>,
+    pub(crate) auth_provider: Option<Arc<dyn AuthProvider>>,
+    pub(crate) url_prefix: Option<Url>,
+    pub(crate) base_path: Option<String>,
+    pub(crate) cancellation_token: CancellationToken,
+    pub(crate) doc_gc: bool,
+    pub(crate) skip_gc: bool,
+    pub(crate) max_body_size: Option<usize>,
+    pub(crate) body_limits: BodyLimits,
+    pub(crate) message_limits: MessageLimits,
+    pub(crate) max_connections: Option<usize>,
+    pub(crate) max_connections_per_doc: Option<usize>,
+    pub(crate) update_validator: Option<Arc<dyn UpdateValidator>>,
+    pub(crate) event_handler: Option<Arc<dyn EventHandler>>,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self {
+            store: None,
+            checkpoint_freq: DEFAULT_CHECKPOINT_FREQ,
+            checkpoint_triggers: CheckpointTriggers::default(),
+            authenticator: None,
+            auth_provider: None,
+            url_prefix: None,
+            base_path: None,
+            cancellation_token: CancellationToken::new(),
+            doc_gc: true,
+            skip_gc: false,
+            max_body_size: None,
+            body_limits: BodyLimits::default(),
+            message_limits: MessageLimits::default(),
+            max_connections: None,
+            max_connections_per_doc: None,
+            update_validator: None,
+            event_handler: None,
+        }
+    }
+}
+
+impl ServerBuilder {
+    /// Persists documents to `store`. Without a store, documents only live in memory.
+    pub fn store(self, store: Option<Box<dyn Store>>) -> Self {
+        Self { store, ..self }
+    }
+
+    /// Persists changed documents at most once per `checkpoint_freq`.
+    pub fn checkpoint_freq(self, checkpoint_freq: Duration) -> Self {
+        Self {
+            checkpoint_freq,
+            ..self
+        }
+    }
+
+    /// Persists a document as soon as the changes pending since its last checkpoint reach
+    /// one of `triggers`, instead of waiting for the checkpoint interval to pass.
+    pub fn checkpoint_triggers(self, checkpoint_triggers: CheckpointTriggers) -> Self {
+        Self {
+            checkpoint_triggers,
+            ..self
+        }
+    }
+
+    /// Verifies tokens with `authenticator`. Without one, every request is allowed.
+    pub fn authenticator(self, authenticator: Option<Authenticator>) -> Self {
+        Self {
+            authenticator,
+            ..self
+        }
+    }
+
+    /// Verifies and issues tokens with `provider` instead of the authenticator, to use a
+    /// token scheme of its own.
+    pub fn auth_provider(self, provider: impl AuthProvider + 'static) -> Self {
+        Self {
+            auth_provider: Some(Arc::new(provider)),
+            ..self
+        }
+    }
+
+    /// Base URL of the server as seen by clients, used in the URLs it returns.
+    pub fn url_prefix(self, url_prefix: Option<Url>) -> Self {
+        Self { url_prefix, ..self }
+    }
+
+    /// Serves the routes under `base_path` (e.g. `/collab`) instead of the root, to nest
+    /// the server inside another app. The URLs the server returns include it.
+    pub fn base_path(self, base_path: Option<String>) -> Self {
+        Self { base_path, ..self }
+    }
+
+    /// Stops the server, persisting documents, when `cancellation_token` is cancelled.
+    pub fn cancellation_token(self, cancellation_token: CancellationToken) -> Self {
+        Self {
+            cancellation_token,
+            ..self
+        }
+    }
+
+    /// Whether documents no longer in use are unloaded. Enabled by default.
+    pub fn doc_gc(self, doc_gc: bool) -> Self {
+        Self { doc_gc, ..self }
+    }
+
+    /// Whether garbage collection of deleted content in documents is skipped.
+    pub fn skip_gc(self, skip_gc: bool) -> Self {
+        Self { skip_gc, ..self }
+    }
+
+    /// Largest request body accepted, in bytes.
+    pub fn max_body_size(self, max_body_size: Option<usize>) -> Self {
+        Self {
+            max_body_size,
+            ..self
+        }
+    }
+
+    /// Sets the largest request bodies accepted by the update, import and asset routes,
+    /// which otherwise use `max_body_size`.
+    pub fn body_limits(self, body_limits: BodyLimits) -> Self {
+        Self {
+            body_limits,
+            ..self
+        }
+    }
+
+    /// Sets the largest message and the message rate accepted from each WebSocket client.
+    pub fn message_limits(self, message_limits: MessageLimits) -> Self {
+        Self {
+            message_limits,
+            ..self
+        }
+    }
+
+    /// Caps the number of concurrent WebSocket connections, in total and per document.
+    pub fn connection_limits(
+        self,
+        max_connections: Option<usize>,
+        max_connections_per_doc: Option<usize>,
+    ) -> Self {
+        Self {
+            max_connections,
+            max_connections_per_doc,
+            ..self
+        }
+    }
+
+    /// Validates the updates clients send with `validator` before they are applied.
+    pub fn update_validator(self, validator: impl UpdateValidator + 'static) -> Self {
+        Self {
+            update_validator: Some(Arc::new(validator)),
+            ..self
+        }
+    }
+
+    /// Tells `handler` about document lifecycle events.
+    pub fn event_handler(self, handler: impl EventHandler + 'static) -> Self {
+        Self {
+            event_handler: Some(Arc::new(handler)),
+            ..self
+        }
+    }
+
+    pub async fn build(self) -> Result<Server> {
+        let base_path = self
+            .base_path
+            .as_deref()
+            .map(normalize_base_path)
+            .transpose()?;
+        Ok(Server::from_builder(Self {
+            base_path: base_path.flatten(),
+            ..self
+        }))
+    }
+}
+
+/// `base_path` with a leading slash and without a trailing one, or None for the root.
+fn normalize_base_path(base_path: &str) -> Result<Option<String>> {
+    let base_path = base_path.trim_end_matches('/');
+    if base_path.is_empty() {
+        return Ok(None);
+    }
+    if !base_path.starts_with('/') {
+        bail!("Base path {:?} must start with a slash", base_path);
+    }
+    // Would be read as route parameters
+    if base_path.contains([':', '*']) {
+        bail!("Base path {:?} must not contain ':' or '*'", base_path);
+    }
+    Ok(Some(base_path.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing_ext::TestServer;
+    use axum::http::{header, Method, Request, StatusCode};
+    use http_body_util::Full;
+    use y_sweet_core::{
+        api_types::Authorization,
+        auth::{AuthError, ExpirationTimeEpochMillis},
+    };
+
+    #[tokio::test]
+    async fn test_builder_defaults_and_options() {
+        let server = Server::builder()
+            .url_prefix(Some("https://docs.example.com/".parse().unwrap()))
+            .base_path(Some("/collab/".to_string()))
+            .connection_limits(Some(10), None)
+            .message_limits(MessageLimits {
+                max_message_size: Some(1024),
+                max_messages_per_second: None,
+            })
+            .build()
+            .await
+            .unwrap();
+
+        assert!(server.store.is_none());
+        assert!(server.authenticator().is_none());
+        assert_eq!(
+            server.url_prefix().map(Url::as_str),
+            Some("https://docs.example.com/")
+        );
+        assert_eq!(server.base_path(), Some("/collab"));
+        assert_eq!(server.message_limits().max_message_size, Some(1024));
+        assert!(server.update_validator().is_none());
+
+        let error = Server::builder()
+            .base_path(Some("collab".to_string()))
+            .build()
+            .await
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("must start with a slash"));
+    }
+
+    /// Tokens in plain text: `server`, or `<doc id>:<authorization>`.
+    struct PlainTokens;
+
+    impl AuthProvider for PlainTokens {
+        fn verify_server_token(&self, token: &str, _: u64) -> Result<(), AuthError> {
+            (token == "server")
+                .then_some(())
+                .ok_or(AuthError::InvalidToken)
+        }
+
+        fn verify_doc_token(
+            &self,
+            token: &str,
+            doc: &str,
+            _: u64,
+        ) -> Result<Authorization, AuthError> {
+            match token.split_once(':') {
+                Some((doc_id, "full")) if doc_id == doc => Ok(Authorization::Full),
+                Some((doc_id, "read")) if doc_id == doc => Ok(Authorization::ReadOnly),
+                Some(_) => Err(AuthError::InvalidResource),
+                None => Err(AuthError::InvalidToken),
+            }
+        }
+
+        fn gen_doc_token(
+            &self,
+            doc_id: &str,
+            authorization: Authorization,
+            _: ExpirationTimeEpochMillis,
+        ) -> String {
+            match authorization {
+                Authorization::Full => format!("{}:full", doc_id),
+                Authorization::ReadOnly => format!("{}:read", doc_id),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_auth_provider() {
+        let server = TestServer::start(Server::builder().auth_provider(PlainTokens))
+            .await
+            .unwrap();
+
+        let request = |token: &str| {
+            Request::builder()
+                .method(Method::POST)
+                .uri("/doc/new")
+                .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Full::new("{}".into()))
+                .unwrap()
+        };
+        let response = server.request(request("nope")).await.unwrap();
+        assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+        let response = server.request(request("server")).await.unwrap();
+        assert_eq!(response.status, StatusCode::OK);
+        let doc_id = response.json::<serde_json::Value>().unwrap()["docId"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        assert_eq!(
+            server.doc_token(&doc_id, Authorization::ReadOnly),
+            Some(format!("{}:read", doc_id))
+        );
+        #[cfg(feature = "client")]
+        {
+            assert!(server
+                .connect_with_token(&doc_id, Some("other:full"))
+                .await
+                .is_err());
+            let mut client = server
+                .connect_with_token(&doc_id, Some(&format!("{}:full", doc_id)))
+                .await
+                .unwrap();
+            client.sync().await.unwrap();
+        }
+    }
+}
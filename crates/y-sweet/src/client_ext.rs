@@ -0,0 +1,595 @@
+//! A typed client of the HTTP API and the sync protocol, for Rust backends.
+//!
+//! A [Client] calls the endpoints of a server with its server token: creating documents,
+//! minting client tokens, reading and updating documents, managing assets, deleting,
+//! copying, labelling, publishing, listing and searching documents. [Client::connect]
+//! opens a [DocSocket], which syncs a local copy of a document over WebSocket the way a Yjs
+//! provider does.
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use y_sweet::client_ext::Client;
+//! use yrs::{Text, WriteTxn};
+//!
+//! let client = Client::from_connection_string("ys://token@localhost:8080")?;
+//! let doc_id = client.create_doc(None).await?;
+//! let mut socket = client.connect(&doc_id).await?;
+//! socket.sync().await?;
+//! socket.update(|txn| txn.get_or_insert_text("text").insert(txn, 0, "hello")).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures::{SinkExt, StreamExt};
+use reqwest::{RequestBuilder, Response, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize};
+use std::{collections::BTreeMap, fmt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_tungstenite::{tungstenite, MaybeTlsStream, WebSocketStream};
+use url::Url;
+use y_sweet_core::{
+    api_types::{Authorization, ClientToken},
+    api_types_ext::{
+        AssetConfirmResponse, AssetUrl, AssetsResponse, ContentUploadRequest,
+        ContentUploadResponse, DocCopyRequest, DocCopyResponse, DocDeleteRequest,
+        DocDeleteResponse, DocLabelsRequest, DocLabelsResponse, DocListResponse, DocPublishRequest,
+        DocPublishResponse, PublishFormat, SearchHit, SearchResponse,
+    },
+    sync::{
+        awareness::{Awareness, AwarenessUpdate},
+        Message, MessageReader, SyncMessage,
+    },
+};
+use yrs::{
+    encoding::read::Cursor,
+    updates::{
+        decoder::{Decode, DecoderV1},
+        encoder::Encode,
+    },
+    Doc, ReadTxn, StateVector, Transact, TransactionMut, Update,
+};
+
+/// An error response of the server.
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The server responded {}: {}", self.status, self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+#[derive(Deserialize)]
+struct NewDoc {
+    #[serde(rename = "docId")]
+    doc_id: String,
+}
+
+/// A client of the HTTP API of a server.
+#[derive(Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    /// Base URL of the server, with a trailing slash.
+    server_url: Url,
+    server_token: Option<String>,
+}
+
+impl Client {
+    /// A client of the server at `server_url` (e.g. `http://localhost:8080`), calling it with
+    /// `server_token` if the server requires one.
+    pub fn new(server_url: Url, server_token: Option<String>) -> Self {
+        let mut server_url = server_url;
+        if !server_url.path().ends_with('/') {
+            server_url.set_path(&format!("{}/", server_url.path()));
+        }
+        Self {
+            http: reqwest::Client::new(),
+            server_url,
+            server_token,
+        }
+    }
+
+    /// A client from a connection string printed by `y-sweet serve`, e.g.
+    /// `ys://token@localhost:8080` (`yss://` for HTTPS).
+    pub fn from_connection_string(connection_string: &str) -> Result<Self> {
+        let (scheme, rest) = if let Some(rest) = connection_string.strip_prefix("ys://") {
+            ("http", rest)
+        } else if let Some(rest) = connection_string.strip_prefix("yss://") {
+            ("https", rest)
+        } else {
+            bail!("Connection strings start with ys:// or yss://");
+        };
+        // The scheme is replaced in string form, like the connection string is built
+        let mut url =
+            Url::parse(&format!("{}://{}", scheme, rest)).context("Invalid connection string")?;
+        let server_token = (!url.username().is_empty()).then(|| url.username().to_string());
+        url.set_username("")
+            .map_err(|_| anyhow!("Invalid connection string"))?;
+        Ok(Self::new(url, server_token))
+    }
+
+    pub fn server_url(&self) -> &Url {
+        &self.server_url
+    }
+
+    fn url(&self, path: &str) -> Result<Url> {
+        Ok(self.server_url.join(path)?)
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.server_token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    /// Create a document, with a generated ID if `doc_id` is None. Returns its ID.
+    pub async fn create_doc(&self, doc_id: Option<&str>) -> Result<String> {
+        let body = match doc_id {
+            Some(doc_id) => serde_json::json!({ "docId": doc_id }),
+            None => serde_json::json!({}),
+        };
+        let request = self.http.post(self.url("doc/new")?).json(&body);
+        let new_doc: NewDoc = json(send(self.authorize(request)).await?).await?;
+        Ok(new_doc.doc_id)
+    }
+
+    /// A token for clients to connect to `doc_id` with `authorization`, valid for
+    /// `valid_for_seconds` (an hour by default).
+    pub async fn auth_doc(
+        &self,
+        doc_id: &str,
+        authorization: Authorization,
+        valid_for_seconds: Option<u64>,
+    ) -> Result<ClientToken> {
+        let mut body = serde_json::json!({ "authorization": authorization });
+        if let Some(valid_for_seconds) = valid_for_seconds {
+            body["validForSeconds"] = valid_for_seconds.into();
+        }
+        let request = self
+            .http
+            .post(self.url(&format!("doc/{}/auth", doc_id))?)
+            .json(&body);
+        json(send(self.authorize(request)).await?).await
+    }
+
+    /// The whole state of `doc_id`, encoded as a v1 update.
+    pub async fn as_update(&self, doc_id: &str) -> Result<Vec<u8>> {
+        let request = self.http.get(self.url(&format!("d/{}/as-update", doc_id))?);
+        Ok(send(self.authorize(request)).await?.bytes().await?.to_vec())
+    }
+
+    /// Apply a v1 update to `doc_id`.
+    pub async fn update(&self, doc_id: &str, update: Vec<u8>) -> Result<()> {
+        let request = self
+            .http
+            .post(self.url(&format!("d/{}/update", doc_id))?)
+            .body(update);
+        send(self.authorize(request)).await?;
+        Ok(())
+    }
+
+    /// A token for the asset endpoints of `doc_id`, which take client tokens, not the
+    /// server token.
+    async fn asset_token(&self, doc_id: &str) -> Result<Option<String>> {
+        if self.server_token.is_none() {
+            return Ok(None);
+        }
+        Ok(self
+            .auth_doc(doc_id, Authorization::Full, None)
+            .await?
+            .token)
+    }
+
+    /// A presigned URL to upload an asset of `content_type` to `doc_id`. With the SHA-256
+    /// of the content, the upload is skipped if the document already has it. Servers with a
+    /// quota on asset bytes require the size of the content. The asset is downloaded as
+    /// `filename`, if given.
+    pub async fn upload_asset_url(
+        &self,
+        doc_id: &str,
+        content_type: &str,
+        sha256: Option<&str>,
+        content_length: Option<u64>,
+        filename: Option<&str>,
+    ) -> Result<ContentUploadResponse> {
+        let mut request = self
+            .http
+            .post(self.url(&format!("d/{}/assets", doc_id))?)
+            .json(&ContentUploadRequest {
+                content_type: content_type.to_string(),
+                sha256: sha256.map(str::to_string),
+                content_length,
+                filename: filename.map(str::to_string),
+            });
+        if let Some(token) = self.asset_token(doc_id).await? {
+            request = request.bearer_auth(token);
+        }
+        json(send(request).await?).await
+    }
+
+    /// Confirm that `asset_id` was uploaded to `doc_id`, so that the asset hook of the
+    /// server processes it.
+    pub async fn confirm_asset(
+        &self,
+        doc_id: &str,
+        asset_id: &str,
+    ) -> Result<AssetConfirmResponse> {
+        let mut request = self
+            .http
+            .post(self.url(&format!("d/{}/assets/{}/confirm", doc_id, asset_id))?);
+        if let Some(token) = self.asset_token(doc_id).await? {
+            request = request.bearer_auth(token);
+        }
+        json(send(request).await?).await
+    }
+
+    /// Delete `asset_id` of `doc_id`, with its thumbnails.
+    pub async fn delete_asset(&self, doc_id: &str, asset_id: &str) -> Result<()> {
+        let mut request = self
+            .http
+            .delete(self.url(&format!("d/{}/assets/{}", doc_id, asset_id))?);
+        if let Some(token) = self.asset_token(doc_id).await? {
+            request = request.bearer_auth(token);
+        }
+        send(request).await?;
+        Ok(())
+    }
+
+    /// The assets of `doc_id`, with presigned download URLs.
+    pub async fn assets(&self, doc_id: &str) -> Result<Vec<AssetUrl>> {
+        let mut request = self.http.get(self.url(&format!("d/{}/assets", doc_id))?);
+        if let Some(token) = self.asset_token(doc_id).await? {
+            request = request.bearer_auth(token);
+        }
+        let assets: AssetsResponse = json(send(request).await?).await?;
+        Ok(assets.assets)
+    }
+
+    /// Delete `doc_id` and its assets, or only list what would be removed if `dry_run`.
+    pub async fn delete_doc(&self, doc_id: &str, dry_run: bool) -> Result<DocDeleteResponse> {
+        let request = self
+            .http
+            .delete(self.url(&format!("d/{}", doc_id))?)
+            .json(&DocDeleteRequest { dry_run });
+        json(send(self.authorize(request)).await?).await
+    }
+
+    /// Copy `doc_id` to `destination_doc_id`, replacing it if it exists.
+    pub async fn copy_doc(
+        &self,
+        doc_id: &str,
+        destination_doc_id: &str,
+        dry_run: bool,
+    ) -> Result<DocCopyResponse> {
+        let request = self
+            .http
+            .post(self.url(&format!("d/{}/copy", doc_id))?)
+            .json(&DocCopyRequest {
+                destination_doc_id: destination_doc_id.to_string(),
+                dry_run,
+            });
+        json(send(self.authorize(request)).await?).await
+    }
+
+    /// Replace the labels of `doc_id`.
+    pub async fn set_labels(
+        &self,
+        doc_id: &str,
+        labels: BTreeMap<String, String>,
+    ) -> Result<DocLabelsResponse> {
+        let request = self
+            .http
+            .put(self.url(&format!("d/{}/labels", doc_id))?)
+            .json(&DocLabelsRequest { labels });
+        json(send(self.authorize(request)).await?).await
+    }
+
+    /// Publish a read-only snapshot of `doc_id` as `format`, returning its public URL.
+    pub async fn publish(&self, doc_id: &str, format: PublishFormat) -> Result<String> {
+        let request = self
+            .http
+            .post(self.url(&format!("d/{}/publish", doc_id))?)
+            .json(&DocPublishRequest { format, root: None });
+        let response: DocPublishResponse = json(send(self.authorize(request)).await?).await?;
+        response
+            .url
+            .ok_or_else(|| anyhow!("The server returned no URL for the published document"))
+    }
+
+    /// Remove the published snapshot of `doc_id`.
+    pub async fn unpublish(&self, doc_id: &str) -> Result<()> {
+        let request = self
+            .http
+            .post(self.url(&format!("d/{}/unpublish", doc_id))?);
+        send(self.authorize(request)).await?;
+        Ok(())
+    }
+
+    /// A page of the documents whose ID starts with `prefix` and whose labels match the
+    /// selector `label` (e.g. `project=apollo,customer`), after `cursor` if given.
+    pub async fn list_docs(
+        &self,
+        prefix: Option<&str>,
+        label: Option<&str>,
+        cursor: Option<&str>,
+    ) -> Result<DocListResponse> {
+        let query = [("prefix", prefix), ("label", label), ("cursor", cursor)];
+        let query: Vec<_> = query
+            .iter()
+            .filter_map(|(name, value)| value.map(|value| (*name, value)))
+            .collect();
+        let request = self.http.get(self.url("docs")?).query(&query);
+        json(send(self.authorize(request)).await?).await
+    }
+
+    /// The documents whose content matches `query` best, among those whose ID starts with
+    /// `prefix` if given.
+    pub async fn search(&self, query: &str, prefix: Option<&str>) -> Result<Vec<SearchHit>> {
+        let mut request = self.http.get(self.url("search")?).query(&[("q", query)]);
+        if let Some(prefix) = prefix {
+            request = request.query(&[("prefix", prefix)]);
+        }
+        let response: SearchResponse = json(send(self.authorize(request)).await?).await?;
+        Ok(response.results)
+    }
+
+    /// A connection to `doc_id` with full access, not synced yet.
+    pub async fn connect(&self, doc_id: &str) -> Result<DocSocket<MaybeTlsStream<TcpStream>>> {
+        let client_token = self.auth_doc(doc_id, Authorization::Full, None).await?;
+        DocSocket::connect(&client_token).await
+    }
+}
+
+/// Send `request`, turning error responses into [ApiError]s.
+async fn send(request: RequestBuilder) -> Result<Response> {
+    let response = request.send().await.context("Failed to reach the server")?;
+    let status = response.status();
+    if !status.is_success() {
+        let message = response.text().await.unwrap_or_default();
+        return Err(ApiError { status, message }.into());
+    }
+    Ok(response)
+}
+
+async fn json<T: DeserializeOwned>(response: Response) -> Result<T> {
+    response
+        .json()
+        .await
+        .context("Unexpected response from the server")
+}
+
+/// The WebSocket URL a client token connects to, as the y-websocket provider builds it.
+pub fn socket_url(client_token: &ClientToken) -> Result<Url> {
+    let mut url = Url::parse(&format!(
+        "{}/{}",
+        client_token.url.trim_end_matches('/'),
+        client_token.doc_id
+    ))?;
+    if let Some(token) = &client_token.token {
+        url.query_pairs_mut().append_pair("token", token);
+    }
+    Ok(url)
+}
+
+/// A WebSocket connection to a document, keeping a local copy of it in sync with the
+/// server.
+pub struct DocSocket<S> {
+    socket: WebSocketStream<S>,
+    awareness: Awareness,
+}
+
+impl DocSocket<MaybeTlsStream<TcpStream>> {
+    /// Connect with a token returned by `/doc/:doc_id/auth`.
+    pub async fn connect(client_token: &ClientToken) -> Result<Self> {
+        let (socket, _) = tokio_tungstenite::connect_async(socket_url(client_token)?.as_str())
+            .await
+            .context("Failed to connect to the document")?;
+        Ok(Self::new(socket))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> DocSocket<S> {
+    /// Speak the sync protocol on an established WebSocket, with an empty local copy.
+    pub fn new(socket: WebSocketStream<S>) -> Self {
+        Self {
+            socket,
+            awareness: Awareness::new(Doc::new()),
+        }
+    }
+
+    /// The local copy of the document.
+    pub fn doc(&self) -> &Doc {
+        self.awareness.doc()
+    }
+
+    pub fn awareness(&self) -> &Awareness {
+        &self.awareness
+    }
+
+    pub async fn send(&mut self, message: Message) -> Result<()> {
+        self.send_raw(message.encode_v1()).await
+    }
+
+    /// Send a binary frame as is, e.g. a malformed one.
+    pub async fn send_raw(&mut self, payload: Vec<u8>) -> Result<()> {
+        self.socket
+            .send(tungstenite::Message::Binary(payload))
+            .await?;
+        Ok(())
+    }
+
+    /// The messages of the next frame from the server, once applied to the local copy:
+    /// updates are applied, and the state the server asks for is sent back.
+    pub async fn recv(&mut self) -> Result<Vec<Message>> {
+        let payload = loop {
+            match self.socket.next().await {
+                Some(Ok(tungstenite::Message::Binary(payload))) => break payload,
+                Some(Ok(tungstenite::Message::Close(frame))) => {
+                    bail!("Connection closed by the server: {:?}", frame)
+                }
+                // Pings are answered by the socket itself
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Err(e.into()),
+                None => bail!("Connection closed"),
+            }
+        };
+
+        let mut decoder = DecoderV1::new(Cursor::new(&payload));
+        let messages = MessageReader::new(&mut decoder).collect::<Result<Vec<_>, _>>()?;
+        for message in &messages {
+            self.handle(message).await?;
+        }
+        Ok(messages)
+    }
+
+    async fn handle(&mut self, message: &Message) -> Result<()> {
+        match message {
+            Message::Sync(SyncMessage::SyncStep1(state_vector)) => {
+                let update = self
+                    .doc()
+                    .transact()
+                    .encode_state_as_update_v1(state_vector);
+                self.send(Message::Sync(SyncMessage::SyncStep2(update)))
+                    .await?;
+            }
+            Message::Sync(SyncMessage::SyncStep2(update) | SyncMessage::Update(update)) => {
+                let update = Update::decode_v1(update)?;
+                self.doc().transact_mut().apply_update(update);
+            }
+            Message::Awareness(update) => {
+                // Not Clone, so applied from a copy decoded from its encoding
+                let update = AwarenessUpdate::decode_v1(&update.encode_v1())?;
+                self.awareness
+                    .apply_update(update)
+                    .map_err(|e| anyhow!("Invalid awareness update: {}", e))?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Exchange states with the server, until the local copy has the state of the server.
+    pub async fn sync(&mut self) -> Result<()> {
+        let state_vector = self.doc().transact().state_vector();
+        self.send(Message::Sync(SyncMessage::SyncStep1(state_vector)))
+            .await?;
+        loop {
+            let messages = self.recv().await?;
+            if messages
+                .iter()
+                .any(|m| matches!(m, Message::Sync(SyncMessage::SyncStep2(_))))
+            {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Change the local copy in a transaction, and send the change to the server.
+    pub async fn update<T>(&mut self, f: impl FnOnce(&mut TransactionMut) -> T) -> Result<T> {
+        let before = self.doc().transact().state_vector();
+        let (result, update) = {
+            let mut txn = self.doc().transact_mut();
+            let result = f(&mut txn);
+            (result, txn.encode_state_as_update_v1(&before))
+        };
+        self.send(Message::Sync(SyncMessage::Update(update)))
+            .await?;
+        Ok(result)
+    }
+
+    /// Receive messages from the server until `f` holds for the local copy.
+    pub async fn wait_for(&mut self, f: impl Fn(&Doc) -> bool) -> Result<()> {
+        while !f(self.doc()) {
+            self.recv().await?;
+        }
+        Ok(())
+    }
+
+    /// The state of the local copy, encoded as a v1 update.
+    pub fn state(&self) -> Vec<u8> {
+        self.doc()
+            .transact()
+            .encode_state_as_update_v1(&StateVector::default())
+    }
+
+    pub async fn close(mut self) -> Result<()> {
+        self.socket.close(None).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing_ext::TestServer;
+    use yrs::{GetString, Text, WriteTxn};
+
+    #[test]
+    fn test_parses_connection_strings() {
+        let client =
+            Client::from_connection_string("yss://secret@docs.example.com/collab").unwrap();
+        assert_eq!(
+            client.server_url().as_str(),
+            "https://docs.example.com/collab/"
+        );
+        assert_eq!(client.server_token.as_deref(), Some("secret"));
+
+        let client = Client::from_connection_string("ys://localhost:8080").unwrap();
+        assert_eq!(client.server_url().as_str(), "http://localhost:8080/");
+        assert!(client.server_token.is_none());
+
+        assert!(Client::from_connection_string("http://localhost:8080").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_client_calls_server() {
+        let server = TestServer::new().await.unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let app = server.server().routes();
+        tokio::spawn(async move { axum::serve(listener, app.into_make_service()).await });
+        let client = Client::new(url.parse().unwrap(), None);
+
+        let doc_id = client.create_doc(Some("notes")).await.unwrap();
+        let mut socket = client.connect(&doc_id).await.unwrap();
+        socket.sync().await.unwrap();
+        socket
+            .update(|txn| {
+                let text = txn.get_or_insert_text("text");
+                text.insert(txn, 0, "hello");
+            })
+            .await
+            .unwrap();
+        // The server answers in order, so the update is applied once this sync is done
+        socket.sync().await.unwrap();
+
+        let copy = client.copy_doc(&doc_id, "copy", false).await.unwrap();
+        assert!(copy.success);
+        let update = client.as_update("copy").await.unwrap();
+        let doc = Doc::new();
+        doc.transact_mut()
+            .apply_update(Update::decode_v1(&update).unwrap());
+        assert_eq!(
+            doc.get_or_insert_text("text").get_string(&doc.transact()),
+            "hello"
+        );
+
+        let deleted = client.delete_doc("copy", false).await.unwrap();
+        assert!(deleted.data_deleted);
+        let Err(error) = client.auth_doc("copy", Authorization::Full, None).await else {
+            panic!("Deleted document was authorized");
+        };
+        let error = error.downcast_ref::<ApiError>().unwrap();
+        assert_eq!(error.status, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,449 @@
+//! Sharding of documents across a cluster of nodes.
+//!
+//! All nodes are configured with the same list of nodes, from which each derives the same
+//! consistent-hash ring. Every document is owned by exactly one node. Any node issues client
+//! tokens, with the URLs of the owner, so clients connect to the owner directly. A node
+//! receiving another request for a document it does not own redirects it to the owner (307,
+//! keeping the method and body), so a document is only ever loaded, and persisted, by its
+//! owner. Documents named in request bodies rather than paths cannot be redirected, so
+//! requests naming one another node owns are rejected (421) instead, except for new
+//! documents, which any node creates in the store and leaves to their owner to load. Adding
+//! or removing a node only moves the documents of that node's ring segments.
+
+use anyhow::{anyhow, Result};
+use axum::{
+    extract::{OriginalUri, Request, State},
+    http::{header::LOCATION, HeaderName, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{collections::BTreeMap, sync::Arc};
+use tracing::debug;
+use url::Url;
+
+use crate::server::{AppError, Server};
+
+/// Points each node takes on the ring. More points spread documents more evenly.
+const POINTS_PER_NODE: u32 = 128;
+
+/// Response header naming the node that owns the requested document.
+pub const OWNER_HEADER: &str = "x-y-sweet-owner";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClusterNode {
+    pub id: String,
+    /// URL of the root of the node, under which its routes are mounted at the base path.
+    pub url: Url,
+}
+
+impl ClusterNode {
+    /// Parse a comma-separated list of `id=url` pairs.
+    pub fn parse_list(nodes: &str) -> Result<Vec<ClusterNode>> {
+        nodes
+            .split(',')
+            .map(str::trim)
+            .filter(|node| !node.is_empty())
+            .map(|node| {
+                let (id, url) = node
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("Expected id=url, got {}", node))?;
+                let mut url = Url::parse(url)?;
+                // Relative paths are joined onto the URL, so it must end with a slash
+                if !url.path().ends_with('/') {
+                    url.set_path(&format!("{}/", url.path()));
+                }
+                Ok(ClusterNode {
+                    id: id.to_string(),
+                    url,
+                })
+            })
+            .collect()
+    }
+}
+
+pub struct Cluster {
+    node_id: String,
+    nodes: Vec<ClusterNode>,
+    ring: BTreeMap<u64, usize>,
+}
+
+impl Cluster {
+    /// Create the cluster view of node `node_id`, which must be one of `nodes`.
+    pub fn new(node_id: &str, nodes: Vec<ClusterNode>) -> Result<Self> {
+        if !nodes.iter().any(|node| node.id == node_id) {
+            return Err(anyhow!("Node {} is not in the cluster", node_id));
+        }
+
+        let mut ring = BTreeMap::new();
+        for (index, node) in nodes.iter().enumerate() {
+            for point in 0..POINTS_PER_NODE {
+                ring.insert(hash(&format!("{}#{}", node.id, point)), index);
+            }
+        }
+
+        Ok(Self {
+            node_id: node_id.to_string(),
+            nodes,
+            ring,
+        })
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    /// The node owning a document: the first ring point at or after the document's hash.
+    pub fn owner(&self, doc_id: &str) -> &ClusterNode {
+        let index = self
+            .ring
+            .range(hash(doc_id)..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .map(|(_, index)| *index)
+            .expect("cluster has at least one node");
+        &self.nodes[index]
+    }
+
+    pub fn is_local(&self, doc_id: &str) -> bool {
+        self.owner(doc_id).id == self.node_id
+    }
+}
+
+/// URL of the routes of `doc_id` on its owner, if another node of the cluster owns it.
+pub(crate) fn owner_doc_base_url(server: &Server, doc_id: &str) -> Option<String> {
+    let cluster = server.cluster()?;
+    let owner = cluster.owner(doc_id);
+    if owner.id == cluster.node_id() {
+        return None;
+    }
+    Some(format!(
+        "{}{}",
+        owner.url.as_str().trim_end_matches('/'),
+        server.doc_path(doc_id)
+    ))
+}
+
+/// Whether this node owns `doc_id`, as it does every document outside a cluster.
+pub fn is_local(server: &Server, doc_id: &str) -> bool {
+    server
+        .cluster()
+        .is_none_or(|cluster| cluster.is_local(doc_id))
+}
+
+/// Fail with 421 when another node of the cluster owns `doc_id`, for documents that are not
+/// addressed by the request path, and so are not redirected.
+pub fn check_local(server: &Server, doc_id: &str) -> Result<(), AppError> {
+    let Some(cluster) = server.cluster() else {
+        return Ok(());
+    };
+    let owner = cluster.owner(doc_id);
+    if owner.id == cluster.node_id() {
+        return Ok(());
+    }
+    Err(AppError(
+        StatusCode::MISDIRECTED_REQUEST,
+        anyhow!(
+            "Doc {} is owned by node {} ({})",
+            doc_id,
+            owner.id,
+            owner.url
+        ),
+    ))
+}
+
+/// 64-bit FNV-1a. Stable across processes and builds, unlike the std hasher. FNV barely
+/// mixes the last bytes into the high bits, which order the ring, so similar IDs (`doc-1`,
+/// `doc-2`) would land next to each other without the final avalanche step (from
+/// MurmurHash3).
+fn hash(value: &str) -> u64 {
+    let mut hash = value.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+/// Document ID addressed by a request path, for the document routes that must be served by
+/// the owner of the document.
+fn doc_id_from_path(path: &str) -> Option<&str> {
+    let mut segments = path.trim_start_matches('/').split('/');
+    match (segments.next(), segments.next(), segments.next()) {
+        (Some("d"), Some(doc_id), _) => Some(doc_id),
+        (Some("doc"), Some("ws"), Some(doc_id)) => Some(doc_id),
+        // Created by any node, and only loaded by the owner of its ID
+        (Some("doc"), Some("new"), _) => None,
+        // Answered by any node, with the URLs of the owner. WebSocket clients would not
+        // follow a redirect, and HTTP clients drop the token when redirected to another host.
+        (Some("doc"), Some(_), Some("auth")) => None,
+        (Some("doc"), Some(doc_id), Some(_)) => Some(doc_id),
+        _ => None,
+    }
+}
+
+/// Redirect requests for documents owned by another node to that node.
+pub async fn route_to_owner(
+    State(server): State<Arc<Server>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(cluster) = server.cluster() else {
+        return next.run(req).await;
+    };
+    let Some(doc_id) = doc_id_from_path(req.uri().path()) else {
+        return next.run(req).await;
+    };
+
+    let owner = cluster.owner(doc_id);
+    if owner.id == cluster.node_id() {
+        return next.run(req).await;
+    }
+
+    // The router strips the base path from the URI of nested routes
+    let uri = req
+        .extensions()
+        .get::<OriginalUri>()
+        .map_or(req.uri(), |OriginalUri(uri)| uri);
+    let path_and_query = uri
+        .path_and_query()
+        .map(|path_and_query| path_and_query.as_str())
+        .unwrap_or("/");
+    let Ok(location) = owner.url.join(path_and_query.trim_start_matches('/')) else {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Invalid owner URL").into_response();
+    };
+
+    debug!(
+        message = "Redirecting request to the document owner",
+        event = "cluster_redirect",
+        doc_id = %doc_id,
+        owner = %owner.id
+    );
+    (
+        StatusCode::TEMPORARY_REDIRECT,
+        [
+            (LOCATION, location.to_string()),
+            (HeaderName::from_static(OWNER_HEADER), owner.id.clone()),
+        ],
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stores::filesystem::FileSystemStore;
+    use std::time::Duration;
+    use tower::ServiceExt;
+
+    fn nodes(count: usize) -> Vec<ClusterNode> {
+        (0..count)
+            .map(|i| ClusterNode {
+                id: format!("node-{}", i),
+                url: Url::parse(&format!("http://node-{}.internal:8080/", i)).unwrap(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_nodes() {
+        let nodes = ClusterNode::parse_list("a=http://a:8080, b=http://b:8080/").unwrap();
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[1].id, "b");
+        assert_eq!(nodes[1].url.as_str(), "http://b:8080/");
+        assert!(ClusterNode::parse_list("a").is_err());
+    }
+
+    #[test]
+    fn test_ownership_is_agreed_and_stable() {
+        let a = Cluster::new("node-0", nodes(3)).unwrap();
+        let b = Cluster::new("node-1", nodes(3)).unwrap();
+        let grown = Cluster::new("node-0", nodes(4)).unwrap();
+
+        let doc_ids: Vec<String> = (0..1000).map(|i| format!("doc-{}", i)).collect();
+        let mut moved = 0;
+        for doc_id in &doc_ids {
+            assert_eq!(a.owner(doc_id), b.owner(doc_id));
+            assert!(!(a.is_local(doc_id) && b.is_local(doc_id)));
+            if a.owner(doc_id) != grown.owner(doc_id) {
+                // Only documents taken over by the new node move
+                assert_eq!(grown.owner(doc_id).id, "node-3");
+                moved += 1;
+            }
+        }
+        assert!(moved > 0 && moved < 500);
+    }
+
+    #[test]
+    fn test_doc_id_from_path() {
+        assert_eq!(doc_id_from_path("/d/abc/ws/abc"), Some("abc"));
+        assert_eq!(doc_id_from_path("/d/abc"), Some("abc"));
+        assert_eq!(doc_id_from_path("/doc/abc/auth"), None);
+        assert_eq!(doc_id_from_path("/doc/abc/as-update"), Some("abc"));
+        assert_eq!(doc_id_from_path("/doc/ws/abc"), Some("abc"));
+        assert_eq!(doc_id_from_path("/doc/new"), None);
+        assert_eq!(doc_id_from_path("/ready"), None);
+    }
+
+    #[tokio::test]
+    async fn test_requests_are_redirected_to_owner() {
+        let cluster = Cluster::new("node-0", nodes(3)).unwrap();
+        let doc_id = (0..)
+            .map(|i| format!("doc-{}", i))
+            .find(|doc_id| !cluster.is_local(doc_id))
+            .unwrap();
+        let owner = cluster.owner(&doc_id).clone();
+
+        let server = Arc::new(
+            Server::builder()
+                .checkpoint_freq(Duration::from_secs(60))
+                .build()
+                .await
+                .unwrap()
+                .with_cluster(cluster),
+        );
+
+        let response = server
+            .routes()
+            .oneshot(
+                http::Request::builder()
+                    .uri(format!("/d/{doc_id}/as-update"))
+                    .body(axum::body::Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(
+            response.headers()[LOCATION],
+            format!("{}d/{}/as-update", owner.url, doc_id)
+        );
+        assert_eq!(response.headers()[OWNER_HEADER], owner.id.as_str());
+        assert!(!server.docs.contains_key(&doc_id));
+    }
+
+    #[tokio::test]
+    async fn test_redirects_and_client_urls_keep_base_path() {
+        let cluster = Cluster::new("node-0", nodes(3)).unwrap();
+        let doc_id = (0..)
+            .map(|i| format!("doc-{}", i))
+            .find(|doc_id| !cluster.is_local(doc_id))
+            .unwrap();
+        let owner = cluster.owner(&doc_id).clone();
+
+        let server = Arc::new(
+            Server::builder()
+                .base_path(Some("/collab".to_string()))
+                .checkpoint_freq(Duration::from_secs(60))
+                .build()
+                .await
+                .unwrap()
+                .with_cluster(cluster),
+        );
+        server.get_or_create_doc(&doc_id).await.unwrap();
+
+        let response = server
+            .routes()
+            .oneshot(
+                http::Request::builder()
+                    .uri(format!("/collab/d/{doc_id}/as-update"))
+                    .body(axum::body::Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(
+            response.headers()[LOCATION],
+            format!("{}collab/d/{}/as-update", owner.url, doc_id)
+        );
+
+        // Tokens are issued locally, pointing the client at the owner
+        let response = server
+            .routes()
+            .oneshot(
+                http::Request::builder()
+                    .method("POST")
+                    .uri(format!("/collab/doc/{doc_id}/auth"))
+                    .header("host", "node-0.internal:8080")
+                    .header("content-type", "application/json")
+                    .body(axum::body::Body::from("{}"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let token: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let host = owner.url.host_str().unwrap();
+        assert_eq!(
+            token["url"],
+            format!("ws://{host}:8080/collab/d/{doc_id}/ws")
+        );
+        assert_eq!(
+            token["baseUrl"],
+            format!("http://{host}:8080/collab/d/{doc_id}")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_docs_owned_elsewhere_are_created_but_not_loaded() {
+        let cluster = Cluster::new("node-0", nodes(3)).unwrap();
+        let (local, remote): (Vec<String>, Vec<String>) = (0..20)
+            .map(|i| format!("doc-{}", i))
+            .partition(|doc_id| cluster.is_local(doc_id));
+        let path = std::env::temp_dir().join(format!("y-sweet-cluster-{}", nanoid::nanoid!()));
+        let server = Arc::new(
+            Server::builder()
+                .store(Some(Box::new(FileSystemStore::new(path).unwrap())))
+                .checkpoint_freq(Duration::from_secs(60))
+                .build()
+                .await
+                .unwrap()
+                .with_cluster(cluster),
+        );
+
+        let post = |uri: String, body: String| {
+            server.admin_routes().oneshot(
+                http::Request::builder()
+                    .method(http::Method::POST)
+                    .uri(uri)
+                    .header(http::header::CONTENT_TYPE, "application/json")
+                    .body(axum::body::Body::from(body))
+                    .unwrap(),
+            )
+        };
+
+        for doc_id in [&local[0], &remote[0]] {
+            let response = post("/doc/new".to_string(), format!(r#"{{"docId":"{doc_id}"}}"#))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert!(server.doc_exists(doc_id).await);
+            assert_eq!(server.docs.contains_key(doc_id), doc_id == &local[0]);
+        }
+
+        for _ in 0..10 {
+            let doc_id = server.create_doc().await.unwrap();
+            assert!(server.doc_exists(&doc_id).await);
+            assert_eq!(
+                server.docs.contains_key(&doc_id),
+                server.cluster().unwrap().is_local(&doc_id)
+            );
+        }
+
+        // The owner of a document named in the body is not redirected to
+        let response = post(
+            format!("/d/{}/merge", local[0]),
+            format!(r#"{{"sourceDocId":"{}"}}"#, remote[0]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::MISDIRECTED_REQUEST);
+        assert!(!server.docs.contains_key(&remote[0]));
+    }
+}
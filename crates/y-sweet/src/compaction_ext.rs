@@ -0,0 +1,250 @@
+//! Compaction of update logs into snapshots.
+//!
+//! In update log mode, every checkpoint adds a segment to the store, and segments are kept
+//! after the snapshot includes them. Compacting a document writes a fresh snapshot of its
+//! loaded state and removes every segment the snapshot includes, so a reload replays
+//! nothing. The compaction worker periodically compacts the loaded documents that have
+//! accumulated enough segments; `POST /d/:doc_id/compact` compacts one document on demand,
+//! or, with `dryRun`, only reports the objects a compaction would write and remove.
+
+use anyhow::Result;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+use y_sweet_core::update_log_ext::{Compaction, CompactionPlan};
+use yrs::{ReadTxn, StateVector, Transact};
+
+use crate::server::Server;
+
+/// Default interval between compaction worker runs.
+pub const DEFAULT_COMPACTION_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Default number of stored segments at which the worker compacts a document.
+pub const DEFAULT_COMPACTION_MIN_SEGMENTS: u32 = 50;
+
+/// Totals over every compaction since the server started.
+#[derive(Default)]
+pub struct CompactionMetrics {
+    runs: AtomicU64,
+    reclaimed_objects: AtomicU64,
+    duration_micros: AtomicU64,
+}
+
+impl CompactionMetrics {
+    fn record(&self, compaction: &Compaction, duration: Duration) {
+        self.runs.fetch_add(1, Ordering::Relaxed);
+        self.reclaimed_objects
+            .fetch_add(compaction.reclaimed_objects as u64, Ordering::Relaxed);
+        self.duration_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Number of documents compacted.
+    pub fn runs(&self) -> u64 {
+        self.runs.load(Ordering::Relaxed)
+    }
+
+    /// Number of update log segments removed from the store.
+    pub fn reclaimed_objects(&self) -> u64 {
+        self.reclaimed_objects.load(Ordering::Relaxed)
+    }
+
+    /// Time spent compacting.
+    pub fn total_duration(&self) -> Duration {
+        Duration::from_micros(self.duration_micros.load(Ordering::Relaxed))
+    }
+}
+
+/// Compact a loaded document. Returns `None` if it is not loaded or has no update log.
+pub async fn compact_doc(server: &Server, doc_id: &str) -> Result<Option<(Compaction, Duration)>> {
+    let Some((sync_kv, awareness)) = server
+        .docs
+        .get(doc_id)
+        .map(|doc| (doc.sync_kv(), doc.awareness()))
+    else {
+        return Ok(None);
+    };
+
+    let start = Instant::now();
+    let encode_state = || {
+        let awareness = awareness.read().unwrap();
+        let txn = awareness.doc().transact();
+        txn.encode_state_as_update_v1(&StateVector::default())
+    };
+    let Some(compaction) = sync_kv.compact(encode_state).await? else {
+        return Ok(None);
+    };
+    let duration = start.elapsed();
+    server.compaction_metrics().record(&compaction, duration);
+
+    info!(
+        message = format!(
+            "Compacted update log, removed {} segments",
+            compaction.reclaimed_objects
+        ),
+        event = "compaction_completed",
+        doc_id = %doc_id,
+        reclaimed_objects = compaction.reclaimed_objects,
+        duration_ms = duration.as_millis() as u64
+    );
+    Ok(Some((compaction, duration)))
+}
+
+/// Objects compacting a loaded document would write and remove, without compacting it.
+/// Returns `None` if it is not loaded or has no update log.
+pub async fn plan_compaction(server: &Server, doc_id: &str) -> Result<Option<CompactionPlan>> {
+    let Some(sync_kv) = server.docs.get(doc_id).map(|doc| doc.sync_kv()) else {
+        return Ok(None);
+    };
+    sync_kv.plan_compaction().await
+}
+
+/// Compact every loaded document with at least `min_segments` stored segments. Returns the
+/// number of compacted documents.
+pub async fn compact_loaded_docs(server: &Arc<Server>, min_segments: u32) -> usize {
+    // Collect first, so no map guard is held across awaits
+    let doc_ids: Vec<String> = server
+        .docs
+        .iter()
+        .filter(|doc| doc.sync_kv().stored_segments() >= min_segments.max(1))
+        .map(|doc| doc.key().clone())
+        .collect();
+
+    let mut compacted = 0;
+    for doc_id in doc_ids {
+        match compact_doc(server, &doc_id).await {
+            Ok(Some(_)) => compacted += 1,
+            Ok(None) => {}
+            Err(e) => {
+                // The segments are still in place, so the next run retries
+                error!(
+                    message = "Failed to compact update log",
+                    event = "compaction_failed",
+                    doc_id = %doc_id,
+                    error = %e
+                );
+            }
+        }
+    }
+    compacted
+}
+
+/// Periodically compact loaded documents until the server shuts down
+pub(crate) async fn compaction_worker(
+    server: Arc<Server>,
+    interval: Duration,
+    min_segments: u32,
+    cancellation_token: CancellationToken,
+) {
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {
+                compact_loaded_docs(&server, min_segments).await;
+            }
+            _ = cancellation_token.cancelled() => {
+                break;
+            }
+        }
+    }
+    tracing::debug!("Exiting compaction loop");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stores::filesystem::FileSystemStore;
+    use y_sweet_core::{
+        store::Store,
+        update_log_ext::{list_segments, segment_key, UpdateLogConfig},
+    };
+    use yrs::{GetString, Text, Transact};
+
+    async fn server(path: &std::path::Path) -> Arc<Server> {
+        Arc::new(
+            Server::builder()
+                .store(Some(Box::new(
+                    FileSystemStore::new(path.to_path_buf()).unwrap(),
+                )))
+                .checkpoint_freq(Duration::from_secs(60))
+                .build()
+                .await
+                .unwrap()
+                .with_update_log(UpdateLogConfig {
+                    snapshot_every: 100,
+                }),
+        )
+    }
+
+    async fn append(server: &Server, doc_id: &str, word: &str) {
+        {
+            let doc = server.docs.get(doc_id).unwrap();
+            let awareness = doc.awareness();
+            let awareness = awareness.write().unwrap();
+            let text = awareness.doc().get_or_insert_text("text");
+            let mut txn = awareness.doc().transact_mut();
+            let len = text.len(&txn);
+            text.insert(&mut txn, len, word);
+        }
+        let sync_kv = server.docs.get(doc_id).unwrap().sync_kv();
+        sync_kv.persist().await.unwrap();
+    }
+
+    fn text(server: &Server, doc_id: &str) -> String {
+        let doc = server.docs.get(doc_id).unwrap();
+        let awareness = doc.awareness();
+        let awareness = awareness.read().unwrap();
+        let text = awareness.doc().get_or_insert_text("text");
+        let string = text.get_string(&awareness.doc().transact());
+        string
+    }
+
+    #[tokio::test]
+    async fn test_compaction_removes_segments_and_keeps_content() {
+        let path = std::env::temp_dir().join(format!("y-sweet-compaction-{}", nanoid::nanoid!()));
+        let store = FileSystemStore::new(path.clone()).unwrap();
+
+        let server_state = server(&path).await;
+        server_state.load_doc("doc").await.unwrap();
+        for word in ["one ", "two ", "three "] {
+            append(&server_state, "doc", word).await;
+        }
+        assert_eq!(list_segments(&store, "doc").await.unwrap().len(), 3);
+
+        // A dry run lists what would change, and changes nothing
+        let plan = plan_compaction(&server_state, "doc")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(plan.snapshot_seq, Some(2));
+        assert!(plan.written.contains(&"doc/data.ysweet".to_string()));
+        assert_eq!(
+            plan.removed,
+            (0..3)
+                .map(|seq| segment_key("doc", seq))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(list_segments(&store, "doc").await.unwrap().len(), 3);
+
+        // Below the threshold, nothing is compacted
+        assert_eq!(compact_loaded_docs(&server_state, 4).await, 0);
+        assert_eq!(compact_loaded_docs(&server_state, 3).await, 1);
+        assert!(list_segments(&store, "doc").await.unwrap().is_empty());
+        assert_eq!(server_state.compaction_metrics().runs(), 1);
+        assert_eq!(server_state.compaction_metrics().reclaimed_objects(), 3);
+
+        // Segments written after the compaction are numbered after it
+        append(&server_state, "doc", "four").await;
+        assert_eq!(list_segments(&store, "doc").await.unwrap(), vec![3]);
+
+        let reloaded = server(&path).await;
+        reloaded.load_doc("doc").await.unwrap();
+        assert_eq!(text(&reloaded, "doc"), "one two three four");
+        assert!(store.exists("doc/data.ysweet").await.unwrap());
+    }
+}
@@ -0,0 +1,325 @@
+//! Configuration files for `y-sweet serve`.
+//!
+//! A configuration file (TOML, or YAML for `.yaml` and `.yml` files) sets the options of
+//! `serve` under the names of their flags, with underscores: `checkpoint_freq_seconds = 30`
+//! for `--checkpoint-freq-seconds 30`. Each option is applied as the environment variable
+//! of its flag, unless that variable is already set, so options are taken from flags first,
+//! then environment variables, then the file, then their defaults.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use url::Url;
+
+/// Environment variable holding the path of the configuration file.
+pub const CONFIG_ENV: &str = "Y_SWEET_CONFIG";
+
+/// Options whose value is never printed.
+const SECRET_KEYS: &[&str] = &["auth", "asset_events_secret"];
+
+/// The value of an option, as its environment variable holds it.
+trait EnvValue {
+    fn to_env(&self) -> String;
+}
+
+impl EnvValue for String {
+    fn to_env(&self) -> String {
+        self.clone()
+    }
+}
+
+impl EnvValue for bool {
+    fn to_env(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl EnvValue for u16 {
+    fn to_env(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl EnvValue for u32 {
+    fn to_env(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl EnvValue for u64 {
+    fn to_env(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl EnvValue for Vec<String> {
+    fn to_env(&self) -> String {
+        self.join(",")
+    }
+}
+
+macro_rules! serve_config {
+    ($($key:ident: $ty:ty => $env:literal,)*) => {
+        /// The options of `y-sweet serve` a configuration file can set.
+        #[derive(Deserialize, Serialize, Debug, Default, Clone)]
+        #[serde(deny_unknown_fields)]
+        pub struct ServeConfig {
+            $(
+                #[serde(default, skip_serializing_if = "Option::is_none")]
+                pub $key: Option<$ty>,
+            )*
+        }
+
+        impl ServeConfig {
+            /// The name and environment variable of every option.
+            pub const OPTIONS: &'static [(&'static str, &'static str)] =
+                &[$((stringify!($key), $env),)*];
+
+            /// The environment variables of the options set, with their values.
+            pub fn env_vars(&self) -> Vec<(&'static str, String)> {
+                let mut vars = Vec::new();
+                $(
+                    if let Some(value) = &self.$key {
+                        vars.push(($env, value.to_env()));
+                    }
+                )*
+                vars
+            }
+        }
+    };
+}
+
+serve_config! {
+    store: String => "Y_SWEET_STORE",
+    store_routes: Vec<String> => "Y_SWEET_STORE_ROUTES",
+    port: u16 => "PORT",
+    host: String => "Y_SWEET_HOST",
+    unix_socket: String => "Y_SWEET_UNIX_SOCKET",
+    checkpoint_freq_seconds: u64 => "Y_SWEET_CHECKPOINT_FREQ_SECONDS",
+    checkpoint_max_updates: u64 => "Y_SWEET_CHECKPOINT_MAX_UPDATES",
+    checkpoint_max_bytes: u64 => "Y_SWEET_CHECKPOINT_MAX_BYTES",
+    snapshot_backup: bool => "Y_SWEET_SNAPSHOT_BACKUP",
+    snapshot_shard_bytes: u64 => "Y_SWEET_SNAPSHOT_SHARD_BYTES",
+    persist_max_retries: u32 => "Y_SWEET_PERSIST_MAX_RETRIES",
+    persist_reject_writes_after_seconds: u64 => "Y_SWEET_PERSIST_REJECT_WRITES_AFTER_SECONDS",
+    auth: String => "Y_SWEET_AUTH",
+    url_prefix: String => "Y_SWEET_URL_PREFIX",
+    base_path: String => "Y_SWEET_BASE_PATH",
+    trusted_proxies: Vec<String> => "Y_SWEET_TRUSTED_PROXIES",
+    slow_request_ms: u64 => "Y_SWEET_SLOW_REQUEST_MS",
+    slow_message_ms: u64 => "Y_SWEET_SLOW_MESSAGE_MS",
+    slow_persist_ms: u64 => "Y_SWEET_SLOW_PERSIST_MS",
+    prod: bool => "Y_SWEET_PROD",
+    log_format: String => "Y_SWEET_LOG_FORMAT",
+    redact_fields: Vec<String> => "Y_SWEET_REDACT_FIELDS",
+    redact_mode: String => "Y_SWEET_REDACT_MODE",
+    max_body_size: u64 => "Y_SWEET_MAX_BODY_SIZE",
+    max_update_body_size: u64 => "Y_SWEET_MAX_UPDATE_BODY_SIZE",
+    max_import_body_size: u64 => "Y_SWEET_MAX_IMPORT_BODY_SIZE",
+    max_asset_body_size: u64 => "Y_SWEET_MAX_ASSET_BODY_SIZE",
+    skip_gc: bool => "Y_SWEET_SKIP_GC",
+    disable_compression: bool => "Y_SWEET_DISABLE_COMPRESSION",
+    ws_ping_interval_seconds: u64 => "Y_SWEET_WS_PING_INTERVAL_SECONDS",
+    ws_idle_timeout_seconds: u64 => "Y_SWEET_WS_IDLE_TIMEOUT_SECONDS",
+    ws_send_buffer: u64 => "Y_SWEET_WS_SEND_BUFFER",
+    slow_consumer_policy: String => "Y_SWEET_SLOW_CONSUMER_POLICY",
+    ws_max_message_size: u64 => "Y_SWEET_WS_MAX_MESSAGE_SIZE",
+    ws_max_messages_per_second: u32 => "Y_SWEET_WS_MAX_MESSAGES_PER_SECOND",
+    shutdown_delay_seconds: u64 => "Y_SWEET_SHUTDOWN_DELAY_SECONDS",
+    shutdown_drain_seconds: u64 => "Y_SWEET_SHUTDOWN_DRAIN_SECONDS",
+    shutdown_retry_after_seconds: u64 => "Y_SWEET_SHUTDOWN_RETRY_AFTER_SECONDS",
+    shutdown_persist_concurrency: u64 => "Y_SWEET_SHUTDOWN_PERSIST_CONCURRENCY",
+    shutdown_persist_deadline_seconds: u64 => "Y_SWEET_SHUTDOWN_PERSIST_DEADLINE_SECONDS",
+    shutdown_timeout_seconds: u64 => "Y_SWEET_SHUTDOWN_TIMEOUT_SECONDS",
+    max_connections: u64 => "Y_SWEET_MAX_CONNECTIONS",
+    max_connections_per_doc: u64 => "Y_SWEET_MAX_CONNECTIONS_PER_DOC",
+    ttl_reap_interval_seconds: u64 => "Y_SWEET_TTL_REAP_INTERVAL_SECONDS",
+    update_log: bool => "Y_SWEET_UPDATE_LOG",
+    snapshot_every: u32 => "Y_SWEET_SNAPSHOT_EVERY",
+    compaction_interval_seconds: u64 => "Y_SWEET_COMPACTION_INTERVAL_SECONDS",
+    compaction_min_segments: u32 => "Y_SWEET_COMPACTION_MIN_SEGMENTS",
+    doc_lease_seconds: u64 => "Y_SWEET_DOC_LEASE_SECONDS",
+    follower: bool => "Y_SWEET_FOLLOWER",
+    follower_refresh_seconds: u64 => "Y_SWEET_FOLLOWER_REFRESH_SECONDS",
+    wal_dir: String => "Y_SWEET_WAL_DIR",
+    memory_budget_mb: u64 => "Y_SWEET_MEMORY_BUDGET_MB",
+    doc_idle_timeout_seconds: u64 => "Y_SWEET_DOC_IDLE_TIMEOUT_SECONDS",
+    doc_min_residency_seconds: u64 => "Y_SWEET_DOC_MIN_RESIDENCY_SECONDS",
+    pinned_docs: Vec<String> => "Y_SWEET_PINNED_DOCS",
+    preload_docs: Vec<String> => "Y_SWEET_PRELOAD_DOCS",
+    preload_prefix: String => "Y_SWEET_PRELOAD_PREFIX",
+    preload_manifest: String => "Y_SWEET_PRELOAD_MANIFEST",
+    cluster_node_id: String => "Y_SWEET_CLUSTER_NODE_ID",
+    cluster_nodes: String => "Y_SWEET_CLUSTER_NODES",
+    client_url_template: String => "Y_SWEET_CLIENT_URL_TEMPLATE",
+    doc_id_length: u64 => "Y_SWEET_DOC_ID_LENGTH",
+    doc_id_alphabet: String => "Y_SWEET_DOC_ID_ALPHABET",
+    doc_id_prefix: String => "Y_SWEET_DOC_ID_PREFIX",
+    tenants: String => "Y_SWEET_TENANTS",
+    quotas: String => "Y_SWEET_QUOTAS",
+    audit: bool => "Y_SWEET_AUDIT",
+    doc_stats_flush: String => "Y_SWEET_DOC_STATS_FLUSH",
+    asset_webhook_url: String => "Y_SWEET_ASSET_WEBHOOK_URL",
+    asset_hook_retries: u32 => "Y_SWEET_ASSET_HOOK_RETRIES",
+    asset_events_secret: String => "Y_SWEET_ASSET_EVENTS_SECRET",
+    search_index_dir: String => "Y_SWEET_SEARCH_INDEX_DIR",
+    telemetry: String => "Y_SWEET_TELEMETRY",
+    admin_port: u16 => "Y_SWEET_ADMIN_PORT",
+    admin_host: String => "Y_SWEET_ADMIN_HOST",
+    grpc_port: u16 => "Y_SWEET_GRPC_PORT",
+    webtransport: bool => "Y_SWEET_WEBTRANSPORT",
+    redis_url: String => "Y_SWEET_REDIS_URL",
+    redis_channel_prefix: String => "Y_SWEET_REDIS_CHANNEL_PREFIX",
+    nats_url: String => "Y_SWEET_NATS_URL",
+    nats_subject_prefix: String => "Y_SWEET_NATS_SUBJECT_PREFIX",
+    nats_stream: String => "Y_SWEET_NATS_STREAM",
+    tls_cert: String => "Y_SWEET_TLS_CERT",
+    tls_key: String => "Y_SWEET_TLS_KEY",
+    acme_domains: Vec<String> => "Y_SWEET_ACME_DOMAINS",
+    acme_contact: String => "Y_SWEET_ACME_CONTACT",
+    acme_cache_dir: String => "Y_SWEET_ACME_CACHE_DIR",
+    acme_staging: bool => "Y_SWEET_ACME_STAGING",
+}
+
+/// Read the configuration file at `path`.
+pub fn load_config(path: &Path) -> Result<ServeConfig> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read the configuration file {:?}", path))?;
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default();
+    match extension {
+        "toml" => toml::from_str(&content)
+            .with_context(|| format!("Invalid configuration file {:?}", path)),
+        "yaml" | "yml" => serde_yaml::from_str(&content)
+            .with_context(|| format!("Invalid configuration file {:?}", path)),
+        _ => bail!(
+            "The configuration file {:?} must be a .toml, .yaml or .yml file",
+            path
+        ),
+    }
+}
+
+/// Set the environment variable of every option of `config` that is not already set.
+pub fn apply_config(config: &ServeConfig) {
+    for (env, value) in config.env_vars() {
+        if std::env::var_os(env).is_none() {
+            std::env::set_var(env, value);
+        }
+    }
+}
+
+/// The configuration file given to `serve` on the command line (`args`, program name
+/// included), or in the environment.
+pub fn config_path_from_args(args: &[String]) -> Option<PathBuf> {
+    if args.get(1).map(String::as_str) != Some("serve") {
+        return None;
+    }
+    let mut args = args.iter().skip(2);
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+    std::env::var_os(CONFIG_ENV).map(PathBuf::from)
+}
+
+/// The value of an option and where it comes from.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ResolvedOption {
+    pub key: &'static str,
+    pub env: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    /// `env`, `file`, `default`, or `unset`.
+    pub source: &'static str,
+}
+
+/// Resolve every option from the environment, then `config`, then `default`, which gives
+/// the default value of an option by name. Secrets are redacted.
+pub fn resolve_config(
+    config: &ServeConfig,
+    default: impl Fn(&str) -> Option<String>,
+) -> Vec<ResolvedOption> {
+    let from_file = config.env_vars();
+    ServeConfig::OPTIONS
+        .iter()
+        .map(|&(key, env)| {
+            let (value, source) = if let Ok(value) = std::env::var(env) {
+                (Some(value), "env")
+            } else if let Some((_, value)) = from_file.iter().find(|(e, _)| *e == env) {
+                (Some(value.clone()), "file")
+            } else if let Some(value) = default(key) {
+                (Some(value), "default")
+            } else {
+                (None, "unset")
+            };
+            ResolvedOption {
+                key,
+                env,
+                value: value.map(|value| redact(key, value)),
+                source,
+            }
+        })
+        .collect()
+}
+
+fn redact(key: &str, value: String) -> String {
+    if SECRET_KEYS.contains(&key) {
+        return "<redacted>".to_string();
+    }
+    // Credentials in URLs, e.g. of Redis
+    match Url::parse(&value) {
+        Ok(mut url) if url.password().is_some() => {
+            let _ = url.set_password(Some("redacted"));
+            url.to_string()
+        }
+        _ => value,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_loads_and_resolves_config() {
+        let dir = std::env::temp_dir().join(format!("y-sweet-config-{}", nanoid::nanoid!()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("config.toml");
+        std::fs::write(
+            &path,
+            "store = \"s3://bucket/docs\"\nport = 9000\nauth = \"secret\"\npinned_docs = [\"a\", \"b\"]\n",
+        )
+        .unwrap();
+        let config = load_config(&path).unwrap();
+        assert_eq!(config.port, Some(9000));
+        let vars = config.env_vars();
+        assert!(vars.contains(&("Y_SWEET_PINNED_DOCS", "a,b".to_string())));
+
+        let resolved = resolve_config(&config, |key| {
+            (key == "ws_send_buffer").then(|| "1024".to_string())
+        });
+        let option = |key: &str| resolved.iter().find(|option| option.key == key).unwrap();
+        assert_eq!(option("store").source, "file");
+        assert_eq!(option("auth").value.as_deref(), Some("<redacted>"));
+        assert_eq!(option("ws_send_buffer").source, "default");
+        assert_eq!(option("grpc_port").source, "unset");
+
+        let path = dir.join("config.yaml");
+        std::fs::write(&path, "port: 9000\nunknown_option: 1\n").unwrap();
+        assert!(load_config(&path).is_err());
+
+        let args = ["y-sweet", "serve", "--config=config.toml"].map(String::from);
+        assert_eq!(
+            config_path_from_args(&args),
+            Some(PathBuf::from("config.toml"))
+        );
+    }
+}
@@ -0,0 +1,169 @@
+use anyhow::anyhow;
+use axum::http::StatusCode;
+use dashmap::{mapref::entry::Entry, DashMap};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use tracing::warn;
+
+use crate::server::AppError;
+
+/// Caps on concurrent WebSocket connections, per server and per document.
+#[derive(Default)]
+pub struct ConnectionLimits {
+    max_connections: Option<usize>,
+    max_connections_per_doc: Option<usize>,
+    total: AtomicUsize,
+    per_doc: DashMap<String, usize>,
+}
+
+impl ConnectionLimits {
+    pub fn new(max_connections: Option<usize>, max_connections_per_doc: Option<usize>) -> Self {
+        Self {
+            max_connections,
+            max_connections_per_doc,
+            ..Default::default()
+        }
+    }
+
+    pub fn max_connections(&self) -> Option<usize> {
+        self.max_connections
+    }
+
+    pub fn max_connections_per_doc(&self) -> Option<usize> {
+        self.max_connections_per_doc
+    }
+
+    /// Number of open connections on the server
+    pub fn total(&self) -> usize {
+        self.total.load(Ordering::SeqCst)
+    }
+
+    /// Number of open connections to a document
+    pub fn for_doc(&self, doc_id: &str) -> usize {
+        self.per_doc
+            .get(doc_id)
+            .map(|count| *count)
+            .unwrap_or_default()
+    }
+
+    /// Reserve a slot for a new connection to `doc_id`, or fail with 429 if a limit is reached.
+    /// The slot is released when the returned permit is dropped.
+    pub fn acquire(self: &Arc<Self>, doc_id: &str) -> Result<ConnectionPermit, AppError> {
+        let max_connections = self.max_connections.unwrap_or(usize::MAX);
+        if self
+            .total
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |total| {
+                (total < max_connections).then_some(total + 1)
+            })
+            .is_err()
+        {
+            warn!(
+                message = "Server connection limit reached",
+                event = "websocket_connection_limit_exceeded",
+                doc_id = %doc_id,
+                limit = max_connections,
+                scope = "server"
+            );
+            return Err(AppError(
+                StatusCode::TOO_MANY_REQUESTS,
+                anyhow!("Too many connections to this server"),
+            ));
+        }
+
+        let max_connections_per_doc = self.max_connections_per_doc.unwrap_or(usize::MAX);
+        // The entry holds the shard lock, so checking and incrementing is atomic
+        let admitted = match self.per_doc.entry(doc_id.to_string()) {
+            Entry::Occupied(mut entry) if *entry.get() < max_connections_per_doc => {
+                *entry.get_mut() += 1;
+                true
+            }
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) if max_connections_per_doc > 0 => {
+                entry.insert(1);
+                true
+            }
+            Entry::Vacant(_) => false,
+        };
+
+        if !admitted {
+            self.total.fetch_sub(1, Ordering::SeqCst);
+            warn!(
+                message = "Document connection limit reached",
+                event = "websocket_connection_limit_exceeded",
+                doc_id = %doc_id,
+                limit = max_connections_per_doc,
+                scope = "document"
+            );
+            return Err(AppError(
+                StatusCode::TOO_MANY_REQUESTS,
+                anyhow!("Too many connections to this document"),
+            ));
+        }
+
+        Ok(ConnectionPermit {
+            limits: self.clone(),
+            doc_id: doc_id.to_string(),
+        })
+    }
+
+    fn release(&self, doc_id: &str) {
+        if let Entry::Occupied(mut entry) = self.per_doc.entry(doc_id.to_string()) {
+            if *entry.get() <= 1 {
+                entry.remove();
+            } else {
+                *entry.get_mut() -= 1;
+            }
+        }
+        self.total.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A reserved connection slot, released on drop.
+pub struct ConnectionPermit {
+    limits: Arc<ConnectionLimits>,
+    doc_id: String,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.limits.release(&self.doc_id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_connection_limits() {
+        let limits = Arc::new(ConnectionLimits::new(Some(3), Some(2)));
+
+        let a1 = limits.acquire("a").unwrap();
+        let _a2 = limits.acquire("a").unwrap();
+        let error = limits.acquire("a").err().unwrap();
+        assert_eq!(error.0, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(limits.for_doc("a"), 2);
+
+        let _b1 = limits.acquire("b").unwrap();
+        let error = limits.acquire("c").err().unwrap();
+        assert_eq!(error.0, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(limits.total(), 3);
+
+        drop(a1);
+        assert_eq!(limits.for_doc("a"), 1);
+        let _c1 = limits.acquire("c").unwrap();
+        assert_eq!(limits.total(), 3);
+    }
+
+    #[test]
+    fn test_unlimited_by_default() {
+        let limits = Arc::new(ConnectionLimits::default());
+        let permits: Vec<_> = (0..100).map(|_| limits.acquire("a").unwrap()).collect();
+        assert_eq!(limits.for_doc("a"), 100);
+        drop(permits);
+        assert_eq!(limits.total(), 0);
+        assert_eq!(limits.for_doc("a"), 0);
+    }
+}
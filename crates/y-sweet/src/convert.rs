@@ -1,13 +1,34 @@
-use anyhow::Result;
-use std::sync::Arc;
+use anyhow::{anyhow, bail, Result};
+use serde::{
+    ser::{Error as _, SerializeMap, SerializeSeq},
+    Serialize, Serializer,
+};
+use serde_json::json;
+use std::{io::Write, sync::Arc};
 use y_sweet_core::{doc_connection::DOC_NAME, store::Store, sync_kv::SyncKv};
+use yrs::{
+    types::{
+        text::{Diff, YChange},
+        Attrs, ToJson,
+    },
+    updates::decoder::Decode,
+    Any, Array, Doc, GetString, Map, Out, ReadTxn, StateVector, Text, Transact, TransactionMut,
+    Update, Xml, XmlElementPrelim, XmlElementRef, XmlFragment, XmlOut, XmlTextPrelim, XmlTextRef,
+};
 use yrs_kvstore::DocOps;
 
 /// Convert a Yjs document (encoded as a v1 update) to a .ysweet store.
 pub async fn convert(store: Box<dyn Store>, doc_as_update: &[u8], doc_id: &str) -> Result<()> {
-    let store = Some(Arc::new(store));
+    convert_to_store(&Arc::new(store), doc_as_update, doc_id).await
+}
 
-    let sync_kv = SyncKv::new(store, doc_id, || ()).await?;
+/// Like [convert], with a store shared by several conversions.
+pub async fn convert_to_store(
+    store: &Arc<Box<dyn Store>>,
+    doc_as_update: &[u8],
+    doc_id: &str,
+) -> Result<()> {
+    let sync_kv = SyncKv::new(Some(store.clone()), doc_id, || ()).await?;
 
     let sync_kv = Arc::new(sync_kv);
 
@@ -26,3 +47,1026 @@ pub async fn convert(store: Box<dyn Store>, doc_as_update: &[u8], doc_id: &str)
 
     Ok(())
 }
+
+fn load_update(doc_as_update: &[u8]) -> Result<Doc> {
+    let doc = Doc::new();
+    let update =
+        Update::decode_v1(doc_as_update).map_err(|_| anyhow!("Failed to decode update"))?;
+    doc.transact_mut().apply_update(update);
+    Ok(doc)
+}
+
+/// Convert a Yjs document (encoded as a v1 update) to JSON, with a field for each of its
+/// root types. Updates do not record the types of roots, so each root is read as a map if
+/// it has entries, as text if it holds text, and as an array otherwise.
+pub fn doc_to_json(doc_as_update: &[u8]) -> Result<serde_json::Value> {
+    let roots = JsonRoots {
+        doc_as_update,
+        names: root_names(doc_as_update)?,
+    };
+    Ok(serde_json::to_value(roots)?)
+}
+
+/// Like [doc_to_json], writing the JSON to `writer` one root type at a time rather than
+/// building the whole document in memory first.
+pub fn write_json<W: Write>(doc_as_update: &[u8], writer: W) -> Result<()> {
+    let roots = JsonRoots {
+        doc_as_update,
+        names: root_names(doc_as_update)?,
+    };
+    serde_json::to_writer(writer, &roots)?;
+    Ok(())
+}
+
+/// The root types of a document, serialized one at a time.
+struct JsonRoots<'a> {
+    doc_as_update: &'a [u8],
+    names: Vec<String>,
+}
+
+impl Serialize for JsonRoots<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.names.len()))?;
+        for name in &self.names {
+            let (_, value) = read_root(self.doc_as_update, name).map_err(S::Error::custom)?;
+            map.serialize_entry(name, &value)?;
+        }
+        map.end()
+    }
+}
+
+/// Convert JSON with a field for each root type, as written by [doc_to_json], to a Yjs
+/// document encoded as a v1 update. Objects become maps, strings text, and arrays arrays.
+pub fn json_to_update(json: &serde_json::Value) -> Result<Vec<u8>> {
+    let serde_json::Value::Object(roots) = json else {
+        return Err(anyhow!("The document must be a JSON object of root types"));
+    };
+    let doc = Doc::new();
+    for (name, value) in roots {
+        match value {
+            serde_json::Value::Object(entries) => {
+                let map = doc.get_or_insert_map(name.as_str());
+                let mut txn = doc.transact_mut();
+                for (key, value) in entries {
+                    map.insert(
+                        &mut txn,
+                        key.as_str(),
+                        serde_json::from_value::<Any>(value.clone())?,
+                    );
+                }
+            }
+            serde_json::Value::String(string) => {
+                let text = doc.get_or_insert_text(name.as_str());
+                text.insert(&mut doc.transact_mut(), 0, string);
+            }
+            serde_json::Value::Array(items) => {
+                let array = doc.get_or_insert_array(name.as_str());
+                let mut txn = doc.transact_mut();
+                for item in items {
+                    array.push_back(&mut txn, serde_json::from_value::<Any>(item.clone())?);
+                }
+            }
+            _ => {
+                return Err(anyhow!(
+                    "Root type {} must be an object, a string or an array",
+                    name
+                ))
+            }
+        }
+    }
+    let update = doc
+        .transact()
+        .encode_state_as_update_v1(&StateVector::default());
+    Ok(update)
+}
+
+/// Names of the root types of a Yjs document (encoded as a v1 update).
+pub fn root_names(doc_as_update: &[u8]) -> Result<Vec<String>> {
+    let doc = load_update(doc_as_update)?;
+    let txn = doc.transact();
+    Ok(txn.root_refs().map(|(name, _)| name.to_string()).collect())
+}
+
+/// The kind (`map`, `text` or `array`) and JSON content of the root type `name`, guessed
+/// as described in [doc_to_json].
+pub fn read_root(doc_as_update: &[u8], name: &str) -> Result<(&'static str, serde_json::Value)> {
+    // Reading a root fixes its type, so each attempt starts from a fresh document
+    let doc = load_update(doc_as_update)?;
+    let map = doc.get_or_insert_map(name);
+    let txn = doc.transact();
+    if map.len(&txn) > 0 {
+        return Ok(("map", serde_json::to_value(map.to_json(&txn))?));
+    }
+
+    let doc = load_update(doc_as_update)?;
+    let text = doc.get_or_insert_text(name);
+    let string = text.get_string(&doc.transact());
+    if !string.is_empty() {
+        return Ok(("text", serde_json::Value::String(string)));
+    }
+
+    let doc = load_update(doc_as_update)?;
+    let array = doc.get_or_insert_array(name);
+    let value = serde_json::to_value(array.to_json(&doc.transact()))?;
+    Ok(("array", value))
+}
+
+/// Root type exported as text when none is given, the one Tiptap binds editors to.
+pub const DEFAULT_TEXT_ROOT: &str = "default";
+
+/// Text formats a document can be rendered to by [doc_to_text].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextFormat {
+    Markdown,
+    Text,
+}
+
+/// Convert the XmlFragment `root` of a Yjs document (encoded as a v1 update) to a
+/// ProseMirror (Tiptap) JSON document, mapped the way y-prosemirror maps them: elements are
+/// nodes named after their tag, with their attributes as attrs, and texts are text nodes
+/// with their formatting attributes as marks. Attributes that parse as JSON other than a
+/// string (e.g. a heading `level`) are read as that value.
+pub fn doc_to_prosemirror(doc_as_update: &[u8], root: &str) -> Result<serde_json::Value> {
+    let doc = load_update(doc_as_update)?;
+    let fragment = doc.get_or_insert_xml_fragment(root);
+    let txn = doc.transact();
+    let content = ProsemirrorContent {
+        parent: &fragment,
+        txn: &txn,
+    };
+    Ok(json!({
+        "type": "doc",
+        "content": serde_json::to_value(content)?,
+    }))
+}
+
+/// Like [doc_to_prosemirror], writing the JSON to `writer` node by node rather than
+/// building the whole document in memory first.
+pub fn write_prosemirror<W: Write>(doc_as_update: &[u8], root: &str, mut writer: W) -> Result<()> {
+    let doc = load_update(doc_as_update)?;
+    let fragment = doc.get_or_insert_xml_fragment(root);
+    let txn = doc.transact();
+    writer.write_all(br#"{"type":"doc","content":"#)?;
+    let content = ProsemirrorContent {
+        parent: &fragment,
+        txn: &txn,
+    };
+    serde_json::to_writer(&mut writer, &content)?;
+    writer.write_all(b"}")?;
+    Ok(())
+}
+
+/// Call `f` with each top-level node of the ProseMirror document [doc_to_prosemirror]
+/// converts the XmlFragment `root` to, one at a time, so that documents can be rendered
+/// without holding all of their nodes in memory.
+pub fn for_each_prosemirror_node(
+    doc_as_update: &[u8],
+    root: &str,
+    mut f: impl FnMut(serde_json::Value) -> Result<()>,
+) -> Result<()> {
+    let doc = load_update(doc_as_update)?;
+    let fragment = doc.get_or_insert_xml_fragment(root);
+    let txn = doc.transact();
+    visit_prosemirror_nodes(&fragment, &txn, &mut f)
+}
+
+fn visit_prosemirror_nodes<F: XmlFragment, T: ReadTxn>(
+    parent: &F,
+    txn: &T,
+    f: &mut impl FnMut(serde_json::Value) -> Result<()>,
+) -> Result<()> {
+    for index in 0..parent.len(txn) {
+        match parent.get(txn, index) {
+            Some(XmlOut::Element(element)) => f(serde_json::to_value(ProsemirrorElement {
+                element: &element,
+                txn,
+            })?)?,
+            Some(XmlOut::Text(text)) => {
+                for node in prosemirror_text_nodes(&text, txn)? {
+                    f(node)?;
+                }
+            }
+            Some(XmlOut::Fragment(fragment)) => visit_prosemirror_nodes(&fragment, txn, f)?,
+            None => {}
+        }
+    }
+    Ok(())
+}
+
+/// The content of a node, serialized child by child as ProseMirror nodes.
+struct ProsemirrorContent<'a, F, T> {
+    parent: &'a F,
+    txn: &'a T,
+}
+
+impl<F: XmlFragment, T: ReadTxn> Serialize for ProsemirrorContent<'_, F, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(None)?;
+        serialize_prosemirror_nodes(&mut seq, self.parent, self.txn)?;
+        seq.end()
+    }
+}
+
+fn serialize_prosemirror_nodes<S: SerializeSeq, F: XmlFragment, T: ReadTxn>(
+    seq: &mut S,
+    parent: &F,
+    txn: &T,
+) -> Result<(), S::Error> {
+    for index in 0..parent.len(txn) {
+        match parent.get(txn, index) {
+            Some(XmlOut::Element(element)) => {
+                seq.serialize_element(&ProsemirrorElement {
+                    element: &element,
+                    txn,
+                })?;
+            }
+            Some(XmlOut::Text(text)) => {
+                for node in prosemirror_text_nodes(&text, txn).map_err(S::Error::custom)? {
+                    seq.serialize_element(&node)?;
+                }
+            }
+            Some(XmlOut::Fragment(fragment)) => serialize_prosemirror_nodes(seq, &fragment, txn)?,
+            None => {}
+        }
+    }
+    Ok(())
+}
+
+/// An element, serialized as a ProseMirror node named after its tag, with its attributes
+/// as attrs.
+struct ProsemirrorElement<'a, T> {
+    element: &'a XmlElementRef,
+    txn: &'a T,
+}
+
+impl<T: ReadTxn> Serialize for ProsemirrorElement<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (element, txn) = (self.element, self.txn);
+        let mut node = serializer.serialize_map(None)?;
+        node.serialize_entry("type", element.tag().as_ref())?;
+        let attrs: serde_json::Map<String, serde_json::Value> = element
+            .attributes(txn)
+            .map(|(name, value)| {
+                let value = value.to_string();
+                let parsed = serde_json::from_str(&value)
+                    .ok()
+                    .filter(|parsed: &serde_json::Value| !parsed.is_string());
+                (name.to_string(), parsed.unwrap_or(value.into()))
+            })
+            .collect();
+        if !attrs.is_empty() {
+            node.serialize_entry("attrs", &attrs)?;
+        }
+        if has_prosemirror_nodes(element, txn) {
+            let content = ProsemirrorContent {
+                parent: element,
+                txn,
+            };
+            node.serialize_entry("content", &content)?;
+        }
+        node.end()
+    }
+}
+
+/// Whether the content of `parent` converts to any ProseMirror node.
+fn has_prosemirror_nodes<F: XmlFragment, T: ReadTxn>(parent: &F, txn: &T) -> bool {
+    (0..parent.len(txn)).any(|index| match parent.get(txn, index) {
+        Some(XmlOut::Element(_)) => true,
+        Some(XmlOut::Text(text)) => text
+            .diff(txn, YChange::identity)
+            .iter()
+            .any(|chunk| matches!(chunk.insert, Out::Any(Any::String(_)))),
+        Some(XmlOut::Fragment(fragment)) => has_prosemirror_nodes(&fragment, txn),
+        None => false,
+    })
+}
+
+/// The text nodes of an XmlText, one for each run of text with the same formatting
+/// attributes, which are its marks.
+fn prosemirror_text_nodes<T: ReadTxn>(
+    text: &XmlTextRef,
+    txn: &T,
+) -> Result<Vec<serde_json::Value>> {
+    let mut nodes = Vec::new();
+    for chunk in text.diff(txn, YChange::identity) {
+        // Embeds have no ProseMirror equivalent
+        let Out::Any(Any::String(string)) = chunk.insert else {
+            continue;
+        };
+        let mut node = json!({ "type": "text", "text": string.as_ref() });
+        let mut marks: Vec<(String, serde_json::Value)> = chunk
+            .attributes
+            .iter()
+            .flat_map(|attributes| attributes.iter())
+            .map(|(name, value)| -> Result<(String, serde_json::Value)> {
+                let mut mark = json!({ "type": name.as_ref() });
+                if matches!(value, Any::Map(attrs) if !attrs.is_empty()) {
+                    mark["attrs"] = serde_json::to_value(value)?;
+                }
+                Ok((name.to_string(), mark))
+            })
+            .collect::<Result<_>>()?;
+        if !marks.is_empty() {
+            marks.sort_by(|(a, _), (b, _)| a.cmp(b));
+            node["marks"] = marks.into_iter().map(|(_, mark)| mark).collect();
+        }
+        nodes.push(node);
+    }
+    Ok(nodes)
+}
+
+/// Convert a ProseMirror (Tiptap) JSON document to a Yjs document with its content in the
+/// XmlFragment `root`, encoded as a v1 update. The inverse of [doc_to_prosemirror].
+pub fn prosemirror_to_update(json: &serde_json::Value, root: &str) -> Result<Vec<u8>> {
+    if json.get("type").and_then(|kind| kind.as_str()) != Some("doc") {
+        bail!("The document must be a ProseMirror node of type doc");
+    }
+    let doc = Doc::new();
+    let fragment = doc.get_or_insert_xml_fragment(root);
+    {
+        let mut txn = doc.transact_mut();
+        insert_prosemirror_content(&fragment, &mut txn, json)?;
+    }
+    let update = doc
+        .transact()
+        .encode_state_as_update_v1(&StateVector::default());
+    Ok(update)
+}
+
+fn insert_prosemirror_content<F: XmlFragment>(
+    parent: &F,
+    txn: &mut TransactionMut,
+    node: &serde_json::Value,
+) -> Result<()> {
+    let content = match node.get("content") {
+        None => return Ok(()),
+        Some(serde_json::Value::Array(content)) => content,
+        Some(_) => bail!("The content of a node must be an array"),
+    };
+
+    // Consecutive text nodes are one XmlText, as in y-prosemirror
+    let mut current_text: Option<XmlTextRef> = None;
+    for child in content {
+        let kind = child
+            .get("type")
+            .and_then(|kind| kind.as_str())
+            .ok_or_else(|| anyhow!("Every node must have a type"))?;
+
+        if kind == "text" {
+            let string = child
+                .get("text")
+                .and_then(|text| text.as_str())
+                .unwrap_or("");
+            let mut attributes = Attrs::new();
+            for mark in child
+                .get("marks")
+                .and_then(|marks| marks.as_array())
+                .into_iter()
+                .flatten()
+            {
+                let name = mark
+                    .get("type")
+                    .and_then(|kind| kind.as_str())
+                    .ok_or_else(|| anyhow!("Every mark must have a type"))?;
+                let attrs = mark.get("attrs").cloned().unwrap_or_else(|| json!({}));
+                attributes.insert(name.into(), serde_json::from_value::<Any>(attrs)?);
+            }
+            let xml_text =
+                current_text.get_or_insert_with(|| parent.push_back(txn, XmlTextPrelim::new("")));
+            let index = xml_text.len(&*txn);
+            xml_text.insert_with_attributes(txn, index, string, attributes);
+            continue;
+        }
+
+        current_text = None;
+        let element = parent.push_back(txn, XmlElementPrelim::empty(kind));
+        if let Some(serde_json::Value::Object(attrs)) = child.get("attrs") {
+            for (name, value) in attrs {
+                let value = match value {
+                    serde_json::Value::Null => continue,
+                    serde_json::Value::String(string) => string.clone(),
+                    value => value.to_string(),
+                };
+                element.insert_attribute(txn, name.as_str(), value.as_str());
+            }
+        }
+        insert_prosemirror_content(&element, txn, child)?;
+    }
+    Ok(())
+}
+
+/// Convert the text `root` of a Yjs document (encoded as a v1 update) to a Quill Delta,
+/// mapped the way y-quill maps them: each run of text with the same formatting attributes
+/// is an insert with those attributes, and each embed (e.g. an image) an insert of its
+/// object. Returns `{"ops": [...]}`, as Quill's `getContents` does.
+pub fn doc_to_delta(doc_as_update: &[u8], root: &str) -> Result<serde_json::Value> {
+    let doc = load_update(doc_as_update)?;
+    let text = doc.get_or_insert_text(root);
+    let txn = doc.transact();
+    let ops = DeltaOps {
+        text: &text,
+        txn: &txn,
+    };
+    Ok(json!({ "ops": serde_json::to_value(ops)? }))
+}
+
+/// Like [doc_to_delta], writing the JSON to `writer` op by op rather than building the
+/// whole delta in memory first.
+pub fn write_delta<W: Write>(doc_as_update: &[u8], root: &str, mut writer: W) -> Result<()> {
+    let doc = load_update(doc_as_update)?;
+    let text = doc.get_or_insert_text(root);
+    let txn = doc.transact();
+    writer.write_all(br#"{"ops":"#)?;
+    let ops = DeltaOps {
+        text: &text,
+        txn: &txn,
+    };
+    serde_json::to_writer(&mut writer, &ops)?;
+    writer.write_all(b"}")?;
+    Ok(())
+}
+
+/// The content of a text, serialized op by op as the inserts of a Quill Delta.
+struct DeltaOps<'a, X, T> {
+    text: &'a X,
+    txn: &'a T,
+}
+
+impl<X: Text, T: ReadTxn> Serialize for DeltaOps<'_, X, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(None)?;
+        for chunk in self.text.diff(self.txn, YChange::identity) {
+            if let Some(op) = delta_op(chunk).map_err(S::Error::custom)? {
+                seq.serialize_element(&op)?;
+            }
+        }
+        seq.end()
+    }
+}
+
+/// The insert op of a run of text or an embed, `None` for shared types embedded in a text,
+/// which have no Delta representation.
+fn delta_op(chunk: Diff<YChange>) -> Result<Option<serde_json::Value>> {
+    let Out::Any(insert) = chunk.insert else {
+        return Ok(None);
+    };
+    let mut op = serde_json::Map::new();
+    op.insert("insert".to_string(), serde_json::to_value(&insert)?);
+    if let Some(attributes) = chunk.attributes.filter(|attributes| !attributes.is_empty()) {
+        let mut attributes: Vec<_> = attributes.into_iter().collect();
+        attributes.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let mut json_attributes = serde_json::Map::new();
+        for (name, value) in attributes {
+            json_attributes.insert(name.to_string(), serde_json::to_value(&value)?);
+        }
+        op.insert(
+            "attributes".to_string(),
+            serde_json::Value::Object(json_attributes),
+        );
+    }
+    Ok(Some(serde_json::Value::Object(op)))
+}
+
+/// Convert a Quill Delta, either `{"ops": [...]}` or the array of ops, to a Yjs document
+/// with its content in the text `root`, encoded as a v1 update. The inverse of
+/// [doc_to_delta]. A document is made of inserts only, so deltas with `retain` or `delete`
+/// ops (changes rather than documents) are rejected.
+pub fn delta_to_update(json: &serde_json::Value, root: &str) -> Result<Vec<u8>> {
+    let ops = match json {
+        serde_json::Value::Array(ops) => ops,
+        serde_json::Value::Object(delta) => match delta.get("ops") {
+            Some(serde_json::Value::Array(ops)) => ops,
+            _ => bail!("The delta must have an array of ops"),
+        },
+        _ => bail!("The delta must be an object with ops or an array of ops"),
+    };
+    let doc = Doc::new();
+    let text = doc.get_or_insert_text(root);
+    {
+        let mut txn = doc.transact_mut();
+        for op in ops {
+            let mut attributes = Attrs::new();
+            if let Some(op_attributes) = op.get("attributes") {
+                let serde_json::Value::Object(op_attributes) = op_attributes else {
+                    bail!("The attributes of an op must be an object");
+                };
+                for (name, value) in op_attributes {
+                    // Null attributes remove formatting, which a document has none to remove
+                    if !value.is_null() {
+                        attributes.insert(
+                            name.as_str().into(),
+                            serde_json::from_value::<Any>(value.clone())?,
+                        );
+                    }
+                }
+            }
+            let index = text.len(&txn);
+            match op.get("insert") {
+                Some(serde_json::Value::String(string)) => {
+                    text.insert_with_attributes(&mut txn, index, string, attributes);
+                }
+                Some(embed @ serde_json::Value::Object(_)) => {
+                    let embed = serde_json::from_value::<Any>(embed.clone())?;
+                    text.insert_embed_with_attributes(&mut txn, index, embed, attributes);
+                }
+                Some(_) => bail!("Inserts must be a string or an embed object"),
+                None => bail!("The delta of a document must only have insert ops"),
+            }
+        }
+    }
+    let update = doc
+        .transact()
+        .encode_state_as_update_v1(&StateVector::default());
+    Ok(update)
+}
+
+/// Render the root type `root` of a Yjs document (encoded as a v1 update) as Markdown or
+/// plain text. An XmlFragment is read as a ProseMirror document (paragraphs, headings,
+/// lists, quotes, code blocks and marks, under both the Tiptap and the ProseMirror node
+/// names); other roots are read as text, with their formatting marks. Unknown nodes are
+/// rendered as their content.
+pub fn doc_to_text(doc_as_update: &[u8], root: &str, format: TextFormat) -> Result<String> {
+    let mut text = Vec::new();
+    write_text(doc_as_update, root, format, &mut text)?;
+    Ok(String::from_utf8(text)?)
+}
+
+/// The text of every root type of a Yjs document (encoded as a v1 update), for full-text
+/// search: XmlFragments and texts as plain text (see [doc_to_text]), and the strings in
+/// maps and arrays. Roots are separated by blank lines.
+pub fn doc_to_search_text(doc_as_update: &[u8]) -> Result<String> {
+    let mut texts = Vec::new();
+    for name in root_names(doc_as_update)? {
+        let text = doc_to_text(doc_as_update, &name, TextFormat::Text)?;
+        if !text.is_empty() {
+            texts.push(text);
+            continue;
+        }
+        let (_, value) = read_root(doc_as_update, &name)?;
+        collect_strings(&value, &mut texts);
+    }
+    Ok(texts.join("\n\n"))
+}
+
+fn collect_strings(value: &serde_json::Value, strings: &mut Vec<String>) {
+    match value {
+        serde_json::Value::String(string) if !string.is_empty() => strings.push(string.clone()),
+        serde_json::Value::Array(values) => values
+            .iter()
+            .for_each(|value| collect_strings(value, strings)),
+        serde_json::Value::Object(map) => map
+            .values()
+            .for_each(|value| collect_strings(value, strings)),
+        _ => {}
+    }
+}
+
+/// Like [doc_to_text], writing the text to `writer` one top-level block (or, for a text
+/// root, one run of text) at a time rather than rendering the whole document in memory
+/// first.
+pub fn write_text<W: Write>(
+    doc_as_update: &[u8],
+    root: &str,
+    format: TextFormat,
+    mut writer: W,
+) -> Result<()> {
+    // Reading a root fixes its type, so each attempt starts from a fresh document
+    let doc = load_update(doc_as_update)?;
+    let fragment = doc.get_or_insert_xml_fragment(root);
+    let txn = doc.transact();
+    let renderer = TextRenderer { txn: &txn, format };
+    // The content of a text read as an XmlFragment has no XML nodes
+    let blocks = renderer.children(&fragment);
+    if !blocks.is_empty() {
+        let mut first = true;
+        for node in &blocks {
+            let block = renderer.block(node);
+            if block.is_empty() {
+                continue;
+            }
+            if !first {
+                writer.write_all(renderer.block_separator().as_bytes())?;
+            }
+            writer.write_all(block.as_bytes())?;
+            first = false;
+        }
+        return Ok(());
+    }
+
+    let doc = load_update(doc_as_update)?;
+    let text = doc.get_or_insert_text(root);
+    let txn = doc.transact();
+    let renderer = TextRenderer { txn: &txn, format };
+    for chunk in text.diff(&txn, YChange::identity) {
+        if let Some(string) = renderer.marked_chunk(chunk) {
+            writer.write_all(string.as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+struct TextRenderer<'a, T: ReadTxn> {
+    txn: &'a T,
+    format: TextFormat,
+}
+
+impl<T: ReadTxn> TextRenderer<'_, T> {
+    fn markdown(&self) -> bool {
+        self.format == TextFormat::Markdown
+    }
+
+    fn block_separator(&self) -> &'static str {
+        if self.markdown() {
+            "\n\n"
+        } else {
+            "\n"
+        }
+    }
+
+    fn children<F: XmlFragment>(&self, parent: &F) -> Vec<XmlOut> {
+        (0..parent.len(self.txn))
+            .filter_map(|index| parent.get(self.txn, index))
+            .collect()
+    }
+
+    /// Render the children of `parent` as blocks, joined by `separator`.
+    fn blocks<F: XmlFragment>(&self, parent: &F, separator: &str) -> String {
+        self.children(parent)
+            .iter()
+            .map(|node| self.block(node))
+            .filter(|block| !block.is_empty())
+            .collect::<Vec<_>>()
+            .join(separator)
+    }
+
+    fn block(&self, node: &XmlOut) -> String {
+        let element = match node {
+            XmlOut::Element(element) => element,
+            XmlOut::Fragment(fragment) => return self.blocks(fragment, self.block_separator()),
+            XmlOut::Text(text) => return self.marked_text(text),
+        };
+        let markdown = self.markdown();
+        match element.tag().as_ref() {
+            "paragraph" => self.inline(element),
+            "heading" if markdown => {
+                let level = attribute(element, self.txn, "level")
+                    .and_then(|level| level.parse::<usize>().ok())
+                    .unwrap_or(1)
+                    .clamp(1, 6);
+                format!("{} {}", "#".repeat(level), self.inline(element))
+            }
+            "heading" => self.inline(element),
+            "blockquote" if markdown => {
+                prefix_lines(&self.blocks(element, self.block_separator()), "> ", "> ")
+            }
+            "bulletList" | "bullet_list" | "taskList" | "task_list" => self.list(element, None),
+            "orderedList" | "ordered_list" => {
+                let start = attribute(element, self.txn, "start")
+                    .and_then(|start| start.parse::<usize>().ok())
+                    .unwrap_or(1);
+                self.list(element, Some(start))
+            }
+            "codeBlock" | "code_block" if markdown => {
+                let language = attribute(element, self.txn, "language").unwrap_or_default();
+                format!("```{}\n{}\n```", language, self.plain(element))
+            }
+            "codeBlock" | "code_block" => self.plain(element),
+            "horizontalRule" | "horizontal_rule" if markdown => "---".to_string(),
+            "horizontalRule" | "horizontal_rule" => String::new(),
+            "image" => self.image(element),
+            _ => self.blocks(element, self.block_separator()),
+        }
+    }
+
+    /// Render the items of a list, numbered from `start` if it is ordered.
+    fn list(&self, list: &XmlElementRef, start: Option<usize>) -> String {
+        self.children(list)
+            .iter()
+            .enumerate()
+            .map(|(index, item)| {
+                let content = match item {
+                    XmlOut::Element(item) => self.blocks(item, "\n"),
+                    node => self.block(node),
+                };
+                if !self.markdown() {
+                    return content;
+                }
+                let marker = match start {
+                    Some(start) => format!("{}. ", start + index),
+                    None => "- ".to_string(),
+                };
+                let indent = " ".repeat(marker.len());
+                prefix_lines(&content, &marker, &indent)
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Render the inline content of a textblock.
+    fn inline<F: XmlFragment>(&self, parent: &F) -> String {
+        self.children(parent)
+            .iter()
+            .map(|node| match node {
+                XmlOut::Text(text) => self.marked_text(text),
+                XmlOut::Element(element) => match element.tag().as_ref() {
+                    "hardBreak" | "hard_break" => "\n".to_string(),
+                    "image" => self.image(element),
+                    _ => self.inline(element),
+                },
+                XmlOut::Fragment(fragment) => self.inline(fragment),
+            })
+            .collect()
+    }
+
+    /// The text of `parent`, without marks.
+    fn plain<F: XmlFragment>(&self, parent: &F) -> String {
+        self.children(parent)
+            .iter()
+            .map(|node| match node {
+                XmlOut::Text(text) => text.get_string(self.txn),
+                XmlOut::Element(element) => self.plain(element),
+                XmlOut::Fragment(fragment) => self.plain(fragment),
+            })
+            .collect()
+    }
+
+    fn image(&self, image: &XmlElementRef) -> String {
+        let alt = attribute(image, self.txn, "alt").unwrap_or_default();
+        match attribute(image, self.txn, "src") {
+            Some(src) if self.markdown() => format!("![{}]({})", alt, src),
+            _ => alt,
+        }
+    }
+
+    /// The text of `text`, with its marks in Markdown.
+    fn marked_text<X: Text>(&self, text: &X) -> String {
+        text.diff(self.txn, YChange::identity)
+            .into_iter()
+            .filter_map(|chunk| self.marked_chunk(chunk))
+            .collect()
+    }
+
+    /// The text of a run of text, with its marks in Markdown. `None` for embeds, which
+    /// have no text to render.
+    fn marked_chunk(&self, chunk: Diff<YChange>) -> Option<String> {
+        let Out::Any(Any::String(string)) = chunk.insert else {
+            return None;
+        };
+        let Some(marks) = chunk.attributes.filter(|_| self.markdown()) else {
+            return Some(string.to_string());
+        };
+        let mut string = string.to_string();
+        if marks.contains_key("code") {
+            string = format!("`{}`", string);
+        }
+        for (names, delimiter) in [
+            (["bold", "strong"], "**"),
+            (["italic", "em"], "*"),
+            (["strike", "strikethrough"], "~~"),
+        ] {
+            if names.iter().any(|name| marks.contains_key(*name)) {
+                string = format!("{}{}{}", delimiter, string, delimiter);
+            }
+        }
+        // ProseMirror links have an href attribute, Quill links are the URL itself
+        let href = match marks.get("link") {
+            Some(Any::Map(link)) => link.get("href"),
+            link => link,
+        };
+        if let Some(Any::String(href)) = href {
+            string = format!("[{}]({})", string, href);
+        }
+        Some(string)
+    }
+}
+
+fn attribute<T: ReadTxn>(element: &XmlElementRef, txn: &T, name: &str) -> Option<String> {
+    element
+        .get_attribute(txn, name)
+        .map(|value| value.to_string())
+}
+
+/// Prefix the first line of `text` with `first` and the others with `rest`.
+fn prefix_lines(text: &str, first: &str, rest: &str) -> String {
+    text.lines()
+        .enumerate()
+        .map(|(index, line)| {
+            let prefix = if index == 0 { first } else { rest };
+            format!("{}{}", prefix, line).trim_end().to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_doc_to_search_text() {
+        let doc = Doc::new();
+        let title = doc.get_or_insert_text("title");
+        let settings = doc.get_or_insert_map("settings");
+        let body = doc.get_or_insert_xml_fragment("default");
+        {
+            let mut txn = doc.transact_mut();
+            title.insert(&mut txn, 0, "Roadmap");
+            settings.insert(&mut txn, "owner", "Platform team");
+            settings.insert(&mut txn, "version", 2.0);
+            let paragraph = body.push_back(&mut txn, XmlElementPrelim::empty("paragraph"));
+            paragraph.push_back(&mut txn, XmlTextPrelim::new("Ship search"));
+        }
+        let update = doc
+            .transact()
+            .encode_state_as_update_v1(&StateVector::default());
+
+        let text = doc_to_search_text(&update).unwrap();
+        assert!(text.contains("Roadmap"));
+        assert!(text.contains("Platform team"));
+        assert!(text.contains("Ship search"));
+        assert!(!text.contains('2'));
+    }
+
+    #[test]
+    fn test_doc_to_json() {
+        let doc = Doc::new();
+        let map = doc.get_or_insert_map("settings");
+        let text = doc.get_or_insert_text("title");
+        let array = doc.get_or_insert_array("items");
+        {
+            let mut txn = doc.transact_mut();
+            map.insert(&mut txn, "theme", "dark");
+            text.insert(&mut txn, 0, "Hello");
+            array.push_back(&mut txn, 1.0);
+        }
+        let update = doc
+            .transact()
+            .encode_state_as_update_v1(&StateVector::default());
+
+        assert_eq!(
+            doc_to_json(&update).unwrap(),
+            serde_json::json!({
+                "settings": { "theme": "dark" },
+                "title": "Hello",
+                "items": [1],
+            })
+        );
+    }
+
+    #[test]
+    fn test_json_to_update_round_trips() {
+        let json = serde_json::json!({
+            "settings": { "theme": "dark", "sizes": [1, 2] },
+            "title": "Hello",
+            "items": [{ "done": true }],
+        });
+        let update = json_to_update(&json).unwrap();
+        assert_eq!(doc_to_json(&update).unwrap(), json);
+        assert!(json_to_update(&serde_json::json!({ "count": 1 })).is_err());
+    }
+
+    #[test]
+    fn test_doc_to_text() {
+        use yrs::{XmlElementPrelim, XmlTextPrelim};
+
+        let doc = Doc::new();
+        let fragment = doc.get_or_insert_xml_fragment("default");
+        {
+            let mut txn = doc.transact_mut();
+            let heading = fragment.push_back(&mut txn, XmlElementPrelim::empty("heading"));
+            heading.insert_attribute(&mut txn, "level", "2");
+            heading.push_back(&mut txn, XmlTextPrelim::new("Notes"));
+
+            let paragraph = fragment.push_back(&mut txn, XmlElementPrelim::empty("paragraph"));
+            let text = paragraph.push_back(&mut txn, XmlTextPrelim::new("Some bold text"));
+            let bold = [(Arc::from("bold"), Any::Bool(true))].into_iter().collect();
+            text.format(&mut txn, 5, 4, bold);
+
+            let list = fragment.push_back(&mut txn, XmlElementPrelim::empty("bulletList"));
+            for item in ["one", "two"] {
+                let item_element = list.push_back(&mut txn, XmlElementPrelim::empty("listItem"));
+                let paragraph =
+                    item_element.push_back(&mut txn, XmlElementPrelim::empty("paragraph"));
+                paragraph.push_back(&mut txn, XmlTextPrelim::new(item));
+            }
+        }
+        let update = doc
+            .transact()
+            .encode_state_as_update_v1(&StateVector::default());
+
+        assert_eq!(
+            doc_to_text(&update, "default", TextFormat::Markdown).unwrap(),
+            "## Notes\n\nSome **bold** text\n\n- one\n- two"
+        );
+        assert_eq!(
+            doc_to_text(&update, "default", TextFormat::Text).unwrap(),
+            "Notes\nSome bold text\none\ntwo"
+        );
+        assert_eq!(
+            doc_to_text(&update, "missing", TextFormat::Markdown).unwrap(),
+            ""
+        );
+    }
+
+    #[test]
+    fn test_prosemirror_round_trips() {
+        let json = serde_json::json!({
+            "type": "doc",
+            "content": [
+                {
+                    "type": "heading",
+                    "attrs": { "level": 2 },
+                    "content": [{ "type": "text", "text": "Notes" }],
+                },
+                {
+                    "type": "paragraph",
+                    "content": [
+                        { "type": "text", "text": "A " },
+                        {
+                            "type": "text",
+                            "text": "link",
+                            "marks": [
+                                { "type": "bold" },
+                                { "type": "link", "attrs": { "href": "https://example.com" } },
+                            ],
+                        },
+                    ],
+                },
+                { "type": "horizontalRule" },
+            ],
+        });
+        let update = prosemirror_to_update(&json, "default").unwrap();
+        assert_eq!(doc_to_prosemirror(&update, "default").unwrap(), json);
+        assert_eq!(
+            doc_to_text(&update, "default", TextFormat::Markdown).unwrap(),
+            "## Notes\n\nA [**link**](https://example.com)\n\n---"
+        );
+        assert!(
+            prosemirror_to_update(&serde_json::json!({ "type": "paragraph" }), "default").is_err()
+        );
+    }
+
+    #[test]
+    fn test_delta_round_trips() {
+        let json = serde_json::json!({
+            "ops": [
+                { "insert": "Hello " },
+                {
+                    "insert": "world",
+                    "attributes": { "bold": true, "link": "https://example.com" },
+                },
+                { "insert": { "image": "https://example.com/a.png" } },
+                { "insert": "\n" },
+            ],
+        });
+        let update = delta_to_update(&json, "quill").unwrap();
+        assert_eq!(doc_to_delta(&update, "quill").unwrap(), json);
+        assert_eq!(
+            doc_to_text(&update, "quill", TextFormat::Markdown).unwrap(),
+            "Hello [**world**](https://example.com)\n"
+        );
+        assert!(delta_to_update(&serde_json::json!([{ "retain": 5 }]), "quill").is_err());
+    }
+
+    #[test]
+    fn test_writers_match_in_memory_conversions() {
+        let json = serde_json::json!({
+            "type": "doc",
+            "content": [
+                {
+                    "type": "paragraph",
+                    "content": [{ "type": "text", "text": "One", "marks": [{ "type": "bold" }] }],
+                },
+                { "type": "paragraph" },
+                {
+                    "type": "blockquote",
+                    "content": [{ "type": "paragraph", "content": [{ "type": "text", "text": "Two" }] }],
+                },
+            ],
+        });
+        let update = prosemirror_to_update(&json, "default").unwrap();
+
+        let mut written = Vec::new();
+        write_prosemirror(&update, "default", &mut written).unwrap();
+        let written: serde_json::Value = serde_json::from_slice(&written).unwrap();
+        assert_eq!(written, json);
+        assert_eq!(doc_to_prosemirror(&update, "default").unwrap(), json);
+
+        let mut written = Vec::new();
+        write_text(&update, "default", TextFormat::Markdown, &mut written).unwrap();
+        assert_eq!(String::from_utf8(written).unwrap(), "**One**\n\n> Two");
+
+        let mut written = Vec::new();
+        write_json(&update, &mut written).unwrap();
+        let written: serde_json::Value = serde_json::from_slice(&written).unwrap();
+        assert_eq!(written, doc_to_json(&update).unwrap());
+
+        let delta =
+            serde_json::json!({ "ops": [{ "insert": "Hi", "attributes": { "italic": true } }] });
+        let update = delta_to_update(&delta, "quill").unwrap();
+        let mut written = Vec::new();
+        write_delta(&update, "quill", &mut written).unwrap();
+        let written: serde_json::Value = serde_json::from_slice(&written).unwrap();
+        assert_eq!(written, delta);
+    }
+}
@@ -0,0 +1,99 @@
+//! Wakeups of the worker of a loaded document when it has changes to persist.
+//!
+//! The signal is a flag rather than a queue: any number of changes made before the worker
+//! wakes up leave a single pending wakeup, so signalling never blocks, fails or allocates,
+//! however fast a document changes. The worker also learns that the document was unloaded
+//! when the sending half, held by the document, is dropped.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use tokio::sync::Notify;
+
+#[derive(Default)]
+struct Signal {
+    pending: AtomicBool,
+    closed: AtomicBool,
+    notify: Notify,
+}
+
+/// Create a dirty signal, returning its sending and receiving halves.
+pub fn dirty_signal() -> (DirtySender, DirtyReceiver) {
+    let signal = Arc::new(Signal::default());
+    (DirtySender(signal.clone()), DirtyReceiver(signal))
+}
+
+pub struct DirtySender(Arc<Signal>);
+
+impl DirtySender {
+    /// Wake the receiver, unless a wakeup is already pending.
+    pub fn notify(&self) {
+        if !self.0.pending.swap(true, Ordering::SeqCst) {
+            self.0.notify.notify_one();
+        }
+    }
+}
+
+impl Drop for DirtySender {
+    fn drop(&mut self) {
+        self.0.closed.store(true, Ordering::SeqCst);
+        self.0.notify.notify_one();
+    }
+}
+
+pub struct DirtyReceiver(Arc<Signal>);
+
+impl DirtyReceiver {
+    /// Wait for a wakeup. Returns `None` once the sender is dropped and no wakeup is
+    /// pending.
+    pub async fn recv(&mut self) -> Option<()> {
+        loop {
+            // Created before checking the flags, so a notification in between is not missed
+            let notified = self.0.notify.notified();
+            if self.0.pending.swap(false, Ordering::SeqCst) {
+                return Some(());
+            }
+            if self.0.closed.load(Ordering::SeqCst) {
+                return None;
+            }
+            notified.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_signals_coalesce_and_close() {
+        let (send, mut recv) = dirty_signal();
+        let send = Arc::new(send);
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let send = send.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..10_000 {
+                        send.notify();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(recv.recv().await, Some(()));
+        // Every signal sent before the wakeup was received is covered by it
+        let next = tokio::time::timeout(Duration::from_millis(50), recv.recv()).await;
+        assert!(next.is_err());
+
+        send.notify();
+        drop(send);
+        assert_eq!(recv.recv().await, Some(()));
+        assert_eq!(recv.recv().await, None);
+    }
+}
@@ -0,0 +1,234 @@
+//! Creating a document from a local file.
+//!
+//! `y-sweet doc create` seeds a new document with the content of a Yjs v1 update, of a JSON
+//! file with a field for each root type (the format `export-all` writes), or of a
+//! ProseMirror (Tiptap) JSON document, imported into one XmlFragment. The document
+//! is written directly to a store, or created through a running server with its HTTP API
+//! (with the `client` feature), so that the server picks it up while it is running.
+//!
+//! Migrations from the persistence of other Yjs servers (`import-leveldb`,
+//! `import-hocuspocus`) write documents to a store the same way, with [import_doc].
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Serialize;
+use std::{path::Path, sync::Arc};
+#[cfg(feature = "client")]
+use url::Url;
+use y_sweet_core::{
+    api_types::validate_doc_name, snapshot_ext::snapshot_key, store::Store,
+    update_log_ext::list_segments,
+};
+use yrs::{updates::decoder::Decode, Update};
+
+use crate::convert::{convert, convert_to_store, json_to_update, prosemirror_to_update};
+
+/// Read the content of a document from `path`: a JSON file if its extension is `.json`, a
+/// Yjs v1 update otherwise. With `prosemirror_root`, the JSON file is a ProseMirror
+/// document, imported into the XmlFragment with that name.
+pub fn read_doc_file(path: &Path, prosemirror_root: Option<&str>) -> Result<Vec<u8>> {
+    let content = std::fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+    if path
+        .extension()
+        .is_some_and(|extension| extension == "json")
+    {
+        let json: serde_json::Value = serde_json::from_slice(&content)
+            .with_context(|| format!("{:?} is not valid JSON", path))?;
+        return match prosemirror_root {
+            Some(root) => prosemirror_to_update(&json, root),
+            None => json_to_update(&json),
+        };
+    }
+    if prosemirror_root.is_some() {
+        bail!("ProseMirror documents are read from .json files");
+    }
+    Update::decode_v1(&content).map_err(|_| anyhow!("{:?} is not a Yjs (v1) update", path))?;
+    Ok(content)
+}
+
+/// Write `doc_as_update` to `store` as the new document `doc_id`.
+pub async fn create_doc_in_store(
+    store: Box<dyn Store>,
+    doc_id: &str,
+    doc_as_update: &[u8],
+) -> Result<()> {
+    if !validate_doc_name(doc_id) {
+        bail!("Invalid document ID {:?}", doc_id);
+    }
+    if doc_exists_in_store(store.as_ref(), doc_id).await? {
+        bail!("Document {} already exists", doc_id);
+    }
+    convert(store, doc_as_update, doc_id).await
+}
+
+/// Whether `doc_id` has a snapshot or an update log in `store`.
+pub async fn doc_exists_in_store(store: &dyn Store, doc_id: &str) -> Result<bool> {
+    Ok(store.exists(&snapshot_key(doc_id)).await?
+        || !list_segments(store, doc_id).await?.is_empty())
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DocImportOutcome {
+    pub doc_id: String,
+    /// Whether the document was skipped because it already exists in the store.
+    pub skipped: bool,
+    /// Number of stored updates merged into the document.
+    pub updates: usize,
+    /// Size of the imported document.
+    pub bytes: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl DocImportOutcome {
+    pub fn failed(doc_id: &str, error: anyhow::Error) -> Self {
+        Self {
+            doc_id: doc_id.to_string(),
+            skipped: false,
+            updates: 0,
+            bytes: 0,
+            error: Some(format!("{:#}", error)),
+        }
+    }
+}
+
+/// Report of a migration of documents from another Yjs server's persistence.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportReport {
+    pub results: Vec<DocImportOutcome>,
+    pub imported: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+impl ImportReport {
+    pub fn new(results: Vec<DocImportOutcome>) -> Self {
+        let skipped = results.iter().filter(|result| result.skipped).count();
+        let failed = results
+            .iter()
+            .filter(|result| result.error.is_some())
+            .count();
+        Self {
+            imported: results.len() - skipped - failed,
+            skipped,
+            failed,
+            results,
+        }
+    }
+}
+
+/// Write `doc_as_update` to `store` as the new document `doc_id`, for migrations of many
+/// documents: failures are reported in the outcome, and documents that already exist fail,
+/// or are skipped with `skip_existing`.
+pub async fn import_doc(
+    store: &Arc<Box<dyn Store>>,
+    doc_id: &str,
+    doc_as_update: &[u8],
+    skip_existing: bool,
+) -> DocImportOutcome {
+    let import = async {
+        if !validate_doc_name(doc_id) {
+            bail!("Not a valid y-sweet document ID");
+        }
+        if doc_exists_in_store(store.as_ref().as_ref(), doc_id).await? {
+            if !skip_existing {
+                bail!("Document already exists in the store");
+            }
+            return Ok(true);
+        }
+        convert_to_store(store, doc_as_update, doc_id).await?;
+        Ok(false)
+    };
+    match import.await {
+        Ok(skipped) => DocImportOutcome {
+            doc_id: doc_id.to_string(),
+            skipped,
+            updates: 1,
+            bytes: if skipped { 0 } else { doc_as_update.len() },
+            error: None,
+        },
+        Err(e) => DocImportOutcome::failed(doc_id, e),
+    }
+}
+
+/// Create the document `doc_id` with the content of `doc_as_update` through the server at
+/// `server_url`, authenticating with `server_token` if the server requires auth.
+#[cfg(feature = "client")]
+pub async fn create_doc_on_server(
+    server_url: &Url,
+    server_token: Option<&str>,
+    doc_id: &str,
+    doc_as_update: Vec<u8>,
+) -> Result<()> {
+    if !validate_doc_name(doc_id) {
+        bail!("Invalid document ID {:?}", doc_id);
+    }
+    let mut server_url = server_url.clone();
+    if !server_url.path().ends_with('/') {
+        server_url.set_path(&format!("{}/", server_url.path()));
+    }
+    let client = reqwest::Client::new();
+    let authorize = |request: reqwest::RequestBuilder| match server_token {
+        Some(token) => request.bearer_auth(token),
+        None => request,
+    };
+
+    let existing = authorize(client.get(server_url.join(&format!("d/{doc_id}/as-update"))?))
+        .send()
+        .await
+        .context("Failed to reach the server")?;
+    match existing.status() {
+        reqwest::StatusCode::NOT_FOUND => {}
+        status if status.is_success() => bail!("Document {} already exists", doc_id),
+        status => bail!("The server responded {} when checking the document", status),
+    }
+
+    authorize(client.post(server_url.join("doc/new")?))
+        .json(&serde_json::json!({ "docId": doc_id }))
+        .send()
+        .await?
+        .error_for_status()
+        .context("Failed to create the document")?;
+
+    authorize(client.post(server_url.join(&format!("d/{doc_id}/update"))?))
+        .body(doc_as_update)
+        .send()
+        .await?
+        .error_for_status()
+        .context("Failed to write the content of the document")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stores::filesystem::FileSystemStore;
+    use y_sweet_core::doc_sync_ext::DocWithSyncKv;
+
+    #[tokio::test]
+    async fn test_creates_doc_from_json_once() {
+        let dir = std::env::temp_dir().join(format!("y-sweet-create-{}", nanoid::nanoid!()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("seed.json");
+        std::fs::write(&path, r#"{"text": "hello"}"#).unwrap();
+        let update = read_doc_file(&path, None).unwrap();
+
+        let store_dir = dir.join("store");
+        let store = Box::new(FileSystemStore::new(store_dir.clone()).unwrap());
+        create_doc_in_store(store, "doc", &update).await.unwrap();
+
+        let store: Arc<Box<dyn Store>> =
+            Arc::new(Box::new(FileSystemStore::new(store_dir.clone()).unwrap()));
+        let doc = DocWithSyncKv::new("doc", Some(store), || {}, false)
+            .await
+            .unwrap();
+        assert_eq!(
+            crate::convert::doc_to_json(&doc.as_update()).unwrap(),
+            serde_json::json!({ "text": "hello" })
+        );
+
+        let store = Box::new(FileSystemStore::new(store_dir).unwrap());
+        assert!(create_doc_in_store(store, "doc", &update).await.is_err());
+    }
+}
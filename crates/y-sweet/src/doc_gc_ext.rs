@@ -0,0 +1,185 @@
+//! When loaded documents are unloaded.
+//!
+//! A document without connections is unloaded once it has been idle for the idle timeout,
+//! and not before it has been loaded for the minimum residency time, so that documents
+//! opened briefly but repeatedly are not reloaded from the store every time. By default a
+//! document is unloaded after being seen idle on two consecutive checks, one checkpoint
+//! interval apart.
+//!
+//! Pinned documents are never unloaded, neither by the GC nor to stay within the memory
+//! budget. Pins are kept in memory: documents pinned through the API are unpinned when the
+//! server restarts, unlike the ones given in the configuration.
+
+use dashmap::{DashMap, DashSet};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio_util::sync::CancellationToken;
+use y_sweet_core::doc_sync_ext::DocWithSyncKv;
+
+use crate::events_ext::EventHandler;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DocGcPolicy {
+    /// How long a document has no connections before it is unloaded. Defaults to the
+    /// checkpoint interval.
+    pub idle_timeout: Option<Duration>,
+    /// How long a document stays loaded at least.
+    pub min_residency: Duration,
+}
+
+/// Documents that are never unloaded.
+#[derive(Default)]
+pub struct DocPins(DashSet<String>);
+
+impl DocPins {
+    pub fn new(doc_ids: impl IntoIterator<Item = String>) -> Self {
+        Self(doc_ids.into_iter().collect())
+    }
+
+    /// Pin a document. Returns whether it was not pinned yet.
+    pub fn pin(&self, doc_id: &str) -> bool {
+        self.0.insert(doc_id.to_string())
+    }
+
+    /// Unpin a document. Returns whether it was pinned.
+    pub fn unpin(&self, doc_id: &str) -> bool {
+        self.0.remove(doc_id).is_some()
+    }
+
+    pub fn is_pinned(&self, doc_id: &str) -> bool {
+        self.0.contains(doc_id)
+    }
+}
+
+/// Unload a document once it may be garbage collected, until it is unloaded otherwise or
+/// the server shuts down.
+pub(crate) async fn doc_gc_worker(
+    docs: Arc<DashMap<String, DocWithSyncKv>>,
+    doc_id: String,
+    checkpoint_freq: Duration,
+    policy: DocGcPolicy,
+    pins: Arc<DocPins>,
+    events: Option<Arc<dyn EventHandler>>,
+    cancellation_token: CancellationToken,
+) {
+    let idle_timeout = policy.idle_timeout.unwrap_or(checkpoint_freq);
+    let check_interval = idle_timeout.min(checkpoint_freq);
+    let loaded_at = Instant::now();
+    let mut idle_since: Option<Instant> = None;
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(check_interval) => {
+                if let Some(doc) = docs.get(&doc_id) {
+                    let awareness = Arc::downgrade(&doc.awareness());
+                    if awareness.strong_count() > 1 {
+                        idle_since = None;
+                        tracing::debug!("doc is still alive - it has {} references", awareness.strong_count());
+                    } else {
+                        let since = *idle_since.get_or_insert_with(Instant::now);
+                        tracing::debug!("doc has only one reference, candidate for GC. idle for {:?}", since.elapsed());
+                    }
+                } else {
+                    break;
+                }
+
+                let collectable = idle_since.is_some_and(|since| since.elapsed() >= idle_timeout)
+                    && loaded_at.elapsed() >= policy.min_residency
+                    && !pins.is_pinned(&doc_id);
+                if collectable {
+                    tracing::debug!("GCing doc");
+                    if let Some(doc) = docs.get(&doc_id) {
+                        doc.sync_kv().shutdown();
+                    }
+
+                    docs.remove(&doc_id);
+                    if let Some(events) = &events {
+                        events.doc_evicted(&doc_id);
+                    }
+                    break;
+                }
+            }
+            _ = cancellation_token.cancelled() => {
+                break;
+            }
+        };
+    }
+    tracing::debug!("Exiting gc_loop");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn docs(doc_id: &str) -> Arc<DashMap<String, DocWithSyncKv>> {
+        let docs = Arc::new(DashMap::new());
+        let doc = DocWithSyncKv::new(doc_id, None, || {}, false)
+            .await
+            .unwrap();
+        docs.insert(doc_id.to_string(), doc);
+        docs
+    }
+
+    #[tokio::test]
+    async fn test_idle_doc_is_unloaded_after_timeout_unless_pinned() {
+        let policy = DocGcPolicy {
+            idle_timeout: Some(Duration::from_millis(50)),
+            min_residency: Duration::ZERO,
+        };
+
+        let unpinned = docs("doc").await;
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            doc_gc_worker(
+                unpinned.clone(),
+                "doc".to_string(),
+                Duration::from_secs(60),
+                policy,
+                Arc::new(DocPins::default()),
+                None,
+                CancellationToken::new(),
+            ),
+        )
+        .await
+        .unwrap();
+        assert!(unpinned.is_empty());
+
+        let pinned = docs("doc").await;
+        let worker = doc_gc_worker(
+            pinned.clone(),
+            "doc".to_string(),
+            Duration::from_secs(60),
+            policy,
+            Arc::new(DocPins::new(["doc".to_string()])),
+            None,
+            CancellationToken::new(),
+        );
+        assert!(tokio::time::timeout(Duration::from_millis(300), worker)
+            .await
+            .is_err());
+        assert!(pinned.contains_key("doc"));
+    }
+
+    #[tokio::test]
+    async fn test_doc_stays_loaded_for_min_residency() {
+        let docs = docs("doc").await;
+        let start = Instant::now();
+        doc_gc_worker(
+            docs.clone(),
+            "doc".to_string(),
+            Duration::from_secs(60),
+            DocGcPolicy {
+                idle_timeout: Some(Duration::from_millis(10)),
+                min_residency: Duration::from_millis(200),
+            },
+            Arc::new(DocPins::default()),
+            None,
+            CancellationToken::new(),
+        )
+        .await;
+        assert!(docs.is_empty());
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,161 @@
+//! In-process access to documents, for servers embedding y-sweet as a library.
+//!
+//! A [DocHandle] reads and changes a loaded document directly, without going through HTTP
+//! or the sync protocol: changes made through it are persisted and sent to connected
+//! clients like those of any client. The document stays loaded for as long as a handle to
+//! it lives, since the GC only unloads documents nothing refers to.
+//!
+//! Changes are refused like they are for clients when the document is frozen or the server
+//! is a read-only follower; update validators are not run, since the server trusts itself.
+
+use anyhow::{anyhow, Result};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, RwLock,
+};
+use tokio::sync::mpsc;
+use y_sweet_core::sync::awareness::Awareness;
+use yrs::{
+    updates::decoder::Decode, ReadTxn, StateVector, Subscription, Transact, Transaction,
+    TransactionMut, Update,
+};
+
+use crate::freeze_ext::doc_freeze_flag;
+use crate::server::Server;
+
+impl Server {
+    /// A handle to `doc_id`, loading the document, or creating it if it doesn't exist.
+    pub async fn doc_handle(&self, doc_id: &str) -> Result<DocHandle> {
+        let awareness = self.get_or_create_doc(doc_id).await?.awareness();
+        let frozen = doc_freeze_flag(self, doc_id).await?;
+        Ok(DocHandle {
+            doc_id: doc_id.to_string(),
+            awareness,
+            frozen,
+            follower: self.is_follower(),
+        })
+    }
+}
+
+/// A loaded document, shared with its connected clients.
+#[derive(Clone)]
+pub struct DocHandle {
+    doc_id: String,
+    awareness: Arc<RwLock<Awareness>>,
+    frozen: Arc<AtomicBool>,
+    follower: bool,
+}
+
+impl DocHandle {
+    pub fn doc_id(&self) -> &str {
+        &self.doc_id
+    }
+
+    pub fn awareness(&self) -> Arc<RwLock<Awareness>> {
+        self.awareness.clone()
+    }
+
+    /// Read the document in a transaction, e.g. `handle.read(|txn| txn.get_text("text"))`.
+    pub fn read<T>(&self, f: impl FnOnce(&Transaction) -> T) -> T {
+        let awareness = self.awareness.read().unwrap();
+        let txn = awareness.doc().transact();
+        f(&txn)
+    }
+
+    /// Change the document in a transaction, committed when `f` returns. Root types are
+    /// obtained from the transaction, e.g. `txn.get_or_insert_text("text")`.
+    pub fn write<T>(&self, f: impl FnOnce(&mut TransactionMut) -> T) -> Result<T> {
+        self.check_writable()?;
+        let awareness = self.awareness.write().unwrap();
+        let mut txn = awareness.doc().transact_mut();
+        Ok(f(&mut txn))
+    }
+
+    /// The whole state of the document, encoded as a v1 update.
+    pub fn as_update(&self) -> Vec<u8> {
+        self.read(|txn| txn.encode_state_as_update_v1(&StateVector::default()))
+    }
+
+    /// Apply a v1 update to the document.
+    pub fn apply_update(&self, update: &[u8]) -> Result<()> {
+        let update = Update::decode_v1(update).map_err(|_| anyhow!("Failed to decode update"))?;
+        self.write(|txn| txn.apply_update(update))
+    }
+
+    /// The updates applied to the document from now on, whatever they come from, for as long
+    /// as the returned [DocUpdates] lives.
+    pub fn subscribe(&self) -> Result<DocUpdates> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let subscription = self
+            .awareness
+            .read()
+            .unwrap()
+            .doc()
+            .observe_update_v1(move |_, event| {
+                // The receiver may have been dropped before the subscription
+                let _ = sender.send(event.update.clone());
+            })
+            .map_err(|e| anyhow!("Failed to subscribe to updates: {}", e))?;
+        Ok(DocUpdates {
+            receiver,
+            _subscription: subscription,
+        })
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.follower {
+            return Err(anyhow!("Documents of a follower are read-only"));
+        }
+        if self.frozen.load(Ordering::SeqCst) {
+            return Err(anyhow!("Doc {} is frozen", self.doc_id));
+        }
+        Ok(())
+    }
+}
+
+/// Updates applied to a document, encoded as v1.
+pub struct DocUpdates {
+    receiver: mpsc::UnboundedReceiver<Vec<u8>>,
+    _subscription: Subscription,
+}
+
+impl DocUpdates {
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+        self.receiver.recv().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use yrs::{GetString, Text, WriteTxn};
+
+    #[tokio::test]
+    async fn test_handle_reads_writes_and_subscribes() {
+        let server = Server::builder().doc_gc(false).build().await.unwrap();
+        let handle = server.doc_handle("notes").await.unwrap();
+        let mut updates = handle.subscribe().unwrap();
+
+        handle
+            .write(|txn| {
+                let text = txn.get_or_insert_text("text");
+                text.insert(txn, 0, "hello");
+            })
+            .unwrap();
+        let text = handle.read(|txn| txn.get_text("text").map(|text| text.get_string(txn)));
+        assert_eq!(text.as_deref(), Some("hello"));
+
+        // Seen by the document the server serves, and reported to the subscriber
+        let doc = server.get_or_create_doc("notes").await.unwrap();
+        assert_eq!(doc.as_update(), handle.as_update());
+        let update = updates.recv().await.unwrap();
+        let copy = yrs::Doc::new();
+        copy.transact_mut()
+            .apply_update(Update::decode_v1(&update).unwrap());
+        let text = copy.get_or_insert_text("text");
+        assert_eq!(text.get_string(&copy.transact()), "hello");
+
+        handle.frozen.store(true, Ordering::SeqCst);
+        assert!(handle.apply_update(&update).is_err());
+    }
+}
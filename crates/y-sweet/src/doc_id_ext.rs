@@ -0,0 +1,143 @@
+//! Generation of the IDs of documents created without one.
+//!
+//! By default IDs are 21-character nanoids, as they have always been. Their length, their
+//! alphabet and a prefix (e.g. the tenant a server serves) can be configured, and embedders
+//! can supply their own function instead. Whatever generates them, IDs must be valid
+//! document names (see [validate_doc_name]): the alphabet and prefix are checked when the
+//! generator is configured, and the IDs of a custom function when they are generated.
+//!
+//! With a prefix, the IDs clients pick themselves when creating, copying or forking a document
+//! must have it too, so that every document of the server carries it.
+
+use anyhow::{anyhow, bail, Result};
+use std::{fmt, sync::Arc};
+use y_sweet_core::{api_types::validate_doc_name, api_types_ext::is_doc_name_char};
+
+pub const DEFAULT_DOC_ID_LENGTH: usize = 21;
+
+#[derive(Clone)]
+pub enum DocIdGenerator {
+    Nanoid {
+        length: usize,
+        alphabet: Vec<char>,
+        prefix: String,
+    },
+    Custom(Arc<dyn Fn() -> String + Send + Sync>),
+}
+
+impl Default for DocIdGenerator {
+    fn default() -> Self {
+        Self::Nanoid {
+            length: DEFAULT_DOC_ID_LENGTH,
+            alphabet: nanoid::alphabet::SAFE.to_vec(),
+            prefix: String::new(),
+        }
+    }
+}
+
+impl fmt::Debug for DocIdGenerator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Nanoid {
+                length,
+                alphabet,
+                prefix,
+            } => f
+                .debug_struct("Nanoid")
+                .field("length", length)
+                .field("alphabet", &alphabet.iter().collect::<String>())
+                .field("prefix", prefix)
+                .finish(),
+            Self::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+impl DocIdGenerator {
+    /// Nanoids of `length` characters of `alphabet` (the default one if None), after
+    /// `prefix`.
+    pub fn nanoid(length: usize, alphabet: Option<&str>, prefix: &str) -> Result<Self> {
+        if length == 0 {
+            bail!("Document IDs must be at least one character long");
+        }
+        let alphabet: Vec<char> = match alphabet {
+            Some(alphabet) => alphabet.chars().collect(),
+            None => nanoid::alphabet::SAFE.to_vec(),
+        };
+        if alphabet.len() < 2 || alphabet.len() > u8::MAX as usize {
+            bail!("The document ID alphabet must have between 2 and 255 characters");
+        }
+        if let Some(c) = alphabet.iter().find(|c| !is_doc_name_char(**c)) {
+            bail!("{:?} is not allowed in document IDs", c);
+        }
+        if let Some(c) = prefix.chars().find(|c| !is_doc_name_char(*c)) {
+            bail!("{:?} is not allowed in document IDs", c);
+        }
+        Ok(Self::Nanoid {
+            length,
+            alphabet,
+            prefix: prefix.to_string(),
+        })
+    }
+
+    /// IDs returned by `generate`, which must be valid document names.
+    pub fn custom(generate: impl Fn() -> String + Send + Sync + 'static) -> Self {
+        Self::Custom(Arc::new(generate))
+    }
+
+    pub fn generate(&self) -> Result<String> {
+        match self {
+            Self::Nanoid {
+                length,
+                alphabet,
+                prefix,
+            } => Ok(format!(
+                "{}{}",
+                prefix,
+                nanoid::format(nanoid::rngs::default, alphabet, *length)
+            )),
+            Self::Custom(generate) => {
+                let doc_id = generate();
+                if !validate_doc_name(&doc_id) {
+                    return Err(anyhow!("Generated document ID {:?} is invalid", doc_id));
+                }
+                Ok(doc_id)
+            }
+        }
+    }
+
+    /// Whether `doc_id`, picked by a client for a new document, is acceptable: a valid
+    /// document name, with the prefix of generated IDs if there is one.
+    pub fn accepts(&self, doc_id: &str) -> bool {
+        let prefix = match self {
+            Self::Nanoid { prefix, .. } => prefix.as_str(),
+            Self::Custom(_) => "",
+        };
+        validate_doc_name(doc_id) && doc_id.starts_with(prefix)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_generates_ids_from_configuration() {
+        let doc_id = DocIdGenerator::default().generate().unwrap();
+        assert_eq!(doc_id.len(), DEFAULT_DOC_ID_LENGTH);
+
+        let generator = DocIdGenerator::nanoid(8, Some("abc123"), "acme-").unwrap();
+        let doc_id = generator.generate().unwrap();
+        assert!(doc_id.starts_with("acme-"));
+        assert_eq!(doc_id.len(), 13);
+        assert!(doc_id[5..].chars().all(|c| "abc123".contains(c)));
+        assert!(generator.accepts("acme-notes"));
+        assert!(!generator.accepts("notes"));
+
+        assert!(DocIdGenerator::nanoid(8, Some("ab/"), "").is_err());
+        assert!(DocIdGenerator::nanoid(8, None, "acme:").is_err());
+
+        let generator = DocIdGenerator::custom(|| "not valid!".to_string());
+        assert!(generator.generate().is_err());
+    }
+}
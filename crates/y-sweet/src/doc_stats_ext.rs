@@ -0,0 +1,288 @@
+//! Runtime statistics of documents, listed by `GET /d/:doc_id/stats`.
+//!
+//! Each loaded document counts the updates applied to it, the bytes exchanged with its
+//! clients, its connections and its last activity (see [DocStats]). The counters start over
+//! when the document is loaded again; with `--doc-stats-flush`, their totals over every load
+//! are flushed on each checkpoint, to `{doc_id}/stats.json` in the store or as a `doc_stats`
+//! log event for the metrics pipeline.
+
+use anyhow::anyhow;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use axum_extra::typed_header::TypedHeader;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::info;
+use y_sweet_core::{
+    api_types::validate_doc_name,
+    doc_stats_ext::{DocStats, DocStatsSnapshot},
+    store::{Store, StoreError},
+};
+
+use crate::server::{current_time_epoch_millis, AppError, Server};
+
+/// Where the totals of the statistics of documents are flushed on each checkpoint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum DocStatsFlush {
+    /// To `{doc_id}/stats.json` in the store.
+    Store,
+    /// As a `doc_stats` log event.
+    Log,
+}
+
+/// Statistics of a document over every time it was loaded, as flushed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DocStatsTotals {
+    /// Times the document was loaded.
+    pub loads: u64,
+    pub updates_applied: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Most connections at once, over every load.
+    pub peak_connections: u64,
+    pub last_activity: u64,
+    pub flushed_at: u64,
+}
+
+impl DocStatsTotals {
+    /// The totals of the previous loads, `self`, with those of the current load.
+    pub fn with_load(&self, current: &DocStatsSnapshot) -> Self {
+        Self {
+            loads: self.loads + 1,
+            updates_applied: self.updates_applied + current.updates_applied,
+            bytes_in: self.bytes_in + current.bytes_in,
+            bytes_out: self.bytes_out + current.bytes_out,
+            peak_connections: self.peak_connections.max(current.peak_connections),
+            last_activity: self.last_activity.max(current.last_activity),
+            flushed_at: current_time_epoch_millis(),
+        }
+    }
+}
+
+pub fn stats_key(doc_id: &str) -> String {
+    format!("{}/stats.json", doc_id)
+}
+
+/// The totals flushed to the store for `doc_id`, if any.
+pub async fn read_doc_stats(
+    store: &dyn Store,
+    doc_id: &str,
+) -> Result<Option<DocStatsTotals>, StoreError> {
+    let Some(data) = store.get(&stats_key(doc_id)).await? else {
+        return Ok(None);
+    };
+    Ok(serde_json::from_slice(&data).ok())
+}
+
+/// Flushes the statistics of one load of a document on its checkpoints.
+pub struct DocStatsFlusher {
+    flush: DocStatsFlush,
+    store: Option<Arc<Box<dyn Store>>>,
+    doc_id: String,
+    stats: Arc<DocStats>,
+    /// Totals of the previous loads, read on the first flush.
+    previous: Option<DocStatsTotals>,
+}
+
+impl DocStatsFlusher {
+    pub fn new(
+        flush: DocStatsFlush,
+        store: Option<Arc<Box<dyn Store>>>,
+        doc_id: String,
+        stats: Arc<DocStats>,
+    ) -> Self {
+        Self {
+            flush,
+            store,
+            doc_id,
+            stats,
+            previous: None,
+        }
+    }
+
+    pub async fn flush(&mut self) {
+        let store = match (self.flush, &self.store) {
+            (DocStatsFlush::Store, Some(store)) => Some(store.as_ref().as_ref()),
+            _ => None,
+        };
+        if self.previous.is_none() {
+            let previous = match store {
+                Some(store) => match read_doc_stats(store, &self.doc_id).await {
+                    Ok(previous) => previous.unwrap_or_default(),
+                    Err(e) => {
+                        // Flushing now would overwrite the totals of the previous loads
+                        tracing::warn!("Failed to read the statistics of {}: {}", self.doc_id, e);
+                        return;
+                    }
+                },
+                None => DocStatsTotals::default(),
+            };
+            self.previous = Some(previous);
+        }
+        let totals = self
+            .previous
+            .as_ref()
+            .unwrap_or(&DocStatsTotals::default())
+            .with_load(&self.stats.snapshot());
+
+        match store {
+            Some(store) => {
+                let data = match serde_json::to_vec(&totals) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        tracing::warn!("Failed to encode the statistics of {}: {}", self.doc_id, e);
+                        return;
+                    }
+                };
+                if let Err(e) = store.set(&stats_key(&self.doc_id), data).await {
+                    tracing::warn!("Failed to flush the statistics of {}: {}", self.doc_id, e);
+                }
+            }
+            None => info!(
+                message = "Document statistics",
+                event = "doc_stats",
+                doc_id = %self.doc_id,
+                loads = totals.loads,
+                updates_applied = totals.updates_applied,
+                bytes_in = totals.bytes_in,
+                bytes_out = totals.bytes_out,
+                peak_connections = totals.peak_connections,
+                last_activity = totals.last_activity
+            ),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DocStatsResponse {
+    pub doc_id: String,
+    /// Statistics of the current load, if the document is loaded.
+    pub current: Option<DocStatsSnapshot>,
+    /// Totals over every load, as last flushed to the store.
+    pub totals: Option<DocStatsTotals>,
+}
+
+pub async fn get_document_stats(
+    Path(doc_id): Path<String>,
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+) -> Result<Json<DocStatsResponse>, AppError> {
+    server_state.check_auth(auth_header)?;
+
+    if !validate_doc_name(&doc_id) {
+        return Err(AppError(
+            StatusCode::BAD_REQUEST,
+            anyhow!("Invalid document ID"),
+        ));
+    }
+
+    let current = server_state
+        .docs
+        .get(&doc_id)
+        .map(|doc| doc.stats().snapshot());
+    let totals = match &server_state.store {
+        Some(store) => read_doc_stats(store.as_ref().as_ref(), &doc_id)
+            .await
+            .map_err(|e| {
+                AppError(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    anyhow!("Failed to read the document statistics: {}", e),
+                )
+            })?,
+        None => None,
+    };
+    if current.is_none() && totals.is_none() {
+        return Err(AppError(
+            StatusCode::NOT_FOUND,
+            anyhow!("Document is not loaded and has no flushed statistics"),
+        ));
+    }
+
+    Ok(Json(DocStatsResponse {
+        doc_id,
+        current,
+        totals,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stores::memory::MemoryStore;
+    use axum::{body::Body, http::Request};
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+    use y_sweet_core::auth::Authenticator;
+
+    #[tokio::test]
+    async fn test_stats_of_loaded_documents_and_flushed_totals() {
+        let auth = Authenticator::gen_key().unwrap();
+        let token = auth.server_token();
+        let store: Arc<Box<dyn Store>> = Arc::new(Box::new(MemoryStore::new()));
+        let server = Arc::new(
+            Server::builder()
+                .store(Some(Box::new(MemoryStore::new())))
+                .authenticator(Some(auth))
+                .build()
+                .await
+                .unwrap(),
+        );
+
+        let get_stats = |doc_id: &str| {
+            Request::builder()
+                .uri(format!("/d/{}/stats", doc_id))
+                .header("authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = server
+            .routes()
+            .oneshot(get_stats("unloaded"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        server.load_doc("stats").await.unwrap();
+        let stats = server.docs.get("stats").unwrap().stats();
+        {
+            let _connection = stats.connect();
+            stats.record_bytes_in(12);
+        }
+        let response = server.routes().oneshot(get_stats("stats")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["current"]["bytesIn"], 12);
+        assert_eq!(body["current"]["peakConnections"], 1);
+        assert_eq!(body["current"]["connections"], 0);
+
+        // Totals add up the loads flushed before
+        let mut first = DocStatsFlusher::new(
+            DocStatsFlush::Store,
+            Some(store.clone()),
+            "stats".to_string(),
+            stats.clone(),
+        );
+        first.flush().await;
+        first.flush().await;
+        let mut second = DocStatsFlusher::new(
+            DocStatsFlush::Store,
+            Some(store.clone()),
+            "stats".to_string(),
+            stats,
+        );
+        second.flush().await;
+        let totals = read_doc_stats(store.as_ref().as_ref(), "stats")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(totals.loads, 2);
+        assert_eq!(totals.bytes_in, 24);
+        assert_eq!(totals.peak_connections, 1);
+    }
+}
@@ -0,0 +1,131 @@
+//! Document lifecycle events, for servers embedding y-sweet as a library.
+//!
+//! An [EventHandler] registered on the server is told when documents are loaded, persisted
+//! and evicted, when WebSocket clients connect and disconnect, and when updates are applied,
+//! e.g. to invalidate caches or index documents as they change. Handlers are called inline,
+//! from the tasks doing the work, so they must return quickly: slow work belongs on a task
+//! of its own.
+
+use std::sync::{Arc, RwLock};
+use y_sweet_core::{api_types::Authorization, sync::awareness::Awareness};
+use yrs::Subscription;
+
+/// Callbacks for document lifecycle events. Every method does nothing by default.
+#[allow(unused_variables)]
+pub trait EventHandler: Send + Sync {
+    /// A document was loaded into memory, from the store or as a new document.
+    fn doc_loaded(&self, doc_id: &str) {}
+
+    /// A checkpoint of a document was written to the store.
+    fn doc_persisted(&self, doc_id: &str) {}
+
+    /// A document was unloaded from memory, because it was idle or to stay within the
+    /// memory budget.
+    fn doc_evicted(&self, doc_id: &str) {}
+
+    fn client_connected(&self, doc_id: &str, authorization: Authorization) {}
+
+    fn client_disconnected(&self, doc_id: &str) {}
+
+    /// An update was applied to a loaded document, whatever it came from. Called while the
+    /// update is being committed, so the document must not be accessed from here.
+    fn update_applied(&self, doc_id: &str, update: &[u8]) {}
+}
+
+/// Report the updates applied to a loaded document to `handler` for as long as the
+/// returned handle lives.
+pub fn attach(
+    handler: &Arc<dyn EventHandler>,
+    doc_id: &str,
+    awareness: &Arc<RwLock<Awareness>>,
+) -> DocEvents {
+    let update_handler = handler.clone();
+    let update_doc_id = doc_id.to_string();
+    let subscription = awareness
+        .read()
+        .unwrap()
+        .doc()
+        .observe_update_v1(move |_, event| {
+            update_handler.update_applied(&update_doc_id, &event.update);
+        })
+        .unwrap();
+
+    DocEvents {
+        handler: handler.clone(),
+        doc_id: doc_id.to_string(),
+        _subscription: subscription,
+    }
+}
+
+/// The events of one loaded document.
+pub struct DocEvents {
+    handler: Arc<dyn EventHandler>,
+    doc_id: String,
+    _subscription: Subscription,
+}
+
+impl DocEvents {
+    pub fn persisted(&self) {
+        self.handler.doc_persisted(&self.doc_id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::Server;
+    use std::{sync::Mutex, time::Duration};
+    use yrs::{Text, Transact};
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl EventHandler for Arc<Recorder> {
+        fn doc_loaded(&self, doc_id: &str) {
+            self.0.lock().unwrap().push(format!("loaded {}", doc_id));
+        }
+
+        fn doc_persisted(&self, doc_id: &str) {
+            self.0.lock().unwrap().push(format!("persisted {}", doc_id));
+        }
+
+        fn update_applied(&self, doc_id: &str, _update: &[u8]) {
+            self.0.lock().unwrap().push(format!("update {}", doc_id));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handler_sees_document_lifecycle() {
+        let recorder = Arc::new(Recorder::default());
+        let server = Server::builder()
+            .checkpoint_freq(Duration::from_millis(10))
+            .doc_gc(false)
+            .build()
+            .await
+            .unwrap()
+            .with_event_handler(recorder.clone());
+
+        {
+            let doc = server.get_or_create_doc("notes").await.unwrap();
+            let awareness = doc.awareness();
+            let awareness = awareness.write().unwrap();
+            let text = awareness.doc().get_or_insert_text("text");
+            text.insert(&mut awareness.doc().transact_mut(), 0, "hello");
+        }
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !recorder
+                .0
+                .lock()
+                .unwrap()
+                .contains(&"persisted notes".into())
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let events = recorder.0.lock().unwrap();
+        assert_eq!(events[..2], ["loaded notes", "update notes"]);
+    }
+}
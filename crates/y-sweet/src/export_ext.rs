@@ -0,0 +1,229 @@
+//! Bulk export of the documents of a store to a directory.
+//!
+//! Each document is written to `{out}/{doc_id}.json` as JSON (see
+//! [crate::convert::doc_to_json]), to `{out}/{doc_id}.prosemirror.json` as a ProseMirror
+//! document (see [crate::convert::doc_to_prosemirror]), or to `{out}/{doc_id}.yupdate` as a
+//! Yjs v1 update, which `convert-from-update` can import again. Documents are loaded the way the server would,
+//! replaying their update log if they have one.
+
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use serde::Serialize;
+use std::{path::Path, sync::Arc};
+use y_sweet_core::{
+    doc_sync_ext::DocWithSyncKv,
+    snapshot_ext::snapshot_key,
+    store::Store,
+    update_log_ext::{list_segments, segment_key, UpdateLogConfig},
+};
+
+use crate::convert::{doc_to_json, doc_to_prosemirror, DEFAULT_TEXT_ROOT};
+
+/// Number of documents exported concurrently.
+const EXPORT_CONCURRENCY: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ExportFormat {
+    /// The content of each root type, as pretty-printed JSON.
+    Json,
+    /// The document state, encoded as a Yjs v1 update.
+    Yupdate,
+    /// The `default` XmlFragment (the one Tiptap binds editors to), as ProseMirror JSON.
+    Prosemirror,
+}
+
+impl ExportFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Json => "json",
+            ExportFormat::Yupdate => "yupdate",
+            ExportFormat::Prosemirror => "prosemirror.json",
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DocExportOutcome {
+    pub doc_id: String,
+    /// Whether the document was skipped because it was not modified since the given time.
+    pub skipped: bool,
+    pub bytes: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportReport {
+    pub results: Vec<DocExportOutcome>,
+    pub exported: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+/// When a document was last persisted, in epoch millis, from the modification time of its
+/// snapshot and of the last segment of its update log. `None` if the store does not record
+/// modification times.
+pub async fn doc_modified_at(store: &Arc<Box<dyn Store>>, doc_id: &str) -> Result<Option<u64>> {
+    let mut modified_at = store.last_modified(&snapshot_key(doc_id)).await?;
+    if let Some(seq) = list_segments(store.as_ref().as_ref(), doc_id).await?.last() {
+        let segment_modified_at = store.last_modified(&segment_key(doc_id, *seq)).await?;
+        modified_at = modified_at.max(segment_modified_at);
+    }
+    Ok(modified_at)
+}
+
+/// The content of `doc_id` in `format`.
+pub async fn export_doc(
+    store: &Arc<Box<dyn Store>>,
+    doc_id: &str,
+    format: ExportFormat,
+) -> Result<Vec<u8>> {
+    let doc = DocWithSyncKv::new_with_update_log(
+        doc_id,
+        Some(store.clone()),
+        || {},
+        false,
+        Some(UpdateLogConfig::default()),
+    )
+    .await
+    .context("Failed to load document")?;
+    let update = doc.as_update();
+    match format {
+        ExportFormat::Json => Ok(serde_json::to_vec_pretty(&doc_to_json(&update)?)?),
+        ExportFormat::Yupdate => Ok(update),
+        ExportFormat::Prosemirror => Ok(serde_json::to_vec_pretty(&doc_to_prosemirror(
+            &update,
+            DEFAULT_TEXT_ROOT,
+        )?)?),
+    }
+}
+
+async fn export_to_file(
+    store: &Arc<Box<dyn Store>>,
+    doc_id: &str,
+    out: &Path,
+    format: ExportFormat,
+    modified_since: Option<u64>,
+) -> Result<DocExportOutcome> {
+    if let Some(modified_since) = modified_since {
+        // Documents without a modification time are exported
+        if let Some(modified_at) = doc_modified_at(store, doc_id).await? {
+            if modified_at < modified_since {
+                return Ok(DocExportOutcome {
+                    doc_id: doc_id.to_string(),
+                    skipped: true,
+                    bytes: 0,
+                    error: None,
+                });
+            }
+        }
+    }
+
+    let content = export_doc(store, doc_id, format).await?;
+    let path = out.join(format!("{}.{}", doc_id, format.extension()));
+    std::fs::write(&path, &content).with_context(|| format!("Failed to write {:?}", path))?;
+    Ok(DocExportOutcome {
+        doc_id: doc_id.to_string(),
+        skipped: false,
+        bytes: content.len(),
+        error: None,
+    })
+}
+
+/// Export `doc_ids` to files in `out`, skipping documents last modified before
+/// `modified_since` (epoch millis), and calling `progress` with each outcome and the
+/// number of documents done so far.
+pub async fn export_all(
+    store: &Arc<Box<dyn Store>>,
+    doc_ids: Vec<String>,
+    out: &Path,
+    format: ExportFormat,
+    modified_since: Option<u64>,
+    progress: impl Fn(&DocExportOutcome, usize),
+) -> Result<ExportReport> {
+    std::fs::create_dir_all(out).with_context(|| format!("Failed to create {:?}", out))?;
+
+    let mut results = Vec::with_capacity(doc_ids.len());
+    let mut exports = futures::stream::iter(doc_ids)
+        .map(|doc_id| async move {
+            export_to_file(store, &doc_id, out, format, modified_since)
+                .await
+                .unwrap_or_else(|e| DocExportOutcome {
+                    doc_id,
+                    skipped: false,
+                    bytes: 0,
+                    error: Some(format!("{:#}", e)),
+                })
+        })
+        .buffer_unordered(EXPORT_CONCURRENCY);
+    while let Some(outcome) = exports.next().await {
+        progress(&outcome, results.len() + 1);
+        results.push(outcome);
+    }
+
+    let skipped = results.iter().filter(|result| result.skipped).count();
+    let failed = results
+        .iter()
+        .filter(|result| result.error.is_some())
+        .count();
+    Ok(ExportReport {
+        exported: results.len() - skipped - failed,
+        skipped,
+        failed,
+        results,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stores::filesystem::FileSystemStore;
+    use yrs::{Text, Transact};
+
+    #[tokio::test]
+    async fn test_exports_documents_modified_since() {
+        let dir = std::env::temp_dir().join(format!("y-sweet-export-{}", nanoid::nanoid!()));
+        let store: Arc<Box<dyn Store>> =
+            Arc::new(Box::new(FileSystemStore::new(dir.join("store")).unwrap()));
+        let doc = DocWithSyncKv::new("doc", Some(store.clone()), || {}, false)
+            .await
+            .unwrap();
+        {
+            let awareness = doc.awareness();
+            let awareness = awareness.write().unwrap();
+            let text = awareness.doc().get_or_insert_text("text");
+            text.insert(&mut awareness.doc().transact_mut(), 0, "hello");
+        }
+        doc.sync_kv().persist().await.unwrap();
+
+        let out = dir.join("out");
+        let report = export_all(
+            &store,
+            vec!["doc".to_string()],
+            &out,
+            ExportFormat::Json,
+            None,
+            |_, _| {},
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.exported, 1);
+        let json: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(out.join("doc.json")).unwrap()).unwrap();
+        assert_eq!(json, serde_json::json!({ "text": "hello" }));
+
+        let report = export_all(
+            &store,
+            vec!["doc".to_string()],
+            &out,
+            ExportFormat::Yupdate,
+            Some(u64::MAX),
+            |_, _| {},
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.skipped, 1);
+    }
+}
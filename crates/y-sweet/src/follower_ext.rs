@@ -0,0 +1,226 @@
+//! Read-only follower mode.
+//!
+//! A follower serves documents from a store that another instance writes to. It never
+//! persists: it reloads the snapshot of each loaded document periodically and, when
+//! replication is configured, also applies the updates other nodes publish, without
+//! publishing any itself. WebSocket clients are always read-only, and HTTP requests that
+//! would change a document are rejected.
+
+use anyhow::{anyhow, Result};
+use axum::{
+    extract::{Request, State},
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+use y_sweet_core::{
+    doc_sync_ext::DocWithSyncKv, store::Store, sync::awareness::Awareness, sync_kv::SyncKv,
+    update_log_ext::UpdateLogConfig,
+};
+use yrs::{updates::decoder::Decode, Transact, Update};
+
+use crate::dirty_signal_ext::DirtyReceiver;
+use crate::server::{AppError, Server};
+
+/// Transaction origin of updates read from the stored snapshot.
+pub const FOLLOWER_ORIGIN: &str = "y-sweet-follower";
+
+/// Default interval between reloads of the snapshot of a loaded document.
+pub const DEFAULT_FOLLOWER_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+const FOLLOWER_WRITE_ERROR: &str = "This server is a read-only follower";
+
+/// Whether a request only reads. Issuing client tokens and checking the store do not
+/// change anything, even though they are POSTs.
+fn is_read_request(req: &Request) -> bool {
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return true;
+    }
+
+    let path = req.uri().path();
+    *req.method() == Method::POST
+        && (path == "/check_store" || (path.starts_with("/doc/") && path.ends_with("/auth")))
+}
+
+/// Fail with 403 when the server is a follower, for writes made outside the HTTP routes.
+pub fn check_not_follower(server: &Server) -> Result<(), AppError> {
+    if server.is_follower() {
+        return Err(AppError(
+            StatusCode::FORBIDDEN,
+            anyhow!(FOLLOWER_WRITE_ERROR),
+        ));
+    }
+    Ok(())
+}
+
+/// Reject requests that would change a document when the server is a follower.
+pub async fn reject_writes(
+    State(server): State<Arc<Server>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if server.is_follower() && !is_read_request(&req) {
+        return (StatusCode::FORBIDDEN, FOLLOWER_WRITE_ERROR).into_response();
+    }
+    next.run(req).await
+}
+
+/// Apply the stored snapshot of a document to its loaded copy, in a transaction with `origin`.
+pub(crate) async fn refresh(
+    store: &Arc<Box<dyn Store>>,
+    doc_id: &str,
+    awareness: &Arc<RwLock<Awareness>>,
+    skip_gc: bool,
+    origin: &str,
+) -> Result<()> {
+    // Also replays the update log, if the leader writes one
+    let snapshot = DocWithSyncKv::new_with_update_log(
+        doc_id,
+        Some(store.clone()),
+        || {},
+        skip_gc,
+        Some(UpdateLogConfig::default()),
+    )
+    .await?;
+    let update = Update::decode_v1(&snapshot.as_update())?;
+
+    let awareness = awareness.write().unwrap();
+    let mut txn = awareness.doc().transact_mut_with(origin);
+    txn.apply_update(update);
+    Ok(())
+}
+
+/// Keep a loaded document up to date with the store until it is unloaded or the server
+/// shuts down. Takes the place of the persistence worker.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn doc_follower_worker(
+    mut recv: DirtyReceiver,
+    store: Arc<Box<dyn Store>>,
+    sync_kv: Arc<SyncKv>,
+    awareness: Arc<RwLock<Awareness>>,
+    doc_id: String,
+    refresh_interval: Duration,
+    skip_gc: bool,
+    cancellation_token: CancellationToken,
+) {
+    loop {
+        tokio::select! {
+            // Changes are never persisted, so dirty signals only matter for shutdown
+            v = recv.recv() => {
+                if v.is_none() {
+                    break;
+                }
+            }
+            _ = tokio::time::sleep(refresh_interval) => {
+                if let Err(e) = refresh(&store, &doc_id, &awareness, skip_gc, FOLLOWER_ORIGIN).await {
+                    warn!(
+                        message = format!("Failed to refresh document from store: {}", e),
+                        event = "follower_refresh_failed",
+                        doc_id = %doc_id,
+                        error = %e
+                    );
+                }
+            }
+            _ = cancellation_token.cancelled() => break,
+        }
+
+        if sync_kv.is_shutdown() {
+            break;
+        }
+    }
+    debug!(
+        message = format!("Stopped following: {}", doc_id),
+        event = "follower_loop_terminated",
+        doc_id = %doc_id
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stores::filesystem::FileSystemStore;
+    use tower::ServiceExt;
+    use yrs::{GetString, Text};
+
+    fn store() -> Box<dyn Store> {
+        let path = std::env::temp_dir().join(format!("y-sweet-follower-{}", nanoid::nanoid!()));
+        Box::new(FileSystemStore::new(path).unwrap())
+    }
+
+    async fn server(store: Box<dyn Store>, follower: bool) -> Arc<Server> {
+        let server = Server::builder()
+            .store(Some(store))
+            .checkpoint_freq(Duration::from_secs(60))
+            .build()
+            .await
+            .unwrap();
+        Arc::new(if follower {
+            server.with_follower(DEFAULT_FOLLOWER_REFRESH_INTERVAL)
+        } else {
+            server
+        })
+    }
+
+    #[tokio::test]
+    async fn test_follower_rejects_writes() {
+        let follower = server(store(), true).await;
+
+        for (method, uri, status) in [
+            (Method::POST, "/doc/new", StatusCode::FORBIDDEN),
+            (Method::POST, "/d/abc/update", StatusCode::FORBIDDEN),
+            (Method::DELETE, "/d/abc", StatusCode::FORBIDDEN),
+            (Method::GET, "/ready", StatusCode::OK),
+        ] {
+            let response = follower
+                .routes()
+                .oneshot(
+                    http::Request::builder()
+                        .method(method)
+                        .uri(uri)
+                        .body(axum::body::Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status, "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_refresh_picks_up_stored_changes() {
+        let path = std::env::temp_dir().join(format!("y-sweet-follower-{}", nanoid::nanoid!()));
+        let store: Arc<Box<dyn Store>> =
+            Arc::new(Box::new(FileSystemStore::new(path.clone()).unwrap()));
+
+        let leader = server(Box::new(FileSystemStore::new(path).unwrap()), false).await;
+        let doc_id = leader.create_doc().await.unwrap();
+
+        let awareness = Arc::new(RwLock::new(Awareness::new(yrs::Doc::new())));
+        refresh(&store, &doc_id, &awareness, false, FOLLOWER_ORIGIN)
+            .await
+            .unwrap();
+
+        {
+            let doc = leader.docs.get(&doc_id).unwrap();
+            let awareness = doc.awareness();
+            let awareness = awareness.write().unwrap();
+            let text = awareness.doc().get_or_insert_text("text");
+            text.insert(&mut awareness.doc().transact_mut(), 0, "hello");
+        }
+        let sync_kv = leader.docs.get(&doc_id).unwrap().sync_kv();
+        sync_kv.persist().await.unwrap();
+
+        refresh(&store, &doc_id, &awareness, false, FOLLOWER_ORIGIN)
+            .await
+            .unwrap();
+        let awareness = awareness.read().unwrap();
+        let text = awareness.doc().get_or_insert_text("text");
+        assert_eq!(text.get_string(&awareness.doc().transact()), "hello");
+    }
+}
@@ -0,0 +1,112 @@
+//! Read-only ("frozen") documents.
+//!
+//! A frozen document accepts no changes, whatever the token of a client allows: new
+//! WebSocket connections get read-only authorization, writes over connections opened
+//! before the document was frozen are denied, and HTTP updates are rejected with
+//! 423 Locked. Frozen documents cannot be deleted, merged into or restored either.
+//!
+//! The frozen state is stored in the document metadata, so it survives restarts, and cached
+//! in memory as a flag per document that open connections share.
+
+use dashmap::DashMap;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use crate::metadata_ext::{read_doc_metadata, write_doc_metadata};
+use crate::server::{AppError, Server};
+
+/// In-memory frozen state of the documents checked so far.
+#[derive(Default)]
+pub struct DocFreezes {
+    flags: DashMap<String, Arc<AtomicBool>>,
+}
+
+impl DocFreezes {
+    fn cached(&self, doc_id: &str) -> Option<Arc<AtomicBool>> {
+        self.flags.get(doc_id).map(|flag| flag.clone())
+    }
+
+    fn set(&self, doc_id: &str, frozen: bool) {
+        self.flags
+            .entry(doc_id.to_string())
+            .or_insert_with(|| Arc::new(AtomicBool::new(frozen)))
+            .store(frozen, Ordering::SeqCst);
+    }
+}
+
+/// The flag set while `doc_id` is frozen, read from its metadata on first use.
+pub async fn doc_freeze_flag(server: &Server, doc_id: &str) -> Result<Arc<AtomicBool>, AppError> {
+    if let Some(flag) = server.freezes().cached(doc_id) {
+        return Ok(flag);
+    }
+
+    let frozen = match &server.store {
+        Some(store) => read_doc_metadata(store, doc_id).await?.frozen,
+        None => false,
+    };
+    // Set concurrently by a freeze if the entry appeared meanwhile
+    let flag = server
+        .freezes()
+        .flags
+        .entry(doc_id.to_string())
+        .or_insert_with(|| Arc::new(AtomicBool::new(frozen)))
+        .clone();
+    Ok(flag)
+}
+
+pub async fn is_doc_frozen(server: &Server, doc_id: &str) -> Result<bool, AppError> {
+    Ok(doc_freeze_flag(server, doc_id)
+        .await?
+        .load(Ordering::SeqCst))
+}
+
+/// Freeze or unfreeze a document, taking effect on its open connections immediately.
+pub async fn set_doc_frozen(server: &Server, doc_id: &str, frozen: bool) -> Result<(), AppError> {
+    if let Some(store) = &server.store {
+        let mut metadata = read_doc_metadata(store, doc_id).await?;
+        if metadata.frozen != frozen {
+            metadata.frozen = frozen;
+            write_doc_metadata(store, doc_id, &metadata).await?;
+        }
+    }
+    server.freezes().set(doc_id, frozen);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stores::filesystem::FileSystemStore;
+    use std::time::Duration;
+
+    async fn server(path: &std::path::Path) -> Server {
+        Server::builder()
+            .store(Some(Box::new(
+                FileSystemStore::new(path.to_path_buf()).unwrap(),
+            )))
+            .checkpoint_freq(Duration::from_secs(60))
+            .build()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_frozen_state_is_shared_and_stored() {
+        let path = std::env::temp_dir().join(format!("y-sweet-freeze-{}", nanoid::nanoid!()));
+        let server_state = server(&path).await;
+
+        let flag = doc_freeze_flag(&server_state, "doc").await.unwrap();
+        assert!(!flag.load(Ordering::SeqCst));
+
+        // Connections holding the flag see the change
+        set_doc_frozen(&server_state, "doc", true).await.unwrap();
+        assert!(flag.load(Ordering::SeqCst));
+
+        let restarted = server(&path).await;
+        assert!(is_doc_frozen(&restarted, "doc").await.unwrap());
+        set_doc_frozen(&restarted, "doc", false).await.unwrap();
+        assert!(!is_doc_frozen(&restarted, "doc").await.unwrap());
+    }
+}
@@ -0,0 +1,198 @@
+//! Garbage collection of the deleted content of stored documents.
+//!
+//! Documents edited for years, or with garbage collection disabled (`--skip-gc`), can hold
+//! far more deleted content than live content. Collecting a document rewrites its snapshot
+//! from its state with every deleted item garbage collected: the content of deleted items
+//! is dropped and only their IDs are kept, so clients that still hold the deleted content
+//! keep syncing with the document as before. Deleted content can no longer be recovered
+//! from the snapshot afterwards.
+//!
+//! A dry run reports the size the snapshot would have, without rewriting it.
+
+use anyhow::{anyhow, Context, Result};
+use axum::http::StatusCode;
+use std::sync::Arc;
+use tracing::info;
+use y_sweet_core::{
+    api_types_ext::DocGcResponse, doc_sync_ext::DocWithSyncKv, store::Store, sync_kv::SyncKv,
+    update_log_ext::UpdateLogConfig,
+};
+use yrs::{updates::decoder::Decode, Doc, Options, ReadTxn, StateVector, Transact, Update};
+
+use crate::server::{AppError, Server};
+
+/// Encode the document of `update` with the content of its deleted items garbage collected.
+pub fn collect_garbage(update: &[u8]) -> Result<Vec<u8>> {
+    let doc = Doc::with_options(Options {
+        skip_gc: false,
+        ..Options::default()
+    });
+    let update =
+        Update::decode_v1(update).map_err(|_| anyhow!("Failed to decode document state"))?;
+    // Items deleted by the transaction are collected when it is committed
+    doc.transact_mut().apply_update(update);
+    let txn = doc.transact();
+    Ok(txn.encode_state_as_update_v1(&StateVector::default()))
+}
+
+/// Garbage collect the stored state of `doc_id`, which must not be loaded, or only report
+/// the savings when `dry_run` is set.
+pub async fn collect_stored_garbage(
+    store: &Arc<Box<dyn Store>>,
+    doc_id: &str,
+    dry_run: bool,
+    snapshot_backup: bool,
+) -> Result<DocGcResponse> {
+    // Loaded without garbage collection, so the state is measured as stored, and with the
+    // update log, if there is one, so that its segments are included
+    let doc = DocWithSyncKv::new_with_update_log(
+        doc_id,
+        Some(store.clone()),
+        || {},
+        true,
+        Some(UpdateLogConfig::default()),
+    )
+    .await
+    .context("Failed to load document")?;
+    let bytes_before = doc.sync_kv().size_bytes();
+    let update = collect_garbage(&doc.as_update())?;
+
+    let sync_kv = if dry_run {
+        Arc::new(SyncKv::new(None, doc_id, || ()).await?)
+    } else {
+        let sync_kv = doc.sync_kv();
+        sync_kv.set_snapshot_backup(snapshot_backup);
+        sync_kv
+    };
+    sync_kv.replace_snapshot(&update).await?;
+    let bytes_after = sync_kv.size_bytes();
+
+    Ok(DocGcResponse {
+        doc_id: doc_id.to_string(),
+        dry_run,
+        bytes_before,
+        bytes_after,
+        saved_bytes: bytes_before.saturating_sub(bytes_after),
+    })
+}
+
+/// Garbage collect a document of the server. A loaded document is unloaded first, unless it
+/// has connections.
+pub async fn collect_doc_garbage(
+    server: &Server,
+    doc_id: &str,
+    dry_run: bool,
+) -> Result<DocGcResponse, AppError> {
+    let Some(store) = &server.store else {
+        return Err(AppError(
+            StatusCode::BAD_REQUEST,
+            anyhow!("Garbage collecting a document requires a store"),
+        ));
+    };
+
+    // Connections hold a reference to the awareness
+    let has_connections = |doc: &DocWithSyncKv| Arc::strong_count(&doc.awareness()) > 2;
+    let loaded = if dry_run {
+        server.docs.get(doc_id).map(|doc| doc.sync_kv())
+    } else {
+        if server
+            .docs
+            .get(doc_id)
+            .is_some_and(|doc| has_connections(&doc))
+        {
+            return Err(AppError(
+                StatusCode::CONFLICT,
+                anyhow!("Document has open connections"),
+            ));
+        }
+        // The loaded copy would otherwise overwrite the rewritten snapshot
+        server
+            .docs
+            .remove_if(doc_id, |_, doc| !has_connections(doc))
+            .map(|(_, doc)| {
+                doc.sync_kv().shutdown();
+                doc.sync_kv()
+            })
+    };
+    // Collect the latest state
+    if let Some(sync_kv) = loaded {
+        sync_kv.persist().await.map_err(|e| {
+            AppError(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                anyhow!("Failed to persist document: {}", e),
+            )
+        })?;
+    }
+
+    let response = collect_stored_garbage(store, doc_id, dry_run, server.snapshot_backup())
+        .await
+        .map_err(|e| {
+            AppError(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                anyhow!("Failed to garbage collect document: {:#}", e),
+            )
+        })?;
+    info!(
+        message = "Document garbage collected",
+        event = "document_gc_completed",
+        doc_id = %doc_id,
+        dry_run = dry_run,
+        bytes_before = response.bytes_before,
+        bytes_after = response.bytes_after
+    );
+    Ok(response)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stores::filesystem::FileSystemStore;
+    use yrs::{GetString, Text};
+
+    #[tokio::test]
+    async fn test_deleted_content_is_dropped() {
+        let path = std::env::temp_dir().join(format!("y-sweet-gc-{}", nanoid::nanoid!()));
+        let store: Arc<Box<dyn Store>> = Arc::new(Box::new(FileSystemStore::new(path).unwrap()));
+
+        // Without garbage collection, deleted content is kept
+        let doc = Doc::with_options(Options {
+            skip_gc: true,
+            ..Options::default()
+        });
+        let text = doc.get_or_insert_text("text");
+        text.insert(&mut doc.transact_mut(), 0, &"x".repeat(10_000));
+        text.remove_range(&mut doc.transact_mut(), 0, 10_000);
+        text.insert(&mut doc.transact_mut(), 0, "kept");
+        let update = doc
+            .transact()
+            .encode_state_as_update_v1(&StateVector::default());
+
+        let stored = DocWithSyncKv::new("doc", Some(store.clone()), || {}, true)
+            .await
+            .unwrap();
+        stored.sync_kv().replace_snapshot(&update).await.unwrap();
+        drop(stored);
+
+        let dry_run = collect_stored_garbage(&store, "doc", true, false)
+            .await
+            .unwrap();
+        assert!(dry_run.saved_bytes > 9_000);
+
+        let collected = collect_stored_garbage(&store, "doc", false, false)
+            .await
+            .unwrap();
+        assert_eq!(collected.bytes_after, dry_run.bytes_after);
+        let again = collect_stored_garbage(&store, "doc", true, false)
+            .await
+            .unwrap();
+        assert_eq!(again.bytes_before, collected.bytes_after);
+
+        let doc = DocWithSyncKv::new("doc", Some(store), || {}, false)
+            .await
+            .unwrap();
+        let awareness = doc.awareness();
+        let awareness = awareness.read().unwrap();
+        let text = awareness.doc().get_or_insert_text("text");
+        assert_eq!(text.get_string(&awareness.doc().transact()), "kept");
+    }
+}
@@ -0,0 +1,382 @@
+//! Optional GraphQL API, enabled with the `graphql` feature.
+//!
+//! Exposes the same operations as the REST admin API so they can be consumed through a
+//! GraphQL gateway. The whole endpoint is an admin API: requests require the server token,
+//! and resolvers delegate to the REST handlers so behavior stays identical.
+
+use async_graphql::{
+    Context, EmptySubscription, Enum, ErrorExtensions, Object, Schema, SimpleObject,
+};
+use axum::{extract::State, routing::post, Extension, Json, Router};
+use axum_extra::typed_header::TypedHeader;
+use std::sync::Arc;
+use y_sweet_core::{
+    api_types::{validate_doc_name, AuthDocRequest, Authorization, DocCreationRequest},
+    api_types_ext::{DocCopyRequest, DocCreationRequestExt, DocLineage},
+};
+
+use crate::metadata_ext::read_doc_metadata;
+use crate::proxy_ext::ClientInfo;
+use crate::server::{auth_doc, new_doc, AppError, Server};
+use crate::server_ext::{copy_document, delete_document, ext_check_not_archived, list_doc_assets};
+use crate::ttl_ext::get_doc_expiration;
+
+type AuthHeader = Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>;
+
+pub type YSweetSchema = Schema<QueryRoot, MutationRoot, EmptySubscription>;
+
+/// Per-request data made available to resolvers
+struct RequestContext {
+    server_state: Arc<Server>,
+    auth_header: AuthHeader,
+    host: headers::Host,
+    /// The client as the trusted proxies forwarding the request say, for the URLs of tokens.
+    client: Option<ClientInfo>,
+}
+
+/// Convert a REST error into a GraphQL error, keeping the HTTP status as an extension
+fn gql_error(AppError(status, error): AppError) -> async_graphql::Error {
+    async_graphql::Error::new(error.to_string())
+        .extend_with(|_, extensions| extensions.set("status", i32::from(status.as_u16())))
+}
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq)]
+pub enum AuthorizationLevel {
+    Full,
+    ReadOnly,
+}
+
+impl From<Authorization> for AuthorizationLevel {
+    fn from(authorization: Authorization) -> Self {
+        match authorization {
+            Authorization::Full => AuthorizationLevel::Full,
+            Authorization::ReadOnly => AuthorizationLevel::ReadOnly,
+        }
+    }
+}
+
+impl From<AuthorizationLevel> for Authorization {
+    fn from(level: AuthorizationLevel) -> Self {
+        match level {
+            AuthorizationLevel::Full => Authorization::Full,
+            AuthorizationLevel::ReadOnly => Authorization::ReadOnly,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct Lineage {
+    parent_doc_id: String,
+    /// Base64 encoded (v1) state vector of the parent at the fork point
+    fork_state_vector: String,
+    forked_at: u64,
+}
+
+impl From<DocLineage> for Lineage {
+    fn from(lineage: DocLineage) -> Self {
+        Self {
+            parent_doc_id: lineage.parent_doc_id,
+            fork_state_vector: lineage.fork_state_vector,
+            forked_at: lineage.forked_at,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct Asset {
+    asset_id: String,
+    download_url: String,
+}
+
+#[derive(SimpleObject)]
+pub struct Presence {
+    client_id: u64,
+    /// Awareness state of the client, as JSON
+    state: String,
+}
+
+#[derive(SimpleObject)]
+pub struct DeleteResult {
+    doc_id: String,
+    data_deleted: bool,
+    deleted_assets: usize,
+}
+
+#[derive(SimpleObject)]
+pub struct ClientToken {
+    url: String,
+    base_url: Option<String>,
+    doc_id: String,
+    token: Option<String>,
+    authorization: AuthorizationLevel,
+}
+
+pub struct Document {
+    doc_id: String,
+}
+
+#[Object]
+impl Document {
+    async fn doc_id(&self) -> &str {
+        &self.doc_id
+    }
+
+    /// Fork lineage, if the document was created by a fork
+    async fn lineage(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<Lineage>> {
+        let request = ctx.data::<RequestContext>()?;
+        let Some(store) = &request.server_state.store else {
+            return Ok(None);
+        };
+        let metadata = read_doc_metadata(store, &self.doc_id)
+            .await
+            .map_err(gql_error)?;
+        Ok(metadata.lineage.map(Lineage::from))
+    }
+
+    /// Expiration time in epoch milliseconds, if the document has a TTL
+    async fn expires_at(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<u64>> {
+        let request = ctx.data::<RequestContext>()?;
+        get_doc_expiration(&request.server_state, &self.doc_id)
+            .await
+            .map_err(gql_error)
+    }
+
+    async fn assets(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<Asset>> {
+        let request = ctx.data::<RequestContext>()?;
+        let assets = list_doc_assets(&request.server_state, &self.doc_id)
+            .await
+            .map_err(gql_error)?;
+        Ok(assets
+            .into_iter()
+            .map(|asset| Asset {
+                asset_id: asset.asset_id,
+                download_url: asset.download_url,
+            })
+            .collect())
+    }
+
+    /// Clients currently present in the document. Empty when the document is not loaded.
+    async fn presence(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<Presence>> {
+        let request = ctx.data::<RequestContext>()?;
+        let Some(doc) = request.server_state.docs.get(&self.doc_id) else {
+            return Ok(Vec::new());
+        };
+        let awareness = doc.awareness();
+        let awareness = awareness.read().unwrap();
+        Ok(awareness
+            .clients()
+            .iter()
+            .map(|(client_id, state)| Presence {
+                client_id: *client_id,
+                state: state.clone(),
+            })
+            .collect())
+    }
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// Look up a document. Returns null if it does not exist.
+    async fn document(
+        &self,
+        ctx: &Context<'_>,
+        doc_id: String,
+    ) -> async_graphql::Result<Option<Document>> {
+        let request = ctx.data::<RequestContext>()?;
+        if !validate_doc_name(&doc_id) {
+            return Err(async_graphql::Error::new("Invalid document ID"));
+        }
+        ext_check_not_archived(&request.server_state, &doc_id)
+            .await
+            .map_err(gql_error)?;
+        if !request.server_state.doc_exists(&doc_id).await {
+            return Ok(None);
+        }
+        Ok(Some(Document { doc_id }))
+    }
+}
+
+pub struct MutationRoot;
+
+#[Object]
+impl MutationRoot {
+    /// Create a document and return its ID
+    async fn create_document(
+        &self,
+        ctx: &Context<'_>,
+        doc_id: Option<String>,
+        expires_in_seconds: Option<u64>,
+    ) -> async_graphql::Result<String> {
+        let request = ctx.data::<RequestContext>()?;
+        let Json(response) = new_doc(
+            request.auth_header.clone(),
+            State(request.server_state.clone()),
+            Json(DocCreationRequestExt {
+                request: DocCreationRequest { doc_id },
+                expires_in_seconds,
+            }),
+        )
+        .await
+        .map_err(gql_error)?;
+        Ok(response.doc_id)
+    }
+
+    /// Copy a document (including assets) to a new document ID
+    async fn copy_document(
+        &self,
+        ctx: &Context<'_>,
+        doc_id: String,
+        destination_doc_id: String,
+    ) -> async_graphql::Result<bool> {
+        let request = ctx.data::<RequestContext>()?;
+        let Json(response) = copy_document(
+            axum::extract::Path(doc_id),
+            State(request.server_state.clone()),
+            request.auth_header.clone(),
+            Json(DocCopyRequest {
+                destination_doc_id,
+                dry_run: false,
+            }),
+        )
+        .await
+        .map_err(gql_error)?;
+        Ok(response.success)
+    }
+
+    /// Delete a document and its assets
+    async fn delete_document(
+        &self,
+        ctx: &Context<'_>,
+        doc_id: String,
+    ) -> async_graphql::Result<DeleteResult> {
+        let request = ctx.data::<RequestContext>()?;
+        let Json(response) = delete_document(
+            axum::extract::Path(doc_id),
+            State(request.server_state.clone()),
+            request.auth_header.clone(),
+            None,
+        )
+        .await
+        .map_err(gql_error)?;
+        Ok(DeleteResult {
+            doc_id: response.doc_id,
+            data_deleted: response.data_deleted,
+            deleted_assets: response.deleted_assets,
+        })
+    }
+
+    /// Generate a client token for connecting to a document
+    async fn auth_token(
+        &self,
+        ctx: &Context<'_>,
+        doc_id: String,
+        authorization: Option<AuthorizationLevel>,
+        valid_for_seconds: Option<u64>,
+    ) -> async_graphql::Result<ClientToken> {
+        let request = ctx.data::<RequestContext>()?;
+        let Json(token) = auth_doc(
+            request.auth_header.clone(),
+            TypedHeader(request.host.clone()),
+            request.client.clone().map(Extension),
+            State(request.server_state.clone()),
+            axum::extract::Path(doc_id),
+            Some(Json(AuthDocRequest {
+                authorization: authorization
+                    .map(Authorization::from)
+                    .unwrap_or(Authorization::Full),
+                user_id: None,
+                valid_for_seconds,
+            })),
+        )
+        .await
+        .map_err(gql_error)?;
+        Ok(ClientToken {
+            url: token.url,
+            base_url: token.base_url,
+            doc_id: token.doc_id,
+            token: token.token,
+            authorization: token.authorization.into(),
+        })
+    }
+}
+
+pub fn build_schema() -> YSweetSchema {
+    Schema::build(QueryRoot, MutationRoot, EmptySubscription).finish()
+}
+
+async fn graphql_handler(
+    State(server_state): State<Arc<Server>>,
+    Extension(schema): Extension<YSweetSchema>,
+    auth_header: AuthHeader,
+    TypedHeader(host): TypedHeader<headers::Host>,
+    client: Option<Extension<ClientInfo>>,
+    Json(request): Json<async_graphql::Request>,
+) -> Result<Json<async_graphql::Response>, AppError> {
+    // Check authentication - the GraphQL API is admin-only
+    server_state.check_auth(auth_header.clone())?;
+
+    let request = request.data(RequestContext {
+        server_state,
+        auth_header,
+        host,
+        client: client.map(|Extension(client)| client),
+    });
+
+    Ok(Json(schema.execute(request).await))
+}
+
+/// GraphQL routes, merged into the extension routes when the `graphql` feature is enabled
+pub fn ext_graphql_routes(server: &Arc<Server>) -> Router {
+    Router::new()
+        .route("/graphql", post(graphql_handler))
+        .layer(Extension(build_schema()))
+        .with_state(server.clone())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_create_and_query_document() {
+        let server_state = Arc::new(
+            Server::builder()
+                .checkpoint_freq(Duration::from_secs(60))
+                .build()
+                .await
+                .unwrap(),
+        );
+        let schema = build_schema();
+        let request = |query: &str| {
+            async_graphql::Request::new(query).data(RequestContext {
+                server_state: server_state.clone(),
+                auth_header: None,
+                host: headers::Host::from(http::uri::Authority::from_static("localhost")),
+                client: None,
+            })
+        };
+
+        let response = schema
+            .execute(request(
+                r#"mutation { createDocument(docId: "gql-doc") authToken(docId: "gql-doc") { url } }"#,
+            ))
+            .await;
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+        let data = response.data.into_json().unwrap();
+        assert_eq!(data["createDocument"], "gql-doc");
+        assert_eq!(data["authToken"]["url"], "ws://localhost/d/gql-doc/ws");
+
+        let response = schema
+            .execute(request(
+                r#"{ document(docId: "gql-doc") { docId assets { assetId } presence { clientId } } missing: document(docId: "missing") { docId } }"#,
+            ))
+            .await;
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+        let data = response.data.into_json().unwrap();
+        assert_eq!(data["document"]["docId"], "gql-doc");
+        assert!(data["missing"].is_null());
+    }
+}
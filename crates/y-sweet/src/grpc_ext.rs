@@ -0,0 +1,512 @@
+//! Optional gRPC management API, enabled with the `grpc` feature.
+//!
+//! Mirrors the document management operations of the HTTP admin API on a separate port
+//! (see `proto/y_sweet.proto`). Every call requires the server token, passed as
+//! `authorization: Bearer <token>` metadata. Handlers delegate to the REST handlers where
+//! possible so behavior stays identical; updates are exchanged as raw bytes. The checks the
+//! HTTP API makes in middleware, that the server is not a follower and owns the document,
+//! are made by the handlers here.
+
+use axum::{extract::State, http::StatusCode, Json};
+use axum_extra::typed_header::TypedHeader;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::TcpListenerStream;
+use tokio_util::sync::CancellationToken;
+use tonic::{Request, Response, Status};
+use tracing::{error, info};
+use y_sweet_core::{
+    api_types::{AuthDocRequest, DocCreationRequest},
+    api_types_ext::{DocCopyRequest, DocCreationRequestExt},
+};
+use yrs::{
+    updates::{decoder::Decode, encoder::Encode},
+    ReadTxn, Transact, Update,
+};
+
+use crate::cluster_ext::check_local;
+use crate::follower_ext::check_not_follower;
+use crate::server::{auth_doc, new_doc, update_doc_inner, AppError, Server};
+use crate::server_ext::{copy_document, delete_document, ext_check_not_archived};
+
+mod proto {
+    tonic::include_proto!("y_sweet.v1");
+}
+
+pub use proto::document_service_server::{DocumentService, DocumentServiceServer};
+pub use proto::*;
+
+type AuthHeader = Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>;
+
+/// Convert a REST error into a gRPC status
+fn grpc_status(AppError(status, error): AppError) -> Status {
+    let message = error.to_string();
+    match status {
+        StatusCode::BAD_REQUEST => Status::invalid_argument(message),
+        StatusCode::UNAUTHORIZED => Status::unauthenticated(message),
+        StatusCode::FORBIDDEN => Status::permission_denied(message),
+        StatusCode::NOT_FOUND => Status::not_found(message),
+        StatusCode::CONFLICT => Status::already_exists(message),
+        StatusCode::GONE | StatusCode::MISDIRECTED_REQUEST | StatusCode::LOCKED => {
+            Status::failed_precondition(message)
+        }
+        StatusCode::UNPROCESSABLE_ENTITY => Status::invalid_argument(message),
+        StatusCode::SERVICE_UNAVAILABLE => Status::unavailable(message),
+        _ => Status::internal(message),
+    }
+}
+
+/// Read the server token from the `authorization` metadata, in the same form as the HTTP header
+// `Status` is the error type of every tonic handler
+#[allow(clippy::result_large_err)]
+fn auth_header<T>(request: &Request<T>) -> Result<AuthHeader, Status> {
+    let Some(value) = request.metadata().get("authorization") else {
+        return Ok(None);
+    };
+
+    let token = value
+        .to_str()
+        .ok()
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or_else(|| Status::unauthenticated("Malformed authorization metadata"))?;
+
+    let header = headers::Authorization::bearer(token)
+        .map_err(|_| Status::unauthenticated("Malformed authorization metadata"))?;
+
+    Ok(Some(TypedHeader(header)))
+}
+
+impl From<y_sweet_core::api_types::Authorization> for proto::Authorization {
+    fn from(authorization: y_sweet_core::api_types::Authorization) -> Self {
+        match authorization {
+            y_sweet_core::api_types::Authorization::Full => proto::Authorization::Full,
+            y_sweet_core::api_types::Authorization::ReadOnly => proto::Authorization::ReadOnly,
+        }
+    }
+}
+
+impl From<proto::Authorization> for y_sweet_core::api_types::Authorization {
+    fn from(authorization: proto::Authorization) -> Self {
+        match authorization {
+            proto::Authorization::Full => y_sweet_core::api_types::Authorization::Full,
+            proto::Authorization::ReadOnly => y_sweet_core::api_types::Authorization::ReadOnly,
+        }
+    }
+}
+
+pub struct GrpcDocumentService {
+    server_state: Arc<Server>,
+}
+
+impl GrpcDocumentService {
+    pub fn new(server_state: Arc<Server>) -> Self {
+        Self { server_state }
+    }
+
+    /// Check the server token and that the document exists and is not archived
+    async fn check_doc(&self, auth_header: AuthHeader, doc_id: &str) -> Result<(), Status> {
+        self.server_state
+            .check_auth(auth_header)
+            .map_err(grpc_status)?;
+
+        ext_check_not_archived(&self.server_state, doc_id)
+            .await
+            .map_err(grpc_status)?;
+
+        if !self.server_state.doc_exists(doc_id).await {
+            return Err(Status::not_found(format!("Doc {} not found", doc_id)));
+        }
+
+        Ok(())
+    }
+
+    /// Check that this node may change the document: that it is not a follower, and that it
+    /// owns the document when it is part of a cluster
+    // `Status` is the error type of every tonic handler
+    #[allow(clippy::result_large_err)]
+    fn check_writable(&self, doc_id: &str) -> Result<(), Status> {
+        check_not_follower(&self.server_state).map_err(grpc_status)?;
+        check_local(&self.server_state, doc_id).map_err(grpc_status)
+    }
+}
+
+#[tonic::async_trait]
+impl DocumentService for GrpcDocumentService {
+    async fn create_document(
+        &self,
+        request: Request<CreateDocumentRequest>,
+    ) -> Result<Response<CreateDocumentResponse>, Status> {
+        let auth_header = auth_header(&request)?;
+        let CreateDocumentRequest {
+            doc_id,
+            expires_in_seconds,
+        } = request.into_inner();
+        check_not_follower(&self.server_state).map_err(grpc_status)?;
+
+        let Json(response) = new_doc(
+            auth_header,
+            State(self.server_state.clone()),
+            Json(DocCreationRequestExt {
+                request: DocCreationRequest { doc_id },
+                expires_in_seconds,
+            }),
+        )
+        .await
+        .map_err(grpc_status)?;
+
+        Ok(Response::new(CreateDocumentResponse {
+            doc_id: response.doc_id,
+        }))
+    }
+
+    async fn delete_document(
+        &self,
+        request: Request<DeleteDocumentRequest>,
+    ) -> Result<Response<DeleteDocumentResponse>, Status> {
+        let auth_header = auth_header(&request)?;
+        let DeleteDocumentRequest { doc_id } = request.into_inner();
+        self.check_writable(&doc_id)?;
+
+        let Json(response) = delete_document(
+            axum::extract::Path(doc_id),
+            State(self.server_state.clone()),
+            auth_header,
+            None,
+        )
+        .await
+        .map_err(grpc_status)?;
+
+        Ok(Response::new(DeleteDocumentResponse {
+            doc_id: response.doc_id,
+            data_deleted: response.data_deleted,
+            deleted_assets: response.deleted_assets as u64,
+        }))
+    }
+
+    async fn copy_document(
+        &self,
+        request: Request<CopyDocumentRequest>,
+    ) -> Result<Response<CopyDocumentResponse>, Status> {
+        let auth_header = auth_header(&request)?;
+        let CopyDocumentRequest {
+            doc_id,
+            destination_doc_id,
+        } = request.into_inner();
+        self.check_writable(&doc_id)?;
+        self.check_writable(&destination_doc_id)?;
+
+        let Json(response) = copy_document(
+            axum::extract::Path(doc_id),
+            State(self.server_state.clone()),
+            auth_header,
+            Json(DocCopyRequest {
+                destination_doc_id,
+                dry_run: false,
+            }),
+        )
+        .await
+        .map_err(grpc_status)?;
+
+        Ok(Response::new(CopyDocumentResponse {
+            success: response.success,
+        }))
+    }
+
+    async fn get_auth_token(
+        &self,
+        request: Request<AuthTokenRequest>,
+    ) -> Result<Response<ClientToken>, Status> {
+        let auth_header = auth_header(&request)?;
+        let request = request.into_inner();
+        let authorization = request.authorization().into();
+
+        // The host is only used to build connection URLs when there is no URL prefix
+        let host = match request.host.as_deref() {
+            Some(host) => host,
+            None if self.server_state.url_prefix().is_some() => "localhost",
+            None => {
+                return Err(Status::invalid_argument(
+                    "host is required when the server has no URL prefix",
+                ))
+            }
+        };
+        let host = host
+            .parse::<axum::http::uri::Authority>()
+            .map_err(|_| Status::invalid_argument("Invalid host"))?;
+
+        let Json(token) = auth_doc(
+            auth_header,
+            TypedHeader(headers::Host::from(host)),
+            // Served on its own port, without the trusted proxies of the HTTP API
+            None,
+            State(self.server_state.clone()),
+            axum::extract::Path(request.doc_id),
+            Some(Json(AuthDocRequest {
+                authorization,
+                user_id: None,
+                valid_for_seconds: request.valid_for_seconds,
+            })),
+        )
+        .await
+        .map_err(grpc_status)?;
+
+        let mut response = ClientToken {
+            url: token.url,
+            base_url: token.base_url,
+            doc_id: token.doc_id,
+            token: token.token,
+            authorization: 0,
+        };
+        response.set_authorization(token.authorization.into());
+
+        Ok(Response::new(response))
+    }
+
+    async fn get_as_update(
+        &self,
+        request: Request<GetAsUpdateRequest>,
+    ) -> Result<Response<DocumentUpdate>, Status> {
+        let auth_header = auth_header(&request)?;
+        let GetAsUpdateRequest { doc_id } = request.into_inner();
+        self.check_doc(auth_header, &doc_id).await?;
+
+        let dwskv = self
+            .server_state
+            .get_or_create_doc(&doc_id)
+            .await
+            .map_err(|e| grpc_status(AppError(StatusCode::INTERNAL_SERVER_ERROR, e)))?;
+
+        Ok(Response::new(DocumentUpdate {
+            update: dwskv.as_update(),
+        }))
+    }
+
+    async fn apply_update(
+        &self,
+        request: Request<ApplyUpdateRequest>,
+    ) -> Result<Response<ApplyUpdateResponse>, Status> {
+        let auth_header = auth_header(&request)?;
+        let ApplyUpdateRequest { doc_id, update } = request.into_inner();
+        self.check_doc(auth_header, &doc_id).await?;
+
+        self.check_writable(&doc_id)?;
+
+        // Malformed updates are the caller's error, which the REST path reports as a 500
+        Update::decode_v1(&update).map_err(|e| Status::invalid_argument(e.to_string()))?;
+        update_doc_inner(
+            doc_id.clone(),
+            self.server_state.clone(),
+            y_sweet_core::api_types::Authorization::Full,
+            update.into(),
+            None,
+        )
+        .await
+        .map_err(grpc_status)?;
+
+        let dwskv = self
+            .server_state
+            .get_or_create_doc(&doc_id)
+            .await
+            .map_err(|e| grpc_status(AppError(StatusCode::INTERNAL_SERVER_ERROR, e)))?;
+
+        let state_vector = {
+            let awareness = dwskv.awareness();
+            let awareness = awareness.read().unwrap();
+            let state_vector = awareness.doc.transact().state_vector();
+            state_vector.encode_v1()
+        };
+
+        Ok(Response::new(ApplyUpdateResponse { state_vector }))
+    }
+}
+
+/// Serve the gRPC management API until the server shuts down
+pub(crate) async fn serve_grpc(
+    server_state: Arc<Server>,
+    listener: TcpListener,
+    cancellation_token: CancellationToken,
+) {
+    let addr = listener.local_addr().ok();
+    info!(
+        message = "gRPC management API listening",
+        event = "grpc_server_started",
+        address = ?addr
+    );
+
+    let result = tonic::transport::Server::builder()
+        .add_service(DocumentServiceServer::new(GrpcDocumentService::new(
+            server_state,
+        )))
+        .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async move {
+            cancellation_token.cancelled().await
+        })
+        .await;
+
+    if let Err(e) = result {
+        error!(
+            message = "gRPC management API failed",
+            event = "grpc_server_failed",
+            error = %e
+        );
+    }
+    tracing::debug!("Exiting gRPC server");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+    use y_sweet_core::auth::Authenticator;
+    use yrs::{Doc, GetString, Text};
+
+    async fn service() -> (GrpcDocumentService, String) {
+        let authenticator = Authenticator::gen_key().unwrap();
+        let server_token = authenticator.server_token();
+        let server_state = Server::builder()
+            .checkpoint_freq(Duration::from_secs(60))
+            .authenticator(Some(authenticator))
+            .build()
+            .await
+            .unwrap();
+        (
+            GrpcDocumentService::new(Arc::new(server_state)),
+            server_token,
+        )
+    }
+
+    fn authorized<T>(message: T, server_token: &str) -> Request<T> {
+        let mut request = Request::new(message);
+        request.metadata_mut().insert(
+            "authorization",
+            format!("Bearer {}", server_token).parse().unwrap(),
+        );
+        request
+    }
+
+    #[tokio::test]
+    async fn test_grpc_requires_server_token() {
+        let (service, _) = service().await;
+        let status = service
+            .create_document(Request::new(CreateDocumentRequest::default()))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+
+    #[tokio::test]
+    async fn test_grpc_update_round_trip() {
+        let (service, server_token) = service().await;
+
+        let doc_id = service
+            .create_document(authorized(
+                CreateDocumentRequest {
+                    doc_id: Some("grpc-doc".to_string()),
+                    expires_in_seconds: None,
+                },
+                &server_token,
+            ))
+            .await
+            .unwrap()
+            .into_inner()
+            .doc_id;
+        assert_eq!(doc_id, "grpc-doc");
+
+        let doc = Doc::new();
+        let text = doc.get_or_insert_text("content");
+        let update = {
+            let mut txn = doc.transact_mut();
+            text.insert(&mut txn, 0, "hello");
+            txn.encode_update_v1()
+        };
+
+        let response = service
+            .apply_update(authorized(
+                ApplyUpdateRequest {
+                    doc_id: doc_id.clone(),
+                    update,
+                },
+                &server_token,
+            ))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(!response.state_vector.is_empty());
+
+        let update = service
+            .get_as_update(authorized(GetAsUpdateRequest { doc_id }, &server_token))
+            .await
+            .unwrap()
+            .into_inner()
+            .update;
+
+        let doc = Doc::new();
+        let text = doc.get_or_insert_text("content");
+        {
+            let mut txn = doc.transact_mut();
+            txn.apply_update(Update::decode_v1(&update).unwrap());
+        }
+        assert_eq!(text.get_string(&doc.transact()), "hello");
+
+        let status = service
+            .get_as_update(authorized(
+                GetAsUpdateRequest {
+                    doc_id: "missing".to_string(),
+                },
+                &server_token,
+            ))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_grpc_updates_are_checked_like_rest_updates() {
+        let (service, server_token) = service().await;
+        service
+            .create_document(authorized(
+                CreateDocumentRequest {
+                    doc_id: Some("grpc-doc".to_string()),
+                    expires_in_seconds: None,
+                },
+                &server_token,
+            ))
+            .await
+            .unwrap();
+
+        let apply = |update: Vec<u8>| {
+            service.apply_update(authorized(
+                ApplyUpdateRequest {
+                    doc_id: "grpc-doc".to_string(),
+                    update,
+                },
+                &server_token,
+            ))
+        };
+
+        let status = apply(vec![0xff, 0xff]).await.err().unwrap();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        let Json(frozen) = crate::server_ext::freeze_document(
+            axum::extract::Path("grpc-doc".to_string()),
+            State(service.server_state.clone()),
+            Some(TypedHeader(
+                headers::Authorization::bearer(&server_token).unwrap(),
+            )),
+        )
+        .await
+        .unwrap();
+        assert!(frozen.frozen);
+
+        let doc = Doc::new();
+        let text = doc.get_or_insert_text("content");
+        let update = {
+            let mut txn = doc.transact_mut();
+            text.insert(&mut txn, 0, "hello");
+            txn.encode_update_v1()
+        };
+        let status = apply(update).await.err().unwrap();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+    }
+}
@@ -0,0 +1,166 @@
+//! Migrating documents between a store and the SQLite persistence of Hocuspocus.
+//!
+//! The SQLite extension of Hocuspocus keeps each document as one row of its `documents`
+//! table: its name, and its whole state encoded as a Yjs v1 update. Importing writes each
+//! row to the store as a document snapshot, the way `convert-from-update` writes them.
+//! Exporting goes the other way, creating the table if needed and replacing the rows of
+//! documents that are already in it, as Hocuspocus itself stores them.
+
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection, OpenFlags};
+use std::{path::Path, sync::Arc};
+use y_sweet_core::store::Store;
+
+use crate::doc_create_ext::{import_doc, DocImportOutcome, ImportReport};
+use crate::export_ext::{export_doc, DocExportOutcome, ExportFormat, ExportReport};
+
+/// The schema Hocuspocus creates its table with.
+const CREATE_TABLE: &str = r#"CREATE TABLE IF NOT EXISTS "documents" (
+  "name" varchar(255) NOT NULL,
+  "data" blob NOT NULL,
+  UNIQUE(name)
+)"#;
+
+const UPSERT: &str = r#"INSERT INTO "documents" ("name", "data") VALUES (?1, ?2)
+  ON CONFLICT(name) DO UPDATE SET data = ?2"#;
+
+/// Import the documents of the Hocuspocus database at `path` whose name starts with `prefix`
+/// into `store`, under their name as document ID. Documents that already exist in the store
+/// fail, or are skipped with `skip_existing`. Calls `progress` with each outcome and the
+/// number of documents done so far.
+pub async fn import_hocuspocus(
+    path: &Path,
+    store: &Arc<Box<dyn Store>>,
+    prefix: &str,
+    skip_existing: bool,
+    progress: impl Fn(&DocImportOutcome, usize),
+) -> Result<ImportReport> {
+    let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("Failed to open Hocuspocus database {:?}", path))?;
+    let mut statement = connection
+        .prepare(r#"SELECT "name", "data" FROM "documents" ORDER BY "name""#)
+        .context("Failed to read the documents table")?;
+    let mut rows = statement.query([])?;
+
+    let mut results = Vec::new();
+    while let Some(row) = rows.next()? {
+        let doc_id: String = row.get(0)?;
+        if !doc_id.starts_with(prefix) {
+            continue;
+        }
+        let update: Vec<u8> = row.get(1)?;
+        let outcome = import_doc(store, &doc_id, &update, skip_existing).await;
+        progress(&outcome, results.len() + 1);
+        results.push(outcome);
+    }
+    Ok(ImportReport::new(results))
+}
+
+/// Export `doc_ids` from `store` to the Hocuspocus database at `path`, which is created if
+/// needed. Calls `progress` with each outcome and the number of documents done so far.
+pub async fn export_hocuspocus(
+    store: &Arc<Box<dyn Store>>,
+    doc_ids: Vec<String>,
+    path: &Path,
+    progress: impl Fn(&DocExportOutcome, usize),
+) -> Result<ExportReport> {
+    let connection = Connection::open(path)
+        .with_context(|| format!("Failed to open Hocuspocus database {:?}", path))?;
+    connection
+        .execute(CREATE_TABLE, [])
+        .context("Failed to create the documents table")?;
+
+    let mut results = Vec::with_capacity(doc_ids.len());
+    for doc_id in doc_ids {
+        let export = async {
+            let update = export_doc(store, &doc_id, ExportFormat::Yupdate).await?;
+            connection
+                .execute(UPSERT, params![doc_id, update])
+                .context("Failed to write the document")?;
+            Ok::<_, anyhow::Error>(update.len())
+        };
+        let outcome = match export.await {
+            Ok(bytes) => DocExportOutcome {
+                doc_id,
+                skipped: false,
+                bytes,
+                error: None,
+            },
+            Err(e) => DocExportOutcome {
+                doc_id,
+                skipped: false,
+                bytes: 0,
+                error: Some(format!("{:#}", e)),
+            },
+        };
+        progress(&outcome, results.len() + 1);
+        results.push(outcome);
+    }
+
+    let failed = results
+        .iter()
+        .filter(|result| result.error.is_some())
+        .count();
+    Ok(ExportReport {
+        exported: results.len() - failed,
+        skipped: 0,
+        failed,
+        results,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stores::filesystem::FileSystemStore;
+    use y_sweet_core::doc_sync_ext::DocWithSyncKv;
+    use yrs::{GetString, Text, Transact};
+
+    #[tokio::test]
+    async fn test_round_trips_through_hocuspocus() {
+        let dir = std::env::temp_dir().join(format!("y-sweet-hocuspocus-{}", nanoid::nanoid!()));
+        let store: Arc<Box<dyn Store>> =
+            Arc::new(Box::new(FileSystemStore::new(dir.join("from")).unwrap()));
+        let doc = DocWithSyncKv::new("notes", Some(store.clone()), || {}, false)
+            .await
+            .unwrap();
+        {
+            let awareness = doc.awareness();
+            let awareness = awareness.write().unwrap();
+            let text = awareness.doc().get_or_insert_text("text");
+            text.insert(&mut awareness.doc().transact_mut(), 0, "hello");
+        }
+        doc.sync_kv().persist().await.unwrap();
+
+        let db_path = dir.join("hocuspocus.sqlite");
+        let report = export_hocuspocus(&store, vec!["notes".to_string()], &db_path, |_, _| {})
+            .await
+            .unwrap();
+        assert_eq!(report.exported, 1);
+        // Exporting again replaces the row
+        export_hocuspocus(&store, vec!["notes".to_string()], &db_path, |_, _| {})
+            .await
+            .unwrap();
+
+        let to: Arc<Box<dyn Store>> =
+            Arc::new(Box::new(FileSystemStore::new(dir.join("to")).unwrap()));
+        let report = import_hocuspocus(&db_path, &to, "", false, |_, _| {})
+            .await
+            .unwrap();
+        assert_eq!(report.imported, 1);
+        let doc = DocWithSyncKv::new("notes", Some(to.clone()), || {}, false)
+            .await
+            .unwrap();
+        {
+            let awareness = doc.awareness();
+            let awareness = awareness.read().unwrap();
+            let text = awareness.doc().get_or_insert_text("text");
+            assert_eq!(text.get_string(&awareness.doc().transact()), "hello");
+        }
+
+        let report = import_hocuspocus(&db_path, &to, "", false, |_, _| {})
+            .await
+            .unwrap();
+        assert_eq!(report.failed, 1);
+    }
+}
@@ -0,0 +1,343 @@
+//! HTML rendering of rich-text documents.
+//!
+//! The XmlFragment of a document is converted to ProseMirror JSON (see
+//! [crate::convert::doc_to_prosemirror]), whose nodes and marks are then rendered by name.
+//! The common Tiptap and ProseMirror nodes and marks are rendered out of the box; custom
+//! editor nodes and marks are rendered by registering renderers for their names, which also
+//! override the built-in ones. Nodes without a renderer are rendered as their content, and
+//! marks without a renderer are dropped, so unknown content degrades to its text.
+
+use anyhow::Result;
+use serde_json::Value;
+use std::{collections::HashMap, io::Write, sync::Arc};
+
+use crate::convert::{doc_to_prosemirror, for_each_prosemirror_node};
+
+/// Renders a node from its ProseMirror JSON and the HTML of its content.
+pub type NodeRenderer = Arc<dyn Fn(&Value, &str) -> String + Send + Sync>;
+
+/// Renders a mark from its ProseMirror JSON and the HTML it applies to.
+pub type MarkRenderer = Arc<dyn Fn(&Value, &str) -> String + Send + Sync>;
+
+/// Renderers of nodes and marks, by name.
+#[derive(Clone)]
+pub struct HtmlRenderer {
+    nodes: HashMap<String, NodeRenderer>,
+    marks: HashMap<String, MarkRenderer>,
+}
+
+impl Default for HtmlRenderer {
+    fn default() -> Self {
+        let mut renderer = Self {
+            nodes: HashMap::new(),
+            marks: HashMap::new(),
+        };
+        for (name, tag) in [
+            ("paragraph", "p"),
+            ("blockquote", "blockquote"),
+            ("bulletList", "ul"),
+            ("bullet_list", "ul"),
+            ("listItem", "li"),
+            ("list_item", "li"),
+        ] {
+            renderer = renderer.with_node(name, move |_, content| wrap(tag, "", content));
+        }
+        for name in ["orderedList", "ordered_list"] {
+            renderer = renderer.with_node(name, |node, content| {
+                let start = attr(node, "start")
+                    .filter(|start| start != "1")
+                    .map(|start| format!(" start=\"{}\"", escape(&start)))
+                    .unwrap_or_default();
+                wrap("ol", &start, content)
+            });
+        }
+        for name in ["codeBlock", "code_block"] {
+            renderer = renderer.with_node(name, |node, content| {
+                let class = attr(node, "language")
+                    .map(|language| format!(" class=\"language-{}\"", escape(&language)))
+                    .unwrap_or_default();
+                format!("<pre><code{}>{}</code></pre>", class, content)
+            });
+        }
+        for name in ["horizontalRule", "horizontal_rule"] {
+            renderer = renderer.with_node(name, |_, _| "<hr>".to_string());
+        }
+        for name in ["hardBreak", "hard_break"] {
+            renderer = renderer.with_node(name, |_, _| "<br>".to_string());
+        }
+        renderer = renderer
+            .with_node("heading", |node, content| {
+                let level = attr(node, "level")
+                    .and_then(|level| level.parse::<u8>().ok())
+                    .unwrap_or(1)
+                    .clamp(1, 6);
+                wrap(&format!("h{}", level), "", content)
+            })
+            .with_node("taskList", |_, content| {
+                wrap("ul", " data-type=\"taskList\"", content)
+            })
+            .with_node("taskItem", |node, content| {
+                let checked = attr(node, "checked").is_some_and(|checked| checked == "true");
+                wrap(
+                    "li",
+                    &format!(" data-checked=\"{}\"", checked),
+                    &format!(
+                        "<input type=\"checkbox\" disabled{}>{}",
+                        if checked { " checked" } else { "" },
+                        content
+                    ),
+                )
+            })
+            .with_node("image", |node, _| {
+                let mut attributes = String::new();
+                for name in ["src", "alt", "title"] {
+                    if let Some(value) = attr(node, name) {
+                        if name == "src" && !is_safe_url(&value) {
+                            continue;
+                        }
+                        attributes.push_str(&format!(" {}=\"{}\"", name, escape(&value)));
+                    }
+                }
+                format!("<img{}>", attributes)
+            });
+
+        for (name, tag) in [
+            ("bold", "strong"),
+            ("strong", "strong"),
+            ("italic", "em"),
+            ("em", "em"),
+            ("code", "code"),
+            ("strike", "s"),
+            ("underline", "u"),
+            ("subscript", "sub"),
+            ("superscript", "sup"),
+        ] {
+            renderer = renderer.with_mark(name, move |_, content| wrap(tag, "", content));
+        }
+        renderer.with_mark("link", |mark, content| {
+            let href = attr(mark, "href")
+                .filter(|href| is_safe_url(href))
+                .map(|href| format!(" href=\"{}\"", escape(&href)))
+                .unwrap_or_default();
+            wrap("a", &href, content)
+        })
+    }
+}
+
+impl HtmlRenderer {
+    /// Renders the nodes named `name` with `renderer`, given the node and the HTML of its
+    /// content, which is already escaped.
+    pub fn with_node(
+        mut self,
+        name: &str,
+        renderer: impl Fn(&Value, &str) -> String + Send + Sync + 'static,
+    ) -> Self {
+        self.nodes.insert(name.to_string(), Arc::new(renderer));
+        self
+    }
+
+    /// Renders the marks named `name` with `renderer`, given the mark and the HTML it
+    /// applies to, which is already escaped.
+    pub fn with_mark(
+        mut self,
+        name: &str,
+        renderer: impl Fn(&Value, &str) -> String + Send + Sync + 'static,
+    ) -> Self {
+        self.marks.insert(name.to_string(), Arc::new(renderer));
+        self
+    }
+
+    /// Render a ProseMirror JSON node, and its content, as HTML.
+    pub fn render(&self, node: &Value) -> String {
+        if node.get("type").and_then(Value::as_str) == Some("text") {
+            let mut html = escape(node.get("text").and_then(Value::as_str).unwrap_or(""));
+            // The first mark is the outermost
+            let marks = node.get("marks").and_then(Value::as_array);
+            for mark in marks.into_iter().flatten().rev() {
+                let renderer = mark
+                    .get("type")
+                    .and_then(Value::as_str)
+                    .and_then(|name| self.marks.get(name));
+                if let Some(renderer) = renderer {
+                    html = renderer(mark, &html);
+                }
+            }
+            return html;
+        }
+
+        let content: String = node
+            .get("content")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .map(|child| self.render(child))
+            .collect();
+        let renderer = node
+            .get("type")
+            .and_then(Value::as_str)
+            .and_then(|name| self.nodes.get(name));
+        match renderer {
+            Some(renderer) => renderer(node, &content),
+            None => content,
+        }
+    }
+}
+
+/// Render the XmlFragment `root` of a Yjs document (encoded as a v1 update) as HTML.
+pub fn doc_to_html(doc_as_update: &[u8], root: &str, renderer: &HtmlRenderer) -> Result<String> {
+    let mut html = Vec::new();
+    write_html(doc_as_update, root, renderer, &mut html)?;
+    Ok(String::from_utf8(html)?)
+}
+
+/// Like [doc_to_html], writing the HTML to `writer` one top-level node at a time rather
+/// than rendering the whole document in memory first. A renderer registered for the `doc`
+/// node needs the HTML of the whole document, so the document is then rendered at once.
+pub fn write_html<W: Write>(
+    doc_as_update: &[u8],
+    root: &str,
+    renderer: &HtmlRenderer,
+    mut writer: W,
+) -> Result<()> {
+    if renderer.nodes.contains_key("doc") {
+        let html = renderer.render(&doc_to_prosemirror(doc_as_update, root)?);
+        writer.write_all(html.as_bytes())?;
+        return Ok(());
+    }
+    for_each_prosemirror_node(doc_as_update, root, |node| {
+        writer.write_all(renderer.render(&node).as_bytes())?;
+        Ok(())
+    })
+}
+
+/// Escape text for use in HTML content and quoted attribute values.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The attribute `name` of a node or mark, as a string.
+pub fn attr(node: &Value, name: &str) -> Option<String> {
+    match node.get("attrs")?.get(name)? {
+        Value::Null => None,
+        Value::String(value) => Some(value.clone()),
+        value => Some(value.to_string()),
+    }
+}
+
+fn wrap(tag: &str, attributes: &str, content: &str) -> String {
+    format!("<{}{}>{}</{}>", tag, attributes, content, tag)
+}
+
+/// Whether `url` can be linked to from a published page: an `http`, `https` or `mailto`
+/// URL, or a relative one. The scheme is read as browsers do, without tabs and newlines
+/// anywhere and without control characters and spaces at either end.
+fn is_safe_url(url: &str) -> bool {
+    let url: String = url
+        .chars()
+        .filter(|c| !matches!(c, '\t' | '\n' | '\r'))
+        .collect();
+    let url = url.trim_matches(|c: char| c <= ' ');
+    match url.find([':', '/', '?', '#']) {
+        Some(end) if url[end..].starts_with(':') => matches!(
+            url[..end].to_ascii_lowercase().as_str(),
+            "http" | "https" | "mailto"
+        ),
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::convert::prosemirror_to_update;
+    use serde_json::json;
+
+    #[test]
+    fn test_only_web_and_mail_urls_are_safe() {
+        for url in [
+            "https://example.com",
+            "HTTP://example.com",
+            "mailto:a@example.com",
+            "/docs/a?b=c:d",
+            "page#top",
+            "//example.com/a",
+            "",
+        ] {
+            assert!(is_safe_url(url), "{:?} should be safe", url);
+        }
+        for url in [
+            "javascript:alert(1)",
+            " JavaScript:alert(1)",
+            "java\tscript:alert(1)",
+            "java\nscript:alert(1)",
+            "javascript\r:alert(1)",
+            "\u{1}javascript:alert(1)",
+            "\u{0}\u{1f} javascript:alert(1)",
+            "vbscript:msgbox(1)",
+            "data:text/html,<script>alert(1)</script>",
+            "file:///etc/passwd",
+            ":alert(1)",
+        ] {
+            assert!(!is_safe_url(url), "{:?} should not be safe", url);
+        }
+    }
+
+    #[test]
+    fn test_renders_html_with_custom_nodes() {
+        let update = prosemirror_to_update(
+            &json!({
+                "type": "doc",
+                "content": [
+                    {
+                        "type": "heading",
+                        "attrs": { "level": 2 },
+                        "content": [{ "type": "text", "text": "Q&A" }],
+                    },
+                    {
+                        "type": "paragraph",
+                        "content": [
+                            {
+                                "type": "text",
+                                "text": "<click>",
+                                "marks": [
+                                    { "type": "link", "attrs": { "href": "javascript:alert(1)" } },
+                                ],
+                            },
+                            { "type": "mention", "attrs": { "id": "ada" } },
+                        ],
+                    },
+                ],
+            }),
+            "default",
+        )
+        .unwrap();
+
+        let renderer = HtmlRenderer::default();
+        assert_eq!(
+            doc_to_html(&update, "default", &renderer).unwrap(),
+            "<h2>Q&amp;A</h2><p><a>&lt;click&gt;</a></p>"
+        );
+
+        let renderer = renderer.with_node("mention", |node, _| {
+            format!(
+                "<span class=\"mention\">@{}</span>",
+                escape(&attr(node, "id").unwrap())
+            )
+        });
+        assert_eq!(
+            doc_to_html(&update, "default", &renderer).unwrap(),
+            "<h2>Q&amp;A</h2><p><a>&lt;click&gt;</a><span class=\"mention\">@ada</span></p>"
+        );
+    }
+}
@@ -0,0 +1,231 @@
+//! Inspection of the stored state of a document.
+//!
+//! Inspecting a document reports what makes up its size: the stored snapshot, shards and
+//! update log, the clients that edited it with their clocks, and each root type with its
+//! kind and content size. The document is loaded the way the server would, without
+//! writing anything back.
+//!
+//! A document can also be inspected from a local file: a `data.ysweet` snapshot of a
+//! filesystem store, or a Yjs v1 update such as the ones `export-all` writes.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::{cmp::Reverse, path::Path, sync::Arc};
+use y_sweet_core::{
+    doc_sync_ext::DocWithSyncKv,
+    shard_ext::decode_manifest,
+    snapshot_ext::snapshot_key,
+    store::Store,
+    update_log_ext::{list_segments, segment_key, UpdateLogConfig},
+};
+use yrs::{updates::decoder::Decode, updates::encoder::Encode, Update};
+
+use crate::convert::{read_root, root_names};
+use crate::stores::filesystem::FileSystemStore;
+
+#[derive(Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct DocInspection {
+    pub doc_id: String,
+    /// Size of the stored snapshot, if the document has one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snapshot_bytes: Option<usize>,
+    /// Number of shards of the snapshot, if it is sharded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shards: Option<usize>,
+    /// Total size of the shards of the snapshot.
+    pub shard_bytes: usize,
+    pub update_log_segments: usize,
+    pub update_log_bytes: usize,
+    /// Size of the document encoded as a Yjs (v1) update.
+    pub update_bytes: usize,
+    /// Base64 encoded Yjs (v1) state vector.
+    pub state_vector: String,
+    /// Clients that edited the document, by decreasing clock.
+    pub clients: Vec<ClientClock>,
+    /// Root types of the document, by decreasing content size.
+    pub root_types: Vec<RootTypeInspection>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientClock {
+    pub client: u64,
+    /// Number of elements the client inserted, deleted ones included.
+    pub clock: u32,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RootTypeInspection {
+    pub name: String,
+    /// `map`, `text` or `array`, guessed from the content (see `convert::doc_to_json`).
+    pub kind: &'static str,
+    /// Number of entries, characters or items.
+    pub len: usize,
+    /// Size of the content as JSON, deleted content excluded.
+    pub json_bytes: usize,
+}
+
+/// Inspect `doc_id`, from its stored objects.
+pub async fn inspect_doc(store: &Arc<Box<dyn Store>>, doc_id: &str) -> Result<DocInspection> {
+    let mut inspection = DocInspection {
+        doc_id: doc_id.to_string(),
+        ..DocInspection::default()
+    };
+
+    let snapshot = store
+        .get(&snapshot_key(doc_id))
+        .await
+        .context("Failed to read the snapshot")?;
+    if let Some(snapshot) = &snapshot {
+        inspection.snapshot_bytes = Some(snapshot.len());
+        if let Some(manifest) = decode_manifest(snapshot) {
+            let manifest = manifest?;
+            inspection.shards = Some(manifest.shards.len());
+            inspection.shard_bytes = manifest.shards.iter().map(|shard| shard.bytes).sum();
+        }
+    }
+
+    let segments = list_segments(store.as_ref().as_ref(), doc_id)
+        .await
+        .context("Failed to list the update log")?;
+    inspection.update_log_segments = segments.len();
+    for seq in segments {
+        if let Some(segment) = store.get(&segment_key(doc_id, seq)).await? {
+            inspection.update_log_bytes += segment.len();
+        }
+    }
+    if snapshot.is_none() && inspection.update_log_segments == 0 {
+        anyhow::bail!("Document {} does not exist", doc_id);
+    }
+
+    // Documents without an update log load as if it was disabled
+    let doc = DocWithSyncKv::new_with_update_log(
+        doc_id,
+        Some(store.clone()),
+        || {},
+        false,
+        Some(UpdateLogConfig::default()),
+    )
+    .await
+    .context("The document cannot be loaded")?;
+    inspect_update(&mut inspection, &doc.as_update())?;
+    Ok(inspection)
+}
+
+/// Inspect the document in the local file at `path`.
+pub async fn inspect_file(path: &Path) -> Result<DocInspection> {
+    // The snapshot of a filesystem store is at {store}/{doc_id}/data.ysweet
+    if path.file_name().is_some_and(|name| name == "data.ysweet") {
+        let doc_dir = path
+            .parent()
+            .context("The snapshot has no parent directory")?;
+        let doc_id = doc_dir
+            .file_name()
+            .and_then(|name| name.to_str())
+            .context("The snapshot is not in a document directory")?;
+        let store_dir = doc_dir.parent().unwrap_or(Path::new("."));
+        let store: Arc<Box<dyn Store>> =
+            Arc::new(Box::new(FileSystemStore::new(store_dir.to_path_buf())?));
+        return inspect_doc(&store, doc_id).await;
+    }
+
+    let update = std::fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+    let mut inspection = DocInspection {
+        doc_id: path
+            .file_stem()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        ..DocInspection::default()
+    };
+    inspect_update(&mut inspection, &update)?;
+    Ok(inspection)
+}
+
+fn inspect_update(inspection: &mut DocInspection, doc_as_update: &[u8]) -> Result<()> {
+    let update = Update::decode_v1(doc_as_update)
+        .map_err(|_| anyhow::anyhow!("The file is not a Yjs (v1) update"))?;
+    let state_vector = update.state_vector();
+    inspection.update_bytes = doc_as_update.len();
+    inspection.state_vector = data_encoding::BASE64.encode(&state_vector.encode_v1());
+
+    let mut clients: Vec<ClientClock> = state_vector
+        .iter()
+        .map(|(client, clock)| ClientClock {
+            client: *client,
+            clock: *clock,
+        })
+        .collect();
+    clients.sort_by_key(|client| Reverse(client.clock));
+    inspection.clients = clients;
+
+    let mut root_types = Vec::new();
+    for name in root_names(doc_as_update)? {
+        let (kind, value) = read_root(doc_as_update, &name)?;
+        let len = match &value {
+            serde_json::Value::Object(map) => map.len(),
+            serde_json::Value::String(string) => string.chars().count(),
+            serde_json::Value::Array(array) => array.len(),
+            _ => 0,
+        };
+        root_types.push(RootTypeInspection {
+            name,
+            kind,
+            len,
+            json_bytes: serde_json::to_vec(&value)?.len(),
+        });
+    }
+    root_types.sort_by_key(|root_type| Reverse(root_type.json_bytes));
+    inspection.root_types = root_types;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use yrs::{Map, Text, Transact};
+
+    #[tokio::test]
+    async fn test_inspects_stored_and_local_documents() {
+        let dir = std::env::temp_dir().join(format!("y-sweet-inspect-{}", nanoid::nanoid!()));
+        let store: Arc<Box<dyn Store>> =
+            Arc::new(Box::new(FileSystemStore::new(dir.clone()).unwrap()));
+        let doc = DocWithSyncKv::new("doc", Some(store.clone()), || {}, false)
+            .await
+            .unwrap();
+        {
+            let awareness = doc.awareness();
+            let awareness = awareness.write().unwrap();
+            let text = awareness.doc().get_or_insert_text("text");
+            let map = awareness.doc().get_or_insert_map("settings");
+            let mut txn = awareness.doc().transact_mut();
+            text.insert(&mut txn, 0, "hello world");
+            map.insert(&mut txn, "theme", "dark");
+        }
+        doc.sync_kv().persist().await.unwrap();
+
+        let inspection = inspect_doc(&store, "doc").await.unwrap();
+        assert!(inspection.snapshot_bytes.is_some());
+        assert_eq!(inspection.shards, None);
+        assert_eq!(inspection.clients.len(), 1);
+        assert_eq!(inspection.clients[0].clock, 12);
+        assert_eq!(inspection.root_types.len(), 2);
+        assert_eq!(inspection.root_types[0].name, "settings");
+        assert_eq!(inspection.root_types[0].kind, "map");
+        assert_eq!(inspection.root_types[1].kind, "text");
+        assert_eq!(inspection.root_types[1].len, 11);
+
+        let local = inspect_file(&dir.join("doc").join("data.ysweet"))
+            .await
+            .unwrap();
+        assert_eq!(local.doc_id, "doc");
+        assert_eq!(local.state_vector, inspection.state_vector);
+
+        let update_path = dir.join("doc.yupdate");
+        std::fs::write(&update_path, doc.as_update()).unwrap();
+        let update = inspect_file(&update_path).await.unwrap();
+        assert_eq!(update.snapshot_bytes, None);
+        assert_eq!(update.update_bytes, inspection.update_bytes);
+    }
+}
@@ -0,0 +1,420 @@
+//! Labels of documents (`GET`/`PUT /d/:doc_id/labels`), e.g. `project: apollo` or
+//! `customer: acme`, to group documents without keeping a mapping elsewhere.
+//!
+//! Labels are stored as JSON next to the document data (`{doc_id}/labels.json`), so they
+//! are copied and deleted together with the document. `GET /docs` lists documents with
+//! their labels, filtered by ID prefix and by a label selector:
+//! `label=project=apollo,customer` matches the documents whose `project` is `apollo` and
+//! which have any `customer`.
+
+use anyhow::{anyhow, bail, Result};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use axum_extra::typed_header::TypedHeader;
+use serde::Deserialize;
+use serde_json::json;
+use std::{collections::BTreeMap, sync::Arc};
+use y_sweet_core::{
+    api_types::validate_doc_name,
+    api_types_ext::{DocLabelsRequest, DocLabelsResponse, DocListEntry, DocListResponse},
+    store::Store,
+};
+
+use crate::audit_ext::{record_audit, AuditPrincipal};
+use crate::server::{AppError, Server};
+use crate::server_ext::ext_check_not_archived;
+
+pub const LABELS_FILE: &str = "labels.json";
+
+/// Most labels a document may have.
+pub const MAX_LABELS: usize = 64;
+const MAX_LABEL_KEY_LEN: usize = 63;
+const MAX_LABEL_VALUE_LEN: usize = 256;
+
+/// Number of documents listed by `GET /docs` when no `limit` is given.
+pub const DEFAULT_DOC_LIST_LIMIT: usize = 100;
+/// Largest `limit` of `GET /docs`.
+pub const MAX_DOC_LIST_LIMIT: usize = 1000;
+
+pub(crate) fn labels_key(doc_id: &str) -> String {
+    format!("{}/{}", doc_id, LABELS_FILE)
+}
+
+/// Check that `labels` can be stored: at most [MAX_LABELS], with keys of letters, digits,
+/// `.`, `_`, `-` and `/`, and values without control characters.
+pub fn validate_labels(labels: &BTreeMap<String, String>) -> Result<()> {
+    if labels.len() > MAX_LABELS {
+        bail!("A document may have at most {} labels", MAX_LABELS);
+    }
+    for (key, value) in labels {
+        let valid_key = !key.is_empty()
+            && key.len() <= MAX_LABEL_KEY_LEN
+            && key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | '/'));
+        if !valid_key {
+            bail!(
+                "Invalid label key {:?}: use up to {} letters, digits, '.', '_', '-' or '/'",
+                key,
+                MAX_LABEL_KEY_LEN
+            );
+        }
+        if value.len() > MAX_LABEL_VALUE_LEN || value.chars().any(char::is_control) {
+            bail!(
+                "Invalid value of label {}: use up to {} bytes without control characters",
+                key,
+                MAX_LABEL_VALUE_LEN
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Read the labels of a document. Documents without stored labels have none.
+pub async fn read_doc_labels(
+    store: &Arc<Box<dyn Store>>,
+    doc_id: &str,
+) -> Result<BTreeMap<String, String>, AppError> {
+    let value = store.get(&labels_key(doc_id)).await.map_err(|e| {
+        AppError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            anyhow!("Failed to read document labels: {}", e),
+        )
+    })?;
+
+    let Some(value) = value else {
+        return Ok(BTreeMap::new());
+    };
+
+    serde_json::from_slice(&value).map_err(|e| {
+        AppError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            anyhow!("Malformed labels for document {}: {}", doc_id, e),
+        )
+    })
+}
+
+/// Replace the labels of a document. Removing every label removes the stored object.
+pub async fn write_doc_labels(
+    store: &Arc<Box<dyn Store>>,
+    doc_id: &str,
+    labels: &BTreeMap<String, String>,
+) -> Result<(), AppError> {
+    if labels.is_empty() {
+        return remove_doc_labels(store, doc_id).await.map(|_| ());
+    }
+
+    let value = serde_json::to_vec(labels)
+        .map_err(|e| AppError(StatusCode::INTERNAL_SERVER_ERROR, e.into()))?;
+
+    store.set(&labels_key(doc_id), value).await.map_err(|e| {
+        AppError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            anyhow!("Failed to write document labels: {}", e),
+        )
+    })
+}
+
+/// Remove the labels of a document. Returns whether there were any.
+pub async fn remove_doc_labels(
+    store: &Arc<Box<dyn Store>>,
+    doc_id: &str,
+) -> Result<bool, AppError> {
+    let key = labels_key(doc_id);
+    let result = match store.exists(&key).await {
+        Ok(true) => store.remove(&key).await.map(|_| true),
+        Ok(false) => Ok(false),
+        Err(e) => Err(e),
+    };
+
+    result.map_err(|e| {
+        AppError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            anyhow!("Failed to remove document labels: {}", e),
+        )
+    })
+}
+
+/// Labels a document must have: each key, with the given value if any.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct LabelSelector(Vec<(String, Option<String>)>);
+
+impl LabelSelector {
+    /// Parse a selector such as `project=apollo,customer`.
+    pub fn parse(selector: &str) -> Result<Self> {
+        let mut requirements = Vec::new();
+        for requirement in selector.split(',').map(str::trim) {
+            if requirement.is_empty() {
+                continue;
+            }
+            let (key, value) = match requirement.split_once('=') {
+                Some((key, value)) => (key.trim(), Some(value.trim().to_string())),
+                None => (requirement, None),
+            };
+            if key.is_empty() {
+                bail!("Invalid label selector {:?}", requirement);
+            }
+            requirements.push((key.to_string(), value));
+        }
+        Ok(Self(requirements))
+    }
+
+    pub fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        self.0
+            .iter()
+            .all(|(key, value)| match (labels.get(key), value) {
+                (Some(actual), Some(value)) => actual == value,
+                (Some(_), None) => true,
+                (None, _) => false,
+            })
+    }
+}
+
+/// The store keeping the labels of `doc_id`, once the ID is checked.
+fn labels_store<'a>(
+    server_state: &'a Server,
+    doc_id: &str,
+) -> Result<&'a Arc<Box<dyn Store>>, AppError> {
+    if !validate_doc_name(doc_id) {
+        return Err(AppError(
+            StatusCode::BAD_REQUEST,
+            anyhow!("Invalid document ID"),
+        ));
+    }
+    server_state.store.as_ref().ok_or_else(|| {
+        AppError(
+            StatusCode::BAD_REQUEST,
+            anyhow!("Document labels require a store"),
+        )
+    })
+}
+
+/// Get the labels of a document
+pub async fn get_document_labels(
+    Path(doc_id): Path<String>,
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+) -> Result<Json<DocLabelsResponse>, AppError> {
+    server_state.check_auth(auth_header)?;
+    let store = labels_store(&server_state, &doc_id)?;
+
+    ext_check_not_archived(&server_state, &doc_id).await?;
+
+    if !server_state.doc_exists(&doc_id).await {
+        return Err(AppError(
+            StatusCode::NOT_FOUND,
+            anyhow!("Document not found"),
+        ));
+    }
+
+    let labels = read_doc_labels(store, &doc_id).await?;
+    Ok(Json(DocLabelsResponse { doc_id, labels }))
+}
+
+/// Replace the labels of a document
+pub async fn set_document_labels(
+    Path(doc_id): Path<String>,
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    Json(body): Json<DocLabelsRequest>,
+) -> Result<Json<DocLabelsResponse>, AppError> {
+    let principal = AuditPrincipal::server(&auth_header);
+    server_state.check_auth(auth_header)?;
+    let store = labels_store(&server_state, &doc_id)?;
+
+    ext_check_not_archived(&server_state, &doc_id).await?;
+
+    if !server_state.doc_exists(&doc_id).await {
+        return Err(AppError(
+            StatusCode::NOT_FOUND,
+            anyhow!("Document not found"),
+        ));
+    }
+
+    validate_labels(&body.labels).map_err(|e| AppError(StatusCode::BAD_REQUEST, e))?;
+    write_doc_labels(store, &doc_id, &body.labels).await?;
+    record_audit(
+        &server_state,
+        &doc_id,
+        "labels_set",
+        principal,
+        Some(json!({ "labels": body.labels })),
+    )
+    .await;
+
+    Ok(Json(DocLabelsResponse {
+        doc_id,
+        labels: body.labels,
+    }))
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct DocListQuery {
+    prefix: Option<String>,
+    label: Option<String>,
+    limit: Option<usize>,
+    cursor: Option<String>,
+}
+
+/// List documents with their labels, sorted by ID
+pub async fn list_documents(
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    Query(query): Query<DocListQuery>,
+) -> Result<Json<DocListResponse>, AppError> {
+    server_state.check_auth(auth_header)?;
+
+    let limit = query.limit.unwrap_or(DEFAULT_DOC_LIST_LIMIT);
+    if !(1..=MAX_DOC_LIST_LIMIT).contains(&limit) {
+        return Err(AppError(
+            StatusCode::BAD_REQUEST,
+            anyhow!("limit must be between 1 and {}", MAX_DOC_LIST_LIMIT),
+        ));
+    }
+    let selector = LabelSelector::parse(query.label.as_deref().unwrap_or_default())
+        .map_err(|e| AppError(StatusCode::BAD_REQUEST, e))?;
+    let prefix = query.prefix.as_deref().unwrap_or_default();
+
+    let mut doc_ids = match &server_state.store {
+        Some(store) => store.list_documents(prefix).await.map_err(|e| {
+            AppError(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                anyhow!("Failed to list documents: {}", e),
+            )
+        })?,
+        None => Vec::new(),
+    };
+    // Documents that were never persisted only exist in memory
+    doc_ids.extend(
+        server_state
+            .docs
+            .iter()
+            .filter(|entry| entry.key().starts_with(prefix))
+            .map(|entry| entry.key().clone()),
+    );
+    doc_ids.sort();
+    doc_ids.dedup();
+    if let Some(cursor) = &query.cursor {
+        doc_ids.retain(|doc_id| doc_id > cursor);
+    }
+
+    let mut docs = Vec::new();
+    let mut remaining = doc_ids.into_iter();
+    for doc_id in remaining.by_ref() {
+        let labels = match &server_state.store {
+            Some(store) => read_doc_labels(store, &doc_id).await?,
+            None => BTreeMap::new(),
+        };
+        if !selector.matches(&labels) {
+            continue;
+        }
+        docs.push(DocListEntry { doc_id, labels });
+        if docs.len() == limit {
+            break;
+        }
+    }
+    let next_cursor = match remaining.next() {
+        Some(_) => docs.last().map(|entry| entry.doc_id.clone()),
+        None => None,
+    };
+
+    Ok(Json(DocListResponse { docs, next_cursor }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stores::memory::MemoryStore;
+    use axum::{body::Body, http::Request};
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    #[test]
+    fn test_label_selector() {
+        let labels = BTreeMap::from([
+            ("project".to_string(), "apollo".to_string()),
+            ("customer".to_string(), "acme".to_string()),
+        ]);
+        assert!(LabelSelector::parse("").unwrap().matches(&labels));
+        assert!(LabelSelector::parse("project=apollo, customer")
+            .unwrap()
+            .matches(&labels));
+        assert!(!LabelSelector::parse("project=gemini")
+            .unwrap()
+            .matches(&labels));
+        assert!(!LabelSelector::parse("team").unwrap().matches(&labels));
+        assert!(LabelSelector::parse("=apollo").is_err());
+
+        assert!(validate_labels(&labels).is_ok());
+        let invalid = BTreeMap::from([("project name".to_string(), String::new())]);
+        assert!(validate_labels(&invalid).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_documents_are_listed_by_label() {
+        let server = Arc::new(
+            Server::builder()
+                .store(Some(Box::new(MemoryStore::new())))
+                .build()
+                .await
+                .unwrap(),
+        );
+        for doc_id in ["apollo-1", "apollo-2", "gemini-1"] {
+            server.get_or_create_doc(doc_id).await.unwrap();
+        }
+        let routes = server.routes();
+
+        for (doc_id, project) in [("apollo-1", "apollo"), ("gemini-1", "gemini")] {
+            let request = Request::builder()
+                .method("PUT")
+                .uri(format!("/d/{}/labels", doc_id))
+                .header("Content-Type", "application/json")
+                .body(Body::from(
+                    json!({ "labels": { "project": project } }).to_string(),
+                ))
+                .unwrap();
+            let response = routes.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let list = |uri: &str| {
+            let routes = routes.clone();
+            let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            async move {
+                let response = routes.oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                serde_json::from_slice::<DocListResponse>(&body).unwrap()
+            }
+        };
+        let doc_ids = |response: &DocListResponse| {
+            response
+                .docs
+                .iter()
+                .map(|entry| entry.doc_id.clone())
+                .collect::<Vec<_>>()
+        };
+
+        let all = list("/docs").await;
+        assert_eq!(doc_ids(&all), ["apollo-1", "apollo-2", "gemini-1"]);
+        assert_eq!(all.docs[0].labels["project"], "apollo");
+        assert!(all.docs[1].labels.is_empty());
+
+        let labelled = list("/docs?label=project").await;
+        assert_eq!(doc_ids(&labelled), ["apollo-1", "gemini-1"]);
+        let apollo = list("/docs?label=project%3Dapollo").await;
+        assert_eq!(doc_ids(&apollo), ["apollo-1"]);
+        let prefixed = list("/docs?prefix=apollo").await;
+        assert_eq!(doc_ids(&prefixed), ["apollo-1", "apollo-2"]);
+
+        let first_page = list("/docs?limit=2").await;
+        assert_eq!(doc_ids(&first_page), ["apollo-1", "apollo-2"]);
+        let cursor = first_page.next_cursor.unwrap();
+        let last_page = list(&format!("/docs?limit=2&cursor={}", cursor)).await;
+        assert_eq!(doc_ids(&last_page), ["gemini-1"]);
+        assert_eq!(last_page.next_cursor, None);
+    }
+}
@@ -0,0 +1,325 @@
+//! Ownership leases of documents in the store.
+//!
+//! When several server instances share a store, each document should be persisted by at
+//! most one of them, or their snapshots overwrite each other. A node holding the lease of a
+//! document records it as `.lease/{doc_id}` with an expiration time and renews it while the
+//! document is loaded. Other nodes may still serve the document but do not persist it until
+//! the lease expires, at which point the first of them to renew takes it over.
+//!
+//! A node that did not hold the lease for a while may be missing changes its holder
+//! persisted in the meantime, so it applies the stored snapshot to its copy of the document
+//! before persisting again.
+//!
+//! Stores have no conditional writes, so a lease is re-read after it is written and only
+//! counts as held if the write survived. This narrows, but does not close, the window in
+//! which two nodes acquiring an expired lease at the same time both believe they hold it.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+use y_sweet_core::{store::Store, sync::awareness::Awareness, sync_kv::SyncKv};
+
+use crate::follower_ext::refresh;
+use crate::server::current_time_epoch_millis;
+
+/// Transaction origin of updates read from the snapshot persisted by the previous holder.
+pub const LEASE_ORIGIN: &str = "y-sweet-lease";
+
+/// Storage prefix of document leases. The leading dot keeps it outside the namespace of
+/// valid document IDs.
+pub const LEASE_PREFIX: &str = ".lease";
+
+/// Default time a lease stays valid without being renewed.
+pub const DEFAULT_LEASE_TTL: Duration = Duration::from_secs(30);
+
+fn lease_key(doc_id: &str) -> String {
+    format!("{}/{}", LEASE_PREFIX, doc_id)
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct LeaseRecord {
+    holder: String,
+    /// Epoch millis after which another node may take the lease over.
+    expires_at: u64,
+}
+
+/// Issues the leases of the documents loaded on this node.
+pub struct DocLeases {
+    store: Arc<Box<dyn Store>>,
+    node_id: String,
+    ttl: Duration,
+}
+
+impl DocLeases {
+    pub fn new(store: Arc<Box<dyn Store>>, ttl: Duration) -> Self {
+        Self {
+            store,
+            node_id: nanoid::nanoid!(),
+            ttl,
+        }
+    }
+
+    /// Random ID of this node, recorded as the holder of its leases.
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    /// The lease of a document, not yet acquired.
+    pub fn lease(self: &Arc<Self>, doc_id: &str) -> Arc<DocLease> {
+        Arc::new(DocLease {
+            leases: self.clone(),
+            doc_id: doc_id.to_string(),
+            held_until: AtomicU64::new(0),
+            behind: AtomicBool::new(false),
+        })
+    }
+}
+
+/// This node's lease of one document.
+pub struct DocLease {
+    leases: Arc<DocLeases>,
+    doc_id: String,
+    /// Epoch millis until which this node holds the lease, or 0 if it does not.
+    held_until: AtomicU64,
+    /// Whether another node may have persisted the document since this node last did:
+    /// the lease was found held elsewhere, or lapsed.
+    behind: AtomicBool,
+}
+
+impl DocLease {
+    /// Whether this node holds the lease. A lease that could not be renewed in time is
+    /// no longer held, even before another node takes it over.
+    pub fn is_held(&self) -> bool {
+        current_time_epoch_millis() < self.held_until.load(Ordering::SeqCst)
+    }
+
+    /// Acquire or renew the lease, unless another node holds it. Returns whether this node
+    /// holds the lease afterwards.
+    pub async fn try_acquire(&self) -> Result<bool> {
+        let store = &self.leases.store;
+        let key = lease_key(&self.doc_id);
+        let now = current_time_epoch_millis();
+
+        let held_until = self.held_until.load(Ordering::SeqCst);
+        if held_until != 0 && held_until <= now {
+            self.behind.store(true, Ordering::SeqCst);
+        }
+
+        if let Some(record) = self.read().await? {
+            if record.holder != self.leases.node_id && record.expires_at > now {
+                self.held_until.store(0, Ordering::SeqCst);
+                self.behind.store(true, Ordering::SeqCst);
+                return Ok(false);
+            }
+        }
+
+        let expires_at = now + self.leases.ttl.as_millis() as u64;
+        let record = LeaseRecord {
+            holder: self.leases.node_id.clone(),
+            expires_at,
+        };
+        store
+            .set(&key, serde_json::to_vec(&record)?)
+            .await
+            .context("Failed to write lease")?;
+
+        // Another node may have written the lease at the same time
+        let held = self.read().await? == Some(record);
+        self.held_until
+            .store(if held { expires_at } else { 0 }, Ordering::SeqCst);
+        if !held {
+            self.behind.store(true, Ordering::SeqCst);
+        }
+        Ok(held)
+    }
+
+    /// Apply the stored snapshot to the loaded document if another node may have persisted
+    /// changes it is missing, so that persisting it does not overwrite them.
+    pub async fn catch_up(&self, awareness: &Arc<RwLock<Awareness>>, skip_gc: bool) -> Result<()> {
+        if !self.behind.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let result = refresh(
+            &self.leases.store,
+            &self.doc_id,
+            awareness,
+            skip_gc,
+            LEASE_ORIGIN,
+        )
+        .await;
+        if result.is_err() {
+            self.behind.store(true, Ordering::SeqCst);
+        } else {
+            info!(
+                message = "Applied the snapshot persisted by the previous lease holder",
+                event = "lease_caught_up",
+                doc_id = %self.doc_id
+            );
+        }
+        result
+    }
+
+    /// Give the lease up so another node can take it over without waiting for it to expire.
+    pub async fn release(&self) -> Result<()> {
+        self.held_until.store(0, Ordering::SeqCst);
+        if let Some(record) = self.read().await? {
+            if record.holder == self.leases.node_id {
+                self.leases
+                    .store
+                    .remove(&lease_key(&self.doc_id))
+                    .await
+                    .context("Failed to remove lease")?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn read(&self) -> Result<Option<LeaseRecord>> {
+        let value = self
+            .leases
+            .store
+            .get(&lease_key(&self.doc_id))
+            .await
+            .context("Failed to read lease")?;
+        // An unreadable lease is treated as absent, so it is overwritten
+        Ok(value.and_then(|value| serde_json::from_slice(&value).ok()))
+    }
+
+    /// Renew the lease, or try to take it over, until the document is unloaded or the
+    /// server shuts down. Releasing the lease is left to the persistence worker, after its
+    /// last write.
+    pub async fn heartbeat(
+        self: Arc<Self>,
+        sync_kv: Arc<SyncKv>,
+        cancellation_token: CancellationToken,
+    ) {
+        let mut interval = tokio::time::interval(self.leases.ttl / 3);
+        let mut was_held = self.is_held();
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = cancellation_token.cancelled() => break,
+            }
+            if sync_kv.is_shutdown() {
+                break;
+            }
+
+            match self.try_acquire().await {
+                Ok(held) if held != was_held => {
+                    info!(
+                        message = if held {
+                            "Acquired document lease"
+                        } else {
+                            "Document lease is held by another node"
+                        },
+                        event = if held { "lease_acquired" } else { "lease_lost" },
+                        doc_id = %self.doc_id
+                    );
+                    was_held = held;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!(
+                        message = format!("Failed to renew document lease: {}", e),
+                        event = "lease_renew_failed",
+                        doc_id = %self.doc_id,
+                        error = %e
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stores::filesystem::FileSystemStore;
+    use y_sweet_core::doc_sync_ext::DocWithSyncKv;
+    use yrs::{Doc, GetString, Text, Transact};
+
+    fn leases(store: &Arc<Box<dyn Store>>, ttl: Duration) -> Arc<DocLeases> {
+        Arc::new(DocLeases::new(store.clone(), ttl))
+    }
+
+    fn store() -> Arc<Box<dyn Store>> {
+        let path = std::env::temp_dir().join(format!("y-sweet-lease-{}", nanoid::nanoid!()));
+        Arc::new(Box::new(FileSystemStore::new(path).unwrap()))
+    }
+
+    #[tokio::test]
+    async fn test_lease_is_exclusive_until_released() {
+        let store = store();
+        let a = leases(&store, Duration::from_secs(30)).lease("doc");
+        let b = leases(&store, Duration::from_secs(30)).lease("doc");
+
+        assert!(a.try_acquire().await.unwrap());
+        assert!(a.is_held());
+        assert!(!b.try_acquire().await.unwrap());
+        assert!(!b.is_held());
+
+        // Renewing keeps the lease
+        assert!(a.try_acquire().await.unwrap());
+
+        a.release().await.unwrap();
+        assert!(!a.is_held());
+        assert!(b.try_acquire().await.unwrap());
+        assert!(!a.try_acquire().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_expired_lease_is_taken_over() {
+        let store = store();
+        let a = leases(&store, Duration::from_millis(50)).lease("doc");
+        let b = leases(&store, Duration::from_secs(30)).lease("doc");
+
+        assert!(a.try_acquire().await.unwrap());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!a.is_held());
+
+        assert!(b.try_acquire().await.unwrap());
+        assert!(!a.try_acquire().await.unwrap());
+
+        // Releasing a lease held by another node leaves it in place
+        a.release().await.unwrap();
+        assert!(b.try_acquire().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_takeover_catches_up_with_stored_snapshot() {
+        let store = store();
+        let a = leases(&store, Duration::from_secs(30)).lease("doc");
+        let b = leases(&store, Duration::from_secs(30)).lease("doc");
+        assert!(a.try_acquire().await.unwrap());
+        assert!(!b.try_acquire().await.unwrap());
+
+        // The holder persists changes the other node never saw
+        let doc = DocWithSyncKv::new("doc", Some(store.clone()), || {}, false)
+            .await
+            .unwrap();
+        {
+            let awareness = doc.awareness();
+            let awareness = awareness.write().unwrap();
+            let text = awareness.doc().get_or_insert_text("text");
+            text.insert(&mut awareness.doc().transact_mut(), 0, "hello");
+        }
+        doc.sync_kv().persist().await.unwrap();
+        a.release().await.unwrap();
+
+        let awareness = Arc::new(RwLock::new(Awareness::new(Doc::new())));
+        assert!(b.try_acquire().await.unwrap());
+        b.catch_up(&awareness, false).await.unwrap();
+        let awareness = awareness.read().unwrap();
+        let text = awareness.doc().get_or_insert_text("text");
+        assert_eq!(text.get_string(&awareness.doc().transact()), "hello");
+    }
+}
@@ -0,0 +1,252 @@
+//! Importing documents from the LevelDB persistence of y-websocket servers.
+//!
+//! y-websocket persists documents with y-leveldb, which keeps every document of a server in
+//! one LevelDB database. Each update is stored as its own entry, under the key
+//! `["v1", docName, "update", clock]`; state vectors and metadata are stored under other
+//! keys, and are derived from the updates anyway. Keys are encoded the lib0 way: a tag byte
+//! (0 for a string, 1 for a number) followed by a var string or a big-endian u32.
+//!
+//! Since keys sort by document name first, the updates of a document are contiguous, so
+//! documents are read one at a time: the updates of each are merged into one document and
+//! written to the store as its snapshot, the way `convert-from-update` writes them.
+//!
+//! Opening a database recovers its log like LevelDB itself would, so the y-websocket server
+//! must be stopped (LevelDB locks the database while it is open).
+
+use anyhow::{anyhow, Context, Result};
+use rusty_leveldb::{DBIterator, LdbIterator, Options, DB};
+use std::{path::Path, sync::Arc};
+use y_sweet_core::store::Store;
+use yrs::{updates::decoder::Decode, Doc, ReadTxn, StateVector, Transact, Update};
+
+use crate::doc_create_ext::{import_doc, DocImportOutcome, ImportReport};
+
+const KEY_STRING: u8 = 0;
+const KEY_UINT32: u8 = 1;
+
+/// A y-leveldb database, read one document at a time.
+pub struct LevelDbDocs {
+    // Kept open for the iterator
+    _db: DB,
+    iter: DBIterator,
+    /// The first update of the next document, read past the end of the previous one.
+    next: Option<(String, Vec<u8>)>,
+    /// Set at the end, since advancing the iterator from there starts over.
+    done: bool,
+}
+
+impl LevelDbDocs {
+    pub fn open(path: &Path) -> Result<Self> {
+        let options = Options {
+            create_if_missing: false,
+            ..Options::default()
+        };
+        let mut db = DB::open(path, options)
+            .map_err(|e| anyhow!("Failed to open LevelDB database {:?}: {}", path, e))?;
+        let iter = db
+            .new_iter()
+            .map_err(|e| anyhow!("Failed to read LevelDB database: {}", e))?;
+        Ok(Self {
+            _db: db,
+            iter,
+            next: None,
+            done: false,
+        })
+    }
+
+    /// The name and updates, in the order they were stored, of the next document.
+    pub fn next_doc(&mut self) -> Option<(String, Vec<Vec<u8>>)> {
+        let (name, first) = self.next.take().or_else(|| self.next_update())?;
+        let mut updates = vec![first];
+        while let Some((next_name, update)) = self.next_update() {
+            if next_name != name {
+                self.next = Some((next_name, update));
+                break;
+            }
+            updates.push(update);
+        }
+        Some((name, updates))
+    }
+
+    /// The next update entry, skipping other entries.
+    fn next_update(&mut self) -> Option<(String, Vec<u8>)> {
+        while !self.done {
+            let Some((key, value)) = self.iter.next() else {
+                self.done = true;
+                break;
+            };
+            if let Some(name) = parse_update_key(&key) {
+                return Some((name, value));
+            }
+        }
+        None
+    }
+}
+
+/// The document name of a `["v1", docName, "update", clock]` key.
+fn parse_update_key(key: &[u8]) -> Option<String> {
+    let mut key = key;
+    if read_key_string(&mut key)? != "v1" {
+        return None;
+    }
+    let name = read_key_string(&mut key)?;
+    if read_key_string(&mut key)? != "update" {
+        return None;
+    }
+    match key {
+        [KEY_UINT32, _, _, _, _] => Some(name),
+        _ => None,
+    }
+}
+
+fn read_key_string(key: &mut &[u8]) -> Option<String> {
+    let (&tag, rest) = key.split_first()?;
+    if tag != KEY_STRING {
+        return None;
+    }
+    // lib0 var uint: 7 bits per byte, least significant first
+    let mut len = 0usize;
+    let mut shift = 0;
+    let mut rest = rest;
+    loop {
+        let (&byte, tail) = rest.split_first()?;
+        rest = tail;
+        len |= ((byte & 0x7f) as usize).checked_shl(shift)?;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    if rest.len() < len {
+        return None;
+    }
+    let (string, rest) = rest.split_at(len);
+    *key = rest;
+    String::from_utf8(string.to_vec()).ok()
+}
+
+/// Merge the updates of a document into one v1 update of its whole state.
+fn merge_updates(updates: &[Vec<u8>]) -> Result<Vec<u8>> {
+    let doc = Doc::new();
+    {
+        let mut txn = doc.transact_mut();
+        for update in updates {
+            let update = Update::decode_v1(update).context("Failed to decode an update")?;
+            txn.apply_update(update);
+        }
+    }
+    let update = doc
+        .transact()
+        .encode_state_as_update_v1(&StateVector::default());
+    Ok(update)
+}
+
+/// Import the documents of the y-leveldb database at `path` whose name starts with
+/// `prefix` into `store`, under their name as document ID. Documents that already exist
+/// in the store fail, or are skipped with `skip_existing`. Calls `progress` with each
+/// outcome and the number of documents done so far.
+pub async fn import_leveldb(
+    path: &Path,
+    store: &Arc<Box<dyn Store>>,
+    prefix: &str,
+    skip_existing: bool,
+    progress: impl Fn(&DocImportOutcome, usize),
+) -> Result<ImportReport> {
+    let mut docs = LevelDbDocs::open(path)?;
+    let mut results = Vec::new();
+    while let Some((doc_id, updates)) = docs.next_doc() {
+        if !doc_id.starts_with(prefix) {
+            continue;
+        }
+        let outcome = match merge_updates(&updates) {
+            Ok(update) => import_doc(store, &doc_id, &update, skip_existing).await,
+            Err(e) => DocImportOutcome::failed(&doc_id, e),
+        };
+        let outcome = DocImportOutcome {
+            updates: updates.len(),
+            ..outcome
+        };
+        progress(&outcome, results.len() + 1);
+        results.push(outcome);
+    }
+    Ok(ImportReport::new(results))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stores::filesystem::FileSystemStore;
+    use y_sweet_core::doc_sync_ext::DocWithSyncKv;
+    use yrs::{GetString, Text};
+
+    /// Encode a key the way y-leveldb does.
+    fn key(parts: &[&str], clock: Option<u32>) -> Vec<u8> {
+        let mut key = Vec::new();
+        for part in parts {
+            key.push(KEY_STRING);
+            key.push(part.len() as u8);
+            key.extend_from_slice(part.as_bytes());
+        }
+        if let Some(clock) = clock {
+            key.push(KEY_UINT32);
+            key.extend_from_slice(&clock.to_be_bytes());
+        }
+        key
+    }
+
+    #[tokio::test]
+    async fn test_imports_documents_from_y_leveldb() {
+        let dir = std::env::temp_dir().join(format!("y-sweet-leveldb-{}", nanoid::nanoid!()));
+        let db_path = dir.join("db");
+        {
+            let mut db = DB::open(&db_path, Options::default()).unwrap();
+            for (name, words) in [("notes", ["hello", " world"]), ("todo", ["a", "b"])] {
+                let doc = Doc::new();
+                let text = doc.get_or_insert_text("text");
+                let mut state = StateVector::default();
+                for (clock, word) in words.into_iter().enumerate() {
+                    let len = text.len(&doc.transact());
+                    text.insert(&mut doc.transact_mut(), len, word);
+                    let txn = doc.transact();
+                    let update = txn.encode_state_as_update_v1(&state);
+                    state = txn.state_vector();
+                    db.put(&key(&["v1", name, "update"], Some(clock as u32)), &update)
+                        .unwrap();
+                }
+                db.put(&key(&["v1_sv", name], None), b"state vector")
+                    .unwrap();
+                db.put(&key(&["v1", name, "meta", "owner"], None), b"meta")
+                    .unwrap();
+            }
+            db.put(&key(&["v1", "not valid!", "update"], Some(0)), b"")
+                .unwrap();
+            db.close().unwrap();
+        }
+
+        let store: Arc<Box<dyn Store>> =
+            Arc::new(Box::new(FileSystemStore::new(dir.join("store")).unwrap()));
+        let report = import_leveldb(&db_path, &store, "", false, |_, _| {})
+            .await
+            .unwrap();
+        assert_eq!(report.imported, 2);
+        assert_eq!(report.failed, 1);
+        let failed = report.results.iter().find(|result| result.error.is_some());
+        assert_eq!(failed.unwrap().doc_id, "not valid!");
+
+        let doc = DocWithSyncKv::new("notes", Some(store.clone()), || {}, false)
+            .await
+            .unwrap();
+        {
+            let awareness = doc.awareness();
+            let awareness = awareness.read().unwrap();
+            let text = awareness.doc().get_or_insert_text("text");
+            assert_eq!(text.get_string(&awareness.doc().transact()), "hello world");
+        }
+
+        let report = import_leveldb(&db_path, &store, "notes", true, |_, _| {})
+            .await
+            .unwrap();
+        assert_eq!(report.results.len(), 1);
+        assert_eq!(report.skipped, 1);
+    }
+}
@@ -1,11 +1,102 @@
 #![doc = include_str!("../README.md")]
 
+pub mod admin_ext;
+pub mod affinity_ext;
+#[cfg(feature = "assets")]
+pub mod asset_events_ext;
+#[cfg(feature = "assets")]
+pub mod asset_filename_ext;
+#[cfg(feature = "assets")]
+pub mod asset_hook_ext;
+#[cfg(feature = "assets")]
+pub mod asset_token_ext;
+pub mod audit_ext;
+pub mod backpressure_ext;
+#[cfg(feature = "backup")]
+pub mod backup_ext;
+#[cfg(feature = "client")]
+pub mod bench_ext;
+pub mod body_limits_ext;
+pub mod broadcast_ext;
+pub mod builder_ext;
 pub mod cli;
+#[cfg(feature = "client")]
+pub mod client_ext;
+pub mod cluster_ext;
+pub mod compaction_ext;
+#[cfg(feature = "config-files")]
+pub mod config_ext;
+pub mod connection_limits_ext;
 pub mod convert;
+pub mod dirty_signal_ext;
+pub mod doc_create_ext;
+pub mod doc_gc_ext;
+pub mod doc_handle_ext;
+pub mod doc_id_ext;
+pub mod doc_stats_ext;
+pub mod events_ext;
+pub mod export_ext;
+pub mod follower_ext;
+pub mod freeze_ext;
+pub mod gc_ext;
+#[cfg(feature = "graphql")]
+pub mod graphql_ext;
+#[cfg(feature = "grpc")]
+pub mod grpc_ext;
+#[cfg(feature = "hocuspocus")]
+pub mod hocuspocus_ext;
+pub mod html_ext;
+pub mod inspect_ext;
+pub mod labels_ext;
+pub mod lease_ext;
+#[cfg(feature = "leveldb")]
+pub mod leveldb_ext;
+pub mod memory_budget_ext;
+pub mod message_limits_ext;
+pub mod metadata_ext;
+pub mod metrics_ext;
+#[cfg(feature = "nats")]
+pub mod nats_broker_ext;
+#[cfg(feature = "otel")]
+pub mod otel_ext;
+pub mod persistence_ext;
+pub mod preload_ext;
+pub mod proxy_ext;
+pub mod publish_ext;
+pub mod quota_ext;
+pub mod readiness_ext;
+pub mod redact_ext;
+#[cfg(feature = "redis")]
+pub mod redis_broker_ext;
+#[cfg(feature = "config-files")]
+pub mod reload_ext;
+pub mod replication_ext;
+pub mod restore_ext;
+pub mod search_ext;
 pub mod server;
 pub mod server_ext;
+pub mod shutdown_ext;
+pub mod slow_ext;
+pub mod store_copy_ext;
+pub mod store_registry_ext;
 pub mod stores;
+pub mod subdoc_ext;
+#[cfg(feature = "client")]
+pub mod tail_ext;
+#[cfg(feature = "search")]
+pub mod tantivy_search_ext;
+pub mod tenants_ext;
+pub mod testing_ext;
+#[cfg(feature = "thumbnails")]
+pub mod thumbnail_ext;
+pub mod tls_ext;
 pub mod tracing_setup;
+pub mod ttl_ext;
+pub mod unix_socket_ext;
+pub mod verify_ext;
+pub mod wal_ext;
+#[cfg(feature = "webtransport")]
+pub mod webtransport_ext;
 
-#[cfg(test)]
+#[cfg(all(test, feature = "assets", feature = "client"))]
 mod tests;
@@ -1,11 +1,14 @@
 use anyhow::Context;
 use anyhow::Result;
+#[cfg(feature = "config-files")]
+use clap::CommandFactory;
 use clap::{Parser, Subcommand};
 use serde_json::json;
 use std::{
     env,
     net::{IpAddr, Ipv4Addr, SocketAddr},
     path::PathBuf,
+    sync::Arc,
 };
 use tokio::io::AsyncReadExt;
 use tokio::net::TcpListener;
@@ -13,53 +16,747 @@ use tokio_util::sync::CancellationToken;
 
 use tracing_subscriber::EnvFilter;
 use url::Url;
+use y_sweet::affinity_ext::ClientUrlTemplate;
+#[cfg(feature = "assets")]
+use y_sweet::asset_hook_ext::{AssetHooks, WebhookAssetHook, DEFAULT_ASSET_HOOK_RETRIES};
+use y_sweet::backpressure_ext::SlowConsumerPolicy;
+#[cfg(feature = "backup")]
+use y_sweet::backup_ext::{restore_backup, write_backup};
+#[cfg(feature = "client")]
+use y_sweet::bench_ext::{run_bench, BenchOptions};
+use y_sweet::body_limits_ext::BodyLimits;
 use y_sweet::cli::{print_auth_message, print_server_url};
-use y_sweet::stores::filesystem::FileSystemStore;
-use y_sweet::tracing_setup::init_tracing;
+use y_sweet::cluster_ext::{Cluster, ClusterNode};
+#[cfg(feature = "config-files")]
+use y_sweet::config_ext::{apply_config, config_path_from_args, load_config, resolve_config};
+#[cfg(feature = "client")]
+use y_sweet::doc_create_ext::create_doc_on_server;
+use y_sweet::doc_create_ext::{create_doc_in_store, read_doc_file};
+#[cfg(any(feature = "leveldb", feature = "hocuspocus"))]
+use y_sweet::doc_create_ext::{DocImportOutcome, ImportReport};
+use y_sweet::doc_gc_ext::DocGcPolicy;
+use y_sweet::doc_id_ext::{DocIdGenerator, DEFAULT_DOC_ID_LENGTH};
+use y_sweet::doc_stats_ext::DocStatsFlush;
+use y_sweet::export_ext::{export_all, ExportFormat};
+use y_sweet::gc_ext::collect_stored_garbage;
+#[cfg(feature = "hocuspocus")]
+use y_sweet::hocuspocus_ext::{export_hocuspocus, import_hocuspocus};
+use y_sweet::inspect_ext::{inspect_doc, inspect_file};
+#[cfg(feature = "leveldb")]
+use y_sweet::leveldb_ext::import_leveldb;
+use y_sweet::persistence_ext::PersistRetryPolicy;
+use y_sweet::preload_ext::PreloadSource;
+use y_sweet::proxy_ext::TrustedProxies;
+#[cfg(feature = "config-files")]
+use y_sweet::quota_ext::load_quotas;
+use y_sweet::readiness_ext::Readiness;
+use y_sweet::redact_ext::{RedactField, RedactMode, Redaction};
+#[cfg(any(feature = "redis", feature = "nats"))]
+use y_sweet::replication_ext::Replication;
+use y_sweet::shutdown_ext::{wait_for_shutdown, DEFAULT_SHUTDOWN_PERSIST_CONCURRENCY};
+use y_sweet::slow_ext::SlowThresholds;
+use y_sweet::store_copy_ext::{copy_store, StoreCopyOptions};
+use y_sweet::store_registry_ext::{open_s3_store, open_store};
+use y_sweet::stores::routed::RoutedStore;
+#[cfg(feature = "client")]
+use y_sweet::tail_ext::{socket_url, tail};
+#[cfg(feature = "search")]
+use y_sweet::tantivy_search_ext::TantivySearchIndex;
+#[cfg(feature = "config-files")]
+use y_sweet::tenants_ext::{load_tenants, open_tenants};
+use y_sweet::tls_ext::Tls;
+use y_sweet::tracing_setup::{init_tracing, LogFormat};
+#[cfg(unix)]
+use y_sweet::unix_socket_ext::bind_unix_socket;
+use y_sweet::unix_socket_ext::Listener;
+use y_sweet::verify_ext::verify_docs;
+use y_sweet::wal_ext::Wal;
 use y_sweet_core::{
-    auth::Authenticator,
-    store::{
-        s3::{S3Config, S3Store},
-        Store,
-    },
+    api_types::{validate_doc_name, Authorization},
+    auth::{Authenticator, ExpirationTimeEpochMillis, DEFAULT_EXPIRATION_SECONDS},
+    checkpoint_ext::CheckpointTriggers,
+    snapshot_ext::snapshot_key,
+    store::Store,
+    update_log_ext::UpdateLogConfig,
 };
 
-const DEFAULT_S3_REGION: &str = "us-east-1";
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+/// Access granted by a token signed with `gen-doc-token`.
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum TokenAuthorization {
+    ReadOnly,
+    Full,
+}
+
+impl From<TokenAuthorization> for Authorization {
+    fn from(authorization: TokenAuthorization) -> Self {
+        match authorization {
+            TokenAuthorization::ReadOnly => Authorization::ReadOnly,
+            TokenAuthorization::Full => Authorization::Full,
+        }
+    }
+}
+
 #[derive(Parser)]
 struct Opts {
     #[clap(subcommand)]
     subcmd: ServSubcommand,
+
+    /// Format of log lines: `json`, or `pretty` or `compact` to read them during local
+    /// development. Independent of where traces are exported.
+    #[clap(
+        long,
+        global = true,
+        value_enum,
+        default_value = "json",
+        env = "Y_SWEET_LOG_FORMAT"
+    )]
+    log_format: LogFormat,
+
+    /// Redact these from logs and traces: `doc_id` (also in request paths), `remote_addr`
+    /// and `user_agent`, comma-separated. Requires JSON logs, and traces exported over OTLP
+    /// if any.
+    #[clap(
+        long,
+        global = true,
+        value_enum,
+        value_delimiter = ',',
+        env = "Y_SWEET_REDACT_FIELDS"
+    )]
+    redact_fields: Vec<RedactField>,
+
+    /// Replace redacted values with `[redacted]`, or with a hash of the value so that the
+    /// events of one document or client can still be correlated.
+    #[clap(
+        long,
+        global = true,
+        value_enum,
+        default_value = "redact",
+        env = "Y_SWEET_REDACT_MODE"
+    )]
+    redact_mode: RedactMode,
+}
+
+/// TLS termination options shared by `serve` and `serve-doc`.
+#[derive(clap::Args)]
+struct TlsOpts {
+    /// PEM file of the TLS certificate chain, to serve over https:// and wss://. Reloaded
+    /// on SIGHUP.
+    #[cfg(feature = "tls")]
+    #[clap(long, env = "Y_SWEET_TLS_CERT", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM file of the private key of the TLS certificate.
+    #[cfg(feature = "tls")]
+    #[clap(long, env = "Y_SWEET_TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Domains to obtain a TLS certificate for from Let's Encrypt. Its TLS-ALPN-01
+    /// challenge is answered on the listening port, which must be reachable on port 443.
+    #[cfg(feature = "acme")]
+    #[clap(
+        long,
+        env = "Y_SWEET_ACME_DOMAINS",
+        value_delimiter = ',',
+        conflicts_with = "tls_cert"
+    )]
+    acme_domains: Vec<String>,
+
+    /// Email address Let's Encrypt may contact about the certificates.
+    #[cfg(feature = "acme")]
+    #[clap(long, env = "Y_SWEET_ACME_CONTACT")]
+    acme_contact: Option<String>,
+
+    /// Directory the ACME account and certificates are cached in, so they survive restarts.
+    #[cfg(feature = "acme")]
+    #[clap(long, env = "Y_SWEET_ACME_CACHE_DIR", default_value = "acme-cache")]
+    acme_cache_dir: PathBuf,
+
+    /// Use the staging directory of Let's Encrypt, for testing.
+    #[cfg(feature = "acme")]
+    #[clap(long, env = "Y_SWEET_ACME_STAGING")]
+    acme_staging: bool,
+}
+
+impl TlsOpts {
+    fn tls(&self) -> Option<Tls> {
+        #[cfg(feature = "tls")]
+        if let (Some(cert), Some(key)) = (&self.tls_cert, &self.tls_key) {
+            return Some(Tls::Files {
+                cert: cert.clone(),
+                key: key.clone(),
+            });
+        }
+        #[cfg(feature = "acme")]
+        if !self.acme_domains.is_empty() {
+            return Some(Tls::Acme {
+                domains: self.acme_domains.clone(),
+                contact: self.acme_contact.clone(),
+                cache_dir: self.acme_cache_dir.clone(),
+                staging: self.acme_staging,
+            });
+        }
+        None
+    }
+}
+
+#[derive(Subcommand)]
+#[cfg(feature = "config-files")]
+enum ConfigSubcommand {
+    /// Check a configuration file and print the effective configuration of `serve`, with
+    /// environment variables applied over the file, and where each option comes from.
+    Validate {
+        /// The configuration file to check.
+        #[clap(long, env = "Y_SWEET_CONFIG")]
+        config: PathBuf,
+
+        /// Print the configuration as JSON.
+        #[clap(long)]
+        json: bool,
+    },
 }
 
 #[derive(Subcommand)]
+enum DocSubcommand {
+    /// Check that the stored state of documents is intact and loads into a valid Yjs
+    /// document. Exits with an error if any document is invalid.
+    Verify {
+        /// The store the documents are in.
+        #[clap(long, env = "Y_SWEET_STORE")]
+        store: String,
+
+        /// The ID of the document to verify.
+        #[clap(required_unless_present = "prefix")]
+        doc_id: Option<String>,
+
+        /// Verify every document whose ID starts with this prefix.
+        #[clap(long, conflicts_with = "doc_id")]
+        prefix: Option<String>,
+
+        /// Print the results as JSON.
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Rewrite the snapshot of a document with its deleted content garbage collected. The
+    /// document must not be loaded by a running server.
+    Gc {
+        /// The store the document is in.
+        #[clap(long, env = "Y_SWEET_STORE")]
+        store: String,
+
+        /// The ID of the document to garbage collect.
+        doc_id: String,
+
+        /// Only report the expected savings, without rewriting the document.
+        #[clap(long)]
+        dry_run: bool,
+
+        /// Print the result as JSON.
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Connect to a running server as a read-only client and print the updates and
+    /// awareness changes it sends for a document, as they arrive.
+    #[cfg(feature = "client")]
+    Tail {
+        /// The URL of the server, e.g. https://y-sweet.example.com.
+        url: Url,
+
+        /// The ID of the document to tail.
+        doc_id: String,
+
+        /// A client token for the document, as returned by `/doc/:doc_id/auth`. Required
+        /// when the server has auth enabled. The tail never sends changes, so a read-only
+        /// token is enough.
+        #[clap(long, env = "Y_SWEET_CLIENT_TOKEN")]
+        token: Option<String>,
+
+        /// Also print the content of the root types changed by each update.
+        #[clap(long)]
+        content: bool,
+    },
+
+    /// Create a document with the content of a local file: a Yjs (v1) update, a JSON file
+    /// of root types as written by `export-all`, or a ProseMirror (Tiptap) JSON document.
+    /// Fails if the document exists.
+    Create {
+        /// The ID of the document to create.
+        doc_id: String,
+
+        /// The file to read the content of the document from. `.json` files are read as
+        /// JSON, other files as Yjs (v1) updates.
+        #[clap(long)]
+        from: PathBuf,
+
+        /// Read the JSON file as a ProseMirror (Tiptap) document, into the XmlFragment with
+        /// this name (`default` for Tiptap).
+        #[clap(long)]
+        prosemirror_root: Option<String>,
+
+        /// The store to write the document to.
+        #[cfg_attr(
+            feature = "client",
+            clap(long, env = "Y_SWEET_STORE", required_unless_present = "server")
+        )]
+        #[cfg_attr(
+            not(feature = "client"),
+            clap(long, env = "Y_SWEET_STORE", required = true)
+        )]
+        store: Option<String>,
+
+        /// Create the document through the running server at this URL instead of writing
+        /// to its store.
+        #[cfg(feature = "client")]
+        #[clap(long)]
+        server: Option<Url>,
+
+        /// The server token, if the server requires auth.
+        #[cfg(feature = "client")]
+        #[clap(long, env = "Y_SWEET_SERVER_TOKEN", requires = "server")]
+        server_token: Option<String>,
+    },
+
+    /// Print what makes up a document: its stored size, clients with their clocks, and
+    /// root types with their sizes.
+    Inspect {
+        /// The store the document is in.
+        #[clap(long, env = "Y_SWEET_STORE")]
+        store: Option<String>,
+
+        /// The ID of the document to inspect.
+        #[clap(required_unless_present = "file")]
+        doc_id: Option<String>,
+
+        /// Inspect a local file instead: a `data.ysweet` snapshot of a filesystem store, or
+        /// a Yjs (v1) update.
+        #[clap(long, conflicts_with = "doc_id")]
+        file: Option<PathBuf>,
+
+        /// Print the result as JSON.
+        #[clap(long)]
+        json: bool,
+    },
+}
+
+#[derive(Subcommand)]
+#[allow(clippy::large_enum_variant)] // Parsed once at startup.
 enum ServSubcommand {
     Serve {
         #[clap(env = "Y_SWEET_STORE")]
         store: Option<String>,
 
+        /// Store the documents whose ID starts with a prefix in another store, as a
+        /// comma-separated list of `PREFIX=STORE` routes, e.g. `eu-*=s3://eu-bucket/docs`.
+        /// Other documents are kept in the store. The longest matching prefix wins.
+        #[clap(long, env = "Y_SWEET_STORE_ROUTES", value_delimiter = ',')]
+        store_routes: Vec<String>,
+
+        /// A TOML or YAML file setting the other options, by the names of their flags with
+        /// underscores. Flags and environment variables take precedence over the file.
+        /// `auth`, the WebSocket message limits and the asset webhook are read from it again
+        /// on SIGHUP or `POST /admin/reload`, and then take precedence.
+        #[clap(long, env = "Y_SWEET_CONFIG")]
+        #[cfg(feature = "config-files")]
+        config: Option<PathBuf>,
+
         #[clap(long, default_value = "8080", env = "PORT")]
         port: u16,
         #[clap(long, env = "Y_SWEET_HOST")]
         host: Option<IpAddr>,
+
+        /// Listen on a Unix domain socket at this path instead of --port, for a proxy on
+        /// the same host. A socket left behind by a previous run is replaced.
+        #[cfg(unix)]
+        #[clap(long, env = "Y_SWEET_UNIX_SOCKET")]
+        unix_socket: Option<PathBuf>,
+
         #[clap(long, default_value = "10", env = "Y_SWEET_CHECKPOINT_FREQ_SECONDS")]
         checkpoint_freq_seconds: u64,
 
+        /// Also persist a document as soon as this many updates are pending.
+        #[clap(long, env = "Y_SWEET_CHECKPOINT_MAX_UPDATES")]
+        checkpoint_max_updates: Option<u64>,
+
+        /// Also persist a document as soon as this many bytes of updates are pending.
+        #[clap(long, env = "Y_SWEET_CHECKPOINT_MAX_BYTES")]
+        checkpoint_max_bytes: Option<u64>,
+
+        /// Also write each document snapshot to a backup (`data.ysweet.bak`), loaded in its
+        /// place if the snapshot is corrupted. Doubles the writes of every checkpoint.
+        #[clap(long, default_value = "false", env = "Y_SWEET_SNAPSHOT_BACKUP")]
+        snapshot_backup: bool,
+
+        /// Split each document snapshot into shards of about this many bytes, so that
+        /// checkpoints of large documents only write the shards that changed.
+        #[clap(long, env = "Y_SWEET_SNAPSHOT_SHARD_BYTES")]
+        snapshot_shard_bytes: Option<usize>,
+
+        /// Number of times a failed checkpoint is retried, with exponential backoff, before
+        /// waiting for the next one.
+        #[clap(long, default_value = "5", env = "Y_SWEET_PERSIST_MAX_RETRIES")]
+        persist_max_retries: u32,
+
+        /// Stop accepting writes for a document once its checkpoints have been failing for
+        /// this many seconds. New connections are read-only until a checkpoint succeeds.
+        #[clap(long, env = "Y_SWEET_PERSIST_REJECT_WRITES_AFTER_SECONDS")]
+        persist_reject_writes_after_seconds: Option<u64>,
+
         #[clap(long, env = "Y_SWEET_AUTH")]
         auth: Option<String>,
 
         #[clap(long, env = "Y_SWEET_URL_PREFIX")]
         url_prefix: Option<Url>,
 
-        #[clap(long)]
+        /// Serve the routes under this path (e.g. `/collab`) instead of the root, when
+        /// proxied along with other apps on the same host.
+        #[clap(long, env = "Y_SWEET_BASE_PATH")]
+        base_path: Option<String>,
+
+        /// Take the client address from `Forwarded` or `X-Forwarded-For`, and the scheme and
+        /// host of the URLs returned by the auth endpoint from `Forwarded` or
+        /// `X-Forwarded-Proto` and `X-Forwarded-Host`, for requests from these proxies: a
+        /// comma-separated list of addresses and networks (`10.0.0.0/8`), or `*` for any.
+        #[clap(long, env = "Y_SWEET_TRUSTED_PROXIES", value_delimiter = ',')]
+        trusted_proxies: Vec<String>,
+
+        /// Log a warning for each HTTP request taking longer than this many milliseconds.
+        #[clap(long, env = "Y_SWEET_SLOW_REQUEST_MS")]
+        slow_request_ms: Option<u64>,
+
+        /// Log a warning for each WebSocket message whose handling takes longer than this
+        /// many milliseconds.
+        #[clap(long, env = "Y_SWEET_SLOW_MESSAGE_MS")]
+        slow_message_ms: Option<u64>,
+
+        /// Log a warning for each persist of a document taking longer than this many
+        /// milliseconds.
+        #[clap(long, env = "Y_SWEET_SLOW_PERSIST_MS")]
+        slow_persist_ms: Option<u64>,
+
+        #[clap(flatten)]
+        tls: TlsOpts,
+
+        #[clap(long, env = "Y_SWEET_PROD")]
         prod: bool,
 
         #[clap(long, env = "Y_SWEET_MAX_BODY_SIZE")]
         max_body_size: Option<usize>,
 
+        /// Largest body of document updates, in bytes (defaults to --max-body-size).
+        #[clap(long, env = "Y_SWEET_MAX_UPDATE_BODY_SIZE")]
+        max_update_body_size: Option<usize>,
+
+        /// Largest body of document imports, in bytes (defaults to --max-body-size).
+        #[clap(long, env = "Y_SWEET_MAX_IMPORT_BODY_SIZE")]
+        max_import_body_size: Option<usize>,
+
+        /// Largest body of asset requests, in bytes (defaults to --max-body-size).
+        #[clap(long, env = "Y_SWEET_MAX_ASSET_BODY_SIZE")]
+        max_asset_body_size: Option<usize>,
+
         #[clap(long, default_value = "false", env = "Y_SWEET_SKIP_GC")]
         skip_gc: bool,
+
+        /// Disable gzip/deflate compression of document and asset list responses.
+        #[clap(long, default_value = "false", env = "Y_SWEET_DISABLE_COMPRESSION")]
+        disable_compression: bool,
+
+        /// How often WebSocket clients are pinged.
+        #[clap(long, default_value = "20", env = "Y_SWEET_WS_PING_INTERVAL_SECONDS")]
+        ws_ping_interval_seconds: u64,
+
+        /// Close WebSocket connections that have not sent anything (including pongs) for this long.
+        #[clap(long, default_value = "40", env = "Y_SWEET_WS_IDLE_TIMEOUT_SECONDS")]
+        ws_idle_timeout_seconds: u64,
+
+        /// Outgoing messages buffered per WebSocket connection before the slow consumer policy applies.
+        #[clap(long, default_value = "1024", env = "Y_SWEET_WS_SEND_BUFFER")]
+        ws_send_buffer: usize,
+
+        /// What to do when a WebSocket client cannot keep up: close it so it reconnects,
+        /// or resend the full document state once it has caught up.
+        #[clap(
+            long,
+            value_enum,
+            default_value = "close",
+            env = "Y_SWEET_SLOW_CONSUMER_POLICY"
+        )]
+        slow_consumer_policy: SlowConsumerPolicy,
+
+        /// Disconnect WebSocket clients that send a message larger than this many bytes.
+        #[clap(long, env = "Y_SWEET_WS_MAX_MESSAGE_SIZE")]
+        ws_max_message_size: Option<usize>,
+
+        /// Disconnect WebSocket clients that send more than this many messages per second.
+        #[clap(long, env = "Y_SWEET_WS_MAX_MESSAGES_PER_SECOND")]
+        ws_max_messages_per_second: Option<u32>,
+
+        /// On shutdown, how long to keep serving requests, with `/ready` reporting the server
+        /// as draining, before closing connections, for load balancers to stop routing to it.
+        #[clap(long, default_value = "0", env = "Y_SWEET_SHUTDOWN_DELAY_SECONDS")]
+        shutdown_delay_seconds: u64,
+
+        /// On shutdown, how long WebSocket clients get to acknowledge the close frame.
+        #[clap(long, default_value = "5", env = "Y_SWEET_SHUTDOWN_DRAIN_SECONDS")]
+        shutdown_drain_seconds: u64,
+
+        /// Reconnect delay suggested to WebSocket clients when the server shuts down.
+        #[clap(long, env = "Y_SWEET_SHUTDOWN_RETRY_AFTER_SECONDS")]
+        shutdown_retry_after_seconds: Option<u64>,
+
+        /// On shutdown, the number of loaded documents persisted concurrently.
+        #[clap(
+            long,
+            default_value = "16",
+            env = "Y_SWEET_SHUTDOWN_PERSIST_CONCURRENCY"
+        )]
+        shutdown_persist_concurrency: usize,
+
+        /// On shutdown, how long to wait for loaded documents to be persisted before exiting.
+        /// Waits for all of them by default.
+        #[clap(long, env = "Y_SWEET_SHUTDOWN_PERSIST_DEADLINE_SECONDS")]
+        shutdown_persist_deadline_seconds: Option<u64>,
+
+        /// On shutdown, how long to wait for connections to drain and documents to be
+        /// persisted before exiting anyway, logging the documents that were not persisted.
+        /// Waits indefinitely by default.
+        #[clap(
+            long,
+            alias = "shutdown-timeout",
+            env = "Y_SWEET_SHUTDOWN_TIMEOUT_SECONDS"
+        )]
+        shutdown_timeout_seconds: Option<u64>,
+
+        /// Maximum number of concurrent WebSocket connections on this server.
+        #[clap(long, env = "Y_SWEET_MAX_CONNECTIONS")]
+        max_connections: Option<usize>,
+
+        /// Maximum number of concurrent WebSocket connections to a single document.
+        #[clap(long, env = "Y_SWEET_MAX_CONNECTIONS_PER_DOC")]
+        max_connections_per_doc: Option<usize>,
+
+        /// How often documents whose TTL has passed are deleted.
+        #[clap(long, default_value = "60", env = "Y_SWEET_TTL_REAP_INTERVAL_SECONDS")]
+        ttl_reap_interval_seconds: u64,
+
+        /// Persist documents by appending the updates made since the previous checkpoint to
+        /// an update log (`{doc_id}/updates/{seq}`), rather than writing the full snapshot
+        /// every time. Makes checkpoints of large documents cheap.
+        #[clap(long, env = "Y_SWEET_UPDATE_LOG")]
+        update_log: bool,
+
+        /// Number of update log segments written between full snapshots.
+        #[clap(long, default_value = "100", env = "Y_SWEET_SNAPSHOT_EVERY")]
+        snapshot_every: u32,
+
+        /// How often the update logs of loaded documents are compacted into snapshots.
+        #[clap(
+            long,
+            default_value = "300",
+            env = "Y_SWEET_COMPACTION_INTERVAL_SECONDS"
+        )]
+        compaction_interval_seconds: u64,
+
+        /// Number of stored update log segments at which a document is compacted.
+        #[clap(long, default_value = "50", env = "Y_SWEET_COMPACTION_MIN_SEGMENTS")]
+        compaction_min_segments: u32,
+
+        /// Only persist documents while holding their lease in the store, which expires
+        /// after this many seconds without renewal. Makes it safe to run several instances
+        /// on the same store.
+        #[clap(long, env = "Y_SWEET_DOC_LEASE_SECONDS")]
+        doc_lease_seconds: Option<u64>,
+
+        /// Serve documents read-only from the store without ever persisting, for scaling
+        /// out reads. Requires --store. Updates published by other nodes through
+        /// replication are applied as they arrive.
+        #[clap(long, env = "Y_SWEET_FOLLOWER")]
+        follower: bool,
+
+        /// How often a follower reloads the snapshots of the documents it serves.
+        #[clap(long, default_value = "10", env = "Y_SWEET_FOLLOWER_REFRESH_SECONDS")]
+        follower_refresh_seconds: u64,
+
+        /// Also log every document update to a write-ahead log in this local directory,
+        /// replayed when the document is next loaded, so a crash between checkpoints does not
+        /// lose updates. Requires --store.
+        #[clap(long, env = "Y_SWEET_WAL_DIR")]
+        wal_dir: Option<PathBuf>,
+
+        /// Approximate memory, in megabytes, the loaded documents may take. Beyond it, the
+        /// least recently used documents without connections are persisted and unloaded.
+        /// Requires --store.
+        #[clap(long, env = "Y_SWEET_MEMORY_BUDGET_MB")]
+        memory_budget_mb: Option<usize>,
+
+        /// Unload a document once it has had no connections for this many seconds.
+        /// Defaults to the checkpoint interval.
+        #[clap(long, env = "Y_SWEET_DOC_IDLE_TIMEOUT_SECONDS")]
+        doc_idle_timeout_seconds: Option<u64>,
+
+        /// Keep every document loaded for at least this many seconds.
+        #[clap(long, default_value = "0", env = "Y_SWEET_DOC_MIN_RESIDENCY_SECONDS")]
+        doc_min_residency_seconds: u64,
+
+        /// Never unload these documents once loaded, as a comma-separated list of IDs.
+        #[clap(long, env = "Y_SWEET_PINNED_DOCS", value_delimiter = ',')]
+        pinned_docs: Vec<String>,
+
+        /// Load these documents at startup, as a comma-separated list of IDs. /ready reports
+        /// the server as not ready until every preloaded document is loaded. Requires
+        /// --store.
+        #[clap(long, env = "Y_SWEET_PRELOAD_DOCS", value_delimiter = ',')]
+        preload_docs: Vec<String>,
+
+        /// Load every stored document whose ID starts with this prefix at startup.
+        #[clap(long, env = "Y_SWEET_PRELOAD_PREFIX")]
+        preload_prefix: Option<String>,
+
+        /// Load the documents listed in this store object, a JSON array of document IDs,
+        /// at startup.
+        #[clap(long, env = "Y_SWEET_PRELOAD_MANIFEST")]
+        preload_manifest: Option<String>,
+
+        /// ID of this node, in the cluster given by --cluster-nodes and in client URLs.
+        #[clap(long, env = "Y_SWEET_CLUSTER_NODE_ID")]
+        cluster_node_id: Option<String>,
+
+        /// Shard documents across these nodes, as a comma-separated list of `id=url`, with
+        /// the URL of the root of each node (without --base-path). Every node must be given
+        /// the same list.
+        #[clap(long, env = "Y_SWEET_CLUSTER_NODES", requires = "cluster_node_id")]
+        cluster_nodes: Option<String>,
+
+        /// Template of the URLs handed to clients, replacing --url-prefix. `{node}` is
+        /// replaced by the node owning the document and `{doc_id}` by its ID, so a load
+        /// balancer can route on them, e.g. `https://{node}.sync.example.com`.
+        #[clap(long, env = "Y_SWEET_CLIENT_URL_TEMPLATE")]
+        client_url_template: Option<String>,
+
+        /// Length of the IDs generated for documents created without one, after the prefix.
+        #[clap(long, env = "Y_SWEET_DOC_ID_LENGTH", default_value_t = DEFAULT_DOC_ID_LENGTH)]
+        doc_id_length: usize,
+
+        /// Characters of the IDs generated for documents created without one. Letters,
+        /// digits, `-` and `_` are allowed, and all of them are used by default.
+        #[clap(long, env = "Y_SWEET_DOC_ID_ALPHABET")]
+        doc_id_alphabet: Option<String>,
+
+        /// Prefix of the IDs generated for documents created without one. Documents
+        /// created, copied or forked under an ID of the client's choosing must have it too.
+        #[clap(long, env = "Y_SWEET_DOC_ID_PREFIX", default_value = "")]
+        doc_id_prefix: String,
+
+        /// Serve the tenants listed in this TOML or YAML file, each with documents, a store
+        /// prefix and optionally an auth key of its own, under `/t/{tenant_id}` or to
+        /// requests with the `X-Y-Sweet-Tenant` header. Tenants take the other options of
+        /// the server, except replication, clustering, leases, the WAL, the memory budget
+        /// and preloading.
+        #[clap(long, env = "Y_SWEET_TENANTS")]
+        #[cfg(feature = "config-files")]
+        tenants: Option<PathBuf>,
+
+        /// Enforce the quotas listed in this TOML or YAML file on the number of documents
+        /// and the bytes of document state and assets, each for the documents whose ID
+        /// starts with a prefix. Usage is listed by `GET /quotas`. Tenants have the quota
+        /// of their entry in the tenants file instead.
+        #[clap(long, env = "Y_SWEET_QUOTAS")]
+        #[cfg(feature = "config-files")]
+        quotas: Option<PathBuf>,
+
+        /// Keep an audit trail of each document in the store (`{doc_id}/audit/`): tokens
+        /// issued, connections, REST updates, deletions, copies and asset operations, with
+        /// their time and principal. Listed by `GET /d/:doc_id/audit`.
+        #[clap(long, env = "Y_SWEET_AUDIT")]
+        audit: bool,
+
+        /// Flush the totals of the statistics of each document (updates applied, bytes in
+        /// and out, peak connections, last activity) on each checkpoint: to
+        /// `{doc_id}/stats.json` in the store, or as a `doc_stats` log event. The statistics
+        /// of loaded documents are listed by `GET /d/:doc_id/stats` either way.
+        #[clap(long, value_enum, env = "Y_SWEET_DOC_STATS_FLUSH")]
+        doc_stats_flush: Option<DocStatsFlush>,
+
+        /// POST each confirmed asset upload (`POST /d/:doc_id/assets/:asset_id/confirm`) to
+        /// this URL as JSON (`docId`, `assetId`, `contentType`), e.g. to scan, transcode or
+        /// index the asset. The status of the processing is listed with the assets.
+        #[cfg(feature = "assets")]
+        #[clap(long, env = "Y_SWEET_ASSET_WEBHOOK_URL")]
+        asset_webhook_url: Option<Url>,
+
+        /// Retry a failed asset webhook up to this many times, with exponential backoff,
+        /// before marking the asset as failed.
+        #[cfg(feature = "assets")]
+        #[clap(long, default_value_t = DEFAULT_ASSET_HOOK_RETRIES, env = "Y_SWEET_ASSET_HOOK_RETRIES")]
+        asset_hook_retries: u32,
+
+        /// Accept the store notifications of `POST /assets/events` with this secret in the
+        /// `secret` query parameter, for SNS subscriptions that cannot send the server token.
+        /// Use a secret of its own, not the server token: URLs end up in logs.
+        #[cfg(feature = "assets")]
+        #[clap(long, env = "Y_SWEET_ASSET_EVENTS_SECRET")]
+        asset_events_secret: Option<String>,
+
+        /// Index the text of documents in this local directory on their checkpoints, and
+        /// search it on `GET /search?q=`. Documents are indexed at their first checkpoint
+        /// after the index is enabled.
+        #[cfg(feature = "search")]
+        #[clap(long, env = "Y_SWEET_SEARCH_INDEX_DIR")]
+        search_index_dir: Option<PathBuf>,
+
+        /// Serve the management endpoints (creating, copying, deleting and managing
+        /// documents, and checking the store) on this port only, leaving sync, auth and
+        /// asset endpoints on --port. In a cluster, management requests for documents owned
+        /// by another node are redirected to its URL, so they should be sent to the owner.
+        #[clap(long, env = "Y_SWEET_ADMIN_PORT")]
+        admin_port: Option<u16>,
+
+        /// Interface of the management endpoints, e.g. 127.0.0.1 or a private address.
+        /// Defaults to --host.
+        #[clap(long, env = "Y_SWEET_ADMIN_HOST", requires = "admin_port")]
+        admin_host: Option<IpAddr>,
+
+        /// Serve the gRPC management API on this port (same host as the HTTP API).
+        #[cfg(feature = "grpc")]
+        #[clap(long, env = "Y_SWEET_GRPC_PORT")]
+        grpc_port: Option<u16>,
+
+        /// Also serve the sync protocol over WebTransport (experimental), on the UDP port of
+        /// the listening address. Requires --tls-cert and --tls-key.
+        #[cfg(feature = "webtransport")]
+        #[clap(long, env = "Y_SWEET_WEBTRANSPORT", requires = "tls_cert")]
+        webtransport: bool,
+
+        /// Sync document updates with other instances through this Redis server
+        /// (e.g. redis://localhost:6379).
+        #[cfg(feature = "redis")]
+        #[clap(long, env = "Y_SWEET_REDIS_URL")]
+        redis_url: Option<String>,
+
+        /// Prefix of the Redis channels document updates are published on.
+        #[cfg(feature = "redis")]
+        #[clap(long, env = "Y_SWEET_REDIS_CHANNEL_PREFIX")]
+        redis_channel_prefix: Option<String>,
+
+        /// Sync document updates with other instances through this NATS server, and publish
+        /// them to a JetStream stream other services can consume (e.g. nats://localhost:4222).
+        /// Takes precedence over --redis-url.
+        #[cfg(feature = "nats")]
+        #[clap(long, env = "Y_SWEET_NATS_URL")]
+        nats_url: Option<String>,
+
+        /// Subject prefix document updates are published under.
+        #[cfg(feature = "nats")]
+        #[clap(long, env = "Y_SWEET_NATS_SUBJECT_PREFIX")]
+        nats_subject_prefix: Option<String>,
+
+        /// Name of the JetStream stream holding document updates.
+        #[cfg(feature = "nats")]
+        #[clap(long, env = "Y_SWEET_NATS_STREAM")]
+        nats_stream: Option<String>,
     },
 
     GenAuth {
@@ -67,6 +764,29 @@ enum ServSubcommand {
         json: bool,
     },
 
+    /// Sign a client token for a document offline with the auth key, the way the
+    /// `/doc/:doc_id/auth` endpoint of the server would.
+    GenDocToken {
+        /// The ID of the document the token grants access to.
+        doc_id: String,
+
+        /// The access the token grants.
+        #[clap(long, value_enum, default_value = "full")]
+        auth: TokenAuthorization,
+
+        /// How long the token is valid for, in seconds.
+        #[clap(long, default_value_t = DEFAULT_EXPIRATION_SECONDS)]
+        valid_for: u64,
+
+        /// The auth key of the server, as printed by `gen-auth`.
+        #[clap(long, env = "Y_SWEET_AUTH")]
+        auth_key: String,
+
+        /// Print the token with its document, access and expiration as JSON.
+        #[clap(long)]
+        json: bool,
+    },
+
     /// Convert from a YDoc v1 update format to a .ysweet file.
     /// The YDoc update should be passed in via stdin.
     ConvertFromUpdate {
@@ -78,8 +798,242 @@ enum ServSubcommand {
         doc_id: String,
     },
 
+    /// Import the documents of a y-websocket server from its LevelDB persistence
+    /// (y-leveldb), each under its name as document ID. The y-websocket server must be
+    /// stopped.
+    #[cfg(feature = "leveldb")]
+    ImportLeveldb {
+        /// The directory of the LevelDB database.
+        path: PathBuf,
+
+        /// The store to write the documents to.
+        #[clap(long, env = "Y_SWEET_STORE")]
+        store: String,
+
+        /// Only import the documents whose name starts with this prefix.
+        #[clap(long, default_value = "")]
+        prefix: String,
+
+        /// Skip documents that already exist in the store, instead of failing them.
+        #[clap(long)]
+        skip_existing: bool,
+
+        /// Print the report as JSON.
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Import the documents of a Hocuspocus server from its SQLite database, each under its
+    /// name as document ID.
+    #[cfg(feature = "hocuspocus")]
+    ImportHocuspocus {
+        /// The SQLite database file.
+        path: PathBuf,
+
+        /// The store to write the documents to.
+        #[clap(long, env = "Y_SWEET_STORE")]
+        store: String,
+
+        /// Only import the documents whose name starts with this prefix.
+        #[clap(long, default_value = "")]
+        prefix: String,
+
+        /// Skip documents that already exist in the store, instead of failing them.
+        #[clap(long)]
+        skip_existing: bool,
+
+        /// Print the report as JSON.
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Export the documents of a store to a SQLite database in the format of the SQLite
+    /// extension of Hocuspocus, replacing documents already in it.
+    #[cfg(feature = "hocuspocus")]
+    ExportHocuspocus {
+        /// The SQLite database file, created if it does not exist.
+        path: PathBuf,
+
+        /// The store to export documents from.
+        #[clap(long, env = "Y_SWEET_STORE")]
+        store: String,
+
+        /// Only export the documents whose ID starts with this prefix.
+        #[clap(long, default_value = "")]
+        prefix: String,
+
+        /// Print the report as JSON.
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Inspect documents in a store.
+    Doc {
+        #[clap(subcommand)]
+        command: DocSubcommand,
+    },
+
+    /// Archive the documents of a store, with their assets, update log history and metadata,
+    /// to a zstd-compressed tar file with a manifest.
+    #[cfg(feature = "backup")]
+    Backup {
+        /// The store to back up.
+        #[clap(env = "Y_SWEET_STORE")]
+        store: String,
+
+        /// The archive to write, e.g. `backup.tar.zst`.
+        #[clap(long)]
+        out: PathBuf,
+
+        /// Only back up the documents whose ID starts with this prefix.
+        #[clap(long, default_value = "")]
+        prefix: String,
+
+        /// Print the manifest as JSON.
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Restore the documents of an archive written by `backup` to a store, after checking
+    /// the archive against its manifest.
+    #[cfg(feature = "backup")]
+    Restore {
+        /// The store to restore the documents to.
+        #[clap(env = "Y_SWEET_STORE")]
+        store: String,
+
+        /// The archive to restore.
+        #[clap(long = "in")]
+        input: PathBuf,
+
+        /// Replace documents that already exist in the store, instead of skipping them.
+        #[clap(long)]
+        overwrite: bool,
+
+        /// Print the report as JSON.
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Export every document of a store to a file per document in a directory.
+    ExportAll {
+        /// The store to export documents from.
+        #[clap(long, env = "Y_SWEET_STORE")]
+        store: String,
+
+        /// The format of the exported files.
+        #[clap(long, value_enum, default_value = "json")]
+        format: ExportFormat,
+
+        /// The directory to write the files to.
+        #[clap(long)]
+        out: PathBuf,
+
+        /// Only export the documents whose ID starts with this prefix.
+        #[clap(long, default_value = "")]
+        prefix: String,
+
+        /// Only export the documents modified since this time, in Unix epoch seconds.
+        /// Documents are exported if the store does not record modification times.
+        #[clap(long)]
+        modified_since: Option<u64>,
+
+        /// Print the report as JSON.
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Copy documents, with their assets, update logs and metadata, from one store to
+    /// another. Credentials of S3-compatible stores are read from the usual environment
+    /// variables, overridden for each store by the same variables prefixed with
+    /// `Y_SWEET_FROM_` or `Y_SWEET_TO_` (e.g. `Y_SWEET_TO_AWS_ENDPOINT_URL_S3`).
+    CopyStore {
+        /// The store to copy documents from.
+        #[clap(long)]
+        from: String,
+
+        /// The store to copy documents to.
+        #[clap(long)]
+        to: String,
+
+        /// Only copy the documents whose ID starts with this prefix.
+        #[clap(long, default_value = "")]
+        prefix: String,
+
+        /// Number of documents copied concurrently.
+        #[clap(long, default_value = "8")]
+        concurrency: usize,
+
+        /// Skip documents that already exist in the destination, to resume a copy.
+        #[clap(long)]
+        skip_existing: bool,
+
+        /// Do not read copied objects back, nor verify copied documents.
+        #[clap(long)]
+        no_verify: bool,
+
+        /// Only list the objects that would be written, without copying anything.
+        #[clap(long)]
+        dry_run: bool,
+
+        /// Print the report as JSON.
+        #[clap(long)]
+        json: bool,
+    },
+
     Version,
 
+    /// Work with configuration files of `serve`.
+    #[cfg(feature = "config-files")]
+    Config {
+        #[clap(subcommand)]
+        command: ConfigSubcommand,
+    },
+
+    /// Load test a running server: connect synthetic clients editing documents over
+    /// WebSocket and report the latency of their updates, dropped updates and, for a local
+    /// server, its CPU and memory use.
+    #[cfg(feature = "client")]
+    Bench {
+        /// The URL of the server, e.g. http://127.0.0.1:8080.
+        #[clap(long)]
+        url: Url,
+
+        /// Number of documents to edit.
+        #[clap(long, default_value = "10")]
+        docs: usize,
+
+        /// Number of clients editing each document.
+        #[clap(long, default_value = "4", value_parser = clap::value_parser!(u16).range(2..))]
+        clients_per_doc: u16,
+
+        /// Edits per second made by each client.
+        #[clap(long, default_value = "2")]
+        ops_rate: f64,
+
+        /// How long clients edit for, in seconds.
+        #[clap(long, default_value = "30")]
+        duration_seconds: u64,
+
+        /// The server's auth key, to sign client tokens with, if the server has auth enabled.
+        #[clap(long, env = "Y_SWEET_AUTH")]
+        auth: Option<String>,
+
+        /// Prefix of the IDs of the documents edited. Documents are created if they do not
+        /// exist.
+        #[clap(long, default_value = "bench-")]
+        doc_prefix: String,
+
+        /// PID of the server process, to report its CPU and memory use if it runs on this
+        /// (Linux) host.
+        #[clap(long)]
+        server_pid: Option<u32>,
+
+        /// Print the report as JSON.
+        #[clap(long)]
+        json: bool,
+    },
+
     ServeDoc {
         #[clap(long, default_value = "8080", env = "PORT")]
         port: u16,
@@ -90,171 +1044,663 @@ enum ServSubcommand {
         #[clap(long, default_value = "10", env = "Y_SWEET_CHECKPOINT_FREQ_SECONDS")]
         checkpoint_freq_seconds: u64,
 
+        /// Also persist a document as soon as this many updates are pending.
+        #[clap(long, env = "Y_SWEET_CHECKPOINT_MAX_UPDATES")]
+        checkpoint_max_updates: Option<u64>,
+
+        /// Also persist a document as soon as this many bytes of updates are pending.
+        #[clap(long, env = "Y_SWEET_CHECKPOINT_MAX_BYTES")]
+        checkpoint_max_bytes: Option<u64>,
+
+        /// Also write each document snapshot to a backup (`data.ysweet.bak`), loaded in its
+        /// place if the snapshot is corrupted. Doubles the writes of every checkpoint.
+        #[clap(long, default_value = "false", env = "Y_SWEET_SNAPSHOT_BACKUP")]
+        snapshot_backup: bool,
+
+        /// Split each document snapshot into shards of about this many bytes, so that
+        /// checkpoints of large documents only write the shards that changed.
+        #[clap(long, env = "Y_SWEET_SNAPSHOT_SHARD_BYTES")]
+        snapshot_shard_bytes: Option<usize>,
+
         #[clap(long, env = "Y_SWEET_MAX_BODY_SIZE")]
         max_body_size: Option<usize>,
 
+        /// Largest body of document updates, in bytes (defaults to --max-body-size).
+        #[clap(long, env = "Y_SWEET_MAX_UPDATE_BODY_SIZE")]
+        max_update_body_size: Option<usize>,
+
+        /// Largest body of document imports, in bytes (defaults to --max-body-size).
+        #[clap(long, env = "Y_SWEET_MAX_IMPORT_BODY_SIZE")]
+        max_import_body_size: Option<usize>,
+
+        /// Largest body of asset requests, in bytes (defaults to --max-body-size).
+        #[clap(long, env = "Y_SWEET_MAX_ASSET_BODY_SIZE")]
+        max_asset_body_size: Option<usize>,
+
         #[clap(long, default_value = "false", env = "Y_SWEET_SKIP_GC")]
         skip_gc: bool,
+
+        /// Disable gzip/deflate compression of document and asset list responses.
+        #[clap(long, default_value = "false", env = "Y_SWEET_DISABLE_COMPRESSION")]
+        disable_compression: bool,
+
+        /// How often WebSocket clients are pinged.
+        #[clap(long, default_value = "20", env = "Y_SWEET_WS_PING_INTERVAL_SECONDS")]
+        ws_ping_interval_seconds: u64,
+
+        /// Close WebSocket connections that have not sent anything (including pongs) for this long.
+        #[clap(long, default_value = "40", env = "Y_SWEET_WS_IDLE_TIMEOUT_SECONDS")]
+        ws_idle_timeout_seconds: u64,
+
+        /// Outgoing messages buffered per WebSocket connection before the slow consumer policy applies.
+        #[clap(long, default_value = "1024", env = "Y_SWEET_WS_SEND_BUFFER")]
+        ws_send_buffer: usize,
+
+        /// What to do when a WebSocket client cannot keep up: close it so it reconnects,
+        /// or resend the full document state once it has caught up.
+        #[clap(
+            long,
+            value_enum,
+            default_value = "close",
+            env = "Y_SWEET_SLOW_CONSUMER_POLICY"
+        )]
+        slow_consumer_policy: SlowConsumerPolicy,
+
+        /// Disconnect WebSocket clients that send a message larger than this many bytes.
+        #[clap(long, env = "Y_SWEET_WS_MAX_MESSAGE_SIZE")]
+        ws_max_message_size: Option<usize>,
+
+        /// Disconnect WebSocket clients that send more than this many messages per second.
+        #[clap(long, env = "Y_SWEET_WS_MAX_MESSAGES_PER_SECOND")]
+        ws_max_messages_per_second: Option<u32>,
+
+        /// On shutdown, how long to keep serving requests, with `/ready` reporting the server
+        /// as draining, before closing connections, for load balancers to stop routing to it.
+        #[clap(long, default_value = "0", env = "Y_SWEET_SHUTDOWN_DELAY_SECONDS")]
+        shutdown_delay_seconds: u64,
+
+        /// On shutdown, how long WebSocket clients get to acknowledge the close frame.
+        #[clap(long, default_value = "5", env = "Y_SWEET_SHUTDOWN_DRAIN_SECONDS")]
+        shutdown_drain_seconds: u64,
+
+        /// Reconnect delay suggested to WebSocket clients when the server shuts down.
+        #[clap(long, env = "Y_SWEET_SHUTDOWN_RETRY_AFTER_SECONDS")]
+        shutdown_retry_after_seconds: Option<u64>,
+
+        /// On shutdown, how long to wait for loaded documents to be persisted before exiting.
+        /// Waits for all of them by default.
+        #[clap(long, env = "Y_SWEET_SHUTDOWN_PERSIST_DEADLINE_SECONDS")]
+        shutdown_persist_deadline_seconds: Option<u64>,
+
+        /// On shutdown, how long to wait for connections to drain and documents to be
+        /// persisted before exiting anyway, logging the documents that were not persisted.
+        /// Waits indefinitely by default.
+        #[clap(
+            long,
+            alias = "shutdown-timeout",
+            env = "Y_SWEET_SHUTDOWN_TIMEOUT_SECONDS"
+        )]
+        shutdown_timeout_seconds: Option<u64>,
+
+        /// Maximum number of concurrent WebSocket connections to the document.
+        #[clap(long, env = "Y_SWEET_MAX_CONNECTIONS")]
+        max_connections: Option<usize>,
+
+        #[clap(flatten)]
+        tls: TlsOpts,
     },
 }
 
-const S3_ACCESS_KEY_ID: &str = "AWS_ACCESS_KEY_ID";
-const S3_SECRET_ACCESS_KEY: &str = "AWS_SECRET_ACCESS_KEY";
-const S3_SESSION_TOKEN: &str = "AWS_SESSION_TOKEN";
-const S3_REGION: &str = "AWS_REGION";
-const S3_ENDPOINT: &str = "AWS_ENDPOINT_URL_S3";
-const S3_USE_PATH_STYLE: &str = "AWS_S3_USE_PATH_STYLE";
-fn parse_s3_config_from_env_and_args(
-    bucket: String,
-    prefix: Option<String>,
-) -> anyhow::Result<S3Config> {
-    let use_path_style = env::var(S3_USE_PATH_STYLE).ok();
-    let path_style = if let Some(use_path_style) = use_path_style {
-        if use_path_style.to_lowercase() == "true" {
-            true
-        } else if use_path_style.to_lowercase() == "false" || use_path_style.is_empty() {
-            false
-        } else {
-            anyhow::bail!(
-                "If AWS_S3_USE_PATH_STYLE is set, it must be either \"true\" or \"false\""
-            )
-        }
-    } else {
-        false
+#[cfg(any(feature = "leveldb", feature = "hocuspocus"))]
+fn print_import_outcome(outcome: &DocImportOutcome, done: usize) {
+    let status = match (&outcome.error, outcome.skipped) {
+        (Some(error), _) => format!("FAILED  {}", error),
+        (None, true) => "skipped, already in the store".to_string(),
+        (None, false) => format!("{} updates, {} bytes", outcome.updates, outcome.bytes),
     };
+    println!("[{}] {}  {}", done, outcome.doc_id, status);
+}
 
-    Ok(S3Config {
-        key: env::var(S3_ACCESS_KEY_ID)
-            .map_err(|_| anyhow::anyhow!("{} env var not supplied", S3_ACCESS_KEY_ID))?,
-        region: env::var(S3_REGION).unwrap_or_else(|_| DEFAULT_S3_REGION.to_string()),
-        endpoint: env::var(S3_ENDPOINT).unwrap_or_else(|_| {
-            format!(
-                "https://s3.dualstack.{}.amazonaws.com",
-                env::var(S3_REGION).unwrap_or_else(|_| DEFAULT_S3_REGION.to_string())
-            )
-        }),
-        secret: env::var(S3_SECRET_ACCESS_KEY)
-            .map_err(|_| anyhow::anyhow!("{} env var not supplied", S3_SECRET_ACCESS_KEY))?,
-        token: env::var(S3_SESSION_TOKEN).ok(),
-        bucket,
-        bucket_prefix: prefix,
-        // If the endpoint is overridden, we assume that the user wants path-style URLs.
-        path_style,
-    })
+#[cfg(any(feature = "leveldb", feature = "hocuspocus"))]
+fn print_import_report(report: &ImportReport, json: bool) -> Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(report)?);
+    } else {
+        println!(
+            "{} documents imported, {} skipped, {} failed",
+            report.imported, report.skipped, report.failed
+        );
+    }
+    if report.failed > 0 {
+        anyhow::bail!("{} documents failed to import", report.failed);
+    }
+    Ok(())
 }
 
 async fn get_store_from_opts(store_path: &str) -> Result<Box<dyn Store>> {
-    if store_path.starts_with("s3://") {
-        let url = url::Url::parse(store_path)?;
-        let bucket = url
-            .host_str()
-            .ok_or_else(|| anyhow::anyhow!("Invalid S3 URL"))?
-            .to_owned();
-        let bucket_prefix = url.path().trim_start_matches('/').to_owned();
-        let bucket_prefix = (!bucket_prefix.is_empty()).then_some(bucket_prefix); // "" => None
-        let config = parse_s3_config_from_env_and_args(bucket, bucket_prefix)?;
-        let store = S3Store::new(config).await?;
-        Ok(Box::new(store))
-    } else {
-        Ok(Box::new(FileSystemStore::new(PathBuf::from(store_path))?))
+    open_store(store_path, "").await
+}
+
+/// Report the server as draining on `/ready`, and keep serving for `delay_seconds` so that
+/// load balancers stop routing to it before it shuts down.
+async fn drain_before_shutdown(readiness: &Readiness, delay_seconds: u64) {
+    readiness.start_draining();
+    if delay_seconds > 0 {
+        tracing::info!(
+            message = format!(
+                "Draining for {} seconds before shutting down",
+                delay_seconds
+            ),
+            event = "shutdown_delay_started",
+            delay_seconds = delay_seconds
+        );
+        tokio::time::sleep(std::time::Duration::from_secs(delay_seconds)).await;
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let opts = Opts::parse();
+#[tokio::main]
+async fn main() -> Result<()> {
+    // The configuration file of `serve` is applied as environment variables, before they
+    // are parsed along with flags
+    #[cfg(feature = "config-files")]
+    if let Some(path) = config_path_from_args(&env::args().collect::<Vec<_>>()) {
+        apply_config(&load_config(&path)?);
+    }
+    let opts = Opts::parse();
+
+    // Logging: default WARN, override via Y_SWEET_LOG (e.g. "info", "debug", "trace" or full filter spec)
+    // Example: Y_SWEET_LOG=y_sweet=debug,y_sweet_core=info,hyper=warn
+    let filter = if let Ok(spec) = std::env::var("Y_SWEET_LOG") {
+        EnvFilter::new(spec)
+    } else {
+        EnvFilter::new("warn")
+    };
+    let redaction = Redaction::new(opts.redact_fields.clone(), opts.redact_mode);
+    let _tracing_guard = init_tracing(filter, opts.log_format, redaction)?;
+
+    match &opts.subcmd {
+        ServSubcommand::Serve {
+            port,
+            host,
+            #[cfg(unix)]
+            unix_socket,
+            checkpoint_freq_seconds,
+            checkpoint_max_updates,
+            checkpoint_max_bytes,
+            snapshot_backup,
+            snapshot_shard_bytes,
+            persist_max_retries,
+            persist_reject_writes_after_seconds,
+            store,
+            store_routes,
+            auth,
+            url_prefix,
+            trusted_proxies,
+            slow_request_ms,
+            slow_message_ms,
+            slow_persist_ms,
+            base_path,
+            prod,
+            max_body_size,
+            max_update_body_size,
+            max_import_body_size,
+            max_asset_body_size,
+            skip_gc,
+            disable_compression,
+            ws_ping_interval_seconds,
+            ws_idle_timeout_seconds,
+            ws_send_buffer,
+            slow_consumer_policy,
+            ws_max_message_size,
+            ws_max_messages_per_second,
+            shutdown_delay_seconds,
+            shutdown_drain_seconds,
+            shutdown_retry_after_seconds,
+            shutdown_persist_concurrency,
+            shutdown_persist_deadline_seconds,
+            shutdown_timeout_seconds,
+            max_connections,
+            max_connections_per_doc,
+            ttl_reap_interval_seconds,
+            update_log,
+            snapshot_every,
+            compaction_interval_seconds,
+            compaction_min_segments,
+            doc_lease_seconds,
+            follower,
+            follower_refresh_seconds,
+            wal_dir,
+            memory_budget_mb,
+            doc_idle_timeout_seconds,
+            doc_min_residency_seconds,
+            pinned_docs,
+            preload_docs,
+            preload_prefix,
+            preload_manifest,
+            cluster_node_id,
+            cluster_nodes,
+            client_url_template,
+            doc_id_length,
+            doc_id_alphabet,
+            doc_id_prefix,
+            #[cfg(feature = "config-files")]
+            tenants,
+            #[cfg(feature = "config-files")]
+            quotas,
+            audit,
+            doc_stats_flush,
+            #[cfg(feature = "assets")]
+            asset_webhook_url,
+            #[cfg(feature = "assets")]
+            asset_hook_retries,
+            #[cfg(feature = "assets")]
+            asset_events_secret,
+            #[cfg(feature = "search")]
+            search_index_dir,
+            admin_port,
+            admin_host,
+            #[cfg(feature = "grpc")]
+            grpc_port,
+            #[cfg(feature = "webtransport")]
+            webtransport,
+            #[cfg(feature = "redis")]
+            redis_url,
+            #[cfg(feature = "redis")]
+            redis_channel_prefix,
+            #[cfg(feature = "nats")]
+            nats_url,
+            #[cfg(feature = "nats")]
+            nats_subject_prefix,
+            #[cfg(feature = "nats")]
+            nats_stream,
+            // Applied before parsing, see `config_ext`, and read again on reload
+            #[cfg(feature = "config-files")]
+            config,
+            tls,
+        } => {
+            let auth = if let Some(auth) = auth {
+                Some(Authenticator::new(auth)?)
+            } else {
+                tracing::warn!(
+                    message = "No auth key set. Only use this for local development!",
+                    event = "auth_warning"
+                );
+                None
+            };
+
+            let addr = SocketAddr::new(
+                host.unwrap_or(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))),
+                *port,
+            );
+
+            #[cfg(unix)]
+            let (listener, addr): (Listener, _) = if let Some(path) = unix_socket {
+                (bind_unix_socket(path)?.into(), addr)
+            } else {
+                let listener = TcpListener::bind(addr).await?;
+                let addr = listener.local_addr()?;
+                (listener.into(), addr)
+            };
+            #[cfg(not(unix))]
+            let (listener, addr): (Listener, _) = {
+                let listener = TcpListener::bind(addr).await?;
+                let addr = listener.local_addr()?;
+                (listener.into(), addr)
+            };
+            #[cfg(unix)]
+            let listening_on = unix_socket
+                .as_ref()
+                .map(|path| format!("unix:{}", path.display()));
+            #[cfg(not(unix))]
+            let listening_on: Option<String> = None;
+
+            #[cfg(feature = "config-files")]
+            let store_path = store.as_deref();
+            if !store_routes.is_empty() && store.is_none() {
+                anyhow::bail!("--store-routes requires a store for the other documents");
+            }
+            if *audit && store.is_none() {
+                anyhow::bail!("--audit requires a store to keep the audit trails in");
+            }
+            if *doc_stats_flush == Some(DocStatsFlush::Store) && store.is_none() {
+                anyhow::bail!("--doc-stats-flush store requires a store");
+            }
+            let store = if let Some(store) = store {
+                let mut store = get_store_from_opts(store).await?;
+                if !store_routes.is_empty() {
+                    store = Box::new(RoutedStore::open(store_routes, store).await?);
+                }
+                store.init().await?;
+                Some(store)
+            } else {
+                tracing::warn!(
+                    message = "No store set. Documents will be stored in memory only.",
+                    event = "store_warning"
+                );
+                None
+            };
+
+            if *follower && store.is_none() {
+                anyhow::bail!("--follower requires a store to follow");
+            }
+
+            if wal_dir.is_some() && store.is_none() {
+                anyhow::bail!("--wal-dir requires a store to persist to");
+            }
+
+            if memory_budget_mb.is_some() && store.is_none() {
+                anyhow::bail!("--memory-budget-mb requires a store to persist evicted documents");
+            }
+
+            let mut preload = Vec::new();
+            if !preload_docs.is_empty() {
+                preload.push(PreloadSource::Docs(preload_docs.clone()));
+            }
+            if let Some(prefix) = preload_prefix {
+                preload.push(PreloadSource::Prefix(prefix.clone()));
+            }
+            if let Some(manifest) = preload_manifest {
+                preload.push(PreloadSource::Manifest(manifest.clone()));
+            }
+            if !preload.is_empty() && store.is_none() {
+                anyhow::bail!("Preloading documents requires a store to load them from");
+            }
+
+            let tls = tls.tls();
+            // Clients of a Unix socket reach the server through the URL prefix of the proxy
+            if !prod && (listening_on.is_none() || url_prefix.is_some()) {
+                // Without a URL prefix, clients connect to the listening address directly
+                let url_prefix = match (url_prefix, &tls) {
+                    (None, Some(_)) => Some(Url::parse(&format!("https://{}", addr))?),
+                    (None, None) if base_path.is_some() => {
+                        Some(Url::parse(&format!("http://{}", addr))?)
+                    }
+                    (url_prefix, _) => url_prefix.clone(),
+                };
+                // Clients reach the routes under the base path
+                let url_prefix = match (url_prefix, base_path) {
+                    (Some(mut url), Some(base_path)) => {
+                        let path = format!(
+                            "{}/{}",
+                            url.path().trim_end_matches('/'),
+                            base_path.trim_matches('/')
+                        );
+                        url.set_path(&path);
+                        Some(url)
+                    }
+                    (url_prefix, _) => url_prefix,
+                };
+                print_server_url(auth.as_ref(), url_prefix.as_ref(), addr);
+            }
+
+            let token = CancellationToken::new();
+
+            let server = y_sweet::server::Server::builder()
+                .store(store)
+                .checkpoint_freq(std::time::Duration::from_secs(*checkpoint_freq_seconds))
+                .authenticator(auth)
+                .url_prefix(url_prefix.clone())
+                .base_path(base_path.clone())
+                .cancellation_token(token.clone())
+                .max_body_size(*max_body_size)
+                .body_limits(BodyLimits {
+                    update: *max_update_body_size,
+                    import: *max_import_body_size,
+                    assets: *max_asset_body_size,
+                })
+                .skip_gc(*skip_gc)
+                .checkpoint_triggers(CheckpointTriggers {
+                    max_pending_updates: *checkpoint_max_updates,
+                    max_pending_bytes: *checkpoint_max_bytes,
+                })
+                .connection_limits(*max_connections, *max_connections_per_doc)
+                .build()
+                .await?
+                .with_compression(!*disable_compression)
+                .with_ws_keepalive(
+                    std::time::Duration::from_secs(*ws_ping_interval_seconds),
+                    std::time::Duration::from_secs(*ws_idle_timeout_seconds),
+                )
+                .with_slow_consumer_policy(*ws_send_buffer, *slow_consumer_policy)
+                .with_message_limits(*ws_max_message_size, *ws_max_messages_per_second)
+                .with_shutdown_drain(
+                    std::time::Duration::from_secs(*shutdown_drain_seconds),
+                    shutdown_retry_after_seconds.map(std::time::Duration::from_secs),
+                )
+                .with_ttl_reap_interval(std::time::Duration::from_secs(*ttl_reap_interval_seconds))
+                .with_snapshot_backup(*snapshot_backup)
+                .with_snapshot_shards(*snapshot_shard_bytes)
+                .with_persist_retry_policy(PersistRetryPolicy {
+                    max_retries: *persist_max_retries,
+                    reject_writes_after: persist_reject_writes_after_seconds
+                        .map(std::time::Duration::from_secs),
+                })
+                .with_shutdown_persistence(
+                    *shutdown_persist_concurrency,
+                    shutdown_persist_deadline_seconds.map(std::time::Duration::from_secs),
+                );
+
+            let server = if *update_log {
+                server
+                    .with_update_log(UpdateLogConfig {
+                        snapshot_every: *snapshot_every,
+                    })
+                    .with_compaction(
+                        std::time::Duration::from_secs(*compaction_interval_seconds),
+                        *compaction_min_segments,
+                    )
+            } else {
+                server
+            };
+
+            let server = if *follower {
+                server.with_follower(std::time::Duration::from_secs(*follower_refresh_seconds))
+            } else {
+                server
+            };
+
+            let server = if let Some(wal_dir) = wal_dir {
+                server.with_wal(Wal::open(wal_dir.clone())?)
+            } else {
+                server
+            };
+
+            let server = server
+                .with_doc_gc_policy(DocGcPolicy {
+                    idle_timeout: doc_idle_timeout_seconds.map(std::time::Duration::from_secs),
+                    min_residency: std::time::Duration::from_secs(*doc_min_residency_seconds),
+                })
+                .with_pinned_docs(pinned_docs.iter().cloned())
+                .with_preload(preload);
+
+            let server = if let Some(memory_budget_mb) = memory_budget_mb {
+                server.with_memory_budget(memory_budget_mb * 1024 * 1024)
+            } else {
+                server
+            };
+
+            let server = if let Some(doc_lease_seconds) = doc_lease_seconds {
+                server.with_doc_leases(std::time::Duration::from_secs(*doc_lease_seconds))
+            } else {
+                server
+            };
+
+            let server = if let (Some(node_id), Some(nodes)) = (cluster_node_id, cluster_nodes) {
+                let cluster = Cluster::new(node_id, ClusterNode::parse_list(nodes)?)?;
+                server.with_cluster(cluster)
+            } else {
+                server
+            };
+
+            let server = if let Some(template) = client_url_template {
+                let template = ClientUrlTemplate::new(template, cluster_node_id.clone())?;
+                server.with_client_url_template(template)
+            } else {
+                server
+            };
+
+            let server = server.with_doc_id_generator(DocIdGenerator::nanoid(
+                *doc_id_length,
+                doc_id_alphabet.as_deref(),
+                doc_id_prefix,
+            )?);
+
+            #[cfg(feature = "config-files")]
+            let server = if let Some(tenants) = tenants {
+                let configs = load_tenants(tenants)?;
+                server.with_tenants(open_tenants(&configs, store_path).await?)?
+            } else {
+                server
+            };
+
+            #[cfg(feature = "config-files")]
+            let server = if let Some(quotas) = quotas {
+                server.with_quotas(load_quotas(quotas)?)
+            } else {
+                server
+            };
+
+            let server = if *audit { server.with_audit() } else { server };
+
+            let server = if let Some(doc_stats_flush) = doc_stats_flush {
+                server.with_doc_stats_flush(*doc_stats_flush)
+            } else {
+                server
+            };
+
+            #[cfg(feature = "assets")]
+            let server = if let Some(asset_webhook_url) = asset_webhook_url {
+                let hook = WebhookAssetHook::new(asset_webhook_url.clone());
+                server.with_asset_hooks(AssetHooks::new(hook).with_retries(*asset_hook_retries))
+            } else {
+                server
+            };
+            #[cfg(feature = "assets")]
+            let server = if let Some(secret) = asset_events_secret {
+                server.with_asset_events_secret(secret.clone())
+            } else {
+                server
+            };
+
+            #[cfg(feature = "search")]
+            let server = if let Some(search_index_dir) = search_index_dir {
+                let index = TantivySearchIndex::open(search_index_dir).with_context(|| {
+                    format!("Failed to open the search index in {:?}", search_index_dir)
+                })?;
+                server.with_search_index(index)
+            } else {
+                server
+            };
 
-    // Logging: default WARN, override via Y_SWEET_LOG (e.g. "info", "debug", "trace" or full filter spec)
-    // Example: Y_SWEET_LOG=y_sweet=debug,y_sweet_core=info,hyper=warn
-    let filter = if let Ok(spec) = std::env::var("Y_SWEET_LOG") {
-        EnvFilter::new(spec)
-    } else {
-        EnvFilter::new("warn")
-    };
-    let _ddtrace_guard = init_tracing(filter)?;
+            let server = if trusted_proxies.is_empty() {
+                server
+            } else {
+                server.with_trusted_proxies(TrustedProxies::parse(trusted_proxies)?)
+            };
 
-    match &opts.subcmd {
-        ServSubcommand::Serve {
-            port,
-            host,
-            checkpoint_freq_seconds,
-            store,
-            auth,
-            url_prefix,
-            prod,
-            max_body_size,
-            skip_gc,
-        } => {
-            let auth = if let Some(auth) = auth {
-                Some(Authenticator::new(auth)?)
+            let server = server.with_slow_thresholds(SlowThresholds {
+                request: slow_request_ms.map(std::time::Duration::from_millis),
+                message: slow_message_ms.map(std::time::Duration::from_millis),
+                persist: slow_persist_ms.map(std::time::Duration::from_millis),
+            });
+
+            let server = if let Some(admin_port) = admin_port {
+                let admin_addr = SocketAddr::new(admin_host.unwrap_or(addr.ip()), *admin_port);
+                let admin_listener = TcpListener::bind(admin_addr).await?;
+                server.with_admin_listener(admin_listener)
             } else {
-                tracing::warn!(
-                    message = "No auth key set. Only use this for local development!",
-                    event = "auth_warning"
-                );
-                None
+                server
             };
 
-            let addr = SocketAddr::new(
-                host.unwrap_or(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))),
-                *port,
-            );
+            #[cfg(feature = "grpc")]
+            let server = if let Some(grpc_port) = grpc_port {
+                let grpc_listener =
+                    TcpListener::bind(SocketAddr::new(addr.ip(), *grpc_port)).await?;
+                server.with_grpc_listener(grpc_listener)
+            } else {
+                server
+            };
 
-            let listener = TcpListener::bind(addr).await?;
-            let addr = listener.local_addr()?;
+            #[cfg(feature = "webtransport")]
+            let server = if *webtransport {
+                let Some(Tls::Files { cert, key }) = &tls else {
+                    anyhow::bail!("WebTransport requires --tls-cert and --tls-key");
+                };
+                server.with_webtransport(y_sweet::webtransport_ext::WebTransport {
+                    addr,
+                    cert: cert.clone(),
+                    key: key.clone(),
+                })
+            } else {
+                server
+            };
 
-            let store = if let Some(store) = store {
-                let store = get_store_from_opts(store).await?;
-                store.init().await?;
-                Some(store)
+            #[cfg(feature = "redis")]
+            let server = if let Some(redis_url) = redis_url {
+                let broker = y_sweet::redis_broker_ext::RedisBroker::new(
+                    redis_url,
+                    redis_channel_prefix.clone(),
+                )?;
+                server.with_replication(Replication::new(std::sync::Arc::new(broker)))
             } else {
-                tracing::warn!(
-                    message = "No store set. Documents will be stored in memory only.",
-                    event = "store_warning"
-                );
-                None
+                server
             };
 
-            if !prod {
-                print_server_url(auth.as_ref(), url_prefix.as_ref(), addr);
-            }
+            #[cfg(feature = "nats")]
+            let server = if let Some(nats_url) = nats_url {
+                let broker = y_sweet::nats_broker_ext::NatsBroker::connect(
+                    nats_url,
+                    nats_subject_prefix.clone(),
+                    nats_stream.clone(),
+                )
+                .await?;
+                server.with_replication(Replication::new(std::sync::Arc::new(broker)))
+            } else {
+                server
+            };
 
-            let token = CancellationToken::new();
+            let scheme = if tls.is_some() { "wss" } else { "ws" };
+            let server = match tls {
+                Some(tls) => server.with_tls(tls),
+                None => server,
+            };
 
-            let server = y_sweet::server::Server::new(
-                store,
-                std::time::Duration::from_secs(*checkpoint_freq_seconds),
-                auth,
-                url_prefix.clone(),
-                token.clone(),
-                true,
-                *max_body_size,
-                *skip_gc,
-            )
-            .await?;
+            #[cfg(feature = "config-files")]
+            let server = if let Some(config) = config {
+                server.with_config_path(config.clone())
+            } else {
+                server
+            };
 
+            let shutdown_persistence = server.shutdown_persistence();
+            let readiness = server.readiness();
             let prod = *prod;
             let handle = tokio::spawn(async move {
                 server.serve(listener, prod).await.unwrap();
             });
 
+            let listening_on = listening_on.unwrap_or_else(|| format!("{}://{}", scheme, addr));
             tracing::info!(
-                message = format!("Listening on ws://{}", addr),
+                message = format!("Listening on {}", listening_on),
                 event = "server_started",
-                address = %addr
+                address = %listening_on
             );
 
             tokio::signal::ctrl_c()
                 .await
                 .expect("Failed to install CTRL+C signal handler");
 
+            drain_before_shutdown(&readiness, *shutdown_delay_seconds).await;
             tracing::info!(
                 message = "Shutting down.",
                 event = "server_shutdown_started"
             );
             token.cancel();
 
-            handle.await?;
+            let timeout = shutdown_timeout_seconds.map(std::time::Duration::from_secs);
+            if !wait_for_shutdown(handle, timeout, &shutdown_persistence).await? {
+                std::process::exit(1);
+            }
             tracing::info!(
                 message = "Server shut down.",
                 event = "server_shutdown_completed"
@@ -274,6 +1720,38 @@ async fn main() -> Result<()> {
                 print_auth_message(&auth);
             }
         }
+        ServSubcommand::GenDocToken {
+            doc_id,
+            auth,
+            valid_for,
+            auth_key,
+            json,
+        } => {
+            if !validate_doc_name(doc_id) {
+                anyhow::bail!("Invalid document ID {:?}", doc_id);
+            }
+            let authenticator = Authenticator::new(auth_key)?;
+            let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+            let expires_at = now.as_millis() as u64 + valid_for * 1000;
+            let authorization = Authorization::from(*auth);
+            let token = authenticator.gen_doc_token(
+                doc_id,
+                authorization,
+                ExpirationTimeEpochMillis(expires_at),
+            );
+
+            if *json {
+                let result = json!({
+                    "docId": doc_id,
+                    "token": token,
+                    "authorization": authorization,
+                    "expiresAt": expires_at,
+                });
+                println!("{}", serde_json::to_string_pretty(&result)?);
+            } else {
+                println!("{}", token);
+            }
+        }
         ServSubcommand::ConvertFromUpdate { store, doc_id } => {
             let store = get_store_from_opts(store).await?;
             store.init().await?;
@@ -284,6 +1762,535 @@ async fn main() -> Result<()> {
 
             y_sweet::convert::convert(store, &buf, doc_id).await?;
         }
+        #[cfg(feature = "leveldb")]
+        ServSubcommand::ImportLeveldb {
+            path,
+            store,
+            prefix,
+            skip_existing,
+            json,
+        } => {
+            let store: Arc<Box<dyn Store>> = Arc::new(get_store_from_opts(store).await?);
+            store.init().await?;
+
+            let report = import_leveldb(path, &store, prefix, *skip_existing, |outcome, done| {
+                if !*json {
+                    print_import_outcome(outcome, done);
+                }
+            })
+            .await?;
+            print_import_report(&report, *json)?;
+        }
+        #[cfg(feature = "hocuspocus")]
+        ServSubcommand::ImportHocuspocus {
+            path,
+            store,
+            prefix,
+            skip_existing,
+            json,
+        } => {
+            let store: Arc<Box<dyn Store>> = Arc::new(get_store_from_opts(store).await?);
+            store.init().await?;
+
+            let report =
+                import_hocuspocus(path, &store, prefix, *skip_existing, |outcome, done| {
+                    if !*json {
+                        print_import_outcome(outcome, done);
+                    }
+                })
+                .await?;
+            print_import_report(&report, *json)?;
+        }
+        #[cfg(feature = "hocuspocus")]
+        ServSubcommand::ExportHocuspocus {
+            path,
+            store,
+            prefix,
+            json,
+        } => {
+            let store: Arc<Box<dyn Store>> = Arc::new(get_store_from_opts(store).await?);
+            store.init().await?;
+
+            let doc_ids = store.list_documents(prefix).await?;
+            let total = doc_ids.len();
+            let report = export_hocuspocus(&store, doc_ids, path, |outcome, done| {
+                if *json {
+                    return;
+                }
+                let status = match &outcome.error {
+                    Some(error) => format!("FAILED  {}", error),
+                    None => format!("{} bytes", outcome.bytes),
+                };
+                println!("[{}/{}] {}  {}", done, total, outcome.doc_id, status);
+            })
+            .await?;
+
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                println!(
+                    "{} documents exported to {}, {} failed",
+                    report.exported,
+                    path.display(),
+                    report.failed
+                );
+            }
+
+            if report.failed > 0 {
+                anyhow::bail!("{} documents failed to export", report.failed);
+            }
+        }
+        ServSubcommand::Doc {
+            command:
+                DocSubcommand::Verify {
+                    store,
+                    doc_id,
+                    prefix,
+                    json,
+                },
+        } => {
+            let store: Arc<Box<dyn Store>> = Arc::new(get_store_from_opts(store).await?);
+            store.init().await?;
+
+            let doc_ids = match (doc_id, prefix) {
+                (Some(doc_id), _) => vec![doc_id.clone()],
+                (None, Some(prefix)) => store.list_documents(prefix).await?,
+                (None, None) => unreachable!("clap requires a document ID or a prefix"),
+            };
+            let response = verify_docs(&store, doc_ids).await;
+
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&response)?);
+            } else {
+                for result in &response.results {
+                    match (&result.stats, &result.error) {
+                        (Some(stats), _) => println!(
+                            "ok       {}  {} bytes stored, {} clients, {} elements, roots: {}",
+                            result.doc_id,
+                            result.snapshot_bytes.unwrap_or_default(),
+                            stats.clients,
+                            stats.elements,
+                            stats.root_types.join(", ")
+                        ),
+                        (None, error) => println!(
+                            "INVALID  {}  {}",
+                            result.doc_id,
+                            error.as_deref().unwrap_or_default()
+                        ),
+                    }
+                }
+                println!(
+                    "{} documents verified, {} invalid",
+                    response.results.len(),
+                    response.invalid
+                );
+            }
+
+            if response.invalid > 0 {
+                anyhow::bail!("{} documents failed verification", response.invalid);
+            }
+        }
+        #[cfg(feature = "client")]
+        ServSubcommand::Doc {
+            command:
+                DocSubcommand::Tail {
+                    url,
+                    doc_id,
+                    token,
+                    content,
+                },
+        } => {
+            let url = socket_url(url, doc_id, token.as_deref())?;
+            tail(&url, *content).await?;
+        }
+        ServSubcommand::Doc {
+            command:
+                DocSubcommand::Create {
+                    doc_id,
+                    from,
+                    prosemirror_root,
+                    store,
+                    #[cfg(feature = "client")]
+                    server,
+                    #[cfg(feature = "client")]
+                    server_token,
+                },
+        } => {
+            let update = read_doc_file(from, prosemirror_root.as_deref())?;
+            #[cfg(feature = "client")]
+            if let Some(server) = server {
+                create_doc_on_server(server, server_token.as_deref(), doc_id, update).await?;
+                println!("Created document {}", doc_id);
+                return Ok(());
+            }
+            if let Some(store) = store {
+                let store = get_store_from_opts(store).await?;
+                store.init().await?;
+                create_doc_in_store(store, doc_id, &update).await?;
+            }
+            println!("Created document {}", doc_id);
+        }
+        ServSubcommand::Doc {
+            command:
+                DocSubcommand::Inspect {
+                    store,
+                    doc_id,
+                    file,
+                    json,
+                },
+        } => {
+            let inspection = match (file, doc_id, store) {
+                (Some(file), _, _) => inspect_file(file).await?,
+                (None, Some(doc_id), Some(store)) => {
+                    let store: Arc<Box<dyn Store>> = Arc::new(get_store_from_opts(store).await?);
+                    store.init().await?;
+                    inspect_doc(&store, doc_id).await?
+                }
+                (None, Some(_), None) => {
+                    anyhow::bail!("A store is required to inspect a document by ID")
+                }
+                (None, None, _) => unreachable!("clap requires a document ID or a file"),
+            };
+
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&inspection)?);
+            } else {
+                println!("Document      {}", inspection.doc_id);
+                if let Some(snapshot_bytes) = inspection.snapshot_bytes {
+                    match inspection.shards {
+                        Some(shards) => println!(
+                            "Snapshot      {} bytes, {} shards of {} bytes",
+                            snapshot_bytes, shards, inspection.shard_bytes
+                        ),
+                        None => println!("Snapshot      {} bytes", snapshot_bytes),
+                    }
+                }
+                if inspection.update_log_segments > 0 {
+                    println!(
+                        "Update log    {} segments, {} bytes",
+                        inspection.update_log_segments, inspection.update_log_bytes
+                    );
+                }
+                println!("Encoded       {} bytes", inspection.update_bytes);
+                println!("State vector  {}", inspection.state_vector);
+                println!("Clients       {}", inspection.clients.len());
+                for client in &inspection.clients {
+                    println!("  {:>20}  clock {}", client.client, client.clock);
+                }
+                println!("Root types    {}", inspection.root_types.len());
+                for root in &inspection.root_types {
+                    println!(
+                        "  {:<20}  {:<5}  {} items, {} bytes as JSON",
+                        root.name, root.kind, root.len, root.json_bytes
+                    );
+                }
+            }
+        }
+        ServSubcommand::Doc {
+            command:
+                DocSubcommand::Gc {
+                    store,
+                    doc_id,
+                    dry_run,
+                    json,
+                },
+        } => {
+            let store: Arc<Box<dyn Store>> = Arc::new(get_store_from_opts(store).await?);
+            store.init().await?;
+
+            if !store.exists(&snapshot_key(doc_id)).await? {
+                anyhow::bail!("Document {} does not exist", doc_id);
+            }
+            let response = collect_stored_garbage(&store, doc_id, *dry_run, false).await?;
+
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&response)?);
+            } else {
+                println!(
+                    "{} {} bytes of {} ({} bytes before, {} bytes after)",
+                    if *dry_run { "Would save" } else { "Saved" },
+                    response.saved_bytes,
+                    doc_id,
+                    response.bytes_before,
+                    response.bytes_after
+                );
+            }
+        }
+        #[cfg(feature = "backup")]
+        ServSubcommand::Backup {
+            store,
+            out,
+            prefix,
+            json,
+        } => {
+            let store: Arc<Box<dyn Store>> = Arc::new(get_store_from_opts(store).await?);
+            store.init().await?;
+
+            let doc_ids = store.list_documents(prefix).await?;
+            let total = doc_ids.len();
+            let manifest = write_backup(&store, doc_ids, out, |doc, done| {
+                if !*json {
+                    let bytes: usize = doc.objects.iter().map(|object| object.bytes).sum();
+                    println!(
+                        "[{}/{}] {}  {} objects, {} bytes",
+                        done,
+                        total,
+                        doc.doc_id,
+                        doc.objects.len(),
+                        bytes
+                    );
+                }
+            })
+            .await?;
+
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&manifest)?);
+            } else {
+                println!(
+                    "{} documents backed up to {}",
+                    manifest.documents.len(),
+                    out.display()
+                );
+            }
+        }
+        #[cfg(feature = "backup")]
+        ServSubcommand::Restore {
+            store,
+            input,
+            overwrite,
+            json,
+        } => {
+            let store: Arc<Box<dyn Store>> = Arc::new(get_store_from_opts(store).await?);
+            store.init().await?;
+
+            let report = restore_backup(&store, input, *overwrite).await?;
+
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                for doc_id in &report.skipped {
+                    println!("skipped  {}  already exists", doc_id);
+                }
+                println!(
+                    "{} documents restored ({} objects, {} bytes), {} skipped",
+                    report.restored.len(),
+                    report.objects,
+                    report.bytes,
+                    report.skipped.len()
+                );
+            }
+        }
+        ServSubcommand::ExportAll {
+            store,
+            format,
+            out,
+            prefix,
+            modified_since,
+            json,
+        } => {
+            let store: Arc<Box<dyn Store>> = Arc::new(get_store_from_opts(store).await?);
+            store.init().await?;
+
+            let doc_ids = store.list_documents(prefix).await?;
+            let total = doc_ids.len();
+            let report = export_all(
+                &store,
+                doc_ids,
+                out,
+                *format,
+                modified_since.map(|seconds| seconds * 1000),
+                |outcome, done| {
+                    if *json {
+                        return;
+                    }
+                    let status = match (&outcome.error, outcome.skipped) {
+                        (Some(error), _) => format!("FAILED  {}", error),
+                        (None, true) => "skipped, not modified since".to_string(),
+                        (None, false) => format!("{} bytes", outcome.bytes),
+                    };
+                    println!("[{}/{}] {}  {}", done, total, outcome.doc_id, status);
+                },
+            )
+            .await?;
+
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                println!(
+                    "{} documents exported to {}, {} skipped, {} failed",
+                    report.exported,
+                    out.display(),
+                    report.skipped,
+                    report.failed
+                );
+            }
+
+            if report.failed > 0 {
+                anyhow::bail!("{} documents failed to export", report.failed);
+            }
+        }
+        ServSubcommand::CopyStore {
+            from,
+            to,
+            prefix,
+            concurrency,
+            skip_existing,
+            no_verify,
+            dry_run,
+            json,
+        } => {
+            let from: Arc<Box<dyn Store>> = Arc::new(open_store(from, "Y_SWEET_FROM_").await?);
+            from.init().await?;
+            let to: Arc<Box<dyn Store>> = Arc::new(open_store(to, "Y_SWEET_TO_").await?);
+            to.init().await?;
+
+            let doc_ids = from.list_documents(prefix).await?;
+            let total = doc_ids.len();
+            let options = StoreCopyOptions {
+                concurrency: *concurrency,
+                verify: !*no_verify,
+                skip_existing: *skip_existing,
+                dry_run: *dry_run,
+            };
+            let report = copy_store(&from, &to, doc_ids, options, |outcome, done| {
+                if *json {
+                    return;
+                }
+                let status = match (&outcome.error, outcome.skipped) {
+                    (Some(error), _) => format!("FAILED  {}", error),
+                    (None, true) => "skipped, already in the destination".to_string(),
+                    (None, false) if *dry_run => {
+                        format!("would write {} objects", outcome.objects)
+                    }
+                    (None, false) => {
+                        format!("{} objects, {} bytes", outcome.objects, outcome.bytes)
+                    }
+                };
+                println!("[{}/{}] {}  {}", done, total, outcome.doc_id, status);
+                for key in outcome.written_objects.iter().flatten() {
+                    println!("    {}", key);
+                }
+            })
+            .await;
+
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else if *dry_run {
+                println!(
+                    "{} documents would be copied, {} skipped, {} failed",
+                    report.copied, report.skipped, report.failed
+                );
+            } else {
+                println!(
+                    "{} documents copied ({} bytes), {} skipped, {} failed",
+                    report.copied, report.bytes, report.skipped, report.failed
+                );
+            }
+
+            if report.failed > 0 {
+                anyhow::bail!("{} documents failed to copy", report.failed);
+            }
+        }
+        #[cfg(feature = "client")]
+        ServSubcommand::Bench {
+            url,
+            docs,
+            clients_per_doc,
+            ops_rate,
+            duration_seconds,
+            auth,
+            doc_prefix,
+            server_pid,
+            json,
+        } => {
+            if *ops_rate <= 0.0 {
+                anyhow::bail!("--ops-rate must be positive");
+            }
+            let auth = auth.as_deref().map(Authenticator::new).transpose()?;
+            let report = run_bench(BenchOptions {
+                url: url.clone(),
+                docs: *docs,
+                clients_per_doc: *clients_per_doc as usize,
+                ops_rate: *ops_rate,
+                duration: std::time::Duration::from_secs(*duration_seconds),
+                auth,
+                doc_prefix: doc_prefix.clone(),
+                server_pid: *server_pid,
+            })
+            .await?;
+
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                println!(
+                    "{} clients on {} documents, {} failed",
+                    report.clients, report.docs, report.failed_clients
+                );
+                println!(
+                    "Sent       {} edits ({:.1}/s)",
+                    report.sent, report.sent_per_second
+                );
+                println!(
+                    "Delivered  {} updates, {} dropped",
+                    report.delivered, report.dropped
+                );
+                println!(
+                    "Latency    p50 {:.1} ms, p90 {:.1} ms, p99 {:.1} ms, max {:.1} ms",
+                    report.latency.p50_ms,
+                    report.latency.p90_ms,
+                    report.latency.p99_ms,
+                    report.latency.max_ms
+                );
+                if let Some(server) = &report.server {
+                    println!(
+                        "Server     {:.1} CPU seconds, RSS {} -> {} bytes (peak {})",
+                        server.cpu_seconds,
+                        server.rss_start_bytes,
+                        server.rss_end_bytes,
+                        server.rss_peak_bytes
+                    );
+                }
+            }
+        }
+        #[cfg(feature = "config-files")]
+        ServSubcommand::Config {
+            command: ConfigSubcommand::Validate { config, json },
+        } => {
+            let file = load_config(config)?;
+            let command = Opts::command();
+            let serve = command
+                .find_subcommand("serve")
+                .expect("serve is a subcommand");
+            let resolved = resolve_config(&file, |key| {
+                let arg = serve.get_arguments().find(|arg| arg.get_id() == key)?;
+                let defaults: Vec<String> = arg
+                    .get_default_values()
+                    .iter()
+                    .map(|value| value.to_string_lossy().into_owned())
+                    .collect();
+                (!defaults.is_empty()).then(|| defaults.join(","))
+            });
+
+            // Parse the options the way `serve` would, to check their values
+            apply_config(&file);
+            if let Err(e) = Opts::try_parse_from(["y-sweet", "serve"]) {
+                anyhow::bail!("Invalid configuration: {}", e);
+            }
+
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&resolved)?);
+            } else {
+                for option in &resolved {
+                    match &option.value {
+                        Some(value) => {
+                            println!("{} = {}  ({})", option.key, value, option.source)
+                        }
+                        None => println!("{}  (unset)", option.key),
+                    }
+                }
+                println!("Configuration file {:?} is valid", config);
+            }
+        }
         ServSubcommand::Version => {
             println!("{}", VERSION);
         }
@@ -291,8 +2298,29 @@ async fn main() -> Result<()> {
             port,
             host,
             checkpoint_freq_seconds,
+            checkpoint_max_updates,
+            checkpoint_max_bytes,
+            snapshot_backup,
+            snapshot_shard_bytes,
             max_body_size,
+            max_update_body_size,
+            max_import_body_size,
+            max_asset_body_size,
             skip_gc,
+            disable_compression,
+            ws_ping_interval_seconds,
+            ws_idle_timeout_seconds,
+            ws_send_buffer,
+            slow_consumer_policy,
+            ws_max_message_size,
+            ws_max_messages_per_second,
+            shutdown_delay_seconds,
+            shutdown_drain_seconds,
+            shutdown_retry_after_seconds,
+            shutdown_persist_deadline_seconds,
+            shutdown_timeout_seconds,
+            max_connections,
+            tls,
         } => {
             let doc_id = env::var("SESSION_BACKEND_KEY").expect("SESSION_BACKEND_KEY must be set");
 
@@ -317,9 +2345,7 @@ async fn main() -> Result<()> {
                     None
                 };
 
-                let s3_config = parse_s3_config_from_env_and_args(bucket, prefix)?;
-                let store = S3Store::new(s3_config).await?;
-                let store: Box<dyn Store> = Box::new(store);
+                let store = open_s3_store(bucket, prefix, "").await?;
                 store.init().await?;
                 Some(store)
             } else {
@@ -331,17 +2357,42 @@ async fn main() -> Result<()> {
             };
 
             let cancellation_token = CancellationToken::new();
-            let server = y_sweet::server::Server::new(
-                store,
-                std::time::Duration::from_secs(*checkpoint_freq_seconds),
-                None, // No authenticator
-                None, // No URL prefix
-                cancellation_token.clone(),
-                false,
-                *max_body_size,
-                *skip_gc,
-            )
-            .await?;
+            let server = y_sweet::server::Server::builder()
+                .store(store)
+                .checkpoint_freq(std::time::Duration::from_secs(*checkpoint_freq_seconds))
+                .cancellation_token(cancellation_token.clone())
+                .doc_gc(false)
+                .max_body_size(*max_body_size)
+                .body_limits(BodyLimits {
+                    update: *max_update_body_size,
+                    import: *max_import_body_size,
+                    assets: *max_asset_body_size,
+                })
+                .skip_gc(*skip_gc)
+                .checkpoint_triggers(CheckpointTriggers {
+                    max_pending_updates: *checkpoint_max_updates,
+                    max_pending_bytes: *checkpoint_max_bytes,
+                })
+                .connection_limits(*max_connections, None)
+                .build()
+                .await?
+                .with_compression(!*disable_compression)
+                .with_ws_keepalive(
+                    std::time::Duration::from_secs(*ws_ping_interval_seconds),
+                    std::time::Duration::from_secs(*ws_idle_timeout_seconds),
+                )
+                .with_slow_consumer_policy(*ws_send_buffer, *slow_consumer_policy)
+                .with_message_limits(*ws_max_message_size, *ws_max_messages_per_second)
+                .with_shutdown_drain(
+                    std::time::Duration::from_secs(*shutdown_drain_seconds),
+                    shutdown_retry_after_seconds.map(std::time::Duration::from_secs),
+                )
+                .with_snapshot_backup(*snapshot_backup)
+                .with_snapshot_shards(*snapshot_shard_bytes)
+                .with_shutdown_persistence(
+                    DEFAULT_SHUTDOWN_PERSIST_CONCURRENCY,
+                    shutdown_persist_deadline_seconds.map(std::time::Duration::from_secs),
+                );
 
             // Load the one document we're operating with
             server
@@ -357,12 +2408,21 @@ async fn main() -> Result<()> {
             let listener = TcpListener::bind(addr).await?;
             let addr = listener.local_addr()?;
 
-            tokio::spawn(async move {
+            let tls = tls.tls();
+            let scheme = if tls.is_some() { "wss" } else { "ws" };
+            let server = match tls {
+                Some(tls) => server.with_tls(tls),
+                None => server,
+            };
+
+            let shutdown_persistence = server.shutdown_persistence();
+            let readiness = server.readiness();
+            let handle = tokio::spawn(async move {
                 server.serve_doc(listener, false).await.unwrap();
             });
 
             tracing::info!(
-                message = format!("Listening on ws://{}", addr),
+                message = format!("Listening on {}://{}", scheme, addr),
                 event = "doc_server_started",
                 address = %addr
             );
@@ -400,12 +2460,19 @@ async fn main() -> Result<()> {
                 }
             }
 
+            drain_before_shutdown(&readiness, *shutdown_delay_seconds).await;
             cancellation_token.cancel();
             tracing::info!(
                 message = "Shutting down.",
                 event = "doc_server_shutdown_started"
             );
 
+            // Wait for connections to drain
+            let timeout = shutdown_timeout_seconds.map(std::time::Duration::from_secs);
+            if !wait_for_shutdown(handle, timeout, &shutdown_persistence).await? {
+                std::process::exit(1);
+            }
+
             tracing::info!(
                 message = "Server shut down.",
                 event = "doc_server_shutdown_completed"
@@ -0,0 +1,231 @@
+//! Eviction of loaded documents to stay within a memory budget.
+//!
+//! The GC worker of a document only unloads it after a while without connections, so
+//! touching many large documents in a short window can exhaust memory long before any of
+//! them is collected. With a budget, the memory of loaded documents is checked whenever a
+//! document is loaded and periodically: while it is over budget, the least recently used
+//! documents without connections are persisted and unloaded. Pinned documents are never
+//! unloaded.
+//!
+//! The memory of a document is approximated by the size of its stored state, which is what
+//! a loaded document keeps in memory besides its Yrs structures.
+
+use dashmap::DashMap;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+use crate::server::Server;
+
+/// Default interval between memory budget checks, besides the checks after loading a doc.
+pub const DEFAULT_MEMORY_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+pub struct MemoryBudget {
+    budget_bytes: usize,
+    last_used: DashMap<String, Instant>,
+    check: Notify,
+}
+
+impl MemoryBudget {
+    pub fn new(budget_bytes: usize) -> Self {
+        Self {
+            budget_bytes,
+            last_used: DashMap::new(),
+            check: Notify::new(),
+        }
+    }
+
+    pub fn budget_bytes(&self) -> usize {
+        self.budget_bytes
+    }
+
+    /// Record a use of a document.
+    pub fn touch(&self, doc_id: &str) {
+        self.last_used.insert(doc_id.to_string(), Instant::now());
+    }
+
+    /// Record the load of a document, which may take memory over budget.
+    pub(crate) fn loaded(&self, doc_id: &str) {
+        self.touch(doc_id);
+        self.check.notify_one();
+    }
+}
+
+struct LoadedDoc {
+    doc_id: String,
+    size: usize,
+    idle: bool,
+    last_used: Option<Instant>,
+}
+
+/// Approximate memory used by the loaded documents, in bytes.
+pub fn loaded_docs_memory(server: &Server) -> usize {
+    server
+        .docs
+        .iter()
+        .map(|doc| doc.sync_kv().size_bytes())
+        .sum()
+}
+
+/// Persist and unload the least recently used idle documents until the loaded documents
+/// fit in the budget. Returns the number of unloaded documents.
+pub async fn enforce_memory_budget(server: &Server, budget: &MemoryBudget) -> usize {
+    // Collect first, so no map guard is held across awaits
+    let mut loaded: Vec<LoadedDoc> = server
+        .docs
+        .iter()
+        .map(|doc| LoadedDoc {
+            doc_id: doc.key().clone(),
+            size: doc.sync_kv().size_bytes(),
+            // Connections hold a reference to the awareness
+            idle: Arc::strong_count(&doc.awareness()) <= 2,
+            last_used: budget.last_used.get(doc.key()).map(|t| *t),
+        })
+        .collect();
+    budget
+        .last_used
+        .retain(|doc_id, _| server.docs.contains_key(doc_id));
+
+    let mut total: usize = loaded.iter().map(|doc| doc.size).sum();
+    if total <= budget.budget_bytes {
+        return 0;
+    }
+
+    loaded.retain(|doc| doc.idle && !server.pins().is_pinned(&doc.doc_id));
+    // Documents with no recorded use sort first
+    loaded.sort_by_key(|doc| doc.last_used);
+
+    let mut evicted = 0;
+    for doc in loaded {
+        if total <= budget.budget_bytes {
+            break;
+        }
+        let Some(sync_kv) = server.docs.get(&doc.doc_id).map(|d| d.sync_kv()) else {
+            continue;
+        };
+
+        // Followers never persist
+        if !server.is_follower() {
+            let error = sync_kv.persist().await.err().map(|e| e.to_string());
+            if let Some(error) = error {
+                error!(
+                    message = "Failed to persist document before evicting it",
+                    event = "doc_eviction_failed",
+                    doc_id = %doc.doc_id,
+                    error = %error
+                );
+                continue;
+            }
+        }
+
+        sync_kv.shutdown();
+        server.docs.remove(&doc.doc_id);
+        budget.last_used.remove(&doc.doc_id);
+        if let Some(handler) = server.event_handler() {
+            handler.doc_evicted(&doc.doc_id);
+        }
+        total = total.saturating_sub(doc.size);
+        evicted += 1;
+        info!(
+            message = "Evicted document to stay within the memory budget",
+            event = "doc_evicted",
+            doc_id = %doc.doc_id,
+            size = doc.size
+        );
+    }
+
+    if total > budget.budget_bytes {
+        warn!(
+            message = "Loaded documents exceed the memory budget, but none is idle",
+            event = "memory_budget_exceeded",
+            memory_bytes = total,
+            budget_bytes = budget.budget_bytes
+        );
+    }
+    evicted
+}
+
+/// Enforce the memory budget after documents are loaded, and periodically, until the
+/// server shuts down
+pub(crate) async fn memory_budget_worker(
+    server: Arc<Server>,
+    budget: Arc<MemoryBudget>,
+    interval: Duration,
+    cancellation_token: CancellationToken,
+) {
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = budget.check.notified() => {}
+            _ = cancellation_token.cancelled() => {
+                break;
+            }
+        }
+        enforce_memory_budget(&server, &budget).await;
+    }
+    tracing::debug!("Exiting memory budget loop");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stores::filesystem::FileSystemStore;
+    use y_sweet_core::store::Store;
+    use yrs::{GetString, Text, Transact};
+
+    async fn write(server: &Server, budget: &MemoryBudget, doc_id: &str, text: &str) {
+        server.get_or_create_doc(doc_id).await.unwrap();
+        budget.touch(doc_id);
+        let doc = server.docs.get(doc_id).unwrap();
+        let awareness = doc.awareness();
+        let awareness = awareness.write().unwrap();
+        let field = awareness.doc().get_or_insert_text("text");
+        field.insert(&mut awareness.doc().transact_mut(), 0, text);
+    }
+
+    #[tokio::test]
+    async fn test_least_recently_used_idle_docs_are_evicted() {
+        let path = std::env::temp_dir().join(format!("y-sweet-memory-{}", nanoid::nanoid!()));
+        let server = Server::builder()
+            .store(Some(Box::new(FileSystemStore::new(path.clone()).unwrap())))
+            .checkpoint_freq(Duration::from_secs(60))
+            .build()
+            .await
+            .unwrap();
+
+        let large = "x".repeat(4_000);
+        let unbounded = MemoryBudget::new(usize::MAX);
+        for doc_id in ["a", "b", "c"] {
+            write(&server, &unbounded, doc_id, &large).await;
+        }
+        assert_eq!(enforce_memory_budget(&server, &unbounded).await, 0);
+
+        // Room for two of the three documents
+        let memory = loaded_docs_memory(&server);
+        let budget = MemoryBudget::new(memory - memory / 6);
+        for doc_id in ["a", "b", "c"] {
+            budget.touch(doc_id);
+        }
+        // A connection keeps "a" loaded, although it is the least recently used
+        let connection = server.docs.get("a").unwrap().awareness();
+
+        assert_eq!(enforce_memory_budget(&server, &budget).await, 1);
+        assert!(server.docs.contains_key("a"));
+        assert!(!server.docs.contains_key("b"));
+        assert!(server.docs.contains_key("c"));
+        drop(connection);
+
+        // The evicted document was persisted
+        let store = FileSystemStore::new(path).unwrap();
+        assert!(store.exists("b/data.ysweet").await.unwrap());
+        let doc = server.get_or_create_doc("b").await.unwrap();
+        let awareness = doc.awareness();
+        let awareness = awareness.read().unwrap();
+        let field = awareness.doc().get_or_insert_text("text");
+        assert_eq!(field.get_string(&awareness.doc().transact()), large);
+    }
+}
@@ -0,0 +1,144 @@
+use axum::extract::ws::{close_code, CloseFrame};
+use std::time::Instant;
+
+/// Limits on what a single WebSocket client may send.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MessageLimits {
+    /// Largest binary message accepted, in bytes.
+    pub max_message_size: Option<usize>,
+    /// Sustained number of messages accepted per second. Bursts of up to one second's
+    /// worth of messages are allowed.
+    pub max_messages_per_second: Option<u32>,
+}
+
+impl MessageLimits {
+    /// Start tracking the messages of a new connection.
+    pub fn limiter(&self) -> MessageLimiter {
+        MessageLimiter {
+            limits: *self,
+            tokens: self.max_messages_per_second.unwrap_or_default() as f64,
+            last_refill: Instant::now(),
+        }
+    }
+}
+
+/// Why a client's message was rejected.
+#[derive(Debug, PartialEq)]
+pub enum LimitExceeded {
+    MessageTooBig { size: usize, limit: usize },
+    RateExceeded { limit: u32 },
+}
+
+impl LimitExceeded {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            LimitExceeded::MessageTooBig { .. } => "message_too_big",
+            LimitExceeded::RateExceeded { .. } => "message_rate_exceeded",
+        }
+    }
+
+    /// The frame the connection is closed with.
+    pub fn close_frame(&self) -> CloseFrame<'static> {
+        match self {
+            LimitExceeded::MessageTooBig { limit, .. } => CloseFrame {
+                code: close_code::SIZE,
+                reason: format!("message exceeds {} bytes", limit).into(),
+            },
+            LimitExceeded::RateExceeded { limit } => CloseFrame {
+                code: close_code::POLICY,
+                reason: format!("more than {} messages per second", limit).into(),
+            },
+        }
+    }
+}
+
+/// Token bucket enforcing [MessageLimits] on one connection.
+pub struct MessageLimiter {
+    limits: MessageLimits,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl MessageLimiter {
+    /// Account for a message of `size` bytes received now.
+    pub fn check(&mut self, size: usize) -> Result<(), LimitExceeded> {
+        self.check_at(size, Instant::now())
+    }
+
+    fn check_at(&mut self, size: usize, now: Instant) -> Result<(), LimitExceeded> {
+        if let Some(limit) = self.limits.max_message_size {
+            if size > limit {
+                return Err(LimitExceeded::MessageTooBig { size, limit });
+            }
+        }
+
+        if let Some(limit) = self.limits.max_messages_per_second {
+            let elapsed = now.saturating_duration_since(self.last_refill);
+            self.last_refill = now;
+            self.tokens = (self.tokens + elapsed.as_secs_f64() * limit as f64).min(limit as f64);
+            if self.tokens < 1.0 {
+                return Err(LimitExceeded::RateExceeded { limit });
+            }
+            self.tokens -= 1.0;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_message_size_limit() {
+        let limits = MessageLimits {
+            max_message_size: Some(10),
+            max_messages_per_second: None,
+        };
+        let mut limiter = limits.limiter();
+        assert!(limiter.check(10).is_ok());
+        let error = limiter.check(11).err().unwrap();
+        assert_eq!(
+            error,
+            LimitExceeded::MessageTooBig {
+                size: 11,
+                limit: 10
+            }
+        );
+        assert_eq!(error.close_frame().code, close_code::SIZE);
+    }
+
+    #[test]
+    fn test_message_rate_limit() {
+        let limits = MessageLimits {
+            max_message_size: None,
+            max_messages_per_second: Some(2),
+        };
+        let mut limiter = limits.limiter();
+        let start = limiter.last_refill;
+
+        assert!(limiter.check_at(1, start).is_ok());
+        assert!(limiter.check_at(1, start).is_ok());
+        let error = limiter.check_at(1, start).err().unwrap();
+        assert_eq!(error, LimitExceeded::RateExceeded { limit: 2 });
+        assert_eq!(error.close_frame().code, close_code::POLICY);
+
+        // Half a second refills one message
+        assert!(limiter
+            .check_at(1, start + Duration::from_millis(500))
+            .is_ok());
+        assert!(limiter
+            .check_at(1, start + Duration::from_millis(500))
+            .is_err());
+    }
+
+    #[test]
+    fn test_unlimited_by_default() {
+        let mut limiter = MessageLimits::default().limiter();
+        for _ in 0..10_000 {
+            assert!(limiter.check(usize::MAX).is_ok());
+        }
+    }
+}
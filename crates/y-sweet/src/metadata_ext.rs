@@ -0,0 +1,75 @@
+use anyhow::anyhow;
+use axum::http::StatusCode;
+use std::sync::Arc;
+use y_sweet_core::{api_types_ext::DocMetadata, store::Store};
+
+use crate::server::AppError;
+
+/// Metadata is stored as JSON next to the document data (`{doc_id}/metadata.json`),
+/// so it is copied, archived and deleted together with the document.
+pub const METADATA_FILE: &str = "metadata.json";
+
+pub(crate) fn metadata_key(doc_id: &str) -> String {
+    format!("{}/{}", doc_id, METADATA_FILE)
+}
+
+/// Read the metadata of a document. Documents without stored metadata get the default.
+pub async fn read_doc_metadata(
+    store: &Arc<Box<dyn Store>>,
+    doc_id: &str,
+) -> Result<DocMetadata, AppError> {
+    let value = store.get(&metadata_key(doc_id)).await.map_err(|e| {
+        AppError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            anyhow!("Failed to read document metadata: {}", e),
+        )
+    })?;
+
+    let Some(value) = value else {
+        return Ok(DocMetadata::default());
+    };
+
+    serde_json::from_slice(&value).map_err(|e| {
+        AppError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            anyhow!("Malformed metadata for document {}: {}", doc_id, e),
+        )
+    })
+}
+
+/// Replace the metadata of a document
+pub async fn write_doc_metadata(
+    store: &Arc<Box<dyn Store>>,
+    doc_id: &str,
+    metadata: &DocMetadata,
+) -> Result<(), AppError> {
+    let value = serde_json::to_vec(metadata)
+        .map_err(|e| AppError(StatusCode::INTERNAL_SERVER_ERROR, e.into()))?;
+
+    store.set(&metadata_key(doc_id), value).await.map_err(|e| {
+        AppError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            anyhow!("Failed to write document metadata: {}", e),
+        )
+    })
+}
+
+/// Remove the metadata of a document. Returns whether there was any.
+pub async fn remove_doc_metadata(
+    store: &Arc<Box<dyn Store>>,
+    doc_id: &str,
+) -> Result<bool, AppError> {
+    let key = metadata_key(doc_id);
+    let result = match store.exists(&key).await {
+        Ok(true) => store.remove(&key).await.map(|_| true),
+        Ok(false) => Ok(false),
+        Err(e) => Err(e),
+    };
+
+    result.map_err(|e| {
+        AppError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            anyhow!("Failed to remove document metadata: {}", e),
+        )
+    })
+}
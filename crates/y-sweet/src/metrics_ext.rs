@@ -0,0 +1,327 @@
+//! Latency histograms of the server, exposed with its counters in the Prometheus text
+//! format by `GET /metrics`.
+//!
+//! - `y_sweet_update_broadcast_seconds`: from receiving an update, over WebSocket or the
+//!   REST API, until it is applied and broadcast to the connections of the document.
+//! - `y_sweet_persist_seconds`: each attempt to persist a document.
+//! - `y_sweet_doc_load_seconds`: loading a document from the store.
+//! - `y_sweet_first_sync_seconds`: from the WebSocket handshake until the client has been
+//!   sent the state it is missing.
+//!
+//! Each is labelled by the `outcome` of what it measures. With `Y_SWEET_TELEMETRY=otlp`,
+//! the histograms are also exported over OTLP (see `otel_ext`).
+
+use axum::{
+    extract::State,
+    http::{header, HeaderValue},
+    response::{IntoResponse, Response},
+};
+use axum_extra::typed_header::TypedHeader;
+use dashmap::DashMap;
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use y_sweet_core::sync::{MSG_SYNC, MSG_SYNC_STEP_1, MSG_SYNC_STEP_2, MSG_SYNC_UPDATE};
+
+use crate::server::{AppError, Server};
+
+/// Upper bounds, in seconds, of the buckets of every histogram.
+pub const LATENCY_BUCKETS: [f64; 14] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+#[derive(Default)]
+struct HistogramCounts {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+/// A histogram of durations for each outcome.
+pub struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    outcomes: DashMap<&'static str, HistogramCounts>,
+    #[cfg(feature = "otel")]
+    exported: opentelemetry::metrics::Histogram<f64>,
+}
+
+impl Histogram {
+    pub fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            outcomes: DashMap::new(),
+            #[cfg(feature = "otel")]
+            exported: opentelemetry::global::meter(crate::otel_ext::SCOPE)
+                .f64_histogram(name)
+                .with_unit("s")
+                .with_description(help)
+                .with_boundaries(LATENCY_BUCKETS.to_vec())
+                .build(),
+        }
+    }
+
+    pub fn observe(&self, outcome: &'static str, duration: Duration) {
+        #[cfg(feature = "otel")]
+        self.exported.record(
+            duration.as_secs_f64(),
+            &[opentelemetry::KeyValue::new("outcome", outcome)],
+        );
+        let counts = self.outcomes.entry(outcome).or_default();
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|le| seconds <= *le) {
+            counts.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        counts.count.fetch_add(1, Ordering::Relaxed);
+        counts
+            .sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Number of durations observed with `outcome`.
+    pub fn count(&self, outcome: &str) -> u64 {
+        self.outcomes
+            .get(outcome)
+            .map_or(0, |counts| counts.count.load(Ordering::Relaxed))
+    }
+
+    fn render(&self, out: &mut String) {
+        let (name, help) = (self.name, self.help);
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut outcomes: Vec<&'static str> = self.outcomes.iter().map(|e| *e.key()).collect();
+        outcomes.sort();
+        for outcome in outcomes {
+            let Some(counts) = self.outcomes.get(outcome) else {
+                continue;
+            };
+            let mut cumulative = 0;
+            for (le, bucket) in LATENCY_BUCKETS.iter().zip(&counts.buckets) {
+                cumulative += bucket.load(Ordering::Relaxed);
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{{outcome=\"{}\",le=\"{}\"}} {}",
+                    name, outcome, le, cumulative
+                );
+            }
+            let count = counts.count.load(Ordering::Relaxed);
+            let sum = counts.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{outcome=\"{}\",le=\"+Inf\"}} {}",
+                name, outcome, count
+            );
+            let _ = writeln!(out, "{}_sum{{outcome=\"{}\"}} {}", name, outcome, sum);
+            let _ = writeln!(out, "{}_count{{outcome=\"{}\"}} {}", name, outcome, count);
+        }
+    }
+}
+
+/// Latencies of syncing and persisting documents, shared by the servers of every tenant.
+pub struct SyncMetrics {
+    pub update_broadcast: Histogram,
+    pub persist: Histogram,
+    pub doc_load: Histogram,
+    pub first_sync: Histogram,
+}
+
+impl Default for SyncMetrics {
+    fn default() -> Self {
+        Self {
+            update_broadcast: Histogram::new(
+                "y_sweet_update_broadcast_seconds",
+                "Time from receiving an update until it is applied and broadcast.",
+            ),
+            persist: Histogram::new(
+                "y_sweet_persist_seconds",
+                "Time taken by each attempt to persist a document.",
+            ),
+            doc_load: Histogram::new("y_sweet_doc_load_seconds", "Time taken to load a document."),
+            first_sync: Histogram::new(
+                "y_sweet_first_sync_seconds",
+                "Time from a WebSocket handshake until the client is sent the state it is missing.",
+            ),
+        }
+    }
+}
+
+/// Whether a sync protocol message carries an update to apply to the document.
+pub fn is_update_message(msg: &[u8]) -> bool {
+    matches!(msg, [MSG_SYNC, MSG_SYNC_STEP_2 | MSG_SYNC_UPDATE, ..])
+}
+
+/// Whether a sync protocol message asks for the state the client is missing.
+pub fn is_sync_step1_message(msg: &[u8]) -> bool {
+    matches!(msg, [MSG_SYNC, MSG_SYNC_STEP_1, ..])
+}
+
+/// Outcome of a request, by its result.
+pub fn outcome<T>(result: &Result<T, AppError>) -> &'static str {
+    match result {
+        Ok(_) => "ok",
+        Err(AppError(status, _)) if status.is_server_error() => "error",
+        Err(_) => "rejected",
+    }
+}
+
+fn render_counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn render_gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// The metrics of `server` in the Prometheus text format.
+pub fn render_metrics(server: &Server) -> String {
+    let metrics = server.sync_metrics();
+    let mut out = String::new();
+    metrics.update_broadcast.render(&mut out);
+    metrics.persist.render(&mut out);
+    metrics.doc_load.render(&mut out);
+    metrics.first_sync.render(&mut out);
+
+    render_gauge(
+        &mut out,
+        "y_sweet_loaded_docs",
+        "Number of documents loaded in memory.",
+        server.docs.len() as u64,
+    );
+    render_counter(
+        &mut out,
+        "y_sweet_persist_failures_total",
+        "Number of failed attempts to persist a document.",
+        server.persistence_health().total_failures(),
+    );
+    let compaction = server.compaction_metrics();
+    render_counter(
+        &mut out,
+        "y_sweet_compactions_total",
+        "Number of update log compactions.",
+        compaction.runs(),
+    );
+    render_counter(
+        &mut out,
+        "y_sweet_compaction_reclaimed_objects_total",
+        "Number of update log segments removed by compactions.",
+        compaction.reclaimed_objects(),
+    );
+    out
+}
+
+/// List the metrics of the server for Prometheus
+pub async fn get_metrics(
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+) -> Result<Response, AppError> {
+    server_state.check_auth(auth_header)?;
+
+    let mut response = render_metrics(&server_state).into_response();
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; version=0.0.4"),
+    );
+    Ok(response)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stores::memory::MemoryStore;
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+    };
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+    use y_sweet_core::auth::Authenticator;
+    use yrs::{Doc, ReadTxn, StateVector, Text, Transact};
+
+    #[test]
+    fn test_histogram_buckets_are_cumulative() {
+        let histogram = Histogram::new("latency_seconds", "Latency.");
+        histogram.observe("ok", Duration::from_micros(500));
+        histogram.observe("ok", Duration::from_millis(20));
+        histogram.observe("error", Duration::from_secs(60));
+
+        let mut out = String::new();
+        histogram.render(&mut out);
+        assert!(out.contains("# TYPE latency_seconds histogram\n"));
+        assert!(out.contains("latency_seconds_bucket{outcome=\"ok\",le=\"0.001\"} 1\n"));
+        assert!(out.contains("latency_seconds_bucket{outcome=\"ok\",le=\"0.01\"} 1\n"));
+        assert!(out.contains("latency_seconds_bucket{outcome=\"ok\",le=\"0.025\"} 2\n"));
+        assert!(out.contains("latency_seconds_bucket{outcome=\"ok\",le=\"+Inf\"} 2\n"));
+        assert!(out.contains("latency_seconds_count{outcome=\"ok\"} 2\n"));
+        assert!(out.contains("latency_seconds_bucket{outcome=\"error\",le=\"30\"} 0\n"));
+        assert!(out.contains("latency_seconds_count{outcome=\"error\"} 1\n"));
+        assert!(out.contains("latency_seconds_sum{outcome=\"error\"} 60\n"));
+    }
+
+    #[test]
+    fn test_update_messages_are_recognized() {
+        assert!(is_update_message(&[MSG_SYNC, MSG_SYNC_UPDATE, 1]));
+        assert!(is_update_message(&[MSG_SYNC, MSG_SYNC_STEP_2, 1]));
+        assert!(!is_update_message(&[MSG_SYNC, MSG_SYNC_STEP_1, 1]));
+        assert!(!is_update_message(&[1, MSG_SYNC_UPDATE]));
+        assert!(is_sync_step1_message(&[MSG_SYNC, MSG_SYNC_STEP_1, 0]));
+    }
+
+    #[tokio::test]
+    async fn test_rest_updates_are_measured() {
+        let auth = Authenticator::gen_key().unwrap();
+        let token = auth.server_token();
+        let server = Arc::new(
+            Server::builder()
+                .store(Some(Box::new(MemoryStore::new())))
+                .authenticator(Some(auth))
+                .build()
+                .await
+                .unwrap(),
+        );
+        let send = |method: &str, path: &str, body: Body| {
+            Request::builder()
+                .method(method)
+                .uri(path)
+                .header(header::HOST, "localhost")
+                .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                .body(body)
+                .unwrap()
+        };
+
+        let doc = Doc::new();
+        let text = doc.get_or_insert_text("text");
+        text.insert(&mut doc.transact_mut(), 0, "hello");
+        let update = doc
+            .transact()
+            .encode_state_as_update_v1(&StateVector::default());
+        let request = send("POST", "/d/measured/update", update.into());
+        let response = server.routes().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let request = send("POST", "/d/measured/update", Body::from("not an update"));
+        let response = server.routes().oneshot(request).await.unwrap();
+        assert!(!response.status().is_success());
+
+        let metrics = server.sync_metrics();
+        assert_eq!(metrics.update_broadcast.count("ok"), 1);
+        assert_eq!(metrics.doc_load.count("ok"), 1);
+
+        let request = send("GET", "/metrics", Body::empty());
+        let response = server.routes().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("y_sweet_update_broadcast_seconds_count{outcome=\"ok\"} 1\n"));
+        assert!(body.contains("y_sweet_doc_load_seconds_count{outcome=\"ok\"} 1\n"));
+        assert!(body.contains("y_sweet_loaded_docs 1\n"));
+    }
+}
@@ -0,0 +1,152 @@
+//! [Broker] over NATS JetStream.
+//!
+//! Updates of a document are published on the subject `{prefix}.{doc_id}` of a JetStream
+//! stream. The payload is the update itself, in Yjs v1 encoding, and the headers
+//! `Y-Sweet-Doc-Id` and `Y-Sweet-Origin` carry the document ID and the ID of the node that
+//! applied it. Besides feeding other y-sweet nodes, the stream can be consumed by other
+//! services that want to follow document changes.
+//!
+//! Messages nodes catch up with carry the `Y-Sweet-Message` header, which other consumers
+//! should skip: `state-vector`, with the state vector as payload, or `catch-up`, with an
+//! update as payload and the state vector of the origin node in `Y-Sweet-State-Vector`
+//! (base64).
+
+use anyhow::{anyhow, Result};
+use async_nats::{
+    jetstream::{
+        self,
+        consumer::{pull::OrderedConfig, DeliverPolicy},
+        stream,
+    },
+    HeaderMap,
+};
+use async_trait::async_trait;
+use futures::{stream::BoxStream, StreamExt};
+use std::time::Duration;
+
+use crate::replication_ext::{Broker, BrokerMessage, BrokerPayload};
+
+/// Subject prefix updates are published under, unless configured otherwise.
+pub const DEFAULT_SUBJECT_PREFIX: &str = "y-sweet.doc";
+
+/// Name of the JetStream stream holding updates, unless configured otherwise.
+pub const DEFAULT_STREAM_NAME: &str = "Y_SWEET_UPDATES";
+
+pub const DOC_ID_HEADER: &str = "Y-Sweet-Doc-Id";
+pub const ORIGIN_HEADER: &str = "Y-Sweet-Origin";
+pub const MESSAGE_HEADER: &str = "Y-Sweet-Message";
+pub const STATE_VECTOR_HEADER: &str = "Y-Sweet-State-Vector";
+
+/// How long updates are kept in the stream for other consumers.
+const STREAM_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub struct NatsBroker {
+    jetstream: jetstream::Context,
+    subject_prefix: String,
+    stream_name: String,
+}
+
+impl NatsBroker {
+    /// Connect to the NATS server at `url`, creating the stream if it does not exist.
+    pub async fn connect(
+        url: &str,
+        subject_prefix: Option<String>,
+        stream_name: Option<String>,
+    ) -> Result<Self> {
+        let subject_prefix = subject_prefix.unwrap_or_else(|| DEFAULT_SUBJECT_PREFIX.to_string());
+        let stream_name = stream_name.unwrap_or_else(|| DEFAULT_STREAM_NAME.to_string());
+
+        let client = async_nats::connect(url).await?;
+        let jetstream = jetstream::new(client);
+        jetstream
+            .get_or_create_stream(stream::Config {
+                name: stream_name.clone(),
+                subjects: vec![format!("{}.>", subject_prefix)],
+                max_age: STREAM_MAX_AGE,
+                ..Default::default()
+            })
+            .await?;
+
+        Ok(Self {
+            jetstream,
+            subject_prefix,
+            stream_name,
+        })
+    }
+}
+
+#[async_trait]
+impl Broker for NatsBroker {
+    async fn publish(&self, message: &BrokerMessage) -> Result<()> {
+        let mut headers = HeaderMap::new();
+        headers.insert(DOC_ID_HEADER, message.doc_id.as_str());
+        headers.insert(ORIGIN_HEADER, message.origin.as_str());
+        let payload = match &message.payload {
+            BrokerPayload::Update(update) => update.clone(),
+            BrokerPayload::StateVector(state_vector) => {
+                headers.insert(MESSAGE_HEADER, "state-vector");
+                state_vector.clone()
+            }
+            BrokerPayload::CatchUp {
+                update,
+                state_vector,
+            } => {
+                headers.insert(MESSAGE_HEADER, "catch-up");
+                headers.insert(
+                    STATE_VECTOR_HEADER,
+                    data_encoding::BASE64.encode(state_vector).as_str(),
+                );
+                update.clone()
+            }
+        };
+
+        let subject = format!("{}.{}", self.subject_prefix, message.doc_id);
+        self.jetstream
+            .publish_with_headers(subject, headers, payload.into())
+            .await?
+            .await?;
+        Ok(())
+    }
+
+    async fn subscribe(&self) -> Result<BoxStream<'static, Result<BrokerMessage>>> {
+        let consumer = self
+            .jetstream
+            .get_stream(&self.stream_name)
+            .await?
+            .create_consumer(OrderedConfig {
+                filter_subject: format!("{}.>", self.subject_prefix),
+                deliver_policy: DeliverPolicy::New,
+                ..Default::default()
+            })
+            .await?;
+
+        let messages = consumer.messages().await?.map(|msg| {
+            let msg = msg?;
+            let header = |name: &str| {
+                msg.message
+                    .headers
+                    .as_ref()
+                    .and_then(|headers| headers.get(name))
+                    .map(|value| value.as_str().to_string())
+                    .ok_or_else(|| anyhow!("Message is missing the {} header", name))
+            };
+            let data = msg.message.payload.to_vec();
+            let payload = match header(MESSAGE_HEADER).ok().as_deref() {
+                None => BrokerPayload::Update(data),
+                Some("state-vector") => BrokerPayload::StateVector(data),
+                Some("catch-up") => BrokerPayload::CatchUp {
+                    update: data,
+                    state_vector: data_encoding::BASE64
+                        .decode(header(STATE_VECTOR_HEADER)?.as_bytes())?,
+                },
+                Some(kind) => return Err(anyhow!("Unknown message kind {}", kind)),
+            };
+            Ok(BrokerMessage {
+                doc_id: header(DOC_ID_HEADER)?,
+                origin: header(ORIGIN_HEADER)?,
+                payload,
+            })
+        });
+        Ok(messages.boxed())
+    }
+}
@@ -0,0 +1,114 @@
+//! Export of traces and metrics over OTLP, to any OpenTelemetry collector or backend
+//! (Jaeger, Tempo, Honeycomb, ...) rather than a Datadog agent.
+//!
+//! Selected with `Y_SWEET_TELEMETRY=otlp`. The exporters are configured by the standard
+//! `OTEL_` environment variables: `OTEL_EXPORTER_OTLP_ENDPOINT` (and the `_TRACES_` and
+//! `_METRICS_` variants), `OTEL_EXPORTER_OTLP_PROTOCOL` (`http/protobuf` by default, or
+//! `grpc`), `OTEL_EXPORTER_OTLP_HEADERS`, `OTEL_EXPORTER_OTLP_TIMEOUT`,
+//! `OTEL_SERVICE_NAME`, `OTEL_RESOURCE_ATTRIBUTES`, `OTEL_TRACES_SAMPLER` and
+//! `OTEL_METRIC_EXPORT_INTERVAL`. Trace context is propagated with W3C `traceparent`
+//! headers.
+
+use anyhow::{bail, Context, Result};
+use opentelemetry::{global, trace::TracerProvider};
+use opentelemetry_otlp::{MetricExporter, SpanExporter};
+use opentelemetry_sdk::{
+    metrics::SdkMeterProvider,
+    propagation::TraceContextPropagator,
+    trace::{BatchSpanProcessor, SdkTracerProvider},
+    Resource,
+};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+use crate::redact_ext::{RedactingSpanProcessor, Redaction};
+use crate::tracing_setup::{log_layer, LogFormat};
+
+/// Name of the instrumentation scope of the spans and metrics of the server.
+pub const SCOPE: &str = "y-sweet";
+
+/// Flushes and shuts down the exporters when dropped.
+pub struct OtlpGuard {
+    tracer_provider: SdkTracerProvider,
+    meter_provider: SdkMeterProvider,
+}
+
+impl Drop for OtlpGuard {
+    fn drop(&mut self) {
+        let _ = self.tracer_provider.force_flush();
+        let _ = self.tracer_provider.shutdown();
+        let _ = self.meter_provider.force_flush();
+        let _ = self.meter_provider.shutdown();
+    }
+}
+
+/// Whether to export over gRPC, rather than HTTP, by `OTEL_EXPORTER_OTLP_PROTOCOL` or the
+/// variable of the signal, e.g. `OTEL_EXPORTER_OTLP_TRACES_PROTOCOL`.
+fn use_grpc(signal: &str) -> Result<bool> {
+    let protocol = std::env::var(format!("OTEL_EXPORTER_OTLP_{}_PROTOCOL", signal))
+        .or_else(|_| std::env::var("OTEL_EXPORTER_OTLP_PROTOCOL"));
+    match protocol.as_deref() {
+        Ok("grpc") => Ok(true),
+        Ok("http/protobuf") | Err(_) => Ok(false),
+        Ok(protocol) => bail!(
+            "Unsupported OTLP protocol {:?}, expected \"http/protobuf\" or \"grpc\"",
+            protocol
+        ),
+    }
+}
+
+fn resource() -> Resource {
+    let builder = Resource::builder();
+    // OTEL_SERVICE_NAME, when set, is picked up by the builder
+    if std::env::var("OTEL_SERVICE_NAME").is_ok() {
+        builder.build()
+    } else {
+        builder.with_service_name(SCOPE).build()
+    }
+}
+
+/// Install the OTLP exporters of traces and metrics, with logs in `format`. Spans are
+/// exported with `redaction` applied to their attributes.
+pub fn init_otlp(filter: EnvFilter, format: LogFormat, redaction: Redaction) -> Result<OtlpGuard> {
+    let span_exporter = if use_grpc("TRACES")? {
+        SpanExporter::builder().with_tonic().build()
+    } else {
+        SpanExporter::builder().with_http().build()
+    }
+    .context("Failed to build the OTLP span exporter")?;
+    let metric_exporter = if use_grpc("METRICS")? {
+        MetricExporter::builder().with_tonic().build()
+    } else {
+        MetricExporter::builder().with_http().build()
+    }
+    .context("Failed to build the OTLP metric exporter")?;
+
+    let resource = resource();
+    let span_processor = BatchSpanProcessor::builder(span_exporter).build();
+    let tracer_provider = SdkTracerProvider::builder()
+        .with_resource(resource.clone())
+        .with_span_processor(RedactingSpanProcessor::new(
+            redaction.clone(),
+            span_processor,
+        ))
+        .build();
+    let meter_provider = SdkMeterProvider::builder()
+        .with_resource(resource)
+        .with_periodic_exporter(metric_exporter)
+        .build();
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    global::set_tracer_provider(tracer_provider.clone());
+    global::set_meter_provider(meter_provider.clone());
+
+    let otel_layer = tracing_opentelemetry::layer().with_tracer(tracer_provider.tracer(SCOPE));
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(log_layer(format, &redaction))
+        .with(otel_layer)
+        .init();
+
+    Ok(OtlpGuard {
+        tracer_provider,
+        meter_provider,
+    })
+}
@@ -0,0 +1,155 @@
+//! Handling of failures to persist documents.
+//!
+//! A failed checkpoint is retried with exponential backoff, up to a bounded number of
+//! times, before the persistence worker goes back to its regular schedule. Every document
+//! whose last checkpoint failed is tracked until one succeeds, and reported by `/health`.
+//! Optionally, a document that has been failing for too long stops accepting writes: new
+//! WebSocket connections are read-only and HTTP updates are rejected, so that clients keep
+//! their changes instead of handing them to a server that cannot store them.
+
+use dashmap::DashMap;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::server::current_time_epoch_millis;
+
+/// Default number of retries of a failed checkpoint.
+pub const DEFAULT_PERSIST_MAX_RETRIES: u32 = 5;
+
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug)]
+pub struct PersistRetryPolicy {
+    /// Number of retries of a failed checkpoint before waiting for the next one.
+    pub max_retries: u32,
+    /// Stop accepting writes for a document once its checkpoints have been failing for
+    /// this long.
+    pub reject_writes_after: Option<Duration>,
+}
+
+impl Default for PersistRetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: DEFAULT_PERSIST_MAX_RETRIES,
+            reject_writes_after: None,
+        }
+    }
+}
+
+impl PersistRetryPolicy {
+    /// Delay before retry number `retry` (from 0): 1s, 2s, 4s, ... up to a minute.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        RETRY_BASE_DELAY
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(RETRY_MAX_DELAY)
+    }
+}
+
+/// A document whose changes could not be persisted.
+#[derive(Clone, Debug)]
+pub struct PersistFailure {
+    /// Epoch millis of the first failure since the last successful checkpoint.
+    pub failing_since: u64,
+    /// Failed attempts since the last successful checkpoint.
+    pub attempts: u32,
+    pub last_error: String,
+}
+
+/// Documents whose checkpoints are failing.
+#[derive(Default)]
+pub struct PersistenceHealth {
+    policy: PersistRetryPolicy,
+    failing: DashMap<String, PersistFailure>,
+    total_failures: AtomicU64,
+}
+
+impl PersistenceHealth {
+    pub fn new(policy: PersistRetryPolicy) -> Self {
+        Self {
+            policy,
+            ..Self::default()
+        }
+    }
+
+    pub fn policy(&self) -> &PersistRetryPolicy {
+        &self.policy
+    }
+
+    pub fn record_failure(&self, doc_id: &str, error: &str) {
+        self.total_failures.fetch_add(1, Ordering::Relaxed);
+        let mut failure =
+            self.failing
+                .entry(doc_id.to_string())
+                .or_insert_with(|| PersistFailure {
+                    failing_since: current_time_epoch_millis(),
+                    attempts: 0,
+                    last_error: String::new(),
+                });
+        failure.attempts += 1;
+        failure.last_error = error.to_string();
+    }
+
+    /// Record a successful checkpoint. Returns the failure it ends, if any.
+    pub fn record_success(&self, doc_id: &str) -> Option<PersistFailure> {
+        self.failing.remove(doc_id).map(|(_, failure)| failure)
+    }
+
+    /// Documents whose last checkpoint failed.
+    pub fn failing_docs(&self) -> Vec<(String, PersistFailure)> {
+        self.failing
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect()
+    }
+
+    /// Failed checkpoints since the server started.
+    pub fn total_failures(&self) -> u64 {
+        self.total_failures.load(Ordering::Relaxed)
+    }
+
+    /// Whether a document has been failing for long enough to stop accepting writes.
+    pub fn rejects_writes(&self, doc_id: &str) -> bool {
+        let Some(after) = self.policy.reject_writes_after else {
+            return false;
+        };
+        self.failing.get(doc_id).is_some_and(|failure| {
+            current_time_epoch_millis().saturating_sub(failure.failing_since)
+                >= after.as_millis() as u64
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backoff_is_capped() {
+        let policy = PersistRetryPolicy::default();
+        assert_eq!(policy.backoff(0), Duration::from_secs(1));
+        assert_eq!(policy.backoff(3), Duration::from_secs(8));
+        assert_eq!(policy.backoff(40), RETRY_MAX_DELAY);
+    }
+
+    #[test]
+    fn test_failing_doc_rejects_writes_until_persisted() {
+        let health = PersistenceHealth::new(PersistRetryPolicy {
+            max_retries: 1,
+            reject_writes_after: Some(Duration::ZERO),
+        });
+        assert!(!health.rejects_writes("doc"));
+
+        health.record_failure("doc", "store unavailable");
+        health.record_failure("doc", "store unavailable");
+        assert!(health.rejects_writes("doc"));
+        assert_eq!(health.failing_docs()[0].1.attempts, 2);
+        assert_eq!(health.total_failures(), 2);
+
+        assert_eq!(health.record_success("doc").unwrap().attempts, 2);
+        assert!(!health.rejects_writes("doc"));
+        assert!(health.failing_docs().is_empty());
+    }
+}
@@ -0,0 +1,266 @@
+//! Loading documents into memory at startup.
+//!
+//! Documents to preload are given as IDs, as ID prefixes listed from the store, or as a
+//! manifest in the store: a JSON array of document IDs. They are loaded in the background
+//! once the server starts, and `/ready` responds with 503 and the progress of the preload
+//! until every document was attempted, so that a load balancer only sends traffic to a
+//! warm instance. Documents that do not exist or fail to load are logged and do not hold
+//! readiness back.
+//!
+//! Preloaded documents are unloaded like any other once idle; pin them or set a minimum
+//! residency (see `doc_gc_ext`) to keep them loaded until they are used.
+
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+use y_sweet_core::store::Store;
+
+use crate::server::Server;
+
+/// Number of documents loaded concurrently while preloading.
+const PRELOAD_CONCURRENCY: usize = 8;
+
+#[derive(Clone, Debug)]
+pub enum PreloadSource {
+    Docs(Vec<String>),
+    /// Every stored document whose ID starts with the prefix.
+    Prefix(String),
+    /// Store key of a JSON array of document IDs.
+    Manifest(String),
+}
+
+/// Progress of the preload, reported by `/ready`.
+pub struct PreloadProgress {
+    total: AtomicUsize,
+    loaded: AtomicUsize,
+    failed: AtomicUsize,
+    done: AtomicBool,
+}
+
+impl PreloadProgress {
+    /// Progress of a preload that has not started.
+    pub(crate) fn pending() -> Self {
+        Self {
+            done: AtomicBool::new(false),
+            ..Self::default()
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.total.load(Ordering::SeqCst)
+    }
+
+    pub fn loaded(&self) -> usize {
+        self.loaded.load(Ordering::SeqCst)
+    }
+
+    pub fn failed(&self) -> usize {
+        self.failed.load(Ordering::SeqCst)
+    }
+
+    /// Whether every document to preload was attempted, or there was nothing to preload.
+    pub fn is_done(&self) -> bool {
+        self.done.load(Ordering::SeqCst)
+    }
+}
+
+impl Default for PreloadProgress {
+    fn default() -> Self {
+        Self {
+            total: AtomicUsize::new(0),
+            loaded: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+            done: AtomicBool::new(true),
+        }
+    }
+}
+
+/// The IDs of the documents to preload, without duplicates.
+pub async fn resolve_preload(
+    store: &Arc<Box<dyn Store>>,
+    sources: &[PreloadSource],
+) -> Result<Vec<String>> {
+    let mut doc_ids = Vec::new();
+    for source in sources {
+        match source {
+            PreloadSource::Docs(ids) => doc_ids.extend(ids.iter().cloned()),
+            PreloadSource::Prefix(prefix) => doc_ids.extend(
+                store
+                    .list_documents(prefix)
+                    .await
+                    .with_context(|| format!("Failed to list documents with prefix {}", prefix))?,
+            ),
+            PreloadSource::Manifest(key) => {
+                let manifest = store
+                    .get(key)
+                    .await
+                    .with_context(|| format!("Failed to read preload manifest {}", key))?
+                    .with_context(|| format!("Preload manifest {} does not exist", key))?;
+                let ids: Vec<String> = serde_json::from_slice(&manifest)
+                    .with_context(|| format!("Malformed preload manifest {}", key))?;
+                doc_ids.extend(ids);
+            }
+        }
+    }
+
+    let mut seen = std::collections::HashSet::new();
+    doc_ids.retain(|doc_id| seen.insert(doc_id.clone()));
+    Ok(doc_ids)
+}
+
+/// Load the documents of `sources` that this node serves, recording progress in
+/// `progress`.
+pub(crate) async fn preload_docs(
+    server: Arc<Server>,
+    sources: Vec<PreloadSource>,
+    progress: Arc<PreloadProgress>,
+    cancellation_token: CancellationToken,
+) {
+    let Some(store) = server.store.clone() else {
+        progress.done.store(true, Ordering::SeqCst);
+        return;
+    };
+
+    let doc_ids = match resolve_preload(&store, &sources).await {
+        Ok(doc_ids) => doc_ids,
+        Err(e) => {
+            warn!(
+                message = "Failed to resolve documents to preload",
+                event = "preload_failed",
+                error = %e
+            );
+            Vec::new()
+        }
+    };
+    // In a cluster, other nodes preload the documents they own
+    let doc_ids: Vec<String> = doc_ids
+        .into_iter()
+        .filter(|doc_id| server.cluster().is_none_or(|c| c.is_local(doc_id)))
+        .collect();
+    progress.total.store(doc_ids.len(), Ordering::SeqCst);
+    info!(
+        message = format!("Preloading {} documents", doc_ids.len()),
+        event = "preload_started",
+        doc_count = doc_ids.len()
+    );
+
+    let load_all = futures::stream::iter(doc_ids)
+        .map(|doc_id| {
+            let server = server.clone();
+            let progress = progress.clone();
+            async move {
+                if !server.doc_exists(&doc_id).await {
+                    progress.failed.fetch_add(1, Ordering::SeqCst);
+                    warn!(
+                        message = "Document to preload does not exist",
+                        event = "preload_doc_failed",
+                        doc_id = %doc_id
+                    );
+                    return;
+                }
+                match server.get_or_create_doc(&doc_id).await.map(|_| ()) {
+                    Ok(()) => {
+                        progress.loaded.fetch_add(1, Ordering::SeqCst);
+                    }
+                    Err(e) => {
+                        progress.failed.fetch_add(1, Ordering::SeqCst);
+                        warn!(
+                            message = "Failed to preload document",
+                            event = "preload_doc_failed",
+                            doc_id = %doc_id,
+                            error = %e
+                        );
+                    }
+                }
+            }
+        })
+        .buffer_unordered(PRELOAD_CONCURRENCY)
+        .collect::<Vec<()>>();
+
+    tokio::select! {
+        _ = load_all => {
+            info!(
+                message = "Preload completed",
+                event = "preload_completed",
+                loaded = progress.loaded(),
+                failed = progress.failed()
+            );
+        }
+        _ = cancellation_token.cancelled() => {}
+    }
+    progress.done.store(true, Ordering::SeqCst);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stores::filesystem::FileSystemStore;
+    use std::time::Duration;
+    use y_sweet_core::doc_sync_ext::DocWithSyncKv;
+    use yrs::{Text, Transact};
+
+    async fn create(store: &Arc<Box<dyn Store>>, doc_id: &str) {
+        let doc = DocWithSyncKv::new(doc_id, Some(store.clone()), || {}, false)
+            .await
+            .unwrap();
+        {
+            let awareness = doc.awareness();
+            let awareness = awareness.write().unwrap();
+            let text = awareness.doc().get_or_insert_text("text");
+            text.insert(&mut awareness.doc().transact_mut(), 0, doc_id);
+        }
+        doc.sync_kv().persist().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_preload_loads_listed_prefixed_and_manifest_docs() {
+        let path = std::env::temp_dir().join(format!("y-sweet-preload-{}", nanoid::nanoid!()));
+        let store: Arc<Box<dyn Store>> =
+            Arc::new(Box::new(FileSystemStore::new(path.clone()).unwrap()));
+        for doc_id in ["team-a", "team-b", "other"] {
+            create(&store, doc_id).await;
+        }
+        store
+            .set("preload.json", br#"["other", "team-a"]"#.to_vec())
+            .await
+            .unwrap();
+
+        let server = Arc::new(
+            Server::builder()
+                .store(Some(Box::new(FileSystemStore::new(path).unwrap())))
+                .checkpoint_freq(Duration::from_secs(60))
+                .build()
+                .await
+                .unwrap(),
+        );
+        let progress = Arc::new(PreloadProgress::pending());
+        assert!(!progress.is_done());
+
+        preload_docs(
+            server.clone(),
+            vec![
+                PreloadSource::Prefix("team-".to_string()),
+                PreloadSource::Manifest("preload.json".to_string()),
+                PreloadSource::Docs(vec!["missing".to_string()]),
+            ],
+            progress.clone(),
+            CancellationToken::new(),
+        )
+        .await;
+
+        assert!(progress.is_done());
+        assert_eq!(progress.total(), 4);
+        assert_eq!(progress.loaded(), 3);
+        assert_eq!(progress.failed(), 1);
+        for doc_id in ["team-a", "team-b", "other"] {
+            assert!(server.docs.contains_key(doc_id));
+        }
+        // Documents that do not exist are not created
+        assert!(!server.docs.contains_key("missing"));
+    }
+}
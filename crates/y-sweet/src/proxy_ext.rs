@@ -0,0 +1,309 @@
+//! Trusting the headers of reverse proxies for the address and scheme of clients.
+//!
+//! Behind a load balancer, the peer of every connection is the load balancer, and requests
+//! it received over https reach the server over http. With `--trusted-proxies`, requests
+//! from the listed addresses have their client address taken from `Forwarded` or
+//! `X-Forwarded-For`, and the URLs returned by `auth_doc` use the scheme and host of
+//! `Forwarded` or `X-Forwarded-Proto` and `X-Forwarded-Host`. Headers of other peers are
+//! ignored, as anyone can send them.
+
+use anyhow::{anyhow, Result};
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+};
+use ipnet::IpNet;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+/// The proxies whose forwarding headers are trusted.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    networks: Vec<IpNet>,
+    any: bool,
+}
+
+impl TrustedProxies {
+    /// Parse proxies given as addresses (`10.0.0.1`) or networks (`10.0.0.0/8`). `*`
+    /// trusts every peer.
+    pub fn parse(proxies: &[String]) -> Result<Self> {
+        let mut trusted = Self::default();
+        for proxy in proxies {
+            let proxy = proxy.trim();
+            if proxy == "*" {
+                trusted.any = true;
+                continue;
+            }
+            let network = match proxy.parse::<IpNet>() {
+                Ok(network) => network,
+                Err(_) => proxy
+                    .parse::<IpAddr>()
+                    .map(IpNet::from)
+                    .map_err(|_| anyhow!("Invalid trusted proxy {:?}", proxy))?,
+            };
+            trusted.networks.push(network);
+        }
+        Ok(trusted)
+    }
+
+    fn trusts(&self, addr: IpAddr) -> bool {
+        self.any || self.networks.iter().any(|network| network.contains(&addr))
+    }
+
+    /// Whether the forwarding headers of the peer `peer` are trusted. A peer without an
+    /// address is on the Unix socket of the server, which only local proxies can reach, so
+    /// it is trusted if any proxy is.
+    pub fn trusts_peer(&self, peer: Option<IpAddr>) -> bool {
+        match peer {
+            Some(peer) => self.trusts(peer),
+            None => self.any || !self.networks.is_empty(),
+        }
+    }
+
+    /// What the trusted proxies forwarding a request from `peer` say about its client.
+    pub fn client(&self, headers: &HeaderMap, peer: Option<IpAddr>) -> ClientInfo {
+        if !self.trusts_peer(peer) {
+            return ClientInfo {
+                addr: peer,
+                ..Default::default()
+            };
+        }
+        let forwarded = forwarded_elements(headers);
+        let hops = if forwarded.is_empty() {
+            forwarded_for(headers)
+        } else {
+            forwarded
+                .iter()
+                .map(|element| element.get("for").and_then(|node| parse_node(node)))
+                .collect()
+        };
+
+        // Right to left, the first hop that is not a trusted proxy is the client
+        let mut addr = peer;
+        for hop in hops.iter().rev() {
+            match hop {
+                Some(hop) => {
+                    addr = Some(*hop);
+                    if !self.trusts(*hop) {
+                        break;
+                    }
+                }
+                // Obfuscated or unknown: nothing further left can be trusted
+                None => break,
+            }
+        }
+
+        // The first proxy saw the request as the client sent it
+        let first = forwarded.first();
+        let proto = first
+            .and_then(|element| element.get("proto").cloned())
+            .or_else(|| first_value(headers, "x-forwarded-proto"));
+        let host = first
+            .and_then(|element| element.get("host").cloned())
+            .or_else(|| first_value(headers, "x-forwarded-host"));
+        ClientInfo {
+            addr,
+            proto: proto.map(|proto| proto.to_ascii_lowercase()),
+            host,
+        }
+    }
+}
+
+/// The client of a request, as the trusted proxies forwarding it say.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientInfo {
+    /// Address of the client, or of the peer if no trusted proxy forwarded the request.
+    pub addr: Option<IpAddr>,
+    /// Scheme the client used, `http` or `https`.
+    pub proto: Option<String>,
+    /// Host the client requested.
+    pub host: Option<String>,
+}
+
+fn first_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    let value = headers.get(name)?.to_str().ok()?;
+    let value = value.split(',').next()?.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// Addresses of `X-Forwarded-For`, from the client to the last proxy.
+fn forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|node| parse_node(node.trim()))
+        .collect()
+}
+
+type ForwardedElement = std::collections::HashMap<String, String>;
+
+/// Elements of `Forwarded` (RFC 7239), one per proxy, with their lowercased parameters.
+fn forwarded_elements(headers: &HeaderMap) -> Vec<ForwardedElement> {
+    headers
+        .get_all("forwarded")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .map(|(key, value)| {
+                    let value = value.trim().trim_matches('"');
+                    (key.trim().to_ascii_lowercase(), value.to_string())
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Address of a node: `192.0.2.1`, `192.0.2.1:4711`, `[2001:db8::1]` or
+/// `[2001:db8::1]:4711`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(addr) = node.parse::<IpAddr>() {
+        return Some(addr);
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    node.strip_prefix('[')?
+        .strip_suffix(']')?
+        .parse::<IpAddr>()
+        .ok()
+}
+
+/// Record what the trusted proxies say about the client of a request, for logging and the
+/// URLs of `auth_doc`.
+pub async fn resolve_client(
+    State(trusted): State<Arc<TrustedProxies>>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let client = trusted.client(req.headers(), peer);
+    req.extensions_mut().insert(client);
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn ip(addr: &str) -> Option<IpAddr> {
+        Some(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn test_client_is_taken_from_trusted_proxies_only() {
+        let trusted = TrustedProxies::parse(&["10.0.0.0/8".to_string()]).unwrap();
+        let forwarded = headers(&[
+            ("x-forwarded-for", "203.0.113.7, 10.0.0.2"),
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-host", "docs.example.com"),
+        ]);
+
+        let client = trusted.client(&forwarded, ip("10.0.0.1"));
+        assert_eq!(client.addr, ip("203.0.113.7"));
+        assert_eq!(client.proto.as_deref(), Some("https"));
+        assert_eq!(client.host.as_deref(), Some("docs.example.com"));
+
+        // Anyone else could have made the headers up
+        let client = trusted.client(&forwarded, ip("198.51.100.1"));
+        assert_eq!(
+            client,
+            ClientInfo {
+                addr: ip("198.51.100.1"),
+                ..Default::default()
+            }
+        );
+
+        // A spoofed first hop is not taken past an untrusted one
+        let spoofed = headers(&[("x-forwarded-for", "1.2.3.4, 203.0.113.7")]);
+        let client = trusted.client(&spoofed, ip("10.0.0.1"));
+        assert_eq!(client.addr, ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn test_forwarded_header_is_preferred() {
+        let trusted = TrustedProxies::parse(&["*".to_string()]).unwrap();
+        let forwarded = headers(&[
+            (
+                "forwarded",
+                "for=\"[2001:db8:cafe::17]:4711\";proto=HTTPS;host=docs.example.com, for=10.0.0.2",
+            ),
+            ("x-forwarded-for", "192.0.2.1"),
+        ]);
+        let client = trusted.client(&forwarded, ip("10.0.0.1"));
+        assert_eq!(client.addr, ip("2001:db8:cafe::17"));
+        assert_eq!(client.proto.as_deref(), Some("https"));
+        assert_eq!(client.host.as_deref(), Some("docs.example.com"));
+
+        let trusted = TrustedProxies::parse(&["10.0.0.1".to_string()]).unwrap();
+        let client = trusted.client(&forwarded, ip("10.0.0.1"));
+        assert_eq!(client.addr, ip("10.0.0.2"));
+
+        assert!(TrustedProxies::parse(&["not-an-address".to_string()]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_auth_urls_use_the_forwarded_scheme_and_host() {
+        use crate::{server::Server, stores::memory::MemoryStore};
+        use axum::{body::Body, http::Request};
+        use http_body_util::BodyExt;
+        use tower::ServiceExt;
+        use y_sweet_core::{api_types::ClientToken, auth::Authenticator};
+
+        let auth = Authenticator::gen_key().unwrap();
+        let token = auth.server_token();
+        let server = Arc::new(
+            Server::builder()
+                .store(Some(Box::new(MemoryStore::new())))
+                .authenticator(Some(auth))
+                .build()
+                .await
+                .unwrap()
+                .with_trusted_proxies(TrustedProxies::parse(&["*".to_string()]).unwrap()),
+        );
+        server.load_doc("proxied").await.unwrap();
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/doc/proxied/auth")
+            .header("host", "10.0.0.5:8080")
+            .header("authorization", format!("Bearer {}", token))
+            .header("x-forwarded-proto", "https")
+            .header("x-forwarded-host", "docs.example.com")
+            .body(Body::empty())
+            .unwrap();
+        let response = server
+            .app(server.routes(), true)
+            .oneshot(request)
+            .await
+            .unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let client_token: ClientToken = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            client_token.base_url.as_deref(),
+            Some("https://docs.example.com/d/proxied")
+        );
+        assert_eq!(client_token.url, "wss://docs.example.com/d/proxied/ws");
+    }
+}
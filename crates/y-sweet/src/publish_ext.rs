@@ -0,0 +1,477 @@
+//! Read-only publishing of documents (`POST /d/:doc_id/publish` and `/unpublish`).
+//!
+//! Publishing renders the document as a standalone HTML page or as Markdown (see `convert`
+//! and `html_ext`) and writes it to `{doc_id}/published/` in the store. Anyone can read it
+//! at `GET /d/:doc_id/published`, without a token, so the URL returned can be shared as is.
+//! The page is a snapshot: later edits show once the document is published again.
+//! Unpublishing removes it, as does deleting the document. Archiving moves it to the archive
+//! with the rest of the document, and unarchiving publishes it again.
+
+use anyhow::{anyhow, Result};
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use axum_extra::typed_header::TypedHeader;
+use serde_json::json;
+use std::sync::Arc;
+use y_sweet_core::{
+    api_types::validate_doc_name,
+    api_types_ext::{DocPublishRequest, DocPublishResponse, PublishFormat},
+    store::{Store, StoreError},
+};
+
+use crate::audit_ext::{record_audit, AuditPrincipal};
+use crate::convert::{write_text, TextFormat, DEFAULT_TEXT_ROOT};
+use crate::html_ext::{escape, write_html};
+use crate::proxy_ext::ClientInfo;
+use crate::server::{AppError, Server};
+use crate::server_ext::{ext_check_not_archived, is_doc_archived};
+
+/// Directory under `{doc_id}/` holding the published snapshot of the document
+pub(crate) const PUBLISHED_DIR: &str = "published";
+
+/// Published pages run no scripts, since they are served from the origin of the server.
+const PUBLISHED_CSP: &str = "default-src 'none'; img-src * data:; style-src 'unsafe-inline'";
+
+/// How long caches may serve a published snapshot without checking for a newer one.
+const PUBLISHED_MAX_AGE_SECONDS: u64 = 60;
+
+fn file_name(format: PublishFormat) -> &'static str {
+    match format {
+        PublishFormat::Html => "index.html",
+        PublishFormat::Markdown => "index.md",
+    }
+}
+
+fn content_type(format: PublishFormat) -> &'static str {
+    match format {
+        PublishFormat::Html => "text/html; charset=utf-8",
+        PublishFormat::Markdown => "text/markdown; charset=utf-8",
+    }
+}
+
+/// Key of the snapshot of `doc_id` published as `format`.
+pub fn published_key(doc_id: &str, format: PublishFormat) -> String {
+    format!("{}/{}/{}", doc_id, PUBLISHED_DIR, file_name(format))
+}
+
+/// Render `doc_as_update` as `format`.
+pub fn render_published(
+    server_state: &Server,
+    doc_id: &str,
+    doc_as_update: &[u8],
+    root: &str,
+    format: PublishFormat,
+) -> Result<Vec<u8>> {
+    let mut rendered = Vec::new();
+    match format {
+        PublishFormat::Markdown => {
+            write_text(doc_as_update, root, TextFormat::Markdown, &mut rendered)?
+        }
+        PublishFormat::Html => {
+            rendered.extend_from_slice(
+                format!(
+                    "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+                     <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+                     <title>{}</title>\n</head>\n<body>\n",
+                    escape(doc_id)
+                )
+                .as_bytes(),
+            );
+            write_html(
+                doc_as_update,
+                root,
+                server_state.html_renderer(),
+                &mut rendered,
+            )?;
+            rendered.extend_from_slice(b"\n</body>\n</html>\n");
+        }
+    }
+    Ok(rendered)
+}
+
+/// The format `doc_id` is published as, if it is.
+async fn published_format(
+    store: &Arc<Box<dyn Store>>,
+    doc_id: &str,
+) -> Result<Option<PublishFormat>> {
+    for format in [PublishFormat::Html, PublishFormat::Markdown] {
+        if store.exists(&published_key(doc_id, format)).await? {
+            return Ok(Some(format));
+        }
+    }
+    Ok(None)
+}
+
+/// Remove the published snapshot of `doc_id`, if any.
+pub(crate) async fn remove_published(
+    store: &Arc<Box<dyn Store>>,
+    doc_id: &str,
+) -> Result<(), StoreError> {
+    let prefix = format!("{}/{}/", doc_id, PUBLISHED_DIR);
+    let names = match store.list_objects(&prefix).await {
+        Ok(names) => names,
+        Err(StoreError::DoesNotExist(_)) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for name in names {
+        match store.remove(&format!("{}{}", prefix, name)).await {
+            Ok(()) | Err(StoreError::DoesNotExist(_)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+fn store_error(e: impl std::fmt::Display) -> AppError {
+    AppError(StatusCode::INTERNAL_SERVER_ERROR, anyhow!("{}", e))
+}
+
+fn checked_store(server_state: &Server, doc_id: &str) -> Result<Arc<Box<dyn Store>>, AppError> {
+    if !validate_doc_name(doc_id) {
+        return Err(AppError(
+            StatusCode::BAD_REQUEST,
+            anyhow!("Invalid document ID"),
+        ));
+    }
+    server_state.store.clone().ok_or_else(|| {
+        AppError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            anyhow!("No store configured"),
+        )
+    })
+}
+
+/// Publish a read-only snapshot of a document at a public URL
+pub async fn publish_document(
+    Path(doc_id): Path<String>,
+    TypedHeader(host): TypedHeader<headers::Host>,
+    client: Option<Extension<ClientInfo>>,
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    body: Option<Json<DocPublishRequest>>,
+) -> Result<Json<DocPublishResponse>, AppError> {
+    server_state.check_auth(auth_header.clone())?;
+    let store = checked_store(&server_state, &doc_id)?;
+    ext_check_not_archived(&server_state, &doc_id).await?;
+    if !server_state.doc_exists(&doc_id).await {
+        return Err(AppError(
+            StatusCode::NOT_FOUND,
+            anyhow!("Document not found"),
+        ));
+    }
+
+    let Json(DocPublishRequest { format, root }) = body.unwrap_or_default();
+    let root = root.unwrap_or_else(|| DEFAULT_TEXT_ROOT.to_string());
+    let update = server_state
+        .get_or_create_doc(&doc_id)
+        .await
+        .map_err(|e| AppError(StatusCode::INTERNAL_SERVER_ERROR, e))?
+        .as_update();
+    let rendered = {
+        let server_state = server_state.clone();
+        let doc_id = doc_id.clone();
+        tokio::task::spawn_blocking(move || {
+            render_published(&server_state, &doc_id, &update, &root, format)
+        })
+        .await
+        .map_err(store_error)?
+        .map_err(|e| AppError(StatusCode::INTERNAL_SERVER_ERROR, e))?
+    };
+
+    store
+        .set(&published_key(&doc_id, format), rendered)
+        .await
+        .map_err(store_error)?;
+    // Only one format is published at a time
+    for other in [PublishFormat::Html, PublishFormat::Markdown] {
+        if other != format {
+            store
+                .remove(&published_key(&doc_id, other))
+                .await
+                .map_err(store_error)?;
+        }
+    }
+
+    record_audit(
+        &server_state,
+        &doc_id,
+        "published",
+        AuditPrincipal::server(&auth_header),
+        Some(json!({ "format": format })),
+    )
+    .await;
+
+    let client = client.map(|Extension(client)| client).unwrap_or_default();
+    let url = format!(
+        "{}/{}",
+        server_state.doc_base_url(&doc_id, &host, client),
+        PUBLISHED_DIR
+    );
+    Ok(Json(DocPublishResponse {
+        doc_id,
+        published: true,
+        url: Some(url),
+        format: Some(format),
+    }))
+}
+
+/// Remove the published snapshot of a document
+pub async fn unpublish_document(
+    Path(doc_id): Path<String>,
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+) -> Result<Json<DocPublishResponse>, AppError> {
+    server_state.check_auth(auth_header.clone())?;
+    let store = checked_store(&server_state, &doc_id)?;
+
+    let Some(format) = published_format(&store, &doc_id)
+        .await
+        .map_err(store_error)?
+    else {
+        return Err(AppError(
+            StatusCode::NOT_FOUND,
+            anyhow!("Document is not published"),
+        ));
+    };
+    store
+        .remove(&published_key(&doc_id, format))
+        .await
+        .map_err(store_error)?;
+
+    record_audit(
+        &server_state,
+        &doc_id,
+        "unpublished",
+        AuditPrincipal::server(&auth_header),
+        None,
+    )
+    .await;
+
+    Ok(Json(DocPublishResponse {
+        doc_id,
+        published: false,
+        url: None,
+        format: None,
+    }))
+}
+
+/// Serve the published snapshot of a document, to anyone
+pub async fn get_published_document(
+    Path(doc_id): Path<String>,
+    State(server_state): State<Arc<Server>>,
+) -> Result<Response, AppError> {
+    let store = checked_store(&server_state, &doc_id)?;
+    let not_published = || AppError(StatusCode::NOT_FOUND, anyhow!("Document is not published"));
+    // Snapshots published before their document was archived may still be in the store
+    if is_doc_archived(&server_state, &doc_id).await? {
+        return Err(not_published());
+    }
+    let format = published_format(&store, &doc_id)
+        .await
+        .map_err(store_error)?
+        .ok_or_else(not_published)?;
+    let content = store
+        .get(&published_key(&doc_id, format))
+        .await
+        .map_err(store_error)?
+        .ok_or_else(not_published)?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(content_type(format)),
+    );
+    headers.insert(
+        header::CONTENT_SECURITY_POLICY,
+        HeaderValue::from_static(PUBLISHED_CSP),
+    );
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_str(&format!("public, max-age={}", PUBLISHED_MAX_AGE_SECONDS))
+            .map_err(store_error)?,
+    );
+    Ok((headers, content).into_response())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stores::memory::MemoryStore;
+    use axum::{body::Body, http::Request};
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+    use y_sweet_core::auth::Authenticator;
+    use yrs::{Transact, XmlElementPrelim, XmlFragment, XmlTextPrelim};
+
+    #[tokio::test]
+    async fn test_publish_and_unpublish() {
+        let store = MemoryStore::new();
+        let auth = Authenticator::gen_key().unwrap();
+        let server_token = auth.server_token();
+        let server = Arc::new(
+            Server::builder()
+                .store(Some(Box::new(store.clone())))
+                .authenticator(Some(auth))
+                .build()
+                .await
+                .unwrap(),
+        );
+        let doc_id = server.create_doc().await.unwrap();
+        {
+            let doc = server.get_or_create_doc(&doc_id).await.unwrap();
+            let awareness = doc.awareness();
+            let awareness = awareness.write().unwrap();
+            let fragment = awareness.doc().get_or_insert_xml_fragment("default");
+            let mut txn = awareness.doc().transact_mut();
+            let paragraph = fragment.push_back(&mut txn, XmlElementPrelim::empty("paragraph"));
+            paragraph.push_back(&mut txn, XmlTextPrelim::new("Release <notes>"));
+        }
+
+        let publish = |body: &str| {
+            Request::builder()
+                .method("POST")
+                .uri(format!("/d/{}/publish", doc_id))
+                .header("host", "docs.example.com")
+                .header("authorization", format!("Bearer {}", server_token))
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+        let published = || {
+            Request::builder()
+                .uri(format!("/d/{}/published", doc_id))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = server.routes().oneshot(publish("{}")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: DocPublishResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body.url.as_deref(),
+            Some(format!("http://docs.example.com/d/{}/published", doc_id).as_str())
+        );
+
+        let response = server.routes().oneshot(published()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/html; charset=utf-8"
+        );
+        let page = response.into_body().collect().await.unwrap().to_bytes();
+        let page = String::from_utf8(page.to_vec()).unwrap();
+        assert!(page.starts_with("<!DOCTYPE html>"));
+        assert!(page.contains("<p>Release &lt;notes&gt;</p>"));
+
+        // Republishing as Markdown replaces the page
+        let response = server
+            .routes()
+            .oneshot(publish(r#"{"format": "markdown"}"#))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(store
+            .get(&published_key(&doc_id, PublishFormat::Html))
+            .await
+            .unwrap()
+            .is_none());
+        let response = server.routes().oneshot(published()).await.unwrap();
+        let page = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&page[..], b"Release <notes>");
+
+        let request = Request::builder()
+            .method("POST")
+            .uri(format!("/d/{}/unpublish", doc_id))
+            .header("authorization", format!("Bearer {}", server_token))
+            .body(Body::empty())
+            .unwrap();
+        let response = server.routes().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = server.routes().oneshot(published()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_archived_and_deleted_docs_are_not_published() {
+        let store = MemoryStore::new();
+        let auth = Authenticator::gen_key().unwrap();
+        let server_token = auth.server_token();
+        let server = Arc::new(
+            Server::builder()
+                .store(Some(Box::new(store.clone())))
+                .authenticator(Some(auth))
+                .build()
+                .await
+                .unwrap(),
+        );
+        let doc_id = server.create_doc().await.unwrap();
+        {
+            let doc = server.get_or_create_doc(&doc_id).await.unwrap();
+            let awareness = doc.awareness();
+            let awareness = awareness.write().unwrap();
+            let fragment = awareness.doc().get_or_insert_xml_fragment("default");
+            let mut txn = awareness.doc().transact_mut();
+            fragment.push_back(&mut txn, XmlTextPrelim::new("Draft"));
+        }
+
+        let admin = |method: &str, path: &str| {
+            Request::builder()
+                .method(method)
+                .uri(format!("/d/{}{}", doc_id, path))
+                .header("host", "docs.example.com")
+                .header("authorization", format!("Bearer {}", server_token))
+                .body(Body::empty())
+                .unwrap()
+        };
+        let published = || {
+            Request::builder()
+                .uri(format!("/d/{}/published", doc_id))
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = server
+            .routes()
+            .oneshot(admin("POST", "/publish"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // The snapshot moves to the archive with the document, and back
+        let response = server
+            .routes()
+            .oneshot(admin("POST", "/archive"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = server.routes().oneshot(published()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(store
+            .get(&published_key(&doc_id, PublishFormat::Html))
+            .await
+            .unwrap()
+            .is_none());
+        let response = server
+            .routes()
+            .oneshot(admin("POST", "/unarchive"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = server.routes().oneshot(published()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = server.routes().oneshot(admin("DELETE", "")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = server.routes().oneshot(published()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(store
+            .list_objects(&format!("{}/{}/", doc_id, PUBLISHED_DIR))
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
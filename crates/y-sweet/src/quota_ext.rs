@@ -0,0 +1,627 @@
+//! Quotas on the documents of a server.
+//!
+//! A quota covers the documents whose ID starts with a prefix (every document for an empty
+//! prefix) and limits their number, the total size of their stored state and the total
+//! size of their assets. Creating a document is refused once the number of documents
+//! reaches the limit, applying an update once the stored state would exceed it, and
+//! presigning an asset upload once the size it declares would exceed it. The presigned URL
+//! does not bind the size of the upload, so the declared size is trusted. Tenants have a
+//! quota of their own, covering all of their documents.
+//!
+//! Usage is counted from the store when the server starts, then kept up to date as
+//! documents are loaded, updated, persisted and deleted: the stored state of a document
+//! grows by the size of each update applied to it, and is measured again each time the
+//! document is persisted.
+//!
+//! `y-sweet serve --quotas quotas.toml` reads the quotas from a file:
+//!
+//! ```toml
+//! [[quotas]]
+//! prefix = "team-a-"
+//! max_docs = 1000
+//! max_snapshot_bytes = 1_000_000_000
+//! max_asset_bytes = 10_000_000_000
+//! ```
+
+#[cfg(feature = "config-files")]
+use anyhow::Context;
+use anyhow::{anyhow, Result};
+use axum::{extract::State, http::StatusCode, Json};
+use axum_extra::typed_header::TypedHeader;
+use dashmap::{mapref::entry::Entry, DashMap};
+use serde::Deserialize;
+#[cfg(feature = "config-files")]
+use std::path::Path;
+use std::{
+    collections::BTreeSet,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tracing::{error, info};
+use y_sweet_core::{
+    api_types_ext::{QuotaStatus, QuotasResponse},
+    store::Store,
+    subdoc_ext::is_subdoc_key,
+    validate_ext::{IncomingUpdate, UpdateValidator},
+};
+
+use crate::server::{AppError, Server};
+use crate::server_ext::list_document_objects;
+
+/// Limits on the documents covered by a quota. A limit that is not set is not enforced.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quota {
+    #[serde(default)]
+    pub max_docs: Option<u64>,
+    /// Total size of the stored state of the documents: snapshots, update logs and backups.
+    #[serde(default)]
+    pub max_snapshot_bytes: Option<u64>,
+    #[serde(default)]
+    pub max_asset_bytes: Option<u64>,
+}
+
+/// A quota on the documents whose ID starts with `prefix`.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct QuotaRule {
+    #[serde(default)]
+    pub prefix: String,
+    #[serde(flatten)]
+    pub quota: Quota,
+}
+
+impl QuotaRule {
+    /// A quota on every document of the server.
+    pub fn all(quota: Quota) -> Self {
+        Self {
+            prefix: String::new(),
+            quota,
+        }
+    }
+}
+
+#[cfg(feature = "config-files")]
+#[derive(Deserialize)]
+struct QuotasFile {
+    quotas: Vec<QuotaRule>,
+}
+
+/// Read the quotas file at `path`, a TOML file (or YAML for `.yaml` and `.yml` files).
+#[cfg(feature = "config-files")]
+pub fn load_quotas(path: &Path) -> Result<Vec<QuotaRule>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read the quotas file {:?}", path))?;
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default();
+    let file: QuotasFile = match extension {
+        "yaml" | "yml" => serde_yaml::from_str(&content)
+            .with_context(|| format!("Invalid quotas file {:?}", path))?,
+        _ => toml::from_str(&content).with_context(|| format!("Invalid quotas file {:?}", path))?,
+    };
+    Ok(file.quotas)
+}
+
+/// A change refused because it would exceed a quota.
+#[derive(Debug)]
+pub struct QuotaExceeded {
+    pub prefix: String,
+    pub resource: &'static str,
+    pub limit: u64,
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.prefix.is_empty() {
+            write!(
+                f,
+                "Quota exceeded: at most {} {}",
+                self.limit, self.resource
+            )
+        } else {
+            write!(
+                f,
+                "Quota exceeded: at most {} {} for documents starting with {:?}",
+                self.limit, self.resource, self.prefix
+            )
+        }
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+impl From<QuotaExceeded> for AppError {
+    fn from(e: QuotaExceeded) -> Self {
+        AppError(StatusCode::FORBIDDEN, e.into())
+    }
+}
+
+#[derive(Default)]
+struct Usage {
+    docs: AtomicU64,
+    snapshot_bytes: AtomicU64,
+    asset_bytes: AtomicU64,
+}
+
+#[derive(Default, Clone, Copy)]
+struct DocUsage {
+    snapshot_bytes: u64,
+    asset_bytes: u64,
+}
+
+fn adjust(counter: &AtomicU64, before: u64, after: u64) {
+    if after >= before {
+        counter.fetch_add(after - before, Ordering::Relaxed);
+    } else {
+        counter.fetch_sub(before - after, Ordering::Relaxed);
+    }
+}
+
+/// The quotas of a server, with the usage of the documents they cover.
+pub struct Quotas {
+    rules: Vec<(QuotaRule, Usage)>,
+    docs: DashMap<String, DocUsage>,
+    counted: AtomicBool,
+}
+
+impl Quotas {
+    pub fn new(rules: Vec<QuotaRule>) -> Self {
+        Self {
+            rules: rules
+                .into_iter()
+                .map(|rule| (rule, Usage::default()))
+                .collect(),
+            docs: DashMap::new(),
+            counted: AtomicBool::new(false),
+        }
+    }
+
+    fn covering<'a>(&'a self, doc_id: &'a str) -> impl Iterator<Item = &'a (QuotaRule, Usage)> {
+        self.rules
+            .iter()
+            .filter(move |(rule, _)| doc_id.starts_with(&rule.prefix))
+    }
+
+    fn apply(&self, doc_id: &str, is_new: bool, before: DocUsage, after: DocUsage) {
+        for (_, usage) in self.covering(doc_id) {
+            if is_new {
+                usage.docs.fetch_add(1, Ordering::Relaxed);
+            }
+            adjust(
+                &usage.snapshot_bytes,
+                before.snapshot_bytes,
+                after.snapshot_bytes,
+            );
+            adjust(&usage.asset_bytes, before.asset_bytes, after.asset_bytes);
+        }
+    }
+
+    /// Change the usage of `doc_id`, counting it as a document if it is new.
+    fn set(&self, doc_id: &str, update: impl FnOnce(&mut DocUsage)) {
+        match self.docs.entry(doc_id.to_string()) {
+            Entry::Occupied(mut entry) => {
+                let before = *entry.get();
+                update(entry.get_mut());
+                self.apply(doc_id, false, before, *entry.get());
+            }
+            Entry::Vacant(entry) => {
+                let mut after = DocUsage::default();
+                update(&mut after);
+                self.apply(doc_id, true, DocUsage::default(), after);
+                entry.insert(after);
+            }
+        }
+    }
+
+    /// Whether the usage of `doc_id` is counted.
+    pub fn contains(&self, doc_id: &str) -> bool {
+        self.docs.contains_key(doc_id)
+    }
+
+    /// Whether the documents in the store were counted.
+    pub fn counted(&self) -> bool {
+        self.counted.load(Ordering::Relaxed)
+    }
+
+    /// Refuse the creation of `doc_id` if a quota covering it has no room for another
+    /// document.
+    pub fn check_new_doc(&self, doc_id: &str) -> Result<(), QuotaExceeded> {
+        if self.contains(doc_id) {
+            return Ok(());
+        }
+        for (rule, usage) in self.covering(doc_id) {
+            if let Some(limit) = rule.quota.max_docs {
+                if usage.docs.load(Ordering::Relaxed) >= limit {
+                    return Err(QuotaExceeded {
+                        prefix: rule.prefix.clone(),
+                        resource: "documents",
+                        limit,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Refuse an update of `update_len` bytes to `doc_id` if the stored state would exceed
+    /// a quota covering it. Otherwise, the update is counted in the size of the document.
+    pub fn check_update(&self, doc_id: &str, update_len: u64) -> Result<(), QuotaExceeded> {
+        // Subdocuments count towards their parent
+        let doc_id = doc_id.split('/').next().unwrap_or(doc_id);
+        for (rule, usage) in self.covering(doc_id) {
+            if let Some(limit) = rule.quota.max_snapshot_bytes {
+                if usage.snapshot_bytes.load(Ordering::Relaxed) + update_len > limit {
+                    return Err(QuotaExceeded {
+                        prefix: rule.prefix.clone(),
+                        resource: "bytes of document state",
+                        limit,
+                    });
+                }
+            }
+        }
+        self.set(doc_id, |doc| doc.snapshot_bytes += update_len);
+        Ok(())
+    }
+
+    /// Whether a quota limits the assets of `doc_id`.
+    pub fn limits_assets(&self, doc_id: &str) -> bool {
+        self.covering(doc_id)
+            .any(|(rule, _)| rule.quota.max_asset_bytes.is_some())
+    }
+
+    /// Refuse an asset of `asset_len` bytes for `doc_id` if it would exceed a quota covering
+    /// it. Otherwise, the asset is counted.
+    pub fn check_asset(&self, doc_id: &str, asset_len: u64) -> Result<(), QuotaExceeded> {
+        for (rule, usage) in self.covering(doc_id) {
+            if let Some(limit) = rule.quota.max_asset_bytes {
+                if usage.asset_bytes.load(Ordering::Relaxed) + asset_len > limit {
+                    return Err(QuotaExceeded {
+                        prefix: rule.prefix.clone(),
+                        resource: "bytes of assets",
+                        limit,
+                    });
+                }
+            }
+        }
+        self.set(doc_id, |doc| doc.asset_bytes += asset_len);
+        Ok(())
+    }
+
+    /// Record the size of the stored state of `doc_id`, when it is loaded or persisted.
+    pub fn record_doc(&self, doc_id: &str, snapshot_bytes: u64) {
+        if is_subdoc_key(doc_id) {
+            return;
+        }
+        self.set(doc_id, |doc| doc.snapshot_bytes = snapshot_bytes);
+    }
+
+    /// Stop counting `doc_id`, after it is removed from the store.
+    pub fn record_removed(&self, doc_id: &str) {
+        let Some((_, before)) = self.docs.remove(doc_id) else {
+            return;
+        };
+        for (_, usage) in self.covering(doc_id) {
+            usage.docs.fetch_sub(1, Ordering::Relaxed);
+            adjust(&usage.snapshot_bytes, before.snapshot_bytes, 0);
+            adjust(&usage.asset_bytes, before.asset_bytes, 0);
+        }
+    }
+
+    /// Measure the objects of `doc_id` in `store`. The size of the state of a document
+    /// that is already counted is kept, as it includes updates not persisted yet.
+    pub async fn count_doc(&self, store: &Arc<Box<dyn Store>>, doc_id: &str) -> Result<()> {
+        if self.covering(doc_id).next().is_none() {
+            return Ok(());
+        }
+        let keys = list_document_objects(store, doc_id)
+            .await
+            .map_err(|e| e.1)?;
+        let assets_prefix = format!("{}/assets/", doc_id);
+        let mut measured = DocUsage::default();
+        for key in keys {
+            let size = store.object_size(&key).await?.unwrap_or_default();
+            if key.starts_with(&assets_prefix) {
+                measured.asset_bytes += size;
+            } else {
+                measured.snapshot_bytes += size;
+            }
+        }
+        let counted = self.contains(doc_id);
+        self.set(doc_id, |doc| {
+            if !counted {
+                doc.snapshot_bytes = measured.snapshot_bytes;
+            }
+            doc.asset_bytes = measured.asset_bytes;
+        });
+        Ok(())
+    }
+
+    /// Count the documents in `store` that the quotas cover.
+    pub async fn count_store(&self, store: &Arc<Box<dyn Store>>) -> Result<()> {
+        let prefixes: BTreeSet<&str> = self
+            .rules
+            .iter()
+            .map(|(rule, _)| rule.prefix.as_str())
+            .collect();
+        let mut doc_ids = BTreeSet::new();
+        for prefix in prefixes {
+            doc_ids.extend(store.list_documents(prefix).await?);
+        }
+        for doc_id in doc_ids {
+            self.count_doc(store, &doc_id).await?;
+        }
+        self.counted.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// The limits of every quota and the usage of the documents it covers.
+    pub fn status(&self) -> Vec<QuotaStatus> {
+        self.rules
+            .iter()
+            .map(|(rule, usage)| QuotaStatus {
+                prefix: rule.prefix.clone(),
+                max_docs: rule.quota.max_docs,
+                max_snapshot_bytes: rule.quota.max_snapshot_bytes,
+                max_asset_bytes: rule.quota.max_asset_bytes,
+                docs: usage.docs.load(Ordering::Relaxed),
+                snapshot_bytes: usage.snapshot_bytes.load(Ordering::Relaxed),
+                asset_bytes: usage.asset_bytes.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+/// Refuses the updates that would exceed a quota, after those `inner` rejects.
+pub(crate) struct QuotaValidator {
+    pub quotas: Arc<Quotas>,
+    pub inner: Option<Arc<dyn UpdateValidator>>,
+}
+
+impl UpdateValidator for QuotaValidator {
+    fn validate(&self, update: &IncomingUpdate) -> Result<()> {
+        if let Some(inner) = &self.inner {
+            inner.validate(update)?;
+        }
+        self.quotas
+            .check_update(update.doc_id, update.update.len() as u64)?;
+        Ok(())
+    }
+}
+
+/// Refuse the creation of `doc_id` if it would exceed a quota of the server. Documents
+/// that are already in the store are not new.
+pub async fn ext_check_doc_quota(server_state: &Server, doc_id: &str) -> Result<(), AppError> {
+    let Some(quotas) = server_state.quotas() else {
+        return Ok(());
+    };
+    if quotas.contains(doc_id) || server_state.doc_exists(doc_id).await {
+        return Ok(());
+    }
+    Ok(quotas.check_new_doc(doc_id)?)
+}
+
+/// Refuse an asset upload to `doc_id` of `content_length` bytes if it would exceed a quota
+/// of the server.
+pub fn ext_check_asset_quota(
+    server_state: &Server,
+    doc_id: &str,
+    content_length: Option<u64>,
+) -> Result<(), AppError> {
+    let Some(quotas) = server_state.quotas() else {
+        return Ok(());
+    };
+    if !quotas.limits_assets(doc_id) {
+        return Ok(());
+    }
+    let Some(content_length) = content_length else {
+        return Err(AppError(
+            StatusCode::BAD_REQUEST,
+            anyhow!("contentLength is required by the asset quota of the document"),
+        ));
+    };
+    Ok(quotas.check_asset(doc_id, content_length)?)
+}
+
+/// Count the objects of `doc_id` after they were written to the store without loading the
+/// document, by a copy or a restore.
+pub async fn ext_count_doc(server_state: &Server, doc_id: &str) {
+    let (Some(quotas), Some(store)) = (server_state.quotas(), &server_state.store) else {
+        return;
+    };
+    if let Err(e) = quotas.count_doc(store, doc_id).await {
+        error!(
+            message = "Failed to count the objects of a document for quotas",
+            event = "quota_count_failed",
+            doc_id = %doc_id,
+            error = %e
+        );
+    }
+}
+
+/// Count the documents of the store towards the quotas of the server
+pub(crate) async fn quota_count_worker(server: Arc<Server>) {
+    let (Some(quotas), Some(store)) = (server.quotas(), &server.store) else {
+        return;
+    };
+    match quotas.count_store(store).await {
+        Ok(()) => info!(
+            message = "Counted the documents of the store for quotas",
+            event = "quota_count_completed",
+            docs = quotas.docs.len()
+        ),
+        Err(e) => error!(
+            message = "Failed to count the documents of the store for quotas",
+            event = "quota_count_failed",
+            error = %e
+        ),
+    }
+}
+
+/// List the quotas of the server with the usage of the documents they cover
+pub async fn list_quotas(
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+) -> Result<Json<QuotasResponse>, AppError> {
+    server_state.check_auth(auth_header)?;
+    Ok(Json(match server_state.quotas() {
+        Some(quotas) => QuotasResponse {
+            counted: quotas.counted(),
+            quotas: quotas.status(),
+        },
+        None => QuotasResponse {
+            counted: true,
+            quotas: Vec::new(),
+        },
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stores::memory::MemoryStore;
+    use axum::{
+        body::Body,
+        http::{header, Request},
+        response::Response,
+    };
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+    use y_sweet_core::auth::Authenticator;
+    use yrs::{Doc, ReadTxn, StateVector, Text, Transact};
+
+    async fn send(
+        server: &Arc<Server>,
+        method: &str,
+        path: &str,
+        token: &str,
+        body: Body,
+    ) -> Response {
+        let request = Request::builder()
+            .method(method)
+            .uri(path)
+            .header(header::HOST, "localhost")
+            .header(header::AUTHORIZATION, format!("Bearer {}", token))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .unwrap();
+        server.routes().oneshot(request).await.unwrap()
+    }
+
+    fn text_update(text: &str) -> Vec<u8> {
+        let doc = Doc::new();
+        let field = doc.get_or_insert_text("text");
+        field.insert(&mut doc.transact_mut(), 0, text);
+        let update = doc
+            .transact()
+            .encode_state_as_update_v1(&StateVector::default());
+        update
+    }
+
+    #[tokio::test]
+    async fn test_quotas_are_enforced_per_prefix() {
+        let auth = Authenticator::gen_key().unwrap();
+        let token = auth.server_token();
+        let server = Arc::new(
+            Server::builder()
+                .store(Some(Box::new(MemoryStore::new())))
+                .authenticator(Some(auth))
+                .build()
+                .await
+                .unwrap()
+                .with_quotas(vec![QuotaRule {
+                    prefix: "team-".to_string(),
+                    quota: Quota {
+                        max_docs: Some(1),
+                        max_snapshot_bytes: Some(200),
+                        max_asset_bytes: Some(100),
+                    },
+                }]),
+        );
+        let new_doc = |doc_id: &str| Body::from(format!("{{\"docId\": \"{}\"}}", doc_id));
+
+        let response = send(&server, "POST", "/doc/new", &token, new_doc("team-a")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send(&server, "POST", "/doc/new", &token, new_doc("team-b")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = send(&server, "POST", "/doc/new", &token, new_doc("other")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let update = Body::from(text_update("hello"));
+        let response = send(&server, "POST", "/d/team-a/update", &token, update).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let update = Body::from(text_update(&"x".repeat(300)));
+        let response = send(&server, "POST", "/d/team-a/update", &token, update).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let update = Body::from(text_update(&"x".repeat(300)));
+        let response = send(&server, "POST", "/d/other/update", &token, update).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        #[cfg(feature = "assets")]
+        {
+            let asset = |length: Option<u64>| {
+                let mut body = serde_json::json!({ "contentType": "image/png" });
+                if let Some(length) = length {
+                    body["contentLength"] = length.into();
+                }
+                Body::from(body.to_string())
+            };
+            let response = send(&server, "POST", "/d/team-a/assets", &token, asset(None)).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            let response = send(&server, "POST", "/d/team-a/assets", &token, asset(Some(60))).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let response = send(&server, "POST", "/d/team-a/assets", &token, asset(Some(60))).await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+            let response = send(&server, "POST", "/d/other/assets", &token, asset(None)).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let response = send(&server, "GET", "/quotas", &token, Body::empty()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let status: QuotasResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(status.quotas.len(), 1);
+        assert_eq!(status.quotas[0].prefix, "team-");
+        assert_eq!(status.quotas[0].docs, 1);
+        assert!(status.quotas[0].snapshot_bytes > 0);
+        assert!(status.quotas[0].snapshot_bytes <= 200);
+
+        // Deleting a document makes room for another
+        let response = send(&server, "DELETE", "/d/team-a", &token, Body::empty()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send(&server, "POST", "/doc/new", &token, new_doc("team-b")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_usage_is_counted_from_the_store() {
+        let store = MemoryStore::new();
+        store.insert_object("team-a/data.ysweet", vec![0; 10]);
+        store.insert_object("team-a/assets/logo.png", vec![0; 25]);
+        store.insert_object("other/data.ysweet", vec![0; 40]);
+        let store: Arc<Box<dyn Store>> = Arc::new(Box::new(store));
+
+        let quotas = Quotas::new(vec![
+            QuotaRule {
+                prefix: "team-".to_string(),
+                quota: Quota::default(),
+            },
+            QuotaRule::all(Quota::default()),
+        ]);
+        quotas.count_store(&store).await.unwrap();
+        assert!(quotas.counted());
+
+        let usage: Vec<_> = quotas
+            .status()
+            .into_iter()
+            .map(|quota| (quota.docs, quota.snapshot_bytes, quota.asset_bytes))
+            .collect();
+        assert_eq!(usage, vec![(1, 10, 25), (2, 50, 25)]);
+
+        quotas.record_removed("team-a");
+        assert_eq!(quotas.status()[1].docs, 1);
+        assert_eq!(quotas.status()[1].asset_bytes, 0);
+    }
+}
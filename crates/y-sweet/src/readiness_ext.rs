@@ -0,0 +1,150 @@
+//! Readiness of the server for load balancers (`/ready`), and its liveness (`/live`).
+//!
+//! `/ready` fails with 503 and `"status": "draining"` once the server shuts down, along with
+//! the WebSocket connections and persists still in flight. As the listener stops accepting
+//! connections when the shutdown starts, `--shutdown-delay-seconds` keeps serving requests
+//! for that long after the shutdown signal, reporting `draining`, so that load balancers
+//! stop routing to the server before it closes its connections. `/live` only reports that
+//! the process responds, for restarting it when it does not.
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc,
+};
+
+use crate::server::Server;
+
+/// Whether the server is shutting down, and the work it still has in flight.
+#[derive(Debug, Default)]
+pub struct Readiness {
+    draining: AtomicBool,
+    persists: AtomicUsize,
+}
+
+impl Readiness {
+    /// Report the server as draining from now on.
+    pub fn start_draining(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Count a persist as in flight while the returned guard lives.
+    pub(crate) fn persisting(&self) -> PersistInFlight<'_> {
+        self.persists.fetch_add(1, Ordering::SeqCst);
+        PersistInFlight { readiness: self }
+    }
+
+    /// Number of documents being persisted.
+    pub fn persists_in_flight(&self) -> usize {
+        self.persists.load(Ordering::SeqCst)
+    }
+}
+
+/// A persist in flight, until dropped.
+pub(crate) struct PersistInFlight<'a> {
+    readiness: &'a Readiness,
+}
+
+impl Drop for PersistInFlight<'_> {
+    fn drop(&mut self) {
+        self.readiness.persists.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Returns 200 OK while the server accepts work, or 503 while documents are still being
+/// preloaded or once it is shutting down, with the connections and persists in flight.
+pub async fn ready(State(server_state): State<Arc<Server>>) -> Response {
+    let readiness = server_state.readiness();
+    let progress = server_state.preload_progress();
+    let preloading = server_state.has_preload() && !progress.is_done();
+    let status = if server_state.is_draining() {
+        "draining"
+    } else if preloading {
+        "preloading"
+    } else {
+        "ready"
+    };
+    let websockets = server_state.connection_limits().total()
+        + server_state
+            .tenants()
+            .iter()
+            .map(|tenant| tenant.server.connection_limits().total())
+            .sum::<usize>();
+
+    let ok = status == "ready";
+    let mut body = json!({
+        "ok": ok,
+        "status": status,
+        "websockets": websockets,
+        "persistsInFlight": readiness.persists_in_flight(),
+    });
+    if server_state.has_preload() {
+        body["preload"] = json!({
+            "total": progress.total(),
+            "loaded": progress.loaded(),
+            "failed": progress.failed(),
+        });
+    }
+    let code = if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (code, Json(body)).into_response()
+}
+
+/// Returns 200 OK as long as the server responds.
+pub async fn live() -> Json<serde_json::Value> {
+    Json(json!({"ok": true}))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stores::memory::MemoryStore;
+    use axum::{body::Body, http::Request};
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_ready_reports_draining_once_shutdown_starts() {
+        let server = Arc::new(
+            Server::builder()
+                .store(Some(Box::new(MemoryStore::new())))
+                .build()
+                .await
+                .unwrap(),
+        );
+        let get = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+        let response = server.routes().oneshot(get("/ready")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let readiness = server.readiness();
+        let persist = readiness.persisting();
+        readiness.start_draining();
+        let response = server.routes().oneshot(get("/ready")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["status"], "draining");
+        assert_eq!(body["persistsInFlight"], 1);
+        assert_eq!(body["websockets"], 0);
+        drop(persist);
+        assert_eq!(readiness.persists_in_flight(), 0);
+
+        // Still alive while draining
+        let response = server.routes().oneshot(get("/live")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,426 @@
+//! Redaction of document IDs, client addresses and user agents from logs and traces, for
+//! log pipelines that must not receive customer data.
+//!
+//! With `--redact-fields doc_id,remote_addr,user_agent` (any of them), the values of those
+//! fields are replaced by `[redacted]`, or by a hash with `--redact-mode hash` so that the
+//! events of one document can still be correlated. Document IDs are also redacted from the
+//! paths of requests (`uri`, `url.path`, ...), and every value redacted from an event is
+//! redacted from its other fields too, such as its message.
+//!
+//! The values of query parameters are always left out of the requests logged (see
+//! [redact_query]), since they may hold tokens and secrets.
+//!
+//! Log lines are redacted as written, so redaction requires `--log-format json`. Spans
+//! exported over OTLP are redacted before export; those exported to Datadog APM cannot be.
+
+use axum::http::Uri;
+use sha2::{Digest, Sha256};
+use std::io::{self, Write};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// What is redacted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum RedactField {
+    /// Document IDs, in `doc_id` fields (and `source_doc_id` etc.) and request paths.
+    #[value(name = "doc_id")]
+    DocId,
+    /// Addresses of clients.
+    #[value(name = "remote_addr")]
+    RemoteAddr,
+    /// User agents of clients.
+    #[value(name = "user_agent")]
+    UserAgent,
+}
+
+impl RedactField {
+    fn matches(&self, key: &str) -> bool {
+        match self {
+            RedactField::DocId => key.ends_with("doc_id"),
+            RedactField::RemoteAddr => {
+                matches!(
+                    key,
+                    "remote_addr" | "client.address" | "network.peer.address"
+                )
+            }
+            RedactField::UserAgent => {
+                matches!(
+                    key,
+                    "user_agent" | "user_agent.original" | "http.user_agent"
+                )
+            }
+        }
+    }
+}
+
+/// What redacted values are replaced with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum RedactMode {
+    /// `[redacted]`.
+    #[default]
+    Redact,
+    /// A hash of the value, the same for every occurrence.
+    Hash,
+}
+
+/// Fields holding request paths, whose document IDs are redacted.
+const PATH_KEYS: &[&str] = &["uri", "url.path", "url.full", "http.target"];
+
+/// Which fields are redacted from logs and traces, and how.
+#[derive(Clone, Debug, Default)]
+pub struct Redaction {
+    fields: Vec<RedactField>,
+    mode: RedactMode,
+}
+
+impl Redaction {
+    pub fn new(fields: Vec<RedactField>, mode: RedactMode) -> Self {
+        Self { fields, mode }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    fn replacement(&self, value: &str) -> String {
+        match self.mode {
+            RedactMode::Redact => "[redacted]".to_string(),
+            RedactMode::Hash => format!(
+                "hash:{}",
+                data_encoding::HEXLOWER.encode(&Sha256::digest(value.as_bytes())[..8])
+            ),
+        }
+    }
+
+    fn is_redacted(&self, key: &str) -> bool {
+        self.fields.iter().any(|field| field.matches(key))
+    }
+
+    /// The values to redact from the fields of an event, given as key and value, with
+    /// what they are replaced with.
+    pub fn secrets<'a>(
+        &self,
+        fields: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Vec<(String, String)> {
+        let mut secrets: Vec<(String, String)> = Vec::new();
+        let mut add = |value: &str| {
+            if !value.is_empty() && !secrets.iter().any(|(secret, _)| secret == value) {
+                secrets.push((value.to_string(), self.replacement(value)));
+            }
+        };
+        for (key, value) in fields {
+            if self.is_redacted(key) {
+                add(value);
+            } else if PATH_KEYS.contains(&key) && self.fields.contains(&RedactField::DocId) {
+                for doc_id in doc_ids_of_path(value) {
+                    add(doc_id);
+                }
+            }
+        }
+        // Longest first, so that no secret is left partly replaced by another
+        secrets.sort_by_key(|(secret, _)| std::cmp::Reverse(secret.len()));
+        secrets
+    }
+
+    /// The value of the field `key` with `secrets` redacted.
+    pub fn redact(&self, key: &str, value: &str, secrets: &[(String, String)]) -> String {
+        if self.is_redacted(key) {
+            return self.replacement(value);
+        }
+        let mut value = value.to_string();
+        for (secret, replacement) in secrets {
+            if value.contains(secret.as_str()) {
+                value = value.replace(secret.as_str(), replacement);
+            }
+        }
+        value
+    }
+
+    /// Redact a JSON log line, in place.
+    pub fn redact_json(&self, line: &mut serde_json::Value) {
+        let mut fields = Vec::new();
+        collect_strings(line, &mut fields);
+        let secrets = self.secrets(fields.iter().map(|(key, value)| (*key, value.as_str())));
+        redact_strings(self, line, &secrets);
+    }
+}
+
+/// The document IDs in a request path: after `/d/` or `/doc/` (and `/doc/ws/`).
+fn doc_ids_of_path(path: &str) -> Vec<&str> {
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    let mut doc_ids = Vec::new();
+    let mut segments = path.split('/').peekable();
+    while let Some(segment) = segments.next() {
+        match segment {
+            "d" => {
+                if let Some(doc_id) = segments.next() {
+                    doc_ids.push(doc_id);
+                }
+            }
+            "doc" => {
+                if segments.peek() == Some(&"ws") {
+                    segments.next();
+                }
+                if let Some(doc_id) = segments.next().filter(|id| *id != "new") {
+                    doc_ids.push(doc_id);
+                }
+            }
+            _ => {}
+        }
+    }
+    doc_ids.retain(|doc_id| !doc_id.is_empty());
+    doc_ids
+}
+
+/// The path and query of `uri` as logged, with the values of the query parameters replaced
+/// by `[redacted]`: WebSocket URLs carry client tokens, and `POST /assets/events` a secret.
+pub fn redact_query(uri: &Uri) -> String {
+    let Some(query) = uri.query() else {
+        return uri.path().to_string();
+    };
+    let query = query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((key, _)) => format!("{}=[redacted]", key),
+            None => pair.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    format!("{}?{}", uri.path(), query)
+}
+
+fn collect_strings<'a>(value: &'a serde_json::Value, fields: &mut Vec<(&'a str, String)>) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map {
+                match value {
+                    serde_json::Value::String(s) => fields.push((key, s.clone())),
+                    serde_json::Value::Number(n) => fields.push((key, n.to_string())),
+                    _ => collect_strings(value, fields),
+                }
+            }
+        }
+        serde_json::Value::Array(values) => {
+            for value in values {
+                collect_strings(value, fields);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn redact_strings(
+    redaction: &Redaction,
+    value: &mut serde_json::Value,
+    secrets: &[(String, String)],
+) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                match value {
+                    serde_json::Value::String(s) => *s = redaction.redact(key, s, secrets),
+                    serde_json::Value::Number(n) if redaction.is_redacted(key) => {
+                        *value = redaction.replacement(&n.to_string()).into()
+                    }
+                    _ => redact_strings(redaction, value, secrets),
+                }
+            }
+        }
+        serde_json::Value::Array(values) => {
+            for value in values {
+                redact_strings(redaction, value, secrets);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Writes the JSON log lines of `inner` redacted.
+pub struct RedactingMakeWriter<M> {
+    redaction: Redaction,
+    inner: M,
+}
+
+impl<M> RedactingMakeWriter<M> {
+    pub fn new(redaction: Redaction, inner: M) -> Self {
+        Self { redaction, inner }
+    }
+}
+
+impl<'a, M: MakeWriter<'a>> MakeWriter<'a> for RedactingMakeWriter<M> {
+    type Writer = RedactingWriter<'a, M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RedactingWriter {
+            redaction: &self.redaction,
+            inner: self.inner.make_writer(),
+        }
+    }
+}
+
+/// Redacts each log line written to it, which the formatter writes at once.
+pub struct RedactingWriter<'a, W> {
+    redaction: &'a Redaction,
+    inner: W,
+}
+
+impl<W: Write> Write for RedactingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match serde_json::from_slice::<serde_json::Value>(buf) {
+            Ok(mut line) => {
+                self.redaction.redact_json(&mut line);
+                let mut out = serde_json::to_vec(&line)?;
+                out.push(b'\n');
+                self.inner.write_all(&out)?;
+            }
+            // Not a whole JSON line, so it cannot be told what to redact
+            Err(_) => self.inner.write_all(buf)?,
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Redacts the attributes of spans, and of their events, before passing them on to
+/// `inner` for export.
+#[cfg(feature = "otel")]
+#[derive(Debug)]
+pub struct RedactingSpanProcessor<P> {
+    redaction: Redaction,
+    inner: P,
+}
+
+#[cfg(feature = "otel")]
+impl<P> RedactingSpanProcessor<P> {
+    pub fn new(redaction: Redaction, inner: P) -> Self {
+        Self { redaction, inner }
+    }
+
+    fn redact_attributes(
+        &self,
+        attributes: &mut [opentelemetry::KeyValue],
+        secrets: &[(String, String)],
+    ) {
+        for attribute in attributes {
+            if let opentelemetry::Value::String(value) = &attribute.value {
+                let redacted =
+                    self.redaction
+                        .redact(attribute.key.as_str(), value.as_str(), secrets);
+                attribute.value = opentelemetry::Value::String(redacted.into());
+            }
+        }
+    }
+}
+
+#[cfg(feature = "otel")]
+impl<P: opentelemetry_sdk::trace::SpanProcessor> opentelemetry_sdk::trace::SpanProcessor
+    for RedactingSpanProcessor<P>
+{
+    fn on_start(&self, span: &mut opentelemetry_sdk::trace::Span, cx: &opentelemetry::Context) {
+        self.inner.on_start(span, cx)
+    }
+
+    fn on_end(&self, mut span: opentelemetry_sdk::trace::SpanData) {
+        if self.redaction.is_empty() {
+            return self.inner.on_end(span);
+        }
+        let events = &mut span.events.events;
+        let strings = span
+            .attributes
+            .iter()
+            .chain(events.iter().flat_map(|event| event.attributes.iter()))
+            .filter_map(|attribute| match &attribute.value {
+                opentelemetry::Value::String(value) => {
+                    Some((attribute.key.as_str(), value.as_str()))
+                }
+                _ => None,
+            });
+        let secrets = self.redaction.secrets(strings);
+        self.redact_attributes(&mut span.attributes, &secrets);
+        for event in events.iter_mut() {
+            self.redact_attributes(&mut event.attributes, &secrets);
+            event.name = self.redaction.redact("", &event.name, &secrets).into();
+        }
+        self.inner.on_end(span)
+    }
+
+    fn force_flush(&self) -> opentelemetry_sdk::error::OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn shutdown_with_timeout(
+        &self,
+        timeout: std::time::Duration,
+    ) -> opentelemetry_sdk::error::OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &opentelemetry_sdk::Resource) {
+        self.inner.set_resource(resource)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_query_values_are_redacted() {
+        let uri: Uri = "/assets/events?secret=s3cr3t&verbose".parse().unwrap();
+        assert_eq!(
+            redact_query(&uri),
+            "/assets/events?secret=[redacted]&verbose"
+        );
+        let uri: Uri = "/doc/ws/doc1?token=abc&x=1".parse().unwrap();
+        assert_eq!(
+            redact_query(&uri),
+            "/doc/ws/doc1?token=[redacted]&x=[redacted]"
+        );
+        let uri: Uri = "/d/doc1/as-update".parse().unwrap();
+        assert_eq!(redact_query(&uri), "/d/doc1/as-update");
+    }
+
+    #[test]
+    fn test_redacted_values_are_redacted_everywhere() {
+        let redaction = Redaction::new(
+            vec![RedactField::DocId, RedactField::RemoteAddr],
+            RedactMode::Redact,
+        );
+        let mut line = json!({
+            "message": "Request completed: GET /d/customer-doc/as-update - 3ms",
+            "uri": "/d/customer-doc/as-update",
+            "remote_addr": "203.0.113.7",
+            "user_agent": "curl/8.0",
+            "span": {"doc_id": "other-doc", "name": "persist other-doc"}
+        });
+        redaction.redact_json(&mut line);
+        assert_eq!(
+            line,
+            json!({
+                "message": "Request completed: GET /d/[redacted]/as-update - 3ms",
+                "uri": "/d/[redacted]/as-update",
+                "remote_addr": "[redacted]",
+                "user_agent": "curl/8.0",
+                "span": {"doc_id": "[redacted]", "name": "persist [redacted]"}
+            })
+        );
+    }
+
+    #[test]
+    fn test_hashes_are_stable() {
+        let redaction = Redaction::new(vec![RedactField::DocId], RedactMode::Hash);
+        let mut first = json!({"doc_id": "customer-doc"});
+        let mut second = json!({"uri": "/doc/ws/customer-doc"});
+        redaction.redact_json(&mut first);
+        redaction.redact_json(&mut second);
+        let hash = first["doc_id"].as_str().unwrap();
+        assert!(hash.starts_with("hash:"));
+        assert_eq!(second["uri"], format!("/doc/ws/{}", hash));
+
+        assert_eq!(doc_ids_of_path("/doc/new"), Vec::<&str>::new());
+        assert_eq!(doc_ids_of_path("/base/d/a/copy?x=1"), vec!["a"]);
+    }
+}
@@ -0,0 +1,161 @@
+//! [Broker] over Redis pub/sub.
+//!
+//! Messages about a document are published on the channel `{prefix}{doc_id}`. Updates are
+//! encoded as `[origin node ID : varString, update : varBuf]`, and the messages nodes
+//! catch up with are followed by `[kind : varUint, state vector : varBuf]`, where kind is 1
+//! for a state vector (with an empty update) and 2 for a catch-up.
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use futures::{stream::BoxStream, StreamExt};
+use redis::{aio::MultiplexedConnection, AsyncCommands};
+use tokio::sync::Mutex;
+use yrs::{
+    encoding::{
+        read::{Cursor, Read},
+        write::Write,
+    },
+    updates::{
+        decoder::DecoderV1,
+        encoder::{Encoder, EncoderV1},
+    },
+};
+
+use crate::replication_ext::{Broker, BrokerMessage, BrokerPayload};
+
+/// Prefix of the channels updates are published on, unless configured otherwise.
+pub const DEFAULT_CHANNEL_PREFIX: &str = "y-sweet:doc:";
+
+pub struct RedisBroker {
+    client: redis::Client,
+    channel_prefix: String,
+    publisher: Mutex<Option<MultiplexedConnection>>,
+}
+
+impl RedisBroker {
+    /// Use the Redis server at `redis_url`. No connection is made until the broker is used.
+    pub fn new(redis_url: &str, channel_prefix: Option<String>) -> Result<Self> {
+        Ok(Self {
+            client: redis::Client::open(redis_url)?,
+            channel_prefix: channel_prefix.unwrap_or_else(|| DEFAULT_CHANNEL_PREFIX.to_string()),
+            publisher: Mutex::new(None),
+        })
+    }
+
+    async fn publisher(&self) -> Result<MultiplexedConnection> {
+        let mut publisher = self.publisher.lock().await;
+        if let Some(connection) = publisher.as_ref() {
+            return Ok(connection.clone());
+        }
+        let connection = self.client.get_multiplexed_async_connection().await?;
+        *publisher = Some(connection.clone());
+        Ok(connection)
+    }
+}
+
+#[async_trait]
+impl Broker for RedisBroker {
+    async fn publish(&self, message: &BrokerMessage) -> Result<()> {
+        let mut connection = self.publisher().await?;
+        let channel = format!("{}{}", self.channel_prefix, message.doc_id);
+        let result = connection
+            .publish::<_, _, ()>(channel, encode_envelope(&message.origin, &message.payload))
+            .await;
+        if result.is_err() {
+            // Reconnect on the next publish
+            *self.publisher.lock().await = None;
+        }
+        Ok(result?)
+    }
+
+    async fn subscribe(&self) -> Result<BoxStream<'static, Result<BrokerMessage>>> {
+        let mut pubsub = self.client.get_async_pubsub().await?;
+        pubsub
+            .psubscribe(format!("{}*", self.channel_prefix))
+            .await?;
+
+        let channel_prefix = self.channel_prefix.clone();
+        let messages = pubsub.into_on_message().filter_map(move |msg| {
+            let message = msg
+                .get_channel_name()
+                .strip_prefix(&channel_prefix)
+                .map(|doc_id| {
+                    let (origin, payload) = decode_envelope(msg.get_payload_bytes())?;
+                    Ok(BrokerMessage {
+                        doc_id: doc_id.to_string(),
+                        origin,
+                        payload,
+                    })
+                });
+            futures::future::ready(message)
+        });
+        Ok(messages.boxed())
+    }
+}
+
+const KIND_STATE_VECTOR: u32 = 1;
+const KIND_CATCH_UP: u32 = 2;
+
+fn encode_envelope(origin: &str, payload: &BrokerPayload) -> Vec<u8> {
+    let mut encoder = EncoderV1::new();
+    encoder.write_string(origin);
+    match payload {
+        BrokerPayload::Update(update) => encoder.write_buf(update),
+        BrokerPayload::StateVector(state_vector) => {
+            encoder.write_buf(b"");
+            encoder.write_var(KIND_STATE_VECTOR);
+            encoder.write_buf(state_vector);
+        }
+        BrokerPayload::CatchUp {
+            update,
+            state_vector,
+        } => {
+            encoder.write_buf(update);
+            encoder.write_var(KIND_CATCH_UP);
+            encoder.write_buf(state_vector);
+        }
+    }
+    encoder.to_vec()
+}
+
+fn decode_envelope(payload: &[u8]) -> Result<(String, BrokerPayload)> {
+    let mut decoder = DecoderV1::new(Cursor::new(payload));
+    let origin = decoder.read_string()?.to_string();
+    let update = decoder.read_buf()?.to_vec();
+    // Plain updates end here
+    let Ok(kind) = decoder.read_var::<u32>() else {
+        return Ok((origin, BrokerPayload::Update(update)));
+    };
+    let state_vector = decoder.read_buf()?.to_vec();
+    let payload = match kind {
+        KIND_STATE_VECTOR => BrokerPayload::StateVector(state_vector),
+        KIND_CATCH_UP => BrokerPayload::CatchUp {
+            update,
+            state_vector,
+        },
+        kind => return Err(anyhow!("Unknown message kind {}", kind)),
+    };
+    Ok((origin, payload))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_envelope_round_trip() {
+        for payload in [
+            BrokerPayload::Update(vec![1, 2, 3]),
+            BrokerPayload::StateVector(vec![4, 5]),
+            BrokerPayload::CatchUp {
+                update: vec![1, 2, 3],
+                state_vector: vec![4, 5],
+            },
+        ] {
+            let data = encode_envelope("node-a", &payload);
+            let (origin, decoded) = decode_envelope(&data).unwrap();
+            assert_eq!(origin, "node-a");
+            assert_eq!(decoded, payload);
+        }
+    }
+}
@@ -0,0 +1,209 @@
+//! Reloading configuration without a restart.
+//!
+//! On `SIGHUP`, or `POST /admin/reload`, the configuration file given to `serve` is read
+//! again and its reloadable options replace the running ones, without dropping WebSocket
+//! connections:
+//!
+//! - `auth`: tokens signed with the new key are accepted and issued from then on, and tokens
+//!   signed with the previous key are rejected.
+//! - `ws_max_message_size` and `ws_max_messages_per_second` (the rate limit of the server):
+//!   apply to connections opened from then on.
+//! - `asset_webhook_url`, with `asset_hook_retries`: called for the uploads confirmed from
+//!   then on.
+//! - `asset_events_secret`: notifications of `POST /assets/events` carrying the previous
+//!   secret are rejected from then on.
+//!
+//! The server has no CORS origins to reload: it sets no CORS headers, leaving them to the
+//! proxy in front of it. The content types accepted for assets are fixed.
+//!
+//! Options the file leaves out keep their running values, so auth cannot be turned off by a
+//! reload. Other options only take effect on restart. A file that fails to load or holds an
+//! invalid value is rejected as a whole, and nothing is changed.
+
+use anyhow::{anyhow, Result};
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+use y_sweet_core::auth::Authenticator;
+
+#[cfg(feature = "assets")]
+use crate::asset_hook_ext::{AssetHooks, WebhookAssetHook, DEFAULT_ASSET_HOOK_RETRIES};
+use crate::config_ext::load_config;
+use crate::server::Server;
+
+/// Read the configuration file of `server` again and apply its reloadable options.
+/// Returns the options applied.
+pub fn reload_config(server: &Server) -> Result<Vec<String>> {
+    let path = server
+        .config_path()
+        .ok_or_else(|| anyhow!("The server was not started with a configuration file"))?;
+    let config = load_config(path)?;
+
+    // Everything is validated before anything is applied
+    let authenticator = config.auth.as_deref().map(Authenticator::new).transpose()?;
+    #[cfg(feature = "assets")]
+    let asset_hooks = config
+        .asset_webhook_url
+        .as_deref()
+        .map(|url| -> Result<AssetHooks> {
+            let retries = config
+                .asset_hook_retries
+                .or_else(|| server.asset_hooks().map(|hooks| hooks.retries()))
+                .unwrap_or(DEFAULT_ASSET_HOOK_RETRIES);
+            let hook = WebhookAssetHook::new(url::Url::parse(url)?);
+            Ok(AssetHooks::new(hook).with_retries(retries))
+        })
+        .transpose()?;
+    let mut message_limits = server.message_limits();
+    let mut reloaded = Vec::new();
+
+    if let Some(max_message_size) = config.ws_max_message_size {
+        message_limits.max_message_size = Some(max_message_size as usize);
+        reloaded.push("ws_max_message_size".to_string());
+    }
+    if let Some(max_messages_per_second) = config.ws_max_messages_per_second {
+        message_limits.max_messages_per_second = Some(max_messages_per_second);
+        reloaded.push("ws_max_messages_per_second".to_string());
+    }
+    server.set_message_limits(message_limits);
+
+    if let Some(authenticator) = authenticator {
+        server.set_authenticator(authenticator);
+        reloaded.push("auth".to_string());
+    }
+
+    #[cfg(feature = "assets")]
+    if let Some(asset_hooks) = asset_hooks {
+        server.set_asset_hooks(asset_hooks);
+        reloaded.push("asset_webhook_url".to_string());
+    }
+    #[cfg(feature = "assets")]
+    if let Some(secret) = config.asset_events_secret {
+        server.set_asset_events_secret(secret);
+        reloaded.push("asset_events_secret".to_string());
+    }
+
+    info!(
+        message = "Reloaded the configuration",
+        event = "config_reloaded",
+        path = ?path,
+        reloaded = reloaded.join(",")
+    );
+    Ok(reloaded)
+}
+
+/// Reload the configuration of `server` on every `SIGHUP` until the server shuts down.
+#[cfg(unix)]
+pub(crate) async fn reload_on_sighup(server: Arc<Server>, cancellation_token: CancellationToken) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let Ok(mut hangups) = signal(SignalKind::hangup()) else {
+        return;
+    };
+    loop {
+        tokio::select! {
+            hangup = hangups.recv() => {
+                if hangup.is_none() {
+                    return;
+                }
+                if let Err(e) = reload_config(&server) {
+                    warn!(
+                        message = "Failed to reload the configuration, keeping the current one",
+                        event = "config_reload_failed",
+                        error = e.to_string()
+                    );
+                }
+            }
+            _ = cancellation_token.cancelled() => return,
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) async fn reload_on_sighup(_server: Arc<Server>, _cancellation_token: CancellationToken) {
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_reloads_auth_and_message_limits() {
+        let dir = std::env::temp_dir().join(format!("y-sweet-reload-{}", nanoid::nanoid!()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        std::fs::write(&path, "ws_max_messages_per_second = 10\n").unwrap();
+
+        let old = Authenticator::gen_key().unwrap();
+        let server = Server::builder()
+            .checkpoint_freq(Duration::from_secs(60))
+            .authenticator(Some(Authenticator::new(&old.private_key()).unwrap()))
+            .build()
+            .await
+            .unwrap()
+            .with_message_limits(Some(1024), None)
+            .with_config_path(path.clone());
+
+        let reloaded = reload_config(&server).unwrap();
+        assert_eq!(reloaded, vec!["ws_max_messages_per_second"]);
+        assert_eq!(server.message_limits().max_message_size, Some(1024));
+        assert_eq!(server.message_limits().max_messages_per_second, Some(10));
+        assert_eq!(
+            server.authenticator().unwrap().private_key(),
+            old.private_key()
+        );
+
+        let new = Authenticator::gen_key().unwrap();
+        std::fs::write(&path, format!("auth = \"{}\"\n", new.private_key())).unwrap();
+        reload_config(&server).unwrap();
+        assert_eq!(
+            server.authenticator().unwrap().private_key(),
+            new.private_key()
+        );
+
+        // Invalid files change nothing
+        std::fs::write(&path, "auth = \"not a key\"\nws_max_message_size = 1\n").unwrap();
+        assert!(reload_config(&server).is_err());
+        assert_eq!(server.message_limits().max_message_size, Some(1024));
+    }
+
+    #[cfg(feature = "assets")]
+    #[tokio::test]
+    async fn test_reloads_asset_webhook() {
+        let dir = std::env::temp_dir().join(format!("y-sweet-reload-{}", nanoid::nanoid!()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.yaml");
+        std::fs::write(&path, "asset_webhook_url: http://hooks.internal/assets\n").unwrap();
+
+        let server = Server::builder()
+            .checkpoint_freq(Duration::from_secs(60))
+            .build()
+            .await
+            .unwrap()
+            .with_config_path(path.clone());
+        assert!(server.asset_hooks().is_none());
+
+        let reloaded = reload_config(&server).unwrap();
+        assert_eq!(reloaded, vec!["asset_webhook_url"]);
+        assert_eq!(
+            server.asset_hooks().unwrap().retries(),
+            DEFAULT_ASSET_HOOK_RETRIES
+        );
+
+        std::fs::write(
+            &path,
+            "asset_webhook_url: http://hooks.internal/assets\nasset_hook_retries: 7\nasset_events_secret: rotated\n",
+        )
+        .unwrap();
+        let reloaded = reload_config(&server).unwrap();
+        assert_eq!(reloaded, vec!["asset_webhook_url", "asset_events_secret"]);
+        assert_eq!(server.asset_hooks().unwrap().retries(), 7);
+        assert_eq!(server.asset_events_secret().as_deref(), Some("rotated"));
+
+        // Invalid URLs change nothing
+        std::fs::write(&path, "asset_webhook_url: not a url\n").unwrap();
+        assert!(reload_config(&server).is_err());
+        assert_eq!(server.asset_hooks().unwrap().retries(), 7);
+    }
+}
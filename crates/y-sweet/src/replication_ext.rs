@@ -0,0 +1,409 @@
+//! Cross-instance document sync through a message broker.
+//!
+//! Every node publishes the updates applied to its loaded documents to a [Broker], and
+//! applies the updates published by other nodes to the documents it has loaded. This lets
+//! several nodes serve the same document behind a load balancer.
+//!
+//! Documents that a node has not loaded are read from the store when first requested, so
+//! they only include updates another node has already persisted, and a node misses the
+//! updates published while it is disconnected from the broker. Nodes catch up with a
+//! state-vector exchange, as in the y-sync protocol: a node publishes the state vector of a
+//! document when it loads it, and of every loaded document when it (re)subscribes. Nodes
+//! with the document loaded answer with the updates it is missing and their own state
+//! vector, which the node answers with the updates they are missing in turn.
+//!
+//! Each message carries the ID of the node that published it, and a node ignores its own
+//! messages. Brokers are provided for Redis pub/sub (feature `redis`) and NATS JetStream
+//! (feature `nats`); other transports can implement [Broker].
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use dashmap::DashMap;
+use futures::{stream::BoxStream, StreamExt};
+use std::{
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+use y_sweet_core::sync::awareness::Awareness;
+use yrs::{
+    updates::{decoder::Decode, encoder::Encode},
+    Origin, ReadTxn, StateVector, Subscription, Transact, Update,
+};
+
+use crate::server::Server;
+
+/// Transaction origin of updates received from other nodes. They are not published again.
+pub const REPLICATION_ORIGIN: &str = "y-sweet-replication";
+
+/// Update in Yjs v1 encoding with no blocks and no deletions.
+const EMPTY_UPDATE: &[u8] = &[0, 0];
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// How often subscriptions of documents that were unloaded are dropped.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A message about a document, published by some node.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BrokerMessage {
+    pub doc_id: String,
+    /// ID of the node that published the message.
+    pub origin: String,
+    pub payload: BrokerPayload,
+}
+
+/// Content of a [BrokerMessage]. Updates and state vectors are in Yjs v1 encoding.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BrokerPayload {
+    /// An update applied on the origin node.
+    Update(Vec<u8>),
+    /// The state vector of the origin node's copy, which nodes with the document loaded
+    /// answer with a [BrokerPayload::CatchUp].
+    StateVector(Vec<u8>),
+    /// The updates missing from a copy that published its state vector, and the state
+    /// vector of the origin node's copy, which nodes answer with the updates it is missing.
+    CatchUp {
+        update: Vec<u8>,
+        state_vector: Vec<u8>,
+    },
+}
+
+/// Transport of document updates between nodes.
+#[async_trait]
+pub trait Broker: Send + Sync {
+    /// Publish a message of this node.
+    async fn publish(&self, message: &BrokerMessage) -> Result<()>;
+
+    /// Receive the messages published from now on by all nodes, this one included.
+    async fn subscribe(&self) -> Result<BoxStream<'static, Result<BrokerMessage>>>;
+}
+
+type OutgoingMessage = (String, BrokerPayload);
+
+pub struct Replication {
+    broker: Arc<dyn Broker>,
+    node_id: String,
+    outgoing: UnboundedSender<OutgoingMessage>,
+    outgoing_recv: Mutex<Option<UnboundedReceiver<OutgoingMessage>>>,
+    subscriptions: DashMap<String, Subscription>,
+}
+
+impl Replication {
+    /// Replicate through `broker`. It is connected to when the server starts serving.
+    pub fn new(broker: Arc<dyn Broker>) -> Self {
+        let (outgoing, outgoing_recv) = unbounded_channel();
+        Self {
+            broker,
+            node_id: nanoid::nanoid!(),
+            outgoing,
+            outgoing_recv: Mutex::new(Some(outgoing_recv)),
+            subscriptions: DashMap::new(),
+        }
+    }
+
+    /// Random ID of this node, attached to the updates it publishes.
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    /// Start publishing the updates of a loaded document.
+    pub fn observe(&self, doc_id: &str, awareness: &Arc<RwLock<Awareness>>) {
+        let outgoing = self.outgoing.clone();
+        let message_doc_id = doc_id.to_string();
+        let subscription = awareness
+            .read()
+            .unwrap()
+            .doc()
+            .observe_update_v1(move |txn, event| {
+                if txn.origin() == Some(&Origin::from(REPLICATION_ORIGIN)) {
+                    return;
+                }
+                // Fails only once the replication task has exited
+                let _ = outgoing.send((
+                    message_doc_id.clone(),
+                    BrokerPayload::Update(event.update.clone()),
+                ));
+            })
+            .unwrap();
+        self.subscriptions.insert(doc_id.to_string(), subscription);
+        self.request_catch_up(doc_id, awareness);
+    }
+
+    /// Ask the other nodes for the updates of a document this node is missing.
+    fn request_catch_up(&self, doc_id: &str, awareness: &Arc<RwLock<Awareness>>) {
+        let state_vector = awareness
+            .read()
+            .unwrap()
+            .doc()
+            .transact()
+            .state_vector()
+            .encode_v1();
+        let _ = self
+            .outgoing
+            .send((doc_id.to_string(), BrokerPayload::StateVector(state_vector)));
+    }
+
+    /// Publish local updates and apply remote ones until cancelled, reconnecting on errors.
+    pub(crate) async fn run(
+        self: Arc<Self>,
+        server: Arc<Server>,
+        cancellation_token: CancellationToken,
+    ) {
+        let Some(mut outgoing) = self.outgoing_recv.lock().unwrap().take() else {
+            return;
+        };
+
+        loop {
+            let result = tokio::select! {
+                result = self.run_connection(&server, &mut outgoing) => result,
+                _ = cancellation_token.cancelled() => break,
+            };
+
+            if let Err(e) = result {
+                error!(
+                    message = format!("Replication error: {}", e),
+                    event = "replication_error",
+                    error = %e
+                );
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+                _ = cancellation_token.cancelled() => break,
+            }
+        }
+    }
+
+    async fn run_connection(
+        &self,
+        server: &Server,
+        outgoing: &mut UnboundedReceiver<OutgoingMessage>,
+    ) -> Result<()> {
+        let mut messages = self.broker.subscribe().await?;
+
+        info!(
+            message = "Subscribed to document updates of other nodes",
+            event = "replication_connected",
+            node_id = %self.node_id
+        );
+
+        // Updates published while this node was not subscribed were missed
+        for entry in server.docs.iter() {
+            if self.subscriptions.contains_key(entry.key()) {
+                self.request_catch_up(entry.key(), &entry.value().awareness());
+            }
+        }
+
+        let mut cleanup = tokio::time::interval(CLEANUP_INTERVAL);
+        loop {
+            tokio::select! {
+                msg = messages.next() => {
+                    let Some(msg) = msg else {
+                        return Err(anyhow!("Broker subscription closed"));
+                    };
+                    let msg = msg?;
+                    if let Err(e) = self.apply(server, &msg) {
+                        warn!(
+                            message = format!("Failed to apply replicated update: {}", e),
+                            event = "replication_apply_failed",
+                            doc_id = %msg.doc_id,
+                            error = %e
+                        );
+                    }
+                }
+                update = outgoing.recv() => {
+                    let Some((doc_id, payload)) = update else {
+                        return Ok(());
+                    };
+                    self.broker
+                        .publish(&BrokerMessage {
+                            doc_id,
+                            origin: self.node_id.clone(),
+                            payload,
+                        })
+                        .await?;
+                }
+                _ = cleanup.tick() => {
+                    self.subscriptions.retain(|doc_id, _| server.docs.contains_key(doc_id));
+                }
+            }
+        }
+    }
+
+    fn apply(&self, server: &Server, msg: &BrokerMessage) -> Result<()> {
+        if msg.origin == self.node_id {
+            return Ok(());
+        }
+
+        // Documents that are not loaded pick the update up from the store
+        let Some(awareness) = server.docs.get(&msg.doc_id).map(|doc| doc.awareness()) else {
+            return Ok(());
+        };
+
+        let awareness = awareness.write().unwrap();
+        let reply = match &msg.payload {
+            BrokerPayload::Update(update) => {
+                let update = Update::decode_v1(update)?;
+                let mut txn = awareness.doc().transact_mut_with(REPLICATION_ORIGIN);
+                txn.apply_update(update);
+                None
+            }
+            BrokerPayload::StateVector(state_vector) => {
+                let state_vector = StateVector::decode_v1(state_vector)?;
+                let txn = awareness.doc().transact();
+                Some(BrokerPayload::CatchUp {
+                    update: txn.encode_state_as_update_v1(&state_vector),
+                    state_vector: txn.state_vector().encode_v1(),
+                })
+            }
+            BrokerPayload::CatchUp {
+                update,
+                state_vector,
+            } => {
+                let update = Update::decode_v1(update)?;
+                let state_vector = StateVector::decode_v1(state_vector)?;
+                let mut txn = awareness.doc().transact_mut_with(REPLICATION_ORIGIN);
+                txn.apply_update(update);
+                let missing = txn.encode_state_as_update_v1(&state_vector);
+                (missing != EMPTY_UPDATE).then_some(BrokerPayload::Update(missing))
+            }
+        };
+
+        if let Some(reply) = reply {
+            let _ = self.outgoing.send((msg.doc_id.clone(), reply));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+    use yrs::{Doc, GetString, Text};
+
+    struct NoBroker;
+
+    #[async_trait]
+    impl Broker for NoBroker {
+        async fn publish(&self, _message: &BrokerMessage) -> Result<()> {
+            Ok(())
+        }
+
+        async fn subscribe(&self) -> Result<BoxStream<'static, Result<BrokerMessage>>> {
+            Ok(futures::stream::pending().boxed())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replicated_updates_are_not_republished() {
+        let replication = Replication::new(Arc::new(NoBroker));
+        let mut outgoing = replication.outgoing_recv.lock().unwrap().take().unwrap();
+
+        let awareness = Arc::new(RwLock::new(Awareness::new(Doc::new())));
+        replication.observe("doc", &awareness);
+        // Loading asks the other nodes for the updates this node is missing
+        assert!(matches!(
+            outgoing.try_recv(),
+            Ok((_, BrokerPayload::StateVector(_)))
+        ));
+
+        let text = awareness.read().unwrap().doc().get_or_insert_text("text");
+        {
+            let awareness = awareness.read().unwrap();
+            let mut txn = awareness.doc().transact_mut();
+            text.insert(&mut txn, 0, "local");
+        }
+        let (doc_id, _) = outgoing.try_recv().unwrap();
+        assert_eq!(doc_id, "doc");
+
+        let remote = {
+            let doc = Doc::new();
+            let text = doc.get_or_insert_text("text");
+            let mut txn = doc.transact_mut();
+            text.insert(&mut txn, 0, "remote ");
+            txn.encode_update_v1()
+        };
+        {
+            let awareness = awareness.read().unwrap();
+            let mut txn = awareness.doc().transact_mut_with(REPLICATION_ORIGIN);
+            txn.apply_update(Update::decode_v1(&remote).unwrap());
+        }
+        assert!(outgoing.try_recv().is_err());
+
+        let awareness = awareness.read().unwrap();
+        let value = text.get_string(&awareness.doc().transact());
+        assert!(value.contains("remote") && value.contains("local"));
+    }
+
+    #[tokio::test]
+    async fn test_nodes_catch_up_by_exchanging_state_vectors() {
+        let server = Server::builder()
+            .checkpoint_freq(Duration::from_secs(60))
+            .build()
+            .await
+            .unwrap();
+        let awareness = server.get_or_create_doc("doc").await.unwrap().awareness();
+        {
+            let awareness = awareness.read().unwrap();
+            let text = awareness.doc().get_or_insert_text("text");
+            text.insert(&mut awareness.doc().transact_mut(), 0, "local");
+        }
+
+        let replication = Replication::new(Arc::new(NoBroker));
+        let mut outgoing = replication.outgoing_recv.lock().unwrap().take().unwrap();
+        let message = |payload| BrokerMessage {
+            doc_id: "doc".to_string(),
+            origin: "other-node".to_string(),
+            payload,
+        };
+
+        // Another node that loaded the document without any of its updates
+        let remote = Doc::new();
+        let remote_text = remote.get_or_insert_text("text");
+        let state_vector = remote.transact().state_vector().encode_v1();
+        replication
+            .apply(&server, &message(BrokerPayload::StateVector(state_vector)))
+            .unwrap();
+        let Ok((
+            _,
+            BrokerPayload::CatchUp {
+                update,
+                state_vector,
+            },
+        )) = outgoing.try_recv()
+        else {
+            panic!("Expected a catch-up");
+        };
+        remote
+            .transact_mut()
+            .apply_update(Update::decode_v1(&update).unwrap());
+        assert_eq!(remote_text.get_string(&remote.transact()), "local");
+
+        // Which had updates of its own that this node missed
+        remote_text.insert(&mut remote.transact_mut(), 0, "remote ");
+        let update = remote
+            .transact()
+            .encode_state_as_update_v1(&StateVector::decode_v1(&state_vector).unwrap());
+        let state_vector = remote.transact().state_vector().encode_v1();
+        replication
+            .apply(
+                &server,
+                &message(BrokerPayload::CatchUp {
+                    update,
+                    state_vector,
+                }),
+            )
+            .unwrap();
+        {
+            let awareness = awareness.read().unwrap();
+            let text = awareness.doc().get_or_insert_text("text");
+            assert_eq!(text.get_string(&awareness.doc().transact()), "remote local");
+        }
+        // Nothing is missing from the other node anymore
+        assert!(outgoing.try_recv().is_err());
+    }
+}
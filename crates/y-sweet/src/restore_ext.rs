@@ -0,0 +1,241 @@
+//! Point-in-time recovery of documents from their update log.
+//!
+//! A document is reconstructed as of a segment or a time from its base snapshot and the
+//! segments after it (see `y_sweet_core::update_log_ext`), then written either to a new
+//! document or over the document itself.
+//!
+//! Restoring in place replaces the stored state, rather than applying it as an update, so
+//! that removed content stays removed. Connected clients are disconnected as if the document
+//! were deleted, since syncing their local state back would undo the restore. The restored
+//! state becomes the base snapshot of the document, so history before the restore can no
+//! longer be replayed.
+
+use anyhow::anyhow;
+use axum::http::StatusCode;
+use std::sync::Arc;
+use tracing::error;
+use y_sweet_core::{
+    doc_sync_ext::DocWithSyncKv,
+    shard_ext::remove_shards,
+    snapshot_ext::{snapshot_backup_key, snapshot_key},
+    store::{Store, StoreError},
+    update_log_ext::{
+        list_segments, reconstruct, write_base_snapshot, write_snapshot_meta, BaseSnapshot,
+        HistoryError, RestorePoint, SnapshotMeta,
+    },
+};
+
+use crate::server::{current_time_epoch_millis, AppError, Server};
+
+fn internal_error(context: &str, e: impl std::fmt::Display) -> AppError {
+    AppError(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        anyhow!("{}: {}", context, e),
+    )
+}
+
+/// Write a reconstructed state as the stored state of `doc_id`, which must not be loaded.
+/// `base_seq` is the last segment of its log the state supersedes.
+async fn write_restored(
+    store: &Arc<Box<dyn Store>>,
+    doc_id: &str,
+    update: &[u8],
+    base_seq: Option<u64>,
+) -> Result<(), AppError> {
+    // Loading the document must start from an empty state
+    for key in [snapshot_key(doc_id), snapshot_backup_key(doc_id)] {
+        match store.remove(&key).await {
+            Ok(()) | Err(StoreError::DoesNotExist(_)) => {}
+            Err(e) => return Err(internal_error("Failed to replace document data", e)),
+        }
+    }
+
+    let doc = DocWithSyncKv::new(doc_id, Some(store.clone()), || {}, false)
+        .await
+        .map_err(|e| internal_error("Failed to create document", e))?;
+    doc.apply_update(update)
+        .map_err(|e| internal_error("Failed to apply restored state", e))?;
+    doc.sync_kv()
+        .persist()
+        .await
+        .map_err(|e| internal_error("Failed to persist restored document", e))?;
+
+    let store = store.as_ref().as_ref();
+    // Shards of a sharded snapshot were superseded by the restored snapshot
+    remove_shards(store, doc_id)
+        .await
+        .map_err(|e| internal_error("Failed to remove snapshot shards", e))?;
+
+    // Replay history from the restored state, and skip the segments it supersedes on load
+    write_base_snapshot(
+        store,
+        doc_id,
+        &BaseSnapshot {
+            seq: base_seq,
+            written_at: current_time_epoch_millis(),
+            update: update.to_vec(),
+        },
+    )
+    .await
+    .map_err(|e| internal_error("Failed to write base snapshot", e))?;
+    write_snapshot_meta(
+        store,
+        doc_id,
+        &SnapshotMeta {
+            snapshot_seq: base_seq,
+        },
+    )
+    .await
+    .map_err(|e| internal_error("Failed to write update log metadata", e))?;
+
+    Ok(())
+}
+
+/// Restore `doc_id` as of `point` into `target_doc_id`, which is either `doc_id` itself or
+/// a document that does not exist yet. Returns the last segment included.
+pub async fn restore_doc(
+    server_state: &Arc<Server>,
+    doc_id: &str,
+    point: RestorePoint,
+    target_doc_id: &str,
+) -> Result<Option<u64>, AppError> {
+    let Some(store) = &server_state.store else {
+        return Err(AppError(
+            StatusCode::BAD_REQUEST,
+            anyhow!("Restoring a document requires a store"),
+        ));
+    };
+    let in_place = doc_id == target_doc_id;
+
+    // Write the last updates to the log, so they are part of the history to restore from
+    let loaded = server_state.docs.get(doc_id).map(|doc| doc.sync_kv());
+    if let Some(sync_kv) = loaded {
+        sync_kv
+            .persist()
+            .await
+            .map_err(|e| internal_error("Failed to persist document before restoring", e))?;
+    }
+
+    let reconstruction = reconstruct(store.as_ref().as_ref(), doc_id, point)
+        .await
+        .map_err(|e| match e {
+            HistoryError::NoHistory | HistoryError::Compacted => {
+                AppError(StatusCode::BAD_REQUEST, e.into())
+            }
+            e => {
+                error!(
+                    message = "Failed to reconstruct document",
+                    event = "document_restore_failed",
+                    doc_id = %doc_id,
+                    error = %e
+                );
+                AppError(StatusCode::INTERNAL_SERVER_ERROR, e.into())
+            }
+        })?;
+
+    if in_place {
+        if let Some((_, doc)) = server_state.docs.remove(doc_id) {
+            let sync_kv = doc.sync_kv();
+            sync_kv.shutdown();
+            // Updates made since are superseded too, so they must not be replayed on load
+            sync_kv
+                .persist()
+                .await
+                .map_err(|e| internal_error("Failed to persist document before restoring", e))?;
+        }
+        server_state.close_doc_connections(doc_id);
+    }
+
+    let base_seq = if in_place {
+        list_segments(store.as_ref().as_ref(), doc_id)
+            .await
+            .map_err(|e| internal_error("Failed to list update log", e))?
+            .last()
+            .copied()
+    } else {
+        None
+    };
+    write_restored(store, target_doc_id, &reconstruction.update, base_seq).await?;
+
+    Ok(reconstruction.seq)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stores::filesystem::FileSystemStore;
+    use std::time::Duration;
+    use y_sweet_core::update_log_ext::UpdateLogConfig;
+    use yrs::{GetString, Text, Transact};
+
+    async fn server(path: &std::path::Path) -> Arc<Server> {
+        Arc::new(
+            Server::builder()
+                .store(Some(Box::new(
+                    FileSystemStore::new(path.to_path_buf()).unwrap(),
+                )))
+                .checkpoint_freq(Duration::from_secs(60))
+                .build()
+                .await
+                .unwrap()
+                .with_update_log(UpdateLogConfig::default()),
+        )
+    }
+
+    async fn append(server: &Server, doc_id: &str, word: &str) {
+        server.get_or_create_doc(doc_id).await.unwrap();
+        {
+            let doc = server.docs.get(doc_id).unwrap();
+            let awareness = doc.awareness();
+            let awareness = awareness.write().unwrap();
+            let text = awareness.doc().get_or_insert_text("text");
+            let mut txn = awareness.doc().transact_mut();
+            let len = text.len(&txn);
+            text.insert(&mut txn, len, word);
+        }
+        let sync_kv = server.docs.get(doc_id).unwrap().sync_kv();
+        sync_kv.persist().await.unwrap();
+    }
+
+    async fn text(server: &Server, doc_id: &str) -> String {
+        let doc = server.get_or_create_doc(doc_id).await.unwrap();
+        let awareness = doc.awareness();
+        let awareness = awareness.read().unwrap();
+        let text = awareness.doc().get_or_insert_text("text");
+        let string = text.get_string(&awareness.doc().transact());
+        string
+    }
+
+    #[tokio::test]
+    async fn test_restore_into_new_doc_and_in_place() {
+        let path = std::env::temp_dir().join(format!("y-sweet-restore-{}", nanoid::nanoid!()));
+        let server_state = server(&path).await;
+        for word in ["one ", "two ", "three "] {
+            append(&server_state, "doc", word).await;
+        }
+
+        let seq = restore_doc(&server_state, "doc", RestorePoint::Seq(0), "copy")
+            .await
+            .unwrap();
+        assert_eq!(seq, Some(0));
+        assert_eq!(text(&server_state, "copy").await, "one ");
+        assert_eq!(text(&server_state, "doc").await, "one two three ");
+
+        let seq = restore_doc(&server_state, "doc", RestorePoint::Seq(1), "doc")
+            .await
+            .unwrap();
+        assert_eq!(seq, Some(1));
+        assert!(!server_state.docs.contains_key("doc"));
+
+        // Later segments are not replayed over the restored state
+        let reloaded = server(&path).await;
+        assert_eq!(text(&reloaded, "doc").await, "one two ");
+        append(&reloaded, "doc", "four").await;
+        let reloaded = server(&path).await;
+        assert_eq!(text(&reloaded, "doc").await, "one two four");
+
+        // History before the restore is gone
+        let result = restore_doc(&reloaded, "doc", RestorePoint::Seq(0), "doc").await;
+        assert!(matches!(result, Err(AppError(status, _)) if status == StatusCode::BAD_REQUEST));
+    }
+}
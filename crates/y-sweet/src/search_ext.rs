@@ -0,0 +1,274 @@
+//! Full-text search of the content of documents (`GET /search?q=&prefix=&limit=`).
+//!
+//! With a [SearchIndex] configured, the text of each document (extracted by
+//! [doc_to_search_text]) is indexed on its checkpoints, if it changed since it was last
+//! indexed, and removed from the index when the document is deleted. Documents are indexed
+//! at their first checkpoint after the index is enabled, so documents that are not edited
+//! are not found until then. The index is kept locally with `--search-index-dir` (see
+//! `tantivy_search_ext`); servers embedding y-sweet as a library can plug in an external
+//! backend by implementing [SearchIndex].
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+use axum_extra::typed_header::TypedHeader;
+use serde::Deserialize;
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::{Arc, RwLock},
+};
+use tracing::warn;
+use y_sweet_core::{
+    api_types_ext::{SearchHit, SearchResponse},
+    sync::awareness::Awareness,
+};
+use yrs::{ReadTxn, StateVector, Transact};
+
+use crate::convert::doc_to_search_text;
+use crate::server::{AppError, Server};
+
+/// Number of results of a search when no `limit` is given.
+pub const DEFAULT_SEARCH_LIMIT: usize = 20;
+/// Largest `limit` of a search.
+pub const MAX_SEARCH_LIMIT: usize = 100;
+
+/// An index of the text of documents.
+#[async_trait]
+pub trait SearchIndex: Send + Sync {
+    /// Replace the indexed text of `doc_id` with `text`.
+    async fn index_doc(&self, doc_id: &str, text: &str) -> Result<()>;
+
+    /// Remove `doc_id` from the index.
+    async fn remove_doc(&self, doc_id: &str) -> Result<()>;
+
+    /// The (at most) `limit` documents matching `query` best, among those whose ID starts
+    /// with `prefix` if given.
+    async fn search(
+        &self,
+        query: &str,
+        prefix: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<SearchHit>>;
+}
+
+/// Indexes one loaded document on its checkpoints.
+pub struct DocSearchIndexer {
+    index: Arc<dyn SearchIndex>,
+    doc_id: String,
+    awareness: Arc<RwLock<Awareness>>,
+    /// Hash of the content of the document when it was last indexed.
+    indexed: Option<u64>,
+}
+
+impl DocSearchIndexer {
+    pub fn new(
+        index: Arc<dyn SearchIndex>,
+        doc_id: String,
+        awareness: Arc<RwLock<Awareness>>,
+    ) -> Self {
+        Self {
+            index,
+            doc_id,
+            awareness,
+            indexed: None,
+        }
+    }
+
+    pub async fn index(&mut self) {
+        let update = {
+            let awareness = self.awareness.read().unwrap();
+            let txn = awareness.doc().transact();
+            txn.encode_state_as_update_v1(&StateVector::default())
+        };
+        // Deletions leave the state vector as it was, so the content is compared instead
+        let mut hasher = DefaultHasher::new();
+        update.hash(&mut hasher);
+        let hash = hasher.finish();
+        if self.indexed == Some(hash) {
+            return;
+        }
+
+        let indexed = match tokio::task::spawn_blocking(move || doc_to_search_text(&update)).await {
+            Ok(Ok(text)) => self.index.index_doc(&self.doc_id, &text).await,
+            Ok(Err(e)) => Err(e),
+            Err(e) => Err(e.into()),
+        };
+        match indexed {
+            Ok(()) => self.indexed = Some(hash),
+            Err(e) => warn!(
+                message = "Failed to index document",
+                event = "search_index_failed",
+                doc_id = %self.doc_id,
+                error = %e
+            ),
+        }
+    }
+}
+
+/// Remove a deleted document from the search index of the server, if any.
+pub(crate) async fn remove_from_search_index(server_state: &Server, doc_id: &str) {
+    let Some(index) = server_state.search_index() else {
+        return;
+    };
+    if let Err(e) = index.remove_doc(doc_id).await {
+        warn!(
+            message = "Failed to remove document from the search index",
+            event = "search_remove_failed",
+            doc_id = %doc_id,
+            error = %e
+        );
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchQuery {
+    q: String,
+    prefix: Option<String>,
+    limit: Option<usize>,
+}
+
+/// Search the content of documents
+pub async fn search_documents(
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<SearchResponse>, AppError> {
+    server_state.check_auth(auth_header)?;
+
+    let Some(index) = server_state.search_index() else {
+        return Err(AppError(
+            StatusCode::NOT_FOUND,
+            anyhow!("Search is not enabled on this server"),
+        ));
+    };
+    let q = query.q.trim();
+    if q.is_empty() {
+        return Err(AppError(StatusCode::BAD_REQUEST, anyhow!("q is empty")));
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_SEARCH_LIMIT);
+    if !(1..=MAX_SEARCH_LIMIT).contains(&limit) {
+        return Err(AppError(
+            StatusCode::BAD_REQUEST,
+            anyhow!("limit must be between 1 and {}", MAX_SEARCH_LIMIT),
+        ));
+    }
+
+    let results = index
+        .search(q, query.prefix.as_deref(), limit)
+        .await
+        .map_err(|e| AppError(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    Ok(Json(SearchResponse { results }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stores::memory::MemoryStore;
+    use axum::{body::Body, http::Request};
+    use http_body_util::BodyExt;
+    use std::{collections::BTreeMap, sync::Mutex, time::Duration};
+    use tower::ServiceExt;
+    use yrs::Text;
+
+    /// Finds the documents containing the query verbatim.
+    #[derive(Default)]
+    struct MemoryIndex(Mutex<BTreeMap<String, String>>);
+
+    #[async_trait]
+    impl SearchIndex for Arc<MemoryIndex> {
+        async fn index_doc(&self, doc_id: &str, text: &str) -> Result<()> {
+            self.0
+                .lock()
+                .unwrap()
+                .insert(doc_id.to_string(), text.to_string());
+            Ok(())
+        }
+
+        async fn remove_doc(&self, doc_id: &str) -> Result<()> {
+            self.0.lock().unwrap().remove(doc_id);
+            Ok(())
+        }
+
+        async fn search(
+            &self,
+            query: &str,
+            prefix: Option<&str>,
+            limit: usize,
+        ) -> Result<Vec<SearchHit>> {
+            Ok(self
+                .0
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(doc_id, text)| {
+                    doc_id.starts_with(prefix.unwrap_or_default()) && text.contains(query)
+                })
+                .take(limit)
+                .map(|(doc_id, text)| SearchHit {
+                    doc_id: doc_id.clone(),
+                    score: 1.0,
+                    snippet: Some(text.clone()),
+                })
+                .collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_documents_are_indexed_on_checkpoints() {
+        let index = Arc::new(MemoryIndex::default());
+        let server = Arc::new(
+            Server::builder()
+                .store(Some(Box::new(MemoryStore::new())))
+                .checkpoint_freq(Duration::from_millis(10))
+                .doc_gc(false)
+                .build()
+                .await
+                .unwrap()
+                .with_search_index(index.clone()),
+        );
+
+        {
+            let doc = server.get_or_create_doc("notes").await.unwrap();
+            let awareness = doc.awareness();
+            let awareness = awareness.write().unwrap();
+            let text = awareness.doc().get_or_insert_text("text");
+            text.insert(
+                &mut awareness.doc().transact_mut(),
+                0,
+                "Quarterly billing review",
+            );
+        }
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !index.0.lock().unwrap().contains_key("notes") {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let request = Request::builder()
+            .uri("/search?q=billing")
+            .body(Body::empty())
+            .unwrap();
+        let response = server.routes().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: SearchResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body.results.len(), 1);
+        assert_eq!(body.results[0].doc_id, "notes");
+
+        let request = Request::builder()
+            .method("DELETE")
+            .uri("/d/notes")
+            .body(Body::empty())
+            .unwrap();
+        let response = server.routes().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(index.0.lock().unwrap().is_empty());
+    }
+}
@@ -2,7 +2,7 @@ use anyhow::{anyhow, Result};
 use axum::{
     body::Bytes,
     extract::{
-        ws::{Message, WebSocket},
+        ws::{close_code, CloseFrame, Message, WebSocket},
         DefaultBodyLimit, Path, Query, Request, State, WebSocketUpgrade,
     },
     http::{
@@ -12,48 +12,106 @@ use axum::{
     middleware::{self, Next},
     response::{IntoResponse, Response},
     routing::{get, post},
-    Json, Router,
+    Extension, Json, Router,
 };
 use axum_extra::typed_header::TypedHeader;
 use dashmap::{mapref::one::MappedRef, DashMap};
-use ddtrace::axum::OtelAxumLayer;
 use futures::{SinkExt, StreamExt};
-use http_body_util::BodyExt;
 use serde::Deserialize;
 use serde_json::{json, Value};
 use std::{
-    sync::{Arc, RwLock},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{atomic::AtomicBool, Arc, RwLock, Weak},
     time::{Duration, Instant},
 };
 use tokio::{
     net::TcpListener,
-    sync::mpsc::{channel, Receiver},
+    sync::{broadcast, mpsc::channel},
 };
 use tokio_util::{sync::CancellationToken, task::TaskTracker};
 use tracing::{error, info, span, warn, Level};
 use url::Url;
 use y_sweet_core::{
-    api_types::{
-        validate_doc_name, AuthDocRequest, Authorization, ClientToken, DocCreationRequest,
-        NewDocResponse,
-    },
+    api_types::{AuthDocRequest, Authorization, ClientToken, NewDocResponse},
+    api_types_ext::DocCreationRequestExt,
     auth::{Authenticator, ExpirationTimeEpochMillis, DEFAULT_EXPIRATION_SECONDS},
-    doc_connection::DocConnection,
-    doc_sync::DocWithSyncKv,
+    auth_ext::AuthProvider,
+    checkpoint_ext::CheckpointTriggers,
+    doc_connection::DOC_NAME,
+    doc_connection_ext::DocConnection,
+    doc_stats_ext::DocStats,
+    doc_sync_ext::DocWithSyncKv,
+    protocol_error_ext::error_reply,
     store::Store,
+    subdoc_ext::{is_subdoc_key, is_subdoc_message},
     sync::awareness::Awareness,
     sync_kv::SyncKv,
+    update_log_ext::UpdateLogConfig,
+    validate_ext::UpdateValidator,
+};
+use yrs::{StateVector, Transact};
+use yrs_kvstore::DocOps;
+
+use crate::affinity_ext::ClientUrlTemplate;
+#[cfg(feature = "assets")]
+use crate::asset_hook_ext::AssetHooks;
+use crate::audit_ext::{record_audit, token_id as audit_token_id, AuditPrincipal};
+use crate::backpressure_ext::{
+    resync_messages, SendOverflow, SlowConsumerAction, SlowConsumerPolicy, DEFAULT_WS_SEND_BUFFER,
+    RESYNC_REQUIRED_CLOSE_CODE,
 };
+use crate::body_limits_ext::{limit_body, BodyKind, BodyLimits};
+use crate::broadcast_ext::{DocBroadcast, DocBroadcasts, DOC_DELETED_CLOSE_CODE};
+use crate::builder_ext::ServerBuilder;
+use crate::cluster_ext::Cluster;
+use crate::compaction_ext::{
+    CompactionMetrics, DEFAULT_COMPACTION_INTERVAL, DEFAULT_COMPACTION_MIN_SEGMENTS,
+};
+use crate::connection_limits_ext::{ConnectionLimits, ConnectionPermit};
+use crate::dirty_signal_ext::{dirty_signal, DirtyReceiver};
+use crate::doc_gc_ext::{doc_gc_worker, DocGcPolicy, DocPins};
+use crate::doc_id_ext::DocIdGenerator;
+use crate::doc_stats_ext::{DocStatsFlush, DocStatsFlusher};
+use crate::events_ext::{DocEvents, EventHandler};
+use crate::freeze_ext::{doc_freeze_flag, DocFreezes};
+use crate::html_ext::HtmlRenderer;
+use crate::lease_ext::{DocLease, DocLeases};
+use crate::memory_budget_ext::{MemoryBudget, DEFAULT_MEMORY_CHECK_INTERVAL};
+use crate::message_limits_ext::MessageLimits;
+use crate::metrics_ext::{is_sync_step1_message, is_update_message, outcome, SyncMetrics};
+use crate::persistence_ext::{PersistRetryPolicy, PersistenceHealth};
+use crate::preload_ext::{PreloadProgress, PreloadSource};
+use crate::proxy_ext::{resolve_client, ClientInfo, TrustedProxies};
+use crate::quota_ext::{QuotaExceeded, QuotaRule, QuotaValidator, Quotas};
+use crate::readiness_ext::{live, ready, Readiness};
+use crate::replication_ext::Replication;
+use crate::search_ext::{DocSearchIndexer, SearchIndex};
+use crate::server_ext::DocArchives;
+use crate::shutdown_ext::{wait_for_workers, ShutdownPersistence};
+use crate::slow_ext::{check_slow_message, check_slow_persist, warn_slow_requests, SlowThresholds};
+use crate::tenants_ext::{Tenant, Tenants};
+use crate::tls_ext::{serve_tls, Tls};
+use crate::tracing_setup::trace_requests;
+use crate::unix_socket_ext::Listener;
+use crate::wal_ext::{DocWal, Wal};
 
 const PLANE_VERIFIED_USER_DATA_HEADER: &str = "x-verified-user-data";
 
-// Every 20 seconds, we send a ping to the client.
+// By default, every 20 seconds, we send a ping to the client.
 const PING_EVERY: Duration = Duration::from_secs(20);
-// If we haven't received a pong in the last 40 seconds, we close the connection.
-// All modern browsers will respond to websocket pings with a pong message.
+// By default, if we haven't received anything (including a pong) in the last 40 seconds,
+// we close the connection. All modern browsers will respond to websocket pings with a pong message.
 const PONG_TIMEOUT: Duration = Duration::from_secs(40);
 
-fn current_time_epoch_millis() -> u64 {
+// By default, on shutdown, clients get 5 seconds to acknowledge the close frame.
+const DEFAULT_SHUTDOWN_DRAIN: Duration = Duration::from_secs(5);
+
+// The logging middleware reads at most about this much of POST and PUT bodies; longer
+// bodies are logged truncated.
+const LOGGED_BODY_BYTES: usize = 64 * 1024;
+
+pub(crate) fn current_time_epoch_millis() -> u64 {
     let now = std::time::SystemTime::now();
     let duration_since_epoch = now.duration_since(std::time::UNIX_EPOCH).unwrap();
     duration_since_epoch.as_millis() as u64
@@ -94,23 +152,149 @@ impl std::fmt::Display for AppError {
     }
 }
 
+#[derive(Clone, Copy)]
+pub(crate) struct WsOptions {
+    /// How often WebSocket clients are pinged.
+    pub(crate) ping_interval: Duration,
+    /// How long a WebSocket connection may go without receiving anything before it is closed.
+    pub(crate) idle_timeout: Duration,
+    /// Outgoing messages buffered per connection before the slow consumer policy applies.
+    pub(crate) send_buffer: usize,
+    pub(crate) slow_consumer_policy: SlowConsumerPolicy,
+    /// How long connections are given to close cleanly when the server shuts down.
+    pub(crate) drain_period: Duration,
+    /// Reconnect delay suggested to clients in the close frame sent on shutdown.
+    pub(crate) retry_after: Option<Duration>,
+}
+
 pub struct Server {
     pub docs: Arc<DashMap<String, DocWithSyncKv>>,
     doc_worker_tracker: TaskTracker,
     pub store: Option<Arc<Box<dyn Store>>>,
     checkpoint_freq: Duration,
-    authenticator: Option<Authenticator>,
+    /// Amounts of pending changes that trigger a checkpoint before `checkpoint_freq` passes.
+    checkpoint_triggers: CheckpointTriggers,
+    /// Whether snapshots are also written to a backup loaded if they are corrupted.
+    snapshot_backup: bool,
+    /// Target size of the shards snapshots are split into, if they are sharded.
+    snapshot_shard_bytes: Option<usize>,
+    /// Replaced when the configuration is reloaded (see `reload_ext`).
+    authenticator: RwLock<Option<Arc<Authenticator>>>,
+    /// Verifies and issues tokens instead of `authenticator`, if set.
+    auth_provider: Option<Arc<dyn AuthProvider>>,
     url_prefix: Option<Url>,
+    /// Path the routes are served under, without a trailing slash, if not the root.
+    base_path: Option<String>,
     cancellation_token: CancellationToken,
     /// Whether to garbage collect docs that are no longer in use.
     /// Disabled for single-doc mode, since we only have one doc.
     doc_gc: bool,
+    /// When idle documents are garbage collected.
+    doc_gc_policy: DocGcPolicy,
+    /// Documents that are never garbage collected or evicted.
+    pins: Arc<DocPins>,
     max_body_size: Option<usize>,
+    body_limits: BodyLimits,
     /// Whether to skip garbage collection in Yrs documents.
     skip_gc: bool,
+    /// Whether document reads and asset listings may be gzip/deflate compressed.
+    compression: bool,
+    /// How often expired documents are deleted.
+    ttl_reap_interval: Duration,
+    ws_options: WsOptions,
+    /// Shared fan-out of updates to the connections of each document.
+    broadcasts: DocBroadcasts,
+    /// Caps on concurrent WebSocket connections.
+    connection_limits: Arc<ConnectionLimits>,
+    /// Listener of the gRPC management API, if enabled.
+    #[cfg(feature = "grpc")]
+    grpc_listener: Option<TcpListener>,
+    /// WebTransport endpoint of the sync protocol, if enabled.
+    #[cfg(feature = "webtransport")]
+    webtransport: Option<crate::webtransport_ext::WebTransport>,
+    /// Sync of document updates with other instances, if enabled.
+    replication: Option<Arc<Replication>>,
+    /// Ownership of documents in a cluster of nodes, if clustered.
+    cluster: Option<Arc<Cluster>>,
+    /// Leases limiting persistence of each document to one instance, if enabled.
+    leases: Option<Arc<DocLeases>>,
+    /// Template of the URLs returned by `auth_doc`, overriding `url_prefix`.
+    client_url_template: Option<ClientUrlTemplate>,
+    /// Generates the IDs of documents created without one.
+    doc_id_generator: DocIdGenerator,
+    /// Interval between snapshot reloads when serving as a read-only follower.
+    follower_refresh_interval: Option<Duration>,
+    /// Persist documents by appending to an update log instead of full snapshots.
+    update_log: Option<UpdateLogConfig>,
+    /// Local write-ahead log of the updates made between checkpoints, if enabled.
+    wal: Option<Arc<Wal>>,
+    /// How often, and from how many stored segments, update logs are compacted.
+    compaction_interval: Duration,
+    compaction_min_segments: u32,
+    compaction_metrics: CompactionMetrics,
+    /// Latencies of syncing, persisting and loading documents.
+    sync_metrics: Arc<SyncMetrics>,
+    /// Retries of failed checkpoints, and the documents whose checkpoints are failing.
+    persistence_health: Arc<PersistenceHealth>,
+    /// How documents are persisted when the server shuts down.
+    shutdown_persistence: Arc<ShutdownPersistence>,
+    /// Memory budget of the loaded documents, if enabled.
+    memory_budget: Option<Arc<MemoryBudget>>,
+    /// Documents loaded at startup, and the progress of loading them.
+    preload: Vec<PreloadSource>,
+    preload_progress: Arc<PreloadProgress>,
+    /// Cached read-only state of documents.
+    freezes: DocFreezes,
+    /// Cached archive state of documents.
+    archives: DocArchives,
+    /// TLS termination, if the server is exposed without a reverse proxy.
+    tls: Option<Tls>,
+    /// Limits on the messages of each WebSocket client, replaced when the configuration is
+    /// reloaded. Connections keep the limits they were opened with.
+    message_limits: RwLock<MessageLimits>,
+    /// Configuration file the reloadable options are read from again on reload.
+    config_path: Option<PathBuf>,
+    /// Renderers of the nodes and marks of HTML exports.
+    html_renderer: Arc<HtmlRenderer>,
+    /// Validates the updates of clients before they are applied.
+    update_validator: Option<Arc<dyn UpdateValidator>>,
+    /// Told about document lifecycle events.
+    event_handler: Option<Arc<dyn EventHandler>>,
+    /// Listener of the management endpoints, if they are split off the public listener.
+    admin_listener: Option<TcpListener>,
+    /// Servers of the documents of each tenant.
+    tenants: Tenants,
+    /// Quotas on the documents, with their usage, if enabled.
+    quotas: Option<Arc<Quotas>>,
+    /// Whether an audit trail of each document is written to the store.
+    audit: bool,
+    /// Proxies trusted to forward the address and scheme of clients.
+    trusted_proxies: Arc<TrustedProxies>,
+    /// Durations above which requests, messages and persists are logged as slow.
+    slow_thresholds: SlowThresholds,
+    /// Where the statistics of documents are flushed on checkpoints, if anywhere.
+    doc_stats_flush: Option<DocStatsFlush>,
+    /// Whether the server is draining, and the persists in flight, for `/ready`.
+    readiness: Arc<Readiness>,
+    /// Called when the upload of an asset is confirmed, if set.
+    #[cfg(feature = "assets")]
+    asset_hooks: RwLock<Option<Arc<AssetHooks>>>,
+    /// Secret authenticating the store notifications of `POST /assets/events` in its URL,
+    /// replaced when the configuration is reloaded.
+    #[cfg(feature = "assets")]
+    asset_events_secret: RwLock<Option<String>>,
+    /// Index of the text of documents, updated on checkpoints, if search is enabled.
+    search_index: Option<Arc<dyn SearchIndex>>,
 }
 
 impl Server {
+    /// Start building a server, with every option at its default.
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    #[deprecated(note = "use `Server::builder()`, which does not break when options are added")]
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         store: Option<Box<dyn Store>>,
         checkpoint_freq: Duration,
@@ -121,1108 +305,3821 @@ impl Server {
         max_body_size: Option<usize>,
         skip_gc: bool,
     ) -> Result<Self> {
-        Ok(Self {
+        Self::builder()
+            .store(store)
+            .checkpoint_freq(checkpoint_freq)
+            .authenticator(authenticator)
+            .url_prefix(url_prefix)
+            .cancellation_token(cancellation_token)
+            .doc_gc(doc_gc)
+            .max_body_size(max_body_size)
+            .skip_gc(skip_gc)
+            .build()
+            .await
+    }
+
+    pub(crate) fn from_builder(builder: ServerBuilder) -> Self {
+        Self {
             docs: Arc::new(DashMap::new()),
             doc_worker_tracker: TaskTracker::new(),
-            store: store.map(Arc::new),
-            checkpoint_freq,
-            authenticator,
-            url_prefix,
-            cancellation_token,
-            doc_gc,
-            max_body_size,
-            skip_gc,
-        })
+            store: builder.store.map(Arc::new),
+            checkpoint_freq: builder.checkpoint_freq,
+            checkpoint_triggers: builder.checkpoint_triggers,
+            snapshot_backup: false,
+            snapshot_shard_bytes: None,
+            authenticator: RwLock::new(builder.authenticator.map(Arc::new)),
+            auth_provider: builder.auth_provider,
+            url_prefix: builder.url_prefix,
+            base_path: builder.base_path,
+            cancellation_token: builder.cancellation_token,
+            doc_gc: builder.doc_gc,
+            doc_gc_policy: DocGcPolicy::default(),
+            pins: Arc::new(DocPins::default()),
+            max_body_size: builder.max_body_size,
+            body_limits: builder.body_limits,
+            skip_gc: builder.skip_gc,
+            compression: true,
+            ttl_reap_interval: crate::ttl_ext::DEFAULT_TTL_REAP_INTERVAL,
+            ws_options: WsOptions {
+                ping_interval: PING_EVERY,
+                idle_timeout: PONG_TIMEOUT,
+                send_buffer: DEFAULT_WS_SEND_BUFFER,
+                slow_consumer_policy: SlowConsumerPolicy::default(),
+                drain_period: DEFAULT_SHUTDOWN_DRAIN,
+                retry_after: None,
+            },
+            broadcasts: DocBroadcasts::default(),
+            connection_limits: Arc::new(ConnectionLimits::new(
+                builder.max_connections,
+                builder.max_connections_per_doc,
+            )),
+            #[cfg(feature = "grpc")]
+            grpc_listener: None,
+            #[cfg(feature = "webtransport")]
+            webtransport: None,
+            replication: None,
+            cluster: None,
+            leases: None,
+            client_url_template: None,
+            doc_id_generator: DocIdGenerator::default(),
+            follower_refresh_interval: None,
+            update_log: None,
+            wal: None,
+            compaction_interval: DEFAULT_COMPACTION_INTERVAL,
+            compaction_min_segments: DEFAULT_COMPACTION_MIN_SEGMENTS,
+            compaction_metrics: CompactionMetrics::default(),
+            sync_metrics: Arc::new(SyncMetrics::default()),
+            persistence_health: Arc::new(PersistenceHealth::default()),
+            shutdown_persistence: Arc::new(ShutdownPersistence::default()),
+            memory_budget: None,
+            preload: Vec::new(),
+            preload_progress: Arc::new(PreloadProgress::default()),
+            freezes: DocFreezes::default(),
+            archives: DocArchives::default(),
+            tls: None,
+            message_limits: RwLock::new(builder.message_limits),
+            config_path: None,
+            html_renderer: Arc::new(HtmlRenderer::default()),
+            update_validator: builder.update_validator,
+            event_handler: builder.event_handler,
+            admin_listener: None,
+            tenants: Tenants::default(),
+            quotas: None,
+            audit: false,
+            trusted_proxies: Arc::new(TrustedProxies::default()),
+            slow_thresholds: SlowThresholds::default(),
+            doc_stats_flush: None,
+            readiness: Arc::new(Readiness::default()),
+            #[cfg(feature = "assets")]
+            asset_hooks: RwLock::default(),
+            #[cfg(feature = "assets")]
+            asset_events_secret: RwLock::default(),
+            search_index: None,
+        }
     }
 
-    pub async fn doc_exists(&self, doc_id: &str) -> bool {
-        if self.docs.contains_key(doc_id) {
-            return true;
+    /// Persists a document as soon as the changes pending since its last checkpoint reach
+    /// one of `triggers`, instead of waiting for the checkpoint interval to pass.
+    pub fn with_checkpoint_triggers(self, checkpoint_triggers: CheckpointTriggers) -> Self {
+        Self {
+            checkpoint_triggers,
+            ..self
         }
-        if let Some(store) = &self.store {
-            store
-                .exists(&format!("{}/data.ysweet", doc_id))
-                .await
-                .unwrap_or_default()
-        } else {
-            false
+    }
+
+    /// Writes each snapshot to a backup as well, loaded in place of a corrupted snapshot.
+    pub fn with_snapshot_backup(self, snapshot_backup: bool) -> Self {
+        Self {
+            snapshot_backup,
+            ..self
         }
     }
 
-    pub async fn create_doc(&self) -> Result<String> {
-        let doc_id = nanoid::nanoid!();
-        info!(
-            message = format!("Document creation started: {}", doc_id),
-            event = "document_creation_started",
-            doc_id = %doc_id
-        );
-        self.load_doc(&doc_id).await?;
-        info!(
-            message = format!("Document created: {}", doc_id),
-            event = "document_created",
-            doc_id = %doc_id
-        );
-        Ok(doc_id)
+    pub fn snapshot_backup(&self) -> bool {
+        self.snapshot_backup
     }
 
-    pub async fn load_doc(&self, doc_id: &str) -> Result<()> {
-        let (send, recv) = channel(1024);
+    /// Splits snapshots into shards of about `shard_bytes`, so that checkpoints of large
+    /// documents only write the shards that changed.
+    pub fn with_snapshot_shards(self, shard_bytes: Option<usize>) -> Self {
+        Self {
+            snapshot_shard_bytes: shard_bytes,
+            ..self
+        }
+    }
 
-        let dwskv = DocWithSyncKv::new(
-            doc_id,
-            self.store.clone(),
-            move || {
-                send.try_send(()).unwrap();
-            },
-            self.skip_gc,
-        )
-        .await?;
+    /// Enables or disables response compression (negotiated via `Accept-Encoding`).
+    pub fn with_compression(self, compression: bool) -> Self {
+        Self {
+            compression,
+            ..self
+        }
+    }
 
-        dwskv
-            .sync_kv()
-            .persist()
-            .await
-            .map_err(|e| anyhow!("Error persisting: {:?}", e))?;
+    pub fn compression(&self) -> bool {
+        self.compression
+    }
 
-        {
-            let sync_kv = dwskv.sync_kv();
-            let checkpoint_freq = self.checkpoint_freq;
-            let doc_id = doc_id.to_string();
-            let cancellation_token = self.cancellation_token.clone();
+    /// Sets how often WebSocket clients are pinged, and how long a connection may stay
+    /// silent (no pongs or messages) before it is closed.
+    pub fn with_ws_keepalive(self, ping_interval: Duration, idle_timeout: Duration) -> Self {
+        Self {
+            ws_options: WsOptions {
+                ping_interval,
+                idle_timeout,
+                ..self.ws_options
+            },
+            ..self
+        }
+    }
 
-            // Spawn a task to save the document to the store when it changes.
-            self.doc_worker_tracker.spawn(Self::doc_persistence_worker(
-                recv,
-                sync_kv,
-                checkpoint_freq,
-                doc_id.clone(),
-                cancellation_token.clone(),
-            ));
+    /// Sets how many outgoing messages are buffered per WebSocket connection, and what
+    /// happens to a connection once its buffer overflows.
+    pub fn with_slow_consumer_policy(self, send_buffer: usize, policy: SlowConsumerPolicy) -> Self {
+        Self {
+            ws_options: WsOptions {
+                send_buffer,
+                slow_consumer_policy: policy,
+                ..self.ws_options
+            },
+            broadcasts: DocBroadcasts::with_capacity(send_buffer),
+            ..self
+        }
+    }
 
-            if self.doc_gc {
-                self.doc_worker_tracker.spawn(Self::doc_gc_worker(
-                    self.docs.clone(),
-                    doc_id.clone(),
-                    checkpoint_freq,
-                    cancellation_token,
-                ));
-            }
+    /// Sets how WebSocket connections are drained on shutdown: they are closed with code 1012
+    /// (Service Restart), optionally suggesting a reconnect delay, and shutdown waits up to
+    /// `drain_period` for the clients to acknowledge.
+    pub fn with_shutdown_drain(
+        self,
+        drain_period: Duration,
+        retry_after: Option<Duration>,
+    ) -> Self {
+        Self {
+            ws_options: WsOptions {
+                drain_period,
+                retry_after,
+                ..self.ws_options
+            },
+            ..self
         }
+    }
 
-        self.docs.insert(doc_id.to_string(), dwskv);
-        Ok(())
+    /// Sets the largest message and the message rate accepted from each WebSocket client.
+    /// Clients exceeding them are disconnected.
+    pub fn with_message_limits(
+        self,
+        max_message_size: Option<usize>,
+        max_messages_per_second: Option<u32>,
+    ) -> Self {
+        Self {
+            message_limits: RwLock::new(MessageLimits {
+                max_message_size,
+                max_messages_per_second,
+            }),
+            ..self
+        }
     }
 
-    async fn doc_gc_worker(
-        docs: Arc<DashMap<String, DocWithSyncKv>>,
-        doc_id: String,
-        checkpoint_freq: Duration,
-        cancellation_token: CancellationToken,
-    ) {
-        let mut checkpoints_without_refs = 0;
+    /// Reads the reloadable options from `config_path` again on `SIGHUP` and
+    /// `POST /admin/reload`.
+    pub fn with_config_path(self, config_path: PathBuf) -> Self {
+        Self {
+            config_path: Some(config_path),
+            ..self
+        }
+    }
 
-        loop {
-            tokio::select! {
-                _ = tokio::time::sleep(checkpoint_freq) => {
-                    if let Some(doc) = docs.get(&doc_id) {
-                        let awareness = Arc::downgrade(&doc.awareness());
-                        if awareness.strong_count() > 1 {
-                            checkpoints_without_refs = 0;
-                            tracing::debug!("doc is still alive - it has {} references", awareness.strong_count());
-                        } else {
-                            checkpoints_without_refs += 1;
-                            tracing::debug!("doc has only one reference, candidate for GC. checkpoints_without_refs: {}", checkpoints_without_refs);
-                        }
-                    } else {
-                        break;
-                    }
+    pub fn config_path(&self) -> Option<&std::path::Path> {
+        self.config_path.as_deref()
+    }
 
-                    if checkpoints_without_refs >= 2 {
-                        tracing::debug!("GCing doc");
-                        if let Some(doc) = docs.get(&doc_id) {
-                            doc.sync_kv().shutdown();
-                        }
+    /// Renders HTML exports with `html_renderer`, e.g. to render the custom nodes of an
+    /// editor.
+    pub fn with_html_renderer(self, html_renderer: HtmlRenderer) -> Self {
+        Self {
+            html_renderer: Arc::new(html_renderer),
+            ..self
+        }
+    }
 
-                        docs.remove(&doc_id);
-                        break;
-                    }
-                }
-                _ = cancellation_token.cancelled() => {
-                    break;
-                }
-            };
+    pub fn html_renderer(&self) -> &HtmlRenderer {
+        &self.html_renderer
+    }
+
+    /// Validates the updates clients send, over WebSocket connections or `POST /update`,
+    /// with `validator` before they are applied, denying those it rejects.
+    pub fn with_update_validator(self, validator: impl UpdateValidator + 'static) -> Self {
+        Self {
+            update_validator: Some(Arc::new(validator)),
+            ..self
         }
-        tracing::debug!("Exiting gc_loop");
     }
 
-    async fn doc_persistence_worker(
-        mut recv: Receiver<()>,
-        sync_kv: Arc<SyncKv>,
-        checkpoint_freq: Duration,
-        doc_id: String,
-        cancellation_token: CancellationToken,
-    ) {
-        let mut last_save = std::time::Instant::now();
+    pub fn update_validator(&self) -> Option<&Arc<dyn UpdateValidator>> {
+        self.update_validator.as_ref()
+    }
 
-        loop {
-            let is_done = tokio::select! {
-                v = recv.recv() => v.is_none(),
-                _ = cancellation_token.cancelled() => true,
-                _ = tokio::time::sleep(checkpoint_freq) => {
-                    sync_kv.is_shutdown()
-                }
-            };
+    /// Validates the updates of WebSocket connections: the update validator, then the
+    /// quotas.
+    pub(crate) fn connection_update_validator(&self) -> Option<Arc<dyn UpdateValidator>> {
+        match &self.quotas {
+            Some(quotas) => Some(Arc::new(QuotaValidator {
+                quotas: quotas.clone(),
+                inner: self.update_validator.clone(),
+            })),
+            None => self.update_validator.clone(),
+        }
+    }
 
-            tracing::debug!("Received signal. done: {}", is_done);
-            let now = std::time::Instant::now();
-            if !is_done && now - last_save < checkpoint_freq {
-                let sleep = tokio::time::sleep(checkpoint_freq - (now - last_save));
-                tokio::pin!(sleep);
-                tracing::debug!("Throttling.");
+    /// Tells `handler` when documents are loaded, persisted and evicted, when clients
+    /// connect and disconnect, and when updates are applied.
+    pub fn with_event_handler(self, handler: impl EventHandler + 'static) -> Self {
+        Self {
+            event_handler: Some(Arc::new(handler)),
+            ..self
+        }
+    }
 
-                loop {
-                    tokio::select! {
-                        _ = &mut sleep => {
-                            break;
-                        }
-                        v = recv.recv() => {
-                            tracing::debug!("Received dirty while throttling.");
-                            if v.is_none() {
-                                break;
-                            }
-                        }
-                        _ = cancellation_token.cancelled() => {
-                            tracing::debug!("Received cancellation while throttling.");
-                            break;
-                        }
+    pub fn event_handler(&self) -> Option<&Arc<dyn EventHandler>> {
+        self.event_handler.as_ref()
+    }
 
-                    }
-                    tracing::debug!("Done throttling.");
-                }
-            }
-            tracing::debug!("Persisting.");
-            if let Err(e) = sync_kv.persist().await {
-                tracing::error!(
-                    message = format!("Error persisting: {}", e),
-                    event = "persist_error",
-                    error = ?e
-                );
-            } else {
-                tracing::debug!(message = "Done persisting", event = "persist_completed");
-            }
-            last_save = std::time::Instant::now();
+    /// The authenticator of tokens, if auth is enabled.
+    pub fn authenticator(&self) -> Option<Arc<Authenticator>> {
+        self.authenticator.read().unwrap().clone()
+    }
 
-            if is_done {
-                break;
-            }
-        }
-        tracing::debug!(
-            message = format!("Terminating loop for: {}", doc_id),
-            event = "doc_loop_terminated",
-            doc_id = %doc_id
-        );
+    #[cfg(feature = "config-files")]
+    pub(crate) fn set_authenticator(&self, authenticator: Authenticator) {
+        *self.authenticator.write().unwrap() = Some(Arc::new(authenticator));
     }
 
-    pub async fn get_or_create_doc(
-        &self,
-        doc_id: &str,
-    ) -> Result<MappedRef<String, DocWithSyncKv, DocWithSyncKv>> {
-        if !self.docs.contains_key(doc_id) {
-            tracing::debug!(
-                message = format!("Loading doc: {}", doc_id),
-                event = "doc_loading_started",
-                doc_id = ?doc_id
-            );
-            self.load_doc(doc_id).await?;
+    /// What verifies and issues tokens: the custom auth provider if one is set, otherwise
+    /// the authenticator. None if auth is disabled.
+    pub fn auth_provider(&self) -> Option<Arc<dyn AuthProvider>> {
+        if let Some(provider) = &self.auth_provider {
+            return Some(provider.clone());
         }
+        self.authenticator()
+            .map(|authenticator| authenticator as Arc<dyn AuthProvider>)
+    }
 
-        Ok(self
-            .docs
-            .get(doc_id)
-            .ok_or_else(|| anyhow!("Failed to get-or-create doc"))?
-            .map(|d| d))
+    pub fn body_limits(&self) -> BodyLimits {
+        self.body_limits
     }
 
-    pub fn check_auth(
-        &self,
-        auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
-    ) -> Result<(), AppError> {
-        if let Some(auth) = &self.authenticator {
-            if let Some(TypedHeader(headers::Authorization(bearer))) = auth_header {
-                if let Ok(()) =
-                    auth.verify_server_token(bearer.token(), current_time_epoch_millis())
-                {
-                    return Ok(());
-                }
-            }
-            Err((StatusCode::UNAUTHORIZED, anyhow!("Unauthorized.")))?
-        } else {
-            Ok(())
+    pub fn message_limits(&self) -> MessageLimits {
+        *self.message_limits.read().unwrap()
+    }
+
+    #[cfg(feature = "config-files")]
+    pub(crate) fn set_message_limits(&self, message_limits: MessageLimits) {
+        *self.message_limits.write().unwrap() = message_limits;
+    }
+
+    /// Limits concurrent WebSocket connections per server and per document.
+    /// Upgrades beyond a limit are rejected with 429.
+    pub fn with_connection_limits(
+        self,
+        max_connections: Option<usize>,
+        max_connections_per_doc: Option<usize>,
+    ) -> Self {
+        Self {
+            connection_limits: Arc::new(ConnectionLimits::new(
+                max_connections,
+                max_connections_per_doc,
+            )),
+            ..self
         }
     }
 
-    /// Structured logging middleware for request/response logging
-    pub async fn logging_middleware(req: Request, next: Next) -> impl IntoResponse {
-        let start = Instant::now();
-        let method = req.method().clone();
-        let uri = req.uri().clone();
+    pub fn connection_limits(&self) -> &ConnectionLimits {
+        &self.connection_limits
+    }
 
-        // Extract path parameters for better logging
-        let path_params = if let Some(path) = uri.path().split('/').collect::<Vec<_>>().get(2..) {
-            path.join("/")
-        } else {
-            "".to_string()
-        };
+    /// Options of sync connections, which WebTransport sessions share with WebSockets.
+    pub(crate) fn ws_options(&self) -> WsOptions {
+        self.ws_options
+    }
 
-        // Extract and log request body for POST/PUT requests
-        let (request_body, req) = if method == "POST" || method == "PUT" {
-            // Clone the request to avoid consuming it
-            let (parts, body) = req.into_parts();
-            let bytes = match body.collect().await {
-                Ok(collected) => collected.to_bytes(),
-                Err(_) => Bytes::new(),
-            };
+    pub(crate) fn slow_thresholds(&self) -> SlowThresholds {
+        self.slow_thresholds
+    }
 
-            // Process body content for logging
-            let request_body = if !bytes.is_empty() {
-                // Try to parse as JSON for better readability
-                if let Ok(json_value) = serde_json::from_slice::<serde_json::Value>(&bytes) {
-                    Some(json_value) // Store the parsed JSON value directly
-                } else {
-                    // For non-JSON content, show first 1000 characters
-                    let body_str = String::from_utf8_lossy(&bytes);
-                    if body_str.len() > 1000 {
-                        Some(serde_json::Value::String(format!(
-                            "{}... (truncated)",
-                            &body_str[..1000]
-                        )))
-                    } else {
-                        Some(serde_json::Value::String(body_str.to_string()))
-                    }
-                }
-            } else {
-                None
-            };
+    pub(crate) fn cancellation_token(&self) -> CancellationToken {
+        self.cancellation_token.clone()
+    }
 
-            // Reconstruct the request for the next middleware
-            let body_stream = axum::body::Body::from(bytes);
-            let req = Request::from_parts(parts, body_stream);
+    pub fn url_prefix(&self) -> Option<&Url> {
+        self.url_prefix.as_ref()
+    }
 
-            (request_body, req)
+    pub fn base_path(&self) -> Option<&str> {
+        self.base_path.as_deref()
+    }
+
+    /// Path of the routes of `doc_id` as seen by clients, e.g. `/collab/d/{doc_id}`.
+    pub fn doc_path(&self, doc_id: &str) -> String {
+        format!("{}/d/{}", self.base_path().unwrap_or_default(), doc_id)
+    }
+
+    /// URL of the routes of `doc_id`, as seen by the client of a request for `host`.
+    pub(crate) fn doc_base_url(
+        &self,
+        doc_id: &str,
+        host: &headers::Host,
+        client: ClientInfo,
+    ) -> String {
+        let doc_path = self.doc_path(doc_id);
+        // The URL prefix is where the root of the server is reachable, so the base path
+        // comes after its own path
+        if let Some(url_prefix) = &self.url_prefix {
+            format!("{}{doc_path}", url_prefix.as_str().trim_end_matches('/'))
         } else {
-            (None, req)
-        };
+            // As the client sees the server, if trusted proxies forwarded the request
+            let scheme = match client.proto.as_deref() {
+                Some("https") => "https",
+                Some("http") => "http",
+                _ if self.tls.is_some() => "https",
+                _ => "http",
+            };
+            let host = client.host.unwrap_or_else(|| host.to_string());
+            format!("{scheme}://{host}{doc_path}")
+        }
+    }
 
-        // Now extract headers after req has been potentially reconstructed
-        let user_agent = req
-            .headers()
-            .get("user-agent")
-            .and_then(|h| h.to_str().ok())
-            .unwrap_or("unknown");
-        let remote_addr = req
-            .extensions()
-            .get::<std::net::SocketAddr>()
-            .map(|addr| addr.to_string())
-            .unwrap_or_else(|| "unknown".to_string());
+    /// Sets how often the background reaper deletes documents whose TTL has passed.
+    pub fn with_ttl_reap_interval(self, ttl_reap_interval: Duration) -> Self {
+        Self {
+            ttl_reap_interval,
+            ..self
+        }
+    }
 
-        let span = span!(
-            Level::INFO,
-            "http_request",
-            method = %method,
-            uri = %uri,
-            user_agent = %user_agent,
-            remote_addr = %remote_addr,
-            path = %path_params
-        );
+    /// Persists documents by appending the updates made since the previous checkpoint to an
+    /// update log, writing the full snapshot only every `config.snapshot_every` segments.
+    pub fn with_update_log(self, config: UpdateLogConfig) -> Self {
+        Self {
+            update_log: Some(config),
+            ..self
+        }
+    }
 
-        let _enter = span.enter();
+    /// Logs every document update to `wal` as it is made, and replays the updates logged
+    /// but not persisted when a document is loaded.
+    pub fn with_wal(self, wal: Wal) -> Self {
+        Self {
+            wal: Some(Arc::new(wal)),
+            ..self
+        }
+    }
 
-        // Store variables before moving req
-        let method_clone = method.clone();
-        let uri_clone = uri.clone();
-        let user_agent_clone = user_agent.to_string();
-        let remote_addr_clone = remote_addr.clone();
-        let path_params_clone = path_params.clone();
-        let request_body_clone = request_body.clone();
+    pub fn has_update_log(&self) -> bool {
+        self.update_log.is_some()
+    }
 
-        let response = next.run(req).await;
-        let status = response.status();
-        let duration = start.elapsed();
+    /// Sets how often the background worker compacts the update logs of loaded documents,
+    /// and how many stored segments a document needs to be compacted.
+    pub fn with_compaction(self, interval: Duration, min_segments: u32) -> Self {
+        Self {
+            compaction_interval: interval,
+            compaction_min_segments: min_segments,
+            ..self
+        }
+    }
 
-        // Single log per request with message at top level
-        let message = if status.is_server_error() {
-            format!(
-                "Request failed with server error: {} {} - {}ms",
-                method_clone,
-                uri_clone,
-                duration.as_millis()
-            )
-        } else if status.is_client_error() {
-            format!(
-                "Request failed with client error: {} {} - {}ms",
-                method_clone,
-                uri_clone,
-                duration.as_millis()
-            )
-        } else {
-            format!(
-                "Request completed: {} {} - {}ms",
-                method_clone,
-                uri_clone,
-                duration.as_millis()
-            )
-        };
+    pub fn compaction_metrics(&self) -> &CompactionMetrics {
+        &self.compaction_metrics
+    }
 
-        if status.is_server_error() {
-            error!(
-                message = %message,
-                event = "request_failed",
-                method = %method_clone,
-                uri = %uri_clone,
-                status = %status,
-                duration_ms = %duration.as_millis(),
-                error_type = "server_error",
-                remote_addr = %remote_addr_clone,
-                path = %path_params_clone,
-                user_agent = %user_agent_clone,
-                request_body = %request_body_clone.as_ref().map(|b| serde_json::to_string(b).unwrap_or_default()).unwrap_or_default(),
-            );
-        } else if status.is_client_error() {
-            warn!(
-                message = %message,
-                event = "request_failed",
-                method = %method_clone,
-                uri = %uri_clone,
-                status = %status,
-                duration_ms = %duration.as_millis(),
-                error_type = "client_error",
-                remote_addr = %remote_addr_clone,
-                path = %path_params_clone,
-                user_agent = %user_agent_clone,
-                request_body = %request_body_clone.as_ref().map(|b| serde_json::to_string(b).unwrap_or_default()).unwrap_or_default(),
-            );
-        } else {
-            info!(
-                message = %message,
-                event = "request_completed",
-                method = %method_clone,
-                uri = %uri_clone,
-                status = %status,
-                duration_ms = %duration.as_millis(),
-                remote_addr = %remote_addr_clone,
-                path = %path_params_clone,
-                user_agent = %user_agent_clone,
-                request_body = %request_body_clone.as_ref().map(|b| serde_json::to_string(b).unwrap_or_default()).unwrap_or_default(),
-            );
-        }
+    pub fn sync_metrics(&self) -> &SyncMetrics {
+        &self.sync_metrics
+    }
 
-        response
+    /// Sets how failed checkpoints are retried, and whether documents that keep failing to
+    /// persist stop accepting writes.
+    pub fn with_persist_retry_policy(self, policy: PersistRetryPolicy) -> Self {
+        Self {
+            persistence_health: Arc::new(PersistenceHealth::new(policy)),
+            ..self
+        }
     }
 
-    pub async fn redact_error_middleware(req: Request, next: Next) -> impl IntoResponse {
-        let resp = next.run(req).await;
-        if resp.status().is_server_error() || resp.status().is_client_error() {
-            // If we should redact errors, copy over only the status code and
-            // not the response body.
-            return resp.status().into_response();
+    /// Sets how many documents are persisted concurrently when the server shuts down, and
+    /// how long to wait for them before giving up.
+    pub fn with_shutdown_persistence(self, concurrency: usize, deadline: Option<Duration>) -> Self {
+        Self {
+            shutdown_persistence: Arc::new(ShutdownPersistence::new(concurrency, deadline)),
+            ..self
         }
-        resp
     }
 
-    pub fn routes(self: &Arc<Self>) -> Router {
-        let base_routes = Router::new()
-            .route("/ready", get(ready))
-            .route("/check_store", post(check_store))
-            .route("/check_store", get(check_store_deprecated))
-            .route("/doc/ws/:doc_id", get(handle_socket_upgrade_deprecated))
-            .route("/doc/new", post(new_doc))
-            .route("/doc/:doc_id/auth", post(auth_doc))
-            .route("/doc/:doc_id/as-update", get(get_doc_as_update_deprecated))
-            .route("/doc/:doc_id/update", post(update_doc_deprecated))
-            .route("/d/:doc_id/as-update", get(get_doc_as_update))
-            .route("/d/:doc_id/update", post(update_doc))
-            .route(
-                "/d/:doc_id/ws/:doc_id2",
-                get(handle_socket_upgrade_full_path),
-            )
-            .layer(middleware::from_fn(Self::logging_middleware))
-            .layer(OtelAxumLayer::default())
-            .with_state(self.clone());
+    pub fn shutdown_persistence(&self) -> Arc<ShutdownPersistence> {
+        self.shutdown_persistence.clone()
+    }
 
-        // Merge extension routes
-        base_routes.merge(crate::server_ext::ext_routes(self))
+    pub fn persistence_health(&self) -> &PersistenceHealth {
+        &self.persistence_health
     }
 
-    pub fn single_doc_routes(self: &Arc<Self>) -> Router {
-        let base_routes = Router::new()
-            .route("/ws/:doc_id", get(handle_socket_upgrade_single))
-            .route("/as-update", get(get_doc_as_update_single))
-            .route("/update", post(update_doc_single))
-            .layer(middleware::from_fn(Self::logging_middleware))
-            .layer(OtelAxumLayer::default())
-            .with_state(self.clone());
+    /// Persists and unloads the least recently used documents without connections whenever
+    /// the loaded documents take more than `budget_bytes` of memory.
+    pub fn with_memory_budget(self, budget_bytes: usize) -> Self {
+        Self {
+            memory_budget: Some(Arc::new(MemoryBudget::new(budget_bytes))),
+            ..self
+        }
+    }
 
-        // Merge extension routes
-        base_routes.merge(crate::server_ext::ext_single_doc_routes(self))
+    pub fn memory_budget(&self) -> Option<&Arc<MemoryBudget>> {
+        self.memory_budget.as_ref()
     }
 
-    async fn serve_internal(
-        self: Arc<Self>,
-        listener: TcpListener,
-        redact_errors: bool,
-        routes: Router,
-    ) -> Result<()> {
-        let token = self.cancellation_token.clone();
+    /// Refuses new documents, updates and asset uploads that would exceed one of `rules`.
+    pub fn with_quotas(self, rules: Vec<QuotaRule>) -> Self {
+        Self {
+            quotas: Some(Arc::new(Quotas::new(rules))),
+            ..self
+        }
+    }
 
-        let mut app = if let Some(max_body_size) = self.max_body_size {
-            routes.layer(DefaultBodyLimit::max(max_body_size))
-        } else {
-            routes
-        };
+    pub fn quotas(&self) -> Option<&Arc<Quotas>> {
+        self.quotas.as_ref()
+    }
 
-        app = if redact_errors {
-            app
-        } else {
-            app.layer(middleware::from_fn(Self::redact_error_middleware))
-        };
+    /// Writes an audit trail of each document to the store: tokens issued, connections,
+    /// REST updates, deletions, copies and asset operations.
+    pub fn with_audit(self) -> Self {
+        Self {
+            audit: true,
+            ..self
+        }
+    }
 
-        axum::serve(listener, app.into_make_service())
-            .with_graceful_shutdown(async move { token.cancelled().await })
-            .await?;
+    pub fn audit(&self) -> bool {
+        self.audit
+    }
 
-        self.doc_worker_tracker.close();
-        self.doc_worker_tracker.wait().await;
+    /// Flushes the totals of the statistics of each document on its checkpoints, to
+    /// `flush`.
+    pub fn with_doc_stats_flush(self, flush: DocStatsFlush) -> Self {
+        Self {
+            doc_stats_flush: Some(flush),
+            ..self
+        }
+    }
 
-        Ok(())
+    /// Calls `hooks` when the upload of an asset is confirmed, and lists the status of
+    /// their processing with the assets.
+    #[cfg(feature = "assets")]
+    pub fn with_asset_hooks(self, hooks: AssetHooks) -> Self {
+        Self {
+            asset_hooks: RwLock::new(Some(Arc::new(hooks))),
+            ..self
+        }
     }
 
-    pub async fn serve(self, listener: TcpListener, redact_errors: bool) -> Result<()> {
-        let s = Arc::new(self);
-        let routes = s.routes();
-        s.serve_internal(listener, redact_errors, routes).await
+    #[cfg(feature = "assets")]
+    pub fn asset_hooks(&self) -> Option<Arc<AssetHooks>> {
+        self.asset_hooks.read().unwrap().clone()
     }
 
-    pub async fn serve_doc(self, listener: TcpListener, redact_errors: bool) -> Result<()> {
-        let s = Arc::new(self);
-        let routes = s.single_doc_routes();
-        s.serve_internal(listener, redact_errors, routes).await
+    #[cfg(all(feature = "assets", feature = "config-files"))]
+    pub(crate) fn set_asset_hooks(&self, hooks: AssetHooks) {
+        *self.asset_hooks.write().unwrap() = Some(Arc::new(hooks));
     }
 
-    pub fn verify_doc_token(
-        &self,
-        token: Option<&str>,
-        doc: &str,
-    ) -> Result<Authorization, AppError> {
-        if let Some(authenticator) = &self.authenticator {
-            if let Some(token) = token {
-                let authorization = authenticator
-                    .verify_doc_token(token, doc, current_time_epoch_millis())
-                    .map_err(|e| (StatusCode::UNAUTHORIZED, e))?;
-                Ok(authorization)
-            } else {
-                Err((StatusCode::UNAUTHORIZED, anyhow!("No token provided.")))?
-            }
-        } else {
-            Ok(Authorization::Full)
+    /// Accepts store notifications on `POST /assets/events?secret=` with `secret`, for
+    /// notifications that cannot carry the server token in a header (SNS).
+    #[cfg(feature = "assets")]
+    pub fn with_asset_events_secret(self, secret: String) -> Self {
+        Self {
+            asset_events_secret: RwLock::new(Some(secret)),
+            ..self
         }
     }
 
-    pub fn get_single_doc_id(&self) -> Result<String, AppError> {
-        self.docs
-            .iter()
-            .next()
-            .map(|entry| entry.key().clone())
-            .ok_or_else(|| AppError(StatusCode::NOT_FOUND, anyhow!("No document found")))
+    #[cfg(feature = "assets")]
+    pub fn asset_events_secret(&self) -> Option<String> {
+        self.asset_events_secret.read().unwrap().clone()
     }
-}
 
-#[derive(Deserialize)]
-struct HandlerParams {
-    token: Option<String>,
-}
+    #[cfg(all(feature = "assets", feature = "config-files"))]
+    pub(crate) fn set_asset_events_secret(&self, secret: String) {
+        *self.asset_events_secret.write().unwrap() = Some(secret);
+    }
 
-async fn get_doc_as_update(
-    State(server_state): State<Arc<Server>>,
-    Path(doc_id): Path<String>,
-    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
-) -> Result<Response, AppError> {
-    // All authorization types allow reading the document.
-    let token = get_token_from_header(auth_header);
-    let _ = server_state.verify_doc_token(token.as_deref(), &doc_id)?;
+    /// Indexes the text of documents in `index` on their checkpoints, and searches it on
+    /// `GET /search`.
+    pub fn with_search_index(self, index: impl SearchIndex + 'static) -> Self {
+        Self {
+            search_index: Some(Arc::new(index)),
+            ..self
+        }
+    }
 
-    let dwskv = server_state
-        .get_or_create_doc(&doc_id)
-        .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    pub fn search_index(&self) -> Option<&Arc<dyn SearchIndex>> {
+        self.search_index.as_ref()
+    }
 
-    let update = dwskv.as_update();
-    tracing::debug!(
-        message = format!("update: {:?}", update),
-        event = "update_debug",
-        update = ?update
-    );
-    Ok(update.into_response())
-}
+    /// Takes the address, scheme and host of clients from the forwarding headers of
+    /// requests from `trusted_proxies`.
+    pub fn with_trusted_proxies(self, trusted_proxies: TrustedProxies) -> Self {
+        Self {
+            trusted_proxies: Arc::new(trusted_proxies),
+            ..self
+        }
+    }
 
-async fn get_doc_as_update_deprecated(
-    Path(doc_id): Path<String>,
-    State(server_state): State<Arc<Server>>,
-    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
-) -> Result<Response, AppError> {
-    tracing::warn!("/doc/:doc_id/as-update is deprecated; call /doc/:doc_id/auth instead and then call as-update on the returned base URL.");
-    get_doc_as_update(State(server_state), Path(doc_id), auth_header).await
-}
+    /// Logs a warning for each request, WebSocket message or persist taking longer than its
+    /// threshold in `slow_thresholds`.
+    pub fn with_slow_thresholds(self, slow_thresholds: SlowThresholds) -> Self {
+        Self {
+            slow_thresholds,
+            ..self
+        }
+    }
 
-async fn update_doc_deprecated(
-    Path(doc_id): Path<String>,
-    State(server_state): State<Arc<Server>>,
-    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
-    body: Bytes,
-) -> Result<Response, AppError> {
-    tracing::warn!("/doc/:doc_id/update is deprecated; call /doc/:doc_id/auth instead and then call update on the returned base URL.");
-    update_doc(Path(doc_id), State(server_state), auth_header, body).await
-}
+    /// Sets how long documents without connections stay loaded.
+    pub fn with_doc_gc_policy(self, doc_gc_policy: DocGcPolicy) -> Self {
+        Self {
+            doc_gc_policy,
+            ..self
+        }
+    }
 
-async fn get_doc_as_update_single(
-    State(server_state): State<Arc<Server>>,
-    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
-) -> Result<Response, AppError> {
-    let doc_id = server_state.get_single_doc_id()?;
-    get_doc_as_update(State(server_state), Path(doc_id), auth_header).await
-}
+    /// Never unloads `doc_ids` once they are loaded.
+    pub fn with_pinned_docs(self, doc_ids: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            pins: Arc::new(DocPins::new(doc_ids)),
+            ..self
+        }
+    }
 
-async fn update_doc(
-    Path(doc_id): Path<String>,
-    State(server_state): State<Arc<Server>>,
-    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
-    body: Bytes,
-) -> Result<Response, AppError> {
-    let token = get_token_from_header(auth_header);
-    let authorization = server_state.verify_doc_token(token.as_deref(), &doc_id)?;
-    update_doc_inner(doc_id, server_state, authorization, body).await
-}
+    pub fn pins(&self) -> &DocPins {
+        &self.pins
+    }
 
-async fn update_doc_inner(
-    doc_id: String,
-    server_state: Arc<Server>,
-    authorization: Authorization,
-    body: Bytes,
-) -> Result<Response, AppError> {
-    if !matches!(authorization, Authorization::Full) {
-        return Err(AppError(StatusCode::FORBIDDEN, anyhow!("Unauthorized.")));
+    /// Loads the documents of `sources` once the server starts. `/ready` reports the server
+    /// as not ready until they are loaded.
+    pub fn with_preload(self, sources: Vec<PreloadSource>) -> Self {
+        let preload_progress = if sources.is_empty() {
+            PreloadProgress::default()
+        } else {
+            PreloadProgress::pending()
+        };
+        Self {
+            preload: sources,
+            preload_progress: Arc::new(preload_progress),
+            ..self
+        }
     }
 
-    let dwskv = server_state
-        .get_or_create_doc(&doc_id)
-        .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
-
-    if let Err(err) = dwskv.apply_update(&body) {
-        tracing::error!(?err, "Failed to apply update");
-        return Err(AppError(StatusCode::INTERNAL_SERVER_ERROR, err));
+    pub fn preload_progress(&self) -> &PreloadProgress {
+        &self.preload_progress
     }
 
-    Ok(StatusCode::OK.into_response())
-}
-
-async fn update_doc_single(
-    State(server_state): State<Arc<Server>>,
-    headers: HeaderMap,
-    body: Bytes,
-) -> Result<Response, AppError> {
-    let doc_id = server_state.get_single_doc_id()?;
-    // the doc server is meant to be run in Plane, so we expect verified plane
-    // headers to be used for authorization.
-    let authorization = get_authorization_from_plane_header(headers)?;
-    update_doc_inner(doc_id, server_state, authorization, body).await
-}
-
-async fn handle_socket_upgrade(
-    ws: WebSocketUpgrade,
-    Path(doc_id): Path<String>,
-    authorization: Authorization,
-    State(server_state): State<Arc<Server>>,
-) -> Result<Response, AppError> {
-    if !matches!(authorization, Authorization::Full) && !server_state.docs.contains_key(&doc_id) {
-        return Err(AppError(
-            StatusCode::NOT_FOUND,
-            anyhow!("Doc {} not found", doc_id),
-        ));
+    /// Whether documents are loaded once the server starts.
+    pub fn has_preload(&self) -> bool {
+        !self.preload.is_empty()
     }
 
-    let dwskv = server_state
-        .get_or_create_doc(&doc_id)
-        .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
-    let awareness = dwskv.awareness();
-    let cancellation_token = server_state.cancellation_token.clone();
-
-    Ok(ws.on_upgrade(move |socket| {
-        handle_socket(socket, awareness, authorization, cancellation_token)
-    }))
-}
+    pub fn readiness(&self) -> Arc<Readiness> {
+        self.readiness.clone()
+    }
 
-async fn handle_socket_upgrade_deprecated(
-    ws: WebSocketUpgrade,
-    Path(doc_id): Path<String>,
-    Query(params): Query<HandlerParams>,
-    State(server_state): State<Arc<Server>>,
-) -> Result<Response, AppError> {
-    warn!(
-        event = "deprecated_endpoint_used",
-        endpoint = "/doc/ws/:doc_id",
-        suggestion = "call /doc/:doc_id/auth instead and use the returned URL"
-    );
-    let authorization = server_state.verify_doc_token(params.token.as_deref(), &doc_id)?;
-    handle_socket_upgrade(ws, Path(doc_id), authorization, State(server_state)).await
-}
+    /// Whether the server is shutting down, or about to.
+    pub fn is_draining(&self) -> bool {
+        self.readiness.is_draining() || self.cancellation_token.is_cancelled()
+    }
 
-async fn handle_socket_upgrade_full_path(
-    ws: WebSocketUpgrade,
-    Path((doc_id, doc_id2)): Path<(String, String)>,
-    Query(params): Query<HandlerParams>,
-    State(server_state): State<Arc<Server>>,
-) -> Result<Response, AppError> {
-    if doc_id != doc_id2 {
-        return Err(AppError(
-            StatusCode::BAD_REQUEST,
-            anyhow!("For Yjs compatibility, the doc_id appears twice in the URL. It must be the same in both places, but we got {} and {}.", doc_id, doc_id2),
-        ));
+    pub fn freezes(&self) -> &DocFreezes {
+        &self.freezes
     }
-    let authorization = server_state.verify_doc_token(params.token.as_deref(), &doc_id)?;
-    handle_socket_upgrade(ws, Path(doc_id), authorization, State(server_state)).await
-}
 
-async fn handle_socket_upgrade_single(
-    ws: WebSocketUpgrade,
-    Path(doc_id): Path<String>,
-    headers: HeaderMap,
-    State(server_state): State<Arc<Server>>,
-) -> Result<Response, AppError> {
-    let single_doc_id = server_state.get_single_doc_id()?;
-    if doc_id != single_doc_id {
-        return Err(AppError(
-            StatusCode::NOT_FOUND,
-            anyhow!("Document not found"),
-        ));
+    pub fn archives(&self) -> &DocArchives {
+        &self.archives
     }
 
-    // the doc server is meant to be run in Plane, so we expect verified plane
-    // headers to be used for authorization.
-    let authorization = get_authorization_from_plane_header(headers)?;
-    handle_socket_upgrade(ws, Path(single_doc_id), authorization, State(server_state)).await
-}
+    /// Serves documents read-only without ever persisting them, reloading the snapshot of
+    /// each loaded document every `refresh_interval`.
+    pub fn with_follower(self, refresh_interval: Duration) -> Self {
+        Self {
+            follower_refresh_interval: Some(refresh_interval),
+            ..self
+        }
+    }
 
-async fn handle_socket(
-    socket: WebSocket,
-    awareness: Arc<RwLock<Awareness>>,
-    authorization: Authorization,
-    cancellation_token: CancellationToken,
-) {
-    let (mut sink, mut stream) = socket.split();
-    let (send, mut recv) = channel(1024);
+    pub fn is_follower(&self) -> bool {
+        self.follower_refresh_interval.is_some()
+    }
 
-    info!(
-        message = "WebSocket connected",
-        event = "websocket_connected",
-        authorization_type = %match authorization {
-            Authorization::Full => "Full",
-            Authorization::ReadOnly => "ReadOnly",
+    /// Only persists a document while holding its lease in the store, renewing leases every
+    /// third of `ttl`. Has no effect without a store.
+    pub fn with_doc_leases(self, ttl: Duration) -> Self {
+        Self {
+            leases: self
+                .store
+                .clone()
+                .map(|store| Arc::new(DocLeases::new(store, ttl))),
+            ..self
         }
-    );
+    }
 
-    let last_pong = Arc::new(RwLock::new(tokio::time::Instant::now()));
-    let last_pong_clone = last_pong.clone();
+    /// Serves the gRPC management API on the given listener alongside the HTTP API.
+    #[cfg(feature = "grpc")]
+    pub fn with_grpc_listener(self, grpc_listener: TcpListener) -> Self {
+        Self {
+            grpc_listener: Some(grpc_listener),
+            ..self
+        }
+    }
 
-    tokio::spawn(async move {
-        let mut ticker = tokio::time::interval(PING_EVERY);
-        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    /// Serves the sync protocol over WebTransport alongside WebSockets.
+    #[cfg(feature = "webtransport")]
+    pub fn with_webtransport(self, webtransport: crate::webtransport_ext::WebTransport) -> Self {
+        Self {
+            webtransport: Some(webtransport),
+            ..self
+        }
+    }
 
-        loop {
-            tokio::select! {
-                msg = recv.recv() => {
-                    let Some(msg) = msg else {
-                        break;
-                    };
-                    if let Err(e) = sink.send(Message::Binary(msg)).await {
-                        let error_message = format!("WebSocket send error: {}", e);
-                        error!(
-                            message = %error_message,
-                            event = "websocket_send_error",
-                            error = %e
-                        );
-                        break;
-                    }
-                }
-                _ = ticker.tick() => {
-                    if last_pong_clone.read().expect("Failed to get read lock on last_pong").elapsed() > PONG_TIMEOUT {
-                        tracing::info!("Pong timeout, closing connection");
-                        break;
-                    }
-                    let _ = sink.send(Message::Ping(vec![])).await;
-                }
-            }
+    /// Syncs document updates with other instances through a message broker.
+    pub fn with_replication(self, replication: Replication) -> Self {
+        Self {
+            replication: Some(Arc::new(replication)),
+            ..self
         }
-    });
+    }
 
-    let connection = DocConnection::new(awareness, authorization, move |bytes| {
-        if let Err(e) = send.try_send(bytes.to_vec()) {
-            let error_message = format!("WebSocket message error: {}", e);
-            warn!(
-                message = %error_message,
-                event = "websocket_message_error",
-                error = %e
-            );
+    /// Shards documents across the nodes of `cluster`: requests for documents owned by
+    /// another node are redirected to it.
+    pub fn with_cluster(self, cluster: Cluster) -> Self {
+        Self {
+            cluster: Some(Arc::new(cluster)),
+            ..self
         }
-    });
+    }
 
-    let mut message_count = 0u64;
-    loop {
-        tokio::select! {
-            msg = stream.next() => {
-                let Some(msg) = msg else {
-                    break;
-                };
-                let msg = match msg {
-                    Ok(Message::Binary(bytes)) => {
-                        message_count += 1;
-                        bytes
-                    }
-                    Ok(Message::Close(_)) => {
-                        info!(
-                            message = "WebSocket closed by client",
-                            event = "websocket_closed",
-                            total_messages = %message_count,
-                            reason = "client_close"
-                        );
-                        break;
-                    }
-                    Ok(Message::Pong(_)) => {
-                        *last_pong.write().expect("Failed to get write lock on last_pong") = tokio::time::Instant::now();
-                        continue;
-                    }
-                    Err(e) => {
-                        // The stream will complain about things like
-                        // connections being lost without handshake.
-                        let error_message = format!("WebSocket stream error: {}", e);
-                        warn!(
-                            message = %error_message,
-                            event = "websocket_stream_error",
-                            error = %e
-                        );
-                        continue;
-                    }
-                    msg => {
-                        let error_message = format!("WebSocket invalid message: {:?}", msg);
-                        warn!(
-                            message = %error_message,
-                            event = "websocket_invalid_message",
-                            message = ?msg
-                        );
-                        continue;
-                    }
-                };
+    pub fn cluster(&self) -> Option<&Cluster> {
+        self.cluster.as_deref()
+    }
 
-                if let Err(e) = connection.send(&msg).await {
-                    let error_message = format!("WebSocket message handling error: {}", e);
-                    error!(
-                        message = %error_message,
-                        event = "websocket_message_handling_error",
-                        error = %e,
-                        message_count = %message_count
-                    );
-                }
-            }
-            _ = cancellation_token.cancelled() => {
-                info!(
-                    message = "WebSocket closed due to server shutdown",
-                    event = "websocket_closed",
-                    total_messages = %message_count,
-                    reason = "server_shutdown"
-                );
-                break;
-            }
+    /// Builds the URLs handed to clients from `template` instead of the URL prefix, so they
+    /// can carry a routing hint for the load balancer.
+    pub fn with_client_url_template(self, template: ClientUrlTemplate) -> Self {
+        Self {
+            client_url_template: Some(template),
+            ..self
         }
     }
-}
 
-async fn check_store(
-    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
-    State(server_state): State<Arc<Server>>,
-) -> Result<Json<Value>, AppError> {
-    server_state.check_auth(auth_header)?;
+    /// Generates the IDs of documents created without one with `doc_id_generator`.
+    pub fn with_doc_id_generator(self, doc_id_generator: DocIdGenerator) -> Self {
+        Self {
+            doc_id_generator,
+            ..self
+        }
+    }
 
-    if server_state.store.is_none() {
-        return Ok(Json(json!({"ok": false, "error": "No store set."})));
-    };
+    pub fn doc_id_generator(&self) -> &DocIdGenerator {
+        &self.doc_id_generator
+    }
 
-    // The check_store endpoint for the native server is kind of moot, since
-    // the server will not start if store is not ok.
-    Ok(Json(json!({"ok": true})))
-}
+    /// Serves the documents of each of `tenants` under `/t/{tenant_id}`, or to requests with
+    /// the tenant header, from a server of its own. Tenant servers take the options set on
+    /// this server so far, but not its replication, clustering, leases, WAL, memory budget
+    /// or preloading.
+    pub fn with_tenants(self, tenants: Vec<Tenant>) -> Result<Self> {
+        let tenants = Tenants::new(&self, tenants)?;
+        Ok(Self { tenants, ..self })
+    }
 
-async fn check_store_deprecated(
-    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
-    State(server_state): State<Arc<Server>>,
-) -> Result<Json<Value>, AppError> {
-    warn!(
-        message = "Deprecated endpoint used",
-        event = "deprecated_endpoint_used",
-        endpoint = "GET /check_store",
-        suggestion = "use POST /check_store with an empty body instead"
-    );
-    check_store(auth_header, State(server_state)).await
-}
+    pub fn tenants(&self) -> &Tenants {
+        &self.tenants
+    }
 
-/// Always returns a 200 OK response, as long as we are listening.
-async fn ready() -> Result<Json<Value>, AppError> {
-    Ok(Json(json!({"ok": true})))
-}
+    /// A server for a tenant, with the options of this one but documents of its own, served
+    /// under `/t/{tenant_id}`. Its workers are tracked with the workers of this server, so
+    /// that they are waited for when it shuts down.
+    pub(crate) fn tenant_server(
+        &self,
+        tenant_id: &str,
+        store: Option<Box<dyn Store>>,
+        auth_provider: Option<Arc<dyn AuthProvider>>,
+        event_handler: Arc<dyn EventHandler>,
+    ) -> Self {
+        // A tenant with a token scheme of its own does not take the tokens of the server
+        let authenticator = match auth_provider {
+            Some(_) => None,
+            None => self.authenticator(),
+        };
+        Self {
+            docs: Arc::new(DashMap::new()),
+            doc_worker_tracker: self.doc_worker_tracker.clone(),
+            store: store.map(Arc::new),
+            checkpoint_freq: self.checkpoint_freq,
+            checkpoint_triggers: self.checkpoint_triggers,
+            snapshot_backup: self.snapshot_backup,
+            snapshot_shard_bytes: self.snapshot_shard_bytes,
+            authenticator: RwLock::new(authenticator),
+            auth_provider: auth_provider.or_else(|| self.auth_provider.clone()),
+            url_prefix: self.url_prefix.clone(),
+            base_path: Some(format!(
+                "{}/t/{}",
+                self.base_path().unwrap_or_default(),
+                tenant_id
+            )),
+            cancellation_token: self.cancellation_token.clone(),
+            doc_gc: self.doc_gc,
+            doc_gc_policy: self.doc_gc_policy,
+            pins: Arc::new(DocPins::default()),
+            max_body_size: self.max_body_size,
+            body_limits: self.body_limits,
+            skip_gc: self.skip_gc,
+            compression: self.compression,
+            ttl_reap_interval: self.ttl_reap_interval,
+            ws_options: self.ws_options,
+            broadcasts: DocBroadcasts::with_capacity(self.ws_options.send_buffer),
+            connection_limits: Arc::new(ConnectionLimits::new(
+                self.connection_limits.max_connections(),
+                self.connection_limits.max_connections_per_doc(),
+            )),
+            #[cfg(feature = "grpc")]
+            grpc_listener: None,
+            #[cfg(feature = "webtransport")]
+            webtransport: None,
+            replication: None,
+            cluster: None,
+            leases: None,
+            client_url_template: None,
+            doc_id_generator: self.doc_id_generator.clone(),
+            follower_refresh_interval: self.follower_refresh_interval,
+            update_log: self.update_log,
+            wal: None,
+            compaction_interval: self.compaction_interval,
+            compaction_min_segments: self.compaction_min_segments,
+            compaction_metrics: CompactionMetrics::default(),
+            sync_metrics: self.sync_metrics.clone(),
+            persistence_health: Arc::new(PersistenceHealth::new(*self.persistence_health.policy())),
+            shutdown_persistence: self.shutdown_persistence.clone(),
+            memory_budget: None,
+            preload: Vec::new(),
+            preload_progress: Arc::new(PreloadProgress::default()),
+            freezes: DocFreezes::default(),
+            archives: DocArchives::default(),
+            tls: None,
+            message_limits: RwLock::new(self.message_limits()),
+            config_path: None,
+            html_renderer: self.html_renderer.clone(),
+            update_validator: self.update_validator.clone(),
+            event_handler: Some(event_handler),
+            admin_listener: None,
+            tenants: Tenants::default(),
+            quotas: None,
+            audit: self.audit,
+            trusted_proxies: self.trusted_proxies.clone(),
+            slow_thresholds: self.slow_thresholds,
+            doc_stats_flush: self.doc_stats_flush,
+            readiness: self.readiness.clone(),
+            #[cfg(feature = "assets")]
+            asset_hooks: RwLock::new(self.asset_hooks()),
+            #[cfg(feature = "assets")]
+            asset_events_secret: RwLock::new(self.asset_events_secret()),
+            // Not shared: tenants would find the documents of each other
+            search_index: None,
+        }
+    }
 
-async fn new_doc(
-    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
-    State(server_state): State<Arc<Server>>,
-    Json(body): Json<DocCreationRequest>,
-) -> Result<Json<NewDocResponse>, AppError> {
-    server_state.check_auth(auth_header)?;
+    /// Serves the management endpoints on `admin_listener` only, instead of alongside the
+    /// sync, auth and asset endpoints.
+    pub fn with_admin_listener(self, admin_listener: TcpListener) -> Self {
+        Self {
+            admin_listener: Some(admin_listener),
+            ..self
+        }
+    }
 
-    let doc_id = if let Some(doc_id) = body.doc_id {
-        if !validate_doc_name(doc_id.as_str()) {
-            Err((StatusCode::BAD_REQUEST, anyhow!("Invalid document name")))?
+    /// Serves over TLS (`https://` and `wss://`) with `tls`.
+    pub fn with_tls(self, tls: Tls) -> Self {
+        Self {
+            tls: Some(tls),
+            ..self
         }
+    }
 
-        server_state
-            .get_or_create_doc(doc_id.as_str())
+    /// Close the WebSocket connections of a deleted document. Returns whether it had any.
+    pub(crate) fn close_doc_connections(&self, doc_id: &str) -> bool {
+        self.broadcasts.mark_deleted(doc_id)
+    }
+
+    /// Send an encoded message to the WebSocket connections of a document. Returns whether
+    /// it had any.
+    #[cfg(feature = "assets")]
+    pub(crate) fn notify_doc_connections(&self, doc_id: &str, message: Vec<u8>) -> bool {
+        self.broadcasts.send(doc_id, message)
+    }
+
+    pub async fn doc_exists(&self, doc_id: &str) -> bool {
+        if self.docs.contains_key(doc_id) {
+            return true;
+        }
+        if let Some(store) = &self.store {
+            store
+                .exists(&format!("{}/data.ysweet", doc_id))
+                .await
+                .unwrap_or_default()
+        } else {
+            false
+        }
+    }
+
+    pub async fn create_doc(&self) -> Result<String> {
+        let doc_id = self.doc_id_generator.generate()?;
+        if let Some(quotas) = &self.quotas {
+            quotas.check_new_doc(&doc_id)?;
+        }
+        info!(
+            message = format!("Document creation started: {}", doc_id),
+            event = "document_creation_started",
+            doc_id = %doc_id
+        );
+        if crate::cluster_ext::is_local(self, &doc_id) {
+            self.load_doc(&doc_id).await?;
+        } else {
+            self.create_doc_for_owner(&doc_id).await?;
+        }
+        info!(
+            message = format!("Document created: {}", doc_id),
+            event = "document_created",
+            doc_id = %doc_id
+        );
+        Ok(doc_id)
+    }
+
+    /// Create a document another node of the cluster owns without loading it, so that only
+    /// its owner ever does: store an empty snapshot, unless the document exists already.
+    pub(crate) async fn create_doc_for_owner(&self, doc_id: &str) -> Result<()> {
+        let Some(store) = &self.store else {
+            return Err(anyhow!(
+                "Documents owned by another node cannot be created without a store"
+            ));
+        };
+        if self.doc_exists(doc_id).await {
+            return Ok(());
+        }
+        let sync_kv = DocWithSyncKv::new(doc_id, Some(store.clone()), || {}, self.skip_gc)
+            .await?
+            .sync_kv();
+        // An empty document is never dirty, so its state is written explicitly
+        sync_kv
+            .insert_doc(DOC_NAME, &yrs::Doc::new().transact())
+            .map_err(|e| anyhow!("Error creating doc: {:?}", e))?;
+        sync_kv
+            .persist()
+            .await
+            .map_err(|e| anyhow!("Error persisting: {:?}", e))?;
+        Ok(())
+    }
+
+    /// Refuse to load a document being archived, which would write it back to the live key.
+    fn check_not_archiving(&self, doc_id: &str) -> Result<()> {
+        if self.archives.is_archived(doc_id) {
+            return Err(anyhow!("Doc {} has been archived", doc_id));
+        }
+        Ok(())
+    }
+
+    pub async fn load_doc(&self, doc_id: &str) -> Result<()> {
+        let started = Instant::now();
+        let result = self.load_doc_inner(doc_id).await;
+        let outcome = if result.is_ok() { "ok" } else { "error" };
+        self.sync_metrics
+            .doc_load
+            .observe(outcome, started.elapsed());
+        result
+    }
+
+    async fn load_doc_inner(&self, doc_id: &str) -> Result<()> {
+        self.check_not_archiving(doc_id)?;
+        let (send, recv) = dirty_signal();
+
+        let dwskv = DocWithSyncKv::new_with_update_log(
+            doc_id,
+            self.store.clone(),
+            move || send.notify(),
+            self.skip_gc,
+            self.update_log,
+        )
+        .await?;
+        dwskv
+            .sync_kv()
+            .set_checkpoint_triggers(self.checkpoint_triggers);
+        dwskv.sync_kv().set_snapshot_backup(self.snapshot_backup);
+        dwskv.sync_kv().set_shard_size(self.snapshot_shard_bytes);
+        if let Some(quotas) = &self.quotas {
+            quotas.record_doc(doc_id, dwskv.sync_kv().size_bytes() as u64);
+        }
+
+        if let (Some(refresh_interval), Some(store)) = (self.follower_refresh_interval, &self.store)
+        {
+            self.doc_worker_tracker
+                .spawn(crate::follower_ext::doc_follower_worker(
+                    recv,
+                    store.clone(),
+                    dwskv.sync_kv(),
+                    dwskv.awareness(),
+                    doc_id.to_string(),
+                    refresh_interval,
+                    self.skip_gc,
+                    self.cancellation_token.clone(),
+                ));
+            if self.doc_gc {
+                self.doc_worker_tracker.spawn(doc_gc_worker(
+                    self.docs.clone(),
+                    doc_id.to_string(),
+                    self.checkpoint_freq,
+                    self.doc_gc_policy,
+                    self.pins.clone(),
+                    self.event_handler.clone(),
+                    self.cancellation_token.clone(),
+                ));
+            }
+
+            // Not observed for replication: a follower has no changes of its own to publish
+            self.docs.insert(doc_id.to_string(), dwskv);
+            if let Some(budget) = &self.memory_budget {
+                budget.loaded(doc_id);
+            }
+            if let Some(handler) = &self.event_handler {
+                handler.doc_loaded(doc_id);
+            }
+            return Ok(());
+        }
+
+        if let Some(wal) = &self.wal {
+            let replayed = wal.replay(doc_id, &dwskv)?;
+            if replayed > 0 {
+                info!(
+                    message = format!("Replayed {} updates from the WAL", replayed),
+                    event = "wal_replayed",
+                    doc_id = %doc_id,
+                    updates = replayed
+                );
+            }
+        }
+
+        // Archiving may have started while the document was read
+        self.check_not_archiving(doc_id)?;
+        dwskv
+            .sync_kv()
+            .persist()
+            .await
+            .map_err(|e| anyhow!("Error persisting: {:?}", e))?;
+
+        let wal = match &self.wal {
+            Some(wal) => {
+                // Everything replayed is persisted now
+                wal.clear(doc_id)?;
+                Some(wal.attach(doc_id, &dwskv.awareness()))
+            }
+            None => None,
+        };
+        let events = self
+            .event_handler
+            .as_ref()
+            .map(|handler| crate::events_ext::attach(handler, doc_id, &dwskv.awareness()));
+        let stats_flusher = self.doc_stats_flush.map(|flush| {
+            DocStatsFlusher::new(flush, self.store.clone(), doc_id.to_string(), dwskv.stats())
+        });
+        let search_indexer = self.search_index.as_ref().map(|index| {
+            DocSearchIndexer::new(index.clone(), doc_id.to_string(), dwskv.awareness())
+        });
+
+        {
+            let sync_kv = dwskv.sync_kv();
+            let checkpoint_freq = self.checkpoint_freq;
+            let doc_id = doc_id.to_string();
+            let cancellation_token = self.cancellation_token.clone();
+
+            let lease = if let Some(leases) = &self.leases {
+                let lease = leases.lease(&doc_id);
+                if !lease.try_acquire().await? {
+                    tracing::warn!(
+                        message = "Document lease is held by another node, not persisting",
+                        event = "lease_held_elsewhere",
+                        doc_id = %doc_id
+                    );
+                }
+                self.doc_worker_tracker.spawn(
+                    lease
+                        .clone()
+                        .heartbeat(sync_kv.clone(), cancellation_token.clone()),
+                );
+                Some(lease)
+            } else {
+                None
+            };
+
+            // Spawn a task to save the document to the store when it changes.
+            self.doc_worker_tracker.spawn(Self::doc_persistence_worker(
+                recv,
+                sync_kv,
+                Arc::downgrade(&dwskv.awareness()),
+                self.skip_gc,
+                checkpoint_freq,
+                doc_id.clone(),
+                cancellation_token.clone(),
+                lease,
+                wal,
+                events,
+                stats_flusher,
+                search_indexer,
+                self.quotas.clone(),
+                self.sync_metrics.clone(),
+                self.persistence_health.clone(),
+                self.shutdown_persistence.clone(),
+                self.slow_thresholds,
+                self.readiness.clone(),
+            ));
+
+            if self.doc_gc {
+                self.doc_worker_tracker.spawn(doc_gc_worker(
+                    self.docs.clone(),
+                    doc_id.clone(),
+                    checkpoint_freq,
+                    self.doc_gc_policy,
+                    self.pins.clone(),
+                    self.event_handler.clone(),
+                    cancellation_token,
+                ));
+            }
+        }
+
+        let awareness = dwskv.awareness();
+        self.docs.insert(doc_id.to_string(), dwskv);
+        if let Some(budget) = &self.memory_budget {
+            budget.loaded(doc_id);
+        }
+        if let Some(handler) = &self.event_handler {
+            handler.doc_loaded(doc_id);
+        }
+
+        if let Some(replication) = &self.replication {
+            replication.observe(doc_id, &awareness);
+        }
+
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn doc_persistence_worker(
+        mut recv: DirtyReceiver,
+        sync_kv: Arc<SyncKv>,
+        // Weak, so the worker does not count as a connection keeping the document loaded
+        awareness: Weak<RwLock<Awareness>>,
+        skip_gc: bool,
+        checkpoint_freq: Duration,
+        doc_id: String,
+        cancellation_token: CancellationToken,
+        lease: Option<Arc<DocLease>>,
+        wal: Option<DocWal>,
+        events: Option<DocEvents>,
+        mut stats_flusher: Option<DocStatsFlusher>,
+        mut search_indexer: Option<DocSearchIndexer>,
+        quotas: Option<Arc<Quotas>>,
+        metrics: Arc<SyncMetrics>,
+        health: Arc<PersistenceHealth>,
+        shutdown: Arc<ShutdownPersistence>,
+        slow_thresholds: SlowThresholds,
+        readiness: Arc<Readiness>,
+    ) {
+        let _running = shutdown.worker_running(&doc_id);
+        let mut last_save = std::time::Instant::now();
+
+        loop {
+            let is_done = tokio::select! {
+                v = recv.recv() => v.is_none(),
+                _ = cancellation_token.cancelled() => true,
+                _ = tokio::time::sleep(checkpoint_freq) => {
+                    sync_kv.is_shutdown()
+                }
+            };
+
+            tracing::debug!("Received signal. done: {}", is_done);
+            let now = std::time::Instant::now();
+            if !is_done && now - last_save < checkpoint_freq {
+                let sleep = tokio::time::sleep(checkpoint_freq - (now - last_save));
+                tokio::pin!(sleep);
+                tracing::debug!("Throttling.");
+
+                loop {
+                    tokio::select! {
+                        _ = &mut sleep => {
+                            break;
+                        }
+                        v = recv.recv() => {
+                            tracing::debug!("Received dirty while throttling.");
+                            if v.is_none() {
+                                break;
+                            }
+                            if sync_kv.checkpoint_due() {
+                                tracing::debug!("Checkpoint triggered by pending changes.");
+                                break;
+                            }
+                        }
+                        _ = cancellation_token.cancelled() => {
+                            tracing::debug!("Received cancellation while throttling.");
+                            break;
+                        }
+
+                    }
+                    tracing::debug!("Done throttling.");
+                }
+            }
+            // On shutdown, documents take turns to persist, so as not to overload the store
+            let shutting_down = is_done && cancellation_token.is_cancelled();
+            let _permit = if shutting_down {
+                Some(shutdown.acquire().await)
+            } else {
+                None
+            };
+            let mut persisted;
+            let may_persist = match &lease {
+                Some(lease) => lease.is_held() || lease.try_acquire().await.unwrap_or(false),
+                None => true,
+            };
+            let caught_up = match (&lease, awareness.upgrade()) {
+                (Some(lease), Some(awareness)) if may_persist => {
+                    lease.catch_up(&awareness, skip_gc).await
+                }
+                _ => Ok(()),
+            };
+            if !may_persist {
+                // Still dirty, so persisted once the lease is acquired
+                persisted = false;
+                tracing::warn!(
+                    message = "Not persisting, document lease is held by another node",
+                    event = "persist_skipped_lease",
+                    doc_id = %doc_id
+                );
+            } else if let Err(e) = caught_up {
+                // Persisting now could overwrite the changes of the previous holder
+                persisted = false;
+                tracing::warn!(
+                    message = format!("Not persisting, failed to read the stored snapshot: {}", e),
+                    event = "persist_skipped_catch_up",
+                    doc_id = %doc_id,
+                    error = %e
+                );
+            } else {
+                // Updates logged from here on may not be part of this checkpoint
+                if let Some(wal) = &wal {
+                    wal.rotate().await;
+                }
+                let mut retry = 0;
+                loop {
+                    // The error is not Send, so it must not be held across an await
+                    let started = Instant::now();
+                    let in_flight = readiness.persisting();
+                    let error = sync_kv.persist().await.err().map(|e| e.to_string());
+                    drop(in_flight);
+                    let outcome = if error.is_none() { "ok" } else { "error" };
+                    let elapsed = started.elapsed();
+                    metrics.persist.observe(outcome, elapsed);
+                    check_slow_persist(
+                        &slow_thresholds,
+                        &doc_id,
+                        sync_kv.size_bytes(),
+                        error.is_none(),
+                        elapsed,
+                    );
+                    let Some(error) = error else {
+                        if let Some(wal) = &wal {
+                            wal.checkpoint_written();
+                        }
+                        if let Some(events) = &events {
+                            events.persisted();
+                        }
+                        if let Some(stats_flusher) = &mut stats_flusher {
+                            stats_flusher.flush().await;
+                        }
+                        if let Some(search_indexer) = &mut search_indexer {
+                            search_indexer.index().await;
+                        }
+                        if let Some(quotas) = &quotas {
+                            quotas.record_doc(&doc_id, sync_kv.size_bytes() as u64);
+                        }
+                        if let Some(failure) = health.record_success(&doc_id) {
+                            info!(
+                                message = "Persisted document after failures",
+                                event = "persist_recovered",
+                                doc_id = %doc_id,
+                                attempts = failure.attempts
+                            );
+                        }
+                        tracing::debug!(message = "Done persisting", event = "persist_completed");
+                        persisted = true;
+                        break;
+                    };
+
+                    health.record_failure(&doc_id, &error);
+                    persisted = false;
+                    tracing::error!(
+                        message = format!("Error persisting: {}", error),
+                        event = "persist_error",
+                        doc_id = %doc_id,
+                        attempt = retry + 1,
+                        error = %error
+                    );
+                    // Still dirty, so the next checkpoint tries again
+                    if is_done || retry >= health.policy().max_retries {
+                        break;
+                    }
+                    let backoff = health.policy().backoff(retry);
+                    retry += 1;
+                    tokio::select! {
+                        _ = tokio::time::sleep(backoff) => {}
+                        _ = cancellation_token.cancelled() => {}
+                    }
+                }
+            }
+            last_save = std::time::Instant::now();
+            if shutting_down {
+                shutdown.finished(&doc_id, persisted);
+            }
+
+            if is_done {
+                break;
+            }
+        }
+
+        if let Some(lease) = lease {
+            if let Err(e) = lease.release().await {
+                tracing::warn!(
+                    message = format!("Failed to release document lease: {}", e),
+                    event = "lease_release_failed",
+                    doc_id = %doc_id,
+                    error = %e
+                );
+            }
+        }
+        tracing::debug!(
+            message = format!("Terminating loop for: {}", doc_id),
+            event = "doc_loop_terminated",
+            doc_id = %doc_id
+        );
+    }
+
+    pub async fn get_or_create_doc(
+        &self,
+        doc_id: &str,
+    ) -> Result<MappedRef<'_, String, DocWithSyncKv, DocWithSyncKv>> {
+        if !self.docs.contains_key(doc_id) {
+            tracing::debug!(
+                message = format!("Loading doc: {}", doc_id),
+                event = "doc_loading_started",
+                doc_id = ?doc_id
+            );
+            self.load_doc(doc_id).await?;
+        } else if let Some(budget) = &self.memory_budget {
+            budget.touch(doc_id);
+        }
+
+        Ok(self
+            .docs
+            .get(doc_id)
+            .ok_or_else(|| anyhow!("Failed to get-or-create doc"))?
+            .map(|d| d))
+    }
+
+    pub fn check_auth(
+        &self,
+        auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    ) -> Result<(), AppError> {
+        if let Some(auth) = self.auth_provider() {
+            if let Some(TypedHeader(headers::Authorization(bearer))) = auth_header {
+                if let Ok(()) =
+                    auth.verify_server_token(bearer.token(), current_time_epoch_millis())
+                {
+                    return Ok(());
+                }
+            }
+            Err((StatusCode::UNAUTHORIZED, anyhow!("Unauthorized.")))?
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Structured logging middleware for request/response logging
+    pub async fn logging_middleware(req: Request, next: Next) -> impl IntoResponse {
+        let start = Instant::now();
+        let method = req.method().clone();
+        let uri = crate::redact_ext::redact_query(req.uri());
+
+        // Extract path parameters for better logging
+        let path_params =
+            if let Some(path) = req.uri().path().split('/').collect::<Vec<_>>().get(2..) {
+                path.join("/")
+            } else {
+                "".to_string()
+            };
+
+        // Extract and log request body for POST/PUT requests
+        let (request_body, req) = if method == "POST" || method == "PUT" {
+            // Read only the start of the body, so that its size limit still applies to the
+            // rest where the handler reads it
+            let (parts, body) = req.into_parts();
+            let (bytes, complete, body) =
+                crate::body_limits_ext::peek_body(body, LOGGED_BODY_BYTES).await;
+
+            // Process body content for logging
+            let request_body = if !bytes.is_empty() {
+                // Try to parse as JSON for better readability
+                let json = complete
+                    .then(|| serde_json::from_slice::<serde_json::Value>(&bytes).ok())
+                    .flatten();
+                if let Some(json_value) = json {
+                    Some(json_value) // Store the parsed JSON value directly
+                } else {
+                    // For non-JSON content, show first 1000 characters
+                    let body_str = String::from_utf8_lossy(&bytes);
+                    if let Some((end, _)) = body_str.char_indices().nth(1000) {
+                        Some(serde_json::Value::String(format!(
+                            "{}... (truncated)",
+                            &body_str[..end]
+                        )))
+                    } else {
+                        Some(serde_json::Value::String(body_str.to_string()))
+                    }
+                }
+            } else {
+                None
+            };
+
+            // Reconstruct the request for the next middleware
+            let req = Request::from_parts(parts, body);
+
+            (request_body, req)
+        } else {
+            (None, req)
+        };
+
+        // Now extract headers after req has been potentially reconstructed
+        let user_agent = req
+            .headers()
+            .get("user-agent")
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or("unknown");
+        let remote_addr = req
+            .extensions()
+            .get::<ClientInfo>()
+            .and_then(|client| client.addr)
+            .map(|addr| addr.to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+
+        let span = span!(
+            Level::INFO,
+            "http_request",
+            method = %method,
+            uri = %uri,
+            user_agent = %user_agent,
+            remote_addr = %remote_addr,
+            path = %path_params
+        );
+
+        let _enter = span.enter();
+
+        // Store variables before moving req
+        let method_clone = method.clone();
+        let uri_clone = uri.clone();
+        let user_agent_clone = user_agent.to_string();
+        let remote_addr_clone = remote_addr.clone();
+        let path_params_clone = path_params.clone();
+        let request_body_clone = request_body.clone();
+
+        let response = next.run(req).await;
+        let status = response.status();
+        let duration = start.elapsed();
+
+        // Single log per request with message at top level
+        let message = if status.is_server_error() {
+            format!(
+                "Request failed with server error: {} {} - {}ms",
+                method_clone,
+                uri_clone,
+                duration.as_millis()
+            )
+        } else if status.is_client_error() {
+            format!(
+                "Request failed with client error: {} {} - {}ms",
+                method_clone,
+                uri_clone,
+                duration.as_millis()
+            )
+        } else {
+            format!(
+                "Request completed: {} {} - {}ms",
+                method_clone,
+                uri_clone,
+                duration.as_millis()
+            )
+        };
+
+        if status.is_server_error() {
+            error!(
+                message = %message,
+                event = "request_failed",
+                method = %method_clone,
+                uri = %uri_clone,
+                status = %status,
+                duration_ms = %duration.as_millis(),
+                error_type = "server_error",
+                remote_addr = %remote_addr_clone,
+                path = %path_params_clone,
+                user_agent = %user_agent_clone,
+                request_body = %request_body_clone.as_ref().map(|b| serde_json::to_string(b).unwrap_or_default()).unwrap_or_default(),
+            );
+        } else if status.is_client_error() {
+            warn!(
+                message = %message,
+                event = "request_failed",
+                method = %method_clone,
+                uri = %uri_clone,
+                status = %status,
+                duration_ms = %duration.as_millis(),
+                error_type = "client_error",
+                remote_addr = %remote_addr_clone,
+                path = %path_params_clone,
+                user_agent = %user_agent_clone,
+                request_body = %request_body_clone.as_ref().map(|b| serde_json::to_string(b).unwrap_or_default()).unwrap_or_default(),
+            );
+        } else {
+            info!(
+                message = %message,
+                event = "request_completed",
+                method = %method_clone,
+                uri = %uri_clone,
+                status = %status,
+                duration_ms = %duration.as_millis(),
+                remote_addr = %remote_addr_clone,
+                path = %path_params_clone,
+                user_agent = %user_agent_clone,
+                request_body = %request_body_clone.as_ref().map(|b| serde_json::to_string(b).unwrap_or_default()).unwrap_or_default(),
+            );
+        }
+
+        response
+    }
+
+    pub async fn redact_error_middleware(req: Request, next: Next) -> impl IntoResponse {
+        let resp = next.run(req).await;
+        if resp.status().is_server_error() || resp.status().is_client_error() {
+            // If we should redact errors, copy over only the status code and
+            // not the response body.
+            return resp.status().into_response();
+        }
+        resp
+    }
+
+    pub fn routes(self: &Arc<Self>) -> Router {
+        crate::tenants_ext::with_tenant_routes(
+            self,
+            self.mount(self.public_routes().merge(self.admin_routes())),
+            |tenant| tenant.public_routes().merge(tenant.admin_routes()),
+        )
+    }
+
+    /// Nests `routes` under the base path of the server, if it has one. The routes returned
+    /// by [Server::public_routes], [Server::admin_routes] and [Server::single_doc_routes]
+    /// are relative to the base path, to be mounted once merged.
+    pub fn mount(&self, routes: Router) -> Router {
+        match &self.base_path {
+            Some(base_path) => Router::new().nest(base_path, routes),
+            None => routes,
+        }
+    }
+
+    /// Routes of the sync, auth and asset endpoints, exposed to clients.
+    pub fn public_routes(self: &Arc<Self>) -> Router {
+        let compression = crate::server_ext::ext_compression_layer(self.compression);
+        let base_routes = Router::new()
+            .route("/ready", get(ready))
+            .route("/live", get(live))
+            .route("/doc/ws/:doc_id", get(handle_socket_upgrade_deprecated))
+            .route("/doc/:doc_id/auth", post(auth_doc))
+            .route(
+                "/doc/:doc_id/as-update",
+                get(get_doc_as_update_deprecated).layer(compression.clone()),
+            )
+            .route(
+                "/doc/:doc_id/update",
+                limit_body(
+                    post(update_doc_deprecated),
+                    self.body_limits,
+                    BodyKind::Update,
+                ),
+            )
+            .route(
+                "/d/:doc_id/as-update",
+                get(get_doc_as_update).layer(compression),
+            )
+            .route(
+                "/d/:doc_id/update",
+                limit_body(post(update_doc), self.body_limits, BodyKind::Update),
+            )
+            .route(
+                "/d/:doc_id/ws/:doc_id2",
+                get(handle_socket_upgrade_full_path),
+            )
+            .layer(middleware::from_fn(Self::logging_middleware))
+            .with_state(self.clone());
+
+        // Merge extension routes
+        self.route_writes(trace_requests(base_routes).merge(crate::server_ext::ext_routes(self)))
+    }
+
+    /// Routes of the management endpoints, which only the server token can call.
+    pub fn admin_routes(self: &Arc<Self>) -> Router {
+        let base_routes = Router::new()
+            .route("/check_store", post(check_store))
+            .route("/check_store", get(check_store_deprecated))
+            .route("/doc/new", post(new_doc))
+            .layer(middleware::from_fn(Self::logging_middleware))
+            .with_state(self.clone());
+
+        // Merge extension routes
+        self.route_writes(
+            trace_requests(base_routes).merge(crate::server_ext::ext_admin_routes(self)),
+        )
+    }
+
+    /// Rejects writes to a follower, and routes requests to the owner of their document.
+    fn route_writes(self: &Arc<Self>, routes: Router) -> Router {
+        routes
+            .layer(middleware::from_fn_with_state(
+                self.clone(),
+                crate::follower_ext::reject_writes,
+            ))
+            .layer(middleware::from_fn_with_state(
+                self.clone(),
+                crate::cluster_ext::route_to_owner,
+            ))
+    }
+
+    pub fn single_doc_routes(self: &Arc<Self>) -> Router {
+        let compression = crate::server_ext::ext_compression_layer(self.compression);
+        let base_routes = Router::new()
+            .route("/ready", get(ready))
+            .route("/live", get(live))
+            .route("/ws/:doc_id", get(handle_socket_upgrade_single))
+            .route(
+                "/as-update",
+                get(get_doc_as_update_single).layer(compression),
+            )
+            .route(
+                "/update",
+                limit_body(post(update_doc_single), self.body_limits, BodyKind::Update),
+            )
+            .layer(middleware::from_fn(Self::logging_middleware))
+            .with_state(self.clone());
+
+        // Merge extension routes
+        trace_requests(base_routes).merge(crate::server_ext::ext_single_doc_routes(self))
+    }
+
+    /// Applies the body size limit, error redaction, warnings of slow requests and the
+    /// resolution of clients behind trusted proxies to `routes`.
+    pub(crate) fn app(&self, routes: Router, redact_errors: bool) -> Router {
+        let app = if let Some(max_body_size) = self.max_body_size {
+            routes.layer(DefaultBodyLimit::max(max_body_size))
+        } else {
+            routes
+        };
+
+        let app = if redact_errors {
+            app
+        } else {
+            app.layer(middleware::from_fn(Self::redact_error_middleware))
+        };
+        let app = if self.slow_thresholds.request.is_some() {
+            app.layer(middleware::from_fn_with_state(
+                self.slow_thresholds,
+                warn_slow_requests,
+            ))
+        } else {
+            app
+        };
+        app.layer(middleware::from_fn_with_state(
+            self.trusted_proxies.clone(),
+            resolve_client,
+        ))
+    }
+
+    async fn serve_internal(
+        self: Arc<Self>,
+        listener: Listener,
+        redact_errors: bool,
+        routes: Router,
+    ) -> Result<()> {
+        let token = self.cancellation_token.clone();
+        let app = self.app(routes, redact_errors);
+
+        match (listener, &self.tls) {
+            (Listener::Tcp(listener), Some(tls)) => {
+                serve_tls(listener, app, tls, token).await?;
+            }
+            (Listener::Tcp(listener), None) => {
+                axum::serve(
+                    listener,
+                    app.into_make_service_with_connect_info::<SocketAddr>(),
+                )
+                .with_graceful_shutdown(async move { token.cancelled().await })
+                .await?;
+            }
+            #[cfg(unix)]
+            (Listener::Unix(_), Some(_)) => {
+                return Err(anyhow!(
+                    "TLS is not supported on a Unix socket, terminate it in the proxy"
+                ));
+            }
+            #[cfg(unix)]
+            (Listener::Unix(listener), None) => {
+                crate::unix_socket_ext::serve_unix(listener, app, token).await?;
+            }
+        }
+
+        // Followers have nothing of their own to persist
+        let loaded_docs = if self.is_follower() {
+            0
+        } else {
+            self.docs.len()
+                + self
+                    .tenants
+                    .iter()
+                    .map(|tenant| tenant.server.docs.len())
+                    .sum::<usize>()
+        };
+        wait_for_workers(
+            &self.doc_worker_tracker,
+            &self.shutdown_persistence,
+            loaded_docs,
+        )
+        .await;
+
+        Ok(())
+    }
+
+    /// Spawn the workers reaping expired documents and compacting update logs in the store.
+    fn spawn_store_workers(self: &Arc<Self>) {
+        if self.store.is_none() {
+            return;
+        }
+        if self.quotas.is_some() {
+            self.doc_worker_tracker
+                .spawn(crate::quota_ext::quota_count_worker(self.clone()));
+        }
+        self.doc_worker_tracker
+            .spawn(crate::ttl_ext::ttl_reaper_worker(
+                self.clone(),
+                self.ttl_reap_interval,
+                self.cancellation_token.clone(),
+            ));
+        if self.has_update_log() && !self.is_follower() {
+            self.doc_worker_tracker
+                .spawn(crate::compaction_ext::compaction_worker(
+                    self.clone(),
+                    self.compaction_interval,
+                    self.compaction_min_segments,
+                    self.cancellation_token.clone(),
+                ));
+        }
+    }
+
+    pub async fn serve(self, listener: impl Into<Listener>, redact_errors: bool) -> Result<()> {
+        let mut server = self;
+        let admin_listener = server.admin_listener.take();
+        #[cfg(feature = "grpc")]
+        let grpc_listener = server.grpc_listener.take();
+        #[cfg(feature = "webtransport")]
+        let webtransport = server.webtransport.take();
+        let s = Arc::new(server);
+
+        #[cfg(feature = "grpc")]
+        if let Some(grpc_listener) = grpc_listener {
+            s.doc_worker_tracker.spawn(crate::grpc_ext::serve_grpc(
+                s.clone(),
+                grpc_listener,
+                s.cancellation_token.clone(),
+            ));
+        }
+
+        #[cfg(feature = "webtransport")]
+        if let Some(webtransport) = webtransport {
+            let endpoint = webtransport.bind(&s.ws_options).await?;
+            s.doc_worker_tracker
+                .spawn(crate::webtransport_ext::serve_webtransport(
+                    s.clone(),
+                    endpoint,
+                    s.cancellation_token.clone(),
+                ));
+        }
+
+        #[cfg(feature = "config-files")]
+        if s.config_path.is_some() {
+            s.doc_worker_tracker
+                .spawn(crate::reload_ext::reload_on_sighup(
+                    s.clone(),
+                    s.cancellation_token.clone(),
+                ));
+        }
+
+        if let Some(replication) = &s.replication {
+            s.doc_worker_tracker.spawn(
+                replication
+                    .clone()
+                    .run(s.clone(), s.cancellation_token.clone()),
+            );
+        }
+
+        s.spawn_store_workers();
+        for tenant in s.tenants.iter() {
+            tenant.server.spawn_store_workers();
+        }
+
+        if !s.preload.is_empty() {
+            s.doc_worker_tracker.spawn(crate::preload_ext::preload_docs(
+                s.clone(),
+                s.preload.clone(),
+                s.preload_progress.clone(),
+                s.cancellation_token.clone(),
+            ));
+        }
+
+        if let (Some(_), Some(budget)) = (&s.store, &s.memory_budget) {
+            s.doc_worker_tracker
+                .spawn(crate::memory_budget_ext::memory_budget_worker(
+                    s.clone(),
+                    budget.clone(),
+                    DEFAULT_MEMORY_CHECK_INTERVAL,
+                    s.cancellation_token.clone(),
+                ));
+        }
+
+        let routes = if let Some(admin_listener) = admin_listener {
+            let admin_routes = crate::tenants_ext::with_tenant_routes(
+                &s,
+                s.mount(s.admin_routes()),
+                Server::admin_routes,
+            );
+            let admin_app = s.app(admin_routes, redact_errors);
+            s.doc_worker_tracker.spawn(crate::admin_ext::serve_admin(
+                admin_listener,
+                admin_app,
+                s.cancellation_token.clone(),
+            ));
+            crate::tenants_ext::with_tenant_routes(
+                &s,
+                s.mount(s.public_routes()),
+                Server::public_routes,
+            )
+        } else {
+            s.routes()
+        };
+        s.serve_internal(listener.into(), redact_errors, routes)
+            .await
+    }
+
+    pub async fn serve_doc(self, listener: impl Into<Listener>, redact_errors: bool) -> Result<()> {
+        let s = Arc::new(self);
+        let routes = s.mount(s.single_doc_routes());
+        s.serve_internal(listener.into(), redact_errors, routes)
+            .await
+    }
+
+    pub fn verify_doc_token(
+        &self,
+        token: Option<&str>,
+        doc: &str,
+    ) -> Result<Authorization, AppError> {
+        if let Some(auth) = self.auth_provider() {
+            if let Some(token) = token {
+                let authorization = auth
+                    .verify_doc_token(token, doc, current_time_epoch_millis())
+                    .map_err(|e| (StatusCode::UNAUTHORIZED, e))?;
+                Ok(authorization)
+            } else {
+                Err((StatusCode::UNAUTHORIZED, anyhow!("No token provided.")))?
+            }
+        } else {
+            Ok(Authorization::Full)
+        }
+    }
+
+    pub fn get_single_doc_id(&self) -> Result<String, AppError> {
+        self.docs
+            .iter()
+            .find(|entry| !is_subdoc_key(entry.key()))
+            .map(|entry| entry.key().clone())
+            .ok_or_else(|| AppError(StatusCode::NOT_FOUND, anyhow!("No document found")))
+    }
+}
+
+#[derive(Deserialize)]
+struct HandlerParams {
+    token: Option<String>,
+}
+
+async fn get_doc_as_update(
+    State(server_state): State<Arc<Server>>,
+    Path(doc_id): Path<String>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+) -> Result<Response, AppError> {
+    // All authorization types allow reading the document.
+    let token = get_token_from_header(auth_header);
+    let _ = server_state.verify_doc_token(token.as_deref(), &doc_id)?;
+    crate::server_ext::ext_check_not_archived(&server_state, &doc_id).await?;
+
+    let dwskv = server_state
+        .get_or_create_doc(&doc_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+
+    let update = dwskv.as_update();
+    dwskv.stats().record_bytes_out(update.len());
+    tracing::debug!(
+        message = format!("update: {:?}", update),
+        event = "update_debug",
+        update = ?update
+    );
+    Ok(update.into_response())
+}
+
+async fn get_doc_as_update_deprecated(
+    Path(doc_id): Path<String>,
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+) -> Result<Response, AppError> {
+    tracing::warn!("/doc/:doc_id/as-update is deprecated; call /doc/:doc_id/auth instead and then call as-update on the returned base URL.");
+    get_doc_as_update(State(server_state), Path(doc_id), auth_header).await
+}
+
+async fn update_doc_deprecated(
+    Path(doc_id): Path<String>,
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, AppError> {
+    tracing::warn!("/doc/:doc_id/update is deprecated; call /doc/:doc_id/auth instead and then call update on the returned base URL.");
+    update_doc(
+        Path(doc_id),
+        State(server_state),
+        auth_header,
+        headers,
+        body,
+    )
+    .await
+}
+
+async fn get_doc_as_update_single(
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+) -> Result<Response, AppError> {
+    let doc_id = server_state.get_single_doc_id()?;
+    get_doc_as_update(State(server_state), Path(doc_id), auth_header).await
+}
+
+async fn update_doc(
+    Path(doc_id): Path<String>,
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, AppError> {
+    let token = get_token_from_header(auth_header);
+    let authorization = server_state.verify_doc_token(token.as_deref(), &doc_id)?;
+    let expected_state_vector = crate::server_ext::ext_expected_state_vector(&headers)?;
+    let bytes = body.len();
+    let response = update_doc_inner(
+        doc_id.clone(),
+        server_state.clone(),
+        authorization,
+        body,
+        expected_state_vector,
+    )
+    .await?;
+    if response.status().is_success() {
+        record_audit(
+            &server_state,
+            &doc_id,
+            "update_applied",
+            AuditPrincipal::doc(token.as_deref(), authorization),
+            Some(json!({ "bytes": bytes })),
+        )
+        .await;
+    }
+    Ok(response)
+}
+
+pub(crate) async fn update_doc_inner(
+    doc_id: String,
+    server_state: Arc<Server>,
+    authorization: Authorization,
+    body: Bytes,
+    expected_state_vector: Option<StateVector>,
+) -> Result<Response, AppError> {
+    if !matches!(authorization, Authorization::Full) {
+        return Err(AppError(StatusCode::FORBIDDEN, anyhow!("Unauthorized.")));
+    }
+
+    crate::server_ext::ext_check_not_archived(&server_state, &doc_id).await?;
+
+    crate::server_ext::ext_check_not_frozen(&server_state, &doc_id).await?;
+
+    if server_state.persistence_health().rejects_writes(&doc_id) {
+        return Err(AppError(
+            StatusCode::SERVICE_UNAVAILABLE,
+            anyhow!("Document cannot be persisted, writes are suspended."),
+        ));
+    }
+
+    let dwskv = server_state
+        .get_or_create_doc(&doc_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+
+    dwskv.stats().record_bytes_in(body.len());
+    let received = Instant::now();
+    let result = (|| {
+        crate::server_ext::ext_validate_update(&server_state, &doc_id, &dwskv, &body)?;
+
+        if let Some(expected_state_vector) = expected_state_vector {
+            return crate::server_ext::ext_apply_update_if_current(
+                &dwskv,
+                &body,
+                &expected_state_vector,
+            );
+        }
+
+        if let Err(err) = dwskv.apply_update(&body) {
+            tracing::error!(?err, "Failed to apply update");
+            return Err(AppError(StatusCode::INTERNAL_SERVER_ERROR, err));
+        }
+
+        Ok(StatusCode::OK.into_response())
+    })();
+    server_state
+        .sync_metrics()
+        .update_broadcast
+        .observe(outcome(&result), received.elapsed());
+    result
+}
+
+async fn update_doc_single(
+    State(server_state): State<Arc<Server>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, AppError> {
+    let doc_id = server_state.get_single_doc_id()?;
+    let expected_state_vector = crate::server_ext::ext_expected_state_vector(&headers)?;
+    // the doc server is meant to be run in Plane, so we expect verified plane
+    // headers to be used for authorization.
+    let authorization = get_authorization_from_plane_header(headers)?;
+    update_doc_inner(
+        doc_id,
+        server_state,
+        authorization,
+        body,
+        expected_state_vector,
+    )
+    .await
+}
+
+/// A client let into the sync of a document, before its transport is set up.
+pub(crate) struct SyncClient {
+    pub doc_id: String,
+    pub awareness: Arc<RwLock<Awareness>>,
+    /// Downgraded to read-only where the document does not accept changes.
+    pub authorization: Authorization,
+    pub frozen: Arc<AtomicBool>,
+    /// Released when the connection ends.
+    pub permit: ConnectionPermit,
+    pub stats: Arc<DocStats>,
+    pub broadcast: Arc<DocBroadcast>,
+    pub subdocs: crate::subdoc_ext::SubdocRouter,
+    pub message_limits: MessageLimits,
+    pub update_validator: Option<Arc<dyn UpdateValidator>>,
+}
+
+/// Let a client into the sync of `doc_id`, loading the document if needed. The checks are
+/// the same for every transport.
+pub(crate) async fn admit_sync_client(
+    server_state: &Arc<Server>,
+    doc_id: String,
+    authorization: Authorization,
+) -> Result<SyncClient, AppError> {
+    crate::server_ext::ext_check_not_archived(server_state, &doc_id).await?;
+
+    if !matches!(authorization, Authorization::Full) && !server_state.docs.contains_key(&doc_id) {
+        return Err(AppError(
+            StatusCode::NOT_FOUND,
+            anyhow!("Doc {} not found", doc_id),
+        ));
+    }
+
+    // Followers never accept changes, whatever the token allows, and neither do frozen
+    // documents or documents that have been failing to persist for too long
+    let frozen = doc_freeze_flag(server_state, &doc_id).await?;
+    let authorization = if server_state.is_follower()
+        || frozen.load(std::sync::atomic::Ordering::SeqCst)
+        || server_state.persistence_health().rejects_writes(&doc_id)
+    {
+        Authorization::ReadOnly
+    } else {
+        authorization
+    };
+
+    let permit = server_state.connection_limits.acquire(&doc_id)?;
+
+    let dwskv = server_state
+        .get_or_create_doc(&doc_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    let awareness = dwskv.awareness();
+    let stats = dwskv.stats();
+    let broadcast = server_state.broadcasts.get_or_create(&doc_id, &awareness);
+    let subdocs =
+        crate::subdoc_ext::SubdocRouter::new(server_state.clone(), doc_id.clone(), authorization);
+    Ok(SyncClient {
+        doc_id,
+        awareness,
+        authorization,
+        frozen,
+        permit,
+        stats,
+        broadcast,
+        subdocs,
+        message_limits: server_state.message_limits(),
+        update_validator: server_state.connection_update_validator(),
+    })
+}
+
+/// Run `session`, the connection of an admitted client, telling the event handler and the
+/// audit log about it. Tracked so that shutdown waits for connections to drain.
+pub(crate) fn track_sync_client<F>(
+    server_state: Arc<Server>,
+    doc_id: String,
+    authorization: Authorization,
+    principal: AuditPrincipal,
+    session: F,
+) -> impl std::future::Future<Output = ()>
+where
+    F: std::future::Future<Output = ()>,
+{
+    let tracker = server_state.doc_worker_tracker.clone();
+    tracker.track_future(async move {
+        let event_handler = server_state.event_handler().cloned();
+        if let Some(handler) = &event_handler {
+            handler.client_connected(&doc_id, authorization);
+        }
+        record_audit(
+            &server_state,
+            &doc_id,
+            "client_connected",
+            principal.clone(),
+            None,
+        )
+        .await;
+        session.await;
+        if let Some(handler) = &event_handler {
+            handler.client_disconnected(&doc_id);
+        }
+        record_audit(
+            &server_state,
+            &doc_id,
+            "client_disconnected",
+            principal,
+            None,
+        )
+        .await;
+    })
+}
+
+async fn handle_socket_upgrade(
+    ws: WebSocketUpgrade,
+    Path(doc_id): Path<String>,
+    authorization: Authorization,
+    principal: AuditPrincipal,
+    State(server_state): State<Arc<Server>>,
+) -> Result<Response, AppError> {
+    let handshake = Instant::now();
+    let client = admit_sync_client(&server_state, doc_id, authorization).await?;
+    let cancellation_token = server_state.cancellation_token();
+    let options = server_state.ws_options();
+    let metrics = server_state.sync_metrics.clone();
+    let slow_thresholds = server_state.slow_thresholds();
+    Ok(ws.on_upgrade(move |socket| {
+        let SyncClient {
+            doc_id,
+            awareness,
+            authorization,
+            frozen,
+            permit,
+            stats,
+            broadcast,
+            subdocs,
+            message_limits,
+            update_validator,
+        } = client;
+        track_sync_client(
+            server_state,
+            doc_id.clone(),
+            authorization,
+            principal,
+            async move {
+                let _permit = permit;
+                handle_socket(
+                    socket,
+                    doc_id,
+                    awareness,
+                    authorization,
+                    frozen,
+                    cancellation_token,
+                    broadcast,
+                    subdocs,
+                    options,
+                    message_limits,
+                    update_validator,
+                    handshake,
+                    metrics,
+                    stats,
+                    slow_thresholds,
+                )
+                .await;
+            },
+        )
+    }))
+}
+
+async fn handle_socket_upgrade_deprecated(
+    ws: WebSocketUpgrade,
+    Path(doc_id): Path<String>,
+    Query(params): Query<HandlerParams>,
+    State(server_state): State<Arc<Server>>,
+) -> Result<Response, AppError> {
+    warn!(
+        event = "deprecated_endpoint_used",
+        endpoint = "/doc/ws/:doc_id",
+        suggestion = "call /doc/:doc_id/auth instead and use the returned URL"
+    );
+    let authorization = server_state.verify_doc_token(params.token.as_deref(), &doc_id)?;
+    let principal = AuditPrincipal::doc(params.token.as_deref(), authorization);
+    handle_socket_upgrade(
+        ws,
+        Path(doc_id),
+        authorization,
+        principal,
+        State(server_state),
+    )
+    .await
+}
+
+async fn handle_socket_upgrade_full_path(
+    ws: WebSocketUpgrade,
+    Path((doc_id, doc_id2)): Path<(String, String)>,
+    Query(params): Query<HandlerParams>,
+    State(server_state): State<Arc<Server>>,
+) -> Result<Response, AppError> {
+    if doc_id != doc_id2 {
+        return Err(AppError(
+            StatusCode::BAD_REQUEST,
+            anyhow!("For Yjs compatibility, the doc_id appears twice in the URL. It must be the same in both places, but we got {} and {}.", doc_id, doc_id2),
+        ));
+    }
+    let authorization = server_state.verify_doc_token(params.token.as_deref(), &doc_id)?;
+    let principal = AuditPrincipal::doc(params.token.as_deref(), authorization);
+    handle_socket_upgrade(
+        ws,
+        Path(doc_id),
+        authorization,
+        principal,
+        State(server_state),
+    )
+    .await
+}
+
+async fn handle_socket_upgrade_single(
+    ws: WebSocketUpgrade,
+    Path(doc_id): Path<String>,
+    headers: HeaderMap,
+    State(server_state): State<Arc<Server>>,
+) -> Result<Response, AppError> {
+    let single_doc_id = server_state.get_single_doc_id()?;
+    if doc_id != single_doc_id {
+        return Err(AppError(
+            StatusCode::NOT_FOUND,
+            anyhow!("Document not found"),
+        ));
+    }
+
+    // the doc server is meant to be run in Plane, so we expect verified plane
+    // headers to be used for authorization.
+    let authorization = get_authorization_from_plane_header(headers)?;
+    let principal = AuditPrincipal::doc(None, authorization);
+    handle_socket_upgrade(
+        ws,
+        Path(single_doc_id),
+        authorization,
+        principal,
+        State(server_state),
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_socket(
+    socket: WebSocket,
+    doc_id: String,
+    awareness: Arc<RwLock<Awareness>>,
+    authorization: Authorization,
+    frozen: Arc<AtomicBool>,
+    cancellation_token: CancellationToken,
+    broadcast: Arc<DocBroadcast>,
+    subdocs: crate::subdoc_ext::SubdocRouter,
+    options: WsOptions,
+    message_limits: MessageLimits,
+    update_validator: Option<Arc<dyn UpdateValidator>>,
+    handshake: Instant,
+    metrics: Arc<SyncMetrics>,
+    stats: Arc<DocStats>,
+    slow_thresholds: SlowThresholds,
+) {
+    let _connection = stats.connect();
+    let (mut sink, mut stream) = socket.split();
+    // Replies to this client only; updates for everyone come through the document broadcast
+    let (send, mut recv) = channel::<Vec<u8>>(options.send_buffer);
+    let mut updates = broadcast.subscribe();
+    let subdoc_send = send.clone();
+    let error_send = send.clone();
+    let overflow = Arc::new(SendOverflow::default());
+    let mut subdocs = subdocs.with_overflow(overflow.clone());
+
+    info!(
+        message = "WebSocket connected",
+        event = "websocket_connected",
+        authorization_type = %match authorization {
+            Authorization::Full => "Full",
+            Authorization::ReadOnly => "ReadOnly",
+        }
+    );
+
+    let last_pong = Arc::new(RwLock::new(tokio::time::Instant::now()));
+    let last_pong_clone = last_pong.clone();
+    // Cancelled when the sending side gives up, so the receiving loop stops too
+    // instead of keeping a dead connection (and the doc) alive.
+    let connection_lost = CancellationToken::new();
+    let connection_lost_clone = connection_lost.clone();
+    // Lets the receiving loop close the connection with a specific frame
+    let (close_send, mut close_recv) = tokio::sync::oneshot::channel::<CloseFrame<'static>>();
+    let overflow_clone = overflow.clone();
+    let awareness_clone = awareness.clone();
+    let deleted = broadcast.deleted();
+    let sent_stats = stats.clone();
+
+    tokio::spawn(async move {
+        let _connection_lost = connection_lost_clone.drop_guard();
+        let _broadcast = broadcast;
+        let mut ticker = tokio::time::interval(options.ping_interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            // Biased so that an overflow is only handled once the queued messages are sent
+            tokio::select! {
+                biased;
+                frame = &mut close_recv => {
+                    let _ = sink.send(Message::Close(frame.ok())).await;
+                    break;
+                }
+                msg = recv.recv() => {
+                    let Some(msg) = msg else {
+                        break;
+                    };
+                    sent_stats.record_bytes_out(msg.len());
+                    if let Err(e) = sink.send(Message::Binary(msg)).await {
+                        let error_message = format!("WebSocket send error: {}", e);
+                        error!(
+                            message = %error_message,
+                            event = "websocket_send_error",
+                            error = %e
+                        );
+                        break;
+                    }
+                }
+                update = updates.recv() => {
+                    let msg = match update {
+                        Ok(msg) => msg,
+                        Err(broadcast::error::RecvError::Lagged(_)) => {
+                            overflow_clone.mark();
+                            continue;
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    };
+                    sent_stats.record_bytes_out(msg.len());
+                    if let Err(e) = sink.send(Message::Binary(msg.to_vec())).await {
+                        let error_message = format!("WebSocket send error: {}", e);
+                        error!(
+                            message = %error_message,
+                            event = "websocket_send_error",
+                            error = %e
+                        );
+                        break;
+                    }
+                }
+                _ = ticker.tick() => {
+                    if last_pong_clone.read().expect("Failed to get read lock on last_pong").elapsed() > options.idle_timeout {
+                        tracing::info!("Pong timeout, closing connection");
+                        let _ = sink.send(Message::Close(None)).await;
+                        break;
+                    }
+                    let _ = sink.send(Message::Ping(vec![])).await;
+                }
+                _ = std::future::ready(()), if overflow_clone.is_set() => {
+                    match overflow_clone.take(options.slow_consumer_policy) {
+                        Some(SlowConsumerAction::Close) => {
+                            warn!(
+                                message = "WebSocket client too slow, closing connection",
+                                event = "websocket_slow_consumer",
+                                action = "close"
+                            );
+                            let _ = sink
+                                .send(Message::Close(Some(CloseFrame {
+                                    code: RESYNC_REQUIRED_CLOSE_CODE,
+                                    reason: "resync required".into(),
+                                })))
+                                .await;
+                            break;
+                        }
+                        Some(SlowConsumerAction::Resync) => {
+                            warn!(
+                                message = "WebSocket client too slow, resending full state",
+                                event = "websocket_slow_consumer",
+                                action = "resync"
+                            );
+                            let messages = {
+                                let awareness = awareness_clone.read().unwrap();
+                                resync_messages(&awareness)
+                            };
+                            let mut sent = Ok(());
+                            for msg in messages {
+                                sent_stats.record_bytes_out(msg.len());
+                                sent = sink.send(Message::Binary(msg)).await;
+                                if sent.is_err() {
+                                    break;
+                                }
+                            }
+                            if let Err(e) = sent {
+                                let error_message = format!("WebSocket send error: {}", e);
+                                error!(
+                                    message = %error_message,
+                                    event = "websocket_send_error",
+                                    error = %e
+                                );
+                                break;
+                            }
+                        }
+                        None => {}
+                    }
+                }
+            }
+        }
+    });
+
+    let mut connection =
+        DocConnection::new_without_updates(awareness, authorization, move |bytes| {
+            if let Err(e) = send.try_send(bytes.to_vec()) {
+                let error_message = format!("WebSocket message error: {}", e);
+                warn!(
+                    message = %error_message,
+                    event = "websocket_message_error",
+                    error = %e
+                );
+                overflow.mark();
+            }
+        })
+        .with_read_only_flag(frozen);
+    if let Some(update_validator) = update_validator {
+        connection = connection.with_update_validator(&doc_id, update_validator);
+    }
+
+    let mut message_count = 0u64;
+    let mut synced = false;
+    let mut limiter = message_limits.limiter();
+    loop {
+        tokio::select! {
+            msg = stream.next() => {
+                let Some(msg) = msg else {
+                    break;
+                };
+                if msg.is_ok() {
+                    // Any frame from the client shows the connection is alive
+                    *last_pong.write().expect("Failed to get write lock on last_pong") = tokio::time::Instant::now();
+                }
+                let msg = match msg {
+                    Ok(Message::Binary(bytes)) => {
+                        message_count += 1;
+                        stats.record_bytes_in(bytes.len());
+                        if let Err(exceeded) = limiter.check(bytes.len()) {
+                            warn!(
+                                message = "WebSocket client exceeded a message limit, closing connection",
+                                event = "websocket_limit_exceeded",
+                                reason = exceeded.reason(),
+                                size = bytes.len(),
+                                total_messages = %message_count
+                            );
+                            let _ = close_send.send(exceeded.close_frame());
+                            break;
+                        }
+                        bytes
+                    }
+                    Ok(Message::Close(_)) => {
+                        info!(
+                            message = "WebSocket closed by client",
+                            event = "websocket_closed",
+                            total_messages = %message_count,
+                            reason = "client_close"
+                        );
+                        break;
+                    }
+                    Ok(Message::Pong(_)) | Ok(Message::Ping(_)) => {
+                        // Pings are answered automatically
+                        continue;
+                    }
+                    Err(e) => {
+                        // The stream will complain about things like
+                        // connections being lost without handshake.
+                        let error_message = format!("WebSocket stream error: {}", e);
+                        warn!(
+                            message = %error_message,
+                            event = "websocket_stream_error",
+                            error = %e
+                        );
+                        continue;
+                    }
+                    msg => {
+                        let error_message = format!("WebSocket invalid message: {:?}", msg);
+                        warn!(
+                            message = %error_message,
+                            event = "websocket_invalid_message",
+                            message = ?msg
+                        );
+                        continue;
+                    }
+                };
+
+                // Nothing may reach the in-memory copy of a deleted document
+                if deleted.is_cancelled() {
+                    continue;
+                }
+
+                let received = Instant::now();
+                let result = if is_subdoc_message(&msg) {
+                    subdocs.send(&msg, &subdoc_send).await
+                } else {
+                    connection.send(&msg).await
+                };
+                let outcome = if result.is_ok() { "ok" } else { "rejected" };
+                let elapsed = received.elapsed();
+                if is_update_message(&msg) {
+                    metrics.update_broadcast.observe(outcome, elapsed);
+                }
+                check_slow_message(&slow_thresholds, &doc_id, &msg, elapsed);
+                // The reply to the first sync step 1 carries the state the client is missing
+                if !synced && is_sync_step1_message(&msg) {
+                    synced = true;
+                    metrics.first_sync.observe(outcome, handshake.elapsed());
+                }
+
+                if let Err(e) = result {
+                    let error_message = format!("WebSocket message handling error: {}", e);
+                    error!(
+                        message = %error_message,
+                        event = "websocket_message_handling_error",
+                        error = %e,
+                        message_count = %message_count
+                    );
+                    // Tell the client why its message had no effect
+                    let _ = error_send.try_send(error_reply(&e));
+                }
+            }
+            _ = deleted.cancelled() => {
+                info!(
+                    message = "WebSocket closed because the document was deleted",
+                    event = "websocket_closed",
+                    total_messages = %message_count,
+                    reason = "document_deleted"
+                );
+                let _ = close_send.send(CloseFrame {
+                    code: DOC_DELETED_CLOSE_CODE,
+                    reason: "document deleted".into(),
+                });
+                break;
+            }
+            _ = connection_lost.cancelled() => {
+                info!(
+                    message = "WebSocket closed after the connection stopped responding",
+                    event = "websocket_closed",
+                    total_messages = %message_count,
+                    reason = "idle_timeout"
+                );
+                break;
+            }
+            _ = cancellation_token.cancelled() => {
+                info!(
+                    message = "WebSocket closed due to server shutdown",
+                    event = "websocket_closed",
+                    total_messages = %message_count,
+                    reason = "server_shutdown"
+                );
+                let _ = close_send.send(shutdown_close_frame(options.retry_after));
+
+                // Wait for the client to acknowledge, so it sees a clean close rather than a reset
+                let _ = tokio::time::timeout(options.drain_period, async {
+                    while let Some(Ok(msg)) = stream.next().await {
+                        if matches!(msg, Message::Close(_)) {
+                            break;
+                        }
+                    }
+                })
+                .await;
+                break;
+            }
+        }
+    }
+
+    if !synced {
+        metrics.first_sync.observe("closed", handshake.elapsed());
+    }
+}
+
+/// Close frame sent to WebSocket clients when the server shuts down. The reason carries
+/// `retry-after=<seconds>` if a reconnect delay is configured.
+fn shutdown_close_frame(retry_after: Option<Duration>) -> CloseFrame<'static> {
+    let reason = match retry_after {
+        Some(retry_after) => format!("retry-after={}", retry_after.as_secs()),
+        None => "server shutting down".to_string(),
+    };
+    CloseFrame {
+        code: close_code::RESTART,
+        reason: reason.into(),
+    }
+}
+
+async fn check_store(
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    State(server_state): State<Arc<Server>>,
+) -> Result<Json<Value>, AppError> {
+    server_state.check_auth(auth_header)?;
+
+    if server_state.store.is_none() {
+        return Ok(Json(json!({"ok": false, "error": "No store set."})));
+    };
+
+    // The check_store endpoint for the native server is kind of moot, since
+    // the server will not start if store is not ok.
+    Ok(Json(json!({"ok": true})))
+}
+
+async fn check_store_deprecated(
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    State(server_state): State<Arc<Server>>,
+) -> Result<Json<Value>, AppError> {
+    warn!(
+        message = "Deprecated endpoint used",
+        event = "deprecated_endpoint_used",
+        endpoint = "GET /check_store",
+        suggestion = "use POST /check_store with an empty body instead"
+    );
+    check_store(auth_header, State(server_state)).await
+}
+
+pub(crate) async fn new_doc(
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    State(server_state): State<Arc<Server>>,
+    Json(body): Json<DocCreationRequestExt>,
+) -> Result<Json<NewDocResponse>, AppError> {
+    server_state.check_auth(auth_header)?;
+
+    let expires_in_seconds = body.expires_in_seconds;
+    if expires_in_seconds.is_some() && server_state.store.is_none() {
+        Err((
+            StatusCode::BAD_REQUEST,
+            anyhow!("Document TTL requires a store"),
+        ))?
+    }
+
+    let doc_id = if let Some(doc_id) = body.request.doc_id {
+        if !server_state.doc_id_generator().accepts(&doc_id) {
+            Err((StatusCode::BAD_REQUEST, anyhow!("Invalid document name")))?
+        }
+
+        crate::server_ext::ext_check_not_archived(&server_state, &doc_id).await?;
+        crate::quota_ext::ext_check_doc_quota(&server_state, &doc_id).await?;
+
+        let created = if crate::cluster_ext::is_local(&server_state, &doc_id) {
+            server_state
+                .get_or_create_doc(doc_id.as_str())
+                .await
+                .map(|_| ())
+        } else {
+            server_state.create_doc_for_owner(&doc_id).await
+        };
+        created.map_err(|e| {
+            let error_message = format!("Failed to create doc: {}", e);
+            tracing::error!(
+                message = %error_message,
+                event = "doc_creation_failed",
+                error = %e,
+                error_debug = ?e,
+                doc_id = %doc_id
+            );
+            (StatusCode::INTERNAL_SERVER_ERROR, e)
+        })?;
+
+        doc_id
+    } else {
+        server_state.create_doc().await.map_err(|d| {
+            if d.is::<QuotaExceeded>() {
+                return AppError(StatusCode::FORBIDDEN, d);
+            }
+            let error_message = format!("Failed to create doc: {}", d);
+            tracing::error!(
+                message = %error_message,
+                event = "doc_creation_failed",
+                error = %d,
+                error_debug = ?d
+            );
+            AppError(StatusCode::INTERNAL_SERVER_ERROR, d)
+        })?
+    };
+
+    if let Some(expires_in_seconds) = expires_in_seconds {
+        let expires_at =
+            current_time_epoch_millis().saturating_add(expires_in_seconds.saturating_mul(1000));
+        crate::ttl_ext::set_doc_expiration(&server_state, &doc_id, expires_at).await?;
+    }
+
+    Ok(Json(NewDocResponse { doc_id }))
+}
+
+pub(crate) async fn auth_doc(
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    TypedHeader(host): TypedHeader<headers::Host>,
+    client: Option<Extension<ClientInfo>>,
+    State(server_state): State<Arc<Server>>,
+    Path(doc_id): Path<String>,
+    body: Option<Json<AuthDocRequest>>,
+) -> Result<Json<ClientToken>, AppError> {
+    let principal = AuditPrincipal::server(&auth_header);
+    server_state.check_auth(auth_header)?;
+
+    let Json(AuthDocRequest {
+        authorization,
+        valid_for_seconds,
+        ..
+    }) = body.unwrap_or_default();
+
+    crate::server_ext::ext_check_not_archived(&server_state, &doc_id).await?;
+
+    if !server_state.doc_exists(&doc_id).await {
+        Err((StatusCode::NOT_FOUND, anyhow!("Doc {} not found", doc_id)))?;
+    }
+
+    let valid_for_seconds = valid_for_seconds.unwrap_or(DEFAULT_EXPIRATION_SECONDS);
+    let expiration_time =
+        ExpirationTimeEpochMillis(current_time_epoch_millis() + valid_for_seconds * 1000);
+
+    let token = if let Some(auth) = server_state.auth_provider() {
+        let token = auth.gen_doc_token(&doc_id, authorization, expiration_time);
+        Some(token)
+    } else {
+        None
+    };
+    record_audit(
+        &server_state,
+        &doc_id,
+        "token_issued",
+        principal,
+        Some(json!({
+            "authorization": authorization,
+            "validForSeconds": valid_for_seconds,
+            "tokenId": token.as_deref().map(audit_token_id),
+        })),
+    )
+    .await;
+
+    let (url, base_url) = if let Some(template) = &server_state.client_url_template {
+        let owner = server_state
+            .cluster()
+            .map(|cluster| cluster.owner(&doc_id).id.as_str());
+        template
+            .client_urls(&doc_id, owner)
+            .map_err(|e| AppError(StatusCode::INTERNAL_SERVER_ERROR, e))?
+    } else {
+        let base_url = crate::cluster_ext::owner_doc_base_url(&server_state, &doc_id)
+            .unwrap_or_else(|| {
+                let client = client.map(|Extension(client)| client).unwrap_or_default();
+                server_state.doc_base_url(&doc_id, &host, client)
+            });
+        let url = if let Some(rest) = base_url.strip_prefix("https://") {
+            format!("wss://{rest}/ws")
+        } else if let Some(rest) = base_url.strip_prefix("http://") {
+            format!("ws://{rest}/ws")
+        } else {
+            format!("{base_url}/ws")
+        };
+
+        (url, base_url)
+    };
+
+    Ok(Json(ClientToken {
+        url,
+        base_url: Some(base_url),
+        doc_id,
+        token,
+        authorization,
+    }))
+}
+
+pub fn get_token_from_header(
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+) -> Option<String> {
+    if let Some(TypedHeader(headers::Authorization(bearer))) = auth_header {
+        Some(bearer.token().to_string())
+    } else {
+        None
+    }
+}
+
+#[derive(Deserialize)]
+struct PlaneVerifiedUserData {
+    authorization: Authorization,
+}
+
+pub fn get_authorization_from_plane_header(headers: HeaderMap) -> Result<Authorization, AppError> {
+    if let Some(token) = headers.get(HeaderName::from_static(PLANE_VERIFIED_USER_DATA_HEADER)) {
+        let token_str = token.to_str().map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+        let user_data: PlaneVerifiedUserData =
+            serde_json::from_str(token_str).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+        Ok(user_data.authorization)
+    } else {
+        Err((StatusCode::UNAUTHORIZED, anyhow!("No token provided.")))?
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server_ext::{
+        archive_document, copy_document, delete_document, delete_documents_batch, fork_document,
+        get_document_metadata, merge_document, unarchive_document,
+    };
+    #[cfg(feature = "assets")]
+    use crate::server_ext::{generate_upload_presigned_url, get_extension_from_content_type};
+    use async_trait::async_trait;
+    use dashmap::DashMap;
+    use std::sync::Arc;
+    use y_sweet_core::api_types::{Authorization, DocCreationRequest};
+    #[cfg(feature = "assets")]
+    use y_sweet_core::api_types_ext::ContentUploadRequest;
+    use y_sweet_core::api_types_ext::{
+        DocBatchDeleteRequest, DocCopyRequest, DocDeleteRequest, DocForkRequest, DocMergeRequest,
+    };
+    use y_sweet_core::store::{Result, Store};
+    use yrs_kvstore::KVStore;
+
+    #[derive(Default, Clone)]
+    struct TestStore {
+        data: Arc<DashMap<String, Vec<u8>>>,
+    }
+
+    impl TestStore {
+        fn insert(&self, key: &str, value: Vec<u8>) {
+            self.data.insert(key.to_owned(), value);
+        }
+    }
+
+    #[async_trait]
+    impl Store for TestStore {
+        async fn init(&self) -> Result<()> {
+            Ok(())
+        }
+
+        async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+            Ok(self.data.get(key).map(|v| v.clone()))
+        }
+
+        async fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
+            self.data.insert(key.to_owned(), value);
+            Ok(())
+        }
+
+        async fn remove(&self, key: &str) -> Result<()> {
+            self.data.remove(key);
+            Ok(())
+        }
+
+        async fn exists(&self, key: &str) -> Result<bool> {
+            Ok(self.data.contains_key(key))
+        }
+
+        async fn generate_upload_presigned_url(
+            &self,
+            key: &str,
+            _content_type: &str,
+        ) -> Result<String> {
+            Ok(format!("test://localhost/{}", key))
+        }
+
+        async fn generate_download_presigned_url(&self, key: &str) -> Result<String> {
+            Ok(format!("test://localhost/{}", key))
+        }
+
+        async fn list_objects(&self, prefix: &str) -> Result<Vec<String>> {
+            let mut objects = Vec::new();
+            for entry in self.data.iter() {
+                let key = entry.key();
+                if let Some(relative_key) = key.strip_prefix(prefix) {
+                    if !relative_key.is_empty() {
+                        objects.push(relative_key.to_string());
+                    }
+                }
+            }
+            Ok(objects)
+        }
+
+        async fn copy_document(&self, source_doc_id: &str, destination_doc_id: &str) -> Result<()> {
+            let source_prefix = format!("{}/", source_doc_id);
+            let destination_prefix = format!("{}/", destination_doc_id);
+
+            let keys_to_copy: Vec<(String, Vec<u8>)> = self
+                .data
+                .iter()
+                .filter_map(|entry| {
+                    let key = entry.key();
+                    if key.starts_with(&source_prefix) {
+                        let relative_path = &key[source_prefix.len()..];
+                        let destination_key = format!("{}{}", destination_prefix, relative_path);
+                        Some((destination_key, entry.value().clone()))
+                    } else {
+                        None
+                    }
+                })
+                .collect();
+
+            for (key, value) in keys_to_copy {
+                self.data.insert(key, value);
+            }
+
+            Ok(())
+        }
+
+        async fn list_documents(&self, prefix: &str) -> Result<Vec<String>> {
+            Ok(self
+                .data
+                .iter()
+                .filter_map(|entry| {
+                    let doc_id = entry.key().strip_suffix("/data.ysweet")?;
+                    doc_id.starts_with(prefix).then(|| doc_id.to_string())
+                })
+                .collect())
+        }
+    }
+
+    #[tokio::test]
+    #[allow(deprecated)] // Keeps the deprecated constructor covered
+    async fn test_auth_doc() {
+        let server_state = Server::new(
+            None,
+            Duration::from_secs(60),
+            None,
+            None,
+            CancellationToken::new(),
+            true,
+            None,
+            false,
+        )
+        .await
+        .unwrap();
+
+        let doc_id = server_state.create_doc().await.unwrap();
+
+        let token = auth_doc(
+            None,
+            TypedHeader(headers::Host::from(http::uri::Authority::from_static(
+                "localhost",
+            ))),
+            None,
+            State(Arc::new(server_state)),
+            Path(doc_id.clone()),
+            Some(Json(AuthDocRequest {
+                authorization: Authorization::Full,
+                user_id: None,
+                valid_for_seconds: None,
+            })),
+        )
+        .await
+        .unwrap();
+
+        let expected_url = format!("ws://localhost/d/{doc_id}/ws");
+        assert_eq!(token.url, expected_url);
+        assert_eq!(token.doc_id, doc_id);
+        assert!(token.token.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_copy_document_with_sync() {
+        let store = TestStore::default();
+        let server_state = Server::builder()
+            .store(Some(Box::new(store.clone())))
+            .checkpoint_freq(Duration::from_secs(60))
+            .build()
+            .await
+            .unwrap();
+
+        // Create a source document
+        let source_doc_id = server_state.create_doc().await.unwrap();
+
+        // Add some data to the document
+        if let Some(doc) = server_state.docs.get(&source_doc_id) {
+            doc.sync_kv().upsert(b"test_key", b"test_value").unwrap();
+        }
+
+        // Test copy operation
+        let destination_doc_id = "test_destination".to_string();
+        let result = copy_document(
+            Path(source_doc_id.clone()),
+            State(Arc::new(server_state)),
+            None,
+            Json(DocCopyRequest {
+                destination_doc_id: destination_doc_id.clone(),
+                dry_run: false,
+            }),
+        )
+        .await;
+
+        assert!(result.is_ok());
+        let response = result.unwrap();
+        assert_eq!(response.source_doc_id, source_doc_id);
+        assert_eq!(response.destination_doc_id, destination_doc_id);
+        assert!(response.success);
+    }
+
+    #[tokio::test]
+    async fn test_delete_document_removes_data_and_assets() {
+        let store = TestStore::default();
+        let server_state = Arc::new(
+            Server::builder()
+                .store(Some(Box::new(store.clone())))
+                .checkpoint_freq(Duration::from_secs(60))
+                .build()
+                .await
+                .unwrap(),
+        );
+
+        let doc_id = server_state.create_doc().await.unwrap();
+
+        if let Some(doc) = server_state.docs.get(&doc_id) {
+            doc.sync_kv().persist().await.unwrap();
+        }
+
+        store.insert(&format!("{}/assets/foo.png", doc_id), b"asset-1".to_vec());
+        store.insert(&format!("{}/assets/bar.jpg", doc_id), b"asset-2".to_vec());
+
+        let response = delete_document(
+            Path(doc_id.clone()),
+            State(server_state.clone()),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert!(response.success);
+        assert!(response.data_deleted);
+        assert_eq!(response.deleted_assets, 2);
+
+        assert!(!store
+            .exists(&format!("{}/data.ysweet", doc_id))
+            .await
+            .unwrap());
+
+        assert!(store
+            .list_objects(&format!("{}/assets/", doc_id))
+            .await
+            .unwrap()
+            .is_empty());
+
+        assert!(server_state.docs.get(&doc_id).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_delete_document_dry_run() {
+        let store = TestStore::default();
+        let server_state = Arc::new(
+            Server::builder()
+                .store(Some(Box::new(store.clone())))
+                .checkpoint_freq(Duration::from_secs(60))
+                .build()
+                .await
+                .unwrap(),
+        );
+
+        let doc_id = server_state.create_doc().await.unwrap();
+
+        if let Some(doc) = server_state.docs.get(&doc_id) {
+            doc.sync_kv().upsert(b"test_key", b"test_value").unwrap();
+            doc.sync_kv().persist().await.unwrap();
+        }
+
+        store.insert(&format!("{}/assets/foo.png", doc_id), b"asset-1".to_vec());
+        store.insert(&format!("{}/assets/bar.jpg", doc_id), b"asset-2".to_vec());
+
+        let response = delete_document(
+            Path(doc_id.clone()),
+            State(server_state.clone()),
+            None,
+            Some(Json(DocDeleteRequest { dry_run: true })),
+        )
+        .await
+        .unwrap();
+        assert!(response.dry_run);
+        assert!(response.data_deleted);
+        assert_eq!(response.deleted_assets, 2);
+        assert_eq!(
+            response.removed_objects.as_deref(),
+            Some(
+                &[
+                    format!("{}/assets/bar.jpg", doc_id),
+                    format!("{}/assets/foo.png", doc_id),
+                    format!("{}/data.ysweet", doc_id),
+                ][..]
+            )
+        );
+        assert!(store
+            .exists(&format!("{}/data.ysweet", doc_id))
+            .await
+            .unwrap());
+        assert!(server_state.docs.get(&doc_id).is_some());
+        assert!(store
+            .exists(&format!("{}/assets/foo.png", doc_id))
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_delete_documents_batch_by_ids_and_prefix() {
+        let store = TestStore::default();
+        let server_state = Arc::new(
+            Server::builder()
+                .store(Some(Box::new(store.clone())))
+                .checkpoint_freq(Duration::from_secs(60))
+                .build()
+                .await
+                .unwrap(),
+        );
+
+        for doc_id in ["batch-a", "batch-b", "keep"] {
+            let doc = server_state.get_or_create_doc(doc_id).await.unwrap();
+            doc.sync_kv().upsert(b"test_key", b"test_value").unwrap();
+            doc.sync_kv().persist().await.unwrap();
+        }
+
+        let response = delete_documents_batch(
+            State(server_state.clone()),
+            None,
+            Json(DocBatchDeleteRequest {
+                doc_ids: vec!["missing".to_string()],
+                prefix: Some("batch-".to_string()),
+                dry_run: false,
+            }),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(response.deleted, 2);
+        assert_eq!(response.failed, 1);
+
+        let missing = response
+            .results
+            .iter()
+            .find(|r| r.doc_id == "missing")
+            .unwrap();
+        assert!(!missing.success);
+        assert!(missing.error.is_some());
+
+        assert!(!store.exists("batch-a/data.ysweet").await.unwrap());
+        assert!(!store.exists("batch-b/data.ysweet").await.unwrap());
+        assert!(store.exists("keep/data.ysweet").await.unwrap());
+        assert!(server_state.docs.get("keep").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_archive_and_unarchive_document() {
+        let store = TestStore::default();
+        let server_state = Arc::new(
+            Server::builder()
+                .store(Some(Box::new(store.clone())))
+                .checkpoint_freq(Duration::from_secs(60))
+                .build()
+                .await
+                .unwrap(),
+        );
+
+        let doc_id = "archived-doc".to_string();
+        server_state
+            .get_or_create_doc(&doc_id)
             .await
-            .map_err(|e| {
-                let error_message = format!("Failed to create doc: {}", e);
-                tracing::error!(
-                    message = %error_message,
-                    event = "doc_creation_failed",
-                    error = %e,
-                    error_debug = ?e,
-                    doc_id = %doc_id
-                );
-                (StatusCode::INTERNAL_SERVER_ERROR, e)
-            })?;
+            .unwrap()
+            .sync_kv()
+            .upsert(b"test_key", b"test_value")
+            .unwrap();
+        store.insert(&format!("{}/assets/foo.png", doc_id), b"asset".to_vec());
 
-        doc_id
-    } else {
-        server_state.create_doc().await.map_err(|d| {
-            let error_message = format!("Failed to create doc: {}", d);
-            tracing::error!(
-                message = %error_message,
-                event = "doc_creation_failed",
-                error = %d,
-                error_debug = ?d
-            );
-            (StatusCode::INTERNAL_SERVER_ERROR, d)
-        })?
-    };
+        let response = archive_document(Path(doc_id.clone()), State(server_state.clone()), None)
+            .await
+            .unwrap();
+        assert!(response.archived);
+        assert_eq!(response.moved_assets, 1);
+        assert!(server_state.docs.get(&doc_id).is_none());
+        assert!(server_state.archives().is_archived(&doc_id));
+        assert!(!store.exists("archived-doc/data.ysweet").await.unwrap());
+        assert!(store
+            .exists(".archive/archived-doc/data.ysweet")
+            .await
+            .unwrap());
+        assert!(store
+            .exists(".archive/archived-doc/assets/foo.png")
+            .await
+            .unwrap());
 
-    Ok(Json(NewDocResponse { doc_id }))
-}
+        // Clients get 410 Gone while the document is archived
+        let err = get_doc_as_update(State(server_state.clone()), Path(doc_id.clone()), None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.0, StatusCode::GONE);
+        // Nor is it loaded by paths that skip that check
+        assert!(server_state.get_or_create_doc(&doc_id).await.is_err());
+        assert!(!store.exists("archived-doc/data.ysweet").await.unwrap());
 
-async fn auth_doc(
-    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
-    TypedHeader(host): TypedHeader<headers::Host>,
-    State(server_state): State<Arc<Server>>,
-    Path(doc_id): Path<String>,
-    body: Option<Json<AuthDocRequest>>,
-) -> Result<Json<ClientToken>, AppError> {
-    server_state.check_auth(auth_header)?;
+        let err = archive_document(Path(doc_id.clone()), State(server_state.clone()), None)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.0, StatusCode::CONFLICT);
 
-    let Json(AuthDocRequest {
-        authorization,
-        valid_for_seconds,
-        ..
-    }) = body.unwrap_or_default();
+        let response = unarchive_document(Path(doc_id.clone()), State(server_state.clone()), None)
+            .await
+            .unwrap();
+        assert!(!response.archived);
+        assert_eq!(response.moved_assets, 1);
+        assert!(!server_state.archives().is_archived(&doc_id));
+        assert!(store.exists("archived-doc/data.ysweet").await.unwrap());
+        assert!(store.exists("archived-doc/assets/foo.png").await.unwrap());
+        assert!(!store
+            .exists(".archive/archived-doc/data.ysweet")
+            .await
+            .unwrap());
 
-    if !server_state.doc_exists(&doc_id).await {
-        Err((StatusCode::NOT_FOUND, anyhow!("Doc {} not found", doc_id)))?;
+        let doc = server_state.get_or_create_doc(&doc_id).await.unwrap();
+        assert_eq!(
+            doc.sync_kv().get(b"test_key").unwrap(),
+            Some(b"test_value".to_vec())
+        );
     }
 
-    let valid_for_seconds = valid_for_seconds.unwrap_or(DEFAULT_EXPIRATION_SECONDS);
-    let expiration_time =
-        ExpirationTimeEpochMillis(current_time_epoch_millis() + valid_for_seconds * 1000);
+    #[tokio::test]
+    async fn test_expired_documents_are_reaped() {
+        let store = TestStore::default();
+        let server_state = Arc::new(
+            Server::builder()
+                .store(Some(Box::new(store.clone())))
+                .checkpoint_freq(Duration::from_secs(60))
+                .build()
+                .await
+                .unwrap(),
+        );
 
-    let token = if let Some(auth) = &server_state.authenticator {
-        let token = auth.gen_doc_token(&doc_id, authorization, expiration_time);
-        Some(token)
-    } else {
-        None
-    };
+        for (doc_id, expires_in_seconds) in [("expired", 0), ("alive", 3600)] {
+            let _ = new_doc(
+                None,
+                State(server_state.clone()),
+                Json(DocCreationRequestExt {
+                    request: DocCreationRequest {
+                        doc_id: Some(doc_id.to_string()),
+                    },
+                    expires_in_seconds: Some(expires_in_seconds),
+                }),
+            )
+            .await
+            .unwrap();
+            let sync_kv = server_state
+                .get_or_create_doc(doc_id)
+                .await
+                .unwrap()
+                .sync_kv();
+            sync_kv.upsert(b"test_key", b"test_value").unwrap();
+            sync_kv.persist().await.unwrap();
+        }
+        store.insert("expired/assets/foo.png", b"asset".to_vec());
 
-    let url = if let Some(url_prefix) = &server_state.url_prefix {
-        let mut url = url_prefix.clone();
-        let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
-        url.set_scheme(scheme).unwrap();
-        url = url.join(&format!("/d/{doc_id}/ws")).unwrap();
-        url.to_string()
-    } else {
-        format!("ws://{host}/d/{doc_id}/ws")
-    };
+        let deleted = crate::ttl_ext::reap_expired_docs(&server_state).await;
+        assert_eq!(deleted, 1);
+
+        assert!(!store.exists("expired/data.ysweet").await.unwrap());
+        assert!(!store.exists("expired/assets/foo.png").await.unwrap());
+        assert!(!store.exists(".ttl/expired").await.unwrap());
+        assert!(server_state.docs.get("expired").is_none());
+
+        assert!(store.exists("alive/data.ysweet").await.unwrap());
+        assert!(crate::ttl_ext::get_doc_expiration(&server_state, "alive")
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_merge_document_combines_content() {
+        use yrs::{GetString, Text, Transact};
+
+        let server_state = Arc::new(
+            Server::builder()
+                .checkpoint_freq(Duration::from_secs(60))
+                .build()
+                .await
+                .unwrap(),
+        );
 
-    let base_url = if let Some(url_prefix) = &server_state.url_prefix {
-        let mut url_prefix = url_prefix.to_string();
-        if !url_prefix.ends_with('/') {
-            url_prefix = format!("{url_prefix}/");
+        for (doc_id, content) in [("original", "from original. "), ("fork", "from fork. ")] {
+            let update = {
+                let doc = yrs::Doc::new();
+                let text = doc.get_or_insert_text("text");
+                let mut txn = doc.transact_mut();
+                text.insert(&mut txn, 0, content);
+                txn.encode_update_v1()
+            };
+            server_state
+                .get_or_create_doc(doc_id)
+                .await
+                .unwrap()
+                .apply_update(&update)
+                .unwrap();
         }
 
-        format!("{url_prefix}d/{doc_id}")
-    } else {
-        format!("http://{host}/d/{doc_id}")
-    };
+        let response = merge_document(
+            Path("original".to_string()),
+            State(server_state.clone()),
+            None,
+            Json(DocMergeRequest {
+                source_doc_id: "fork".to_string(),
+            }),
+        )
+        .await
+        .unwrap();
+        assert!(response.success);
 
-    Ok(Json(ClientToken {
-        url,
-        base_url: Some(base_url),
-        doc_id,
-        token,
-        authorization,
-    }))
-}
+        let read_text = |doc_id: &str| {
+            let awareness = server_state.docs.get(doc_id).unwrap().awareness();
+            let awareness = awareness.read().unwrap();
+            let text = awareness.doc.get_or_insert_text("text");
+            let txn = awareness.doc.transact();
+            text.get_string(&txn)
+        };
 
-pub fn get_token_from_header(
-    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
-) -> Option<String> {
-    if let Some(TypedHeader(headers::Authorization(bearer))) = auth_header {
-        Some(bearer.token().to_string())
-    } else {
-        None
+        let merged = read_text("original");
+        assert!(merged.contains("from original. "));
+        assert!(merged.contains("from fork. "));
+        assert_eq!(read_text("fork"), "from fork. ");
     }
-}
 
-#[derive(Deserialize)]
-struct PlaneVerifiedUserData {
-    authorization: Authorization,
-}
+    #[tokio::test]
+    async fn test_fork_document_records_lineage() {
+        use yrs::{updates::decoder::Decode, StateVector};
 
-pub fn get_authorization_from_plane_header(headers: HeaderMap) -> Result<Authorization, AppError> {
-    if let Some(token) = headers.get(HeaderName::from_static(PLANE_VERIFIED_USER_DATA_HEADER)) {
-        let token_str = token.to_str().map_err(|e| (StatusCode::BAD_REQUEST, e))?;
-        let user_data: PlaneVerifiedUserData =
-            serde_json::from_str(token_str).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
-        Ok(user_data.authorization)
-    } else {
-        Err((StatusCode::UNAUTHORIZED, anyhow!("No token provided.")))?
+        let store = TestStore::default();
+        let server_state = Arc::new(
+            Server::builder()
+                .store(Some(Box::new(store.clone())))
+                .checkpoint_freq(Duration::from_secs(60))
+                .build()
+                .await
+                .unwrap(),
+        );
+
+        let sync_kv = server_state
+            .get_or_create_doc("parent")
+            .await
+            .unwrap()
+            .sync_kv();
+        sync_kv.upsert(b"test_key", b"test_value").unwrap();
+        store.insert("parent/assets/foo.png", b"asset".to_vec());
+
+        let response = fork_document(
+            Path("parent".to_string()),
+            State(server_state.clone()),
+            None,
+            Some(Json(DocForkRequest {
+                destination_doc_id: Some("child".to_string()),
+            })),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.doc_id, "child");
+        assert_eq!(response.lineage.parent_doc_id, "parent");
+
+        let state_vector = data_encoding::BASE64
+            .decode(response.lineage.fork_state_vector.as_bytes())
+            .unwrap();
+        assert!(StateVector::decode_v1(&state_vector).is_ok());
+
+        assert!(store.exists("child/data.ysweet").await.unwrap());
+        assert!(store.exists("child/assets/foo.png").await.unwrap());
+
+        let metadata =
+            get_document_metadata(Path("child".to_string()), State(server_state.clone()), None)
+                .await
+                .unwrap();
+        let lineage = metadata.metadata.lineage.clone().unwrap();
+        assert_eq!(lineage.parent_doc_id, "parent");
+        assert_eq!(
+            lineage.fork_state_vector,
+            response.lineage.fork_state_vector
+        );
+
+        // The parent has no lineage of its own
+        let metadata = get_document_metadata(
+            Path("parent".to_string()),
+            State(server_state.clone()),
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(metadata.metadata.lineage.is_none());
+
+        // Forking onto an existing document is rejected
+        let err = fork_document(
+            Path("parent".to_string()),
+            State(server_state.clone()),
+            None,
+            Some(Json(DocForkRequest {
+                destination_doc_id: Some("child".to_string()),
+            })),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(err.0, StatusCode::CONFLICT);
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::server_ext::{copy_document, delete_document, get_extension_from_content_type};
-    use async_trait::async_trait;
-    use dashmap::DashMap;
-    use std::sync::Arc;
-    use y_sweet_core::api_types::Authorization;
-    use y_sweet_core::api_types_ext::DocCopyRequest;
-    use y_sweet_core::store::{Result, Store};
-    use yrs_kvstore::KVStore;
+    #[tokio::test]
+    async fn test_update_with_expected_state_vector() {
+        use yrs::{updates::encoder::Encode, Text, Transact};
 
-    #[derive(Default, Clone)]
-    struct TestStore {
-        data: Arc<DashMap<String, Vec<u8>>>,
+        let server_state = Arc::new(
+            Server::builder()
+                .checkpoint_freq(Duration::from_secs(60))
+                .build()
+                .await
+                .unwrap(),
+        );
+        server_state.get_or_create_doc("occ").await.unwrap();
+
+        let text_update = |content: &str| {
+            let doc = yrs::Doc::new();
+            let text = doc.get_or_insert_text("text");
+            let mut txn = doc.transact_mut();
+            text.insert(&mut txn, 0, content);
+            Bytes::from(txn.encode_update_v1())
+        };
+        let expecting = |state_vector: &http::HeaderValue| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-expected-state-vector", state_vector.clone());
+            headers
+        };
+
+        let initial = http::HeaderValue::from_str(
+            &data_encoding::BASE64.encode(&StateVector::default().encode_v1()),
+        )
+        .unwrap();
+
+        let response = update_doc(
+            Path("occ".to_string()),
+            State(server_state.clone()),
+            None,
+            expecting(&initial),
+            text_update("first"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let latest = response.headers().get("x-state-vector").unwrap().clone();
+
+        // Based on the initial state, which is no longer current
+        let response = update_doc(
+            Path("occ".to_string()),
+            State(server_state.clone()),
+            None,
+            expecting(&initial),
+            text_update("stale"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(response.headers().get("x-state-vector").unwrap(), latest);
+
+        let response = update_doc(
+            Path("occ".to_string()),
+            State(server_state.clone()),
+            None,
+            expecting(&latest),
+            text_update("second"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
     }
 
-    impl TestStore {
-        fn insert(&self, key: &str, value: Vec<u8>) {
-            self.data.insert(key.to_owned(), value);
-        }
+    #[tokio::test]
+    async fn test_update_validator_denies_updates() {
+        use y_sweet_core::validate_ext::IncomingUpdate;
+        use yrs::{GetString, Text, Transact};
+
+        let server_state = Arc::new(
+            Server::builder()
+                .checkpoint_freq(Duration::from_secs(60))
+                .build()
+                .await
+                .unwrap()
+                .with_update_validator(|incoming: &IncomingUpdate| {
+                    let preview = incoming.preview()?;
+                    let text = preview.get_or_insert_text("text");
+                    anyhow::ensure!(
+                        !text.get_string(&preview.transact()).contains("forbidden"),
+                        "Forbidden content"
+                    );
+                    Ok(())
+                }),
+        );
+        server_state.get_or_create_doc("checked").await.unwrap();
+
+        let text_update = |content: &str| {
+            let doc = yrs::Doc::new();
+            let text = doc.get_or_insert_text("text");
+            let mut txn = doc.transact_mut();
+            text.insert(&mut txn, 0, content);
+            Bytes::from(txn.encode_update_v1())
+        };
+        let update = |content: &str| {
+            update_doc(
+                Path("checked".to_string()),
+                State(server_state.clone()),
+                None,
+                HeaderMap::new(),
+                text_update(content),
+            )
+        };
+
+        let err = update("forbidden").await.err().unwrap();
+        assert_eq!(err.0, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(err.1.to_string().contains("Forbidden content"));
+        update("allowed").await.unwrap();
+
+        let doc = server_state.get_or_create_doc("checked").await.unwrap();
+        let awareness = doc.awareness();
+        let awareness = awareness.read().unwrap();
+        let text = awareness.doc().get_or_insert_text("text");
+        assert_eq!(text.get_string(&awareness.doc().transact()), "allowed");
+    }
+
+    #[tokio::test]
+    async fn test_subdocs_are_synced_and_persisted_separately() {
+        use crate::subdoc_ext::SubdocRouter;
+        use y_sweet_core::subdoc_ext::{decode_subdoc_payload, encode_subdoc_message};
+        use y_sweet_core::sync::{Message as SyncProtocolMessage, SyncMessage};
+        use yrs::{updates::decoder::Decode, updates::encoder::Encode, GetString, Text, Transact};
+
+        let store = TestStore::default();
+        let server_state = Arc::new(
+            Server::builder()
+                .store(Some(Box::new(store.clone())))
+                .checkpoint_freq(Duration::from_secs(60))
+                .build()
+                .await
+                .unwrap(),
+        );
+        server_state.load_doc("board").await.unwrap();
+
+        let update = {
+            let doc = yrs::Doc::new();
+            let text = doc.get_or_insert_text("text");
+            let mut txn = doc.transact_mut();
+            text.insert(&mut txn, 0, "page one");
+            txn.encode_update_v1()
+        };
+        let message = SyncProtocolMessage::Sync(SyncMessage::Update(update)).encode_v1();
+
+        let (send, mut recv) = channel(16);
+        let mut subdocs = SubdocRouter::new(
+            server_state.clone(),
+            "board".to_string(),
+            Authorization::Full,
+        );
+        subdocs
+            .send(&encode_subdoc_message("page-1", &message), &send)
+            .await
+            .unwrap();
+
+        // Replies are wrapped in the envelope of the same subdocument
+        let reply = recv.try_recv().unwrap();
+        let SyncProtocolMessage::Custom(_, payload) =
+            SyncProtocolMessage::decode_v1(&reply).unwrap()
+        else {
+            panic!("expected a subdocument envelope");
+        };
+        assert_eq!(decode_subdoc_payload(&payload).unwrap().0, "page-1");
+
+        let read_text = |doc_id: &str| {
+            let awareness = server_state.docs.get(doc_id).unwrap().awareness();
+            let awareness = awareness.read().unwrap();
+            let text = awareness.doc.get_or_insert_text("text");
+            let txn = awareness.doc.transact();
+            text.get_string(&txn)
+        };
+        assert_eq!(read_text("board/subdocs/page-1"), "page one");
+        assert_eq!(read_text("board"), "");
+
+        server_state
+            .docs
+            .get("board/subdocs/page-1")
+            .unwrap()
+            .sync_kv()
+            .persist()
+            .await
+            .unwrap();
+        assert!(store
+            .exists("board/subdocs/page-1/data.ysweet")
+            .await
+            .unwrap());
+
+        drop(subdocs);
+        let _ = delete_document(
+            Path("board".to_string()),
+            State(server_state.clone()),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(!store
+            .exists("board/subdocs/page-1/data.ysweet")
+            .await
+            .unwrap());
+        assert!(server_state.docs.get("board/subdocs/page-1").is_none());
     }
 
-    #[async_trait]
-    impl Store for TestStore {
-        async fn init(&self) -> Result<()> {
-            Ok(())
-        }
+    #[tokio::test]
+    async fn test_update_log_is_replayed_on_load() {
+        use y_sweet_core::update_log_ext::{list_segments, read_snapshot_meta};
+        use yrs::{GetString, Text, Transact};
 
-        async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
-            Ok(self.data.get(key).map(|v| v.clone()))
-        }
+        let store = TestStore::default();
+        let config = UpdateLogConfig { snapshot_every: 2 };
+        let server = |store: &TestStore| {
+            let store = store.clone();
+            async move {
+                Server::builder()
+                    .store(Some(Box::new(store)))
+                    .checkpoint_freq(Duration::from_secs(60))
+                    .build()
+                    .await
+                    .unwrap()
+                    .with_update_log(config)
+            }
+        };
 
-        async fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
-            self.data.insert(key.to_owned(), value);
-            Ok(())
+        let server_state = server(&store).await;
+        server_state.load_doc("doc").await.unwrap();
+        let sync_kv = server_state.docs.get("doc").unwrap().sync_kv();
+        for word in ["one ", "two ", "three "] {
+            {
+                let doc = server_state.docs.get("doc").unwrap();
+                let awareness = doc.awareness();
+                let awareness = awareness.write().unwrap();
+                let text = awareness.doc().get_or_insert_text("text");
+                let mut txn = awareness.doc().transact_mut();
+                let len = text.len(&txn);
+                text.insert(&mut txn, len, word);
+            }
+            sync_kv.persist().await.unwrap();
         }
 
-        async fn remove(&self, key: &str) -> Result<()> {
-            self.data.remove(key);
-            Ok(())
-        }
+        // Three segments, the second of which triggered a snapshot
+        assert_eq!(list_segments(&store, "doc").await.unwrap(), vec![0, 1, 2]);
+        assert_eq!(
+            read_snapshot_meta(&store, "doc")
+                .await
+                .unwrap()
+                .snapshot_seq,
+            Some(1)
+        );
 
-        async fn exists(&self, key: &str) -> Result<bool> {
-            Ok(self.data.contains_key(key))
-        }
+        let reloaded = server(&store).await;
+        reloaded.load_doc("doc").await.unwrap();
+        let doc = reloaded.docs.get("doc").unwrap();
+        let awareness = doc.awareness();
+        let awareness = awareness.read().unwrap();
+        let text = awareness.doc().get_or_insert_text("text");
+        assert_eq!(
+            text.get_string(&awareness.doc().transact()),
+            "one two three "
+        );
+    }
 
-        async fn generate_upload_presigned_url(
-            &self,
-            key: &str,
-            _content_type: &str,
-        ) -> Result<String> {
-            Ok(format!("test://localhost/{}", key))
-        }
+    #[tokio::test]
+    async fn test_checkpoint_triggered_by_pending_updates() {
+        use yrs::{Text, Transact};
 
-        async fn generate_download_presigned_url(&self, key: &str) -> Result<String> {
-            Ok(format!("test://localhost/{}", key))
+        let store = TestStore::default();
+        let server_state = Server::builder()
+            .store(Some(Box::new(store.clone())))
+            .checkpoint_freq(Duration::from_secs(60))
+            .build()
+            .await
+            .unwrap()
+            .with_checkpoint_triggers(CheckpointTriggers {
+                max_pending_updates: Some(3),
+                max_pending_bytes: None,
+            });
+        server_state.load_doc("doc").await.unwrap();
+
+        let snapshot = || store.data.get("doc/data.ysweet").map(|v| v.clone());
+        let before = snapshot();
+        for i in 0..3 {
+            if i == 2 {
+                // Within the checkpoint interval, nothing is persisted before a trigger
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                assert_eq!(snapshot(), before);
+            }
+            let doc = server_state.docs.get("doc").unwrap();
+            let awareness = doc.awareness();
+            let awareness = awareness.write().unwrap();
+            let text = awareness.doc().get_or_insert_text("text");
+            text.insert(&mut awareness.doc().transact_mut(), 0, "x");
         }
 
-        async fn list_objects(&self, prefix: &str) -> Result<Vec<String>> {
-            let mut objects = Vec::new();
-            for entry in self.data.iter() {
-                let key = entry.key();
-                if key.starts_with(prefix) {
-                    let relative_key = &key[prefix.len()..];
-                    if !relative_key.is_empty() {
-                        objects.push(relative_key.to_string());
-                    }
-                }
+        for _ in 0..50 {
+            if snapshot() != before {
+                return;
             }
-            Ok(objects)
+            tokio::time::sleep(Duration::from_millis(20)).await;
         }
+        panic!("Document was not persisted after reaching the checkpoint trigger");
+    }
 
-        async fn copy_document(&self, source_doc_id: &str, destination_doc_id: &str) -> Result<()> {
-            let source_prefix = format!("{}/", source_doc_id);
-            let destination_prefix = format!("{}/", destination_doc_id);
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_updates_are_persisted() {
+        use yrs::{GetString, Text, Transact};
 
-            let keys_to_copy: Vec<(String, Vec<u8>)> = self
-                .data
-                .iter()
-                .filter_map(|entry| {
-                    let key = entry.key();
-                    if key.starts_with(&source_prefix) {
-                        let relative_path = &key[source_prefix.len()..];
-                        let destination_key = format!("{}{}", destination_prefix, relative_path);
-                        Some((destination_key, entry.value().clone()))
-                    } else {
-                        None
+        let store = TestStore::default();
+        let server_state = Server::builder()
+            .store(Some(Box::new(store.clone())))
+            .checkpoint_freq(Duration::from_millis(10))
+            .build()
+            .await
+            .unwrap();
+        server_state.load_doc("doc").await.unwrap();
+
+        // Far more changes than the persistence worker can keep up with one by one
+        let awareness = server_state.docs.get("doc").unwrap().awareness();
+        let writers: Vec<_> = (0..8)
+            .map(|_| {
+                let awareness = awareness.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..200 {
+                        let awareness = awareness.write().unwrap();
+                        let text = awareness.doc().get_or_insert_text("text");
+                        text.insert(&mut awareness.doc().transact_mut(), 0, "x");
                     }
                 })
-                .collect();
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
 
-            for (key, value) in keys_to_copy {
-                self.data.insert(key, value);
+        let persisted_len = || async {
+            let store: Arc<Box<dyn Store>> = Arc::new(Box::new(store.clone()));
+            let doc = DocWithSyncKv::new("doc", Some(store), || {}, false)
+                .await
+                .unwrap();
+            let awareness = doc.awareness();
+            let awareness = awareness.read().unwrap();
+            let text = awareness.doc().get_or_insert_text("text");
+            let len = text.get_string(&awareness.doc().transact()).len();
+            len
+        };
+        for _ in 0..100 {
+            if persisted_len().await == 1_600 {
+                return;
             }
-
-            Ok(())
+            tokio::time::sleep(Duration::from_millis(20)).await;
         }
+        panic!("Concurrent updates were not all persisted");
     }
 
+    #[cfg(feature = "assets")]
     #[tokio::test]
-    async fn test_auth_doc() {
-        let server_state = Server::new(
-            None,
-            Duration::from_secs(60),
-            None,
-            None,
-            CancellationToken::new(),
-            true,
-            None,
-            false,
-        )
-        .await
-        .unwrap();
+    async fn test_upload_is_deduplicated_by_sha256() {
+        let store = TestStore::default();
+        let server_state = Arc::new(
+            Server::builder()
+                .store(Some(Box::new(store.clone())))
+                .checkpoint_freq(Duration::from_secs(60))
+                .build()
+                .await
+                .unwrap(),
+        );
+        let doc_id = server_state.create_doc().await.unwrap();
+
+        let existing = "a".repeat(64);
+        store.insert(
+            &format!("{}/assets/{}.png", doc_id, existing),
+            b"screenshot".to_vec(),
+        );
+
+        let upload = |sha256: Option<String>| {
+            generate_upload_presigned_url(
+                Path(doc_id.clone()),
+                State(server_state.clone()),
+                None,
+                Json(ContentUploadRequest {
+                    content_type: "image/png".to_string(),
+                    sha256,
+                    content_length: None,
+                    filename: None,
+                }),
+            )
+        };
+
+        let response = upload(Some("A".repeat(64))).await.unwrap();
+        assert!(response.deduplicated);
+        assert!(response.upload_url.is_none());
+        assert_eq!(response.asset_id, format!("{}.png", existing));
+
+        let new = "b".repeat(64);
+        let response = upload(Some(new.clone())).await.unwrap();
+        assert!(!response.deduplicated);
+        assert!(response.upload_url.is_some());
+        assert_eq!(response.asset_id, format!("{}.png", new));
+
+        let response = upload(None).await.unwrap();
+        assert!(!response.deduplicated);
+        assert_ne!(response.asset_id, format!("{}.png", existing));
+
+        let error = upload(Some("not-a-hash".to_string())).await.err().unwrap();
+        assert_eq!(error.0, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_auth_doc_with_prefix() {
+        let prefix: Url = "https://foo.bar".parse().unwrap();
+        let server_state = Server::builder()
+            .checkpoint_freq(Duration::from_secs(60))
+            .url_prefix(Some(prefix))
+            .build()
+            .await
+            .unwrap();
 
         let doc_id = server_state.create_doc().await.unwrap();
 
@@ -1231,151 +4128,179 @@ mod test {
             TypedHeader(headers::Host::from(http::uri::Authority::from_static(
                 "localhost",
             ))),
+            None,
             State(Arc::new(server_state)),
             Path(doc_id.clone()),
-            Some(Json(AuthDocRequest {
-                authorization: Authorization::Full,
-                user_id: None,
-                valid_for_seconds: None,
-            })),
+            None,
         )
         .await
         .unwrap();
 
-        let expected_url = format!("ws://localhost/d/{doc_id}/ws");
+        let expected_url = format!("wss://foo.bar/d/{doc_id}/ws");
         assert_eq!(token.url, expected_url);
         assert_eq!(token.doc_id, doc_id);
         assert!(token.token.is_none());
     }
 
     #[tokio::test]
-    async fn test_copy_document_with_sync() {
-        let store = TestStore::default();
-        let server_state = Server::new(
-            Some(Box::new(store.clone())),
-            Duration::from_secs(60),
+    async fn test_routes_under_base_path() {
+        use tower::ServiceExt;
+
+        let server_state = Arc::new(
+            Server::builder()
+                .checkpoint_freq(Duration::from_secs(60))
+                .url_prefix(Some("https://foo.bar/app/".parse().unwrap()))
+                .base_path(Some("/collab".to_string()))
+                .build()
+                .await
+                .unwrap(),
+        );
+        let doc_id = server_state.create_doc().await.unwrap();
+
+        let status = |uri: String| {
+            let routes = server_state.routes();
+            async move {
+                let request = http::Request::builder()
+                    .uri(uri)
+                    .body(axum::body::Body::empty())
+                    .unwrap();
+                routes.oneshot(request).await.unwrap().status()
+            }
+        };
+        assert_eq!(status("/collab/ready".into()).await, StatusCode::OK);
+        assert_eq!(
+            status(format!("/collab/d/{doc_id}/as-update")).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(format!("/d/{doc_id}/as-update")).await,
+            StatusCode::NOT_FOUND
+        );
+
+        let token = auth_doc(
             None,
+            TypedHeader(headers::Host::from(http::uri::Authority::from_static(
+                "localhost",
+            ))),
             None,
-            CancellationToken::new(),
-            true,
+            State(server_state.clone()),
+            Path(doc_id.clone()),
             None,
-            false,
         )
         .await
         .unwrap();
-
-        // Create a source document
-        let source_doc_id = server_state.create_doc().await.unwrap();
-
-        // Add some data to the document
-        if let Some(doc) = server_state.docs.get(&source_doc_id) {
-            doc.sync_kv().upsert(b"test_key", b"test_value").unwrap();
-        }
-
-        // Test copy operation
-        let destination_doc_id = "test_destination".to_string();
-        let result = copy_document(
-            Path(source_doc_id.clone()),
-            State(Arc::new(server_state)),
-            None,
-            Json(DocCopyRequest {
-                destination_doc_id: destination_doc_id.clone(),
-            }),
-        )
-        .await;
-
-        assert!(result.is_ok());
-        let response = result.unwrap();
-        assert_eq!(response.source_doc_id, source_doc_id);
-        assert_eq!(response.destination_doc_id, destination_doc_id);
-        assert!(response.success);
+        assert_eq!(token.url, format!("wss://foo.bar/app/collab/d/{doc_id}/ws"));
+        assert_eq!(
+            token.base_url.as_deref(),
+            Some(format!("https://foo.bar/app/collab/d/{doc_id}").as_str())
+        );
     }
 
-    #[tokio::test]
-    async fn test_delete_document_removes_data_and_assets() {
-        let store = TestStore::default();
+    async fn get_as_update_encoding(compression: bool) -> Option<http::HeaderValue> {
+        use tower::ServiceExt;
+        use yrs::{Text, Transact};
+
         let server_state = Arc::new(
-            Server::new(
-                Some(Box::new(store.clone())),
-                Duration::from_secs(60),
-                None,
-                None,
-                CancellationToken::new(),
-                true,
-                None,
-                false,
-            )
-            .await
-            .unwrap(),
+            Server::builder()
+                .checkpoint_freq(Duration::from_secs(60))
+                .build()
+                .await
+                .unwrap()
+                .with_compression(compression),
         );
 
         let doc_id = server_state.create_doc().await.unwrap();
-
-        if let Some(doc) = server_state.docs.get(&doc_id) {
-            doc.sync_kv().persist().await.unwrap();
-        }
-
-        store.insert(&format!("{}/assets/foo.png", doc_id), b"asset-1".to_vec());
-        store.insert(&format!("{}/assets/bar.jpg", doc_id), b"asset-2".to_vec());
-
-        let response = delete_document(Path(doc_id.clone()), State(server_state.clone()), None)
+        let update = {
+            let doc = yrs::Doc::new();
+            let text = doc.get_or_insert_text("text");
+            let mut txn = doc.transact_mut();
+            text.insert(&mut txn, 0, &"compressible content ".repeat(32));
+            txn.encode_update_v1()
+        };
+        server_state
+            .get_or_create_doc(&doc_id)
             .await
+            .unwrap()
+            .apply_update(&update)
             .unwrap();
 
-        assert!(response.success);
-        assert!(response.data_deleted);
-        assert_eq!(response.deleted_assets, 2);
-
-        assert!(!store
-            .exists(&format!("{}/data.ysweet", doc_id))
+        let response = server_state
+            .routes()
+            .oneshot(
+                http::Request::builder()
+                    .uri(format!("/d/{doc_id}/as-update"))
+                    .header(http::header::ACCEPT_ENCODING, "gzip")
+                    .body(axum::body::Body::empty())
+                    .unwrap(),
+            )
             .await
-            .unwrap());
+            .unwrap();
 
-        assert!(store
-            .list_objects(&format!("{}/assets/", doc_id))
-            .await
-            .unwrap()
-            .is_empty());
+        assert_eq!(response.status(), StatusCode::OK);
+        response
+            .headers()
+            .get(http::header::CONTENT_ENCODING)
+            .cloned()
+    }
 
-        assert!(server_state.docs.get(&doc_id).is_none());
+    #[tokio::test]
+    async fn test_as_update_compression() {
+        assert_eq!(
+            get_as_update_encoding(true).await,
+            Some(http::HeaderValue::from_static("gzip"))
+        );
+        assert_eq!(get_as_update_encoding(false).await, None);
     }
 
     #[tokio::test]
-    async fn test_auth_doc_with_prefix() {
-        let prefix: Url = "https://foo.bar".parse().unwrap();
-        let server_state = Server::new(
-            None,
-            Duration::from_secs(60),
-            None,
-            Some(prefix),
-            CancellationToken::new(),
-            true,
-            None,
-            false,
-        )
-        .await
-        .unwrap();
+    async fn test_admin_routes_are_split_from_public_routes() {
+        use tower::ServiceExt;
 
+        let server_state = Arc::new(
+            Server::builder()
+                .checkpoint_freq(Duration::from_secs(60))
+                .build()
+                .await
+                .unwrap(),
+        );
         let doc_id = server_state.create_doc().await.unwrap();
 
-        let token = auth_doc(
-            None,
-            TypedHeader(headers::Host::from(http::uri::Authority::from_static(
-                "localhost",
-            ))),
-            State(Arc::new(server_state)),
-            Path(doc_id.clone()),
-            None,
-        )
-        .await
-        .unwrap();
+        let request = |method: &str, uri: String| {
+            http::Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(axum::body::Body::empty())
+                .unwrap()
+        };
 
-        let expected_url = format!("wss://foo.bar/d/{doc_id}/ws");
-        assert_eq!(token.url, expected_url);
-        assert_eq!(token.doc_id, doc_id);
-        assert!(token.token.is_none());
+        let public = server_state.public_routes();
+        let response = public
+            .clone()
+            .oneshot(request("GET", format!("/d/{doc_id}/as-update")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = public
+            .oneshot(request("POST", "/doc/new".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let admin = server_state.admin_routes();
+        let response = admin
+            .clone()
+            .oneshot(request("GET", format!("/d/{doc_id}/as-update")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = admin
+            .oneshot(request("DELETE", format!("/d/{doc_id}")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
     }
 
+    #[cfg(feature = "assets")]
     #[test]
     fn test_get_extension_from_content_type() {
         // Test with actual extensions returned by mime_guess
@@ -1391,4 +4316,14 @@ mod test {
 
         assert_eq!(get_extension_from_content_type("invalid/type"), ".bin");
     }
+
+    #[test]
+    fn test_shutdown_close_frame() {
+        let frame = shutdown_close_frame(Some(Duration::from_secs(3)));
+        assert_eq!(frame.code, close_code::RESTART);
+        assert_eq!(frame.reason, "retry-after=3");
+
+        let frame = shutdown_close_frame(None);
+        assert_eq!(frame.code, close_code::RESTART);
+    }
 }
@@ -1,27 +1,164 @@
 use anyhow::anyhow;
+#[cfg(feature = "assets")]
+use axum::http::HeaderValue;
 use axum::{
-    extract::{Path, State},
-    http::{header::HeaderMap, HeaderValue, StatusCode},
-    response::IntoResponse,
-    routing::{delete, get, post},
+    body::{Body, Bytes},
+    extract::{Path, Query, State},
+    http::{header::HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post, put},
     Json, Router,
 };
 use axum_extra::typed_header::TypedHeader;
+#[cfg(feature = "assets")]
 use cuid::cuid2;
-use std::sync::Arc;
-use tracing::{error, info};
+use dashmap::DashSet;
+use futures::StreamExt;
+use serde::Deserialize;
+use serde_json::json;
+use std::{io::Write, sync::Arc};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tower_http::compression::CompressionLayer;
+use tracing::{error, info, warn};
+#[cfg(feature = "assets")]
+use y_sweet_core::api_types_ext::{
+    AssetUrl, AssetsResponse, ContentUploadRequest, ContentUploadResponse,
+};
 use y_sweet_core::{
     api_types::validate_doc_name,
     api_types_ext::{
-        AssetUrl, AssetsResponse, ContentUploadRequest, ContentUploadResponse, DocCopyRequest,
-        DocCopyResponse, DocDeleteResponse,
+        DocArchiveResponse, DocBatchDeleteRequest, DocBatchDeleteResponse, DocBatchDeleteResult,
+        DocBatchVerifyRequest, DocBatchVerifyResponse, DocCompactRequest, DocCompactResponse,
+        DocCopyRequest, DocCopyResponse, DocDeleteRequest, DocDeleteResponse, DocForkRequest,
+        DocForkResponse, DocFreezeResponse, DocGcRequest, DocGcResponse, DocLineage,
+        DocMergeRequest, DocMergeResponse, DocMetadata, DocMetadataResponse, DocPinResponse,
+        DocRestoreRequest, DocRestoreResponse, DocTtlRequest, DocTtlResponse,
+        DocUpdateConflictResponse, DocVerifyResult, FailingDoc, HealthResponse,
     },
-    store::StoreError,
+    doc_sync_ext::DocWithSyncKv,
+    shard_ext::{remove_shards, SHARDS_DIR},
+    snapshot_ext::{snapshot_backup_key, snapshot_key},
+    store::{Store, StoreError},
+    update_log_ext::{remove_update_log, RestorePoint, UPDATES_DIR},
+    validate_ext::validate_update,
+};
+#[cfg(feature = "assets")]
+use y_sweet_core::{api_types::Authorization, asset_event_ext::encode_asset_deleted_message};
+use yrs::{
+    updates::{decoder::Decode, encoder::Encode},
+    ReadTxn, StateVector, Transact, Update,
+};
+
+#[cfg(feature = "assets")]
+use crate::asset_filename_ext::{
+    asset_filename_key, content_disposition, read_asset_filename, write_asset_filename,
 };
+#[cfg(feature = "assets")]
+use crate::asset_hook_ext::{asset_status_key, read_asset_status};
+use crate::audit_ext::{record_audit, remove_copied_audit, AuditPrincipal};
+use crate::body_limits_ext::{limit_body, BodyKind};
+use crate::cluster_ext::{check_local, is_local};
+use crate::compaction_ext::{compact_doc, plan_compaction};
+use crate::convert::{
+    delta_to_update, json_to_update, prosemirror_to_update, write_delta, write_prosemirror,
+    write_text, TextFormat, DEFAULT_TEXT_ROOT,
+};
+use crate::freeze_ext::{is_doc_frozen, set_doc_frozen};
+use crate::gc_ext::collect_doc_garbage;
+use crate::html_ext::write_html;
+use crate::labels_ext::{labels_key, remove_doc_labels};
+use crate::metadata_ext::{
+    metadata_key, read_doc_metadata, remove_doc_metadata, write_doc_metadata,
+};
+use crate::publish_ext::{remove_published, PUBLISHED_DIR};
+#[cfg(feature = "assets")]
+use crate::quota_ext::ext_check_asset_quota;
+use crate::quota_ext::{ext_check_doc_quota, ext_count_doc};
+#[cfg(feature = "config-files")]
+use crate::reload_ext::reload_config;
+use crate::restore_ext::restore_doc;
+use crate::search_ext::remove_from_search_index;
+use crate::server::{
+    current_time_epoch_millis, get_authorization_from_plane_header, get_token_from_header,
+    update_doc_inner, AppError, Server,
+};
+use crate::store_copy_ext::list_doc_objects;
+use crate::subdoc_ext::{list_subdoc_objects, remove_subdoc_objects, unload_subdocs};
+use crate::ttl_ext::{clear_doc_expiration, get_doc_expiration, set_doc_expiration, ttl_key};
+use crate::verify_ext::{verify_doc, verify_docs};
+#[cfg(feature = "config-files")]
+use y_sweet_core::api_types_ext::ConfigReloadResponse;
+
+/// Request header carrying the base64 encoded state vector an update was based on
+pub const EXPECTED_STATE_VECTOR_HEADER: &str = "x-expected-state-vector";
+/// Response header carrying the base64 encoded state vector of the document
+pub const STATE_VECTOR_HEADER: &str = "x-state-vector";
+
+/// Maximum number of documents deleted concurrently by the batch delete endpoint
+const BATCH_DELETE_CONCURRENCY: usize = 8;
+
+/// Parse the expected state vector of an optimistic-concurrency update, if one was sent
+pub fn ext_expected_state_vector(headers: &HeaderMap) -> Result<Option<StateVector>, AppError> {
+    let Some(value) = headers.get(EXPECTED_STATE_VECTOR_HEADER) else {
+        return Ok(None);
+    };
+
+    let state_vector = data_encoding::BASE64
+        .decode(value.as_bytes())
+        .ok()
+        .and_then(|bytes| StateVector::decode_v1(&bytes).ok())
+        .ok_or_else(|| {
+            AppError(
+                StatusCode::BAD_REQUEST,
+                anyhow!("Invalid {} header", EXPECTED_STATE_VECTOR_HEADER),
+            )
+        })?;
+
+    Ok(Some(state_vector))
+}
+
+fn encode_state_vector(state_vector: &StateVector) -> String {
+    data_encoding::BASE64.encode(&state_vector.encode_v1())
+}
+
+/// Apply an update only if the document is still at the expected state vector.
+/// Otherwise respond with 409 Conflict and the current state vector, so the caller can
+/// rebase its change on the latest state and retry.
+pub fn ext_apply_update_if_current(
+    dwskv: &DocWithSyncKv,
+    update: &[u8],
+    expected_state_vector: &StateVector,
+) -> Result<Response, AppError> {
+    let update = Update::decode_v1(update)
+        .map_err(|_| AppError(StatusCode::BAD_REQUEST, anyhow!("Failed to decode update")))?;
+
+    let awareness = dwskv.awareness();
+    // Hold the write lock so no other update can land between the check and the apply
+    let awareness = awareness.write().unwrap();
+
+    let current_state_vector = awareness.doc.transact().state_vector();
+    if current_state_vector != *expected_state_vector {
+        let state_vector = encode_state_vector(&current_state_vector);
+        return Ok((
+            StatusCode::CONFLICT,
+            [(STATE_VECTOR_HEADER, state_vector.clone())],
+            Json(DocUpdateConflictResponse { state_vector }),
+        )
+            .into_response());
+    }
+
+    {
+        let mut txn = awareness.doc.transact_mut();
+        txn.apply_update(update);
+    }
 
-use crate::server::{get_authorization_from_plane_header, get_token_from_header, AppError, Server};
+    let state_vector = encode_state_vector(&awareness.doc.transact().state_vector());
+    Ok((StatusCode::OK, [(STATE_VECTOR_HEADER, state_vector)]).into_response())
+}
 
 /// Check if the content type is allowed (only images and videos)
+#[cfg(feature = "assets")]
 pub fn is_allowed_content_type(content_type: &str) -> bool {
     let mime = match content_type.parse::<mime::Mime>() {
         Ok(m) => m,
@@ -34,6 +171,7 @@ pub fn is_allowed_content_type(content_type: &str) -> bool {
 }
 
 /// Get file extension from content type
+#[cfg(feature = "assets")]
 pub fn get_extension_from_content_type(content_type: &str) -> String {
     let mime = content_type
         .parse::<mime::Mime>()
@@ -44,8 +182,27 @@ pub fn get_extension_from_content_type(content_type: &str) -> String {
     format!(".{}", extension)
 }
 
+/// Directory under `{doc_id}/assets/` holding the thumbnails generated from the assets
+pub(crate) const THUMBS_DIR: &str = "thumbs";
+
+/// Directory under `{doc_id}/assets/` holding the status of the processing of the assets
+pub(crate) const STATUS_DIR: &str = "status";
+
+/// Directory under `{doc_id}/assets/` holding the original filenames of the assets
+pub(crate) const FILENAMES_DIR: &str = "filenames";
+
+/// Directories under `{doc_id}/assets/` holding what the server keeps about the assets
+const ASSET_SUBDIRS: [&str; 3] = [THUMBS_DIR, STATUS_DIR, FILENAMES_DIR];
+
+/// Whether `name`, listed under `{doc_id}/assets/`, is an asset rather than a thumbnail,
+/// status or filename
+pub(crate) fn is_asset_name(name: &str) -> bool {
+    !name.contains('/') && !ASSET_SUBDIRS.contains(&name)
+}
+
 /// Extract asset ID from filename (without extension)
-fn extract_asset_id_from_filename(filename: &str) -> Option<String> {
+#[cfg(feature = "assets")]
+pub(crate) fn extract_asset_id_from_filename(filename: &str) -> Option<String> {
     // Find the last dot to separate asset_id and extension
     if let Some(last_dot_pos) = filename.rfind('.') {
         if last_dot_pos > 0 {
@@ -56,15 +213,76 @@ fn extract_asset_id_from_filename(filename: &str) -> Option<String> {
     Some(filename.to_string())
 }
 
+/// Name (with its extension) of the asset of `doc_id` with ID `asset_id`, which may be given
+/// with or without its extension
+#[cfg(feature = "assets")]
+pub(crate) async fn find_asset_name(
+    store: &Arc<Box<dyn Store>>,
+    doc_id: &str,
+    asset_id: &str,
+) -> Result<String, AppError> {
+    let asset_names = store
+        .list_objects(&format!("{}/assets/", doc_id))
+        .await
+        .map_err(|e| {
+            AppError(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                anyhow!("Failed to list assets: {}", e),
+            )
+        })?;
+    asset_names
+        .into_iter()
+        .filter(|name| is_asset_name(name))
+        .find(|name| {
+            name == asset_id || extract_asset_id_from_filename(name).as_deref() == Some(asset_id)
+        })
+        .ok_or_else(|| {
+            AppError(
+                StatusCode::NOT_FOUND,
+                anyhow!("Asset {} not found", asset_id),
+            )
+        })
+}
+
+/// Validate a client supplied SHA-256 (64 hex digits) and normalize it to lowercase
+#[cfg(feature = "assets")]
+fn normalize_sha256(sha256: &str) -> Result<String, AppError> {
+    if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(AppError(
+            StatusCode::BAD_REQUEST,
+            anyhow!("sha256 must be 64 hex digits"),
+        ));
+    }
+    Ok(sha256.to_ascii_lowercase())
+}
+
+/// Check whether an asset object already exists in the store
+#[cfg(feature = "assets")]
+async fn asset_exists(server_state: &Server, key: &str) -> Result<bool, AppError> {
+    let Some(store) = &server_state.store else {
+        return Ok(false);
+    };
+
+    store.exists(key).await.map_err(|e| {
+        AppError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            anyhow!("Failed to check for existing asset: {}", e),
+        )
+    })
+}
+
 /// Generate presigned URL for uploading content
-async fn generate_upload_presigned_url(
+#[cfg(feature = "assets")]
+pub async fn generate_upload_presigned_url(
     Path(doc_id): Path<String>,
     State(server_state): State<Arc<Server>>,
     auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
     Json(body): Json<ContentUploadRequest>,
 ) -> Result<Json<ContentUploadResponse>, AppError> {
     let token = get_token_from_header(auth_header);
-    let _ = server_state.verify_doc_token(token.as_deref(), &doc_id)?;
+    let authorization = server_state.verify_doc_token(token.as_deref(), &doc_id)?;
+
+    ext_check_not_archived(&server_state, &doc_id).await?;
 
     // Check if document exists
     if !server_state.doc_exists(&doc_id).await {
@@ -82,14 +300,39 @@ async fn generate_upload_presigned_url(
         ))?;
     }
 
-    // Generate asset ID with cuid and extension
-    let asset_id = cuid2();
+    // Generate asset ID with cuid (or the content hash) and extension
+    let asset_id = match &body.sha256 {
+        Some(sha256) => normalize_sha256(sha256)?,
+        None => cuid2(),
+    };
     let extension = get_extension_from_content_type(&body.content_type);
     let asset_name = format!("{}{}", asset_id, extension);
 
     // Create the key path: {doc_id}/assets/{asset_name}
     let key = format!("{}/assets/{}", doc_id, asset_name);
 
+    if body.sha256.is_some() && asset_exists(&server_state, &key).await? {
+        if let Some(filename) = &body.filename {
+            write_asset_filename(&server_state, &doc_id, &asset_name, filename).await?;
+        }
+        info!(
+            message = "Asset upload deduplicated",
+            event = "asset_deduplicated",
+            doc_id = %doc_id,
+            asset_id = %asset_name
+        );
+        return Ok(Json(ContentUploadResponse {
+            upload_url: None,
+            asset_id: asset_name,
+            deduplicated: true,
+        }));
+    }
+
+    ext_check_asset_quota(&server_state, &doc_id, body.content_length)?;
+    if let Some(filename) = &body.filename {
+        write_asset_filename(&server_state, &doc_id, &asset_name, filename).await?;
+    }
+
     let upload_url = if let Some(store) = &server_state.store {
         store
             .generate_upload_presigned_url(&key, &body.content_type)
@@ -104,14 +347,24 @@ async fn generate_upload_presigned_url(
         // For local development without store, return a dummy URL
         format!("file://localhost/{}", key)
     };
+    record_audit(
+        &server_state,
+        &doc_id,
+        "asset_upload_url_issued",
+        AuditPrincipal::doc(token.as_deref(), authorization),
+        Some(json!({ "assetId": asset_name, "contentType": body.content_type })),
+    )
+    .await;
 
     Ok(Json(ContentUploadResponse {
-        upload_url,
+        upload_url: Some(upload_url),
         asset_id: asset_name,
+        deduplicated: false,
     }))
 }
 
 /// Generate presigned URL for uploading content (single doc mode)
+#[cfg(feature = "assets")]
 async fn generate_upload_presigned_url_single(
     State(server_state): State<Arc<Server>>,
     headers: HeaderMap,
@@ -134,14 +387,39 @@ async fn generate_upload_presigned_url_single(
         ))?;
     }
 
-    // Generate asset ID with cuid and extension
-    let asset_id = cuid2();
+    // Generate asset ID with cuid (or the content hash) and extension
+    let asset_id = match &body.sha256 {
+        Some(sha256) => normalize_sha256(sha256)?,
+        None => cuid2(),
+    };
     let extension = get_extension_from_content_type(&body.content_type);
     let asset_name = format!("{}{}", asset_id, extension);
 
     // Create the key path: {doc_id}/assets/{asset_name}
     let key = format!("{}/assets/{}", doc_id, asset_name);
 
+    if body.sha256.is_some() && asset_exists(&server_state, &key).await? {
+        if let Some(filename) = &body.filename {
+            write_asset_filename(&server_state, &doc_id, &asset_name, filename).await?;
+        }
+        info!(
+            message = "Asset upload deduplicated",
+            event = "asset_deduplicated",
+            doc_id = %doc_id,
+            asset_id = %asset_name
+        );
+        return Ok(Json(ContentUploadResponse {
+            upload_url: None,
+            asset_id: asset_name,
+            deduplicated: true,
+        }));
+    }
+
+    ext_check_asset_quota(&server_state, &doc_id, body.content_length)?;
+    if let Some(filename) = &body.filename {
+        write_asset_filename(&server_state, &doc_id, &asset_name, filename).await?;
+    }
+
     let upload_url = if let Some(store) = &server_state.store {
         store
             .generate_upload_presigned_url(&key, &body.content_type)
@@ -158,25 +436,18 @@ async fn generate_upload_presigned_url_single(
     };
 
     Ok(Json(ContentUploadResponse {
-        upload_url,
+        upload_url: Some(upload_url),
         asset_id: asset_name,
+        deduplicated: false,
     }))
 }
 
-/// Get all assets for a document with presigned download URLs
-async fn get_doc_assets(
-    Path(doc_id): Path<String>,
-    State(server_state): State<Arc<Server>>,
-    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
-) -> Result<impl IntoResponse, AppError> {
-    let token = get_token_from_header(auth_header);
-    let _ = server_state.verify_doc_token(token.as_deref(), &doc_id)?;
-
-    // Check if document exists
-    if !server_state.doc_exists(&doc_id).await {
-        Err((StatusCode::NOT_FOUND, anyhow!("Doc {} not found", doc_id)))?;
-    }
-
+/// List the assets of a document with presigned download URLs
+#[cfg(feature = "assets")]
+pub(crate) async fn list_doc_assets(
+    server_state: &Server,
+    doc_id: &str,
+) -> Result<Vec<AssetUrl>, AppError> {
     let assets = if let Some(store) = &server_state.store {
         // List assets in the assets directory
         let assets_prefix = format!("{}/assets/", doc_id);
@@ -189,28 +460,39 @@ async fn get_doc_assets(
 
         // Generate signed URLs for each asset
         let mut asset_urls = Vec::new();
-        for filename in asset_names {
+        for filename in asset_names.into_iter().filter(|name| is_asset_name(name)) {
             // Extract asset_id from filename (remove extension)
             if let Some(asset_id) = extract_asset_id_from_filename(&filename) {
                 let key = format!("{}/assets/{}", doc_id, filename);
-                let download_url =
-                    store
-                        .generate_download_presigned_url(&key)
-                        .await
-                        .map_err(|e| {
-                            (
-                                StatusCode::INTERNAL_SERVER_ERROR,
-                                anyhow!(
-                                    "Failed to generate download URL for {}: {:?}",
-                                    filename,
-                                    e
-                                ),
+                let original_filename = read_asset_filename(store, doc_id, &filename).await;
+                let download_url = match &original_filename {
+                    Some(original_filename) => {
+                        store
+                            .generate_download_presigned_url_with_disposition(
+                                &key,
+                                &content_disposition(original_filename),
                             )
-                        })?;
+                            .await
+                    }
+                    None => store.generate_download_presigned_url(&key).await,
+                };
+                let download_url = download_url.map_err(|e| {
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        anyhow!("Failed to generate download URL for {}: {:?}", filename, e),
+                    )
+                })?;
+
+                let status = match server_state.asset_hooks() {
+                    Some(_) => read_asset_status(store, doc_id, &filename).await,
+                    None => None,
+                };
 
                 asset_urls.push(AssetUrl {
                     asset_id,
                     download_url,
+                    filename: original_filename,
+                    status,
                 });
             }
         }
@@ -221,6 +503,36 @@ async fn get_doc_assets(
         Vec::new()
     };
 
+    Ok(assets)
+}
+
+/// Get all assets for a document with presigned download URLs
+#[cfg(feature = "assets")]
+async fn get_doc_assets(
+    Path(doc_id): Path<String>,
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+) -> Result<impl IntoResponse, AppError> {
+    let token = get_token_from_header(auth_header);
+    let authorization = server_state.verify_doc_token(token.as_deref(), &doc_id)?;
+
+    ext_check_not_archived(&server_state, &doc_id).await?;
+
+    // Check if document exists
+    if !server_state.doc_exists(&doc_id).await {
+        Err((StatusCode::NOT_FOUND, anyhow!("Doc {} not found", doc_id)))?;
+    }
+
+    let assets = list_doc_assets(&server_state, &doc_id).await?;
+    record_audit(
+        &server_state,
+        &doc_id,
+        "assets_listed",
+        AuditPrincipal::doc(token.as_deref(), authorization),
+        Some(json!({ "count": assets.len() })),
+    )
+    .await;
+
     let mut headers = HeaderMap::new();
     headers.insert(
         axum::http::header::CACHE_CONTROL,
@@ -229,7 +541,295 @@ async fn get_doc_assets(
     Ok((headers, Json(AssetsResponse { assets })))
 }
 
+/// Delete an asset of a document, with its thumbnails, status and filename, and tell the
+/// clients connected to the document
+#[cfg(feature = "assets")]
+async fn delete_asset(
+    Path((doc_id, asset_id)): Path<(String, String)>,
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+) -> Result<StatusCode, AppError> {
+    let token = get_token_from_header(auth_header);
+    let authorization = server_state.verify_doc_token(token.as_deref(), &doc_id)?;
+    if authorization != Authorization::Full {
+        Err((
+            StatusCode::FORBIDDEN,
+            anyhow!("Deleting assets requires full access to the document"),
+        ))?;
+    }
+
+    ext_check_not_archived(&server_state, &doc_id).await?;
+
+    let Some(store) = server_state.store.clone() else {
+        return Err(AppError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            anyhow!("No store configured"),
+        ));
+    };
+    let asset_name = find_asset_name(&store, &doc_id, &asset_id).await?;
+
+    let thumbs_prefix = format!("{}/assets/{}/", doc_id, THUMBS_DIR);
+    let thumbnail_prefix = format!(
+        "{}-",
+        extract_asset_id_from_filename(&asset_name).unwrap_or_default()
+    );
+    let thumbnails = store.list_objects(&thumbs_prefix).await.map_err(|e| {
+        AppError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            anyhow!("Failed to list thumbnails: {}", e),
+        )
+    })?;
+    let keys = thumbnails
+        .into_iter()
+        .filter(|name| name.starts_with(&thumbnail_prefix))
+        .map(|name| format!("{}{}", thumbs_prefix, name))
+        .chain([
+            asset_status_key(&doc_id, &asset_name),
+            asset_filename_key(&doc_id, &asset_name),
+            // Last, so that a failed deletion can be retried
+            format!("{}/assets/{}", doc_id, asset_name),
+        ]);
+    for key in keys {
+        store.remove(&key).await.map_err(|e| {
+            AppError(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                anyhow!("Failed to remove {}: {}", key, e),
+            )
+        })?;
+    }
+    ext_count_doc(&server_state, &doc_id).await;
+
+    record_audit(
+        &server_state,
+        &doc_id,
+        "asset_deleted",
+        AuditPrincipal::doc(token.as_deref(), authorization),
+        Some(json!({ "assetId": asset_name })),
+    )
+    .await;
+    server_state.notify_doc_connections(&doc_id, encode_asset_deleted_message(&asset_name));
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum DocExportFormat {
+    #[default]
+    Markdown,
+    #[serde(alias = "plain")]
+    Text,
+    #[serde(alias = "tiptap")]
+    Prosemirror,
+    Html,
+    #[serde(alias = "quill")]
+    Delta,
+}
+
+#[derive(Deserialize)]
+struct DocExportParams {
+    #[serde(default)]
+    format: DocExportFormat,
+    /// Root type to render, `default` if not given.
+    root: Option<String>,
+}
+
+/// Deny an update the update validator of the server rejects, before it is applied.
+pub fn ext_validate_update(
+    server_state: &Server,
+    doc_id: &str,
+    dwskv: &DocWithSyncKv,
+    update: &[u8],
+) -> Result<(), AppError> {
+    if let Some(validator) = server_state.update_validator() {
+        let awareness = dwskv.awareness();
+        let awareness = awareness.read().unwrap();
+        validate_update(validator.as_ref(), doc_id, awareness.doc(), update)
+            .map_err(|e| AppError(StatusCode::UNPROCESSABLE_ENTITY, e.into()))?;
+    }
+    if let Some(quotas) = server_state.quotas() {
+        quotas.check_update(doc_id, update.len() as u64)?;
+    }
+    Ok(())
+}
+
+/// Render the content of a document as Markdown, plain text, ProseMirror JSON, HTML or a
+/// Quill Delta
+async fn export_document(
+    Path(doc_id): Path<String>,
+    State(server_state): State<Arc<Server>>,
+    Query(params): Query<DocExportParams>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+) -> Result<Response, AppError> {
+    let token = get_token_from_header(auth_header);
+    let _ = server_state.verify_doc_token(token.as_deref(), &doc_id)?;
+    ext_check_not_archived(&server_state, &doc_id).await?;
+
+    if !server_state.doc_exists(&doc_id).await {
+        Err((StatusCode::NOT_FOUND, anyhow!("Doc {} not found", doc_id)))?;
+    }
+
+    let update = server_state
+        .get_or_create_doc(&doc_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?
+        .as_update();
+    Ok(export_response(&server_state, update, params))
+}
+
+/// Render the content of the document as Markdown, plain text, ProseMirror JSON, HTML or a
+/// Quill Delta (single doc mode)
+async fn export_document_single(
+    State(server_state): State<Arc<Server>>,
+    Query(params): Query<DocExportParams>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let doc_id = server_state.get_single_doc_id()?;
+    let _authorization = get_authorization_from_plane_header(headers)?;
+
+    let update = server_state
+        .get_or_create_doc(&doc_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?
+        .as_update();
+    Ok(export_response(&server_state, update, params))
+}
+
+/// Size of the chunks export responses are streamed in.
+const EXPORT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Render an export as it is sent, so that large documents are never held in memory in
+/// their rendered form. Since the status is sent before rendering starts, a failure ends
+/// the response with an error rather than with a 500.
+fn export_response(server: &Server, update: Vec<u8>, params: DocExportParams) -> Response {
+    let root = params.root.unwrap_or_else(|| DEFAULT_TEXT_ROOT.to_string());
+    let format = params.format;
+    let html_renderer = server.html_renderer().clone();
+    let content_type = match format {
+        DocExportFormat::Markdown => "text/markdown; charset=utf-8",
+        DocExportFormat::Text => "text/plain; charset=utf-8",
+        DocExportFormat::Prosemirror | DocExportFormat::Delta => "application/json",
+        DocExportFormat::Html => "text/html; charset=utf-8",
+    };
+
+    let (sender, receiver) = mpsc::channel(4);
+    tokio::task::spawn_blocking(move || {
+        let mut writer = ChunkWriter {
+            buffer: Vec::with_capacity(EXPORT_CHUNK_SIZE),
+            sender,
+        };
+        let result = match format {
+            DocExportFormat::Markdown => {
+                write_text(&update, &root, TextFormat::Markdown, &mut writer)
+            }
+            DocExportFormat::Text => write_text(&update, &root, TextFormat::Text, &mut writer),
+            DocExportFormat::Prosemirror => write_prosemirror(&update, &root, &mut writer),
+            DocExportFormat::Html => write_html(&update, &root, &html_renderer, &mut writer),
+            DocExportFormat::Delta => write_delta(&update, &root, &mut writer),
+        };
+        if let Err(e) = result.and_then(|()| Ok(writer.flush()?)) {
+            warn!(?e, "Failed to export document");
+            let _ = writer
+                .sender
+                .blocking_send(Err(std::io::Error::other(e.to_string())));
+        }
+    });
+
+    (
+        [(axum::http::header::CONTENT_TYPE, content_type)],
+        Body::from_stream(ReceiverStream::new(receiver)),
+    )
+        .into_response()
+}
+
+/// Sends what is written to it to a response body, in chunks of [EXPORT_CHUNK_SIZE]. Writes
+/// block while the client is behind, and fail once it has gone away.
+struct ChunkWriter {
+    buffer: Vec<u8>,
+    sender: mpsc::Sender<std::io::Result<Bytes>>,
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= EXPORT_CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.buffer, Vec::with_capacity(EXPORT_CHUNK_SIZE));
+        self.sender
+            .blocking_send(Ok(Bytes::from(chunk)))
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum DocImportFormat {
+    Json,
+    #[serde(alias = "tiptap")]
+    Prosemirror,
+    #[serde(alias = "quill")]
+    Delta,
+}
+
+#[derive(Deserialize)]
+struct DocImportParams {
+    format: DocImportFormat,
+    /// Root type to import rich text into, `default` if not given.
+    root: Option<String>,
+}
+
+/// Import JSON root types, a ProseMirror document or a Quill Delta into a document, as an
+/// update
+async fn import_document(
+    Path(doc_id): Path<String>,
+    State(server_state): State<Arc<Server>>,
+    Query(params): Query<DocImportParams>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    Json(content): Json<serde_json::Value>,
+) -> Result<Response, AppError> {
+    let token = get_token_from_header(auth_header);
+    let authorization = server_state.verify_doc_token(token.as_deref(), &doc_id)?;
+    let update = import_update(&content, params)?;
+    update_doc_inner(doc_id, server_state, authorization, update.into(), None).await
+}
+
+/// Import JSON root types, a ProseMirror document or a Quill Delta into the document, as an
+/// update (single doc mode)
+async fn import_document_single(
+    State(server_state): State<Arc<Server>>,
+    Query(params): Query<DocImportParams>,
+    headers: HeaderMap,
+    Json(content): Json<serde_json::Value>,
+) -> Result<Response, AppError> {
+    let doc_id = server_state.get_single_doc_id()?;
+    let authorization = get_authorization_from_plane_header(headers)?;
+    let update = import_update(&content, params)?;
+    update_doc_inner(doc_id, server_state, authorization, update.into(), None).await
+}
+
+fn import_update(
+    content: &serde_json::Value,
+    params: DocImportParams,
+) -> Result<Vec<u8>, AppError> {
+    let root = params.root.as_deref().unwrap_or(DEFAULT_TEXT_ROOT);
+    let update = match params.format {
+        DocImportFormat::Json => json_to_update(content),
+        DocImportFormat::Prosemirror => prosemirror_to_update(content, root),
+        DocImportFormat::Delta => delta_to_update(content, root),
+    };
+    update.map_err(|e| AppError(StatusCode::BAD_REQUEST, e))
+}
+
 /// Get all assets for a document (single doc mode)
+#[cfg(feature = "assets")]
 async fn get_doc_assets_single(
     State(server_state): State<Arc<Server>>,
     headers: HeaderMap,
@@ -249,7 +849,7 @@ async fn get_doc_assets_single(
             )
         })?;
 
-        for object_key in objects {
+        for object_key in objects.into_iter().filter(|name| is_asset_name(name)) {
             // Extract asset ID from the object key
             if let Some(asset_id) = extract_asset_id_from_filename(&object_key) {
                 let download_url = store
@@ -265,6 +865,8 @@ async fn get_doc_assets_single(
                 assets.push(AssetUrl {
                     asset_id,
                     download_url,
+                    filename: read_asset_filename(store, &doc_id, &object_key).await,
+                    status: None,
                 });
             }
         }
@@ -288,10 +890,245 @@ pub async fn delete_document(
     Path(doc_id): Path<String>,
     State(server_state): State<Arc<Server>>,
     auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    body: Option<Json<DocDeleteRequest>>,
 ) -> Result<Json<DocDeleteResponse>, AppError> {
+    let principal = AuditPrincipal::server(&auth_header);
     // Check authentication - this is an admin-only API
     server_state.check_auth(auth_header)?;
 
+    let Json(DocDeleteRequest { dry_run }) = body.unwrap_or_default();
+    delete_document_inner(&server_state, doc_id, dry_run, principal)
+        .await
+        .map(Json)
+}
+
+/// Remove the data, assets and metadata stored under `doc_id`.
+/// Returns whether the data file was removed and how many assets were removed.
+async fn remove_document_objects(
+    store: &Arc<Box<dyn Store>>,
+    doc_id: &str,
+) -> Result<(bool, usize), AppError> {
+    let mut data_deleted = false;
+    let mut deleted_assets = 0usize;
+
+    let data_key = snapshot_key(doc_id);
+    match store.remove(&data_key).await {
+        Ok(_) => {
+            data_deleted = true;
+        }
+        Err(StoreError::DoesNotExist(_)) => {}
+        Err(e) => {
+            error!(
+                message = "Failed to delete document data",
+                event = "document_delete_failed",
+                doc_id = %doc_id,
+                error = %e
+            );
+            return Err(AppError(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                anyhow!("Failed to delete document data: {}", e),
+            ));
+        }
+    }
+
+    let assets_prefix = format!("{}/assets/", doc_id);
+    match store.list_objects(&assets_prefix).await {
+        Ok(asset_names) => {
+            for filename in asset_names.into_iter().filter(|name| is_asset_name(name)) {
+                let key = format!("{}/assets/{}", doc_id, filename);
+                match store.remove(&key).await {
+                    Ok(_) => {
+                        deleted_assets += 1;
+                    }
+                    Err(StoreError::DoesNotExist(_)) => {}
+                    Err(e) => {
+                        error!(
+                            message = "Failed to delete document asset",
+                            event = "document_delete_asset_failed",
+                            doc_id = %doc_id,
+                            asset = %filename,
+                            error = %e
+                        );
+                        return Err(AppError(
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            anyhow!("Failed to delete asset {}: {}", filename, e),
+                        ));
+                    }
+                }
+            }
+        }
+        Err(StoreError::DoesNotExist(_)) => {}
+        Err(e) => {
+            error!(
+                message = "Failed to list document assets",
+                event = "document_delete_failed",
+                doc_id = %doc_id,
+                error = %e
+            );
+            return Err(AppError(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                anyhow!("Failed to list assets for deletion: {}", e),
+            ));
+        }
+    }
+
+    remove_asset_subdirs(store, doc_id).await.map_err(|e| {
+        error!(
+            message = "Failed to delete document thumbnails, asset status and filenames",
+            event = "document_delete_failed",
+            doc_id = %doc_id,
+            error = %e
+        );
+        AppError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            anyhow!(
+                "Failed to delete asset thumbnails, status and filenames: {}",
+                e
+            ),
+        )
+    })?;
+
+    remove_published(store, doc_id).await.map_err(|e| {
+        error!(
+            message = "Failed to delete the published snapshot of the document",
+            event = "document_delete_failed",
+            doc_id = %doc_id,
+            error = %e
+        );
+        AppError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            anyhow!("Failed to delete the published snapshot: {}", e),
+        )
+    })?;
+
+    match store.remove(&snapshot_backup_key(doc_id)).await {
+        Ok(_) | Err(StoreError::DoesNotExist(_)) => {}
+        Err(e) => {
+            error!(
+                message = "Failed to delete document snapshot backup",
+                event = "document_delete_failed",
+                doc_id = %doc_id,
+                error = %e
+            );
+            return Err(AppError(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                anyhow!("Failed to delete document snapshot backup: {}", e),
+            ));
+        }
+    }
+
+    remove_doc_metadata(store, doc_id).await?;
+    remove_doc_labels(store, doc_id).await?;
+    remove_subdoc_objects(store, doc_id).await?;
+    remove_update_log(store.as_ref().as_ref(), doc_id)
+        .await
+        .map_err(|e| {
+            error!(
+                message = "Failed to delete document update log",
+                event = "document_delete_failed",
+                doc_id = %doc_id,
+                error = %e
+            );
+            AppError(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                anyhow!("Failed to delete update log: {}", e),
+            )
+        })?;
+    remove_shards(store.as_ref().as_ref(), doc_id)
+        .await
+        .map_err(|e| {
+            error!(
+                message = "Failed to delete document snapshot shards",
+                event = "document_delete_failed",
+                doc_id = %doc_id,
+                error = %e
+            );
+            AppError(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                anyhow!("Failed to delete snapshot shards: {}", e),
+            )
+        })?;
+
+    Ok((data_deleted, deleted_assets))
+}
+
+/// Remove the thumbnails, status and filenames of the assets of `doc_id`.
+async fn remove_asset_subdirs(store: &Arc<Box<dyn Store>>, doc_id: &str) -> Result<(), StoreError> {
+    for dir in ASSET_SUBDIRS {
+        let prefix = format!("{}/assets/{}/", doc_id, dir);
+        let names = match store.list_objects(&prefix).await {
+            Ok(names) => names,
+            Err(StoreError::DoesNotExist(_)) => continue,
+            Err(e) => return Err(e),
+        };
+        for name in names {
+            match store.remove(&format!("{}{}", prefix, name)).await {
+                Ok(()) | Err(StoreError::DoesNotExist(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Keys of the objects deleting `doc_id` would remove from the store, for dry runs.
+pub(crate) async fn list_document_objects(
+    store: &Arc<Box<dyn Store>>,
+    doc_id: &str,
+) -> Result<Vec<String>, AppError> {
+    let list = async {
+        let mut keys = Vec::new();
+        for key in [
+            snapshot_key(doc_id),
+            snapshot_backup_key(doc_id),
+            metadata_key(doc_id),
+            labels_key(doc_id),
+            ttl_key(doc_id),
+        ] {
+            if store.exists(&key).await? {
+                keys.push(key);
+            }
+        }
+        let asset_subdirs = ASSET_SUBDIRS.map(|dir| format!("assets/{}", dir));
+        let dirs = ["assets"]
+            .into_iter()
+            .chain(asset_subdirs.iter().map(String::as_str))
+            .chain([UPDATES_DIR, SHARDS_DIR, PUBLISHED_DIR]);
+        for dir in dirs {
+            let prefix = format!("{}/{}/", doc_id, dir);
+            match store.list_objects(&prefix).await {
+                Ok(names) => keys.extend(
+                    names
+                        .iter()
+                        .filter(|name| dir != "assets" || is_asset_name(name))
+                        .map(|name| format!("{}{}", prefix, name)),
+                ),
+                Err(StoreError::DoesNotExist(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        keys.extend(list_subdoc_objects(store, doc_id).await?);
+        Ok::<_, StoreError>(keys)
+    };
+
+    let mut keys = list.await.map_err(|e| {
+        AppError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            anyhow!("Failed to list document objects: {}", e),
+        )
+    })?;
+    keys.sort();
+    Ok(keys)
+}
+
+/// Delete a document and its assets without checking authentication. With `dry_run`, only
+/// report the objects that would be removed. `principal` is recorded in the audit trail.
+pub(crate) async fn delete_document_inner(
+    server_state: &Arc<Server>,
+    doc_id: String,
+    dry_run: bool,
+    principal: AuditPrincipal,
+) -> Result<DocDeleteResponse, AppError> {
     if !validate_doc_name(&doc_id) {
         return Err(AppError(
             StatusCode::BAD_REQUEST,
@@ -306,6 +1143,31 @@ pub async fn delete_document(
         ));
     }
 
+    ext_check_not_frozen(server_state, &doc_id).await?;
+
+    if dry_run {
+        let removed_objects = match &server_state.store {
+            Some(store) => list_document_objects(store, &doc_id).await?,
+            None => Vec::new(),
+        };
+        let data_deleted = removed_objects.contains(&snapshot_key(&doc_id));
+        let assets_prefix = format!("{}/assets/", doc_id);
+        let deleted_assets = removed_objects
+            .iter()
+            .filter_map(|key| key.strip_prefix(&assets_prefix))
+            .filter(|name| is_asset_name(name))
+            .count();
+        let success = server_state.docs.contains_key(&doc_id) || data_deleted || deleted_assets > 0;
+        return Ok(DocDeleteResponse {
+            doc_id,
+            success,
+            data_deleted,
+            deleted_assets,
+            dry_run: true,
+            removed_objects: Some(removed_objects),
+        });
+    }
+
     info!(
         message = "Deleting document",
         event = "document_delete_started",
@@ -318,74 +1180,31 @@ pub async fn delete_document(
         // Shut down persistence to avoid resurrecting the document
         doc.sync_kv().shutdown();
     }
+    // Connected clients would otherwise keep editing the unloaded copy
+    let had_connections = server_state.close_doc_connections(&doc_id);
+    unload_subdocs(server_state, &doc_id).await?;
 
-    let mut data_deleted = false;
-    let mut deleted_assets = 0usize;
-
-    if let Some(store) = &server_state.store {
-        let data_key = format!("{}/data.ysweet", doc_id);
-        match store.remove(&data_key).await {
-            Ok(_) => {
-                data_deleted = true;
-            }
-            Err(StoreError::DoesNotExist(_)) => {}
-            Err(e) => {
-                error!(
-                    message = "Failed to delete document data",
-                    event = "document_delete_failed",
-                    doc_id = %doc_id,
-                    error = %e
-                );
-                return Err(AppError(
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    anyhow!("Failed to delete document data: {}", e),
-                ));
-            }
-        }
+    let (data_deleted, deleted_assets) = if let Some(store) = &server_state.store {
+        remove_document_objects(store, &doc_id).await?
+    } else {
+        (false, 0)
+    };
 
-        let assets_prefix = format!("{}/assets/", doc_id);
-        match store.list_objects(&assets_prefix).await {
-            Ok(asset_names) => {
-                for filename in asset_names {
-                    let key = format!("{}/assets/{}", doc_id, filename);
-                    match store.remove(&key).await {
-                        Ok(_) => {
-                            deleted_assets += 1;
-                        }
-                        Err(StoreError::DoesNotExist(_)) => {}
-                        Err(e) => {
-                            error!(
-                                message = "Failed to delete document asset",
-                                event = "document_delete_asset_failed",
-                                doc_id = %doc_id,
-                                asset = %filename,
-                                error = %e
-                            );
-                            return Err(AppError(
-                                StatusCode::INTERNAL_SERVER_ERROR,
-                                anyhow!("Failed to delete asset {}: {}", filename, e),
-                            ));
-                        }
-                    }
-                }
-            }
-            Err(StoreError::DoesNotExist(_)) => {}
-            Err(e) => {
-                error!(
-                    message = "Failed to list document assets",
-                    event = "document_delete_failed",
-                    doc_id = %doc_id,
-                    error = %e
-                );
-                return Err(AppError(
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    anyhow!("Failed to list assets for deletion: {}", e),
-                ));
-            }
-        }
+    clear_doc_expiration(server_state, &doc_id).await?;
+    if let Some(quotas) = server_state.quotas() {
+        quotas.record_removed(&doc_id);
     }
+    remove_from_search_index(server_state, &doc_id).await;
 
     let success = existed_in_memory || data_deleted || deleted_assets > 0;
+    record_audit(
+        server_state,
+        &doc_id,
+        "document_deleted",
+        principal,
+        Some(json!({ "dataDeleted": data_deleted, "deletedAssets": deleted_assets })),
+    )
+    .await;
 
     info!(
         message = "Document deleted",
@@ -393,14 +1212,131 @@ pub async fn delete_document(
         doc_id = %doc_id,
         data_deleted = data_deleted,
         deleted_assets = deleted_assets,
-        existed_in_memory = existed_in_memory
+        existed_in_memory = existed_in_memory,
+        had_connections = had_connections
     );
 
-    Ok(Json(DocDeleteResponse {
+    Ok(DocDeleteResponse {
         doc_id,
         success,
         data_deleted,
         deleted_assets,
+        dry_run: false,
+        removed_objects: None,
+    })
+}
+
+/// Delete multiple documents, selected by ID and/or by ID prefix
+pub async fn delete_documents_batch(
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    Json(body): Json<DocBatchDeleteRequest>,
+) -> Result<Json<DocBatchDeleteResponse>, AppError> {
+    let principal = AuditPrincipal::server(&auth_header);
+    // Check authentication - this is an admin-only API
+    server_state.check_auth(auth_header)?;
+
+    let dry_run = body.dry_run;
+    let mut doc_ids = body.doc_ids;
+
+    if let Some(prefix) = &body.prefix {
+        // An empty prefix would match every document in the store
+        if prefix.is_empty() {
+            return Err(AppError(
+                StatusCode::BAD_REQUEST,
+                anyhow!("Prefix must not be empty"),
+            ));
+        }
+
+        if let Some(store) = &server_state.store {
+            let stored = store.list_documents(prefix).await.map_err(|e| {
+                AppError(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    anyhow!("Failed to list documents: {}", e),
+                )
+            })?;
+            doc_ids.extend(stored);
+        }
+
+        // Documents that were never persisted only exist in memory
+        doc_ids.extend(
+            server_state
+                .docs
+                .iter()
+                .filter(|entry| entry.key().starts_with(prefix.as_str()))
+                .map(|entry| entry.key().clone()),
+        );
+    }
+
+    if doc_ids.is_empty() && body.prefix.is_none() {
+        return Err(AppError(
+            StatusCode::BAD_REQUEST,
+            anyhow!("Either docIds or prefix must be provided"),
+        ));
+    }
+
+    doc_ids.sort();
+    doc_ids.dedup();
+
+    info!(
+        message = "Deleting documents in batch",
+        event = "document_batch_delete_started",
+        doc_count = doc_ids.len(),
+        dry_run = dry_run
+    );
+
+    let results: Vec<DocBatchDeleteResult> = futures::stream::iter(doc_ids)
+        .map(|doc_id| {
+            let server_state = server_state.clone();
+            let principal = principal.clone();
+            async move {
+                let deleted = match check_local(&server_state, &doc_id) {
+                    Ok(()) => {
+                        delete_document_inner(&server_state, doc_id.clone(), dry_run, principal)
+                            .await
+                    }
+                    Err(e) => Err(e),
+                };
+                match deleted {
+                    Ok(response) => DocBatchDeleteResult {
+                        doc_id,
+                        success: response.success,
+                        data_deleted: response.data_deleted,
+                        deleted_assets: response.deleted_assets,
+                        removed_objects: response.removed_objects,
+                        error: None,
+                    },
+                    Err(AppError(_, err)) => DocBatchDeleteResult {
+                        doc_id,
+                        success: false,
+                        data_deleted: false,
+                        deleted_assets: 0,
+                        removed_objects: None,
+                        error: Some(err.to_string()),
+                    },
+                }
+            }
+        })
+        .buffer_unordered(BATCH_DELETE_CONCURRENCY)
+        .collect()
+        .await;
+
+    let deleted = results.iter().filter(|r| r.success).count();
+    let failed = results.len() - deleted;
+
+    info!(
+        message = "Documents deleted in batch",
+        event = "document_batch_delete_completed",
+        deleted = deleted,
+        failed = failed,
+        dry_run = dry_run
+    );
+
+    Ok(Json(DocBatchDeleteResponse {
+        results,
+        deleted,
+        failed,
+        dry_run,
     }))
 }
 
@@ -411,6 +1347,7 @@ pub async fn copy_document(
     auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
     Json(body): Json<DocCopyRequest>,
 ) -> Result<Json<DocCopyResponse>, AppError> {
+    let principal = AuditPrincipal::server(&auth_header);
     // Check authentication - this is an admin-only API
     server_state.check_auth(auth_header)?;
 
@@ -424,13 +1361,16 @@ pub async fn copy_document(
         ));
     }
 
-    if !validate_doc_name(&destination_doc_id) {
+    if !server_state.doc_id_generator().accepts(&destination_doc_id) {
         return Err(AppError(
             StatusCode::BAD_REQUEST,
             anyhow!("Invalid destination document ID"),
         ));
     }
 
+    check_local(&server_state, &destination_doc_id)?;
+    ext_check_not_archived(&server_state, &source_doc_id).await?;
+
     // Check if source document exists
     if !server_state.doc_exists(&source_doc_id).await {
         return Err(AppError(
@@ -439,6 +1379,34 @@ pub async fn copy_document(
         ));
     }
 
+    if body.dry_run {
+        let store = require_store(&server_state)?;
+        let source_prefix = format!("{}/", source_doc_id);
+        let mut written_objects: Vec<String> =
+            list_doc_objects(store.as_ref().as_ref(), &source_doc_id)
+                .await
+                .map_err(|e| {
+                    AppError(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        anyhow!("Failed to list document objects: {}", e),
+                    )
+                })?
+                .iter()
+                .filter_map(|key| key.strip_prefix(&source_prefix))
+                .map(|name| format!("{}/{}", destination_doc_id, name))
+                .collect();
+        written_objects.sort();
+        return Ok(Json(DocCopyResponse {
+            source_doc_id,
+            destination_doc_id,
+            success: true,
+            dry_run: true,
+            written_objects: Some(written_objects),
+        }));
+    }
+
+    ext_check_doc_quota(&server_state, &destination_doc_id).await?;
+
     // Force sync from memory to S3 before copying to ensure we have the latest data
     if let Some(doc) = server_state.docs.get(&source_doc_id) {
         tracing::debug!(
@@ -466,11 +1434,22 @@ pub async fn copy_document(
                     anyhow!("Failed to copy document: {}", e),
                 )
             })?;
+        ext_count_doc(&server_state, &destination_doc_id).await;
+        audit_copy(
+            &server_state,
+            &source_doc_id,
+            &destination_doc_id,
+            "document_copied",
+            principal,
+        )
+        .await;
 
         Ok(Json(DocCopyResponse {
             source_doc_id,
             destination_doc_id,
             success: true,
+            dry_run: false,
+            written_objects: None,
         }))
     } else {
         Err(AppError(
@@ -480,20 +1459,1234 @@ pub async fn copy_document(
     }
 }
 
-/// Extension routes for custom endpoints
-pub fn ext_routes(server: &Arc<Server>) -> Router {
-    Router::new()
-        .route("/d/:doc_id", delete(delete_document))
-        .route("/d/:doc_id/copy", post(copy_document))
-        .route("/d/:doc_id/assets", post(generate_upload_presigned_url))
-        .route("/d/:doc_id/assets", get(get_doc_assets))
-        .with_state(server.clone())
+/// Record a copy of `source_doc_id` in the trails of both documents. The copy brought the
+/// trail of its source along, which is dropped so that it starts a trail of its own.
+async fn audit_copy(
+    server_state: &Server,
+    source_doc_id: &str,
+    destination_doc_id: &str,
+    action: &str,
+    principal: AuditPrincipal,
+) {
+    if !server_state.audit() {
+        return;
+    }
+    if let Some(store) = &server_state.store {
+        if let Err(e) =
+            remove_copied_audit(store.as_ref().as_ref(), source_doc_id, destination_doc_id).await
+        {
+            tracing::warn!(
+                "Failed to remove the copied audit trail of {}: {}",
+                destination_doc_id,
+                e
+            );
+        }
+    }
+    record_audit(
+        server_state,
+        source_doc_id,
+        action,
+        principal.clone(),
+        Some(json!({ "destinationDocId": destination_doc_id })),
+    )
+    .await;
+    record_audit(
+        server_state,
+        destination_doc_id,
+        &format!("{}_from", action),
+        principal,
+        Some(json!({ "sourceDocId": source_doc_id })),
+    )
+    .await;
 }
 
-/// Extension routes for custom endpoints (single doc mode)
-pub fn ext_single_doc_routes(server: &Arc<Server>) -> Router {
-    Router::new()
-        .route("/assets", post(generate_upload_presigned_url_single))
-        .route("/assets", get(get_doc_assets_single))
-        .with_state(server.clone())
+/// Merge another document into a document.
+/// The source document's state is applied as a CRDT update, so concurrent edits from both
+/// documents are preserved and the source document is left unchanged.
+pub async fn merge_document(
+    Path(doc_id): Path<String>,
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    Json(body): Json<DocMergeRequest>,
+) -> Result<Json<DocMergeResponse>, AppError> {
+    // Check authentication - this is an admin-only API
+    server_state.check_auth(auth_header)?;
+
+    let source_doc_id = body.source_doc_id;
+
+    // Validate document IDs
+    if !validate_doc_name(&doc_id) {
+        return Err(AppError(
+            StatusCode::BAD_REQUEST,
+            anyhow!("Invalid document ID"),
+        ));
+    }
+
+    if !validate_doc_name(&source_doc_id) {
+        return Err(AppError(
+            StatusCode::BAD_REQUEST,
+            anyhow!("Invalid source document ID"),
+        ));
+    }
+
+    if doc_id == source_doc_id {
+        return Err(AppError(
+            StatusCode::BAD_REQUEST,
+            anyhow!("A document cannot be merged into itself"),
+        ));
+    }
+
+    check_local(&server_state, &source_doc_id)?;
+
+    ext_check_not_archived(&server_state, &doc_id).await?;
+    ext_check_not_archived(&server_state, &source_doc_id).await?;
+    ext_check_not_frozen(&server_state, &doc_id).await?;
+
+    if !server_state.doc_exists(&doc_id).await {
+        return Err(AppError(
+            StatusCode::NOT_FOUND,
+            anyhow!("Document not found"),
+        ));
+    }
+
+    if !server_state.doc_exists(&source_doc_id).await {
+        return Err(AppError(
+            StatusCode::NOT_FOUND,
+            anyhow!("Source document not found"),
+        ));
+    }
+
+    info!(
+        message = "Merging document",
+        event = "document_merge_started",
+        doc_id = %doc_id,
+        source_doc_id = %source_doc_id
+    );
+
+    // Encode the source first so that only one document is borrowed at a time
+    let update = server_state
+        .get_or_create_doc(&source_doc_id)
+        .await
+        .map_err(|e| AppError(StatusCode::INTERNAL_SERVER_ERROR, e))?
+        .as_update();
+
+    server_state
+        .get_or_create_doc(&doc_id)
+        .await
+        .map_err(|e| AppError(StatusCode::INTERNAL_SERVER_ERROR, e))?
+        .apply_update(&update)
+        .map_err(|e| {
+            error!(
+                message = "Failed to merge document",
+                event = "document_merge_failed",
+                doc_id = %doc_id,
+                source_doc_id = %source_doc_id,
+                error = %e
+            );
+            AppError(StatusCode::INTERNAL_SERVER_ERROR, e)
+        })?;
+
+    info!(
+        message = "Document merged",
+        event = "document_merge_completed",
+        doc_id = %doc_id,
+        source_doc_id = %source_doc_id,
+        update_size = update.len()
+    );
+
+    Ok(Json(DocMergeResponse {
+        doc_id,
+        source_doc_id,
+        success: true,
+    }))
+}
+
+/// Fork a document: copy it (including assets) to a new document and record its lineage,
+/// so the fork can later be compared with or merged back into its parent
+pub async fn fork_document(
+    Path(source_doc_id): Path<String>,
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    body: Option<Json<DocForkRequest>>,
+) -> Result<Json<DocForkResponse>, AppError> {
+    let principal = AuditPrincipal::server(&auth_header);
+    // Check authentication - this is an admin-only API
+    server_state.check_auth(auth_header)?;
+
+    let Json(DocForkRequest { destination_doc_id }) = body.unwrap_or_default();
+    let destination_doc_id = match destination_doc_id {
+        Some(doc_id) => doc_id,
+        None => server_state
+            .doc_id_generator()
+            .generate()
+            .map_err(|e| AppError(StatusCode::INTERNAL_SERVER_ERROR, e))?,
+    };
+
+    // Validate document IDs
+    if !validate_doc_name(&source_doc_id) {
+        return Err(AppError(
+            StatusCode::BAD_REQUEST,
+            anyhow!("Invalid source document ID"),
+        ));
+    }
+
+    if !server_state.doc_id_generator().accepts(&destination_doc_id) {
+        return Err(AppError(
+            StatusCode::BAD_REQUEST,
+            anyhow!("Invalid destination document ID"),
+        ));
+    }
+
+    let Some(store) = &server_state.store else {
+        return Err(AppError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            anyhow!("No store configured"),
+        ));
+    };
+
+    ext_check_not_archived(&server_state, &source_doc_id).await?;
+
+    if !server_state.doc_exists(&source_doc_id).await {
+        return Err(AppError(
+            StatusCode::NOT_FOUND,
+            anyhow!("Source document not found"),
+        ));
+    }
+
+    // A fork never overwrites an existing (or archived) document
+    if server_state.doc_exists(&destination_doc_id).await
+        || is_doc_archived(&server_state, &destination_doc_id).await?
+    {
+        return Err(AppError(
+            StatusCode::CONFLICT,
+            anyhow!("Destination document already exists"),
+        ));
+    }
+
+    ext_check_doc_quota(&server_state, &destination_doc_id).await?;
+
+    info!(
+        message = "Forking document",
+        event = "document_fork_started",
+        doc_id = %source_doc_id,
+        destination_doc_id = %destination_doc_id
+    );
+
+    let (sync_kv, awareness) = {
+        let doc = server_state
+            .get_or_create_doc(&source_doc_id)
+            .await
+            .map_err(|e| AppError(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+        (doc.sync_kv(), doc.awareness())
+    };
+
+    // Capture the fork point before persisting, so the fork contains at least this state
+    let fork_state_vector = {
+        let awareness = awareness.read().unwrap();
+        let txn = awareness.doc.transact();
+        txn.state_vector().encode_v1()
+    };
+
+    sync_kv.persist().await.map_err(|e| {
+        AppError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            anyhow!("Failed to persist source document before forking: {}", e),
+        )
+    })?;
+
+    store
+        .copy_document(&source_doc_id, &destination_doc_id)
+        .await
+        .map_err(|e| {
+            AppError(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                anyhow!("Failed to copy document: {}", e),
+            )
+        })?;
+
+    let lineage = DocLineage {
+        parent_doc_id: source_doc_id.clone(),
+        fork_state_vector: data_encoding::BASE64.encode(&fork_state_vector),
+        forked_at: current_time_epoch_millis(),
+    };
+
+    // The copy carries the parent's metadata; the fork gets its own lineage
+    write_doc_metadata(
+        store,
+        &destination_doc_id,
+        &DocMetadata {
+            lineage: Some(lineage.clone()),
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    // Load the fork so it exists even if the parent had never been persisted. Another node
+    // owning it loads it from the copy.
+    if is_local(&server_state, &destination_doc_id) {
+        server_state
+            .get_or_create_doc(&destination_doc_id)
+            .await
+            .map_err(|e| AppError(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    }
+    ext_count_doc(&server_state, &destination_doc_id).await;
+    audit_copy(
+        &server_state,
+        &source_doc_id,
+        &destination_doc_id,
+        "document_forked",
+        principal,
+    )
+    .await;
+
+    info!(
+        message = "Document forked",
+        event = "document_fork_completed",
+        doc_id = %source_doc_id,
+        destination_doc_id = %destination_doc_id
+    );
+
+    Ok(Json(DocForkResponse {
+        doc_id: destination_doc_id,
+        lineage,
+    }))
+}
+
+/// Get the metadata of a document
+pub async fn get_document_metadata(
+    Path(doc_id): Path<String>,
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+) -> Result<Json<DocMetadataResponse>, AppError> {
+    // Check authentication - this is an admin-only API
+    server_state.check_auth(auth_header)?;
+
+    if !validate_doc_name(&doc_id) {
+        return Err(AppError(
+            StatusCode::BAD_REQUEST,
+            anyhow!("Invalid document ID"),
+        ));
+    }
+
+    ext_check_not_archived(&server_state, &doc_id).await?;
+
+    if !server_state.doc_exists(&doc_id).await {
+        return Err(AppError(
+            StatusCode::NOT_FOUND,
+            anyhow!("Document not found"),
+        ));
+    }
+
+    let metadata = if let Some(store) = &server_state.store {
+        read_doc_metadata(store, &doc_id).await?
+    } else {
+        DocMetadata::default()
+    };
+
+    Ok(Json(DocMetadataResponse { doc_id, metadata }))
+}
+
+/// Storage prefix under which archived documents are kept.
+/// The leading dot keeps it outside the namespace of valid document IDs.
+pub const ARCHIVE_PREFIX: &str = ".archive";
+
+fn archived_doc_key(doc_id: &str) -> String {
+    format!("{}/{}", ARCHIVE_PREFIX, doc_id)
+}
+
+/// Documents known to be archived, including those being archived, kept up to date by the
+/// archive and unarchive endpoints. Only archived documents are cached, so the set stays as
+/// small as the archive; whether any other document is archived is read from the store.
+#[derive(Default)]
+pub struct DocArchives {
+    archived: DashSet<String>,
+}
+
+impl DocArchives {
+    pub fn is_archived(&self, doc_id: &str) -> bool {
+        self.archived.contains(doc_id)
+    }
+
+    fn set(&self, doc_id: &str, archived: bool) {
+        if archived {
+            self.archived.insert(doc_id.to_string());
+        } else {
+            self.archived.remove(doc_id);
+        }
+    }
+}
+
+/// Check whether a document has been moved to the archive
+pub async fn is_doc_archived(server_state: &Server, doc_id: &str) -> Result<bool, AppError> {
+    // A loaded document is live by definition
+    if server_state.docs.contains_key(doc_id) {
+        return Ok(false);
+    }
+    if server_state.archives().is_archived(doc_id) {
+        return Ok(true);
+    }
+    let Some(store) = &server_state.store else {
+        return Ok(false);
+    };
+    let archived = store
+        .exists(&format!("{}/data.ysweet", archived_doc_key(doc_id)))
+        .await
+        .map_err(|e| {
+            AppError(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                anyhow!("Failed to check whether the document is archived: {}", e),
+            )
+        })?;
+    if archived {
+        server_state.archives().set(doc_id, true);
+    }
+    Ok(archived)
+}
+
+/// Reject access to archived documents with 410 Gone
+pub async fn ext_check_not_archived(server_state: &Server, doc_id: &str) -> Result<(), AppError> {
+    if is_doc_archived(server_state, doc_id).await? {
+        return Err(AppError(
+            StatusCode::GONE,
+            anyhow!("Doc {} has been archived", doc_id),
+        ));
+    }
+    Ok(())
+}
+
+/// Reject changes to frozen documents with 423 Locked
+pub async fn ext_check_not_frozen(server_state: &Server, doc_id: &str) -> Result<(), AppError> {
+    if is_doc_frozen(server_state, doc_id).await? {
+        return Err(AppError(
+            StatusCode::LOCKED,
+            anyhow!("Doc {} is frozen", doc_id),
+        ));
+    }
+    Ok(())
+}
+
+/// Make a document read-only, including over the connections already open
+pub async fn freeze_document(
+    Path(doc_id): Path<String>,
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+) -> Result<Json<DocFreezeResponse>, AppError> {
+    // Check authentication - this is an admin-only API
+    server_state.check_auth(auth_header)?;
+    check_live_doc(&server_state, &doc_id).await?;
+
+    set_doc_frozen(&server_state, &doc_id, true).await?;
+    info!(message = "Document frozen", event = "document_frozen", doc_id = %doc_id);
+
+    Ok(Json(DocFreezeResponse {
+        doc_id,
+        frozen: true,
+    }))
+}
+
+/// Let a frozen document be changed again
+pub async fn unfreeze_document(
+    Path(doc_id): Path<String>,
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+) -> Result<Json<DocFreezeResponse>, AppError> {
+    // Check authentication - this is an admin-only API
+    server_state.check_auth(auth_header)?;
+    check_live_doc(&server_state, &doc_id).await?;
+
+    set_doc_frozen(&server_state, &doc_id, false).await?;
+    info!(message = "Document unfrozen", event = "document_unfrozen", doc_id = %doc_id);
+
+    Ok(Json(DocFreezeResponse {
+        doc_id,
+        frozen: false,
+    }))
+}
+
+/// Unload a document marked archived and move its objects under the archive prefix.
+/// Returns the number of assets moved.
+async fn move_to_archive(
+    server_state: &Server,
+    store: &Arc<Box<dyn Store>>,
+    doc_id: &str,
+) -> Result<usize, AppError> {
+    // Unload the document and flush pending changes so nothing is written to the live key afterwards
+    if let Some((_, doc)) = server_state.docs.remove(doc_id) {
+        doc.sync_kv().shutdown();
+        doc.sync_kv().persist().await.map_err(|e| {
+            AppError(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                anyhow!("Failed to persist document before archiving: {}", e),
+            )
+        })?;
+    }
+    unload_subdocs(server_state, doc_id).await?;
+
+    let archive_key = archived_doc_key(doc_id);
+    store
+        .copy_document(doc_id, &archive_key)
+        .await
+        .map_err(|e| {
+            error!(
+                message = "Failed to copy document to archive",
+                event = "document_archive_failed",
+                doc_id = %doc_id,
+                error = %e
+            );
+            AppError(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                anyhow!("Failed to archive document: {}", e),
+            )
+        })?;
+
+    let (_, moved_assets) = remove_document_objects(store, doc_id).await?;
+    Ok(moved_assets)
+}
+
+/// Move a document and its assets under the archive prefix
+pub async fn archive_document(
+    Path(doc_id): Path<String>,
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+) -> Result<Json<DocArchiveResponse>, AppError> {
+    // Check authentication - this is an admin-only API
+    server_state.check_auth(auth_header)?;
+
+    if !validate_doc_name(&doc_id) {
+        return Err(AppError(
+            StatusCode::BAD_REQUEST,
+            anyhow!("Invalid document ID"),
+        ));
+    }
+
+    let Some(store) = &server_state.store else {
+        return Err(AppError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            anyhow!("No store configured"),
+        ));
+    };
+
+    if is_doc_archived(&server_state, &doc_id).await? {
+        return Err(AppError(
+            StatusCode::CONFLICT,
+            anyhow!("Document is already archived"),
+        ));
+    }
+
+    if !server_state.doc_exists(&doc_id).await {
+        return Err(AppError(
+            StatusCode::NOT_FOUND,
+            anyhow!("Document not found"),
+        ));
+    }
+
+    info!(
+        message = "Archiving document",
+        event = "document_archive_started",
+        doc_id = %doc_id
+    );
+
+    // Marked archived before unloading, so that it is not loaded again meanwhile
+    server_state.archives().set(&doc_id, true);
+    let moved_assets = match move_to_archive(&server_state, store, &doc_id).await {
+        Ok(moved_assets) => moved_assets,
+        Err(e) => {
+            server_state.archives().set(&doc_id, false);
+            return Err(e);
+        }
+    };
+    if let Some(quotas) = server_state.quotas() {
+        quotas.record_removed(&doc_id);
+    }
+
+    info!(
+        message = "Document archived",
+        event = "document_archive_completed",
+        doc_id = %doc_id,
+        moved_assets = moved_assets
+    );
+
+    Ok(Json(DocArchiveResponse {
+        doc_id,
+        archived: true,
+        moved_assets,
+    }))
+}
+
+/// Restore an archived document and its assets to the live prefix
+pub async fn unarchive_document(
+    Path(doc_id): Path<String>,
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+) -> Result<Json<DocArchiveResponse>, AppError> {
+    // Check authentication - this is an admin-only API
+    server_state.check_auth(auth_header)?;
+
+    if !validate_doc_name(&doc_id) {
+        return Err(AppError(
+            StatusCode::BAD_REQUEST,
+            anyhow!("Invalid document ID"),
+        ));
+    }
+
+    let Some(store) = &server_state.store else {
+        return Err(AppError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            anyhow!("No store configured"),
+        ));
+    };
+
+    if !is_doc_archived(&server_state, &doc_id).await? {
+        return Err(AppError(
+            StatusCode::NOT_FOUND,
+            anyhow!("Archived document not found"),
+        ));
+    }
+
+    // Never overwrite a live document that was created under the same ID
+    if server_state.doc_exists(&doc_id).await {
+        return Err(AppError(
+            StatusCode::CONFLICT,
+            anyhow!("A live document with this ID already exists"),
+        ));
+    }
+
+    ext_check_doc_quota(&server_state, &doc_id).await?;
+
+    info!(
+        message = "Restoring archived document",
+        event = "document_unarchive_started",
+        doc_id = %doc_id
+    );
+
+    let archive_key = archived_doc_key(&doc_id);
+    store
+        .copy_document(&archive_key, &doc_id)
+        .await
+        .map_err(|e| {
+            error!(
+                message = "Failed to copy document from archive",
+                event = "document_unarchive_failed",
+                doc_id = %doc_id,
+                error = %e
+            );
+            AppError(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                anyhow!("Failed to restore document: {}", e),
+            )
+        })?;
+
+    let (_, moved_assets) = remove_document_objects(store, &archive_key).await?;
+    server_state.archives().set(&doc_id, false);
+    ext_count_doc(&server_state, &doc_id).await;
+
+    info!(
+        message = "Document restored from archive",
+        event = "document_unarchive_completed",
+        doc_id = %doc_id,
+        moved_assets = moved_assets
+    );
+
+    Ok(Json(DocArchiveResponse {
+        doc_id,
+        archived: false,
+        moved_assets,
+    }))
+}
+
+/// Validate the document ID and check that the document is live, for TTL and compaction
+/// endpoints
+async fn check_live_doc(server_state: &Server, doc_id: &str) -> Result<(), AppError> {
+    if !validate_doc_name(doc_id) {
+        return Err(AppError(
+            StatusCode::BAD_REQUEST,
+            anyhow!("Invalid document ID"),
+        ));
+    }
+
+    ext_check_not_archived(server_state, doc_id).await?;
+
+    if !server_state.doc_exists(doc_id).await {
+        return Err(AppError(
+            StatusCode::NOT_FOUND,
+            anyhow!("Document not found"),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Get the expiration time of a document
+pub async fn get_document_ttl(
+    Path(doc_id): Path<String>,
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+) -> Result<Json<DocTtlResponse>, AppError> {
+    // Check authentication - this is an admin-only API
+    server_state.check_auth(auth_header)?;
+    check_live_doc(&server_state, &doc_id).await?;
+
+    let expires_at = get_doc_expiration(&server_state, &doc_id).await?;
+
+    Ok(Json(DocTtlResponse { doc_id, expires_at }))
+}
+
+/// Set a document to be deleted after the given number of seconds
+pub async fn set_document_ttl(
+    Path(doc_id): Path<String>,
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    Json(body): Json<DocTtlRequest>,
+) -> Result<Json<DocTtlResponse>, AppError> {
+    // Check authentication - this is an admin-only API
+    server_state.check_auth(auth_header)?;
+    check_live_doc(&server_state, &doc_id).await?;
+
+    let expires_at =
+        current_time_epoch_millis().saturating_add(body.expires_in_seconds.saturating_mul(1000));
+    set_doc_expiration(&server_state, &doc_id, expires_at).await?;
+
+    Ok(Json(DocTtlResponse {
+        doc_id,
+        expires_at: Some(expires_at),
+    }))
+}
+
+/// Remove the expiration time of a document so it is kept indefinitely
+pub async fn clear_document_ttl(
+    Path(doc_id): Path<String>,
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+) -> Result<Json<DocTtlResponse>, AppError> {
+    // Check authentication - this is an admin-only API
+    server_state.check_auth(auth_header)?;
+    check_live_doc(&server_state, &doc_id).await?;
+
+    clear_doc_expiration(&server_state, &doc_id).await?;
+
+    Ok(Json(DocTtlResponse {
+        doc_id,
+        expires_at: None,
+    }))
+}
+
+/// Check that documents are persisted through an update log, for compaction and restore
+/// endpoints
+/// Rewrite the snapshot of a document with its deleted content garbage collected
+pub async fn gc_document(
+    Path(doc_id): Path<String>,
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    body: Option<Json<DocGcRequest>>,
+) -> Result<Json<DocGcResponse>, AppError> {
+    // Check authentication - this is an admin-only API
+    server_state.check_auth(auth_header)?;
+    check_live_doc(&server_state, &doc_id).await?;
+
+    let Json(DocGcRequest { dry_run }) = body.unwrap_or_default();
+    if !dry_run {
+        ext_check_not_frozen(&server_state, &doc_id).await?;
+    }
+
+    collect_doc_garbage(&server_state, &doc_id, dry_run)
+        .await
+        .map(Json)
+}
+
+/// Verify that the stored state of a document is intact and loads
+pub async fn verify_document(
+    Path(doc_id): Path<String>,
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+) -> Result<Json<DocVerifyResult>, AppError> {
+    // Check authentication - this is an admin-only API
+    server_state.check_auth(auth_header)?;
+    check_live_doc(&server_state, &doc_id).await?;
+    let store = require_store(&server_state)?;
+
+    let result = verify_doc(store, &doc_id).await;
+    if !result.ok {
+        warn!(
+            message = "Document failed verification",
+            event = "document_verify_failed",
+            doc_id = %doc_id,
+            error = result.error.as_deref().unwrap_or_default()
+        );
+    }
+    Ok(Json(result))
+}
+
+/// Verify multiple stored documents, selected by ID and/or by ID prefix
+pub async fn verify_documents_batch(
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    Json(body): Json<DocBatchVerifyRequest>,
+) -> Result<Json<DocBatchVerifyResponse>, AppError> {
+    // Check authentication - this is an admin-only API
+    server_state.check_auth(auth_header)?;
+    let store = require_store(&server_state)?;
+
+    let mut doc_ids = body.doc_ids;
+    match &body.prefix {
+        // An empty prefix verifies every document in the store
+        Some(prefix) => doc_ids.extend(store.list_documents(prefix).await.map_err(|e| {
+            AppError(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                anyhow!("Failed to list documents: {}", e),
+            )
+        })?),
+        None if doc_ids.is_empty() => {
+            return Err(AppError(
+                StatusCode::BAD_REQUEST,
+                anyhow!("Either docIds or prefix must be provided"),
+            ))
+        }
+        None => {}
+    }
+    if let Some(doc_id) = doc_ids.iter().find(|doc_id| !validate_doc_name(doc_id)) {
+        return Err(AppError(
+            StatusCode::BAD_REQUEST,
+            anyhow!("Invalid document ID: {}", doc_id),
+        ));
+    }
+    doc_ids.sort();
+    doc_ids.dedup();
+
+    let response = verify_docs(store, doc_ids).await;
+    info!(
+        message = "Documents verified",
+        event = "document_batch_verify_completed",
+        valid = response.valid,
+        invalid = response.invalid
+    );
+    Ok(Json(response))
+}
+
+fn require_store(server_state: &Server) -> Result<&Arc<Box<dyn Store>>, AppError> {
+    server_state.store.as_ref().ok_or_else(|| {
+        AppError(
+            StatusCode::BAD_REQUEST,
+            anyhow!("This operation requires a store"),
+        )
+    })
+}
+
+fn check_update_log(server_state: &Server) -> Result<(), AppError> {
+    if server_state.store.is_none() || !server_state.has_update_log() {
+        return Err(AppError(
+            StatusCode::BAD_REQUEST,
+            anyhow!("This operation requires update log persistence"),
+        ));
+    }
+    Ok(())
+}
+
+/// Keep a document loaded, never garbage collecting or evicting it
+pub async fn pin_document(
+    Path(doc_id): Path<String>,
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+) -> Result<Json<DocPinResponse>, AppError> {
+    // Check authentication - this is an admin-only API
+    server_state.check_auth(auth_header)?;
+    check_live_doc(&server_state, &doc_id).await?;
+
+    if server_state.pins().pin(&doc_id) {
+        info!(message = "Document pinned", event = "document_pinned", doc_id = %doc_id);
+    }
+    server_state.get_or_create_doc(&doc_id).await.map_err(|e| {
+        AppError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            anyhow!("Failed to load document: {}", e),
+        )
+    })?;
+
+    Ok(Json(DocPinResponse {
+        doc_id,
+        pinned: true,
+    }))
+}
+
+/// Let a pinned document be garbage collected and evicted again
+pub async fn unpin_document(
+    Path(doc_id): Path<String>,
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+) -> Result<Json<DocPinResponse>, AppError> {
+    // Check authentication - this is an admin-only API
+    server_state.check_auth(auth_header)?;
+
+    if server_state.pins().unpin(&doc_id) {
+        info!(message = "Document unpinned", event = "document_unpinned", doc_id = %doc_id);
+    }
+
+    Ok(Json(DocPinResponse {
+        doc_id,
+        pinned: false,
+    }))
+}
+
+/// Fold the update log of a document into a fresh snapshot and remove its segments
+pub async fn compact_document(
+    Path(doc_id): Path<String>,
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    body: Option<Json<DocCompactRequest>>,
+) -> Result<Json<DocCompactResponse>, AppError> {
+    // Check authentication - this is an admin-only API
+    server_state.check_auth(auth_header)?;
+    check_update_log(&server_state)?;
+    check_live_doc(&server_state, &doc_id).await?;
+
+    server_state.get_or_create_doc(&doc_id).await.map_err(|e| {
+        AppError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            anyhow!("Failed to load document: {}", e),
+        )
+    })?;
+
+    let Json(DocCompactRequest { dry_run }) = body.unwrap_or_default();
+    if dry_run {
+        let start = std::time::Instant::now();
+        let plan = plan_compaction(&server_state, &doc_id)
+            .await
+            .map_err(|e| {
+                AppError(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    anyhow!("Failed to plan compaction: {}", e),
+                )
+            })?
+            .ok_or_else(|| {
+                AppError(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    anyhow!("Document was unloaded during compaction"),
+                )
+            })?;
+        let segments_prefix = format!("{}/{}/", doc_id, UPDATES_DIR);
+        let reclaimed_objects = plan
+            .removed
+            .iter()
+            .filter(|key| key.starts_with(&segments_prefix))
+            .count();
+        return Ok(Json(DocCompactResponse {
+            doc_id,
+            snapshot_seq: plan.snapshot_seq,
+            reclaimed_objects,
+            duration_ms: start.elapsed().as_millis() as u64,
+            dry_run: true,
+            written_objects: Some(plan.written),
+            removed_objects: Some(plan.removed),
+        }));
+    }
+    let (compaction, duration) = compact_doc(&server_state, &doc_id)
+        .await
+        .map_err(|e| {
+            AppError(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                anyhow!("Failed to compact document: {}", e),
+            )
+        })?
+        .ok_or_else(|| {
+            AppError(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                anyhow!("Document was unloaded during compaction"),
+            )
+        })?;
+
+    Ok(Json(DocCompactResponse {
+        doc_id,
+        snapshot_seq: compaction.snapshot_seq,
+        reclaimed_objects: compaction.reclaimed_objects,
+        duration_ms: duration.as_millis() as u64,
+        dry_run: false,
+        written_objects: None,
+        removed_objects: None,
+    }))
+}
+
+#[cfg(feature = "config-files")]
+/// Read the configuration file again and apply its reloadable options (see `reload_ext`).
+pub async fn reload_configuration(
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+) -> Result<Json<ConfigReloadResponse>, AppError> {
+    // Check authentication - this is an admin-only API
+    server_state.check_auth(auth_header)?;
+
+    if server_state.config_path().is_none() {
+        return Err(AppError(
+            StatusCode::CONFLICT,
+            anyhow!("The server was not started with a configuration file"),
+        ));
+    }
+    let reloaded =
+        reload_config(&server_state).map_err(|e| AppError(StatusCode::UNPROCESSABLE_ENTITY, e))?;
+    Ok(Json(ConfigReloadResponse { reloaded }))
+}
+
+/// Report whether every loaded document is being persisted. Responds with 503 while any
+/// document is failing to persist; the failing documents are listed for admin requests.
+pub async fn health(
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+) -> Response {
+    let health = server_state.persistence_health();
+    let failing_docs = health.failing_docs();
+    let failing = server_state.check_auth(auth_header).is_ok().then(|| {
+        failing_docs
+            .iter()
+            .map(|(doc_id, failure)| FailingDoc {
+                doc_id: doc_id.clone(),
+                failing_since: failure.failing_since,
+                attempts: failure.attempts,
+                last_error: failure.last_error.clone(),
+                rejects_writes: health.rejects_writes(doc_id),
+            })
+            .collect()
+    });
+
+    let ok = failing_docs.is_empty();
+    let status = if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let body = HealthResponse {
+        ok,
+        failing_docs: failing_docs.len(),
+        persist_failures: health.total_failures(),
+        failing,
+    };
+    (status, Json(body)).into_response()
+}
+
+/// Restore a document as of a point of its history, in place or into a new document
+pub async fn restore_document(
+    Path(doc_id): Path<String>,
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    Json(body): Json<DocRestoreRequest>,
+) -> Result<Json<DocRestoreResponse>, AppError> {
+    // Check authentication - this is an admin-only API
+    server_state.check_auth(auth_header)?;
+    check_update_log(&server_state)?;
+    check_live_doc(&server_state, &doc_id).await?;
+
+    let point = match (body.seq, body.timestamp) {
+        (Some(seq), None) => RestorePoint::Seq(seq),
+        (None, Some(timestamp)) => RestorePoint::Time(timestamp),
+        _ => {
+            return Err(AppError(
+                StatusCode::BAD_REQUEST,
+                anyhow!("Exactly one of seq and timestamp is required"),
+            ))
+        }
+    };
+
+    let target_doc_id = body.target_doc_id.unwrap_or_else(|| doc_id.clone());
+    if target_doc_id == doc_id {
+        ext_check_not_frozen(&server_state, &doc_id).await?;
+    } else {
+        if !validate_doc_name(&target_doc_id) {
+            return Err(AppError(
+                StatusCode::BAD_REQUEST,
+                anyhow!("Invalid target document ID"),
+            ));
+        }
+
+        check_local(&server_state, &target_doc_id)?;
+        ext_check_not_archived(&server_state, &target_doc_id).await?;
+
+        if server_state.doc_exists(&target_doc_id).await {
+            return Err(AppError(
+                StatusCode::CONFLICT,
+                anyhow!("Target document already exists"),
+            ));
+        }
+    }
+
+    info!(
+        message = "Restoring document",
+        event = "document_restore_started",
+        doc_id = %doc_id,
+        target_doc_id = %target_doc_id,
+        point = ?point
+    );
+
+    let restored_seq = restore_doc(&server_state, &doc_id, point, &target_doc_id).await?;
+
+    info!(
+        message = "Document restored",
+        event = "document_restore_completed",
+        doc_id = %doc_id,
+        target_doc_id = %target_doc_id,
+        restored_seq = ?restored_seq
+    );
+
+    Ok(Json(DocRestoreResponse {
+        doc_id: target_doc_id,
+        source_doc_id: doc_id,
+        restored_seq,
+    }))
+}
+
+/// Build the gzip/deflate compression layer applied to document reads and asset listings.
+/// When disabled, responses are passed through uncompressed regardless of `Accept-Encoding`.
+pub fn ext_compression_layer(enabled: bool) -> CompressionLayer {
+    CompressionLayer::new().gzip(enabled).deflate(enabled)
+}
+
+/// Extension routes for custom endpoints
+pub fn ext_routes(server: &Arc<Server>) -> Router {
+    let compression = ext_compression_layer(server.compression());
+    let limits = server.body_limits();
+    let routes = Router::new()
+        .route("/health", get(health))
+        .route(
+            "/d/:doc_id/export",
+            get(export_document).layer(compression.clone()),
+        )
+        .route(
+            "/d/:doc_id/import",
+            limit_body(post(import_document), limits, BodyKind::Import),
+        )
+        .route(
+            "/d/:doc_id/published",
+            get(crate::publish_ext::get_published_document),
+        );
+
+    #[cfg(feature = "assets")]
+    let routes = routes
+        .route(
+            "/d/:doc_id/assets",
+            limit_body(post(generate_upload_presigned_url), limits, BodyKind::Asset),
+        )
+        .route("/d/:doc_id/assets", get(get_doc_assets).layer(compression))
+        .route(
+            "/d/:doc_id/assets/:asset_id",
+            get(crate::asset_filename_ext::download_asset).delete(delete_asset),
+        )
+        .route(
+            "/d/:doc_id/assets/:asset_id/token",
+            limit_body(
+                post(crate::asset_token_ext::issue_asset_token),
+                limits,
+                BodyKind::Asset,
+            ),
+        )
+        .route(
+            "/d/:doc_id/assets/:asset_id/confirm",
+            limit_body(
+                post(crate::asset_hook_ext::confirm_asset_upload),
+                limits,
+                BodyKind::Asset,
+            ),
+        );
+
+    #[cfg(feature = "thumbnails")]
+    let routes = routes.route(
+        "/d/:doc_id/assets/:asset_id/thumbnail",
+        get(crate::thumbnail_ext::get_asset_thumbnail),
+    );
+
+    routes.with_state(server.clone())
+}
+
+/// Extension routes of the management endpoints (multi-doc mode)
+pub fn ext_admin_routes(server: &Arc<Server>) -> Router {
+    let routes = Router::new()
+        .route("/docs/delete-batch", post(delete_documents_batch))
+        .route("/docs/verify", post(verify_documents_batch))
+        .route("/tenants", get(crate::tenants_ext::list_tenants))
+        .route("/quotas", get(crate::quota_ext::list_quotas))
+        .route("/search", get(crate::search_ext::search_documents))
+        .route("/docs", get(crate::labels_ext::list_documents))
+        .route("/metrics", get(crate::metrics_ext::get_metrics))
+        .route(
+            "/d/:doc_id/audit",
+            get(crate::audit_ext::get_document_audit),
+        )
+        .route("/d/:doc_id", delete(delete_document))
+        .route("/d/:doc_id/copy", post(copy_document))
+        .route("/d/:doc_id/merge", post(merge_document))
+        .route("/d/:doc_id/fork", post(fork_document))
+        .route("/d/:doc_id/metadata", get(get_document_metadata))
+        .route(
+            "/d/:doc_id/labels",
+            get(crate::labels_ext::get_document_labels).put(crate::labels_ext::set_document_labels),
+        )
+        .route(
+            "/d/:doc_id/stats",
+            get(crate::doc_stats_ext::get_document_stats),
+        )
+        .route("/d/:doc_id/archive", post(archive_document))
+        .route("/d/:doc_id/unarchive", post(unarchive_document))
+        .route("/d/:doc_id/freeze", post(freeze_document))
+        .route("/d/:doc_id/unfreeze", post(unfreeze_document))
+        .route(
+            "/d/:doc_id/publish",
+            post(crate::publish_ext::publish_document),
+        )
+        .route(
+            "/d/:doc_id/unpublish",
+            post(crate::publish_ext::unpublish_document),
+        )
+        .route("/d/:doc_id/pin", put(pin_document).delete(unpin_document))
+        .route("/d/:doc_id/compact", post(compact_document))
+        .route("/d/:doc_id/restore", post(restore_document))
+        .route("/d/:doc_id/verify", get(verify_document))
+        .route("/d/:doc_id/gc", post(gc_document))
+        .route(
+            "/d/:doc_id/ttl",
+            get(get_document_ttl)
+                .put(set_document_ttl)
+                .delete(clear_document_ttl),
+        );
+
+    #[cfg(feature = "config-files")]
+    let routes = routes.route("/admin/reload", post(reload_configuration));
+    #[cfg(feature = "assets")]
+    let routes = routes.route(
+        "/assets/events",
+        limit_body(
+            post(crate::asset_events_ext::ingest_asset_events),
+            server.body_limits(),
+            BodyKind::Asset,
+        ),
+    );
+    let routes = routes.with_state(server.clone());
+
+    #[cfg(feature = "graphql")]
+    let routes = routes.merge(crate::graphql_ext::ext_graphql_routes(server));
+
+    routes
+}
+
+/// Extension routes for custom endpoints (single doc mode)
+pub fn ext_single_doc_routes(server: &Arc<Server>) -> Router {
+    let compression = ext_compression_layer(server.compression());
+    let limits = server.body_limits();
+    let routes = Router::new()
+        .route(
+            "/export",
+            get(export_document_single).layer(compression.clone()),
+        )
+        .route(
+            "/import",
+            limit_body(post(import_document_single), limits, BodyKind::Import),
+        );
+
+    #[cfg(feature = "assets")]
+    let routes = routes
+        .route(
+            "/assets",
+            limit_body(
+                post(generate_upload_presigned_url_single),
+                limits,
+                BodyKind::Asset,
+            ),
+        )
+        .route("/assets", get(get_doc_assets_single).layer(compression));
+
+    routes.with_state(server.clone())
 }
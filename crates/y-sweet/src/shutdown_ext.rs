@@ -0,0 +1,263 @@
+//! Persisting loaded documents when the server shuts down.
+//!
+//! On shutdown, the worker of every loaded document persists it one last time. Rather than
+//! all of them writing to the store at once, they take turns through a bounded number of
+//! permits, and the progress is logged while they do. With a deadline, the server stops
+//! waiting for the remaining documents once it passes; their changes since the last
+//! checkpoint are lost, unless a WAL (see `wal_ext`) recorded them.
+//!
+//! With a shutdown timeout, the whole shutdown (draining connections included) is bounded,
+//! and the process exits once it passes, listing the documents that were not persisted.
+
+use std::{
+    collections::BTreeSet,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::{Semaphore, SemaphorePermit},
+    task::{JoinError, JoinHandle},
+};
+use tokio_util::task::TaskTracker;
+use tracing::{error, info, warn};
+
+/// Default number of documents persisted concurrently on shutdown.
+pub const DEFAULT_SHUTDOWN_PERSIST_CONCURRENCY: usize = 16;
+
+/// Interval between progress reports while persisting on shutdown.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+pub struct ShutdownPersistence {
+    permits: Semaphore,
+    deadline: Option<Duration>,
+    done: AtomicUsize,
+    failed: AtomicUsize,
+    /// Documents whose persistence worker is still running.
+    running: Mutex<BTreeSet<String>>,
+    /// Documents that failed to be persisted on shutdown.
+    failed_docs: Mutex<BTreeSet<String>>,
+}
+
+/// Marks the persistence worker of a document as running until dropped.
+pub(crate) struct RunningWorker<'a> {
+    persistence: &'a ShutdownPersistence,
+    doc_id: String,
+}
+
+impl Drop for RunningWorker<'_> {
+    fn drop(&mut self) {
+        self.persistence
+            .running
+            .lock()
+            .unwrap()
+            .remove(&self.doc_id);
+    }
+}
+
+impl ShutdownPersistence {
+    pub fn new(concurrency: usize, deadline: Option<Duration>) -> Self {
+        Self {
+            permits: Semaphore::new(concurrency.max(1)),
+            deadline,
+            done: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+            running: Mutex::new(BTreeSet::new()),
+            failed_docs: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    /// Track the persistence worker of `doc_id` while the returned guard lives.
+    pub(crate) fn worker_running(&self, doc_id: &str) -> RunningWorker<'_> {
+        self.running.lock().unwrap().insert(doc_id.to_string());
+        RunningWorker {
+            persistence: self,
+            doc_id: doc_id.to_string(),
+        }
+    }
+
+    /// Wait for a turn to persist a document.
+    pub(crate) async fn acquire(&self) -> SemaphorePermit<'_> {
+        self.permits
+            .acquire()
+            .await
+            .expect("Shutdown permits are never closed")
+    }
+
+    /// Record that a document was persisted, or failed to be.
+    pub(crate) fn finished(&self, doc_id: &str, persisted: bool) {
+        if persisted {
+            self.done.fetch_add(1, Ordering::SeqCst);
+        } else {
+            self.failed.fetch_add(1, Ordering::SeqCst);
+            self.failed_docs.lock().unwrap().insert(doc_id.to_string());
+        }
+    }
+
+    /// Documents that failed to be persisted on shutdown, or whose worker has not finished.
+    pub fn unpersisted_docs(&self) -> Vec<String> {
+        let mut docs = self.failed_docs.lock().unwrap().clone();
+        docs.extend(self.running.lock().unwrap().iter().cloned());
+        docs.into_iter().collect()
+    }
+}
+
+impl Default for ShutdownPersistence {
+    fn default() -> Self {
+        Self::new(DEFAULT_SHUTDOWN_PERSIST_CONCURRENCY, None)
+    }
+}
+
+/// Wait for the workers of `tracker` to finish, reporting the progress of persisting the
+/// `total` loaded documents, until the deadline passes. Returns whether every worker
+/// finished.
+pub(crate) async fn wait_for_workers(
+    tracker: &TaskTracker,
+    persistence: &ShutdownPersistence,
+    total: usize,
+) -> bool {
+    tracker.close();
+    info!(
+        message = format!("Persisting {} documents before shutting down", total),
+        event = "shutdown_persist_started",
+        total = total
+    );
+
+    let deadline = async {
+        match persistence.deadline {
+            Some(deadline) => tokio::time::sleep(deadline).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(deadline);
+    let mut progress = tokio::time::interval(PROGRESS_INTERVAL);
+    // The first tick completes immediately
+    progress.tick().await;
+
+    loop {
+        let done = persistence.done.load(Ordering::SeqCst);
+        let failed = persistence.failed.load(Ordering::SeqCst);
+        tokio::select! {
+            _ = tracker.wait() => {
+                info!(
+                    message = "Persisted documents before shutting down",
+                    event = "shutdown_persist_completed",
+                    persisted = persistence.done.load(Ordering::SeqCst),
+                    failed = persistence.failed.load(Ordering::SeqCst),
+                    failed_docs = persistence.unpersisted_docs().join(",")
+                );
+                return true;
+            }
+            _ = progress.tick() => {
+                info!(
+                    message = format!("Persisted {} of {} documents", done + failed, total),
+                    event = "shutdown_persist_progress",
+                    persisted = done,
+                    failed = failed,
+                    total = total
+                );
+            }
+            _ = &mut deadline => {
+                warn!(
+                    message = "Shutdown deadline passed before every document was persisted",
+                    event = "shutdown_persist_deadline_exceeded",
+                    persisted = done,
+                    failed = failed,
+                    remaining = total.saturating_sub(done + failed),
+                    unpersisted_docs = persistence.unpersisted_docs().join(",")
+                );
+                return false;
+            }
+        }
+    }
+}
+
+/// Wait for the `server` task to shut down, for at most `timeout`. Returns `false`, having
+/// logged the documents that were not persisted, if the timeout passed first.
+pub async fn wait_for_shutdown(
+    server: JoinHandle<()>,
+    timeout: Option<Duration>,
+    persistence: &ShutdownPersistence,
+) -> Result<bool, JoinError> {
+    let Some(timeout) = timeout else {
+        server.await?;
+        return Ok(true);
+    };
+    match tokio::time::timeout(timeout, server).await {
+        Ok(result) => result.map(|_| true),
+        Err(_) => {
+            let unpersisted = persistence.unpersisted_docs();
+            error!(
+                message = format!(
+                    "Shutdown timed out after {:?}, {} documents may not be persisted",
+                    timeout,
+                    unpersisted.len()
+                ),
+                event = "server_shutdown_timeout",
+                unpersisted_docs = unpersisted.join(",")
+            );
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_concurrency_is_bounded_and_deadline_stops_waiting() {
+        let persistence = Arc::new(ShutdownPersistence::new(
+            2,
+            Some(Duration::from_millis(300)),
+        ));
+        let tracker = TaskTracker::new();
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        for _ in 0..6 {
+            let persistence = persistence.clone();
+            let running = running.clone();
+            let max_running = max_running.clone();
+            tracker.spawn(async move {
+                let _permit = persistence.acquire().await;
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_running.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                persistence.finished("doc", true);
+            });
+        }
+        assert!(wait_for_workers(&tracker, &persistence, 6).await);
+        assert_eq!(max_running.load(Ordering::SeqCst), 2);
+        assert_eq!(persistence.done.load(Ordering::SeqCst), 6);
+
+        let stuck = TaskTracker::new();
+        stuck.spawn(std::future::pending::<()>());
+        assert!(!wait_for_workers(&stuck, &persistence, 1).await);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_timeout_reports_unpersisted_docs() {
+        let persistence = Arc::new(ShutdownPersistence::default());
+        persistence.finished("failed", false);
+        let server = {
+            let persistence = persistence.clone();
+            tokio::spawn(async move {
+                let _running = persistence.worker_running("stuck");
+                std::future::pending::<()>().await;
+            })
+        };
+        tokio::task::yield_now().await;
+
+        let shut_down =
+            wait_for_shutdown(server, Some(Duration::from_millis(50)), &persistence).await;
+        assert!(!shut_down.unwrap());
+        assert_eq!(persistence.unpersisted_docs(), vec!["failed", "stuck"]);
+
+        let server = tokio::spawn(async {});
+        assert!(wait_for_shutdown(server, None, &persistence).await.unwrap());
+    }
+}
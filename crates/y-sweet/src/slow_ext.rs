@@ -0,0 +1,170 @@
+//! Warnings for slow HTTP requests, WebSocket messages and persists.
+//!
+//! With `--slow-request-ms`, `--slow-message-ms` or `--slow-persist-ms`, an operation
+//! taking longer than its threshold logs a WARN event (`slow_request`, `slow_message` or
+//! `slow_persist`) with the document and what was done, so that performance regressions
+//! show up in the logs without tracing every request.
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use std::time::{Duration, Instant};
+use tracing::warn;
+use y_sweet_core::{
+    subdoc_ext::MSG_SUBDOC,
+    sync::{
+        MSG_AUTH, MSG_AWARENESS, MSG_QUERY_AWARENESS, MSG_SYNC, MSG_SYNC_STEP_1, MSG_SYNC_STEP_2,
+        MSG_SYNC_UPDATE,
+    },
+};
+
+/// Durations above which operations are logged as slow. None disables the warning.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SlowThresholds {
+    /// Handling an HTTP request, until its response headers.
+    pub request: Option<Duration>,
+    /// Handling a message of a WebSocket client.
+    pub message: Option<Duration>,
+    /// An attempt to persist a document.
+    pub persist: Option<Duration>,
+}
+
+/// Whether `elapsed` is over `threshold`.
+fn is_slow(threshold: Option<Duration>, elapsed: Duration) -> bool {
+    threshold.is_some_and(|threshold| elapsed > threshold)
+}
+
+/// The document a request is about, from its path: `/d/:doc_id/...`, `/doc/:doc_id/...` or
+/// `/doc/ws/:doc_id`, after the base path if any.
+pub fn doc_id_of_path(path: &str) -> Option<&str> {
+    let mut segments = path.split('/');
+    while let Some(segment) = segments.next() {
+        match segment {
+            "d" => return segments.next().filter(|id| !id.is_empty()),
+            "doc" => {
+                return match segments.next()? {
+                    "ws" => segments.next(),
+                    "new" | "" => None,
+                    doc_id => Some(doc_id),
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The kind of a message of the sync protocol, for logging.
+pub fn message_kind(msg: &[u8]) -> &'static str {
+    match msg {
+        [MSG_SYNC, MSG_SYNC_STEP_1, ..] => "sync_step1",
+        [MSG_SYNC, MSG_SYNC_STEP_2, ..] => "sync_step2",
+        [MSG_SYNC, MSG_SYNC_UPDATE, ..] => "sync_update",
+        [MSG_AWARENESS, ..] => "awareness",
+        [MSG_AUTH, ..] => "auth",
+        [MSG_QUERY_AWARENESS, ..] => "query_awareness",
+        [MSG_SUBDOC, ..] => "subdoc",
+        _ => "other",
+    }
+}
+
+/// Log requests taking longer than the request threshold.
+pub async fn warn_slow_requests(
+    State(thresholds): State<SlowThresholds>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(threshold) = thresholds.request else {
+        return next.run(req).await;
+    };
+    let start = Instant::now();
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let uri = crate::redact_ext::redact_query(req.uri());
+    let response = next.run(req).await;
+    let elapsed = start.elapsed();
+    if elapsed > threshold {
+        warn!(
+            message = format!("Slow request: {} {} - {}ms", method, uri, elapsed.as_millis()),
+            event = "slow_request",
+            method = %method,
+            uri = %uri,
+            doc_id = doc_id_of_path(&path).unwrap_or_default(),
+            status = %response.status(),
+            duration_ms = elapsed.as_millis() as u64,
+            threshold_ms = threshold.as_millis() as u64
+        );
+    }
+    response
+}
+
+/// Log a WebSocket message whose handling took longer than the message threshold.
+pub fn check_slow_message(
+    thresholds: &SlowThresholds,
+    doc_id: &str,
+    msg: &[u8],
+    elapsed: Duration,
+) {
+    if is_slow(thresholds.message, elapsed) {
+        warn!(
+            message = format!("Slow WebSocket message - {}ms", elapsed.as_millis()),
+            event = "slow_message",
+            doc_id = %doc_id,
+            message_kind = message_kind(msg),
+            size = msg.len(),
+            duration_ms = elapsed.as_millis() as u64,
+            threshold_ms = thresholds.message.unwrap_or_default().as_millis() as u64
+        );
+    }
+}
+
+/// Log a persist that took longer than the persist threshold.
+pub fn check_slow_persist(
+    thresholds: &SlowThresholds,
+    doc_id: &str,
+    size_bytes: usize,
+    succeeded: bool,
+    elapsed: Duration,
+) {
+    if is_slow(thresholds.persist, elapsed) {
+        warn!(
+            message = format!("Slow persist - {}ms", elapsed.as_millis()),
+            event = "slow_persist",
+            doc_id = %doc_id,
+            size_bytes = size_bytes,
+            succeeded = succeeded,
+            duration_ms = elapsed.as_millis() as u64,
+            threshold_ms = thresholds.persist.unwrap_or_default().as_millis() as u64
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_requests_are_attributed_to_their_document() {
+        assert_eq!(doc_id_of_path("/d/abc/update"), Some("abc"));
+        assert_eq!(doc_id_of_path("/d/abc"), Some("abc"));
+        assert_eq!(doc_id_of_path("/collab/d/abc/ws/abc"), Some("abc"));
+        assert_eq!(doc_id_of_path("/doc/abc/auth"), Some("abc"));
+        assert_eq!(doc_id_of_path("/doc/ws/abc"), Some("abc"));
+        assert_eq!(doc_id_of_path("/doc/new"), None);
+        assert_eq!(doc_id_of_path("/ready"), None);
+
+        assert_eq!(message_kind(&[MSG_SYNC, MSG_SYNC_UPDATE, 1]), "sync_update");
+        assert_eq!(message_kind(&[MSG_AWARENESS, 1]), "awareness");
+        assert_eq!(message_kind(&[]), "other");
+
+        let thresholds = SlowThresholds {
+            persist: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        assert!(is_slow(thresholds.persist, Duration::from_millis(101)));
+        assert!(!is_slow(thresholds.persist, Duration::from_millis(100)));
+        assert!(!is_slow(thresholds.message, Duration::from_secs(60)));
+    }
+}
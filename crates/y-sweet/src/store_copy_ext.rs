@@ -0,0 +1,281 @@
+//! Copying documents between stores.
+//!
+//! Every object of a document (snapshot, backup, shards, update log, metadata, subdocuments
+//! and assets) is copied as it is, so a copy can be loaded from the destination exactly as
+//! from the source. The snapshot is written last, since stores list a document once it has
+//! one: a copy that fails midway does not show up as a document in the destination.
+//!
+//! With verification, every object written is read back and compared with the source, and
+//! the copied document is then verified the way `verify_ext` does. A dry run only lists the
+//! objects that would be written, without reading or writing any of them.
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures::StreamExt;
+use serde::Serialize;
+use std::sync::Arc;
+use y_sweet_core::{snapshot_ext::snapshot_key, store::Store};
+
+use crate::verify_ext::verify_doc;
+
+/// Default number of documents copied concurrently.
+pub const DEFAULT_COPY_CONCURRENCY: usize = 8;
+
+#[derive(Clone, Copy, Debug)]
+pub struct StoreCopyOptions {
+    /// Number of documents copied concurrently.
+    pub concurrency: usize,
+    /// Read every object back and verify the copied documents.
+    pub verify: bool,
+    /// Skip documents that already have a snapshot in the destination.
+    pub skip_existing: bool,
+    /// Only list the objects that would be written.
+    pub dry_run: bool,
+}
+
+impl Default for StoreCopyOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: DEFAULT_COPY_CONCURRENCY,
+            verify: true,
+            skip_existing: false,
+            dry_run: false,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DocCopyOutcome {
+    pub doc_id: String,
+    /// Whether the document was skipped because it already exists in the destination.
+    pub skipped: bool,
+    pub objects: usize,
+    /// Bytes written, not measured by dry runs.
+    pub bytes: usize,
+    /// Keys of the objects that would be written, only listed for dry runs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub written_objects: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct StoreCopyReport {
+    pub results: Vec<DocCopyOutcome>,
+    pub copied: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub bytes: usize,
+}
+
+/// Keys of every object of `doc_id`.
+pub async fn list_doc_objects(store: &dyn Store, doc_id: &str) -> Result<Vec<String>> {
+    let mut objects = Vec::new();
+    let mut dirs = vec![format!("{}/", doc_id)];
+    while let Some(dir) = dirs.pop() {
+        for name in store.list_objects(&dir).await? {
+            let key = format!("{}{}", dir, name);
+            // Stores listing one level at a time return directories as names too
+            if !name.contains('/') && store.exists(&format!("{}/", key)).await? {
+                dirs.push(format!("{}/", key));
+            } else {
+                objects.push(key);
+            }
+        }
+    }
+    Ok(objects)
+}
+
+/// Copy every object of `doc_id` from `from` to `to`.
+pub async fn copy_doc(
+    from: &Arc<Box<dyn Store>>,
+    to: &Arc<Box<dyn Store>>,
+    doc_id: &str,
+    options: StoreCopyOptions,
+) -> Result<DocCopyOutcome> {
+    let snapshot = snapshot_key(doc_id);
+    if options.skip_existing && to.exists(&snapshot).await? {
+        return Ok(DocCopyOutcome {
+            doc_id: doc_id.to_string(),
+            skipped: true,
+            objects: 0,
+            bytes: 0,
+            written_objects: None,
+            error: None,
+        });
+    }
+
+    let mut keys = list_doc_objects(from.as_ref().as_ref(), doc_id)
+        .await
+        .context("Failed to list the objects of the document")?;
+    keys.retain(|key| key != &snapshot);
+    keys.push(snapshot);
+
+    if options.dry_run {
+        return Ok(DocCopyOutcome {
+            doc_id: doc_id.to_string(),
+            skipped: false,
+            objects: keys.len(),
+            bytes: 0,
+            written_objects: Some(keys),
+            error: None,
+        });
+    }
+
+    let mut objects = 0;
+    let mut bytes = 0;
+    for key in keys {
+        // Removed since it was listed
+        let Some(value) = from.get(&key).await? else {
+            continue;
+        };
+        let len = value.len();
+        let expected = options.verify.then(|| value.clone());
+        to.set(&key, value)
+            .await
+            .with_context(|| format!("Failed to write {}", key))?;
+        if let Some(expected) = expected {
+            let written = to
+                .get(&key)
+                .await
+                .with_context(|| format!("Failed to read back {}", key))?;
+            if written.as_deref() != Some(expected.as_slice()) {
+                bail!("{} differs from the source after copying", key);
+            }
+        }
+        objects += 1;
+        bytes += len;
+    }
+
+    if options.verify {
+        let result = verify_doc(to, doc_id).await;
+        if let Some(error) = result.error {
+            return Err(anyhow!("The copy failed verification: {}", error));
+        }
+    }
+
+    Ok(DocCopyOutcome {
+        doc_id: doc_id.to_string(),
+        skipped: false,
+        objects,
+        bytes,
+        written_objects: None,
+        error: None,
+    })
+}
+
+/// Copy `doc_ids` from `from` to `to`, calling `progress` with each outcome and the number
+/// of documents done so far.
+pub async fn copy_store(
+    from: &Arc<Box<dyn Store>>,
+    to: &Arc<Box<dyn Store>>,
+    doc_ids: Vec<String>,
+    options: StoreCopyOptions,
+    progress: impl Fn(&DocCopyOutcome, usize),
+) -> StoreCopyReport {
+    let mut results = Vec::with_capacity(doc_ids.len());
+    let mut copies = futures::stream::iter(doc_ids)
+        .map(|doc_id| async move {
+            copy_doc(from, to, &doc_id, options)
+                .await
+                .unwrap_or_else(|e| DocCopyOutcome {
+                    doc_id,
+                    skipped: false,
+                    objects: 0,
+                    bytes: 0,
+                    written_objects: None,
+                    error: Some(format!("{:#}", e)),
+                })
+        })
+        .buffer_unordered(options.concurrency.max(1));
+    while let Some(outcome) = copies.next().await {
+        progress(&outcome, results.len() + 1);
+        results.push(outcome);
+    }
+
+    let skipped = results.iter().filter(|result| result.skipped).count();
+    let failed = results
+        .iter()
+        .filter(|result| result.error.is_some())
+        .count();
+    StoreCopyReport {
+        copied: results.len() - skipped - failed,
+        skipped,
+        failed,
+        bytes: results.iter().map(|result| result.bytes).sum(),
+        results,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stores::filesystem::FileSystemStore;
+    use y_sweet_core::doc_sync_ext::DocWithSyncKv;
+    use yrs::{Text, Transact};
+
+    fn store(name: &str) -> Arc<Box<dyn Store>> {
+        let path = std::env::temp_dir().join(format!("y-sweet-{}-{}", name, nanoid::nanoid!()));
+        Arc::new(Box::new(FileSystemStore::new(path).unwrap()))
+    }
+
+    #[tokio::test]
+    async fn test_copies_documents_with_their_assets() {
+        let from = store("copy-from");
+        let to = store("copy-to");
+        for doc_id in ["a", "b"] {
+            let doc = DocWithSyncKv::new(doc_id, Some(from.clone()), || {}, false)
+                .await
+                .unwrap();
+            {
+                let awareness = doc.awareness();
+                let awareness = awareness.write().unwrap();
+                let text = awareness.doc().get_or_insert_text("text");
+                text.insert(&mut awareness.doc().transact_mut(), 0, doc_id);
+            }
+            doc.sync_kv().persist().await.unwrap();
+        }
+        from.set("a/assets/image.png", b"image".to_vec())
+            .await
+            .unwrap();
+
+        let dry_run = StoreCopyOptions {
+            dry_run: true,
+            ..StoreCopyOptions::default()
+        };
+        let report = copy_store(&from, &to, vec!["a".to_string()], dry_run, |_, _| {}).await;
+        assert_eq!(
+            report.results[0].written_objects.as_deref(),
+            Some(
+                &[
+                    "a/assets/image.png".to_string(),
+                    "a/data.ysweet".to_string()
+                ][..]
+            )
+        );
+        assert!(!to.exists("a/data.ysweet").await.unwrap());
+
+        let report = copy_store(
+            &from,
+            &to,
+            vec!["a".to_string(), "b".to_string()],
+            StoreCopyOptions::default(),
+            |_, _| {},
+        )
+        .await;
+        assert_eq!(report.copied, 2, "{:?}", report.results);
+        assert_eq!(
+            to.get("a/assets/image.png").await.unwrap(),
+            Some(b"image".to_vec())
+        );
+        assert!(verify_doc(&to, "b").await.ok);
+
+        let options = StoreCopyOptions {
+            skip_existing: true,
+            ..StoreCopyOptions::default()
+        };
+        let report = copy_store(&from, &to, vec!["a".to_string()], options, |_, _| {}).await;
+        assert_eq!(report.skipped, 1);
+    }
+}
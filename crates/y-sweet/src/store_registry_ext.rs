@@ -0,0 +1,273 @@
+//! Opening stores from their URL.
+//!
+//! Commands that take a store (`--store`, `serve`, `copy-store`, ...) open it from a URL
+//! whose scheme picks the implementation: `s3://bucket/prefix` for S3, and `file://` URLs
+//! or plain paths for the filesystem. Embedders add their own backends by registering a
+//! factory for a scheme with [register_store_scheme], after which `mybackend://...` opens
+//! through it wherever a store URL is accepted.
+//!
+//! The `s3` scheme is only registered when the crate is built with the `s3` feature.
+
+use anyhow::{anyhow, Result};
+use std::{
+    collections::HashMap,
+    env,
+    future::Future,
+    path::PathBuf,
+    pin::Pin,
+    sync::{Arc, OnceLock, RwLock},
+};
+use url::Url;
+#[cfg(feature = "s3")]
+use y_sweet_core::store::s3::{S3Config, S3Store};
+use y_sweet_core::store::Store;
+
+use crate::stores::filesystem::FileSystemStore;
+
+#[cfg(feature = "s3")]
+pub const DEFAULT_S3_REGION: &str = "us-east-1";
+
+#[cfg(feature = "s3")]
+const S3_ACCESS_KEY_ID: &str = "AWS_ACCESS_KEY_ID";
+#[cfg(feature = "s3")]
+const S3_SECRET_ACCESS_KEY: &str = "AWS_SECRET_ACCESS_KEY";
+#[cfg(feature = "s3")]
+const S3_SESSION_TOKEN: &str = "AWS_SESSION_TOKEN";
+#[cfg(feature = "s3")]
+const S3_REGION: &str = "AWS_REGION";
+#[cfg(feature = "s3")]
+const S3_ENDPOINT: &str = "AWS_ENDPOINT_URL_S3";
+#[cfg(feature = "s3")]
+const S3_USE_PATH_STYLE: &str = "AWS_S3_USE_PATH_STYLE";
+
+/// What a factory opens a store from.
+pub struct StoreSpec {
+    pub url: Url,
+    /// Prefix of the environment variables configuring the store, e.g. `Y_SWEET_FROM_` for
+    /// the source of `copy-store`. Empty for most commands.
+    pub env_prefix: String,
+}
+
+impl StoreSpec {
+    /// Reads `name` from the environment, preferring the variable prefixed with the
+    /// [StoreSpec::env_prefix].
+    pub fn env(&self, name: &str) -> Result<String, env::VarError> {
+        prefixed_env(&self.env_prefix, name)
+    }
+}
+
+pub type StoreFuture = Pin<Box<dyn Future<Output = Result<Box<dyn Store>>> + Send>>;
+
+/// Opens a store from its [StoreSpec].
+pub type StoreFactory = Arc<dyn Fn(StoreSpec) -> StoreFuture + Send + Sync>;
+
+/// The store factories, by URL scheme.
+#[derive(Clone)]
+pub struct StoreRegistry {
+    factories: HashMap<String, StoreFactory>,
+}
+
+impl Default for StoreRegistry {
+    /// A registry of the built-in schemes, `s3` (with the `s3` feature) and `file`.
+    fn default() -> Self {
+        let mut registry = Self {
+            factories: HashMap::new(),
+        };
+        #[cfg(feature = "s3")]
+        registry.register("s3", |spec| Box::pin(open_s3(spec)));
+        registry.register("file", |spec| Box::pin(open_file(spec)));
+        registry
+    }
+}
+
+impl StoreRegistry {
+    /// Open stores whose URL has `scheme` with `factory`, replacing the factory registered
+    /// for it before, if any. Schemes are case-insensitive.
+    pub fn register<F>(&mut self, scheme: &str, factory: F)
+    where
+        F: Fn(StoreSpec) -> StoreFuture + Send + Sync + 'static,
+    {
+        self.factories
+            .insert(scheme.to_ascii_lowercase(), Arc::new(factory));
+    }
+
+    pub fn schemes(&self) -> Vec<&str> {
+        let mut schemes: Vec<&str> = self.factories.keys().map(String::as_str).collect();
+        schemes.sort();
+        schemes
+    }
+
+    /// Open the store at `store_path`, a URL or a filesystem path.
+    pub async fn open(&self, store_path: &str, env_prefix: &str) -> Result<Box<dyn Store>> {
+        let Some(url) = parse_store_url(store_path) else {
+            return Ok(Box::new(FileSystemStore::new(PathBuf::from(store_path))?));
+        };
+        let factory = self.factories.get(url.scheme()).ok_or_else(|| {
+            anyhow!(
+                "Unknown store scheme {:?}, expected one of: {}",
+                url.scheme(),
+                self.schemes().join(", ")
+            )
+        })?;
+        factory(StoreSpec {
+            url,
+            env_prefix: env_prefix.to_string(),
+        })
+        .await
+    }
+}
+
+/// The URL of a store, or None for a filesystem path. Single-letter schemes are Windows
+/// drives (`C:\stores`), not URLs.
+fn parse_store_url(store_path: &str) -> Option<Url> {
+    if !store_path.contains("://") {
+        return None;
+    }
+    Url::parse(store_path)
+        .ok()
+        .filter(|url| url.scheme().len() > 1)
+}
+
+fn registry() -> &'static RwLock<StoreRegistry> {
+    static REGISTRY: OnceLock<RwLock<StoreRegistry>> = OnceLock::new();
+    REGISTRY.get_or_init(|| RwLock::new(StoreRegistry::default()))
+}
+
+/// Register `factory` for `scheme` in the registry of the process, the one the CLI opens
+/// stores with.
+pub fn register_store_scheme<F>(scheme: &str, factory: F)
+where
+    F: Fn(StoreSpec) -> StoreFuture + Send + Sync + 'static,
+{
+    registry().write().unwrap().register(scheme, factory);
+}
+
+/// Open the store at `store_path` with the registry of the process.
+pub async fn open_store(store_path: &str, env_prefix: &str) -> Result<Box<dyn Store>> {
+    // Cloned so the lock isn't held across the factory
+    let registry = registry().read().unwrap().clone();
+    registry.open(store_path, env_prefix).await
+}
+
+/// Reads `name` from the environment, preferring the variable prefixed with `env_prefix`.
+fn prefixed_env(env_prefix: &str, name: &str) -> Result<String, env::VarError> {
+    env::var(format!("{}{}", env_prefix, name)).or_else(|_| env::var(name))
+}
+
+/// The configuration of an S3 store, with credentials and endpoint from the `AWS_*`
+/// environment variables (preferring those prefixed with `env_prefix`).
+#[cfg(feature = "s3")]
+pub fn s3_config_from_env(
+    bucket: String,
+    prefix: Option<String>,
+    env_prefix: &str,
+) -> Result<S3Config> {
+    let env = |name: &str| prefixed_env(env_prefix, name);
+    let use_path_style = env(S3_USE_PATH_STYLE).ok();
+    let path_style = if let Some(use_path_style) = use_path_style {
+        if use_path_style.to_lowercase() == "true" {
+            true
+        } else if use_path_style.to_lowercase() == "false" || use_path_style.is_empty() {
+            false
+        } else {
+            anyhow::bail!(
+                "If AWS_S3_USE_PATH_STYLE is set, it must be either \"true\" or \"false\""
+            )
+        }
+    } else {
+        false
+    };
+
+    Ok(S3Config {
+        key: env(S3_ACCESS_KEY_ID)
+            .map_err(|_| anyhow::anyhow!("{} env var not supplied", S3_ACCESS_KEY_ID))?,
+        region: env(S3_REGION).unwrap_or_else(|_| DEFAULT_S3_REGION.to_string()),
+        endpoint: env(S3_ENDPOINT).unwrap_or_else(|_| {
+            format!(
+                "https://s3.dualstack.{}.amazonaws.com",
+                env(S3_REGION).unwrap_or_else(|_| DEFAULT_S3_REGION.to_string())
+            )
+        }),
+        secret: env(S3_SECRET_ACCESS_KEY)
+            .map_err(|_| anyhow::anyhow!("{} env var not supplied", S3_SECRET_ACCESS_KEY))?,
+        token: env(S3_SESSION_TOKEN).ok(),
+        bucket,
+        bucket_prefix: prefix,
+        // If the endpoint is overridden, we assume that the user wants path-style URLs.
+        path_style,
+    })
+}
+
+/// Open the S3 store of `bucket`, under `prefix` if given.
+#[cfg(feature = "s3")]
+pub async fn open_s3_store(
+    bucket: String,
+    prefix: Option<String>,
+    env_prefix: &str,
+) -> Result<Box<dyn Store>> {
+    let config = s3_config_from_env(bucket, prefix, env_prefix)?;
+    Ok(Box::new(S3Store::new(config).await?))
+}
+
+/// Open the S3 store of `bucket`, under `prefix` if given.
+#[cfg(not(feature = "s3"))]
+pub async fn open_s3_store(
+    _bucket: String,
+    _prefix: Option<String>,
+    _env_prefix: &str,
+) -> Result<Box<dyn Store>> {
+    Err(anyhow!(
+        "S3 stores are not supported, y-sweet was built without the s3 feature"
+    ))
+}
+
+#[cfg(feature = "s3")]
+async fn open_s3(spec: StoreSpec) -> Result<Box<dyn Store>> {
+    let bucket = spec
+        .url
+        .host_str()
+        .ok_or_else(|| anyhow!("Invalid S3 URL"))?
+        .to_owned();
+    let bucket_prefix = spec.url.path().trim_start_matches('/').to_owned();
+    let bucket_prefix = (!bucket_prefix.is_empty()).then_some(bucket_prefix); // "" => None
+    open_s3_store(bucket, bucket_prefix, &spec.env_prefix).await
+}
+
+async fn open_file(spec: StoreSpec) -> Result<Box<dyn Store>> {
+    let path = spec
+        .url
+        .to_file_path()
+        .map_err(|_| anyhow!("Invalid file URL {}", spec.url))?;
+    Ok(Box::new(FileSystemStore::new(path)?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_opens_registered_scheme() {
+        let dir = std::env::temp_dir().join(format!("y-sweet-registry-{}", nanoid::nanoid!()));
+        let mut registry = StoreRegistry::default();
+        let base = dir.clone();
+        registry.register("mybackend", move |spec| {
+            // Stores under a directory named after the host of the URL
+            let path = base.join(spec.url.host_str().unwrap_or_default());
+            Box::pin(async move { Ok(Box::new(FileSystemStore::new(path)?) as Box<dyn Store>) })
+        });
+
+        let store = registry.open("mybackend://docs", "").await.unwrap();
+        store.set("key", b"value".to_vec()).await.unwrap();
+        assert!(dir.join("docs").join("key").exists());
+
+        let store = registry
+            .open(&format!("file://{}", dir.join("files").display()), "")
+            .await
+            .unwrap();
+        store.set("key", b"value".to_vec()).await.unwrap();
+        assert!(dir.join("files").join("key").exists());
+
+        let error = registry.open("unknown://docs", "").await.err().unwrap();
+        assert!(error.to_string().contains("file, mybackend"));
+    }
+}
@@ -43,8 +43,10 @@ impl Store for FileSystemStore {
 
     async fn remove(&self, key: &str) -> Result<()> {
         let path = self.base_path.join(key);
-        remove_file(path)
-            .map_err(|_| StoreError::NotAuthorized("Error removing file.".to_string()))?;
+        remove_file(path).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => StoreError::DoesNotExist(key.to_string()),
+            _ => StoreError::NotAuthorized("Error removing file.".to_string()),
+        })?;
         Ok(())
     }
 
@@ -78,12 +80,10 @@ impl Store for FileSystemStore {
 
         let mut objects = Vec::new();
         if let Ok(entries) = std::fs::read_dir(path) {
-            for entry in entries {
-                if let Ok(entry) = entry {
-                    let file_name = entry.file_name();
-                    if let Ok(name) = file_name.into_string() {
-                        objects.push(name);
-                    }
+            for entry in entries.flatten() {
+                let file_name = entry.file_name();
+                if let Ok(name) = file_name.into_string() {
+                    objects.push(name);
                 }
             }
         }
@@ -140,4 +140,45 @@ impl Store for FileSystemStore {
 
         Ok(())
     }
+
+    async fn list_documents(&self, prefix: &str) -> Result<Vec<String>> {
+        // Each document is a directory directly below the base path containing data.ysweet
+        let entries = std::fs::read_dir(&self.base_path).map_err(|e| {
+            StoreError::ConnectionError(format!("Failed to read base directory: {}", e))
+        })?;
+
+        let mut doc_ids = Vec::new();
+        for entry in entries.flatten() {
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            if name.starts_with(prefix) && entry.path().join("data.ysweet").is_file() {
+                doc_ids.push(name);
+            }
+        }
+
+        Ok(doc_ids)
+    }
+
+    async fn last_modified(&self, key: &str) -> Result<Option<u64>> {
+        let modified = match std::fs::metadata(self.base_path.join(key)) {
+            Ok(metadata) => metadata
+                .modified()
+                .map_err(|e| StoreError::ConnectionError(e.to_string()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(StoreError::ConnectionError(e.to_string())),
+        };
+        Ok(modified
+            .duration_since(std::time::UNIX_EPOCH)
+            .ok()
+            .map(|since| since.as_millis() as u64))
+    }
+
+    async fn object_size(&self, key: &str) -> Result<Option<u64>> {
+        match std::fs::metadata(self.base_path.join(key)) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(StoreError::ConnectionError(e.to_string())),
+        }
+    }
 }
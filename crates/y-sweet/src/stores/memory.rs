@@ -0,0 +1,111 @@
+use async_trait::async_trait;
+use dashmap::DashMap;
+use std::sync::Arc;
+use y_sweet_core::store::{Result, Store};
+
+/// A store keeping objects in memory, for tests and throwaway servers. Clones share the
+/// same objects, so a test can hand the server a clone and inspect what it persists.
+#[derive(Default, Clone)]
+pub struct MemoryStore {
+    data: Arc<DashMap<String, Vec<u8>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keys of every object, sorted.
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self.data.iter().map(|entry| entry.key().clone()).collect();
+        keys.sort();
+        keys
+    }
+
+    pub fn get_object(&self, key: &str) -> Option<Vec<u8>> {
+        self.data.get(key).map(|value| value.clone())
+    }
+
+    pub fn insert_object(&self, key: &str, value: Vec<u8>) {
+        self.data.insert(key.to_owned(), value);
+    }
+}
+
+#[async_trait]
+impl Store for MemoryStore {
+    async fn init(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_object(key))
+    }
+
+    async fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
+        self.insert_object(key, value);
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> Result<()> {
+        self.data.remove(key);
+        Ok(())
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        Ok(self.data.contains_key(key))
+    }
+
+    async fn generate_upload_presigned_url(
+        &self,
+        key: &str,
+        _content_type: &str,
+    ) -> Result<String> {
+        Ok(format!("memory://localhost/{}", key))
+    }
+
+    async fn generate_download_presigned_url(&self, key: &str) -> Result<String> {
+        Ok(format!("memory://localhost/{}", key))
+    }
+
+    async fn list_objects(&self, prefix: &str) -> Result<Vec<String>> {
+        let prefix = format!("{}/", prefix.trim_end_matches('/'));
+        Ok(self
+            .data
+            .iter()
+            .filter_map(|entry| {
+                let relative_key = entry.key().strip_prefix(&prefix)?;
+                (!relative_key.is_empty()).then(|| relative_key.to_string())
+            })
+            .collect())
+    }
+
+    async fn copy_document(&self, source_doc_id: &str, destination_doc_id: &str) -> Result<()> {
+        let source_prefix = format!("{}/", source_doc_id);
+        let copies: Vec<(String, Vec<u8>)> = self
+            .data
+            .iter()
+            .filter_map(|entry| {
+                let relative_key = entry.key().strip_prefix(&source_prefix)?;
+                Some((
+                    format!("{}/{}", destination_doc_id, relative_key),
+                    entry.value().clone(),
+                ))
+            })
+            .collect();
+        for (key, value) in copies {
+            self.data.insert(key, value);
+        }
+        Ok(())
+    }
+
+    async fn list_documents(&self, prefix: &str) -> Result<Vec<String>> {
+        Ok(self
+            .data
+            .iter()
+            .filter_map(|entry| {
+                let doc_id = entry.key().strip_suffix("/data.ysweet")?;
+                (doc_id.starts_with(prefix) && !doc_id.contains('/')).then(|| doc_id.to_string())
+            })
+            .collect())
+    }
+}
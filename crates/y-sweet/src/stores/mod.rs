@@ -1 +1,3 @@
 pub mod filesystem;
+pub mod memory;
+pub mod routed;
@@ -0,0 +1,278 @@
+//! A store routing each document to one of several stores by the prefix of its ID, e.g. to
+//! keep the documents of EU customers in an EU bucket and the others in a US bucket.
+//!
+//! Objects are routed by the document they belong to: the first segment of their key, or
+//! the second for the namespaces of the server (`.ttl`, `.archive`, `.lease`, ...), whose
+//! names start with a dot. Listings that are not about one document, like the documents of
+//! the store or the TTL index, are merged from every store.
+
+use anyhow::{anyhow, Context};
+use async_trait::async_trait;
+use std::collections::BTreeSet;
+use y_sweet_core::{
+    snapshot_ext::snapshot_key,
+    store::{Result, Store, StoreError},
+};
+
+use crate::store_copy_ext::list_doc_objects;
+use crate::store_registry_ext::open_store;
+
+/// The document an object belongs to, or None if `key` is not the key of an object of a
+/// document (a prefix shared by several documents).
+fn doc_id_of(key: &str) -> Option<&str> {
+    match key.strip_prefix('.') {
+        // `.ttl/{doc_id}`, `.archive/{doc_id}/data.ysweet`
+        Some(namespaced) => {
+            let rest = namespaced.split_once('/')?.1;
+            let doc_id = rest.split_once('/').map_or(rest, |(doc_id, _)| doc_id);
+            (!doc_id.is_empty()).then_some(doc_id)
+        }
+        None => key.split_once('/').map(|(doc_id, _)| doc_id),
+    }
+}
+
+/// Stores documents whose ID starts with the prefix of a route in the store of the route,
+/// and other documents in the default store. The longest matching prefix wins.
+pub struct RoutedStore {
+    routes: Vec<(String, Box<dyn Store>)>,
+    default: Box<dyn Store>,
+}
+
+impl RoutedStore {
+    pub fn new(routes: Vec<(String, Box<dyn Store>)>, default: Box<dyn Store>) -> Self {
+        let mut routes = routes;
+        routes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        Self { routes, default }
+    }
+
+    /// Open the stores of `routes`, each `PREFIX=STORE` with a store URL or path. A prefix
+    /// may end with `*`, as in `eu-*`.
+    pub async fn open(routes: &[String], default: Box<dyn Store>) -> anyhow::Result<Self> {
+        let mut opened = Vec::with_capacity(routes.len());
+        for route in routes {
+            let (prefix, store_path) = route
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Invalid store route {:?}, expected PREFIX=STORE", route))?;
+            let prefix = prefix.trim().trim_end_matches('*');
+            if prefix.is_empty() {
+                return Err(anyhow!("Store route {:?} has an empty prefix", route));
+            }
+            let store = open_store(store_path.trim(), "")
+                .await
+                .with_context(|| format!("Failed to open the store of route {:?}", route))?;
+            opened.push((prefix.to_string(), store));
+        }
+        Ok(Self::new(opened, default))
+    }
+
+    /// The store of the document `doc_id`.
+    pub fn store_for(&self, doc_id: &str) -> &dyn Store {
+        self.routes
+            .iter()
+            .find(|(prefix, _)| doc_id.starts_with(prefix.as_str()))
+            .map(|(_, store)| store.as_ref())
+            .unwrap_or(self.default.as_ref())
+    }
+
+    /// The store of the object at `key`, or None if it is not an object of a document.
+    fn route(&self, key: &str) -> Option<&dyn Store> {
+        doc_id_of(key).map(|doc_id| self.store_for(doc_id))
+    }
+
+    fn route_key(&self, key: &str) -> Result<&dyn Store> {
+        self.route(key)
+            .ok_or_else(|| StoreError::ConnectionError(format!("Key {:?} has no document", key)))
+    }
+
+    fn stores(&self) -> impl Iterator<Item = &dyn Store> {
+        self.routes
+            .iter()
+            .map(|(_, store)| store.as_ref())
+            .chain(std::iter::once(self.default.as_ref()))
+    }
+}
+
+#[async_trait]
+impl Store for RoutedStore {
+    async fn init(&self) -> Result<()> {
+        for store in self.stores() {
+            store.init().await?;
+        }
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.route_key(key)?.get(key).await
+    }
+
+    async fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
+        self.route_key(key)?.set(key, value).await
+    }
+
+    async fn remove(&self, key: &str) -> Result<()> {
+        self.route_key(key)?.remove(key).await
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        match self.route(key) {
+            Some(store) => store.exists(key).await,
+            None => {
+                for store in self.stores() {
+                    if store.exists(key).await? {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+        }
+    }
+
+    async fn generate_upload_presigned_url(&self, key: &str, content_type: &str) -> Result<String> {
+        self.route_key(key)?
+            .generate_upload_presigned_url(key, content_type)
+            .await
+    }
+
+    async fn generate_download_presigned_url(&self, key: &str) -> Result<String> {
+        self.route_key(key)?
+            .generate_download_presigned_url(key)
+            .await
+    }
+
+    async fn generate_download_presigned_url_with_disposition(
+        &self,
+        key: &str,
+        content_disposition: &str,
+    ) -> Result<String> {
+        self.route_key(key)?
+            .generate_download_presigned_url_with_disposition(key, content_disposition)
+            .await
+    }
+
+    async fn list_objects(&self, prefix: &str) -> Result<Vec<String>> {
+        if let Some(store) = self.route(prefix) {
+            return store.list_objects(prefix).await;
+        }
+        let mut names = BTreeSet::new();
+        for store in self.stores() {
+            match store.list_objects(prefix).await {
+                Ok(listed) => names.extend(listed),
+                Err(StoreError::DoesNotExist(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(names.into_iter().collect())
+    }
+
+    async fn copy_document(&self, source_doc_id: &str, destination_doc_id: &str) -> Result<()> {
+        let from = self.route_key(&format!("{}/", source_doc_id))?;
+        let to = self.route_key(&format!("{}/", destination_doc_id))?;
+        if std::ptr::addr_eq(from, to) {
+            return from.copy_document(source_doc_id, destination_doc_id).await;
+        }
+
+        // Across stores, object by object, with the snapshot last so that a copy failing
+        // midway is not listed as a document
+        let keys = list_doc_objects(from, source_doc_id)
+            .await
+            .map_err(|e| StoreError::ConnectionError(e.to_string()))?;
+        let snapshot = snapshot_key(source_doc_id);
+        let (snapshots, others): (Vec<String>, Vec<String>) =
+            keys.into_iter().partition(|key| *key == snapshot);
+        let source_prefix = format!("{}/", source_doc_id);
+        for key in others.into_iter().chain(snapshots) {
+            let Some(value) = from.get(&key).await? else {
+                continue;
+            };
+            let name = key.strip_prefix(&source_prefix).unwrap_or(&key);
+            to.set(&format!("{}/{}", destination_doc_id, name), value)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn list_documents(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut doc_ids = BTreeSet::new();
+        for store in self.stores() {
+            for doc_id in store.list_documents(prefix).await? {
+                // Documents left in a store they are no longer routed to are not listed
+                if std::ptr::addr_eq(self.store_for(&doc_id), store) {
+                    doc_ids.insert(doc_id);
+                }
+            }
+        }
+        Ok(doc_ids.into_iter().collect())
+    }
+
+    async fn last_modified(&self, key: &str) -> Result<Option<u64>> {
+        self.route_key(key)?.last_modified(key).await
+    }
+
+    async fn object_size(&self, key: &str) -> Result<Option<u64>> {
+        self.route_key(key)?.object_size(key).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stores::memory::MemoryStore;
+
+    #[test]
+    fn test_objects_belong_to_their_document() {
+        assert_eq!(doc_id_of("eu-1/data.ysweet"), Some("eu-1"));
+        assert_eq!(doc_id_of("eu-1/assets/"), Some("eu-1"));
+        assert_eq!(doc_id_of(".ttl/eu-1"), Some("eu-1"));
+        assert_eq!(doc_id_of(".archive/eu-1/data.ysweet"), Some("eu-1"));
+        assert_eq!(doc_id_of(".ttl/"), None);
+        assert_eq!(doc_id_of("eu-"), None);
+    }
+
+    #[tokio::test]
+    async fn test_documents_are_routed_by_prefix() {
+        let (eu, eu_west, us) = (MemoryStore::new(), MemoryStore::new(), MemoryStore::new());
+        let store = RoutedStore::new(
+            vec![
+                ("eu-".to_string(), Box::new(eu.clone()) as Box<dyn Store>),
+                ("eu-west-".to_string(), Box::new(eu_west.clone())),
+            ],
+            Box::new(us.clone()),
+        );
+
+        store.set("eu-1/data.ysweet", b"a".to_vec()).await.unwrap();
+        store
+            .set("eu-west-1/data.ysweet", b"b".to_vec())
+            .await
+            .unwrap();
+        store.set("us-1/data.ysweet", b"c".to_vec()).await.unwrap();
+        store
+            .set(".archive/eu-2/data.ysweet", b"d".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            eu.keys(),
+            vec![".archive/eu-2/data.ysweet", "eu-1/data.ysweet"]
+        );
+        assert_eq!(eu_west.keys(), vec!["eu-west-1/data.ysweet"]);
+        assert_eq!(us.keys(), vec!["us-1/data.ysweet"]);
+
+        assert_eq!(
+            store.list_documents("").await.unwrap(),
+            vec!["eu-1", "eu-west-1", "us-1"]
+        );
+        assert_eq!(
+            store.get("us-1/data.ysweet").await.unwrap(),
+            Some(b"c".to_vec())
+        );
+
+        // Copies across stores write the objects to the store of the destination
+        store
+            .set("eu-1/assets/a.png", b"png".to_vec())
+            .await
+            .unwrap();
+        store.copy_document("eu-1", "us-2").await.unwrap();
+        assert_eq!(us.get_object("us-2/assets/a.png"), Some(b"png".to_vec()));
+        assert_eq!(us.get_object("us-2/data.ysweet"), Some(b"a".to_vec()));
+        assert!(!eu.keys().iter().any(|key| key.starts_with("us-2/")));
+    }
+}
@@ -0,0 +1,214 @@
+use anyhow::{anyhow, Result};
+use axum::http::StatusCode;
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::mpsc::Sender;
+use tracing::{error, info, warn};
+use y_sweet_core::{
+    api_types::{validate_doc_name, Authorization},
+    doc_connection_ext::DocConnection,
+    protocol_error_ext::InvalidMessage,
+    store::{Store, StoreError},
+    subdoc_ext::{
+        decode_subdoc_payload, encode_subdoc_message, subdoc_key, subdoc_prefix, MSG_SUBDOC,
+    },
+    sync::Message,
+};
+use yrs::updates::decoder::Decode;
+
+use crate::backpressure_ext::SendOverflow;
+use crate::server::{AppError, Server};
+
+/// Routes subdocument messages of one WebSocket connection.
+///
+/// Each subdocument gets its own [DocConnection], opened when the client first sends a
+/// message for it and closed together with the parent connection.
+pub(crate) struct SubdocRouter {
+    server_state: Arc<Server>,
+    parent_doc_id: String,
+    authorization: Authorization,
+    connections: HashMap<String, DocConnection>,
+    overflow: Arc<SendOverflow>,
+}
+
+impl SubdocRouter {
+    pub fn new(
+        server_state: Arc<Server>,
+        parent_doc_id: String,
+        authorization: Authorization,
+    ) -> Self {
+        Self {
+            server_state,
+            parent_doc_id,
+            authorization,
+            connections: HashMap::new(),
+            overflow: Arc::default(),
+        }
+    }
+
+    /// Report dropped replies to `overflow`, shared with the parent connection.
+    pub fn with_overflow(self, overflow: Arc<SendOverflow>) -> Self {
+        Self { overflow, ..self }
+    }
+
+    /// Handle a subdocument envelope. Replies are wrapped and sent through `send`.
+    pub async fn send(&mut self, data: &[u8], send: &Sender<Vec<u8>>) -> Result<()> {
+        let Message::Custom(MSG_SUBDOC, payload) = Message::decode_v1(data)? else {
+            return Err(anyhow!("Not a subdocument message"));
+        };
+        let (guid, message) = decode_subdoc_payload(&payload)?;
+
+        if !validate_doc_name(&guid) {
+            return Err(InvalidMessage(format!("invalid subdocument guid: {}", guid)).into());
+        }
+
+        if !self.connections.contains_key(&guid) {
+            let connection = self.open(&guid, send.clone()).await?;
+            self.connections.insert(guid.clone(), connection);
+        }
+
+        self.connections[&guid].send(&message).await
+    }
+
+    async fn open(&self, guid: &str, send: Sender<Vec<u8>>) -> Result<DocConnection> {
+        let key = subdoc_key(&self.parent_doc_id, guid);
+        let awareness = self.server_state.get_or_create_doc(&key).await?.awareness();
+
+        info!(
+            message = "Subdocument opened",
+            event = "subdoc_opened",
+            doc_id = %self.parent_doc_id,
+            guid = %guid
+        );
+
+        let guid = guid.to_string();
+        let overflow = self.overflow.clone();
+        let connection = DocConnection::new(awareness, self.authorization, move |bytes| {
+            if let Err(e) = send.try_send(encode_subdoc_message(&guid, bytes)) {
+                let error_message = format!("WebSocket message error: {}", e);
+                warn!(
+                    message = %error_message,
+                    event = "websocket_message_error",
+                    error = %e
+                );
+                overflow.mark_subdoc();
+            }
+        });
+        // Subdocuments are validated under their key, which starts with the parent's ID
+        Ok(match self.server_state.connection_update_validator() {
+            Some(validator) => connection.with_update_validator(&key, validator),
+            None => connection,
+        })
+    }
+}
+
+/// Unload the in-memory subdocuments of a document, flushing pending changes.
+pub(crate) async fn unload_subdocs(server_state: &Server, doc_id: &str) -> Result<(), AppError> {
+    let prefix = subdoc_prefix(doc_id);
+    let keys: Vec<String> = server_state
+        .docs
+        .iter()
+        .filter(|entry| entry.key().starts_with(&prefix))
+        .map(|entry| entry.key().clone())
+        .collect();
+
+    for key in keys {
+        if let Some((_, doc)) = server_state.docs.remove(&key) {
+            doc.sync_kv().shutdown();
+            doc.sync_kv().persist().await.map_err(|e| {
+                AppError(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    anyhow!("Failed to persist subdocument {}: {}", key, e),
+                )
+            })?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Keys of the stored subdocuments of a document, which `remove_subdoc_objects` removes.
+pub(crate) async fn list_subdoc_objects(
+    store: &Arc<Box<dyn Store>>,
+    doc_id: &str,
+) -> Result<Vec<String>, StoreError> {
+    let prefix = subdoc_prefix(doc_id);
+    let entries = match store.list_objects(&prefix).await {
+        Ok(entries) => entries,
+        Err(StoreError::DoesNotExist(_)) => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut guids: Vec<&str> = entries
+        .iter()
+        .filter_map(|entry| entry.split('/').next())
+        .filter(|guid| !guid.is_empty())
+        .collect();
+    guids.sort_unstable();
+    guids.dedup();
+
+    let mut keys = Vec::new();
+    for guid in guids {
+        let key = format!("{}{}/data.ysweet", prefix, guid);
+        if store.exists(&key).await? {
+            keys.push(key);
+        }
+    }
+    Ok(keys)
+}
+
+/// Remove the stored subdocuments of a document. Returns the number removed.
+pub(crate) async fn remove_subdoc_objects(
+    store: &Arc<Box<dyn Store>>,
+    doc_id: &str,
+) -> Result<usize, AppError> {
+    let prefix = subdoc_prefix(doc_id);
+    let entries = match store.list_objects(&prefix).await {
+        Ok(entries) => entries,
+        Err(StoreError::DoesNotExist(_)) => return Ok(0),
+        Err(e) => {
+            return Err(AppError(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                anyhow!("Failed to list subdocuments: {}", e),
+            ))
+        }
+    };
+
+    // Some stores list recursively (`{guid}/data.ysweet`), others one level (`{guid}`)
+    let mut guids: Vec<&str> = entries
+        .iter()
+        .filter_map(|entry| entry.split('/').next())
+        .filter(|guid| !guid.is_empty())
+        .collect();
+    guids.sort_unstable();
+    guids.dedup();
+
+    let mut removed = 0;
+    for guid in guids {
+        let key = format!("{}{}/data.ysweet", prefix, guid);
+        let result = match store.exists(&key).await {
+            Ok(true) => store.remove(&key).await.map(|_| true),
+            Ok(false) => Ok(false),
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(true) => removed += 1,
+            Ok(false) => {}
+            Err(e) => {
+                error!(
+                    message = "Failed to delete subdocument",
+                    event = "document_delete_failed",
+                    doc_id = %doc_id,
+                    guid = %guid,
+                    error = %e
+                );
+                return Err(AppError(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    anyhow!("Failed to delete subdocument {}: {}", guid, e),
+                ));
+            }
+        }
+    }
+
+    Ok(removed)
+}
@@ -0,0 +1,305 @@
+//! Live tail of a document over WebSocket.
+//!
+//! `y-sweet doc tail` connects to a running server the way a client would and prints every
+//! message it receives in a human-readable form: document updates with the clock ranges
+//! they insert, awareness changes with the state of each client, changes to the assets and
+//! protocol errors.
+//!
+//! The tail is read-only: it asks the server for the document state, but never sends
+//! updates or awareness states of its own, whatever the token allows.
+
+use anyhow::{anyhow, Context, Result};
+use futures::{SinkExt, StreamExt};
+use std::{collections::HashMap, time::Instant};
+use tokio_tungstenite::tungstenite;
+use url::Url;
+use y_sweet_core::{
+    asset_event_ext::{
+        decode_asset_deleted_payload, decode_asset_payload, MSG_ASSET, MSG_ASSET_DELETED,
+    },
+    protocol_error_ext::{decode_error_payload, MSG_ERROR},
+    subdoc_ext::MSG_SUBDOC,
+    sync::{awareness::Awareness, Message, MessageReader, SyncMessage},
+};
+use yrs::{
+    encoding::read::Cursor,
+    updates::{
+        decoder::{Decode, DecoderV1},
+        encoder::Encode,
+    },
+    Doc, ReadTxn, StateVector, Transact, Update,
+};
+
+use crate::convert::{read_root, root_names};
+
+/// Root type contents longer than this are cut when printed.
+const MAX_CONTENT_CHARS: usize = 200;
+
+/// The WebSocket URL of `doc_id` on the server at `server_url`, the way the client URL
+/// returned by `/doc/:doc_id/auth` is built.
+pub fn socket_url(server_url: &Url, doc_id: &str, token: Option<&str>) -> Result<Url> {
+    let mut url = server_url.clone();
+    let scheme = match url.scheme() {
+        "http" | "ws" => "ws",
+        "https" | "wss" => "wss",
+        scheme => return Err(anyhow!("Unsupported URL scheme {}", scheme)),
+    };
+    url.set_scheme(scheme)
+        .map_err(|_| anyhow!("Invalid server URL {}", server_url))?;
+    if !url.path().ends_with('/') {
+        url.set_path(&format!("{}/", url.path()));
+    }
+    let mut url = url.join(&format!("d/{doc_id}/ws/{doc_id}"))?;
+    if let Some(token) = token {
+        url.query_pairs_mut().append_pair("token", token);
+    }
+    Ok(url)
+}
+
+/// The state of a tailed document, kept to describe what each message changes.
+pub struct Tail {
+    awareness: Awareness,
+    /// Print the content of the root types changed by each update.
+    content: bool,
+    /// JSON content of each root type, as of the last update.
+    roots: HashMap<String, String>,
+}
+
+impl Tail {
+    pub fn new(content: bool) -> Self {
+        Self {
+            awareness: Awareness::new(Doc::new()),
+            content,
+            roots: HashMap::new(),
+        }
+    }
+
+    /// Lines describing the messages of a WebSocket payload.
+    pub fn describe(&mut self, payload: &[u8]) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut decoder = DecoderV1::new(Cursor::new(payload));
+        for message in MessageReader::new(&mut decoder) {
+            match message {
+                Ok(message) => self.describe_message(message, &mut lines),
+                Err(e) => {
+                    lines.push(format!("undecodable message: {}", e));
+                    break;
+                }
+            }
+        }
+        lines
+    }
+
+    fn describe_message(&mut self, message: Message, lines: &mut Vec<String>) {
+        match message {
+            Message::Sync(SyncMessage::SyncStep1(_)) => {
+                lines.push("sync step 1 (ignored, the tail sends nothing)".to_string())
+            }
+            Message::Sync(SyncMessage::SyncStep2(update)) => {
+                self.describe_update("document state", &update, lines)
+            }
+            Message::Sync(SyncMessage::Update(update)) => {
+                self.describe_update("update", &update, lines)
+            }
+            Message::Awareness(update) => {
+                let before = self.awareness.clients().clone();
+                if let Err(e) = self.awareness.apply_update(update) {
+                    lines.push(format!("awareness: cannot be applied: {}", e));
+                    return;
+                }
+                let after = self.awareness.clients();
+                for (client, state) in after {
+                    match before.get(client) {
+                        None => {
+                            lines.push(format!("awareness: client {} joined {}", client, state))
+                        }
+                        Some(previous) if previous != state => {
+                            lines.push(format!("awareness: client {} {}", client, state))
+                        }
+                        Some(_) => {}
+                    }
+                }
+                for client in before.keys().filter(|client| !after.contains_key(client)) {
+                    lines.push(format!("awareness: client {} left", client));
+                }
+            }
+            Message::AwarenessQuery => lines.push("awareness query".to_string()),
+            Message::Auth(Some(reason)) => lines.push(format!("permission denied: {}", reason)),
+            Message::Auth(None) => lines.push("permission granted".to_string()),
+            Message::Custom(MSG_ERROR, payload) => match decode_error_payload(&payload) {
+                Ok((code, reason)) => lines.push(format!("error {:?}: {}", code, reason)),
+                Err(e) => lines.push(format!("undecodable error: {}", e)),
+            },
+            Message::Custom(MSG_ASSET, payload) => match decode_asset_payload(&payload) {
+                Ok((asset_id, status)) => {
+                    let status = status
+                        .map(|status| format!("{:?}", status).to_lowercase())
+                        .unwrap_or_else(|| "unknown status".to_string());
+                    lines.push(format!("asset {}: {}", asset_id, status))
+                }
+                Err(e) => lines.push(format!("undecodable asset notification: {}", e)),
+            },
+            Message::Custom(MSG_ASSET_DELETED, payload) => {
+                match decode_asset_deleted_payload(&payload) {
+                    Ok(asset_id) => lines.push(format!("asset {} deleted", asset_id)),
+                    Err(e) => lines.push(format!("undecodable asset notification: {}", e)),
+                }
+            }
+            Message::Custom(MSG_SUBDOC, payload) => {
+                lines.push(format!("subdocument message, {} bytes", payload.len()))
+            }
+            Message::Custom(tag, payload) => {
+                lines.push(format!("custom message {}, {} bytes", tag, payload.len()))
+            }
+        }
+    }
+
+    fn describe_update(&mut self, kind: &str, update: &[u8], lines: &mut Vec<String>) {
+        let decoded = match Update::decode_v1(update) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                lines.push(format!(
+                    "{}: undecodable, {} bytes: {}",
+                    kind,
+                    update.len(),
+                    e
+                ));
+                return;
+            }
+        };
+
+        let doc = self.awareness.doc();
+        let before = doc.transact().state_vector();
+        doc.transact_mut().apply_update(decoded);
+        let after = doc.transact().state_vector();
+
+        let mut inserted: Vec<String> = after
+            .iter()
+            .filter(|(client, clock)| before.get(client) < **clock)
+            .map(|(client, clock)| format!("client {} {}..{}", client, before.get(client), clock))
+            .collect();
+        inserted.sort();
+        if inserted.is_empty() {
+            lines.push(format!("{}: {} bytes, no new items", kind, update.len()));
+        } else {
+            lines.push(format!(
+                "{}: {} bytes, inserted {}",
+                kind,
+                update.len(),
+                inserted.join(", ")
+            ));
+        }
+
+        if self.content {
+            self.describe_content(lines);
+        }
+    }
+
+    /// Print the root types whose content changed since the last update.
+    fn describe_content(&mut self, lines: &mut Vec<String>) {
+        let state = self
+            .awareness
+            .doc()
+            .transact()
+            .encode_state_as_update_v1(&StateVector::default());
+        let names = match root_names(&state) {
+            Ok(names) => names,
+            Err(e) => {
+                lines.push(format!("  content cannot be read: {}", e));
+                return;
+            }
+        };
+        for name in names {
+            let content = match read_root(&state, &name) {
+                Ok((_, value)) => value.to_string(),
+                Err(e) => format!("cannot be read: {}", e),
+            };
+            if self.roots.get(&name) == Some(&content) {
+                continue;
+            }
+            let shown: String = content.chars().take(MAX_CONTENT_CHARS).collect();
+            let cut = if shown.len() < content.len() {
+                "…"
+            } else {
+                ""
+            };
+            lines.push(format!("  {} = {}{}", name, shown, cut));
+            self.roots.insert(name, content);
+        }
+    }
+}
+
+/// Connect to `url` and print the messages received until the connection closes.
+pub async fn tail(url: &Url, content: bool) -> Result<()> {
+    // The token is left out of errors
+    let mut shown_url = url.clone();
+    shown_url.set_query(None);
+    let (mut socket, _) = tokio_tungstenite::connect_async(url.as_str())
+        .await
+        .with_context(|| format!("Failed to connect to {}", shown_url))?;
+
+    // Ask for the whole document, as a client with no state would
+    let sync_step_1 = Message::Sync(SyncMessage::SyncStep1(StateVector::default())).encode_v1();
+    socket
+        .send(tungstenite::Message::Binary(sync_step_1))
+        .await
+        .context("Failed to request the document state")?;
+
+    let started = Instant::now();
+    let mut tail = Tail::new(content);
+    while let Some(message) = socket.next().await {
+        let lines = match message.context("The connection failed")? {
+            tungstenite::Message::Binary(payload) => tail.describe(&payload),
+            tungstenite::Message::Close(frame) => {
+                let reason = frame
+                    .map(|frame| format!("{} {}", frame.code, frame.reason))
+                    .unwrap_or_default();
+                vec![format!("connection closed {}", reason)]
+            }
+            _ => continue,
+        };
+        let elapsed = started.elapsed().as_secs_f64();
+        for line in lines {
+            println!("[{:>9.3}s] {}", elapsed, line);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use yrs::Text;
+
+    #[test]
+    fn test_socket_url() {
+        let server = Url::parse("https://example.com/prefix").unwrap();
+        assert_eq!(
+            socket_url(&server, "doc", Some("abc")).unwrap().as_str(),
+            "wss://example.com/prefix/d/doc/ws/doc?token=abc"
+        );
+    }
+
+    #[test]
+    fn test_describes_updates_and_awareness() {
+        let doc = Doc::new();
+        let text = doc.get_or_insert_text("text");
+        text.insert(&mut doc.transact_mut(), 0, "hello");
+        let update = doc
+            .transact()
+            .encode_state_as_update_v1(&StateVector::default());
+
+        let mut tail = Tail::new(true);
+        let lines = tail.describe(&Message::Sync(SyncMessage::Update(update)).encode_v1());
+        assert_eq!(lines.len(), 2, "{:?}", lines);
+        assert!(lines[0].contains("inserted client"));
+        assert_eq!(lines[1], "  text = \"hello\"");
+
+        let mut awareness = Awareness::new(Doc::new());
+        awareness.set_local_state(r#"{"user":"ada"}"#);
+        let message = Message::Awareness(awareness.update().unwrap()).encode_v1();
+        let lines = tail.describe(&message);
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains("joined {\"user\":\"ada\"}"));
+    }
+}
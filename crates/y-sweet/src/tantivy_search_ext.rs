@@ -0,0 +1,216 @@
+//! Local full-text search index of documents, kept with tantivy in a directory of the
+//! server (`--search-index-dir`).
+//!
+//! Each document is one entry of the index, replaced whenever it is indexed again. The text
+//! is stored alongside the index for the snippets of the results, so the directory grows
+//! with the text of the documents; it can be removed at any time, and is rebuilt as
+//! documents are checkpointed.
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use std::{
+    ops::Bound,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use tantivy::{
+    collector::TopDocs,
+    directory::MmapDirectory,
+    doc,
+    query::{BooleanQuery, Occur, Query, QueryParser, RangeQuery},
+    schema::{Field, Schema, Value, STORED, STRING, TEXT},
+    Index, IndexReader, IndexWriter, ReloadPolicy, SnippetGenerator, TantivyDocument, Term,
+};
+use y_sweet_core::api_types_ext::SearchHit;
+
+use crate::search_ext::SearchIndex;
+
+/// Memory used by the writer to buffer documents before writing a segment.
+const WRITER_MEMORY_BYTES: usize = 50_000_000;
+
+/// Longest snippet of a result, in characters.
+const SNIPPET_MAX_CHARS: usize = 200;
+
+/// A tantivy index of the text of documents.
+#[derive(Clone)]
+pub struct TantivySearchIndex {
+    index: Index,
+    reader: IndexReader,
+    writer: Arc<Mutex<IndexWriter>>,
+    doc_id: Field,
+    text: Field,
+}
+
+impl TantivySearchIndex {
+    /// Open the index in `dir`, creating it if needed.
+    pub fn open(dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let directory = MmapDirectory::open(dir)?;
+        Self::new(Index::open_or_create(directory, Self::schema())?)
+    }
+
+    /// An index kept in memory, lost when the server stops.
+    pub fn in_memory() -> Result<Self> {
+        Self::new(Index::create_in_ram(Self::schema()))
+    }
+
+    fn schema() -> Schema {
+        let mut schema = Schema::builder();
+        schema.add_text_field("doc_id", STRING | STORED);
+        schema.add_text_field("text", TEXT | STORED);
+        schema.build()
+    }
+
+    fn new(index: Index) -> Result<Self> {
+        let schema = index.schema();
+        let doc_id = schema.get_field("doc_id")?;
+        let text = schema.get_field("text")?;
+        // Reloaded on each commit, so that searches see the documents just indexed
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::Manual)
+            .try_into()?;
+        let writer = index.writer(WRITER_MEMORY_BYTES)?;
+        Ok(Self {
+            index,
+            reader,
+            writer: Arc::new(Mutex::new(writer)),
+            doc_id,
+            text,
+        })
+    }
+
+    /// Replace the entry of `doc_id` with `text` (none if None), and commit.
+    fn write(&self, doc_id: &str, text: Option<&str>) -> Result<()> {
+        let mut writer = self
+            .writer
+            .lock()
+            .map_err(|_| anyhow!("The search index writer is poisoned"))?;
+        writer.delete_term(Term::from_field_text(self.doc_id, doc_id));
+        if let Some(text) = text {
+            writer.add_document(doc!(self.doc_id => doc_id, self.text => text))?;
+        }
+        writer.commit()?;
+        self.reader.reload()?;
+        Ok(())
+    }
+
+    fn search_blocking(
+        &self,
+        query: &str,
+        prefix: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<SearchHit>> {
+        let searcher = self.reader.searcher();
+        // Syntax errors only drop the offending part of the query
+        let (text_query, _) =
+            QueryParser::for_index(&self.index, vec![self.text]).parse_query_lenient(query);
+        let query: Box<dyn Query> = match prefix {
+            Some(prefix) => {
+                let upper = format!("{}{}", prefix, char::MAX);
+                let prefix_query = RangeQuery::new_str_bounds(
+                    "doc_id".to_string(),
+                    Bound::Included(prefix),
+                    Bound::Excluded(&upper),
+                );
+                Box::new(BooleanQuery::new(vec![
+                    (Occur::Must, text_query.box_clone()),
+                    (Occur::Must, Box::new(prefix_query)),
+                ]))
+            }
+            None => text_query.box_clone(),
+        };
+
+        let top_docs = searcher.search(&*query, &TopDocs::with_limit(limit))?;
+        let mut snippets = SnippetGenerator::create(&searcher, &*text_query, self.text)?;
+        snippets.set_max_num_chars(SNIPPET_MAX_CHARS);
+        let mut hits = Vec::with_capacity(top_docs.len());
+        for (score, address) in top_docs {
+            let document: TantivyDocument = searcher.doc(address)?;
+            let Some(doc_id) = document.get_first(self.doc_id).and_then(|v| v.as_str()) else {
+                continue;
+            };
+            let snippet = snippets.snippet_from_doc(&document);
+            let snippet = snippet.fragment().trim();
+            hits.push(SearchHit {
+                doc_id: doc_id.to_string(),
+                score,
+                snippet: (!snippet.is_empty()).then(|| snippet.to_string()),
+            });
+        }
+        Ok(hits)
+    }
+}
+
+#[async_trait]
+impl SearchIndex for TantivySearchIndex {
+    async fn index_doc(&self, doc_id: &str, text: &str) -> Result<()> {
+        let index = self.clone();
+        let doc_id = doc_id.to_string();
+        let text = text.to_string();
+        tokio::task::spawn_blocking(move || index.write(&doc_id, Some(&text))).await?
+    }
+
+    async fn remove_doc(&self, doc_id: &str) -> Result<()> {
+        let index = self.clone();
+        let doc_id = doc_id.to_string();
+        tokio::task::spawn_blocking(move || index.write(&doc_id, None)).await?
+    }
+
+    async fn search(
+        &self,
+        query: &str,
+        prefix: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<SearchHit>> {
+        let index = self.clone();
+        let query = query.to_string();
+        let prefix = prefix.map(|prefix| prefix.to_string());
+        tokio::task::spawn_blocking(move || index.search_blocking(&query, prefix.as_deref(), limit))
+            .await?
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_search_ranks_and_filters_by_prefix() {
+        let index = TantivySearchIndex::in_memory().unwrap();
+        index
+            .index_doc(
+                "team-a-notes",
+                "Quarterly planning: migrate the billing service",
+            )
+            .await
+            .unwrap();
+        index
+            .index_doc(
+                "team-b-notes",
+                "Billing retro. Billing alerts were too noisy",
+            )
+            .await
+            .unwrap();
+        index.index_doc("team-a-todo", "Buy milk").await.unwrap();
+
+        let hits = index.search("billing", None, 10).await.unwrap();
+        let doc_ids: Vec<_> = hits.iter().map(|hit| hit.doc_id.as_str()).collect();
+        assert_eq!(doc_ids, vec!["team-b-notes", "team-a-notes"]);
+        assert!(hits[1].snippet.as_deref().unwrap().contains("billing"));
+
+        let hits = index.search("billing", Some("team-a"), 10).await.unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].doc_id, "team-a-notes");
+
+        // Indexing again replaces the entry
+        index.index_doc("team-b-notes", "Retro").await.unwrap();
+        index.remove_doc("team-a-notes").await.unwrap();
+        assert!(index.search("billing", None, 10).await.unwrap().is_empty());
+        // Syntax errors are not fatal
+        assert_eq!(
+            index.search("retro AND (", None, 10).await.unwrap().len(),
+            1
+        );
+    }
+}
@@ -0,0 +1,512 @@
+//! Tenants: namespaces of documents served by one process.
+//!
+//! Each tenant has a server of its own, with its own documents, store and tokens, and the
+//! options of the main server. Its routes are served under `/t/{tenant_id}` (after the base
+//! path), or at the usual paths for requests carrying the [TENANT_HEADER], and the URLs it
+//! returns to clients include the tenant path. Documents of a tenant are garbage collected
+//! and reported on by its own server: `GET /tenants` lists the activity of every tenant.
+//!
+//! `y-sweet serve --tenants tenants.toml` reads the tenants from a file, each storing its
+//! documents under a prefix of the store of the server:
+//!
+//! ```toml
+//! [[tenants]]
+//! id = "acme"
+//! # Defaults to the tenant ID
+//! store_prefix = "customers/acme"
+//! # Defaults to the key of the server
+//! auth = "..."
+//!
+//! # Limits on all the documents of the tenant (see `quota_ext`)
+//! [tenants.quota]
+//! max_docs = 1000
+//! ```
+
+use anyhow::{bail, Context, Result};
+use axum::{
+    extract::{Request, State},
+    http::{uri::PathAndQuery, Uri},
+    Json, Router,
+};
+use axum_extra::typed_header::TypedHeader;
+use serde::Deserialize;
+#[cfg(feature = "config-files")]
+use std::path::Path;
+use std::{
+    collections::BTreeSet,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tower::ServiceExt;
+use y_sweet_core::{
+    api_types::validate_doc_name,
+    api_types_ext::{TenantStats, TenantsResponse},
+    auth::Authenticator,
+    auth_ext::AuthProvider,
+    store::Store,
+};
+
+use crate::events_ext::EventHandler;
+use crate::quota_ext::{Quota, QuotaRule};
+use crate::server::{AppError, Server};
+use crate::store_registry_ext::open_store;
+
+/// Header naming the tenant of a request sent to the usual paths.
+pub const TENANT_HEADER: &str = "x-y-sweet-tenant";
+
+/// A tenant of a server.
+pub struct Tenant {
+    /// Letters, digits, `-` and `_`, like document IDs.
+    pub id: String,
+    /// Where the documents of the tenant are persisted. Without a store, they only live in
+    /// memory.
+    pub store: Option<Box<dyn Store>>,
+    /// Verifies and issues the tokens of the tenant. Without one, the tenant takes the
+    /// tokens of the server.
+    pub auth_provider: Option<Arc<dyn AuthProvider>>,
+    /// Limits on the documents of the tenant.
+    pub quota: Option<Quota>,
+}
+
+/// A tenant in a tenants file.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TenantConfig {
+    pub id: String,
+    /// Prefix of the objects of the tenant in the store of the server. Defaults to the
+    /// tenant ID.
+    #[serde(default)]
+    pub store_prefix: Option<String>,
+    /// Private key of the tokens of the tenant. Defaults to the key of the server.
+    #[serde(default)]
+    pub auth: Option<String>,
+    #[serde(default)]
+    pub quota: Option<Quota>,
+}
+
+#[cfg(feature = "config-files")]
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TenantsFile {
+    tenants: Vec<TenantConfig>,
+}
+
+/// Read the tenants file at `path`, a TOML file (or YAML for `.yaml` and `.yml` files).
+#[cfg(feature = "config-files")]
+pub fn load_tenants(path: &Path) -> Result<Vec<TenantConfig>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read the tenants file {:?}", path))?;
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default();
+    let file: TenantsFile = match extension {
+        "yaml" | "yml" => serde_yaml::from_str(&content)
+            .with_context(|| format!("Invalid tenants file {:?}", path))?,
+        _ => {
+            toml::from_str(&content).with_context(|| format!("Invalid tenants file {:?}", path))?
+        }
+    };
+    Ok(file.tenants)
+}
+
+/// Open the stores and authenticators of `configs`, storing each tenant under its prefix
+/// of `store_path` if the server has a store.
+pub async fn open_tenants(
+    configs: &[TenantConfig],
+    store_path: Option<&str>,
+) -> Result<Vec<Tenant>> {
+    let mut tenants = Vec::with_capacity(configs.len());
+    for config in configs {
+        let store = match store_path {
+            Some(store_path) => {
+                let prefix = config.store_prefix.as_deref().unwrap_or(&config.id);
+                let path = format!(
+                    "{}/{}",
+                    store_path.trim_end_matches('/'),
+                    prefix.trim_matches('/')
+                );
+                let store = open_store(&path, "").await?;
+                store.init().await?;
+                Some(store)
+            }
+            None => None,
+        };
+        let auth_provider = match &config.auth {
+            Some(auth) => {
+                let authenticator = Authenticator::new(auth)
+                    .with_context(|| format!("Invalid auth key of tenant {:?}", config.id))?;
+                Some(Arc::new(authenticator) as Arc<dyn AuthProvider>)
+            }
+            None => None,
+        };
+        tenants.push(Tenant {
+            id: config.id.clone(),
+            store,
+            auth_provider,
+            quota: config.quota,
+        });
+    }
+    Ok(tenants)
+}
+
+/// The server of a tenant.
+pub struct TenantServer {
+    pub id: String,
+    pub server: Arc<Server>,
+    events: Arc<TenantEvents>,
+}
+
+/// The servers of the tenants of a server.
+#[derive(Default)]
+pub struct Tenants(Vec<TenantServer>);
+
+impl Tenants {
+    pub(crate) fn new(server: &Server, tenants: Vec<Tenant>) -> Result<Self> {
+        let mut ids = BTreeSet::new();
+        let mut servers = Vec::with_capacity(tenants.len());
+        for tenant in tenants {
+            if !validate_doc_name(&tenant.id) {
+                bail!("Invalid tenant ID {:?}", tenant.id);
+            }
+            if !ids.insert(tenant.id.clone()) {
+                bail!("Duplicate tenant ID {:?}", tenant.id);
+            }
+            let events = Arc::new(TenantEvents {
+                handler: server.event_handler().cloned(),
+                ..TenantEvents::default()
+            });
+            let mut tenant_server = server.tenant_server(
+                &tenant.id,
+                tenant.store,
+                tenant.auth_provider,
+                events.clone(),
+            );
+            if let Some(quota) = tenant.quota {
+                tenant_server = tenant_server.with_quotas(vec![QuotaRule::all(quota)]);
+            }
+            servers.push(TenantServer {
+                id: tenant.id,
+                server: Arc::new(tenant_server),
+                events,
+            });
+        }
+        Ok(Self(servers))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &TenantServer> {
+        self.0.iter()
+    }
+
+    /// The server of `tenant_id`.
+    pub fn get(&self, tenant_id: &str) -> Option<&Arc<Server>> {
+        self.0
+            .iter()
+            .find(|tenant| tenant.id == tenant_id)
+            .map(|tenant| &tenant.server)
+    }
+
+    /// The activity of every tenant.
+    pub fn stats(&self) -> Vec<TenantStats> {
+        self.0
+            .iter()
+            .map(|tenant| TenantStats {
+                tenant_id: tenant.id.clone(),
+                loaded_docs: tenant.server.docs.len(),
+                connections: tenant.server.connection_limits().total(),
+                docs_loaded: tenant.events.docs_loaded.load(Ordering::Relaxed),
+                docs_evicted: tenant.events.docs_evicted.load(Ordering::Relaxed),
+                docs_persisted: tenant.events.docs_persisted.load(Ordering::Relaxed),
+                updates_applied: tenant.events.updates_applied.load(Ordering::Relaxed),
+                failing_docs: tenant.server.persistence_health().failing_docs().len(),
+            })
+            .collect()
+    }
+}
+
+/// Counts the lifecycle events of the documents of a tenant, passing them on to the event
+/// handler of the server.
+#[derive(Default)]
+struct TenantEvents {
+    handler: Option<Arc<dyn EventHandler>>,
+    docs_loaded: AtomicU64,
+    docs_evicted: AtomicU64,
+    docs_persisted: AtomicU64,
+    updates_applied: AtomicU64,
+}
+
+impl EventHandler for TenantEvents {
+    fn doc_loaded(&self, doc_id: &str) {
+        self.docs_loaded.fetch_add(1, Ordering::Relaxed);
+        if let Some(handler) = &self.handler {
+            handler.doc_loaded(doc_id);
+        }
+    }
+
+    fn doc_persisted(&self, doc_id: &str) {
+        self.docs_persisted.fetch_add(1, Ordering::Relaxed);
+        if let Some(handler) = &self.handler {
+            handler.doc_persisted(doc_id);
+        }
+    }
+
+    fn doc_evicted(&self, doc_id: &str) {
+        self.docs_evicted.fetch_add(1, Ordering::Relaxed);
+        if let Some(handler) = &self.handler {
+            handler.doc_evicted(doc_id);
+        }
+    }
+
+    fn client_connected(
+        &self,
+        doc_id: &str,
+        authorization: y_sweet_core::api_types::Authorization,
+    ) {
+        if let Some(handler) = &self.handler {
+            handler.client_connected(doc_id, authorization);
+        }
+    }
+
+    fn client_disconnected(&self, doc_id: &str) {
+        if let Some(handler) = &self.handler {
+            handler.client_disconnected(doc_id);
+        }
+    }
+
+    fn update_applied(&self, doc_id: &str, update: &[u8]) {
+        self.updates_applied.fetch_add(1, Ordering::Relaxed);
+        if let Some(handler) = &self.handler {
+            handler.update_applied(doc_id, update);
+        }
+    }
+}
+
+/// Merge the routes of every tenant, selected by `select` and mounted under their tenant
+/// path, into `routes`, and send requests carrying the tenant header to them.
+pub(crate) fn with_tenant_routes(
+    server: &Server,
+    routes: Router,
+    select: impl Fn(&Arc<Server>) -> Router,
+) -> Router {
+    if server.tenants().is_empty() {
+        return routes;
+    }
+    let routes = server.tenants().iter().fold(routes, |routes, tenant| {
+        routes.merge(tenant.server.mount(select(&tenant.server)))
+    });
+    // Rewritten before routing, which layers of the router come after
+    let base_path = server.base_path().unwrap_or_default().to_string();
+    Router::new().fallback_service(
+        routes.map_request(move |request| route_tenant_header(request, &base_path)),
+    )
+}
+
+/// Move a request carrying the tenant header under the path of its tenant.
+fn route_tenant_header(mut request: Request, base_path: &str) -> Request {
+    let Some(tenant_id) = request
+        .headers()
+        .get(TENANT_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|tenant_id| validate_doc_name(tenant_id))
+    else {
+        return request;
+    };
+    let Some(rest) = request.uri().path().strip_prefix(base_path) else {
+        return request;
+    };
+    if !rest.starts_with('/') || rest.starts_with("/t/") {
+        return request;
+    }
+
+    let mut path_and_query = format!("{}/t/{}{}", base_path, tenant_id, rest);
+    if let Some(query) = request.uri().query() {
+        path_and_query.push('?');
+        path_and_query.push_str(query);
+    }
+    let mut parts = request.uri().clone().into_parts();
+    let Ok(path_and_query) = PathAndQuery::try_from(path_and_query) else {
+        return request;
+    };
+    parts.path_and_query = Some(path_and_query);
+    if let Ok(uri) = Uri::from_parts(parts) {
+        *request.uri_mut() = uri;
+    }
+    request
+}
+
+/// List the tenants of the server with their activity
+pub async fn list_tenants(
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+) -> Result<Json<TenantsResponse>, AppError> {
+    server_state.check_auth(auth_header)?;
+    Ok(Json(TenantsResponse {
+        tenants: server_state.tenants().stats(),
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stores::memory::MemoryStore;
+    use axum::{
+        body::Body,
+        http::{header, StatusCode},
+        response::Response,
+    };
+    use http_body_util::BodyExt;
+    use y_sweet_core::api_types::ClientToken;
+    use yrs::{Text, Transact};
+
+    async fn send(
+        server: &Arc<Server>,
+        method: &str,
+        path: &str,
+        token: &str,
+        tenant: Option<&str>,
+    ) -> Response {
+        let mut request = axum::http::Request::builder()
+            .method(method)
+            .uri(path)
+            .header(header::HOST, "localhost")
+            .header(header::AUTHORIZATION, format!("Bearer {}", token))
+            .header(header::CONTENT_TYPE, "application/json");
+        if let Some(tenant) = tenant {
+            request = request.header(TENANT_HEADER, tenant);
+        }
+        server
+            .routes()
+            .oneshot(request.body(Body::from("{}")).unwrap())
+            .await
+            .unwrap()
+    }
+
+    async fn json<T: serde::de::DeserializeOwned>(response: Response) -> T {
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_tenants_have_own_docs_stores_and_keys() {
+        let auth = Authenticator::gen_key().unwrap();
+        let acme_auth = Authenticator::gen_key().unwrap();
+        let acme_token = acme_auth.server_token();
+        let (acme_store, globex_store) = (MemoryStore::new(), MemoryStore::new());
+        let server = Arc::new(
+            Server::builder()
+                .authenticator(Some(Authenticator::new(&auth.private_key()).unwrap()))
+                .build()
+                .await
+                .unwrap()
+                .with_tenants(vec![
+                    Tenant {
+                        id: "acme".to_string(),
+                        store: Some(Box::new(acme_store.clone())),
+                        auth_provider: Some(Arc::new(acme_auth)),
+                        quota: None,
+                    },
+                    Tenant {
+                        id: "globex".to_string(),
+                        store: Some(Box::new(globex_store.clone())),
+                        auth_provider: None,
+                        quota: None,
+                    },
+                ])
+                .unwrap(),
+        );
+
+        let response = send(
+            &server,
+            "POST",
+            "/t/acme/doc/new",
+            &auth.server_token(),
+            None,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = send(&server, "POST", "/t/acme/doc/new", &acme_token, None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let new_doc: serde_json::Value = json(response).await;
+        let doc_id = new_doc["docId"].as_str().unwrap();
+
+        let response = send(
+            &server,
+            "POST",
+            &format!("/t/acme/doc/{}/auth", doc_id),
+            &acme_token,
+            None,
+        )
+        .await;
+        let client_token: ClientToken = json(response).await;
+        assert_eq!(
+            client_token.base_url.unwrap(),
+            format!("http://localhost/t/acme/d/{}", doc_id)
+        );
+
+        // Without a key of its own, a tenant takes the tokens of the server
+        let response = send(
+            &server,
+            "POST",
+            "/doc/new",
+            &auth.server_token(),
+            Some("globex"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        assert!(server.docs.is_empty());
+        for tenant in server.tenants().iter() {
+            let sync_kvs: Vec<_> = tenant
+                .server
+                .docs
+                .iter()
+                .map(|doc| {
+                    let awareness = doc.awareness();
+                    let awareness = awareness.write().unwrap();
+                    let text = awareness.doc().get_or_insert_text("text");
+                    text.insert(&mut awareness.doc().transact_mut(), 0, "hello");
+                    doc.sync_kv()
+                })
+                .collect();
+            for sync_kv in sync_kvs {
+                sync_kv.persist().await.unwrap();
+            }
+        }
+        assert_eq!(acme_store.keys(), vec![format!("{}/data.ysweet", doc_id)]);
+        assert_eq!(globex_store.keys().len(), 1);
+
+        let response = send(&server, "GET", "/tenants", &auth.server_token(), None).await;
+        let tenants: TenantsResponse = json(response).await;
+        let loaded: Vec<_> = tenants
+            .tenants
+            .iter()
+            .map(|tenant| {
+                (
+                    tenant.tenant_id.as_str(),
+                    tenant.loaded_docs,
+                    tenant.docs_loaded,
+                )
+            })
+            .collect();
+        assert_eq!(loaded, vec![("acme", 1, 1), ("globex", 1, 1)]);
+    }
+
+    #[test]
+    fn test_rejects_invalid_tenant_ids() {
+        let tenant = |id: &str| Tenant {
+            id: id.to_string(),
+            store: None,
+            auth_provider: None,
+            quota: None,
+        };
+        let server = Server::from_builder(Server::builder());
+        assert!(Tenants::new(&server, vec![tenant("a/b")]).is_err());
+        assert!(Tenants::new(&server, vec![tenant("a"), tenant("a")]).is_err());
+    }
+}
@@ -0,0 +1,307 @@
+//! Utilities for testing code built on y-sweet without binding sockets.
+//!
+//! A [TestServer] runs a [Server] backed by a [MemoryStore] and serves each connection over
+//! an in-memory duplex stream, through the same HTTP stack (and WebSocket upgrades) as a
+//! listener would. Its [TestClient] (with the `client` feature) speaks the sync protocol on
+//! such a connection, keeping a local copy of the document the way a Yjs provider does:
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use y_sweet::testing_ext::TestServer;
+//! use yrs::{GetString, Text, Transact, WriteTxn};
+//!
+//! let server = TestServer::new().await?;
+//! let doc_id = server.server().create_doc().await?;
+//! let mut alice = server.connect(&doc_id).await?;
+//! let mut bob = server.connect(&doc_id).await?;
+//!
+//! alice.update(|txn| txn.get_or_insert_text("text").insert(txn, 0, "hello")).await?;
+//! bob.wait_for(|doc| doc.get_or_insert_text("text").get_string(&doc.transact()) == "hello")
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use anyhow::{Context, Result};
+use axum::{
+    body::Bytes,
+    http::{header, HeaderMap, Method, Request, StatusCode},
+    Router,
+};
+use http_body_util::{BodyExt, Full};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
+use serde::{de::DeserializeOwned, Serialize};
+#[cfg(feature = "client")]
+use std::ops::{Deref, DerefMut};
+use std::{sync::Arc, time::Duration};
+use tokio::io::DuplexStream;
+use tracing::debug;
+use y_sweet_core::{
+    api_types::Authorization,
+    auth::{ExpirationTimeEpochMillis, DEFAULT_EXPIRATION_SECONDS},
+};
+#[cfg(feature = "client")]
+use yrs::Doc;
+
+use crate::builder_ext::ServerBuilder;
+#[cfg(feature = "client")]
+use crate::client_ext::DocSocket;
+use crate::server::{current_time_epoch_millis, Server};
+pub use crate::stores::memory::MemoryStore;
+
+/// How long [TestClient::sync] and [TestClient::wait_for] wait before giving up.
+pub const DEFAULT_TEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Buffer of each direction of an in-memory connection.
+const DUPLEX_BUFFER: usize = 64 * 1024;
+
+/// A server for tests, reached over in-memory connections.
+pub struct TestServer {
+    server: Arc<Server>,
+    store: MemoryStore,
+    app: Router,
+}
+
+impl TestServer {
+    /// A server with default options, storing documents in a [MemoryStore].
+    pub async fn new() -> Result<Self> {
+        Self::start(Server::builder()).await
+    }
+
+    /// A server built from `builder`, storing documents in a [MemoryStore] whatever store
+    /// it sets.
+    pub async fn start(builder: ServerBuilder) -> Result<Self> {
+        let store = MemoryStore::new();
+        let server = Arc::new(builder.store(Some(Box::new(store.clone()))).build().await?);
+        let app = server.routes();
+        Ok(Self { server, store, app })
+    }
+
+    pub fn server(&self) -> &Arc<Server> {
+        &self.server
+    }
+
+    /// The store of the server, shared with it.
+    pub fn store(&self) -> &MemoryStore {
+        &self.store
+    }
+
+    /// A new connection to the server, served like one accepted by a listener.
+    pub fn connect_io(&self) -> DuplexStream {
+        let (client, server) = tokio::io::duplex(DUPLEX_BUFFER);
+        let service = TowerToHyperService::new(self.app.clone());
+        tokio::spawn(async move {
+            let builder = auto::Builder::new(TokioExecutor::new());
+            if let Err(e) = builder
+                .serve_connection_with_upgrades(TokioIo::new(server), service)
+                .await
+            {
+                debug!("Test connection closed with an error: {}", e);
+            }
+        });
+        client
+    }
+
+    /// Send `request` on a new connection. Requests without an `Authorization` header get
+    /// the server token, if the server has an authenticator.
+    pub async fn request(&self, mut request: Request<Full<Bytes>>) -> Result<TestResponse> {
+        if let Some(authenticator) = self.server.authenticator() {
+            let headers = request.headers_mut();
+            if !headers.contains_key(header::AUTHORIZATION) {
+                let bearer = format!("Bearer {}", authenticator.server_token());
+                headers.insert(header::AUTHORIZATION, bearer.parse()?);
+            }
+        }
+        if !request.headers().contains_key(header::HOST) {
+            request
+                .headers_mut()
+                .insert(header::HOST, "localhost".parse()?);
+        }
+
+        let (mut sender, connection) =
+            hyper::client::conn::http1::handshake(TokioIo::new(self.connect_io())).await?;
+        tokio::spawn(connection);
+        let response = sender.send_request(request).await?;
+        let (parts, body) = response.into_parts();
+        Ok(TestResponse {
+            status: parts.status,
+            headers: parts.headers,
+            body: body.collect().await?.to_bytes(),
+        })
+    }
+
+    pub async fn get(&self, path: &str) -> Result<TestResponse> {
+        let request = Request::builder()
+            .method(Method::GET)
+            .uri(path)
+            .body(Full::default())?;
+        self.request(request).await
+    }
+
+    pub async fn post_json(&self, path: &str, body: &impl Serialize) -> Result<TestResponse> {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(path)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Full::new(serde_json::to_vec(body)?.into()))?;
+        self.request(request).await
+    }
+
+    /// A token for `doc_id` with `authorization`, if the server has auth enabled.
+    pub fn doc_token(&self, doc_id: &str, authorization: Authorization) -> Option<String> {
+        let expiration = ExpirationTimeEpochMillis(
+            current_time_epoch_millis() + DEFAULT_EXPIRATION_SECONDS * 1000,
+        );
+        self.server
+            .auth_provider()
+            .map(|auth| auth.gen_doc_token(doc_id, authorization, expiration))
+    }
+
+    /// A client with full access to `doc_id`, synced with the server.
+    #[cfg(feature = "client")]
+    pub async fn connect(&self, doc_id: &str) -> Result<TestClient> {
+        let token = self.doc_token(doc_id, Authorization::Full);
+        let mut client = self.connect_with_token(doc_id, token.as_deref()).await?;
+        client.sync().await?;
+        Ok(client)
+    }
+
+    /// A client of `doc_id` connected with `token`, not synced yet.
+    #[cfg(feature = "client")]
+    pub async fn connect_with_token(
+        &self,
+        doc_id: &str,
+        token: Option<&str>,
+    ) -> Result<TestClient> {
+        let mut url = format!(
+            "ws://localhost{}/ws/{}",
+            self.server.doc_path(doc_id),
+            doc_id
+        );
+        if let Some(token) = token {
+            url.push_str(&format!("?token={}", token));
+        }
+        let (socket, _) = tokio_tungstenite::client_async(url, self.connect_io())
+            .await
+            .context("WebSocket handshake failed")?;
+        Ok(TestClient(DocSocket::new(socket)))
+    }
+}
+
+/// The response to a request of a [TestServer].
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl TestResponse {
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T> {
+        serde_json::from_slice(&self.body).with_context(|| {
+            format!(
+                "Invalid JSON response ({}): {}",
+                self.status,
+                String::from_utf8_lossy(&self.body)
+            )
+        })
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+/// A client of a document over an in-memory connection, giving up on syncing and waiting
+/// after [DEFAULT_TEST_TIMEOUT]. Dereferences to its [DocSocket].
+#[cfg(feature = "client")]
+pub struct TestClient(DocSocket<DuplexStream>);
+
+#[cfg(feature = "client")]
+impl Deref for TestClient {
+    type Target = DocSocket<DuplexStream>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[cfg(feature = "client")]
+impl DerefMut for TestClient {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+#[cfg(feature = "client")]
+impl TestClient {
+    /// Exchange states with the server, until the local copy has the state of the server.
+    pub async fn sync(&mut self) -> Result<()> {
+        tokio::time::timeout(DEFAULT_TEST_TIMEOUT, self.0.sync())
+            .await
+            .context("Timed out syncing with the server")?
+    }
+
+    /// Receive messages from the server until `f` holds for the local copy.
+    pub async fn wait_for(&mut self, f: impl Fn(&Doc) -> bool) -> Result<()> {
+        tokio::time::timeout(DEFAULT_TEST_TIMEOUT, self.0.wait_for(f))
+            .await
+            .context("Timed out waiting for the document")?
+    }
+
+    pub async fn close(self) -> Result<()> {
+        self.0.close().await
+    }
+}
+
+#[cfg(all(test, feature = "client"))]
+mod test {
+    use super::*;
+    use yrs::{updates::decoder::Decode, GetString, Text, Transact, Update, WriteTxn};
+
+    fn text(doc: &Doc) -> String {
+        doc.get_or_insert_text("text").get_string(&doc.transact())
+    }
+
+    #[tokio::test]
+    async fn test_clients_sync_through_server() {
+        let server = TestServer::start(Server::builder().doc_gc(false))
+            .await
+            .unwrap();
+        let response = server
+            .post_json("/doc/new", &serde_json::json!({}))
+            .await
+            .unwrap();
+        assert_eq!(response.status, StatusCode::OK);
+        let body: serde_json::Value = response.json().unwrap();
+        let doc_id = body["docId"].as_str().unwrap().to_string();
+
+        let mut alice = server.connect(&doc_id).await.unwrap();
+        let mut bob = server.connect(&doc_id).await.unwrap();
+        alice
+            .update(|txn| {
+                let text = txn.get_or_insert_text("text");
+                text.insert(txn, 0, "hello");
+            })
+            .await
+            .unwrap();
+        bob.wait_for(|doc| text(doc) == "hello").await.unwrap();
+
+        // A late client gets the state on sync
+        let carol = server.connect(&doc_id).await.unwrap();
+        assert_eq!(text(carol.doc()), "hello");
+
+        let response = server
+            .get(&format!("/d/{}/as-update", doc_id))
+            .await
+            .unwrap();
+        assert_eq!(response.status, StatusCode::OK);
+        let copy = Doc::new();
+        copy.transact_mut()
+            .apply_update(Update::decode_v1(&response.body).unwrap());
+        assert_eq!(text(&copy), "hello");
+    }
+}
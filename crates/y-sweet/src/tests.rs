@@ -1,4 +1,15 @@
 use crate::server_ext::{get_extension_from_content_type, is_allowed_content_type};
+use crate::testing_ext::{TestClient, TestServer, DEFAULT_TEST_TIMEOUT};
+use axum::http::{Method, Request, StatusCode};
+use http_body_util::Full;
+use y_sweet_core::{
+    api_types_ext::AssetStatus,
+    asset_event_ext::{
+        decode_asset_deleted_payload, decode_asset_payload, MSG_ASSET, MSG_ASSET_DELETED,
+    },
+    store::Store,
+    sync::Message,
+};
 
 #[test]
 fn test_debug_extensions() {
@@ -188,3 +199,49 @@ fn test_is_allowed_content_type() {
     assert!(!is_allowed_content_type("invalid/type"));
     assert!(!is_allowed_content_type(""));
 }
+
+/// The payload of the next custom message with `tag` received by `client`.
+async fn recv_custom(client: &mut TestClient, tag: u8) -> Vec<u8> {
+    tokio::time::timeout(DEFAULT_TEST_TIMEOUT, async {
+        loop {
+            for message in client.recv().await.unwrap() {
+                match message {
+                    Message::Custom(t, payload) if t == tag => return payload,
+                    _ => {}
+                }
+            }
+        }
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_asset_changes_are_pushed_to_clients() {
+    let server = TestServer::new().await.unwrap();
+    let doc_id = server.server().create_doc().await.unwrap();
+    let mut client = server.connect(&doc_id).await.unwrap();
+    let asset_key = format!("{}/assets/a1.png", doc_id);
+    server.store().insert_object(&asset_key, b"png".to_vec());
+
+    let response = server
+        .post_json(&format!("/d/{}/assets/a1/confirm", doc_id), &())
+        .await
+        .unwrap();
+    assert_eq!(response.status, StatusCode::OK);
+    let payload = recv_custom(&mut client, MSG_ASSET).await;
+    let (asset_id, status) = decode_asset_payload(&payload).unwrap();
+    assert_eq!(asset_id, "a1.png");
+    assert_eq!(status, Some(AssetStatus::Ready));
+
+    let request = Request::builder()
+        .method(Method::DELETE)
+        .uri(format!("/d/{}/assets/a1", doc_id))
+        .body(Full::default())
+        .unwrap();
+    let response = server.request(request).await.unwrap();
+    assert_eq!(response.status, StatusCode::NO_CONTENT);
+    let payload = recv_custom(&mut client, MSG_ASSET_DELETED).await;
+    assert_eq!(decode_asset_deleted_payload(&payload).unwrap(), "a1.png");
+    assert!(!server.store().exists(&asset_key).await.unwrap());
+}
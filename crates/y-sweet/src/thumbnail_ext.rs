@@ -0,0 +1,280 @@
+//! Thumbnails of image assets (`GET /d/:doc_id/assets/:asset_id/thumbnail?w=&h=`).
+//!
+//! The original is downloaded from the store and scaled down to fit within `w`×`h`, keeping
+//! its aspect ratio, then cached under `{doc_id}/assets/thumbs/` so that later requests for
+//! the same size skip the resize. Stores that presign downloads over HTTP (S3) redirect to
+//! the thumbnail; others serve it in the response.
+
+use anyhow::anyhow;
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+};
+use axum_extra::typed_header::TypedHeader;
+use image::{ImageFormat, ImageReader};
+use serde::Deserialize;
+use std::{io::Cursor, sync::Arc};
+use tracing::info;
+
+use crate::asset_token_ext::{check_granted_asset, verify_asset_request, AssetTokenQuery};
+use crate::server::{get_token_from_header, AppError, Server};
+use crate::server_ext::{
+    ext_check_not_archived, extract_asset_id_from_filename, find_asset_name, THUMBS_DIR,
+};
+
+/// Bounds of a thumbnail when neither `w` nor `h` is given.
+pub const DEFAULT_THUMBNAIL_SIZE: u32 = 256;
+/// Largest `w` or `h` of a thumbnail.
+pub const MAX_THUMBNAIL_SIZE: u32 = 1024;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ThumbnailQuery {
+    w: Option<u32>,
+    h: Option<u32>,
+}
+
+impl ThumbnailQuery {
+    /// The box the thumbnail fits in. A missing bound is unconstrained up to
+    /// [`MAX_THUMBNAIL_SIZE`].
+    fn bounds(&self) -> Result<(u32, u32), AppError> {
+        let (width, height) = match (self.w, self.h) {
+            (None, None) => (DEFAULT_THUMBNAIL_SIZE, DEFAULT_THUMBNAIL_SIZE),
+            (w, h) => (
+                w.unwrap_or(MAX_THUMBNAIL_SIZE),
+                h.unwrap_or(MAX_THUMBNAIL_SIZE),
+            ),
+        };
+        if !(1..=MAX_THUMBNAIL_SIZE).contains(&width) || !(1..=MAX_THUMBNAIL_SIZE).contains(&height)
+        {
+            return Err(AppError(
+                StatusCode::BAD_REQUEST,
+                anyhow!("w and h must be between 1 and {}", MAX_THUMBNAIL_SIZE),
+            ));
+        }
+        Ok((width, height))
+    }
+}
+
+/// Format of the thumbnails of an asset: JPEG for JPEG photos, PNG (keeping transparency)
+/// for the rest.
+fn thumbnail_format(asset_name: &str) -> ImageFormat {
+    match ImageFormat::from_path(asset_name) {
+        Ok(ImageFormat::Jpeg) => ImageFormat::Jpeg,
+        _ => ImageFormat::Png,
+    }
+}
+
+/// Key of the thumbnail of `asset_name` fitting within `width`×`height`.
+pub fn thumbnail_key(doc_id: &str, asset_name: &str, width: u32, height: u32) -> String {
+    let asset_id = extract_asset_id_from_filename(asset_name).unwrap_or_default();
+    let extension = thumbnail_format(asset_name).extensions_str()[0];
+    format!(
+        "{}/assets/{}/{}-{}x{}.{}",
+        doc_id, THUMBS_DIR, asset_id, width, height, extension
+    )
+}
+
+/// Decode `original` and scale it down to fit within `width`×`height`, encoded as `format`.
+/// Images that already fit keep their size.
+pub fn resize_image(
+    original: &[u8],
+    width: u32,
+    height: u32,
+    format: ImageFormat,
+) -> anyhow::Result<Vec<u8>> {
+    let image = ImageReader::new(Cursor::new(original))
+        .with_guessed_format()?
+        .decode()?;
+    let image = if image.width() > width || image.height() > height {
+        image.thumbnail(width, height)
+    } else {
+        image
+    };
+    // JPEG has no alpha channel
+    let image = match format {
+        ImageFormat::Jpeg => image.into_rgb8().into(),
+        _ => image,
+    };
+
+    let mut bytes = Vec::new();
+    image.write_to(&mut Cursor::new(&mut bytes), format)?;
+    Ok(bytes)
+}
+
+/// Redirect to (or serve) a thumbnail of an image asset, generating it on first request.
+/// Accepts an asset token in the query instead of a token of the document.
+pub async fn get_asset_thumbnail(
+    Path((doc_id, asset_id)): Path<(String, String)>,
+    Query(query): Query<ThumbnailQuery>,
+    Query(token_query): Query<AssetTokenQuery>,
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+) -> Result<Response, AppError> {
+    let token = get_token_from_header(auth_header);
+    let granted = verify_asset_request(&server_state, &doc_id, token.as_deref(), &token_query)?;
+
+    ext_check_not_archived(&server_state, &doc_id).await?;
+
+    if !server_state.doc_exists(&doc_id).await {
+        Err((StatusCode::NOT_FOUND, anyhow!("Doc {} not found", doc_id)))?;
+    }
+
+    let (width, height) = query.bounds()?;
+    let Some(store) = &server_state.store else {
+        return Err(AppError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            anyhow!("No store configured"),
+        ));
+    };
+
+    let asset_name = find_asset_name(store, &doc_id, &asset_id).await?;
+    check_granted_asset(granted.as_deref(), &asset_name)?;
+    if ImageFormat::from_path(&asset_name).is_err() {
+        return Err(AppError(
+            StatusCode::BAD_REQUEST,
+            anyhow!("Asset {} is not an image", asset_name),
+        ));
+    }
+
+    let key = thumbnail_key(&doc_id, &asset_name, width, height);
+    let store_error = |e| {
+        AppError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            anyhow!("Failed to read or write thumbnail: {}", e),
+        )
+    };
+    let mut generated = None;
+    if !store.exists(&key).await.map_err(store_error)? {
+        let original = store
+            .get(&format!("{}/assets/{}", doc_id, asset_name))
+            .await
+            .map_err(store_error)?
+            .ok_or_else(|| {
+                AppError(
+                    StatusCode::NOT_FOUND,
+                    anyhow!("Asset {} not found", asset_id),
+                )
+            })?;
+        let format = thumbnail_format(&asset_name);
+        let thumbnail =
+            tokio::task::spawn_blocking(move || resize_image(&original, width, height, format))
+                .await
+                .map_err(|e| AppError(StatusCode::INTERNAL_SERVER_ERROR, e.into()))?
+                .map_err(|e| {
+                    AppError(
+                        StatusCode::UNPROCESSABLE_ENTITY,
+                        anyhow!("Failed to resize asset {}: {}", asset_name, e),
+                    )
+                })?;
+        store
+            .set(&key, thumbnail.clone())
+            .await
+            .map_err(store_error)?;
+        info!(
+            message = "Thumbnail generated",
+            event = "thumbnail_generated",
+            doc_id = %doc_id,
+            asset_id = %asset_name,
+            width = width,
+            height = height,
+            size_bytes = thumbnail.len()
+        );
+        generated = Some(thumbnail);
+    }
+
+    let download_url = store
+        .generate_download_presigned_url(&key)
+        .await
+        .map_err(store_error)?;
+    if download_url.starts_with("https://") || download_url.starts_with("http://") {
+        return Ok(Redirect::temporary(&download_url).into_response());
+    }
+
+    let thumbnail = match generated {
+        Some(thumbnail) => thumbnail,
+        None => store.get(&key).await.map_err(store_error)?.ok_or_else(|| {
+            AppError(
+                StatusCode::NOT_FOUND,
+                anyhow!("Thumbnail of {} not found", asset_id),
+            )
+        })?,
+    };
+    let content_type = HeaderValue::from_static(thumbnail_format(&asset_name).to_mime_type());
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type),
+            (
+                header::CACHE_CONTROL,
+                HeaderValue::from_static("private, max-age=3600"),
+            ),
+        ],
+        thumbnail,
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stores::memory::MemoryStore;
+    use axum::{body::Body, http::Request};
+    use http_body_util::BodyExt;
+    use image::{DynamicImage, GenericImageView, RgbaImage};
+    use tower::ServiceExt;
+    use y_sweet_core::auth::Authenticator;
+
+    #[tokio::test]
+    async fn test_thumbnail_is_resized_and_cached() {
+        let store = MemoryStore::new();
+        let auth = Authenticator::gen_key().unwrap();
+        let token = auth.server_token();
+        let server = Arc::new(
+            Server::builder()
+                .store(Some(Box::new(store.clone())))
+                .authenticator(Some(auth))
+                .build()
+                .await
+                .unwrap(),
+        );
+        let doc_id = server.create_doc().await.unwrap();
+
+        let mut photo = Vec::new();
+        DynamicImage::ImageRgba8(RgbaImage::new(200, 100))
+            .write_to(&mut Cursor::new(&mut photo), ImageFormat::Png)
+            .unwrap();
+        store.insert_object(&format!("{}/assets/photo.png", doc_id), photo);
+        let get = |uri: String| {
+            Request::builder()
+                .uri(uri)
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let uri = format!("/d/{}/assets/photo/thumbnail?w=64&h=64", doc_id);
+        let response = server.routes().oneshot(get(uri)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let thumbnail = image::load_from_memory(&body).unwrap();
+        assert_eq!(thumbnail.dimensions(), (64, 32));
+        let cached = format!("{}/assets/thumbs/photo-64x64.png", doc_id);
+        assert_eq!(store.get_object(&cached), Some(body.to_vec()));
+
+        // Thumbnails are not listed as assets
+        let uri = format!("/d/{}/assets", doc_id);
+        let response = server.routes().oneshot(get(uri)).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["assets"].as_array().unwrap().len(), 1);
+
+        let uri = format!("/d/{}/assets/photo.png/thumbnail?w=4096", doc_id);
+        let response = server.routes().oneshot(get(uri)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let uri = format!("/d/{}/assets/missing/thumbnail", doc_id);
+        let response = server.routes().oneshot(get(uri)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
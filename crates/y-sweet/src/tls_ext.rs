@@ -0,0 +1,151 @@
+//! TLS termination, so that the server can be exposed over `https://` and `wss://` without
+//! a reverse proxy.
+//!
+//! The certificate is either read from PEM files, and reloaded from them on `SIGHUP` so
+//! that renewed certificates are picked up without a restart, or, with the `acme` feature,
+//! obtained and renewed from Let's Encrypt (or another ACME directory) with the TLS-ALPN-01
+//! challenge, which is answered on the same port.
+//!
+//! Serving over TLS requires the `tls` feature. Without it, the flags of the certificate
+//! files are not available and a server given a [Tls] fails to start.
+
+#[cfg(feature = "tls")]
+use anyhow::Context;
+use anyhow::Result;
+use axum::Router;
+#[cfg(feature = "tls")]
+use axum_server::tls_rustls::RustlsConfig;
+#[cfg(feature = "tls")]
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+#[cfg(feature = "tls")]
+use tracing::{info, warn};
+
+/// How long connections are given to finish once the server shuts down.
+#[cfg(feature = "tls")]
+const GRACEFUL_SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+#[derive(Clone, Debug)]
+pub enum Tls {
+    /// A certificate chain and private key, read from PEM files.
+    Files { cert: PathBuf, key: PathBuf },
+    /// Certificates obtained from an ACME directory for `domains`, cached in `cache_dir`.
+    #[cfg(feature = "acme")]
+    Acme {
+        domains: Vec<String>,
+        /// Email address the ACME directory may contact about the certificates.
+        contact: Option<String>,
+        cache_dir: PathBuf,
+        /// Use the staging directory of Let's Encrypt, whose certificates are not trusted
+        /// but whose rate limits are higher.
+        staging: bool,
+    },
+}
+
+/// Serve `app` over TLS on `listener` until `token` is cancelled.
+#[cfg(feature = "tls")]
+pub(crate) async fn serve_tls(
+    listener: TcpListener,
+    app: Router,
+    tls: &Tls,
+    token: CancellationToken,
+) -> Result<()> {
+    let listener = listener.into_std()?;
+    let handle = axum_server::Handle::new();
+    {
+        let handle = handle.clone();
+        tokio::spawn(async move {
+            token.cancelled().await;
+            handle.graceful_shutdown(Some(GRACEFUL_SHUTDOWN_TIMEOUT));
+        });
+    }
+
+    match tls {
+        Tls::Files { cert, key } => {
+            let config = RustlsConfig::from_pem_file(cert, key)
+                .await
+                .context("Failed to load the TLS certificate")?;
+            tokio::spawn(reload_on_sighup(config.clone(), cert.clone(), key.clone()));
+            axum_server::from_tcp_rustls(listener, config)
+                .handle(handle)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .await?;
+        }
+        #[cfg(feature = "acme")]
+        Tls::Acme {
+            domains,
+            contact,
+            cache_dir,
+            staging,
+        } => {
+            use futures::StreamExt;
+            use rustls_acme::{caches::DirCache, AcmeConfig};
+
+            let mut state = AcmeConfig::new(domains.clone())
+                .contact(contact.iter().map(|email| format!("mailto:{}", email)))
+                .cache(DirCache::new(cache_dir.clone()))
+                .directory_lets_encrypt(!staging)
+                .state();
+            let acceptor = state.axum_acceptor(state.default_rustls_config());
+            tokio::spawn(async move {
+                while let Some(event) = state.next().await {
+                    match event {
+                        Ok(event) => info!(
+                            message = "ACME certificate event",
+                            event = "acme_event",
+                            detail = format!("{:?}", event)
+                        ),
+                        Err(e) => warn!(
+                            message = "ACME certificate error",
+                            event = "acme_error",
+                            error = format!("{:?}", e)
+                        ),
+                    }
+                }
+            });
+            axum_server::from_tcp(listener)
+                .acceptor(acceptor)
+                .handle(handle)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "tls"))]
+pub(crate) async fn serve_tls(
+    _listener: TcpListener,
+    _app: Router,
+    _tls: &Tls,
+    _token: CancellationToken,
+) -> Result<()> {
+    anyhow::bail!("Serving over TLS requires the `tls` feature")
+}
+
+#[cfg(all(unix, feature = "tls"))]
+async fn reload_on_sighup(config: RustlsConfig, cert: PathBuf, key: PathBuf) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let Ok(mut hangups) = signal(SignalKind::hangup()) else {
+        return;
+    };
+    while hangups.recv().await.is_some() {
+        match config.reload_from_pem_file(&cert, &key).await {
+            Ok(()) => info!(
+                message = "Reloaded the TLS certificate",
+                event = "tls_certificate_reloaded"
+            ),
+            Err(e) => warn!(
+                message = "Failed to reload the TLS certificate, keeping the current one",
+                event = "tls_certificate_reload_failed",
+                error = e.to_string()
+            ),
+        }
+    }
+}
+
+#[cfg(all(not(unix), feature = "tls"))]
+async fn reload_on_sighup(_config: RustlsConfig, _cert: PathBuf, _key: PathBuf) {}
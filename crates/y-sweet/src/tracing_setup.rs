@@ -1,54 +1,205 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
+use axum::Router;
+#[cfg(feature = "datadog")]
 use ddtrace::{
     formatter::DatadogFormatter,
     set_global_propagator,
     tracer::{self, ProviderGuard},
 };
-use tracing_subscriber::EnvFilter;
+use tracing::Subscriber;
+use tracing_subscriber::{fmt::MakeWriter, registry::LookupSpan, EnvFilter, Layer};
 
+use crate::redact_ext::{RedactingMakeWriter, Redaction};
+
+#[cfg(feature = "datadog")]
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-/// Initializes tracing with optional Datadog APM integration
-///
-/// This function sets up tracing with JSON-formatted logs and optionally
-/// integrates with Datadog APM if DD_TRACE_ENABLED is not set to false.
+/// Environment variable selecting where traces are exported.
+pub const TELEMETRY_ENV: &str = "Y_SWEET_TELEMETRY";
+
+/// Where traces (and, over OTLP, metrics) are exported, selected by `Y_SWEET_TELEMETRY`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TelemetryMode {
+    /// JSON logs only (`none`).
+    None,
+    /// Datadog APM, with logs in the Datadog format (`datadog`).
+    Datadog,
+    /// Any OpenTelemetry collector over OTLP (`otlp`), see `otel_ext`.
+    Otlp,
+}
+
+impl TelemetryMode {
+    /// The mode set by `Y_SWEET_TELEMETRY`. Without it, Datadog APM is used when the
+    /// crate is built with the `datadog` feature and `DD_TRACE_ENABLED` is not false.
+    pub fn from_env() -> Result<Self> {
+        match std::env::var(TELEMETRY_ENV).ok().as_deref() {
+            Some("none") => Ok(Self::None),
+            Some("datadog") => Ok(Self::Datadog),
+            Some("otlp") => Ok(Self::Otlp),
+            Some(mode) => bail!(
+                "Invalid {} {:?}, expected \"none\", \"datadog\" or \"otlp\"",
+                TELEMETRY_ENV,
+                mode
+            ),
+            None => {
+                let tracing_disabled = std::env::var("DD_TRACE_ENABLED")
+                    .map(|value| {
+                        matches!(value.as_str(), "0") || value.eq_ignore_ascii_case("false")
+                    })
+                    .unwrap_or(false);
+                if cfg!(feature = "datadog") && !tracing_disabled {
+                    Ok(Self::Datadog)
+                } else {
+                    Ok(Self::None)
+                }
+            }
+        }
+    }
+}
+
+/// How log lines are formatted, selected by `--log-format` (`Y_SWEET_LOG_FORMAT`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// One JSON object per event, or the Datadog format with Datadog APM.
+    #[default]
+    Json,
+    /// Multi-line, human-readable events, for local development.
+    Pretty,
+    /// One human-readable line per event.
+    Compact,
+}
+
+/// Keeps the exporter of traces alive, and flushes it when dropped.
+pub enum TracingGuard {
+    #[cfg(feature = "datadog")]
+    Datadog(ProviderGuard),
+    #[cfg(feature = "otel")]
+    Otlp(crate::otel_ext::OtlpGuard),
+}
+
+/// Log lines in `format`, redacted as `redaction` says (JSON only).
+pub(crate) fn log_layer<S>(
+    format: LogFormat,
+    redaction: &Redaction,
+) -> Box<dyn Layer<S> + Send + Sync + 'static>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    match format {
+        LogFormat::Json if redaction.is_empty() => json_layer(std::io::stdout).boxed(),
+        LogFormat::Json => {
+            json_layer(RedactingMakeWriter::new(redaction.clone(), std::io::stdout)).boxed()
+        }
+        LogFormat::Pretty => tracing_subscriber::fmt::layer()
+            .pretty()
+            .with_span_events(tracing_subscriber::fmt::format::FmtSpan::NONE)
+            .boxed(),
+        LogFormat::Compact => tracing_subscriber::fmt::layer()
+            .compact()
+            .with_target(false)
+            .boxed(),
+    }
+}
+
+/// JSON log lines, one per event, written to `writer`.
+fn json_layer<S, W>(writer: W) -> impl Layer<S> + Send + Sync + 'static
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    tracing_subscriber::fmt::layer()
+        .json()
+        .with_writer(writer)
+        .with_timer(tracing_subscriber::fmt::time::UtcTime::rfc_3339())
+        .with_target(false)
+        .with_thread_ids(false)
+        .with_thread_names(false)
+        .with_file(false)
+        .with_line_number(false)
+        .with_current_span(true)
+        .with_span_list(false)
+        .with_span_events(tracing_subscriber::fmt::format::FmtSpan::NONE)
+}
+
+/// Initializes tracing with logs in `format`, exporting traces to Datadog APM or over
+/// OTLP as `Y_SWEET_TELEMETRY` selects (see [`TelemetryMode::from_env`]).
 ///
 /// # Arguments
 ///
 /// * `filter` - The EnvFilter to apply to the tracing subscriber
+/// * `format` - How log lines are formatted
+/// * `redaction` - What is redacted from logs and traces
 ///
 /// # Returns
 ///
-/// Returns an optional ProviderGuard that must be kept alive for the duration
-/// of the program to maintain the Datadog tracer connection.
-pub fn init_tracing(filter: EnvFilter) -> Result<Option<ProviderGuard>> {
+/// Returns an optional TracingGuard that must be kept alive for the duration
+/// of the program to keep exporting traces.
+pub fn init_tracing(
+    filter: EnvFilter,
+    format: LogFormat,
+    redaction: Redaction,
+) -> Result<Option<TracingGuard>> {
     use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-    let tracing_disabled = std::env::var("DD_TRACE_ENABLED")
-        .map(|value| matches!(value.as_str(), "0") || value.eq_ignore_ascii_case("false"))
-        .unwrap_or(false);
+    let mode = TelemetryMode::from_env()?;
+    if !redaction.is_empty() {
+        if format != LogFormat::Json {
+            bail!("Redacting logs requires --log-format json");
+        }
+        if mode == TelemetryMode::Datadog {
+            bail!(
+                "Traces exported to Datadog APM cannot be redacted, set {} to \"otlp\" or \"none\"",
+                TELEMETRY_ENV
+            );
+        }
+    }
 
-    if tracing_disabled {
-        let fmt_layer = tracing_subscriber::fmt::layer()
-            .json()
-            .with_timer(tracing_subscriber::fmt::time::UtcTime::rfc_3339())
-            .with_target(false)
-            .with_thread_ids(false)
-            .with_thread_names(false)
-            .with_file(false)
-            .with_line_number(false)
-            .with_current_span(true)
-            .with_span_list(false)
-            .with_span_events(tracing_subscriber::fmt::format::FmtSpan::NONE);
-
-        tracing_subscriber::registry()
-            .with(filter)
-            .with(fmt_layer)
-            .init();
-
-        return Ok(None);
+    match mode {
+        TelemetryMode::None => {}
+        #[cfg(feature = "datadog")]
+        TelemetryMode::Datadog => return init_datadog(filter, format),
+        #[cfg(not(feature = "datadog"))]
+        TelemetryMode::Datadog => bail!("Datadog APM requires the `datadog` feature"),
+        #[cfg(feature = "otel")]
+        TelemetryMode::Otlp => {
+            let guard = crate::otel_ext::init_otlp(filter, format, redaction)?;
+            return Ok(Some(TracingGuard::Otlp(guard)));
+        }
+        #[cfg(not(feature = "otel"))]
+        TelemetryMode::Otlp => bail!("OTLP export requires the `otel` feature"),
     }
 
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(log_layer(format, &redaction))
+        .init();
+
+    Ok(None)
+}
+
+/// Traces the requests handled by `routes` as OpenTelemetry spans, exported to Datadog APM
+/// or over OTLP.
+#[cfg(feature = "otel")]
+pub fn trace_requests<S: Clone + Send + Sync + 'static>(routes: Router<S>) -> Router<S> {
+    routes.layer(axum_tracing_opentelemetry::middleware::OtelAxumLayer::default())
+}
+
+/// Traces the requests handled by `routes` as Datadog APM spans.
+#[cfg(all(feature = "datadog", not(feature = "otel")))]
+pub fn trace_requests<S: Clone + Send + Sync + 'static>(routes: Router<S>) -> Router<S> {
+    routes.layer(ddtrace::axum::OtelAxumLayer::default())
+}
+
+/// Leaves `routes` as they are, without an exporter of traces.
+#[cfg(not(any(feature = "datadog", feature = "otel")))]
+pub fn trace_requests<S: Clone + Send + Sync + 'static>(routes: Router<S>) -> Router<S> {
+    routes
+}
+
+#[cfg(feature = "datadog")]
+fn init_datadog(filter: EnvFilter, format: LogFormat) -> Result<Option<TracingGuard>> {
+    use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
     set_global_propagator();
 
     if std::env::var("DD_VERSION").is_err() {
@@ -59,18 +210,23 @@ pub fn init_tracing(filter: EnvFilter) -> Result<Option<ProviderGuard>> {
 
     match tracer::build_layer(service_name) {
         Ok((datadog_layer, guard)) => {
-            let fmt_layer = tracing_subscriber::fmt::layer()
-                .json()
-                .with_timer(tracing_subscriber::fmt::time::UtcTime::rfc_3339())
-                .with_target(false)
-                .with_thread_ids(false)
-                .with_thread_names(false)
-                .with_file(false)
-                .with_line_number(false)
-                .with_current_span(true)
-                .with_span_list(false)
-                .with_span_events(tracing_subscriber::fmt::format::FmtSpan::NONE)
-                .event_format(DatadogFormatter);
+            // Datadog parses its own format; the others are for reading
+            let fmt_layer = match format {
+                LogFormat::Json => tracing_subscriber::fmt::layer()
+                    .json()
+                    .with_timer(tracing_subscriber::fmt::time::UtcTime::rfc_3339())
+                    .with_target(false)
+                    .with_thread_ids(false)
+                    .with_thread_names(false)
+                    .with_file(false)
+                    .with_line_number(false)
+                    .with_current_span(true)
+                    .with_span_list(false)
+                    .with_span_events(tracing_subscriber::fmt::format::FmtSpan::NONE)
+                    .event_format(DatadogFormatter)
+                    .boxed(),
+                format => log_layer(format, &Redaction::default()),
+            };
 
             tracing_subscriber::registry()
                 .with(filter)
@@ -78,24 +234,12 @@ pub fn init_tracing(filter: EnvFilter) -> Result<Option<ProviderGuard>> {
                 .with(datadog_layer)
                 .init();
 
-            Ok(Some(guard))
+            Ok(Some(TracingGuard::Datadog(guard)))
         }
         Err(err) => {
-            let fmt_layer = tracing_subscriber::fmt::layer()
-                .json()
-                .with_timer(tracing_subscriber::fmt::time::UtcTime::rfc_3339())
-                .with_target(false)
-                .with_thread_ids(false)
-                .with_thread_names(false)
-                .with_file(false)
-                .with_line_number(false)
-                .with_current_span(true)
-                .with_span_list(false)
-                .with_span_events(tracing_subscriber::fmt::format::FmtSpan::NONE);
-
             tracing_subscriber::registry()
                 .with(filter)
-                .with(fmt_layer)
+                .with(log_layer(format, &Redaction::default()))
                 .init();
 
             eprintln!("datadog tracer initialization failed, continuing without APM: {err}");
@@ -0,0 +1,224 @@
+use anyhow::anyhow;
+use axum::http::StatusCode;
+use std::{sync::Arc, time::Duration};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+use y_sweet_core::api_types::validate_doc_name;
+
+use crate::audit_ext::AuditPrincipal;
+use crate::server::{current_time_epoch_millis, AppError, Server};
+use crate::server_ext::delete_document_inner;
+
+/// Storage prefix of the expiration index.
+/// Each document with a TTL has an entry `.ttl/{doc_id}` holding its expiration time
+/// (epoch millis), so the reaper does not need to scan every document in the store.
+/// The leading dot keeps it outside the namespace of valid document IDs.
+pub const TTL_PREFIX: &str = ".ttl";
+
+/// Default interval between reaper runs
+pub const DEFAULT_TTL_REAP_INTERVAL: Duration = Duration::from_secs(60);
+
+pub(crate) fn ttl_key(doc_id: &str) -> String {
+    format!("{}/{}", TTL_PREFIX, doc_id)
+}
+
+/// Set the expiration time (epoch millis) of a document
+pub async fn set_doc_expiration(
+    server_state: &Server,
+    doc_id: &str,
+    expires_at: u64,
+) -> Result<(), AppError> {
+    let Some(store) = &server_state.store else {
+        return Err(AppError(
+            StatusCode::BAD_REQUEST,
+            anyhow!("Document TTL requires a store"),
+        ));
+    };
+
+    store
+        .set(&ttl_key(doc_id), expires_at.to_string().into_bytes())
+        .await
+        .map_err(|e| {
+            AppError(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                anyhow!("Failed to set document TTL: {}", e),
+            )
+        })?;
+
+    info!(
+        message = "Document TTL set",
+        event = "document_ttl_set",
+        doc_id = %doc_id,
+        expires_at = expires_at
+    );
+
+    Ok(())
+}
+
+/// Get the expiration time (epoch millis) of a document, if it has one
+pub async fn get_doc_expiration(
+    server_state: &Server,
+    doc_id: &str,
+) -> Result<Option<u64>, AppError> {
+    let Some(store) = &server_state.store else {
+        return Ok(None);
+    };
+
+    let value = store.get(&ttl_key(doc_id)).await.map_err(|e| {
+        AppError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            anyhow!("Failed to get document TTL: {}", e),
+        )
+    })?;
+
+    let Some(value) = value else {
+        return Ok(None);
+    };
+
+    let expires_at = String::from_utf8(value)
+        .ok()
+        .and_then(|s| s.trim().parse::<u64>().ok())
+        .ok_or_else(|| {
+            AppError(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                anyhow!("Malformed TTL entry for document {}", doc_id),
+            )
+        })?;
+
+    Ok(Some(expires_at))
+}
+
+/// Remove the expiration time of a document. Does nothing if it has none.
+pub async fn clear_doc_expiration(server_state: &Server, doc_id: &str) -> Result<(), AppError> {
+    let Some(store) = &server_state.store else {
+        return Ok(());
+    };
+
+    let key = ttl_key(doc_id);
+    let result = match store.exists(&key).await {
+        Ok(true) => store.remove(&key).await,
+        Ok(false) => Ok(()),
+        Err(e) => Err(e),
+    };
+
+    result.map_err(|e| {
+        AppError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            anyhow!("Failed to clear document TTL: {}", e),
+        )
+    })
+}
+
+/// Delete every document whose TTL has passed. Returns the number of deleted documents.
+pub async fn reap_expired_docs(server_state: &Arc<Server>) -> usize {
+    let Some(store) = &server_state.store else {
+        return 0;
+    };
+
+    let doc_ids = match store.list_objects(&format!("{}/", TTL_PREFIX)).await {
+        Ok(doc_ids) => doc_ids,
+        Err(e) => {
+            error!(
+                message = "Failed to list document TTL entries",
+                event = "document_ttl_reap_failed",
+                error = %e
+            );
+            return 0;
+        }
+    };
+
+    let now = current_time_epoch_millis();
+    let mut deleted = 0;
+
+    for doc_id in doc_ids {
+        if !validate_doc_name(&doc_id) {
+            continue;
+        }
+
+        let expires_at = match get_doc_expiration(server_state, &doc_id).await {
+            Ok(Some(expires_at)) => expires_at,
+            Ok(None) => continue,
+            Err(AppError(_, e)) => {
+                error!(
+                    message = "Failed to read document TTL",
+                    event = "document_ttl_reap_failed",
+                    doc_id = %doc_id,
+                    error = %e
+                );
+                continue;
+            }
+        };
+
+        if expires_at > now {
+            continue;
+        }
+
+        // Deletion also clears the TTL entry
+        match delete_document_inner(
+            server_state,
+            doc_id.clone(),
+            false,
+            AuditPrincipal::system(),
+        )
+        .await
+        {
+            Ok(_) => {
+                info!(
+                    message = "Expired document deleted",
+                    event = "document_expired",
+                    doc_id = %doc_id,
+                    expires_at = expires_at
+                );
+                deleted += 1;
+            }
+            Err(AppError(status, _)) if status == StatusCode::NOT_FOUND => {
+                // The document is already gone (or archived); drop the stale entry
+                if let Err(AppError(_, e)) = clear_doc_expiration(server_state, &doc_id).await {
+                    error!(
+                        message = "Failed to clear stale document TTL",
+                        event = "document_ttl_reap_failed",
+                        doc_id = %doc_id,
+                        error = %e
+                    );
+                }
+            }
+            Err(AppError(_, e)) => {
+                // Keep the entry so the next run retries
+                error!(
+                    message = "Failed to delete expired document",
+                    event = "document_ttl_reap_failed",
+                    doc_id = %doc_id,
+                    error = %e
+                );
+            }
+        }
+    }
+
+    deleted
+}
+
+/// Periodically delete expired documents until the server shuts down
+pub(crate) async fn ttl_reaper_worker(
+    server_state: Arc<Server>,
+    interval: Duration,
+    cancellation_token: CancellationToken,
+) {
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {
+                let deleted = reap_expired_docs(&server_state).await;
+                if deleted > 0 {
+                    info!(
+                        message = format!("Deleted {} expired documents", deleted),
+                        event = "document_ttl_reap_completed",
+                        deleted = deleted
+                    );
+                }
+            }
+            _ = cancellation_token.cancelled() => {
+                break;
+            }
+        }
+    }
+    tracing::debug!("Exiting ttl reaper loop");
+}
@@ -0,0 +1,139 @@
+//! Listening on a Unix domain socket instead of a TCP port.
+//!
+//! When a proxy such as nginx or an Envoy sidecar runs on the same host, it can reach the
+//! server through a socket file, which is not exposed on any network interface and whose
+//! access is controlled by file permissions. `axum::serve` only accepts TCP listeners, so
+//! connections on a Unix socket are served with hyper directly, upgrades included.
+
+use tokio::net::TcpListener;
+
+/// What the server accepts connections on.
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::Tcp(listener)
+    }
+}
+
+#[cfg(unix)]
+impl From<tokio::net::UnixListener> for Listener {
+    fn from(listener: tokio::net::UnixListener) -> Self {
+        Listener::Unix(listener)
+    }
+}
+
+#[cfg(unix)]
+pub use unix::{bind_unix_socket, serve_unix};
+
+#[cfg(unix)]
+mod unix {
+    use anyhow::{bail, Context, Result};
+    use axum::Router;
+    use hyper_util::{
+        rt::{TokioExecutor, TokioIo},
+        server::{conn::auto, graceful::GracefulShutdown},
+        service::TowerToHyperService,
+    };
+    use std::{os::unix::fs::FileTypeExt, path::Path, time::Duration};
+    use tokio::net::UnixListener;
+    use tokio_util::sync::CancellationToken;
+    use tracing::{debug, warn};
+
+    /// How long connections are given to finish once the server shuts down.
+    const GRACEFUL_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Bind a Unix socket at `path`, replacing the socket a previous run left behind.
+    pub fn bind_unix_socket(path: &Path) -> Result<UnixListener> {
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => {
+                std::fs::remove_file(path)
+                    .with_context(|| format!("Failed to remove the stale socket {:?}", path))?;
+            }
+            Ok(_) => bail!("{:?} exists and is not a socket", path),
+            Err(_) => {}
+        }
+        UnixListener::bind(path).with_context(|| format!("Failed to bind the socket {:?}", path))
+    }
+
+    /// Serve `app` on `listener` until `token` is cancelled, then remove the socket file.
+    pub async fn serve_unix(
+        listener: UnixListener,
+        app: Router,
+        token: CancellationToken,
+    ) -> Result<()> {
+        let path = listener
+            .local_addr()?
+            .as_pathname()
+            .map(|path| path.to_path_buf());
+        let builder = auto::Builder::new(TokioExecutor::new());
+        let graceful = GracefulShutdown::new();
+
+        loop {
+            let stream = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        warn!(
+                            message = "Failed to accept a connection on the Unix socket",
+                            event = "unix_socket_accept_failed",
+                            error = e.to_string()
+                        );
+                        continue;
+                    }
+                },
+                _ = token.cancelled() => break,
+            };
+
+            let service = TowerToHyperService::new(app.clone());
+            let connection = builder
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                .into_owned();
+            let connection = graceful.watch(connection);
+            tokio::spawn(async move {
+                if let Err(e) = connection.await {
+                    debug!("Unix socket connection closed with an error: {}", e);
+                }
+            });
+        }
+
+        drop(listener);
+        if tokio::time::timeout(GRACEFUL_SHUTDOWN_TIMEOUT, graceful.shutdown())
+            .await
+            .is_err()
+        {
+            debug!("Unix socket connections did not finish before the shutdown timeout");
+        }
+        if let Some(path) = path {
+            let _ = std::fs::remove_file(path);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_replaces_stale_socket_only() {
+        let dir = std::env::temp_dir().join(format!("y-sweet-uds-{}", nanoid::nanoid!()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("y-sweet.sock");
+        let listener = bind_unix_socket(&path).unwrap();
+        drop(listener);
+        // The socket file outlives the listener, as after a crash
+        assert!(path.exists());
+        bind_unix_socket(&path).unwrap();
+
+        let file = dir.join("not-a-socket");
+        std::fs::write(&file, "data").unwrap();
+        assert!(bind_unix_socket(&file).is_err());
+        assert!(file.exists());
+    }
+}
@@ -0,0 +1,161 @@
+//! Verification of the stored state of documents.
+//!
+//! Verifying a document reads its snapshot from the store, checks its checksum, and loads
+//! it the way the server would, replaying its update log if it has one, to make sure it
+//! decodes into a valid Yjs document. Nothing is written back, and the backup of a
+//! corrupted snapshot is not used: a document that only loads from its backup is reported
+//! as invalid, so corruption is caught before the backup is needed.
+
+use anyhow::{bail, Context, Result};
+use futures::StreamExt;
+use std::sync::Arc;
+use y_sweet_core::{
+    api_types_ext::{DocBatchVerifyResponse, DocStats, DocVerifyResult},
+    doc_sync_ext::DocWithSyncKv,
+    snapshot_ext::snapshot_key,
+    store::Store,
+    sync_kv::load_snapshot,
+    update_log_ext::{list_segments, UpdateLogConfig},
+};
+use yrs::{updates::encoder::Encode, ReadTxn, StateVector, Transact};
+
+/// Number of documents verified concurrently.
+const VERIFY_CONCURRENCY: usize = 4;
+
+pub async fn verify_doc(store: &Arc<Box<dyn Store>>, doc_id: &str) -> DocVerifyResult {
+    let mut snapshot_bytes = None;
+    let (error, stats) = match check_doc(store, doc_id, &mut snapshot_bytes).await {
+        Ok(stats) => (None, Some(stats)),
+        Err(e) => (Some(format!("{:#}", e)), None),
+    };
+    DocVerifyResult {
+        doc_id: doc_id.to_string(),
+        ok: error.is_none(),
+        snapshot_bytes,
+        error,
+        stats,
+    }
+}
+
+/// Verify `doc_ids`, returning the results in the same order.
+pub async fn verify_docs(
+    store: &Arc<Box<dyn Store>>,
+    doc_ids: Vec<String>,
+) -> DocBatchVerifyResponse {
+    let results: Vec<DocVerifyResult> = futures::stream::iter(doc_ids)
+        .map(|doc_id| async move { verify_doc(store, &doc_id).await })
+        .buffered(VERIFY_CONCURRENCY)
+        .collect()
+        .await;
+    let valid = results.iter().filter(|result| result.ok).count();
+    DocBatchVerifyResponse {
+        invalid: results.len() - valid,
+        valid,
+        results,
+    }
+}
+
+async fn check_doc(
+    store: &Arc<Box<dyn Store>>,
+    doc_id: &str,
+    snapshot_bytes: &mut Option<usize>,
+) -> Result<DocStats> {
+    let snapshot = store
+        .get(&snapshot_key(doc_id))
+        .await
+        .context("Failed to read the snapshot")?;
+    match &snapshot {
+        Some(snapshot) => {
+            *snapshot_bytes = Some(snapshot.len());
+            load_snapshot(store, doc_id, snapshot)
+                .await
+                .context("The snapshot is corrupted")?;
+        }
+        None => {
+            let segments = list_segments(store.as_ref().as_ref(), doc_id)
+                .await
+                .context("Failed to list the update log")?;
+            if segments.is_empty() {
+                bail!("The document has no stored state");
+            }
+        }
+    }
+
+    // Documents without an update log load as if it was disabled
+    let doc = DocWithSyncKv::new_with_update_log(
+        doc_id,
+        Some(store.clone()),
+        || {},
+        false,
+        Some(UpdateLogConfig::default()),
+    )
+    .await
+    .context("The document cannot be loaded")?;
+
+    let awareness = doc.awareness();
+    let awareness = awareness.read().unwrap();
+    let txn = awareness.doc().transact();
+    let state_vector = txn.state_vector();
+    Ok(DocStats {
+        state_vector: data_encoding::BASE64.encode(&state_vector.encode_v1()),
+        clients: state_vector.len(),
+        elements: state_vector.iter().map(|(_, clock)| *clock as u64).sum(),
+        root_types: txn.root_refs().map(|(name, _)| name.to_string()).collect(),
+        update_bytes: txn.encode_state_as_update_v1(&StateVector::default()).len(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stores::filesystem::FileSystemStore;
+    use yrs::Text;
+
+    #[tokio::test]
+    async fn test_verify_reports_stats_and_corruption() {
+        let path = std::env::temp_dir().join(format!("y-sweet-verify-{}", nanoid::nanoid!()));
+        let store: Arc<Box<dyn Store>> =
+            Arc::new(Box::new(FileSystemStore::new(path.clone()).unwrap()));
+        for doc_id in ["good", "bad"] {
+            let doc = DocWithSyncKv::new(doc_id, Some(store.clone()), || {}, false)
+                .await
+                .unwrap();
+            {
+                let awareness = doc.awareness();
+                let awareness = awareness.write().unwrap();
+                let text = awareness.doc().get_or_insert_text("text");
+                text.insert(&mut awareness.doc().transact_mut(), 0, "hello");
+            }
+            doc.sync_kv().persist().await.unwrap();
+        }
+
+        let result = verify_doc(&store, "good").await;
+        assert!(result.ok, "{:?}", result.error);
+        let stats = result.stats.unwrap();
+        assert_eq!(stats.clients, 1);
+        assert_eq!(stats.elements, 5);
+        assert_eq!(stats.root_types, vec!["text".to_string()]);
+
+        let key = snapshot_key("bad");
+        let mut snapshot = store.get(&key).await.unwrap().unwrap();
+        let last = snapshot.len() - 1;
+        snapshot[last] ^= 1;
+        store.set(&key, snapshot).await.unwrap();
+
+        let response = verify_docs(
+            &store,
+            vec!["bad".to_string(), "good".to_string(), "missing".to_string()],
+        )
+        .await;
+        assert_eq!(response.valid, 1);
+        assert_eq!(response.invalid, 2);
+        assert_eq!(response.results[0].doc_id, "bad");
+        assert!(response.results[0]
+            .error
+            .as_ref()
+            .unwrap()
+            .contains("corrupted"));
+        assert!(response.results[0].snapshot_bytes.is_some());
+        assert_eq!(response.results[2].snapshot_bytes, None);
+    }
+}
@@ -0,0 +1,308 @@
+//! Local write-ahead log of document updates.
+//!
+//! Updates are only written to the store at checkpoints, so a crash loses the updates made
+//! since the last one. With a WAL, every update applied to a loaded document is also
+//! appended to a file on local disk as soon as it is made. Appends are written by a
+//! dedicated thread, which syncs each file once per batch of updates rather than once per
+//! update.
+//!
+//! Before each checkpoint the file of the document is rotated (`{doc}.wal` is moved to
+//! `{doc}.wal.old`), and the rotated file is removed once the checkpoint has been written,
+//! since it only holds updates the checkpoint includes. When a document is loaded, both
+//! files are replayed and, once the document has been persisted, removed.
+//!
+//! File names are the hex encoded document IDs, since IDs of subdocuments contain slashes.
+
+use anyhow::{Context, Result};
+use data_encoding::HEXLOWER;
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, RwLock},
+};
+use tokio::sync::oneshot;
+use tracing::error;
+use y_sweet_core::{doc_sync_ext::DocWithSyncKv, sync::awareness::Awareness};
+use yrs::Subscription;
+
+enum WalOp {
+    Append(String, Vec<u8>),
+    /// Rotate the file of a document once the updates queued before are written.
+    Rotate(String, oneshot::Sender<()>),
+}
+
+pub struct Wal {
+    dir: PathBuf,
+    ops: mpsc::Sender<WalOp>,
+}
+
+impl Wal {
+    /// Keep the WAL in `dir`, which is created if needed.
+    pub fn open(dir: PathBuf) -> Result<Self> {
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create WAL directory {}", dir.display()))?;
+
+        let (ops, recv) = mpsc::channel();
+        let writer_dir = dir.clone();
+        std::thread::Builder::new()
+            .name("y-sweet-wal".to_string())
+            .spawn(move || write_loop(&writer_dir, recv))
+            .context("Failed to start WAL writer")?;
+
+        Ok(Self { dir, ops })
+    }
+
+    fn path(&self, doc_id: &str) -> PathBuf {
+        wal_path(&self.dir, doc_id)
+    }
+
+    /// Apply the updates logged for a document that were not persisted. Returns how many
+    /// were applied.
+    pub fn replay(&self, doc_id: &str, doc: &DocWithSyncKv) -> Result<usize> {
+        let mut replayed = 0;
+        for path in [rotated_path(&self.path(doc_id)), self.path(doc_id)] {
+            for update in read_records(&path)? {
+                doc.apply_update(&update)?;
+                replayed += 1;
+            }
+        }
+        Ok(replayed)
+    }
+
+    /// Remove the logged updates of a document, once they are all persisted. Must not be
+    /// called while the document is attached.
+    pub fn clear(&self, doc_id: &str) -> Result<()> {
+        let path = self.path(doc_id);
+        remove_if_exists(&path)?;
+        remove_if_exists(&rotated_path(&path))?;
+        Ok(())
+    }
+
+    /// Log the updates applied to a loaded document for as long as the returned handle lives.
+    pub fn attach(self: &Arc<Self>, doc_id: &str, awareness: &Arc<RwLock<Awareness>>) -> DocWal {
+        let ops = self.ops.clone();
+        let message_doc_id = doc_id.to_string();
+        let subscription = awareness
+            .read()
+            .unwrap()
+            .doc()
+            .observe_update_v1(move |_, event| {
+                // Fails only once the writer has exited
+                let _ = ops.send(WalOp::Append(message_doc_id.clone(), event.update.clone()));
+            })
+            .unwrap();
+
+        DocWal {
+            wal: self.clone(),
+            doc_id: doc_id.to_string(),
+            _subscription: subscription,
+        }
+    }
+}
+
+/// The WAL of one loaded document.
+pub struct DocWal {
+    wal: Arc<Wal>,
+    doc_id: String,
+    _subscription: Subscription,
+}
+
+impl DocWal {
+    /// Set aside the updates logged so far, before a checkpoint that includes them.
+    pub async fn rotate(&self) {
+        let (ack, done) = oneshot::channel();
+        if self
+            .wal
+            .ops
+            .send(WalOp::Rotate(self.doc_id.clone(), ack))
+            .is_ok()
+        {
+            let _ = done.await;
+        }
+    }
+
+    /// Remove the updates set aside by [DocWal::rotate], once the checkpoint is written.
+    pub fn checkpoint_written(&self) {
+        if let Err(e) = remove_if_exists(&rotated_path(&self.wal.path(&self.doc_id))) {
+            error!(
+                message = "Failed to remove rotated WAL",
+                event = "wal_error",
+                doc_id = %self.doc_id,
+                error = %e
+            );
+        }
+    }
+}
+
+fn wal_path(dir: &Path, doc_id: &str) -> PathBuf {
+    dir.join(format!("{}.wal", HEXLOWER.encode(doc_id.as_bytes())))
+}
+
+fn rotated_path(path: &Path) -> PathBuf {
+    path.with_extension("wal.old")
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Records are a little-endian u32 length followed by the update.
+fn write_record(file: &mut File, update: &[u8]) -> io::Result<()> {
+    let mut record = Vec::with_capacity(4 + update.len());
+    record.extend_from_slice(&(update.len() as u32).to_le_bytes());
+    record.extend_from_slice(update);
+    file.write_all(&record)
+}
+
+/// Read the records of a file. A record cut short by a crash is ignored.
+fn read_records(path: &Path) -> Result<Vec<Vec<u8>>> {
+    let mut data = Vec::new();
+    match File::open(path) {
+        Ok(mut file) => file.read_to_end(&mut data)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut records = Vec::new();
+    let mut rest = data.as_slice();
+    while rest.len() >= 4 {
+        let len = u32::from_le_bytes(rest[..4].try_into().unwrap()) as usize;
+        let Some(update) = rest.get(4..4 + len) else {
+            break;
+        };
+        records.push(update.to_vec());
+        rest = &rest[4 + len..];
+    }
+    Ok(records)
+}
+
+/// Move the file of a document aside, appending it to the rotated file if a previous
+/// checkpoint failed and left one.
+fn rotate(path: &Path) -> io::Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let rotated = rotated_path(path);
+    if !rotated.exists() {
+        return fs::rename(path, rotated);
+    }
+
+    let data = fs::read(path)?;
+    let mut file = OpenOptions::new().append(true).open(&rotated)?;
+    file.write_all(&data)?;
+    file.sync_data()?;
+    fs::remove_file(path)
+}
+
+fn write_loop(dir: &Path, recv: mpsc::Receiver<WalOp>) {
+    while let Ok(op) = recv.recv() {
+        // Everything queued meanwhile is written before syncing once per file
+        let mut files: HashMap<String, File> = HashMap::new();
+        for op in std::iter::once(op).chain(recv.try_iter()) {
+            let (doc_id, result) = match op {
+                WalOp::Append(doc_id, update) => {
+                    let result = match files.get_mut(&doc_id) {
+                        Some(file) => write_record(file, &update),
+                        None => OpenOptions::new()
+                            .create(true)
+                            .append(true)
+                            .open(wal_path(dir, &doc_id))
+                            .and_then(|mut file| {
+                                write_record(&mut file, &update)?;
+                                files.insert(doc_id.clone(), file);
+                                Ok(())
+                            }),
+                    };
+                    (doc_id, result)
+                }
+                WalOp::Rotate(doc_id, ack) => {
+                    let result = match files.remove(&doc_id) {
+                        Some(file) => file.sync_data(),
+                        None => Ok(()),
+                    }
+                    .and_then(|_| rotate(&wal_path(dir, &doc_id)));
+                    let _ = ack.send(());
+                    (doc_id, result)
+                }
+            };
+            if let Err(e) = result {
+                error!(
+                    message = "Failed to write WAL",
+                    event = "wal_error",
+                    doc_id = %doc_id,
+                    error = %e
+                );
+            }
+        }
+
+        for (doc_id, file) in files {
+            if let Err(e) = file.sync_data() {
+                error!(
+                    message = "Failed to sync WAL",
+                    event = "wal_error",
+                    doc_id = %doc_id,
+                    error = %e
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use yrs::{GetString, Text, Transact};
+
+    fn insert(doc: &DocWithSyncKv, text: &str) {
+        let awareness = doc.awareness();
+        let awareness = awareness.write().unwrap();
+        let field = awareness.doc().get_or_insert_text("text");
+        let mut txn = awareness.doc().transact_mut();
+        let len = field.len(&txn);
+        field.insert(&mut txn, len, text);
+    }
+
+    fn text(doc: &DocWithSyncKv) -> String {
+        let awareness = doc.awareness();
+        let awareness = awareness.read().unwrap();
+        let field = awareness.doc().get_or_insert_text("text");
+        let string = field.get_string(&awareness.doc().transact());
+        string
+    }
+
+    #[tokio::test]
+    async fn test_unpersisted_updates_are_replayed() {
+        let dir = std::env::temp_dir().join(format!("y-sweet-wal-{}", nanoid::nanoid!()));
+        let wal = Arc::new(Wal::open(dir.clone()).unwrap());
+        let doc_id = "board/subdocs/page-1";
+
+        let doc = DocWithSyncKv::new(doc_id, None, || {}, false)
+            .await
+            .unwrap();
+        let doc_wal = wal.attach(doc_id, &doc.awareness());
+        insert(&doc, "one ");
+        doc_wal.rotate().await;
+        insert(&doc, "two");
+        // Waits for the append queued before
+        doc_wal.rotate().await;
+
+        let restarted = Wal::open(dir.clone()).unwrap();
+        let replayed = DocWithSyncKv::new(doc_id, None, || {}, false)
+            .await
+            .unwrap();
+        assert_eq!(restarted.replay(doc_id, &replayed).unwrap(), 2);
+        assert_eq!(text(&replayed), "one two");
+
+        // The checkpoint includes everything rotated out
+        doc_wal.checkpoint_written();
+        let empty = DocWithSyncKv::new(doc_id, None, || {}, false)
+            .await
+            .unwrap();
+        assert_eq!(restarted.replay(doc_id, &empty).unwrap(), 0);
+    }
+}
@@ -0,0 +1,672 @@
+//! Experimental WebTransport endpoint of the sync protocol, enabled with the `webtransport`
+//! feature.
+//!
+//! WebTransport runs over HTTP/3 and QUIC, which recovers from packet loss per stream and
+//! survives changes of the client address, so clients on flaky mobile networks see fewer
+//! stalls and reconnects than with WebSockets over TCP.
+//!
+//! The endpoint listens on UDP, on the same address and port as the HTTPS listener and with
+//! the same certificate. A client that supports WebTransport opens a session at
+//! `{baseUrl}/wt?token=...`, where `baseUrl` and `token` come from the client token, and falls
+//! back to the WebSocket `url` otherwise. In the session, the client opens one bidirectional
+//! stream, on which each y-sync message is prefixed with its length as a varUint.
+//!
+//! Sessions are let in like WebSocket connections (see [admit_sync_client]) and close with the
+//! same codes: [RESYNC_REQUIRED_CLOSE_CODE] for slow clients, [DOC_DELETED_CLOSE_CODE] once
+//! the document is deleted, and 1012 (restart) on shutdown. QUIC keep-alives replace pings.
+
+use anyhow::{anyhow, Context, Result};
+use axum::http::StatusCode;
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Instant,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    sync::{broadcast, mpsc::channel},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+use wtransport::{
+    endpoint::{endpoint_side, IncomingSession, SessionRequest},
+    Connection, Endpoint, Identity, RecvStream, SendStream, ServerConfig, VarInt,
+};
+use y_sweet_core::{
+    api_types::Authorization, doc_connection_ext::DocConnection, protocol_error_ext::error_reply,
+    subdoc_ext::is_subdoc_message, sync::awareness::Awareness,
+};
+use yrs::encoding::write::Write;
+
+use crate::audit_ext::AuditPrincipal;
+use crate::backpressure_ext::{
+    resync_messages, SendOverflow, SlowConsumerAction, RESYNC_REQUIRED_CLOSE_CODE,
+};
+use crate::broadcast_ext::DOC_DELETED_CLOSE_CODE;
+use crate::metrics_ext::{is_sync_step1_message, is_update_message};
+use crate::server::{
+    admit_sync_client, track_sync_client, AppError, Server, SyncClient, WsOptions,
+};
+use crate::slow_ext::check_slow_message;
+
+/// Largest message accepted when no `--max-message-size` is set, as for WebSockets.
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 << 20;
+
+/// Close code of sessions ended by a server shutdown, the WebSocket "service restart" code.
+const RESTART_CLOSE_CODE: u32 = 1012;
+
+/// Close code of sessions whose client exceeded a message limit, the WebSocket "policy
+/// violation" code.
+const POLICY_CLOSE_CODE: u32 = 1008;
+
+/// Where the WebTransport endpoint listens, and its certificate.
+#[derive(Clone, Debug)]
+pub struct WebTransport {
+    pub addr: SocketAddr,
+    /// PEM file of the certificate chain.
+    pub cert: PathBuf,
+    /// PEM file of the private key of the certificate.
+    pub key: PathBuf,
+}
+
+impl WebTransport {
+    /// Bind the endpoint, with the certificate read from its files.
+    pub(crate) async fn bind(
+        &self,
+        options: &WsOptions,
+    ) -> Result<Endpoint<endpoint_side::Server>> {
+        let identity = Identity::load_pemfiles(&self.cert, &self.key)
+            .await
+            .context("Failed to load the WebTransport certificate")?;
+        bind_endpoint(self.addr, identity, options)
+    }
+}
+
+/// Bind an endpoint on `addr`. Sessions are kept alive and time out like WebSocket
+/// connections.
+fn bind_endpoint(
+    addr: SocketAddr,
+    identity: Identity,
+    options: &WsOptions,
+) -> Result<Endpoint<endpoint_side::Server>> {
+    let config = ServerConfig::builder()
+        .with_bind_address(addr)
+        .with_identity(identity)
+        .keep_alive_interval(Some(options.ping_interval))
+        .max_idle_timeout(Some(options.idle_timeout))
+        .map_err(|_| anyhow!("Invalid WebTransport idle timeout"))?
+        .build();
+    Endpoint::server(config).context("Failed to bind the WebTransport endpoint")
+}
+
+/// Accept WebTransport sessions until the server shuts down.
+pub(crate) async fn serve_webtransport(
+    server_state: Arc<Server>,
+    endpoint: Endpoint<endpoint_side::Server>,
+    cancellation_token: CancellationToken,
+) {
+    info!(
+        message = "WebTransport endpoint listening",
+        event = "webtransport_server_started",
+        address = ?endpoint.local_addr().ok()
+    );
+
+    loop {
+        let incoming = tokio::select! {
+            incoming = endpoint.accept() => incoming,
+            _ = cancellation_token.cancelled() => break,
+        };
+        tokio::spawn(handle_incoming(server_state.clone(), incoming));
+    }
+
+    // Sessions close themselves on shutdown; give the clients time to acknowledge
+    let drain_period = server_state.ws_options().drain_period;
+    let _ = tokio::time::timeout(drain_period, endpoint.wait_idle()).await;
+    tracing::debug!("Exiting WebTransport server");
+}
+
+async fn handle_incoming(server_state: Arc<Server>, incoming: IncomingSession) {
+    let handshake = Instant::now();
+    let request = match incoming.await {
+        Ok(request) => request,
+        Err(e) => {
+            warn!(
+                message = "WebTransport handshake failed",
+                event = "webtransport_handshake_error",
+                error = %e
+            );
+            return;
+        }
+    };
+
+    let (doc_id, token) = match parse_session_path(request.path(), server_state.base_path()) {
+        Some(target) => target,
+        None => {
+            request.not_found().await;
+            return;
+        }
+    };
+    let (client, principal) = match admit(&server_state, &doc_id, token.as_deref()).await {
+        Ok(admitted) => admitted,
+        Err(e) => {
+            warn!(
+                message = "WebTransport session rejected",
+                event = "webtransport_session_rejected",
+                doc_id = %doc_id,
+                error = %e
+            );
+            reject(request, e.0).await;
+            return;
+        }
+    };
+
+    let connection = match request.accept().await {
+        Ok(connection) => connection,
+        Err(e) => {
+            warn!(
+                message = "WebTransport session failed",
+                event = "webtransport_session_error",
+                error = %e
+            );
+            return;
+        }
+    };
+
+    let authorization = client.authorization;
+    track_sync_client(
+        server_state.clone(),
+        doc_id,
+        authorization,
+        principal,
+        handle_session(server_state, connection, client, handshake),
+    )
+    .await;
+}
+
+/// Let the client into the sync, with the checks of WebSocket upgrades. Sessions are not
+/// proxied to the owner of a document in a cluster, so they are only accepted by the owner.
+async fn admit(
+    server_state: &Arc<Server>,
+    doc_id: &str,
+    token: Option<&str>,
+) -> Result<(SyncClient, AuditPrincipal), AppError> {
+    let authorization = server_state.verify_doc_token(token, doc_id)?;
+    crate::cluster_ext::check_local(server_state, doc_id)?;
+    let principal = AuditPrincipal::doc(token, authorization);
+    let client = admit_sync_client(server_state, doc_id.to_string(), authorization).await?;
+    Ok((client, principal))
+}
+
+/// Reject a session with the closest status WebTransport can reply with.
+async fn reject(request: SessionRequest, status: StatusCode) {
+    match status {
+        StatusCode::NOT_FOUND | StatusCode::GONE => request.not_found().await,
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => {
+            request.too_many_requests().await
+        }
+        _ => request.forbidden().await,
+    }
+}
+
+/// The document ID and token of a session path, `{base_path}/d/{doc_id}/wt?token=...`.
+fn parse_session_path(path: &str, base_path: Option<&str>) -> Option<(String, Option<String>)> {
+    let url = url::Url::parse("https://localhost").ok()?.join(path).ok()?;
+    let rest = url.path().strip_prefix(base_path.unwrap_or_default())?;
+    let doc_id = rest.strip_prefix("/d/")?.strip_suffix("/wt")?;
+    if doc_id.is_empty() || doc_id.contains('/') {
+        return None;
+    }
+    let token = url
+        .query_pairs()
+        .find(|(key, _)| key == "token")
+        .map(|(_, value)| value.into_owned());
+    Some((doc_id.to_string(), token))
+}
+
+/// Prefix `msg` with its length, as sent on the stream of a session.
+fn frame(msg: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(msg.len() + 5);
+    framed.write_buf(msg);
+    framed
+}
+
+/// Read the length of the next message, or `None` once the client finished the stream.
+async fn read_frame_len<R: AsyncRead + Unpin>(recv: &mut R) -> Result<Option<usize>> {
+    let mut len = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = match recv.read_u8().await {
+            Ok(byte) => byte,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof && shift == 0 => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e.into()),
+        };
+        len |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(Some(usize::try_from(len)?));
+        }
+        shift += 7;
+        if shift > 63 {
+            return Err(anyhow!("Message length does not fit in 64 bits"));
+        }
+    }
+}
+
+async fn send_frame(send: &mut SendStream, msg: &[u8]) -> Result<()> {
+    send.write_all(&frame(msg)).await?;
+    Ok(())
+}
+
+fn close(connection: &Connection, code: u32, reason: &str) {
+    connection.close(VarInt::from_u32(code), reason.as_bytes());
+}
+
+async fn handle_session(
+    server_state: Arc<Server>,
+    connection: Connection,
+    client: SyncClient,
+    handshake: Instant,
+) {
+    let SyncClient {
+        doc_id,
+        awareness,
+        authorization,
+        frozen,
+        permit,
+        stats,
+        broadcast,
+        subdocs,
+        message_limits,
+        update_validator,
+    } = client;
+    let _permit = permit;
+    let _connection = stats.connect();
+    let options = server_state.ws_options();
+    let metrics = server_state.sync_metrics();
+    let slow_thresholds = server_state.slow_thresholds();
+    let cancellation_token = server_state.cancellation_token();
+
+    let streams = tokio::select! {
+        streams = connection.accept_bi() => streams,
+        _ = cancellation_token.cancelled() => return,
+    };
+    let (mut send_stream, mut recv_stream) = match streams {
+        Ok(streams) => streams,
+        Err(e) => {
+            warn!(
+                message = "WebTransport client opened no stream",
+                event = "webtransport_session_error",
+                error = %e
+            );
+            return;
+        }
+    };
+
+    info!(
+        message = "WebTransport connected",
+        event = "webtransport_connected",
+        authorization_type = %match authorization {
+            Authorization::Full => "Full",
+            Authorization::ReadOnly => "ReadOnly",
+        }
+    );
+
+    // Replies to this client only; updates for everyone come through the document broadcast
+    let (send, mut recv) = channel::<Vec<u8>>(options.send_buffer);
+    let mut updates = broadcast.subscribe();
+    let subdoc_send = send.clone();
+    let error_send = send.clone();
+    let overflow = Arc::new(SendOverflow::default());
+    let mut subdocs = subdocs.with_overflow(overflow.clone());
+    let deleted = broadcast.deleted();
+    // Cancelled when the sending side gives up, so the receiving loop stops too
+    let connection_lost = CancellationToken::new();
+
+    {
+        let connection = connection.clone();
+        let connection_lost = connection_lost.clone();
+        let overflow = overflow.clone();
+        let awareness: Arc<RwLock<Awareness>> = awareness.clone();
+        let stats = stats.clone();
+        tokio::spawn(async move {
+            let _connection_lost = connection_lost.drop_guard();
+            let _broadcast = broadcast;
+            loop {
+                // Biased so that an overflow is only handled once the queued messages are sent
+                let sent = tokio::select! {
+                    biased;
+                    msg = recv.recv() => {
+                        let Some(msg) = msg else {
+                            break;
+                        };
+                        stats.record_bytes_out(msg.len());
+                        send_frame(&mut send_stream, &msg).await
+                    }
+                    update = updates.recv() => {
+                        let msg = match update {
+                            Ok(msg) => msg,
+                            Err(broadcast::error::RecvError::Lagged(_)) => {
+                                overflow.mark();
+                                continue;
+                            }
+                            Err(broadcast::error::RecvError::Closed) => break,
+                        };
+                        stats.record_bytes_out(msg.len());
+                        send_frame(&mut send_stream, &msg).await
+                    }
+                    _ = std::future::ready(()), if overflow.is_set() => {
+                        match overflow.take(options.slow_consumer_policy) {
+                            Some(SlowConsumerAction::Close) => {
+                                warn!(
+                                    message = "WebTransport client too slow, closing session",
+                                    event = "webtransport_slow_consumer",
+                                    action = "close"
+                                );
+                                close(&connection, RESYNC_REQUIRED_CLOSE_CODE.into(), "resync required");
+                                break;
+                            }
+                            Some(SlowConsumerAction::Resync) => {
+                                warn!(
+                                    message = "WebTransport client too slow, resending full state",
+                                    event = "webtransport_slow_consumer",
+                                    action = "resync"
+                                );
+                                let messages = resync_messages(&awareness.read().unwrap());
+                                let mut sent = Ok(());
+                                for msg in messages {
+                                    stats.record_bytes_out(msg.len());
+                                    sent = send_frame(&mut send_stream, &msg).await;
+                                    if sent.is_err() {
+                                        break;
+                                    }
+                                }
+                                sent
+                            }
+                            None => Ok(()),
+                        }
+                    }
+                };
+                if let Err(e) = sent {
+                    error!(
+                        message = %format!("WebTransport send error: {}", e),
+                        event = "webtransport_send_error",
+                        error = %e
+                    );
+                    break;
+                }
+            }
+        });
+    }
+
+    let mut doc_connection =
+        DocConnection::new_without_updates(awareness, authorization, move |bytes| {
+            if let Err(e) = send.try_send(bytes.to_vec()) {
+                warn!(
+                    message = %format!("WebTransport message error: {}", e),
+                    event = "webtransport_message_error",
+                    error = %e
+                );
+                overflow.mark();
+            }
+        })
+        .with_read_only_flag(frozen);
+    if let Some(update_validator) = update_validator {
+        doc_connection = doc_connection.with_update_validator(&doc_id, update_validator);
+    }
+
+    let max_message_size = message_limits
+        .max_message_size
+        .unwrap_or(DEFAULT_MAX_MESSAGE_SIZE);
+    let mut message_count = 0u64;
+    let mut synced = false;
+    let mut limiter = message_limits.limiter();
+    loop {
+        let msg = tokio::select! {
+            msg = read_message(&mut recv_stream, max_message_size) => msg,
+            _ = deleted.cancelled() => {
+                info!(
+                    message = "WebTransport closed because the document was deleted",
+                    event = "webtransport_closed",
+                    total_messages = %message_count,
+                    reason = "document_deleted"
+                );
+                close(&connection, DOC_DELETED_CLOSE_CODE.into(), "document deleted");
+                break;
+            }
+            _ = connection_lost.cancelled() => {
+                info!(
+                    message = "WebTransport closed after the session stopped responding",
+                    event = "webtransport_closed",
+                    total_messages = %message_count,
+                    reason = "send_failed"
+                );
+                break;
+            }
+            _ = cancellation_token.cancelled() => {
+                info!(
+                    message = "WebTransport closed due to server shutdown",
+                    event = "webtransport_closed",
+                    total_messages = %message_count,
+                    reason = "server_shutdown"
+                );
+                let reason = match options.retry_after {
+                    Some(retry_after) => format!("retry-after={}", retry_after.as_secs()),
+                    None => "server shutting down".to_string(),
+                };
+                close(&connection, RESTART_CLOSE_CODE, &reason);
+                break;
+            }
+        };
+        let msg = match msg {
+            Ok(Some(msg)) => msg,
+            Ok(None) => {
+                info!(
+                    message = "WebTransport closed by client",
+                    event = "webtransport_closed",
+                    total_messages = %message_count,
+                    reason = "client_close"
+                );
+                break;
+            }
+            Err(e) => {
+                warn!(
+                    message = %format!("WebTransport stream error: {}", e),
+                    event = "webtransport_stream_error",
+                    error = %e
+                );
+                close(&connection, POLICY_CLOSE_CODE, "invalid message");
+                break;
+            }
+        };
+
+        message_count += 1;
+        stats.record_bytes_in(msg.len());
+        if let Err(exceeded) = limiter.check(msg.len()) {
+            warn!(
+                message = "WebTransport client exceeded a message limit, closing session",
+                event = "webtransport_limit_exceeded",
+                reason = exceeded.reason(),
+                size = msg.len(),
+                total_messages = %message_count
+            );
+            let frame = exceeded.close_frame();
+            close(&connection, frame.code.into(), &frame.reason);
+            break;
+        }
+
+        // Nothing may reach the in-memory copy of a deleted document
+        if deleted.is_cancelled() {
+            continue;
+        }
+
+        let received = Instant::now();
+        let result = if is_subdoc_message(&msg) {
+            subdocs.send(&msg, &subdoc_send).await
+        } else {
+            doc_connection.send(&msg).await
+        };
+        let outcome = if result.is_ok() { "ok" } else { "rejected" };
+        let elapsed = received.elapsed();
+        if is_update_message(&msg) {
+            metrics.update_broadcast.observe(outcome, elapsed);
+        }
+        check_slow_message(&slow_thresholds, &doc_id, &msg, elapsed);
+        // The reply to the first sync step 1 carries the state the client is missing
+        if !synced && is_sync_step1_message(&msg) {
+            synced = true;
+            metrics.first_sync.observe(outcome, handshake.elapsed());
+        }
+
+        if let Err(e) = result {
+            error!(
+                message = %format!("WebTransport message handling error: {}", e),
+                event = "webtransport_message_handling_error",
+                error = %e,
+                message_count = %message_count
+            );
+            // Tell the client why its message had no effect
+            let _ = error_send.try_send(error_reply(&e));
+        }
+    }
+
+    if !synced {
+        metrics.first_sync.observe("closed", handshake.elapsed());
+    }
+}
+
+/// Read the next message, refusing lengths above `max_message_size` before reading them.
+async fn read_message(recv: &mut RecvStream, max_message_size: usize) -> Result<Option<Vec<u8>>> {
+    let Some(len) = read_frame_len(recv).await? else {
+        return Ok(None);
+    };
+    if len > max_message_size {
+        return Err(anyhow!(
+            "Message of {} bytes exceeds {} bytes",
+            len,
+            max_message_size
+        ));
+    }
+    let mut msg = vec![0; len];
+    recv.read_exact(&mut msg).await?;
+    Ok(Some(msg))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use wtransport::ClientConfig;
+    use y_sweet_core::sync::{Message, SyncMessage};
+    use yrs::{
+        updates::{decoder::Decode, encoder::Encode},
+        Doc, GetString, StateVector, Text, Transact, Update,
+    };
+
+    #[tokio::test]
+    async fn test_clients_sync_over_webtransport() {
+        let server_state = Arc::new(Server::builder().build().await.unwrap());
+        let dwskv = server_state.get_or_create_doc("doc1").await.unwrap();
+        {
+            let awareness = dwskv.awareness();
+            let awareness = awareness.write().unwrap();
+            let text = awareness.doc.get_or_insert_text("text");
+            text.insert(&mut awareness.doc.transact_mut(), 0, "hello");
+        }
+
+        let identity = Identity::self_signed(["localhost"]).unwrap();
+        let hash = identity.certificate_chain().as_slice()[0].hash();
+        let endpoint = bind_endpoint(
+            "127.0.0.1:0".parse().unwrap(),
+            identity,
+            &server_state.ws_options(),
+        )
+        .unwrap();
+        let port = endpoint.local_addr().unwrap().port();
+        let shutdown = CancellationToken::new();
+        tokio::spawn(serve_webtransport(
+            server_state.clone(),
+            endpoint,
+            shutdown.clone(),
+        ));
+
+        let client = Endpoint::client(
+            ClientConfig::builder()
+                .with_bind_default()
+                .with_server_certificate_hashes([hash])
+                .build(),
+        )
+        .unwrap();
+
+        // Only the sync path of a document opens a session
+        assert!(client
+            .connect(format!("https://localhost:{}/d/doc1/ws", port))
+            .await
+            .is_err());
+
+        let connection = client
+            .connect(format!("https://localhost:{}/d/doc1/wt", port))
+            .await
+            .unwrap();
+        let (mut send, mut recv) = connection.open_bi().await.unwrap().await.unwrap();
+        let step1 = Message::Sync(SyncMessage::SyncStep1(StateVector::default())).encode_v1();
+        send.write_all(&frame(&step1)).await.unwrap();
+
+        let doc = Doc::new();
+        let text = doc.get_or_insert_text("text");
+        while text.get_string(&doc.transact()).is_empty() {
+            let msg = tokio::time::timeout(
+                std::time::Duration::from_secs(5),
+                read_message(&mut recv, DEFAULT_MAX_MESSAGE_SIZE),
+            )
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+            if let Ok(Message::Sync(SyncMessage::SyncStep2(update))) = Message::decode_v1(&msg) {
+                doc.transact_mut()
+                    .apply_update(Update::decode_v1(&update).unwrap());
+            }
+        }
+        assert_eq!(text.get_string(&doc.transact()), "hello");
+        shutdown.cancel();
+    }
+
+    #[test]
+    fn test_parse_session_path() {
+        assert_eq!(
+            parse_session_path("/d/doc1/wt?token=abc", None),
+            Some(("doc1".to_string(), Some("abc".to_string())))
+        );
+        assert_eq!(
+            parse_session_path("/collab/d/doc1/wt", Some("/collab")),
+            Some(("doc1".to_string(), None))
+        );
+        assert_eq!(parse_session_path("/d/doc1/wt", Some("/collab")), None);
+        assert_eq!(parse_session_path("/d/doc1/ws", None), None);
+        assert_eq!(parse_session_path("/d/a/b/wt", None), None);
+        assert_eq!(parse_session_path("/d//wt", None), None);
+    }
+
+    #[tokio::test]
+    async fn test_frames_round_trip() {
+        let messages = [vec![], vec![1, 2, 3], vec![7; 300], vec![9; 70_000]];
+        let stream: Vec<u8> = messages.iter().flat_map(|msg| frame(msg)).collect();
+        // Lengths above 127 take more than one byte
+        assert_eq!(&frame(&[7; 300])[..2], &[0xac, 0x02]);
+
+        let mut recv = stream.as_slice();
+        for msg in &messages {
+            let len = read_frame_len(&mut recv).await.unwrap().unwrap();
+            assert_eq!(len, msg.len());
+            let mut read = vec![0; len];
+            recv.read_exact(&mut read).await.unwrap();
+            assert_eq!(&read, msg);
+        }
+        assert_eq!(read_frame_len(&mut recv).await.unwrap(), None);
+
+        // A stream finished in the middle of a length is an error
+        let mut truncated: &[u8] = &[0x80];
+        assert!(read_frame_len(&mut truncated).await.is_err());
+    }
+}
@@ -1,8 +1,17 @@
 import * as Y from 'yjs'
 import {
+  type AssetChange,
   type AuthEndpoint,
+  CLOSE_CODE_DOC_DELETED,
+  EVENT_ASSET_CHANGE,
   EVENT_CONNECTION_STATUS,
+  EVENT_DOC_DELETED,
   EVENT_LOCAL_CHANGES,
+  EVENT_SERVER_ERROR,
+  SERVER_ERROR_INTERNAL,
+  SERVER_ERROR_INVALID_MESSAGE,
+  SERVER_ERROR_PERMISSION_DENIED,
+  type ServerError,
   STATUS_CONNECTED,
   STATUS_CONNECTING,
   STATUS_ERROR,
@@ -13,9 +22,18 @@ import {
   type YSweetStatus,
 } from './provider'
 export {
+  AssetChange,
   AuthEndpoint,
+  CLOSE_CODE_DOC_DELETED,
+  EVENT_ASSET_CHANGE,
   EVENT_CONNECTION_STATUS,
+  EVENT_DOC_DELETED,
   EVENT_LOCAL_CHANGES,
+  EVENT_SERVER_ERROR,
+  SERVER_ERROR_INTERNAL,
+  SERVER_ERROR_INVALID_MESSAGE,
+  SERVER_ERROR_PERMISSION_DENIED,
+  ServerError,
   STATUS_CONNECTED,
   STATUS_CONNECTING,
   STATUS_ERROR,
@@ -17,6 +17,9 @@ const MESSAGE_SYNC = 0
 const MESSAGE_QUERY_AWARENESS = 3
 const MESSAGE_AWARENESS = 1
 const MESSAGE_SYNC_STATUS = 102
+const MESSAGE_ERROR = 104
+const MESSAGE_ASSET = 105
+const MESSAGE_ASSET_DELETED = 106
 
 const RETRIES_BEFORE_TOKEN_REFRESH = 3
 const DELAY_MS_BEFORE_RECONNECT = 500
@@ -37,8 +40,39 @@ const MAX_TIMEOUT_WITHOUT_RECEIVING_HEARTBEAT = 3_000
 // Note: These should not conflict with y-websocket's events, defined in `ws-status.ts`.
 export const EVENT_LOCAL_CHANGES = 'local-changes'
 export const EVENT_CONNECTION_STATUS = 'connection-status'
+/** Emitted with a `ServerError` when the server could not handle a message sent by this provider. */
+export const EVENT_SERVER_ERROR = 'server-error'
+/** Emitted when the document was deleted on the server. The provider goes offline and does not reconnect. */
+export const EVENT_DOC_DELETED = 'doc-deleted'
+/** Emitted with an `AssetChange` when an asset of the document is uploaded, processed or deleted. */
+export const EVENT_ASSET_CHANGE = 'asset-change'
+
+type YSweetEvent =
+  | typeof EVENT_LOCAL_CHANGES
+  | typeof EVENT_CONNECTION_STATUS
+  | typeof EVENT_SERVER_ERROR
+  | typeof EVENT_DOC_DELETED
+  | typeof EVENT_ASSET_CHANGE
+
+/** WebSocket close code the server uses when the document is deleted. */
+export const CLOSE_CODE_DOC_DELETED = 4404
+
+/** Codes of `ServerError`. */
+export const SERVER_ERROR_PERMISSION_DENIED = 1
+export const SERVER_ERROR_INVALID_MESSAGE = 2
+export const SERVER_ERROR_INTERNAL = 3
+
+export type ServerError = {
+  code: number
+  reason: string
+}
 
-type YSweetEvent = typeof EVENT_LOCAL_CHANGES | typeof EVENT_CONNECTION_STATUS
+export type AssetChange = {
+  /** The asset ID, with its extension. */
+  assetId: string
+  /** Status of the processing of the asset, or `deleted`. */
+  status: 'pending' | 'ready' | 'failed' | 'deleted'
+}
 
 /** The provider is offline because it has not been asked to connect or has been disconnected by the application. */
 export const STATUS_OFFLINE = 'offline'
@@ -581,6 +615,29 @@ export class YSweetProvider {
         let ackedVersion = decoding.readVarUint(d2)
         this.updateAckedVersion(ackedVersion)
         break
+      case MESSAGE_ERROR:
+        let errorBytes = decoding.readVarUint8Array(decoder)
+        let d3 = decoding.createDecoder(errorBytes)
+        let serverError: ServerError = {
+          code: decoding.readVarUint(d3),
+          reason: decoding.readVarString(d3),
+        }
+        this.emit(EVENT_SERVER_ERROR, serverError)
+        break
+      case MESSAGE_ASSET:
+        let assetBytes = decoding.readVarUint8Array(decoder)
+        let d4 = decoding.createDecoder(assetBytes)
+        let assetChange: AssetChange = {
+          assetId: decoding.readVarString(d4),
+          status: decoding.readVarString(d4) as AssetChange['status'],
+        }
+        this.emit(EVENT_ASSET_CHANGE, assetChange)
+        break
+      case MESSAGE_ASSET_DELETED:
+        let deletedBytes = decoding.readVarUint8Array(decoder)
+        let d5 = decoding.createDecoder(deletedBytes)
+        this.emit(EVENT_ASSET_CHANGE, { assetId: decoding.readVarString(d5), status: 'deleted' })
+        break
       default:
         break
     }
@@ -588,10 +645,16 @@ export class YSweetProvider {
 
   private websocketClose(event: CloseEvent) {
     this.emit(EVENT_CONNECTION_CLOSE, event)
-    this.setStatus(STATUS_ERROR)
     this.clearHeartbeat()
     this.clearConnectionTimeout()
-    this.connect()
+    if (event.code === CLOSE_CODE_DOC_DELETED) {
+      // Reconnecting would recreate the document
+      this.setStatus(STATUS_OFFLINE)
+      this.emit(EVENT_DOC_DELETED)
+    } else {
+      this.setStatus(STATUS_ERROR)
+      this.connect()
+    }
 
     // Remove all awareness states except for our own.
     awarenessProtocol.removeAwarenessStates(